@@ -0,0 +1,112 @@
+//! Client Authentication
+//!
+//! Anyone who can reach an address the server listens on can connect.
+//! With a token configured (`--token` or `WINPIPE_TOKEN`), a connection
+//! has to present it before any Wayland traffic is read:
+//!
+//! - Client -> server: magic "WPAU", token length (u32 LE), token bytes
+//! - Server -> client: one status byte, 1 if the token was accepted and 0
+//!   otherwise; the server closes the connection after a 0
+//!
+//! Without TLS or Noise (see `crate::tls` and `crate::noise`) the token
+//! is sent in the clear, so it keeps out other machines on a trusted
+//! network, not eavesdroppers; `winpipe ssh` sends it through the ssh
+//! tunnel.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Result, WinpipeError};
+
+/// Environment variable holding the token when none is given on the
+/// command line
+pub const TOKEN_ENV: &str = "WINPIPE_TOKEN";
+
+/// Magic opening the handshake
+pub const MAGIC: &[u8; 4] = b"WPAU";
+
+/// Longest token accepted
+pub const MAX_TOKEN_LEN: usize = 1024;
+
+/// Connections that do not present a token within this time are dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The token given on the command line, or else in `WINPIPE_TOKEN`
+pub fn token_from(arg: Option<String>) -> Option<String> {
+    arg.or_else(|| std::env::var(TOKEN_ENV).ok()).filter(|token| !token.is_empty())
+}
+
+/// Present `token` to the server and wait for its verdict
+pub async fn present<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, token: &str) -> Result<()> {
+    let mut hello = Vec::with_capacity(8 + token.len());
+    hello.extend_from_slice(MAGIC);
+    hello.extend_from_slice(&(token.len() as u32).to_le_bytes());
+    hello.extend_from_slice(token.as_bytes());
+    stream.write_all(&hello).await?;
+
+    let mut status = [0u8];
+    stream.read_exact(&mut status).await?;
+    match status[0] {
+        1 => Ok(()),
+        _ => Err(WinpipeError::Protocol("the server rejected the token".to_string())),
+    }
+}
+
+/// Read the client's token and answer whether it matches `token`
+///
+/// Fails if the client presents another token, or none in time.
+pub async fn verify<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, token: &str) -> Result<()> {
+    let presented = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_token(stream))
+        .await
+        .map_err(|_| WinpipeError::Protocol("no token presented in time".to_string()))?;
+    let accepted = presented.as_ref().is_ok_and(|presented| constant_time_eq(presented, token.as_bytes()));
+    stream.write_all(&[accepted as u8]).await?;
+    match presented {
+        Ok(_) if accepted => Ok(()),
+        Ok(_) => Err(WinpipeError::Protocol("wrong token".to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+async fn read_token<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    if &header[..4] != MAGIC {
+        return Err(WinpipeError::Protocol("no authentication handshake".to_string()));
+    }
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if len > MAX_TOKEN_LEN {
+        return Err(WinpipeError::Protocol(format!("token of {} bytes is too long", len)));
+    }
+    let mut token = vec![0u8; len];
+    stream.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+/// Compare without an early exit, so timing does not tell how much of a
+/// guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_accepts_only_the_token() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (presented, verified) = tokio::join!(present(&mut client, "secret"), verify(&mut server, "secret"));
+        assert!(presented.is_ok() && verified.is_ok());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (presented, verified) = tokio::join!(present(&mut client, "guess!"), verify(&mut server, "secret"));
+        assert!(presented.is_err() && verified.is_err());
+
+        // Wayland traffic instead of a handshake is refused
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[1, 0, 0, 0, 1, 0, 12, 0]).await.unwrap();
+        assert!(verify(&mut server, "secret").await.is_err());
+    }
+}
@@ -37,10 +37,14 @@ fn main() {
     // Build frame header
     let mut frame = Vec::new();
     frame.extend_from_slice(b"WPRD"); // Magic
+    frame.extend_from_slice(&2u32.to_le_bytes()); // Version
+    frame.extend_from_slice(&1u32.to_le_bytes()); // Surface ID
+    frame.extend_from_slice(&1u64.to_le_bytes()); // Sequence
     frame.extend_from_slice(&width.to_le_bytes());
     frame.extend_from_slice(&height.to_le_bytes());
     frame.extend_from_slice(&0u32.to_le_bytes()); // Format: ARGB8888
     frame.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&u32::MAX.to_le_bytes()); // Damage: whole frame
     frame.extend_from_slice(&pixels);
     
     println!("📤 Sending test frame: {}x{} ({} bytes)", width, height, frame.len());
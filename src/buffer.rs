@@ -3,10 +3,117 @@
 //! Waypipe maintains "mirror" copies of shared memory buffers on both sides.
 //! When a buffer is updated, only the changed regions (deltas) are transmitted.
 //! This significantly reduces bandwidth for applications with relatively static UIs.
+//!
+//! Delta wire format:
+//! - Buffer ID (4 bytes, LE)
+//! - Region count (4 bytes, LE)
+//! - Per region: x, y, width, height (4 bytes each, LE), flags (4 bytes,
+//!   LE): 1=LZ4 compressed, 2=XOR, 4=RLE, data size (4 bytes, LE), then
+//!   the data: rows of width * bpp bytes, LZ4 with the uncompressed size
+//!   prepended when compressed, PackBits when run-length encoded
+//!
+//! Checksum wire format:
+//! - Buffer ID (4 bytes, LE)
+//! - XXH64 of the pixel rows without stride padding (8 bytes, LE)
+//! - Tile size (4 bytes, LE): 0 = no tile checksums
+//! - Tile count (4 bytes, LE), then an XXH64 per tile (8 bytes each, LE),
+//!   row-major
+//!
+//! Checksums are sent now and then so the receiver can detect that its
+//! copy diverged, e.g. after a lost delta, and ask for a full frame.
+//!
+//! XOR regions hold the new contents XORed with the previous ones. Where
+//! only a few pixels of a region changed, e.g. re-rendered anti-aliased
+//! text, that is mostly zero bytes and compresses far better than a copy.
+//!
+//! Waypipe interval diff format (for an unmodified waypipe peer), with
+//! the buffer taken as a flat array of 4-byte words:
+//! - Per interval: first word, end word (exclusive) (4 bytes each, LE),
+//!   then the new contents of those words
+//! - The trailing size % 4 bytes of the buffer, always
+//!
+//! Diffing hashes each tile and keeps the hashes for the next frame, so a
+//! tile that hashes the same as before is skipped without reading the
+//! previous frame at all.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::Write;
+use std::hash::Hasher;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use lz4_flex::compress_prepend_size;
+use twox_hash::XxHash64;
 
 use crate::error::{Result, WinpipeError};
+use crate::mapping::SharedMapping;
+use crate::pool::{PoolSlice, ShmPool};
+
+/// Default edge length of the tiles compared by `calculate_delta`, in pixels
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+/// Default number of unchanged pixels a merge of two regions may add
+pub const DEFAULT_MERGE_OVERHEAD: u32 = 256;
+
+/// Buffers at least this large are diffed on several threads
+pub const PARALLEL_DIFF_THRESHOLD: usize = 4 * 1024 * 1024;
+
+/// More rectangles than this are collapsed to their bounding box rather
+/// than coalesced, since coalescing takes cubic time
+pub const MAX_COALESCE_RECTS: usize = 32;
+
+/// Delta region flags
+pub mod delta_flags {
+    /// The region data is LZ4 compressed
+    pub const COMPRESSED: u32 = 1;
+    /// The region data is XORed with the previous contents
+    pub const XOR: u32 = 2;
+    /// The region data is PackBits run-length encoded
+    pub const RLE: u32 = 4;
+}
+
+/// Delta header size: buffer ID and region count
+pub const DELTA_HEADER_SIZE: usize = 8;
+
+/// Region header size: rectangle, flags and data size
+pub const REGION_HEADER_SIZE: usize = 24;
+
+/// Largest pixel size of any buffer format, bounding the data a region
+/// may decompress to
+pub const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+/// Where a mirror buffer keeps its pixels
+#[derive(Debug)]
+pub enum PixelStorage {
+    Heap(Vec<u8>),
+    /// A file mapped by the local renderer too
+    Mapped(SharedMapping),
+    /// Part of a wl_shm_pool shared with other buffers
+    Pool(PoolSlice),
+}
+
+impl Deref for PixelStorage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Heap(data) => data,
+            Self::Mapped(mapping) => mapping,
+            Self::Pool(slice) => slice,
+        }
+    }
+}
+
+impl DerefMut for PixelStorage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(data) => data,
+            Self::Mapped(mapping) => mapping,
+            Self::Pool(slice) => slice,
+        }
+    }
+}
 
 /// A mirrored shared memory buffer
 #[derive(Debug)]
@@ -21,12 +128,23 @@ pub struct MirrorBuffer {
     pub bpp: u32,
     /// Stride (bytes per row)
     pub stride: u32,
-    /// Buffer data
-    pub data: Vec<u8>,
+    /// Buffer data; write it through `update`, `update_region` or
+    /// `apply_delta` so the cached tile hashes stay valid
+    pub data: PixelStorage,
     /// Previous frame data (for delta calculation)
     pub prev_data: Option<Vec<u8>>,
+    /// Edge length of the diffing tiles in pixels
+    pub tile_size: u32,
+    /// Unchanged pixels two regions may waste when merged into one
+    pub merge_overhead: u32,
+    /// Send regions XORed with the previous contents
+    pub xor_deltas: bool,
     /// Dirty regions that need to be synced
     dirty_regions: Vec<DirtyRegion>,
+    /// Tile hashes of `data` from the last diff, with their tile size
+    tile_hashes: Option<(u32, Vec<u64>)>,
+    /// Tile hashes of `prev_data`, if it was diffed as `data` before
+    prev_tile_hashes: Option<(u32, Vec<u64>)>,
 }
 
 /// A dirty (changed) region of a buffer
@@ -39,7 +157,7 @@ pub struct DirtyRegion {
 }
 
 /// Delta encoding result
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BufferDelta {
     pub buffer_id: u32,
     /// Changed regions with their data
@@ -49,33 +167,229 @@ pub struct BufferDelta {
 }
 
 /// A single delta region with data
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeltaRegion {
     pub x: u32,
     pub y: u32,
     pub width: u32,
     pub height: u32,
     pub data: Vec<u8>,
+    /// `data` is XORed with the previous contents
+    pub xor: bool,
+}
+
+/// Checksums of a buffer's contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferChecksum {
+    pub buffer_id: u32,
+    /// XXH64 of the whole buffer
+    pub hash: u64,
+    /// Edge length of the checksummed tiles (0 = none)
+    pub tile_size: u32,
+    /// XXH64 of each tile, row-major
+    pub tiles: Vec<u64>,
+}
+
+/// Fixed part of an encoded checksum
+pub const CHECKSUM_HEADER_SIZE: usize = 20;
+
+impl BufferChecksum {
+    /// Checksum pixel rows `stride` bytes apart; `tile_size` 0 skips the
+    /// tile checksums
+    pub fn compute(buffer_id: u32, size: (u32, u32, u32), stride: u32, data: &[u8], tile_size: u32) -> Self {
+        let (width, height, bpp) = size;
+        let row = |y: u32, x0: u32, x1: u32| {
+            let start = (y * stride + x0 * bpp) as usize;
+            data.get(start..start + ((x1 - x0) * bpp) as usize).unwrap_or_default()
+        };
+
+        let mut hasher = XxHash64::with_seed(0);
+        (0..height).for_each(|y| hasher.write(row(y, 0, width)));
+
+        let mut tiles = Vec::new();
+        if tile_size > 0 {
+            for y0 in (0..height).step_by(tile_size as usize) {
+                for x0 in (0..width).step_by(tile_size as usize) {
+                    let tile = Bounds { x0, y0, x1: width.min(x0 + tile_size), y1: height.min(y0 + tile_size) };
+                    tiles.push(hash_rect(data, stride, bpp, tile));
+                }
+            }
+        }
+        Self { buffer_id, hash: hasher.finish(), tile_size, tiles }
+    }
+
+    /// Tiles whose checksums differ, as indices; all of them if the tile
+    /// layouts differ
+    pub fn mismatched_tiles(&self, other: &BufferChecksum) -> Vec<usize> {
+        if self.tile_size != other.tile_size || self.tiles.len() != other.tiles.len() {
+            return (0..self.tiles.len().max(other.tiles.len())).collect();
+        }
+        self.tiles.iter().zip(&other.tiles).enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(CHECKSUM_HEADER_SIZE + self.tiles.len() * 8);
+        buf.extend_from_slice(&self.buffer_id.to_le_bytes());
+        buf.extend_from_slice(&self.hash.to_le_bytes());
+        buf.extend_from_slice(&self.tile_size.to_le_bytes());
+        buf.extend_from_slice(&(self.tiles.len() as u32).to_le_bytes());
+        for tile in &self.tiles {
+            buf.extend_from_slice(&tile.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode from wire format
+    ///
+    /// Returns the checksum and the bytes it took, or `None` if more data
+    /// is needed. Checksums with more tiles than a buffer of `max_size`
+    /// (width, height) has are refused before waiting for them.
+    pub fn decode(data: &[u8], max_size: (u32, u32)) -> Result<Option<(Self, usize)>> {
+        let u32_at = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let u64_at = |offset: usize| data.get(offset..offset + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+        let (Some(buffer_id), Some(hash), Some(tile_size), Some(count)) = (u32_at(0), u64_at(4), u32_at(12), u32_at(16)) else {
+            return Ok(None);
+        };
+        let grid = tile_count(max_size.0, max_size.1, tile_size);
+        if count as u64 > grid {
+            return Err(WinpipeError::Buffer(format!("{} tile checksums, at most {} expected", count, grid)));
+        }
+        let size = CHECKSUM_HEADER_SIZE + count as usize * 8;
+        if data.len() < size {
+            return Ok(None);
+        }
+        let tiles = (0..count as usize).filter_map(|i| u64_at(CHECKSUM_HEADER_SIZE + i * 8)).collect();
+        Ok(Some((Self { buffer_id, hash, tile_size, tiles }, size)))
+    }
+}
+
+/// Tiles of `tile_size` (0 = none) covering a width x height buffer
+fn tile_count(width: u32, height: u32, tile_size: u32) -> u64 {
+    if tile_size == 0 {
+        return 0;
+    }
+    width.div_ceil(tile_size) as u64 * height.div_ceil(tile_size) as u64
+}
+
+impl DeltaRegion {
+    /// Check that the region lies inside a `width` x `height` buffer and
+    /// carries exactly its rows of `bpp`-byte pixels
+    pub fn validate(&self, width: u32, height: u32, bpp: u32) -> Result<()> {
+        check_region(self.x, self.y, self.width, self.height, self.data.len(), (width, height, bpp))
+    }
+}
+
+/// Validate a region of `len` bytes against (width, height, bpp)
+fn check_region(x: u32, y: u32, width: u32, height: u32, len: usize, buffer: (u32, u32, u32)) -> Result<()> {
+    let (buffer_width, buffer_height, bpp) = buffer;
+    let fits = x.checked_add(width).is_some_and(|right| right <= buffer_width)
+        && y.checked_add(height).is_some_and(|bottom| bottom <= buffer_height);
+    if !fits {
+        return Err(WinpipeError::Buffer(format!(
+            "region {}x{} at ({}, {}) outside {}x{} buffer", width, height, x, y, buffer_width, buffer_height
+        )));
+    }
+    let expected = width as u64 * height as u64 * bpp as u64;
+    if len as u64 != expected {
+        return Err(WinpipeError::Buffer(format!(
+            "region {}x{} carries {} bytes, expected {}", width, height, len, expected
+        )));
+    }
+    Ok(())
+}
+
+impl BufferDelta {
+    /// Encode to wire format
+    ///
+    /// With `compress`, regions are LZ4 compressed where that makes them
+    /// smaller.
+    pub fn encode(&self, compress: bool) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(DELTA_HEADER_SIZE + self.regions.len() * REGION_HEADER_SIZE + self.total_bytes);
+        buf.extend_from_slice(&self.buffer_id.to_le_bytes());
+        buf.extend_from_slice(&(self.regions.len() as u32).to_le_bytes());
+        for region in &self.regions {
+            let lz4 = compress.then(|| compress_prepend_size(&region.data));
+            let rle = compress.then(|| rle_encode(&region.data));
+            let (mut flags, data) = [(delta_flags::COMPRESSED, lz4.as_deref()), (delta_flags::RLE, rle.as_deref())]
+                .into_iter()
+                .filter_map(|(flags, data)| Some((flags, data?)))
+                .filter(|(_, data)| data.len() < region.data.len())
+                .min_by_key(|(_, data)| data.len())
+                .unwrap_or((0, region.data.as_slice()));
+            if region.xor {
+                flags |= delta_flags::XOR;
+            }
+            for value in [region.x, region.y, region.width, region.height, flags, data.len() as u32] {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    /// Decode from wire format
+    ///
+    /// Returns the delta and the bytes it took, or `None` if more data is
+    /// needed. Compressed regions may unpack to no more than their own
+    /// rectangle holds, and all of them together to at most `max_bytes`.
+    pub fn decode(data: &[u8], max_bytes: u64) -> Result<Option<(Self, usize)>> {
+        let read_u32 = |offset: usize| {
+            data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let (Some(buffer_id), Some(count)) = (read_u32(0), read_u32(4)) else {
+            return Ok(None);
+        };
+
+        let mut offset = DELTA_HEADER_SIZE;
+        let mut regions = Vec::new();
+        let mut total_bytes = 0;
+        for _ in 0..count {
+            let fields: Option<Vec<u32>> = (0..6).map(|i| read_u32(offset + i * 4)).collect();
+            let Some(&[x, y, width, height, flags, size]) = fields.as_deref() else {
+                return Ok(None);
+            };
+            let start = offset + REGION_HEADER_SIZE;
+            let Some(payload) = data.get(start..start + size as usize) else {
+                return Ok(None);
+            };
+            let max = (width as u64 * height as u64 * MAX_BYTES_PER_PIXEL).min(max_bytes - total_bytes as u64);
+            let data = if flags & delta_flags::COMPRESSED != 0 {
+                lz4_decode(payload, max)?
+            } else if flags & delta_flags::RLE != 0 {
+                rle_decode(payload, max)?
+            } else if size as u64 <= max {
+                payload.to_vec()
+            } else {
+                return Err(WinpipeError::Buffer(format!("{} byte region, at most {} expected", size, max)));
+            };
+            total_bytes += data.len();
+            let xor = flags & delta_flags::XOR != 0;
+            regions.push(DeltaRegion { x, y, width, height, data, xor });
+            offset = start + size as usize;
+        }
+
+        Ok(Some((Self { buffer_id, regions, total_bytes }, offset)))
+    }
 }
 
 impl MirrorBuffer {
     /// Create a new mirror buffer
     pub fn new(id: u32, width: u32, height: u32, bpp: u32, stride: u32) -> Self {
         let size = (stride * height) as usize;
-        Self {
-            id,
-            width,
-            height,
-            bpp,
-            stride,
-            data: vec![0u8; size],
-            prev_data: None,
-            dirty_regions: Vec::new(),
-        }
+        Self::from_data(id, width, height, bpp, stride, vec![0u8; size])
     }
 
     /// Create from existing data
     pub fn from_data(id: u32, width: u32, height: u32, bpp: u32, stride: u32, data: Vec<u8>) -> Self {
+        Self::with_storage(id, width, height, bpp, stride, PixelStorage::Heap(data))
+    }
+
+    /// Create over existing storage, e.g. part of a shm pool
+    pub fn with_storage(id: u32, width: u32, height: u32, bpp: u32, stride: u32, data: PixelStorage) -> Self {
         Self {
             id,
             width,
@@ -84,7 +398,12 @@ impl MirrorBuffer {
             stride,
             data,
             prev_data: None,
+            tile_size: DEFAULT_TILE_SIZE,
+            merge_overhead: DEFAULT_MERGE_OVERHEAD,
+            xor_deltas: false,
             dirty_regions: Vec::new(),
+            tile_hashes: None,
+            prev_tile_hashes: None,
         }
     }
 
@@ -93,110 +412,189 @@ impl MirrorBuffer {
         self.data.len()
     }
 
+    /// Move the pixels into a file mapped at `path`, replacing the file
+    ///
+    /// The local renderer can map the same file and read frames without
+    /// them being copied to it.
+    pub fn map_file(&mut self, path: &Path) -> Result<()> {
+        let mut mapping = SharedMapping::create(path, self.data.len())?;
+        mapping.copy_from_slice(&self.data);
+        self.data = PixelStorage::Mapped(mapping);
+        Ok(())
+    }
+
+    /// The file the pixels live in, if mapped
+    pub fn mapped_path(&self) -> Option<&Path> {
+        match &self.data {
+            PixelStorage::Mapped(mapping) => Some(mapping.path()),
+            PixelStorage::Heap(_) | PixelStorage::Pool(_) => None,
+        }
+    }
+
+    /// Checksum of the contents, with tile checksums if `tile_size` > 0
+    pub fn checksum(&self, tile_size: u32) -> BufferChecksum {
+        BufferChecksum::compute(self.id, (self.width, self.height, self.bpp), self.stride, &self.data, tile_size)
+    }
+
+    /// Pixel rows without stride padding
+    pub fn packed_data(&self) -> Cow<'_, [u8]> {
+        pack_rows(&self.data, (self.width * self.bpp) as usize, self.stride as usize, self.height as usize)
+            .unwrap_or_default()
+    }
+
     /// Update buffer data
+    ///
+    /// The current contents become the previous frame. Heap buffers write
+    /// into the previous frame's allocation and swap it in, so the pixels
+    /// are copied once per update rather than cloned and then copied.
+    /// Dirty regions from earlier updates no longer apply.
     pub fn update(&mut self, data: &[u8]) {
-        // Save previous for delta calculation
-        self.prev_data = Some(self.data.clone());
-        
-        // Copy new data
-        let copy_len = data.len().min(self.data.len());
-        self.data[..copy_len].copy_from_slice(&data[..copy_len]);
+        let mut spare = self.prev_data.take().unwrap_or_default();
+        spare.resize(self.data.len(), 0);
+        let copy_len = data.len().min(spare.len());
+        match &mut self.data {
+            PixelStorage::Heap(current) => {
+                // Bytes past the new data keep their contents
+                spare[copy_len..].copy_from_slice(&current[copy_len..]);
+                spare[..copy_len].copy_from_slice(&data[..copy_len]);
+                std::mem::swap(current, &mut spare);
+            }
+            // Shared storage is written where others read it
+            shared => {
+                spare.copy_from_slice(shared);
+                shared[..copy_len].copy_from_slice(&data[..copy_len]);
+            }
+        }
+        self.prev_data = Some(spare);
+        self.prev_tile_hashes = self.tile_hashes.take();
+        self.dirty_regions.clear();
     }
 
     /// Update a region of the buffer
-    pub fn update_region(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[u8]) {
-        let src_stride = width * self.bpp;
+    ///
+    /// Fails without touching the buffer if the region does not fit or
+    /// `data` is not exactly its rows of pixels.
+    pub fn update_region(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[u8]) -> Result<()> {
+        check_region(x, y, width, height, data.len(), (self.width, self.height, self.bpp))?;
+        let row_len = (width * self.bpp) as usize;
         
-        for row in 0..height {
-            let dst_y = y + row;
-            if dst_y >= self.height {
-                break;
-            }
-            
-            let src_offset = (row * src_stride) as usize;
-            let dst_offset = (dst_y * self.stride + x * self.bpp) as usize;
-            
-            let copy_len = (width * self.bpp) as usize;
-            let copy_len = copy_len.min(self.data.len() - dst_offset);
-            let copy_len = copy_len.min(data.len() - src_offset);
-            
-            if copy_len > 0 {
-                self.data[dst_offset..dst_offset + copy_len]
-                    .copy_from_slice(&data[src_offset..src_offset + copy_len]);
-            }
+        for (row, line) in data.chunks_exact(row_len.max(1)).enumerate() {
+            let dst_offset = ((y + row as u32) * self.stride + x * self.bpp) as usize;
+            self.data[dst_offset..dst_offset + row_len].copy_from_slice(line);
         }
         
         // Mark region as dirty
         self.dirty_regions.push(DirtyRegion { x, y, width, height });
+        self.tile_hashes = None;
+        Ok(())
+    }
+
+    /// Write raw bytes at a byte offset into the buffer's memory
+    ///
+    /// Fails without touching the buffer if the bytes do not fit.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let end = offset.checked_add(data.len()).filter(|&end| end <= self.data.len()).ok_or_else(|| {
+            WinpipeError::Protocol(format!("{} bytes at offset {} past a {} byte buffer", data.len(), offset, self.data.len()))
+        })?;
+        self.data[offset..end].copy_from_slice(data);
+        self.tile_hashes = None;
+        Ok(())
+    }
+
+    /// Mark a region as changed, clipped to the buffer
+    ///
+    /// Once any region is marked, `calculate_delta` only compares pixels
+    /// inside the dirty regions.
+    pub fn mark_dirty(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let width = width.min(self.width.saturating_sub(x));
+        let height = height.min(self.height.saturating_sub(y));
+        if width > 0 && height > 0 {
+            self.dirty_regions.push(DirtyRegion { x, y, width, height });
+        }
     }
 
     /// Calculate delta from previous frame
+    ///
+    /// The buffer is compared in tiles of `tile_size` pixels. Each run of
+    /// changed tiles along a tile row becomes one region, shrunk to the
+    /// pixels that actually changed, so a blinking cursor costs a few
+    /// bytes rather than whole rows.
+    ///
+    /// Without dirty regions the whole buffer is compared. The dirty
+    /// regions are cleared afterwards.
+    ///
+    /// Overlapping and nearby rectangles, both the dirty regions going in
+    /// and the changed regions coming out, are merged when that costs at
+    /// most `merge_overhead` unchanged pixels, so dozens of tiny damage
+    /// rectangles become a few regions. Past `MAX_COALESCE_RECTS`
+    /// rectangles their bounding box is used instead.
+    ///
+    /// Buffers of `PARALLEL_DIFF_THRESHOLD` bytes or more are split into
+    /// bands of whole tile rows, diffed on scoped threads and merged in
+    /// order, so a 4K frame stays within the frame budget.
     pub fn calculate_delta(&mut self) -> Option<BufferDelta> {
         let prev = self.prev_data.as_ref()?;
-        
+
         if prev.len() != self.data.len() {
             return None;
         }
 
-        // Simple approach: find changed rows
-        // A more sophisticated approach would use block-based comparison
-        let mut regions = Vec::new();
-        let mut total_bytes = 0;
-        
-        let mut in_dirty_region = false;
-        let mut region_start_y = 0u32;
-        
-        for y in 0..self.height {
-            let row_start = (y * self.stride) as usize;
-            let row_end = row_start + (self.width * self.bpp) as usize;
-            
-            // Ensure we don't go out of bounds
-            let row_end = row_end.min(self.data.len()).min(prev.len());
-            
-            let row_changed = self.data[row_start..row_end] != prev[row_start..row_end];
-            
-            if row_changed && !in_dirty_region {
-                // Start new dirty region
-                in_dirty_region = true;
-                region_start_y = y;
-            } else if !row_changed && in_dirty_region {
-                // End dirty region
-                in_dirty_region = false;
-                let region_height = y - region_start_y;
-                
-                // Extract region data
-                let data = self.extract_region(0, region_start_y, self.width, region_height);
-                total_bytes += data.len();
-                
-                regions.push(DeltaRegion {
-                    x: 0,
-                    y: region_start_y,
-                    width: self.width,
-                    height: region_height,
-                    data,
-                });
-            }
-        }
-        
-        // Handle region at end of buffer
-        if in_dirty_region {
-            let region_height = self.height - region_start_y;
-            let data = self.extract_region(0, region_start_y, self.width, region_height);
-            total_bytes += data.len();
-            
-            regions.push(DeltaRegion {
-                x: 0,
-                y: region_start_y,
-                width: self.width,
-                height: region_height,
-                data,
-            });
+        let dirty = self.dirty_regions.iter()
+            .map(|r| Bounds { x0: r.x, y0: r.y, x1: r.x + r.width, y1: r.y + r.height })
+            .collect();
+        self.dirty_regions = coalesce(dirty, self.merge_overhead)
+            .into_iter()
+            .map(|b| DirtyRegion { x: b.x0, y: b.y0, width: b.x1 - b.x0, height: b.y1 - b.y0 })
+            .collect();
+
+        let tile = self.tile_size.max(1);
+        let tile_rows = self.height.div_ceil(tile);
+        let workers = if self.data.len() >= PARALLEL_DIFF_THRESHOLD {
+            std::thread::available_parallelism().map_or(1, |n| n.get() as u32).min(tile_rows)
+        } else {
+            1
+        };
+
+        // Tiles hashing the same as last time are skipped without reading
+        // the previous frame
+        let tile_count = (self.width.div_ceil(tile) * tile_rows) as usize;
+        let known = self.prev_tile_hashes.as_ref()
+            .filter(|(size, hashes)| *size == tile && hashes.len() == tile_count)
+            .map(|(_, hashes)| hashes.as_slice());
+
+        let this = &*self;
+        let bands: Vec<(Vec<Bounds>, Vec<u64>)> = if workers > 1 {
+            let rows_per_band = tile_rows.div_ceil(workers);
+            std::thread::scope(|scope| {
+                let bands: Vec<_> = (0..tile_rows)
+                    .step_by(rows_per_band as usize)
+                    .map(|first| {
+                        let rows = first..tile_rows.min(first + rows_per_band);
+                        scope.spawn(move || this.band_bounds(prev, known, rows))
+                    })
+                    .collect();
+                bands.into_iter()
+                    .map(|band| band.join().expect("delta worker panicked"))
+                    .collect()
+            })
+        } else {
+            vec![this.band_bounds(prev, known, 0..tile_rows)]
+        };
+        let (mut bounds, mut hashes) = (Vec::new(), Vec::with_capacity(tile_count));
+        for (band_bounds, band_hashes) in bands {
+            bounds.extend(band_bounds);
+            hashes.extend(band_hashes);
         }
+        let regions = self.extract(coalesce(bounds, self.merge_overhead));
 
+        self.tile_hashes = Some((tile, hashes));
+        self.dirty_regions.clear();
         if regions.is_empty() {
             return None; // No changes
         }
 
+        let total_bytes = regions.iter().map(|r| r.data.len()).sum();
+
         Some(BufferDelta {
             buffer_id: self.id,
             regions,
@@ -204,6 +602,104 @@ impl MirrorBuffer {
         })
     }
 
+    /// Bounds of the changes within a range of tile rows, and the hashes
+    /// of its tiles
+    fn band_bounds(&self, prev: &[u8], known: Option<&[u64]>, tile_rows: std::ops::Range<u32>) -> (Vec<Bounds>, Vec<u64>) {
+        let tile = self.tile_size.max(1);
+        let columns = self.width.div_ceil(tile) as usize;
+        let mut bounds = Vec::new();
+        let mut hashes = Vec::new();
+        for tile_row in tile_rows {
+            let tile_y = tile_row * tile;
+            let tile_height = tile.min(self.height - tile_y);
+            let mut run: Option<Bounds> = None;
+            for (column, tile_x) in (0..self.width).step_by(tile as usize).enumerate() {
+                let tile_width = tile.min(self.width - tile_x);
+                let known = known.map(|hashes| hashes[tile_row as usize * columns + column]);
+                let (hash, changed) = self.tile_diff(prev, known, tile_x, tile_y, tile_width, tile_height);
+                hashes.push(hash);
+                match changed {
+                    Some(changed) => run = Some(run.map_or(changed, |run| run.union(changed))),
+                    None => bounds.extend(run.take()),
+                }
+            }
+            bounds.extend(run);
+        }
+        (bounds, hashes)
+    }
+
+    /// Hash of a tile and the bounding box of its changes
+    ///
+    /// A tile whose hash matches its `known` hash from the previous frame
+    /// is unchanged; one outside the dirty regions keeps that hash.
+    fn tile_diff(&self, prev: &[u8], known: Option<u64>, x: u32, y: u32, width: u32, height: u32) -> (u64, Option<Bounds>) {
+        let tile = Bounds { x0: x, y0: y, x1: x + width, y1: y + height };
+        let dirty = self.dirty_regions.is_empty() || self.dirty_regions.iter()
+            .any(|r| tile.overlap(Bounds { x0: r.x, y0: r.y, x1: r.x + r.width, y1: r.y + r.height }) > 0);
+        if let Some(hash) = known.filter(|_| !dirty) {
+            return (hash, None);
+        }
+        let hash = hash_rect(&self.data, self.stride, self.bpp, tile);
+        if !dirty || known == Some(hash) {
+            return (hash, None);
+        }
+        (hash, self.tile_changes(prev, x, y, width, height))
+    }
+
+    /// Copy out the data of changed regions, XORed with the previous
+    /// contents with `xor_deltas`
+    fn extract(&self, bounds: Vec<Bounds>) -> Vec<DeltaRegion> {
+        let prev = self.prev_data.as_deref().filter(|_| self.xor_deltas);
+        bounds.into_iter()
+            .map(|b| {
+                let (width, height) = (b.x1 - b.x0, b.y1 - b.y0);
+                let mut data = self.extract_region(b.x0, b.y0, width, height);
+                if let Some(prev) = prev {
+                    let row_len = (width * self.bpp) as usize;
+                    for (row, line) in data.chunks_mut(row_len).enumerate() {
+                        let start = ((b.y0 + row as u32) * self.stride + b.x0 * self.bpp) as usize;
+                        line.iter_mut().zip(&prev[start..]).for_each(|(new, old)| *new ^= old);
+                    }
+                }
+                DeltaRegion { x: b.x0, y: b.y0, width, height, data, xor: prev.is_some() }
+            })
+            .collect()
+    }
+
+    /// Bounding box of the changes in a tile, looking only inside the
+    /// dirty regions if there are any
+    fn tile_changes(&self, prev: &[u8], x: u32, y: u32, width: u32, height: u32) -> Option<Bounds> {
+        if self.dirty_regions.is_empty() {
+            return self.changed_bounds(prev, x, y, width, height);
+        }
+        self.dirty_regions.iter()
+            .filter_map(|r| {
+                let (x0, y0) = (x.max(r.x), y.max(r.y));
+                let (x1, y1) = ((x + width).min(r.x + r.width), (y + height).min(r.y + r.height));
+                (x0 < x1 && y0 < y1).then(|| self.changed_bounds(prev, x0, y0, x1 - x0, y1 - y0)).flatten()
+            })
+            .reduce(Bounds::union)
+    }
+
+    /// Bounding box of the pixels in a tile that differ from `prev`
+    fn changed_bounds(&self, prev: &[u8], x: u32, y: u32, width: u32, height: u32) -> Option<Bounds> {
+        let bpp = self.bpp.max(1) as usize;
+        let mut bounds: Option<Bounds> = None;
+        for row in y..y + height {
+            let start = (row * self.stride + x * self.bpp) as usize;
+            let end = (start + width as usize * bpp).min(self.data.len());
+            if start >= end || self.data[start..end] == prev[start..end] {
+                continue;
+            }
+            let pixels = || self.data[start..end].chunks(bpp).zip(prev[start..end].chunks(bpp));
+            let first = pixels().position(|(a, b)| a != b).unwrap_or(0) as u32;
+            let last = pixels().rposition(|(a, b)| a != b).unwrap_or(0) as u32;
+            let changed = Bounds { x0: x + first, y0: row, x1: x + last + 1, y1: row + 1 };
+            bounds = Some(bounds.map_or(changed, |b| b.union(changed)));
+        }
+        bounds
+    }
+
     /// Extract a region of the buffer
     fn extract_region(&self, x: u32, y: u32, width: u32, height: u32) -> Vec<u8> {
         let mut data = Vec::with_capacity((width * height * self.bpp) as usize);
@@ -226,11 +722,54 @@ impl MirrorBuffer {
     }
 
     /// Apply a delta update
-    pub fn apply_delta(&mut self, delta: &BufferDelta) {
+    ///
+    /// Every region is validated first, so a corrupted delta is rejected
+    /// as a whole instead of being applied in part.
+    pub fn apply_delta(&mut self, delta: &BufferDelta) -> Result<()> {
+        for region in &delta.regions {
+            region.validate(self.width, self.height, self.bpp)?;
+        }
         for region in &delta.regions {
-            self.update_region(region.x, region.y, region.width, region.height, &region.data);
+            if region.xor {
+                self.xor_region(region);
+            } else {
+                self.update_region(region.x, region.y, region.width, region.height, &region.data)?;
+            }
+        }
+        self.dirty_regions.clear();
+        self.tile_hashes = None;
+        Ok(())
+    }
+
+    /// XOR a validated region's data into the buffer
+    fn xor_region(&mut self, region: &DeltaRegion) {
+        let row_len = (region.width * self.bpp) as usize;
+        for (row, line) in region.data.chunks_exact(row_len.max(1)).enumerate() {
+            let start = ((region.y + row as u32) * self.stride + region.x * self.bpp) as usize;
+            let target = &mut self.data[start..start + row_len];
+            target.iter_mut().zip(line).for_each(|(old, delta)| *old ^= delta);
         }
+    }
+
+    /// Changes since the previous contents in waypipe's interval format,
+    /// None if nothing changed
+    pub fn calculate_interval_diff(&mut self) -> Option<Vec<u8>> {
+        let prev = self.prev_data.as_ref().filter(|prev| prev.len() == self.data.len())?;
+        let diff = encode_interval_diff(prev, &self.data);
+        let trailing = self.data.len() - self.data.len() % 4;
+        let changed = diff.len() > self.data.len() % 4 || prev[trailing..] != self.data[trailing..];
+        self.dirty_regions.clear();
+        changed.then_some(diff)
+    }
+
+    /// Apply a diff in waypipe's interval format
+    ///
+    /// The diff is validated first and rejected as a whole if corrupted.
+    pub fn apply_interval_diff(&mut self, diff: &[u8], ntrailing: usize) -> Result<()> {
+        apply_interval_diff(&mut self.data, diff, ntrailing)?;
         self.dirty_regions.clear();
+        self.tile_hashes = None;
+        Ok(())
     }
 
     /// Clear dirty regions
@@ -239,18 +778,356 @@ impl MirrorBuffer {
     }
 }
 
+/// Pixel rectangle as exclusive corners
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+impl Bounds {
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
+
+    fn area(self) -> u64 {
+        (self.x1 - self.x0) as u64 * (self.y1 - self.y0) as u64
+    }
+
+    fn overlap(self, other: Bounds) -> u64 {
+        let (x0, y0) = (self.x0.max(other.x0), self.y0.max(other.y0));
+        let (x1, y1) = (self.x1.min(other.x1), self.y1.min(other.y1));
+        if x0 < x1 && y0 < y1 { Bounds { x0, y0, x1, y1 }.area() } else { 0 }
+    }
+}
+
+/// XXH64 of the pixel rows of a rectangle, rows starting `stride` bytes
+/// apart
+fn hash_rect(data: &[u8], stride: u32, bpp: u32, rect: Bounds) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    for y in rect.y0..rect.y1 {
+        let start = (y * stride + rect.x0 * bpp) as usize;
+        hasher.write(data.get(start..start + ((rect.x1 - rect.x0) * bpp) as usize).unwrap_or_default());
+    }
+    hasher.finish()
+}
+
+/// Rows of `row_len` bytes from data whose rows start `stride` bytes apart
+///
+/// Tightly packed data is borrowed as-is. Returns `None` if the stride is
+/// shorter than a row or the data ends early.
+pub fn pack_rows(data: &[u8], row_len: usize, stride: usize, height: usize) -> Option<Cow<'_, [u8]>> {
+    let needed = height.checked_sub(1).map_or(0, |rows| rows * stride + row_len);
+    if stride < row_len || data.len() < needed {
+        return None;
+    }
+    if stride == row_len {
+        return Some(Cow::Borrowed(&data[..needed]));
+    }
+    Some(Cow::Owned(data.chunks(stride).take(height).flat_map(|line| &line[..row_len]).copied().collect()))
+}
+
+/// PackBits run-length encoding
+///
+/// A header byte n < 128 is followed by n + 1 literal bytes; n > 128
+/// repeats the next byte 257 - n times.
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(128).take_while(|&&b| b == data[i]).count();
+        if run >= 2 {
+            out.extend_from_slice(&[(257 - run) as u8, data[i]]);
+            i += run;
+            continue;
+        }
+        // Literals up to the next run of two or more
+        let start = i;
+        while i < data.len() && i - start < 128 && !(i + 1 < data.len() && data[i] == data[i + 1]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+/// Decode PackBits data, refusing to unpack more than `max` bytes
+pub fn rle_decode(data: &[u8], max: u64) -> Result<Vec<u8>> {
+    let truncated = || WinpipeError::Compression("truncated run-length data".to_string());
+    let oversized = || WinpipeError::Compression(format!("run-length data unpacks to more than {} bytes", max));
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i] as usize;
+        i += 1;
+        match header {
+            0..=127 => {
+                let literals = data.get(i..i + header + 1).ok_or_else(truncated)?;
+                if (out.len() + literals.len()) as u64 > max {
+                    return Err(oversized());
+                }
+                out.extend_from_slice(literals);
+                i += header + 1;
+            }
+            128 => {}
+            _ => {
+                let &byte = data.get(i).ok_or_else(truncated)?;
+                if (out.len() + 257 - header) as u64 > max {
+                    return Err(oversized());
+                }
+                out.resize(out.len() + 257 - header, byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Decompress LZ4 data behind its little-endian u32 size, refusing sizes
+/// above `max` before anything is allocated
+fn lz4_decode(data: &[u8], max: u64) -> Result<Vec<u8>> {
+    let Some((size, block)) = data.split_first_chunk::<4>() else {
+        return Err(WinpipeError::Compression("truncated LZ4 size".to_string()));
+    };
+    let size = u32::from_le_bytes(*size);
+    if size as u64 > max {
+        return Err(WinpipeError::Compression(format!("{} byte LZ4 data, at most {} expected", size, max)));
+    }
+    lz4_flex::block::decompress(block, size as usize).map_err(|e| WinpipeError::Compression(e.to_string()))
+}
+
+/// Unchanged words between two changed ones that are sent rather than
+/// starting a new interval; an interval header costs two words
+const INTERVAL_MERGE_GAP: usize = 2;
+
+/// Diff two buffers of the same size in waypipe's interval format
+pub fn encode_interval_diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let words = new.len() / 4;
+    let changed = |i: usize| old[i * 4..i * 4 + 4] != new[i * 4..i * 4 + 4];
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < words {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        i += 1;
+        while i < words && i - end <= INTERVAL_MERGE_GAP {
+            if changed(i) {
+                end = i + 1;
+            }
+            i += 1;
+        }
+        out.extend_from_slice(&(start as u32).to_le_bytes());
+        out.extend_from_slice(&(end as u32).to_le_bytes());
+        out.extend_from_slice(&new[start * 4..end * 4]);
+        i = end;
+    }
+    out.extend_from_slice(&new[words * 4..]);
+    out
+}
+
+/// Apply a diff in waypipe's interval format, whose last `ntrailing`
+/// bytes are the end of the buffer
+///
+/// Nothing is written unless the whole diff is valid.
+pub fn apply_interval_diff(target: &mut [u8], diff: &[u8], ntrailing: usize) -> Result<()> {
+    let invalid = |msg: String| WinpipeError::Buffer(format!("invalid interval diff: {}", msg));
+    let words = target.len() / 4;
+    if ntrailing > diff.len() || ntrailing != target.len() % 4 {
+        return Err(invalid(format!("{} trailing bytes for a {} byte buffer", ntrailing, target.len())));
+    }
+    let body = &diff[..diff.len() - ntrailing];
+    if !body.len().is_multiple_of(4) {
+        return Err(invalid(format!("{} bytes of intervals", body.len())));
+    }
+
+    let word = |at: usize| u32::from_le_bytes(body[at..at + 4].try_into().unwrap()) as usize;
+    let mut intervals = Vec::new();
+    let mut at = 0;
+    while at < body.len() {
+        if at + 8 > body.len() {
+            return Err(invalid("truncated interval header".to_string()));
+        }
+        let (start, end) = (word(at), word(at + 4));
+        let data = at + 8..at + 8 + end.saturating_sub(start) * 4;
+        if start >= end || end > words || data.end > body.len() {
+            return Err(invalid(format!("interval [{}, {}) of a {} word buffer", start, end, words)));
+        }
+        intervals.push((start, data.clone()));
+        at = data.end;
+    }
+
+    for (start, data) in intervals {
+        target[start * 4..start * 4 + data.len()].copy_from_slice(&body[data]);
+    }
+    let len = target.len();
+    target[len - ntrailing..].copy_from_slice(&diff[diff.len() - ntrailing..]);
+    Ok(())
+}
+
+/// Merge rectangles whose bounding box covers at most `overhead` pixels
+/// neither of them does, until no such pair is left
+///
+/// More than `MAX_COALESCE_RECTS` rectangles become their bounding box.
+fn coalesce(mut rects: Vec<Bounds>, overhead: u32) -> Vec<Bounds> {
+    if rects.len() > MAX_COALESCE_RECTS {
+        return rects.into_iter().reduce(Bounds::union).into_iter().collect();
+    }
+    let mut merged = true;
+    while merged {
+        merged = false;
+        let mut i = 0;
+        while i < rects.len() {
+            let mut j = i + 1;
+            while j < rects.len() {
+                let (a, b) = (rects[i], rects[j]);
+                let covered = a.area() + b.area() - a.overlap(b);
+                if a.union(b).area() - covered <= overhead as u64 {
+                    rects[i] = a.union(b);
+                    rects.remove(j);
+                    merged = true;
+                } else {
+                    j += 1;
+                }
+            }
+            i += 1;
+        }
+    }
+    rects
+}
+
+/// How well delta encoding works for a buffer sent frame after frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    /// Frames sent, as deltas or in full
+    pub frames: u64,
+    /// Frames sent in full
+    pub full_frames: u64,
+    /// Size of all frames
+    pub frame_bytes: u64,
+    /// Bytes inside the changed regions
+    pub dirty_bytes: u64,
+    /// Pixel bytes sent, before compression
+    pub sent_bytes: u64,
+}
+
+impl DeltaStats {
+    /// Count a frame whose changed regions held `dirty_bytes`, sent in
+    /// full or as a delta of those regions
+    pub fn record(&mut self, frame_bytes: usize, dirty_bytes: usize, full: bool) {
+        self.frames += 1;
+        self.frame_bytes += frame_bytes as u64;
+        self.dirty_bytes += dirty_bytes as u64;
+        if full {
+            self.full_frames += 1;
+            self.sent_bytes += frame_bytes as u64;
+        } else {
+            self.sent_bytes += dirty_bytes as u64;
+        }
+    }
+
+    /// Average share of a frame that changed, in percent
+    pub fn average_dirty_percent(&self) -> f64 {
+        if self.frame_bytes == 0 {
+            return 0.0;
+        }
+        self.dirty_bytes as f64 * 100.0 / self.frame_bytes as f64
+    }
+
+    /// Bytes not sent thanks to deltas
+    pub fn bytes_saved(&self) -> u64 {
+        self.frame_bytes.saturating_sub(self.sent_bytes)
+    }
+}
+
+/// Human-readable statistics, one buffer per line
+pub fn format_stats(stats: &[(u32, DeltaStats)]) -> String {
+    let mut out = String::new();
+    for (id, stats) in stats {
+        let saved = if stats.frame_bytes == 0 { 0.0 } else { stats.bytes_saved() as f64 * 100.0 / stats.frame_bytes as f64 };
+        let _ = writeln!(
+            out,
+            "buffer@{}: {} frames ({} full), {:.1}% dirty on average, {} bytes saved ({:.1}%)",
+            id, stats.frames, stats.full_frames, stats.average_dirty_percent(), stats.bytes_saved(), saved
+        );
+    }
+    out
+}
+
 /// Buffer manager for all mirrored buffers
 pub struct BufferManager {
     buffers: HashMap<u32, MirrorBuffer>,
+    stats: HashMap<u32, DeltaStats>,
+    /// Mirrored wl_shm_pools
+    pools: HashMap<u32, ShmPool>,
+    /// Pool each buffer was carved out of
+    pool_buffers: HashMap<u32, u32>,
 }
 
 impl BufferManager {
     pub fn new() -> Self {
         Self {
             buffers: HashMap::new(),
+            stats: HashMap::new(),
+            pools: HashMap::new(),
+            pool_buffers: HashMap::new(),
         }
     }
 
+    /// Register a new shm pool of `size` bytes
+    pub fn create_pool(&mut self, id: u32, size: usize) {
+        self.pools.insert(id, ShmPool::new(size));
+    }
+
+    /// Size of a pool, if it exists
+    pub fn pool_size(&self, id: u32) -> Option<usize> {
+        self.pools.get(&id).map(ShmPool::size)
+    }
+
+    /// Grow a pool, moving its buffers along
+    pub fn resize_pool(&mut self, id: u32, size: usize) -> Result<()> {
+        let pool = self.pools.get_mut(&id)
+            .ok_or_else(|| WinpipeError::Buffer(format!("unknown shm pool {}", id)))?;
+        if !pool.grow(size) {
+            return Err(WinpipeError::Buffer(format!("shm pool {} cannot shrink from {} to {} bytes", id, pool.size(), size)));
+        }
+        for (buffer, _) in self.pool_buffers.iter().filter(|&(_, &pool)| pool == id) {
+            if let Some(PixelStorage::Pool(slice)) = self.buffers.get_mut(buffer).map(|b| &mut b.data) {
+                slice.rebase(pool);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a pool; its buffers keep the memory they use
+    pub fn destroy_pool(&mut self, id: u32) {
+        self.pools.remove(&id);
+    }
+
+    /// Register a buffer at `offset` into a pool, sharing its memory
+    pub fn create_in_pool(&mut self, id: u32, (pool, offset): (u32, usize), width: u32, height: u32, bpp: u32, stride: u32) -> Result<()> {
+        let shm = self.pools.get(&pool)
+            .ok_or_else(|| WinpipeError::Buffer(format!("unknown shm pool {}", pool)))?;
+        let len = stride as usize * height as usize;
+        let slice = shm.slice(offset, len).ok_or_else(|| {
+            WinpipeError::Buffer(format!("{} bytes at offset {} exceed the {} byte pool", len, offset, shm.size()))
+        })?;
+        self.buffers.insert(id, MirrorBuffer::with_storage(id, width, height, bpp, stride, PixelStorage::Pool(slice)));
+        self.pool_buffers.insert(id, pool);
+        Ok(())
+    }
+
     /// Register a new buffer
     pub fn create(&mut self, id: u32, width: u32, height: u32, bpp: u32, stride: u32) {
         let buffer = MirrorBuffer::new(id, width, height, bpp, stride);
@@ -269,6 +1146,7 @@ impl BufferManager {
 
     /// Remove a buffer
     pub fn remove(&mut self, id: u32) -> Option<MirrorBuffer> {
+        self.pool_buffers.remove(&id);
         self.buffers.remove(&id)
     }
 
@@ -277,9 +1155,41 @@ impl BufferManager {
         self.buffers.len()
     }
 
-    /// Total memory usage
+    /// Total memory usage, counting each pool once
     pub fn total_memory(&self) -> usize {
-        self.buffers.values().map(|b| b.size()).sum()
+        let mut blocks: HashMap<usize, usize> = self.pools.values().map(|p| (p.block_id(), p.size())).collect();
+        let mut total = 0;
+        for buffer in self.buffers.values() {
+            match &buffer.data {
+                PixelStorage::Pool(slice) => {
+                    blocks.insert(slice.block_id(), slice.block_size());
+                }
+                _ => total += buffer.size(),
+            }
+        }
+        total + blocks.values().sum::<usize>()
+    }
+
+    /// Count a frame sent for buffer `id` (see `DeltaStats::record`)
+    pub fn record_frame(&mut self, id: u32, frame_bytes: usize, dirty_bytes: usize, full: bool) {
+        self.stats.entry(id).or_default().record(frame_bytes, dirty_bytes, full);
+    }
+
+    /// Delta statistics of a buffer, if frames were sent for it
+    pub fn stats(&self, id: u32) -> Option<&DeltaStats> {
+        self.stats.get(&id)
+    }
+
+    /// Delta statistics of every buffer, by id
+    pub fn all_stats(&self) -> Vec<(u32, DeltaStats)> {
+        let mut stats: Vec<_> = self.stats.iter().map(|(&id, &stats)| (id, stats)).collect();
+        stats.sort_by_key(|&(id, _)| id);
+        stats
+    }
+
+    /// Forget the statistics of a buffer that went away
+    pub fn clear_stats(&mut self, id: u32) {
+        self.stats.remove(&id);
     }
 }
 
@@ -303,6 +1213,14 @@ mod tests {
         
         assert_eq!(buffer.data[0], 0xFF);
         assert!(buffer.prev_data.is_some());
+
+        // The two allocations trade places instead of being cloned
+        let current = buffer.data.as_ptr();
+        buffer.update(&[0x11; 8]);
+        let prev = buffer.prev_data.as_ref().unwrap();
+        assert_eq!(prev.as_ptr(), current);
+        assert!(prev.iter().all(|&b| b == 0xFF));
+        assert_eq!(buffer.data[..9], [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0xFF]);
     }
 
     #[test]
@@ -324,4 +1242,332 @@ mod tests {
         let delta = delta.unwrap();
         assert!(!delta.regions.is_empty());
     }
+
+    #[test]
+    fn test_delta_uses_tight_tiles() {
+        let mut buffer = MirrorBuffer::new(1, 256, 128, 4, 1024);
+        buffer.tile_size = 32;
+        buffer.update(&vec![0u8; buffer.size()]);
+
+        // A 2x3 caret and a pixel far to the right on the same tile row
+        let mut modified = vec![0u8; buffer.size()];
+        for row in 10..13 {
+            modified[row * 1024 + 40 * 4..row * 1024 + 42 * 4].fill(0xFF);
+        }
+        modified[20 * 1024 + 200 * 4] = 0xFF;
+        buffer.update(&modified);
+
+        let delta = buffer.calculate_delta().unwrap();
+        let rects: Vec<_> = delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect();
+        assert_eq!(rects, vec![(40, 10, 2, 3), (200, 20, 1, 1)]);
+        assert_eq!(delta.total_bytes, (6 + 1) * 4);
+
+        let mut mirror = MirrorBuffer::new(1, 256, 128, 4, 1024);
+        mirror.apply_delta(&delta).unwrap();
+        assert_eq!(*mirror.data, modified[..]);
+    }
+
+    #[test]
+    fn test_delta_limited_to_dirty_regions() {
+        let mut buffer = MirrorBuffer::new(1, 128, 64, 4, 512);
+        buffer.tile_size = 32;
+        buffer.update(&vec![0u8; buffer.size()]);
+
+        // A change outside the dirty region is not looked for
+        let mut modified = vec![0u8; buffer.size()];
+        modified[5 * 512 + 4 * 4] = 0xFF;
+        modified[40 * 512 + 100 * 4] = 0xFF;
+        buffer.update(&modified);
+        buffer.mark_dirty(96, 32, 500, 500);
+
+        let delta = buffer.calculate_delta().unwrap();
+        let rects: Vec<_> = delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect();
+        assert_eq!(rects, vec![(100, 40, 1, 1)]);
+
+        // Dirty regions only cover one calculation
+        assert_eq!(buffer.calculate_delta().unwrap().regions.len(), 2);
+    }
+
+    #[test]
+    fn test_overlapping_damage_is_merged() {
+        let mut buffer = MirrorBuffer::new(1, 64, 64, 4, 256);
+        buffer.update(&vec![0u8; buffer.size()]);
+        let mut modified = vec![0u8; buffer.size()];
+        for y in 0..64 {
+            modified[y * 256..y * 256 + 32 * 4].fill(0xFF);
+        }
+        buffer.update(&modified);
+
+        // A glyph-by-glyph pattern of overlapping and adjacent rects
+        for i in 0..16 {
+            buffer.mark_dirty(i * 2, 0, 3, 64);
+        }
+        buffer.mark_dirty(60, 60, 4, 4);
+        let delta = buffer.calculate_delta().unwrap();
+        let rects: Vec<_> = delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect();
+        assert_eq!(rects, vec![(0, 0, 32, 64)]);
+
+        // Without any allowance only exact fits merge
+        let bounds = |x0, y0, x1, y1| Bounds { x0, y0, x1, y1 };
+        let merged = coalesce(vec![bounds(0, 0, 4, 4), bounds(4, 0, 8, 4), bounds(0, 5, 1, 6)], 0);
+        assert_eq!(merged.len(), 2);
+        assert_eq!((merged[0].x0, merged[0].x1), (0, 8));
+        assert_eq!(coalesce(vec![bounds(0, 0, 4, 4), bounds(0, 5, 1, 6)], 12).len(), 1);
+    }
+
+    #[test]
+    fn test_many_rects_collapse_to_bounding_box() {
+        let bounds = |x0, y0, x1, y1| Bounds { x0, y0, x1, y1 };
+        let apart: Vec<_> = (0..MAX_COALESCE_RECTS as u32).map(|i| bounds(i * 10, 0, i * 10 + 1, 1)).collect();
+        assert_eq!(coalesce(apart.clone(), 0).len(), MAX_COALESCE_RECTS);
+
+        // One more and they are not compared pairwise at all
+        let mut many = apart;
+        many.push(bounds(5, 20, 6, 30));
+        let merged = coalesce(many, 0);
+        assert_eq!(merged.len(), 1);
+        assert_eq!((merged[0].x0, merged[0].y0, merged[0].x1, merged[0].y1), (0, 0, (MAX_COALESCE_RECTS as u32 - 1) * 10 + 1, 30));
+
+        // A storm of damage costs one bounding box rather than cubic time
+        let mut buffer = MirrorBuffer::new(1, 1024, 1024, 4, 4096);
+        buffer.update(&vec![0u8; buffer.size()]);
+        buffer.update(&vec![1u8; buffer.size()]);
+        for i in 0..100_000 {
+            buffer.mark_dirty(i % 1000, i / 100, 1, 1);
+        }
+        let delta = buffer.calculate_delta().unwrap();
+        let rects: Vec<_> = delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect();
+        assert_eq!(rects, vec![(0, 0, 1000, 1000)]);
+    }
+
+    #[test]
+    fn test_tile_hashes_skip_unchanged_tiles() {
+        let mut buffer = MirrorBuffer::new(1, 8, 4, 4, 32);
+        buffer.tile_size = 4;
+        let mut frame = vec![0x10u8; buffer.size()];
+        buffer.update(&frame);
+        buffer.calculate_delta().unwrap();
+
+        frame[0] = 0x20;
+        buffer.update(&frame);
+        // The right tile hashes as before, so its stale previous contents
+        // are never compared
+        buffer.prev_data.as_mut().unwrap()[31] = 0;
+        let delta = buffer.calculate_delta().unwrap();
+        assert_eq!(delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect::<Vec<_>>(), vec![(0, 0, 1, 1)]);
+
+        // Writing into the buffer drops the hashes
+        buffer.update_region(0, 0, 1, 1, &[0; 4]).unwrap();
+        buffer.update(&frame);
+        buffer.prev_data.as_mut().unwrap()[31] = 0;
+        let delta = buffer.calculate_delta().unwrap();
+        assert_eq!(delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect::<Vec<_>>(), vec![(0, 0, 8, 1)]);
+    }
+
+    #[test]
+    fn test_parallel_delta_matches_serial() {
+        // 1024x1024 RGBA is at the parallel threshold
+        let mut buffer = MirrorBuffer::new(1, 1024, 1024, 4, 4096);
+        assert!(buffer.size() >= PARALLEL_DIFF_THRESHOLD);
+        buffer.update(&vec![0u8; buffer.size()]);
+
+        let mut modified = vec![0u8; buffer.size()];
+        for (i, row) in [3usize, 200, 517, 1023].into_iter().enumerate() {
+            modified[row * 4096 + i * 300 * 4] = 0xFF;
+        }
+        buffer.update(&modified);
+
+        let delta = buffer.calculate_delta().unwrap();
+        let prev = buffer.prev_data.clone().unwrap();
+        let (serial, hashes) = buffer.band_bounds(&prev, None, 0..buffer.height.div_ceil(buffer.tile_size));
+        assert_eq!(delta.regions, buffer.extract(coalesce(serial, buffer.merge_overhead)));
+        assert_eq!(buffer.tile_hashes, Some((buffer.tile_size, hashes)));
+        let rects: Vec<_> = delta.regions.iter().map(|r| (r.x, r.y)).collect();
+        assert_eq!(rects, vec![(0, 3), (300, 200), (600, 517), (900, 1023)]);
+    }
+
+    #[test]
+    fn test_delta_wire_roundtrip() {
+        let delta = BufferDelta {
+            buffer_id: 7,
+            regions: vec![
+                DeltaRegion { x: 0, y: 2, width: 16, height: 2, data: vec![0xAB; 128], xor: false },
+                DeltaRegion { x: 4, y: 9, width: 1, height: 1, data: vec![1, 2, 3, 4], xor: false },
+            ],
+            total_bytes: 132,
+        };
+        let encoded = delta.encode(true);
+        // The repetitive region shrinks, the tiny one is sent as-is
+        assert!(encoded.len() < DELTA_HEADER_SIZE + 2 * REGION_HEADER_SIZE + 132);
+        assert_eq!(BufferDelta::decode(&encoded, 132).unwrap(), Some((delta.clone(), encoded.len())));
+        assert_eq!(BufferDelta::decode(&encoded[..encoded.len() - 1], 132).unwrap(), None);
+        // More data than the target holds is refused, compressed or not
+        assert!(BufferDelta::decode(&encoded, 131).is_err());
+        assert!(BufferDelta::decode(&delta.encode(false), 131).is_err());
+
+        let mut target = MirrorBuffer::new(7, 16, 10, 4, 64);
+        target.apply_delta(&BufferDelta::decode(&delta.encode(false), 16 * 10 * 4).unwrap().unwrap().0).unwrap();
+        assert_eq!(target.data[2 * 64], 0xAB);
+        assert_eq!(&target.data[9 * 64 + 16..9 * 64 + 20], &[1, 2, 3, 4]);
+
+        // A size prefix larger than the region could hold is refused
+        // before anything is allocated
+        let forge = |size: u32| {
+            let mut payload = compress_prepend_size(&[0xAB; 128]);
+            payload[..4].copy_from_slice(&size.to_le_bytes());
+            let mut forged = [7, 1, 0, 2, 16, 2, delta_flags::COMPRESSED, payload.len() as u32]
+                .iter()
+                .flat_map(|v: &u32| v.to_le_bytes())
+                .collect::<Vec<u8>>();
+            forged.extend_from_slice(&payload);
+            BufferDelta::decode(&forged, u64::MAX)
+        };
+        assert_eq!(forge(128).unwrap().unwrap().0.regions[0].data, vec![0xAB; 128]);
+        assert!(matches!(forge(u32::MAX), Err(WinpipeError::Compression(_))));
+        assert!(forge(16 * 2 * 8 + 1).is_err());
+    }
+
+    #[test]
+    fn test_checksums_detect_divergence() {
+        let data: Vec<u8> = (0..16 * 8 * 4).map(|i| i as u8).collect();
+        let buffer = MirrorBuffer::from_data(3, 16, 8, 4, 64, data.clone());
+        let checksum = buffer.checksum(4);
+        assert_eq!(checksum.tiles.len(), 8);
+        let encoded = checksum.encode();
+        assert_eq!(BufferChecksum::decode(&encoded, (16, 8)).unwrap(), Some((checksum.clone(), CHECKSUM_HEADER_SIZE + 64)));
+        assert_eq!(BufferChecksum::decode(&encoded[..30], (16, 8)).unwrap(), None);
+        // More tiles than the grid has are refused without waiting for them
+        assert!(BufferChecksum::decode(&encoded[..CHECKSUM_HEADER_SIZE], (16, 4)).is_err());
+
+        // Stride padding does not count
+        let mut padded = vec![0xEE; 80 * 8];
+        for (row, line) in data.chunks(64).enumerate() {
+            padded[row * 80..row * 80 + 64].copy_from_slice(line);
+        }
+        assert_eq!(MirrorBuffer::from_data(3, 16, 8, 4, 80, padded).checksum(4), checksum);
+
+        // A lost delta shows up in the right tile
+        let mut diverged = data.clone();
+        diverged[5 * 64 + 9 * 4] ^= 1;
+        let other = MirrorBuffer::from_data(3, 16, 8, 4, 64, diverged).checksum(4);
+        assert_ne!(other.hash, checksum.hash);
+        assert_eq!(checksum.mismatched_tiles(&other), vec![6]);
+    }
+
+    #[test]
+    fn test_hostile_deltas_rejected() {
+        let mut buffer = MirrorBuffer::new(1, 4, 4, 4, 16);
+        let region = |x, y, width, height, len| DeltaRegion { x, y, width, height, data: vec![0xFF; len], xor: false };
+        let hostile = [
+            region(3, 0, 2, 1, 8),
+            region(0, u32::MAX, 1, 2, 8),
+            region(u32::MAX, 0, 2, 1, 8),
+            region(0, 0, 2, 2, 15),
+            region(0, 0, 1, 1, 8),
+        ];
+        for bad in hostile {
+            // A valid region before the bad one is not applied either
+            let delta = BufferDelta { buffer_id: 1, regions: vec![region(0, 0, 1, 1, 4), bad], total_bytes: 0 };
+            assert!(matches!(buffer.apply_delta(&delta), Err(WinpipeError::Buffer(_))));
+        }
+        assert!(buffer.data.iter().all(|&b| b == 0));
+        assert!(buffer.update_region(0, 3, 4, 2, &[0; 32]).is_err());
+
+        buffer.update_region(2, 3, 2, 1, &[7; 8]).unwrap();
+        assert_eq!(&buffer.data[3 * 16 + 8..], &[7; 8]);
+    }
+
+    #[test]
+    fn test_pack_rows_drops_padding() {
+        let data = [1, 2, 0, 3, 4, 0, 5, 6];
+        assert_eq!(pack_rows(&data, 2, 3, 3).unwrap(), &[1, 2, 3, 4, 5, 6][..]);
+        assert!(matches!(pack_rows(&data, 2, 2, 4), Some(Cow::Borrowed(_))));
+        assert!(pack_rows(&data, 2, 3, 4).is_none());
+        assert!(pack_rows(&data, 4, 3, 1).is_none());
+
+        let buffer = MirrorBuffer::from_data(1, 1, 2, 4, 8, (0..16).collect());
+        assert_eq!(buffer.packed_data(), &[0, 1, 2, 3, 8, 9, 10, 11][..]);
+    }
+
+    #[test]
+    fn test_xor_deltas_compress_text_updates() {
+        let mut buffer = MirrorBuffer::new(1, 64, 16, 4, 256);
+        let text: Vec<u8> = (0..buffer.size()).map(|i| (i * 37 % 251) as u8).collect();
+        buffer.update(&text);
+        let mut edited = text.clone();
+        for offset in [5 * 256 + 40, 6 * 256 + 44, 9 * 256 + 200] {
+            edited[offset] ^= 0x10;
+        }
+        buffer.update(&edited);
+        buffer.merge_overhead = u32::MAX;
+
+        let copy = buffer.calculate_delta().unwrap();
+        buffer.xor_deltas = true;
+        buffer.update(&text);
+        buffer.update(&edited);
+        let xor = buffer.calculate_delta().unwrap();
+        assert!(xor.regions.iter().all(|r| r.xor));
+        assert!(xor.encode(true).len() * 4 < copy.encode(true).len());
+
+        let mut mirror = MirrorBuffer::from_data(1, 64, 16, 4, 256, text.clone());
+        mirror.apply_delta(&BufferDelta::decode(&xor.encode(true), 64 * 16 * 4).unwrap().unwrap().0).unwrap();
+        assert_eq!(*mirror.data, edited[..]);
+
+        let samples: [&[u8]; 4] = [&[], &[7], &[1, 2, 2, 2, 3, 4], &[0; 300]];
+        for sample in samples {
+            assert_eq!(rle_decode(&rle_encode(sample), sample.len() as u64).unwrap(), sample);
+        }
+        assert!(rle_decode(&[3, 1], 4).is_err());
+        // A run is refused before it grows the output past the limit
+        assert!(rle_decode(&rle_encode(&[0; 300]), 299).is_err());
+    }
+
+    #[test]
+    fn test_mapped_buffer_is_shared() {
+        let path = std::env::temp_dir().join(format!("winpipe-mirror-{}", std::process::id()));
+        let mut buffer = MirrorBuffer::from_data(1, 4, 2, 4, 16, vec![1; 32]);
+        assert!(buffer.mapped_path().is_none());
+        buffer.map_file(&path).unwrap();
+        assert_eq!(buffer.mapped_path(), Some(path.as_path()));
+        assert_eq!(*buffer.data, [1; 32]);
+
+        // The renderer's view follows updates without another copy
+        let renderer = SharedMapping::open(&path).unwrap();
+        buffer.update_region(0, 1, 4, 1, &[9; 16]).unwrap();
+        assert_eq!(&renderer[..], &[[1; 16], [9; 16]].concat()[..]);
+
+        drop((buffer, renderer));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interval_diff() {
+        let old: Vec<u8> = (0..42).collect();
+        let mut new = old.clone();
+        new[5] = 0xAA; // word 1
+        new[13] = 0xBB; // word 3, merged across the one-word gap
+        new[36] = 0xCC; // word 9
+        let diff = encode_interval_diff(&old, &new);
+        let mut expected = vec![1, 0, 0, 0, 4, 0, 0, 0];
+        expected.extend_from_slice(&new[4..16]);
+        expected.extend_from_slice(&[9, 0, 0, 0, 10, 0, 0, 0]);
+        expected.extend_from_slice(&new[36..40]);
+        expected.extend_from_slice(&new[40..]);
+        assert_eq!(diff, expected);
+
+        let mut mirror = MirrorBuffer::from_data(1, 21, 1, 2, 42, old.clone());
+        mirror.apply_interval_diff(&diff, 2).unwrap();
+        assert_eq!(*mirror.data, new[..]);
+
+        // Out-of-range intervals are rejected before anything is written
+        let mut target = old.clone();
+        let mut bad = diff.clone();
+        bad[20..24].copy_from_slice(&11u32.to_le_bytes());
+        assert!(apply_interval_diff(&mut target, &bad, 2).is_err());
+        assert!(apply_interval_diff(&mut target, &diff[..diff.len() - 3], 2).is_err());
+        assert_eq!(target, old);
+
+        mirror.update(&new);
+        assert_eq!(mirror.calculate_interval_diff(), None);
+    }
 }
@@ -0,0 +1,1154 @@
+//! Built-in Renderer
+//!
+//! `winpipe server --builtin-renderer` shows windows itself instead of
+//! forwarding them to win-way, so a single binary is enough. It speaks the
+//! same render protocol over an in-process pipe, so the runtime treats it
+//! like any other renderer, reconnection included.
+//!
+//! The `Scene` keeps what win-way would: the last frame and metadata of
+//! each window, patched by deltas and checked against checksums. Native
+//! windows live on a thread of their own running a winit event loop: it
+//! applies render messages to the scene, copies the damaged parts of
+//! frames to the windows with softbuffer, draws the stats overlay as a
+//! line of text in the top left corner and reports presents, resizes,
+//! focus, close requests, mouse and keyboard input back. Other platforms
+//! have no native windows, so the renderer fails to start there.
+//!
+//! With `--presenter d3d11`, frames go through the GPU instead: the damaged
+//! parts are uploaded to a Direct3D 11 texture per window, which is copied
+//! to a flip-model DXGI swapchain, stretched to the window's size and
+//! presented at the next vblank. Presents are then reported with the
+//! vblank time. Without a usable GPU, the renderer falls back to software.
+
+use std::collections::HashMap;
+
+use tokio::io::DuplexStream;
+
+use crate::convert;
+use crate::error::Result;
+use crate::positioner::Rect;
+use crate::render::{
+    capability_flags, PixelFormat, RenderFrame, RenderMessage, RendererCapabilities, RendererEvent, StatsOverlay, WindowInfo,
+};
+
+/// Refresh period reported with presents; software presents have no vblanks
+pub const REFRESH_NS: u32 = 16_666_666;
+
+/// What the built-in renderer shows; YUV frames are converted on arrival,
+/// deeper formats are converted by winpipe
+pub const CAPABILITIES: RendererCapabilities = RendererCapabilities {
+    formats: 1 << PixelFormat::ARGB8888 as u32
+        | 1 << PixelFormat::XRGB8888 as u32
+        | 1 << PixelFormat::NV12 as u32
+        | 1 << PixelFormat::I420 as u32,
+    max_width: 0,
+    max_height: 0,
+    flags: capability_flags::DAMAGE | capability_flags::UPDATE | capability_flags::LZ4,
+};
+
+/// Part of a window the stats overlay covers
+pub const OVERLAY_RECT: Rect = Rect { x: 0, y: 0, width: 400, height: 20 };
+
+/// Bytes the in-process pipe buffers in each direction
+pub const PIPE_BUFFER_SIZE: usize = 4 << 20;
+
+/// How the built-in renderer puts frames on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Presenter {
+    /// Copy to the window with softbuffer when it redraws
+    #[default]
+    Software,
+    /// Upload to Direct3D 11 textures and present through DXGI swapchains
+    D3d11,
+}
+
+impl Presenter {
+    /// What the renderer reports with this presenter
+    pub fn capabilities(self) -> RendererCapabilities {
+        match self {
+            Self::Software => CAPABILITIES,
+            Self::D3d11 => RendererCapabilities { flags: CAPABILITIES.flags | capability_flags::VSYNC, ..CAPABILITIES },
+        }
+    }
+}
+
+impl std::str::FromStr for Presenter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "software" => Ok(Self::Software),
+            "d3d11" => Ok(Self::D3d11),
+            other => Err(format!("unknown presenter '{}' (expected software or d3d11)", other)),
+        }
+    }
+}
+
+/// Start the built-in renderer; returns winpipe's end of its pipe
+pub fn spawn(presenter: Presenter) -> Result<DuplexStream> {
+    let (client, renderer) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    platform::start(renderer, presenter)?;
+    Ok(client)
+}
+
+/// A window as the renderer shows it
+#[derive(Debug, Default)]
+pub struct SceneWindow {
+    pub info: WindowInfo,
+    pub frame: Option<RenderFrame>,
+    /// Stats to draw over the frame, while the overlay is on
+    pub stats: Option<StatsOverlay>,
+    /// Sequence of the latest frame or delta
+    sequence: u64,
+    /// The latest frame has not been presented yet
+    pending: bool,
+    /// Parts changed since the last present, None for all of it
+    damage: Option<Vec<Rect>>,
+}
+
+impl SceneWindow {
+    /// Parts of the frame to repaint (or upload), None for all of it
+    pub fn damage(&self) -> Option<&[Rect]> {
+        self.damage.as_deref()
+    }
+}
+
+/// A client and the root wl_surface of the toplevel a window shows
+pub type WindowKey = (u32, u32);
+
+/// What the native windows need to do after a render message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Show the window's new frame, creating the window if needed
+    Show(WindowKey),
+    /// Update the title and other metadata
+    Retitle(WindowKey),
+    Destroy(WindowKey),
+    /// Destroy every window of a client
+    DestroyClient(u32),
+}
+
+/// The renderer's state of all windows
+#[derive(Debug, Default)]
+pub struct Scene {
+    windows: HashMap<WindowKey, SceneWindow>,
+    /// Window render messages apply to
+    target: WindowKey,
+    /// Window the last event was reported for
+    source: WindowKey,
+    /// Presents so far
+    vblanks: u64,
+    presenter: Presenter,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_presenter(presenter: Presenter) -> Self {
+        Self { presenter, ..Self::default() }
+    }
+
+    /// Switch presenters, such as when the GPU is lost; returns the
+    /// capabilities to report
+    pub fn set_presenter(&mut self, presenter: Presenter) -> RendererEvent {
+        self.presenter = presenter;
+        RendererEvent::Capabilities(presenter.capabilities())
+    }
+
+    pub fn window(&self, window: WindowKey) -> Option<&SceneWindow> {
+        self.windows.get(&window)
+    }
+
+    /// Apply a render message; returns the change to show and any events
+    /// for winpipe
+    pub fn apply(&mut self, message: RenderMessage) -> (Option<Change>, Vec<RendererEvent>) {
+        let target = self.target;
+        match message {
+            RenderMessage::Hello(_) => (None, vec![RendererEvent::Capabilities(self.presenter.capabilities())]),
+            RenderMessage::WindowTarget(selected) => {
+                let key = (selected.window, selected.surface);
+                if selected.destroy && selected.surface == 0 {
+                    self.windows.retain(|&(client, _), _| client != selected.window);
+                    return (Some(Change::DestroyClient(selected.window)), Vec::new());
+                }
+                if selected.destroy {
+                    return (self.windows.remove(&key).map(|_| Change::Destroy(key)), Vec::new());
+                }
+                self.target = key;
+                (None, Vec::new())
+            }
+            RenderMessage::Frame(mut frame) => {
+                // Software presents and the swapchains take BGRA only
+                if !matches!(frame.format, PixelFormat::ARGB8888 | PixelFormat::XRGB8888) {
+                    let Some(data) = convert::frame_to_argb8888(frame.format, frame.width, frame.height, &frame.data) else {
+                        return (None, Vec::new());
+                    };
+                    (frame.format, frame.data) = (PixelFormat::XRGB8888, data);
+                }
+                let window = self.windows.entry(target).or_default();
+                window.sequence = frame.sequence;
+                window.damage = match (window.damage.take(), &frame.damage) {
+                    (Some(mut damage), Some(rects)) => {
+                        damage.extend_from_slice(rects);
+                        Some(damage)
+                    }
+                    _ => None,
+                };
+                window.frame = Some(frame);
+                window.pending = true;
+                (Some(Change::Show(target)), Vec::new())
+            }
+            RenderMessage::FrameDelta(delta) => {
+                let window = self.windows.entry(target).or_default();
+                let applied = window.frame.as_mut().map(|frame| frame.apply_delta(&delta));
+                if !matches!(applied, Some(Ok(()))) {
+                    return (None, self.report(target, RendererEvent::Resync { buffer_id: delta.buffer_id }));
+                }
+                window.sequence += 1;
+                window.pending = true;
+                if let Some(damage) = &mut window.damage {
+                    damage.extend(delta.regions.iter().map(|r| Rect::new(r.x as i32, r.y as i32, r.width as i32, r.height as i32)));
+                }
+                (Some(Change::Show(target)), Vec::new())
+            }
+            RenderMessage::FrameUpdate(update) => {
+                let window = self.windows.entry(target).or_default();
+                let applied = window.frame.as_mut().map(|frame| frame.apply_update(&update));
+                if !matches!(applied, Some(Ok(()))) {
+                    return (None, self.report(target, RendererEvent::Resync { buffer_id: update.delta.buffer_id }));
+                }
+                window.sequence = update.sequence;
+                window.pending = true;
+                if let Some(damage) = &mut window.damage {
+                    let regions = &update.delta.regions;
+                    damage.extend(regions.iter().map(|r| Rect::new(r.x as i32, r.y as i32, r.width as i32, r.height as i32)));
+                }
+                (Some(Change::Show(target)), Vec::new())
+            }
+            RenderMessage::Checksum(checksum) => {
+                let matches = self.windows.get(&target)
+                    .and_then(|window| window.frame.as_ref())
+                    .is_some_and(|frame| frame.checksum(checksum.buffer_id, checksum.tile_size).hash == checksum.hash);
+                if matches {
+                    return (None, Vec::new());
+                }
+                (None, self.report(target, RendererEvent::Resync { buffer_id: checksum.buffer_id }))
+            }
+            RenderMessage::Window(info) => {
+                self.windows.entry(target).or_default().info = info;
+                (Some(Change::Retitle(target)), Vec::new())
+            }
+            RenderMessage::StatsOverlay(stats) => {
+                let Some(window) = self.windows.get_mut(&target) else {
+                    return (None, Vec::new());
+                };
+                window.stats = stats.visible.then_some(stats);
+                if let Some(damage) = &mut window.damage {
+                    damage.push(OVERLAY_RECT);
+                }
+                (window.frame.is_some().then_some(Change::Show(target)), Vec::new())
+            }
+            _ => (None, Vec::new()),
+        }
+    }
+
+    /// The window's latest frame reached the screen at `time_ns`
+    pub fn presented(&mut self, window: WindowKey, time_ns: u64) -> Vec<RendererEvent> {
+        let Some(shown) = self.windows.get_mut(&window).filter(|shown| shown.pending) else {
+            return Vec::new();
+        };
+        shown.pending = false;
+        shown.damage = Some(Vec::new());
+        let sequence = shown.sequence;
+        self.vblanks += 1;
+        let mut events = self.report(window, RendererEvent::Presented { time_ns, refresh_ns: REFRESH_NS, seq: self.vblanks, flags: 0 });
+        events.push(RendererEvent::FrameAck { sequence });
+        events
+    }
+
+    /// An event from a window, preceded by a window event if the last one
+    /// came from another window
+    pub fn report(&mut self, window: WindowKey, event: RendererEvent) -> Vec<RendererEvent> {
+        let mut events = Vec::with_capacity(2);
+        if window != self.source {
+            events.push(RendererEvent::Window { window: window.0, surface: window.1 });
+            self.source = window;
+        }
+        events.push(event);
+        events
+    }
+}
+
+/// Move render messages from the pipe to the window thread and events
+/// back; `wake` tells the window thread messages are waiting
+#[cfg(any(windows, test))]
+async fn bridge(
+    stream: DuplexStream,
+    messages: std::sync::mpsc::Sender<RenderMessage>,
+    wake: impl Fn(),
+    mut events: tokio::sync::mpsc::UnboundedReceiver<RendererEvent>,
+) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut decoder = crate::render::FrameDecoder::new();
+    let mut buf = vec![0u8; 64 << 10];
+    loop {
+        tokio::select! {
+            n = reader.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    if let Err(e) = decoder.finish() {
+                        log::warn!("Built-in renderer: {}", e);
+                    }
+                    return Ok(());
+                }
+                decoder.push(&buf[..n]);
+                let mut received = false;
+                while let Some(message) = decoder.decode_message() {
+                    if messages.send(message).is_err() {
+                        return Ok(());
+                    }
+                    received = true;
+                }
+                if received {
+                    wake();
+                }
+            }
+            Some(event) = events.recv() => writer.write_all(&event.encode()).await?,
+        }
+    }
+}
+
+/// Linux key code of a key by its PC scan code; extended keys (E0 prefix)
+/// differ from the keypad keys sharing their code
+pub fn linux_key(scan_code: u32, extended: bool) -> u32 {
+    if !extended {
+        // Set 1 scan codes are the Linux codes for the classic keys
+        return scan_code;
+    }
+    match scan_code {
+        0x1c => 96,  // KEY_KPENTER
+        0x1d => 97,  // KEY_RIGHTCTRL
+        0x35 => 98,  // KEY_KPSLASH
+        0x37 => 99,  // KEY_SYSRQ
+        0x38 => 100, // KEY_RIGHTALT
+        0x47 => 102, // KEY_HOME
+        0x48 => 103, // KEY_UP
+        0x49 => 104, // KEY_PAGEUP
+        0x4b => 105, // KEY_LEFT
+        0x4d => 106, // KEY_RIGHT
+        0x4f => 107, // KEY_END
+        0x50 => 108, // KEY_DOWN
+        0x51 => 109, // KEY_PAGEDOWN
+        0x52 => 110, // KEY_INSERT
+        0x53 => 111, // KEY_DELETE
+        0x5b => 125, // KEY_LEFTMETA
+        0x5c => 126, // KEY_RIGHTMETA
+        0x5d => 127, // KEY_COMPOSE
+        other => other,
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use tokio::io::DuplexStream;
+
+    use super::Presenter;
+    use crate::error::{Result, WinpipeError};
+
+    pub fn start(_stream: DuplexStream, _presenter: Presenter) -> Result<()> {
+        Err(WinpipeError::Protocol("the built-in renderer needs Windows".to_string()))
+    }
+}
+
+/// The GPU presenter: a Direct3D 11 device, and a swapchain and frame
+/// texture per window
+#[cfg(windows)]
+mod d3d11 {
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+
+    use windows::core::{Interface, Result};
+    use windows::Win32::Foundation::{HMODULE, HWND};
+    use windows::Win32::Graphics::Direct3D::D3D_DRIVER_TYPE_HARDWARE;
+    use windows::Win32::Graphics::Direct3D11::{
+        D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BOX, D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+        D3D11_SDK_VERSION, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+    };
+    use windows::Win32::Graphics::Dxgi::Common::{DXGI_ALPHA_MODE_IGNORE, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC};
+    use windows::Win32::Graphics::Dxgi::{
+        IDXGIDevice, IDXGIFactory2, IDXGISwapChain1, DXGI_FRAME_STATISTICS, DXGI_MWA_NO_ALT_ENTER, DXGI_PRESENT,
+        DXGI_SCALING_STRETCH, DXGI_SWAP_CHAIN_DESC1, DXGI_SWAP_CHAIN_FLAG, DXGI_SWAP_EFFECT_FLIP_DISCARD,
+        DXGI_USAGE_RENDER_TARGET_OUTPUT,
+    };
+    use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+    use crate::positioner::Rect;
+    use crate::render::RenderFrame;
+
+    /// BGRA pixels to draw over the top left corner of a frame
+    pub struct Overlay {
+        pub width: u32,
+        pub height: u32,
+        pub pixels: Vec<u8>,
+    }
+
+    /// A window's swapchain and the texture holding its frame
+    struct Surface {
+        swapchain: IDXGISwapChain1,
+        texture: ID3D11Texture2D,
+        size: (u32, u32),
+    }
+
+    pub struct Device {
+        device: ID3D11Device,
+        context: ID3D11DeviceContext,
+        factory: IDXGIFactory2,
+        surfaces: HashMap<usize, Surface>,
+        /// Performance counter when the device was made, and its ticks
+        /// per second, to turn vblank times into nanoseconds
+        origin: i64,
+        frequency: i64,
+    }
+
+    impl Device {
+        pub fn new() -> Result<Self> {
+            let (mut device, mut context) = (None, None);
+            let (mut origin, mut frequency) = (0, 0);
+            // SAFETY: the out pointers outlive the calls
+            unsafe {
+                D3D11CreateDevice(
+                    None,
+                    D3D_DRIVER_TYPE_HARDWARE,
+                    HMODULE::default(),
+                    D3D11_CREATE_DEVICE_BGRA_SUPPORT,
+                    None,
+                    D3D11_SDK_VERSION,
+                    Some(&mut device),
+                    None,
+                    Some(&mut context),
+                )?;
+                QueryPerformanceFrequency(&mut frequency)?;
+                QueryPerformanceCounter(&mut origin)?;
+            }
+            let device: ID3D11Device = device.expect("D3D11CreateDevice returns a device");
+            let context = context.expect("D3D11CreateDevice returns a context");
+            // Swapchains come from the factory of the device's adapter
+            // SAFETY: plain COM calls on a live device
+            let factory = unsafe { device.cast::<IDXGIDevice>()?.GetAdapter()?.GetParent::<IDXGIFactory2>()? };
+            Ok(Self { device, context, factory, surfaces: HashMap::new(), origin, frequency })
+        }
+
+        /// Upload the damaged parts of `frame` to the window's texture
+        /// and present it stretched to the window at the next vblank;
+        /// returns the time of the last vblank in nanoseconds since the
+        /// device was made, if DXGI knows it
+        pub fn present(&mut self, hwnd: *mut c_void, frame: &RenderFrame, damage: Option<&[Rect]>, overlay: Option<&Overlay>) -> Result<Option<u64>> {
+            let size = (frame.width, frame.height);
+            let stride = frame.width * 4;
+            if size.0 == 0 || size.1 == 0 || frame.data.len() < (stride * frame.height) as usize {
+                return Ok(None);
+            }
+            let mut full = false;
+            if !self.surfaces.contains_key(&(hwnd as usize)) {
+                let surface = self.create_surface(HWND(hwnd), size)?;
+                self.surfaces.insert(hwnd as usize, surface);
+                full = true;
+            }
+            let surface = self.surfaces.get_mut(&(hwnd as usize)).expect("created above");
+            if surface.size != size {
+                // SAFETY: no back buffer is held while resizing
+                unsafe { surface.swapchain.ResizeBuffers(0, size.0, size.1, DXGI_FORMAT_UNKNOWN, DXGI_SWAP_CHAIN_FLAG(0))? };
+                surface.texture = create_texture(&self.device, size)?;
+                surface.size = size;
+                full = true;
+            }
+
+            let whole = [Rect::new(0, 0, frame.width as i32, frame.height as i32)];
+            let rects = if full { &whole[..] } else { damage.unwrap_or(&whole) };
+            for rect in rects {
+                let (left, right) = (rect.x.clamp(0, size.0 as i32) as u32, (rect.x + rect.width).clamp(0, size.0 as i32) as u32);
+                let (top, bottom) = (rect.y.clamp(0, size.1 as i32) as u32, (rect.y + rect.height).clamp(0, size.1 as i32) as u32);
+                if left >= right || top >= bottom {
+                    continue;
+                }
+                let bounds = D3D11_BOX { left, top, front: 0, right, bottom, back: 1 };
+                let pixels = &frame.data[(top * stride + left * 4) as usize..];
+                // SAFETY: the box lies within the texture and the frame
+                // holds every row it covers
+                unsafe { self.context.UpdateSubresource(&surface.texture, 0, Some(&bounds), pixels.as_ptr().cast(), stride, 0) };
+            }
+
+            // SAFETY: plain COM calls; the overlay holds width * height
+            // pixels and fits the frame
+            unsafe {
+                let back: ID3D11Texture2D = surface.swapchain.GetBuffer(0)?;
+                self.context.CopyResource(&back, &surface.texture);
+                if let Some(overlay) = overlay {
+                    let bounds = D3D11_BOX { left: 0, top: 0, front: 0, right: overlay.width, bottom: overlay.height, back: 1 };
+                    self.context.UpdateSubresource(&back, 0, Some(&bounds), overlay.pixels.as_ptr().cast(), overlay.width * 4, 0);
+                }
+                drop(back);
+                surface.swapchain.Present(1, DXGI_PRESENT(0)).ok()?;
+            }
+
+            let mut stats = DXGI_FRAME_STATISTICS::default();
+            // SAFETY: the statistics outlive the call; they are not
+            // available for a while after the window changed
+            if unsafe { surface.swapchain.GetFrameStatistics(&mut stats) }.is_err() || stats.SyncQPCTime < self.origin {
+                return Ok(None);
+            }
+            let ticks = (stats.SyncQPCTime - self.origin) as u128;
+            Ok(Some((ticks * 1_000_000_000 / self.frequency.max(1) as u128) as u64))
+        }
+
+        /// Drop the window's swapchain, before the window goes
+        pub fn remove(&mut self, hwnd: *mut c_void) {
+            self.surfaces.remove(&(hwnd as usize));
+        }
+
+        fn create_surface(&self, hwnd: HWND, size: (u32, u32)) -> Result<Surface> {
+            let desc = DXGI_SWAP_CHAIN_DESC1 {
+                Width: size.0,
+                Height: size.1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC { Count: 1, Quality: 0 },
+                BufferUsage: DXGI_USAGE_RENDER_TARGET_OUTPUT,
+                BufferCount: 2,
+                // The frame keeps its size; DXGI scales it to the window
+                Scaling: DXGI_SCALING_STRETCH,
+                SwapEffect: DXGI_SWAP_EFFECT_FLIP_DISCARD,
+                AlphaMode: DXGI_ALPHA_MODE_IGNORE,
+                ..Default::default()
+            };
+            // SAFETY: a window of this thread and a valid description
+            let swapchain = unsafe {
+                let swapchain = self.factory.CreateSwapChainForHwnd(&self.device, hwnd, &desc, None, None)?;
+                // Alt+Enter is the client's to handle
+                self.factory.MakeWindowAssociation(hwnd, DXGI_MWA_NO_ALT_ENTER)?;
+                swapchain
+            };
+            Ok(Surface { swapchain, texture: create_texture(&self.device, size)?, size })
+        }
+    }
+
+    /// Texture the frame is uploaded to and presented from
+    fn create_texture(device: &ID3D11Device, size: (u32, u32)) -> Result<ID3D11Texture2D> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: size.0,
+            Height: size.1,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+            SampleDesc: DXGI_SAMPLE_DESC { Count: 1, Quality: 0 },
+            Usage: D3D11_USAGE_DEFAULT,
+            ..Default::default()
+        };
+        let mut texture = None;
+        // SAFETY: a valid description and out pointer
+        unsafe { device.CreateTexture2D(&desc, None, Some(&mut texture))? };
+        Ok(texture.expect("CreateTexture2D returns a texture"))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+    use std::num::NonZeroU32;
+    use std::ptr;
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, TryRecvError};
+    use std::time::Instant;
+
+    use log::warn;
+    use softbuffer::{Context, Surface};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc as event_mpsc;
+    use windows_sys::Win32::Graphics::Gdi::{
+        CreateCompatibleDC, CreateDIBSection, DeleteDC, DeleteObject, GdiFlush, SelectObject, TextOutW, BITMAPINFO,
+        BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS,
+    };
+    use winit::application::ApplicationHandler;
+    use winit::dpi::PhysicalSize;
+    use winit::event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+    use winit::event_loop::{ActiveEventLoop, EventLoop, OwnedDisplayHandle};
+    use winit::platform::scancode::PhysicalKeyExtScancode;
+    use winit::platform::windows::EventLoopBuilderExtWindows;
+    use winit::raw_window_handle::{HasWindowHandle, RawWindowHandle};
+    use winit::window::{Window, WindowId};
+
+    use super::d3d11::{self, Overlay};
+    use super::{bridge, linux_key, Change, Presenter, Scene, WindowKey, OVERLAY_RECT};
+    use crate::error::{Result, WinpipeError};
+    use crate::positioner::Rect;
+    use crate::render::{RenderFrame, RenderMessage, RendererEvent, StatsOverlay};
+
+    /// A native window and what draws into it
+    struct NativeWindow {
+        window: Rc<Window>,
+        /// Software presenter surface, made on the first present
+        surface: Option<Surface<OwnedDisplayHandle, Rc<Window>>>,
+    }
+
+    impl NativeWindow {
+        /// The window's HWND, for the swapchain
+        fn hwnd(&self) -> Option<*mut c_void> {
+            match self.window.window_handle().ok()?.as_raw() {
+                RawWindowHandle::Win32(handle) => Some(handle.hwnd.get() as *mut c_void),
+                _ => None,
+            }
+        }
+    }
+
+    struct Ui {
+        scene: Scene,
+        messages: mpsc::Receiver<RenderMessage>,
+        events: event_mpsc::UnboundedSender<RendererEvent>,
+        context: Context<OwnedDisplayHandle>,
+        windows: HashMap<WindowKey, NativeWindow>,
+        ids: HashMap<WindowId, WindowKey>,
+        start: Instant,
+        /// The GPU presenter, None to draw with softbuffer
+        gpu: Option<d3d11::Device>,
+    }
+
+    impl Ui {
+        fn send(&self, events: Vec<RendererEvent>) {
+            for event in events {
+                let _ = self.events.send(event);
+            }
+        }
+
+        /// Milliseconds since the renderer started, the time of input events
+        fn time(&self) -> u32 {
+            self.start.elapsed().as_millis() as u32
+        }
+
+        /// Report a window event to winpipe
+        fn report(&mut self, id: WindowId, event: RendererEvent) {
+            if let Some(&window) = self.ids.get(&id) {
+                let events = self.scene.report(window, event);
+                self.send(events);
+            }
+        }
+
+        /// Drop a window, its swapchain first
+        fn close(&mut self, window: WindowKey) {
+            let Some(native) = self.windows.remove(&window) else {
+                return;
+            };
+            self.ids.remove(&native.window.id());
+            if let (Some(gpu), Some(hwnd)) = (&mut self.gpu, native.hwnd()) {
+                gpu.remove(hwnd);
+            }
+        }
+    }
+
+    pub fn start(stream: DuplexStream, presenter: Presenter) -> Result<()> {
+        let (messages_tx, messages) = mpsc::channel();
+        let (events_tx, events) = event_mpsc::unbounded_channel();
+        let (ready_tx, ready) = mpsc::channel();
+        std::thread::Builder::new().name("builtin-renderer".to_string()).spawn(move || {
+            // The event loop runs on this thread rather than the main one
+            let event_loop = match EventLoop::with_user_event().with_any_thread(true).build() {
+                Ok(event_loop) => event_loop,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e.to_string()));
+                    return;
+                }
+            };
+            let context = match Context::new(event_loop.owned_display_handle()) {
+                Ok(context) => context,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e.to_string()));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(event_loop.create_proxy()));
+            run(event_loop, context, messages, events_tx, presenter);
+        })?;
+        let proxy = ready.recv()
+            .map_err(|_| WinpipeError::Protocol("renderer thread failed to start".to_string()))?
+            .map_err(|e| WinpipeError::Protocol(format!("renderer cannot open windows: {}", e)))?;
+
+        let wake = move || {
+            let _ = proxy.send_event(());
+        };
+        tokio::spawn(async move {
+            if let Err(e) = bridge(stream, messages_tx, wake, events).await {
+                warn!("Built-in renderer pipe failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    fn run(
+        event_loop: EventLoop<()>,
+        context: Context<OwnedDisplayHandle>,
+        messages: mpsc::Receiver<RenderMessage>,
+        events: event_mpsc::UnboundedSender<RendererEvent>,
+        presenter: Presenter,
+    ) {
+        let gpu = match presenter {
+            Presenter::Software => None,
+            Presenter::D3d11 => d3d11::Device::new()
+                .inspect_err(|e| warn!("Built-in renderer cannot use Direct3D 11, drawing in software: {}", e))
+                .ok(),
+        };
+        let scene = Scene::with_presenter(if gpu.is_some() { Presenter::D3d11 } else { Presenter::Software });
+        let start = Instant::now();
+        let mut ui = Ui { scene, messages, events, context, windows: HashMap::new(), ids: HashMap::new(), start, gpu };
+        if let Err(e) = event_loop.run_app(&mut ui) {
+            warn!("Built-in renderer event loop failed: {}", e);
+        }
+        // Swapchains go before their windows
+        ui.gpu = None;
+    }
+
+    impl ApplicationHandler for Ui {
+        fn resumed(&mut self, _event_loop: &ActiveEventLoop) {}
+
+        /// Render messages are waiting
+        fn user_event(&mut self, event_loop: &ActiveEventLoop, _event: ()) {
+            loop {
+                let message = match self.messages.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => return,
+                    Err(TryRecvError::Disconnected) => {
+                        event_loop.exit();
+                        return;
+                    }
+                };
+                let (change, events) = self.scene.apply(message);
+                self.send(events);
+                match change {
+                    Some(Change::Show(window)) => self.show(event_loop, window),
+                    Some(Change::Retitle(window)) => {
+                        if let Some(native) = self.windows.get(&window) {
+                            native.window.set_title(&title(&self.scene, window));
+                        }
+                    }
+                    Some(Change::Destroy(window)) => self.close(window),
+                    Some(Change::DestroyClient(client)) => {
+                        let windows: Vec<WindowKey> = self.windows.keys().copied().filter(|&(owner, _)| owner == client).collect();
+                        windows.into_iter().for_each(|window| self.close(window));
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        fn window_event(&mut self, _event_loop: &ActiveEventLoop, id: WindowId, event: WindowEvent) {
+            match event {
+                WindowEvent::RedrawRequested => {
+                    if let Some(&window) = self.ids.get(&id) {
+                        self.present(window);
+                    }
+                }
+                // Closing is up to the client
+                WindowEvent::CloseRequested => self.report(id, RendererEvent::Close),
+                // Minimized windows report no size
+                WindowEvent::Resized(size) if size.width > 0 && size.height > 0 => {
+                    self.report(id, RendererEvent::Resize { width: size.width as i32, height: size.height as i32 });
+                }
+                WindowEvent::Focused(focused) => self.report(id, RendererEvent::Focus { focused }),
+                WindowEvent::CursorMoved { position, .. } => {
+                    let time = self.time();
+                    self.report(id, RendererEvent::PointerMotion { time, x: position.x, y: position.y });
+                }
+                WindowEvent::CursorLeft { .. } => self.report(id, RendererEvent::PointerLeave),
+                WindowEvent::MouseInput { state, button, .. } => {
+                    let button = match button {
+                        MouseButton::Left => BTN_LEFT,
+                        MouseButton::Right => BTN_RIGHT,
+                        MouseButton::Middle => BTN_MIDDLE,
+                        _ => return,
+                    };
+                    let time = self.time();
+                    self.report(id, RendererEvent::PointerButton { time, button, pressed: state == ElementState::Pressed });
+                }
+                WindowEvent::MouseWheel { delta, .. } => {
+                    // winit scrolls up and left for positive deltas,
+                    // Wayland down and right
+                    let (x, y) = match delta {
+                        MouseScrollDelta::LineDelta(x, y) => (x as f64 * 120.0, y as f64 * 120.0),
+                        MouseScrollDelta::PixelDelta(position) => (position.x / WHEEL_STEP * 120.0, position.y / WHEEL_STEP * 120.0),
+                    };
+                    let time = self.time();
+                    for (axis, notches) in [(0, y), (1, x)] {
+                        if notches != 0.0 {
+                            let value120 = -notches.round() as i32;
+                            let value = -notches * WHEEL_STEP / 120.0;
+                            self.report(id, RendererEvent::PointerAxis { time, axis, value, value120 });
+                        }
+                    }
+                }
+                // Clients repeat keys themselves
+                WindowEvent::KeyboardInput { event, .. } if !event.repeat => {
+                    let Some(scan_code) = event.physical_key.to_scancode() else {
+                        return;
+                    };
+                    let key = linux_key(scan_code & 0xff, scan_code & 0xff00 == 0xe000);
+                    let time = self.time();
+                    self.report(id, RendererEvent::Key { time, key, pressed: event.state == ElementState::Pressed });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    impl Ui {
+        /// Redraw a window's damaged parts, creating it sized to its first
+        /// frame
+        fn show(&mut self, event_loop: &ActiveEventLoop, window: WindowKey) {
+            if let Some(native) = self.windows.get(&window) {
+                native.window.request_redraw();
+                return;
+            }
+            let Some(size) = self.scene.window(window).and_then(|shown| shown.frame.as_ref()).map(|frame| (frame.width, frame.height)) else {
+                return;
+            };
+            let attributes = Window::default_attributes()
+                .with_title(title(&self.scene, window))
+                .with_inner_size(PhysicalSize::new(size.0, size.1));
+            match event_loop.create_window(attributes) {
+                Ok(created) => {
+                    self.ids.insert(created.id(), window);
+                    self.windows.insert(window, NativeWindow { window: Rc::new(created), surface: None });
+                }
+                Err(e) => warn!("Built-in renderer cannot create a window: {}", e),
+            }
+        }
+
+        /// Put the window's frame on the screen and report it presented
+        fn present(&mut self, window: WindowKey) {
+            if self.gpu.is_some() && self.present_d3d11(window) {
+                return;
+            }
+            let Some(shown) = self.scene.window(window) else {
+                return;
+            };
+            let Some(frame) = &shown.frame else {
+                return;
+            };
+            let overlay = shown.stats.as_ref().and_then(|stats| render_overlay(frame, stats));
+            let Some(native) = self.windows.get_mut(&window) else {
+                return;
+            };
+            let surface = match &mut native.surface {
+                Some(surface) => surface,
+                None => match Surface::new(&self.context, Rc::clone(&native.window)) {
+                    Ok(surface) => native.surface.insert(surface),
+                    Err(e) => {
+                        warn!("Built-in renderer cannot draw a window: {}", e);
+                        return;
+                    }
+                },
+            };
+            // A present without damage is the system asking for a repaint
+            let damage = shown.damage().filter(|rects| !rects.is_empty());
+            if let Err(e) = draw(surface, frame, damage, overlay.as_ref()) {
+                warn!("Built-in renderer cannot draw a window: {}", e);
+                return;
+            }
+            let time_ns = self.start.elapsed().as_nanos() as u64;
+            let events = self.scene.presented(window, time_ns);
+            self.send(events);
+        }
+
+        /// Present the window's frame through its swapchain and report it
+        /// presented; false if Direct3D failed, after switching to software
+        fn present_d3d11(&mut self, window: WindowKey) -> bool {
+            let (Some(shown), Some(hwnd)) = (self.scene.window(window), self.windows.get(&window).and_then(NativeWindow::hwnd)) else {
+                return true;
+            };
+            let Some(frame) = &shown.frame else {
+                return true;
+            };
+            let overlay = shown.stats.as_ref().and_then(|stats| render_overlay(frame, stats));
+            let Some(gpu) = &mut self.gpu else {
+                return false;
+            };
+            match gpu.present(hwnd, frame, shown.damage(), overlay.as_ref()) {
+                Ok(vblank) => {
+                    let time_ns = vblank.unwrap_or_else(|| self.start.elapsed().as_nanos() as u64);
+                    let events = self.scene.presented(window, time_ns);
+                    self.send(events);
+                    true
+                }
+                Err(e) => {
+                    warn!("Built-in renderer lost Direct3D 11, drawing in software: {}", e);
+                    self.gpu = None;
+                    let capabilities = self.scene.set_presenter(Presenter::Software);
+                    self.send(vec![capabilities]);
+                    // Every window is drawn in software from now on
+                    for native in self.windows.values() {
+                        native.window.request_redraw();
+                    }
+                    false
+                }
+            }
+        }
+    }
+
+    /// Copy the damaged parts of a frame, and the overlay over them, to a
+    /// window's surface and present them; all of it with `damage` None
+    fn draw(
+        surface: &mut Surface<OwnedDisplayHandle, Rc<Window>>,
+        frame: &RenderFrame,
+        damage: Option<&[Rect]>,
+        overlay: Option<&Overlay>,
+    ) -> std::result::Result<(), softbuffer::SoftBufferError> {
+        let (Some(width), Some(height)) = (NonZeroU32::new(frame.width), NonZeroU32::new(frame.height)) else {
+            return Ok(());
+        };
+        surface.resize(width, height)?;
+        let mut buffer = surface.buffer_mut()?;
+        // A new buffer starts out blank
+        let whole = [Rect::new(0, 0, frame.width as i32, frame.height as i32)];
+        let mut rects: Vec<Rect> = if buffer.age() == 0 { whole.to_vec() } else { damage.unwrap_or(&whole).to_vec() };
+        if let Some(overlay) = overlay {
+            rects.push(Rect::new(0, 0, overlay.width as i32, overlay.height as i32));
+        }
+
+        // Frames are BGRA bytes, which softbuffer takes as 0RGB words
+        let row = frame.width as usize;
+        let mut damaged = Vec::with_capacity(rects.len());
+        for rect in &rects {
+            let clamp = |value: i32, max: u32| value.clamp(0, max as i32) as usize;
+            let (left, right) = (clamp(rect.x, frame.width), clamp(rect.x.saturating_add(rect.width), frame.width));
+            let (top, bottom) = (clamp(rect.y, frame.height), clamp(rect.y.saturating_add(rect.height), frame.height));
+            if left >= right || top >= bottom {
+                continue;
+            }
+            for y in top..bottom {
+                let pixels = &frame.data[(y * row + left) * 4..(y * row + right) * 4];
+                for (target, px) in buffer[y * row + left..y * row + right].iter_mut().zip(pixels.chunks_exact(4)) {
+                    *target = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+                }
+            }
+            damaged.push(softbuffer::Rect {
+                x: left as u32,
+                y: top as u32,
+                width: NonZeroU32::new((right - left) as u32).expect("checked above"),
+                height: NonZeroU32::new((bottom - top) as u32).expect("checked above"),
+            });
+        }
+        if let Some(overlay) = overlay {
+            let width = overlay.width as usize;
+            for (y, line) in overlay.pixels.chunks_exact(width * 4).enumerate() {
+                for (target, px) in buffer[y * row..y * row + width].iter_mut().zip(line.chunks_exact(4)) {
+                    *target = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+                }
+            }
+        }
+        buffer.present_with_damage(&damaged)
+    }
+
+    fn title(scene: &Scene, window: WindowKey) -> String {
+        scene.window(window).map_or(String::new(), |shown| shown.info.title.clone())
+    }
+
+    /// The stats line drawn with GDI over the part of the frame it covers
+    fn render_overlay(frame: &RenderFrame, stats: &StatsOverlay) -> Option<Overlay> {
+        let (width, height) = ((OVERLAY_RECT.width as u32).min(frame.width), (OVERLAY_RECT.height as u32).min(frame.height));
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: width as i32,
+                biHeight: -(height as i32),
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB,
+                ..unsafe { std::mem::zeroed() }
+            },
+            // SAFETY: a zeroed palette entry is valid
+            bmiColors: unsafe { std::mem::zeroed() },
+        };
+        let text: Vec<u16> = stats.summary().encode_utf16().collect();
+        let row = width as usize * 4;
+        let mut pixels = vec![0u8; row * height as usize];
+        // SAFETY: GDI objects are created, used and deleted here; the DIB
+        // holds width * height pixels, and the frame at least as many
+        // rows of at least as many pixels
+        unsafe {
+            let dc = CreateCompatibleDC(ptr::null_mut());
+            let mut bits = ptr::null_mut();
+            let bitmap = CreateDIBSection(dc, &info, DIB_RGB_COLORS, &mut bits, ptr::null_mut(), 0);
+            if bitmap.is_null() || bits.is_null() {
+                DeleteDC(dc);
+                return None;
+            }
+            let dib = std::slice::from_raw_parts_mut(bits.cast::<u8>(), pixels.len());
+            for (y, line) in dib.chunks_exact_mut(row).enumerate() {
+                let start = y * frame.width as usize * 4;
+                line.copy_from_slice(&frame.data[start..start + row]);
+            }
+            let previous = SelectObject(dc, bitmap);
+            TextOutW(dc, 4, 2, text.as_ptr(), text.len() as i32);
+            GdiFlush();
+            pixels.copy_from_slice(dib);
+            SelectObject(dc, previous);
+            DeleteObject(bitmap);
+            DeleteDC(dc);
+        }
+        Some(Overlay { width, height, pixels })
+    }
+
+    /// Linux codes of the mouse buttons
+    const BTN_LEFT: u32 = 0x110;
+    const BTN_RIGHT: u32 = 0x111;
+    const BTN_MIDDLE: u32 = 0x112;
+
+    /// Scroll distance of one wheel notch, as libinput reports it
+    const WHEEL_STEP: f64 = 15.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::{BufferDelta, DeltaRegion, MirrorBuffer};
+    use crate::render::{Hello, WindowTarget, FRAME_VERSION};
+
+    fn frame(sequence: u64) -> RenderFrame {
+        let mut frame = RenderFrame::new(2, 1, PixelFormat::ARGB8888, vec![1; 8]);
+        frame.sequence = sequence;
+        frame
+    }
+
+    #[test]
+    fn test_scene_tracks_windows() {
+        let mut scene = Scene::new();
+        let hello = RenderMessage::Hello(Hello { version: FRAME_VERSION });
+        assert_eq!(scene.apply(hello), (None, vec![RendererEvent::Capabilities(CAPABILITIES)]));
+        let target = |surface, destroy| RenderMessage::WindowTarget(WindowTarget { window: 1, surface, destroy });
+        scene.apply(target(10, false));
+        assert_eq!(scene.apply(RenderMessage::Frame(frame(5))), (Some(Change::Show((1, 10))), Vec::new()));
+
+        let delta = BufferDelta {
+            buffer_id: 10,
+            regions: vec![DeltaRegion { x: 1, y: 0, width: 1, height: 1, data: vec![9; 4], xor: false }],
+            total_bytes: 4,
+        };
+        assert_eq!(scene.window((1, 10)).unwrap().damage(), None);
+        scene.presented((1, 10), 50);
+        assert_eq!(scene.window((1, 10)).unwrap().damage(), Some(&[][..]));
+        assert_eq!(scene.apply(RenderMessage::FrameDelta(delta)).0, Some(Change::Show((1, 10))));
+        assert_eq!(scene.window((1, 10)).unwrap().damage(), Some(&[Rect::new(1, 0, 1, 1)][..]));
+        assert_eq!(scene.window((1, 10)).unwrap().frame.as_ref().unwrap().data, [[1; 4], [9; 4]].concat());
+
+        // Presenting acknowledges the delta, once
+        let events = scene.presented((1, 10), 100);
+        assert!(matches!(events[..], [RendererEvent::Presented { time_ns: 100, seq: 2, .. }, RendererEvent::FrameAck { sequence: 6 }]));
+        assert!(scene.presented((1, 10), 200).is_empty());
+
+        // A diverged copy asks for a full frame
+        let checksum = MirrorBuffer::from_data(10, 2, 1, 4, 8, vec![0; 8]).checksum(0);
+        assert_eq!(scene.apply(RenderMessage::Checksum(checksum)).1, vec![RendererEvent::Resync { buffer_id: 10 }]);
+
+        // The overlay repaints its corner
+        let stats = StatsOverlay { visible: true, frame_rate: 6000, ..Default::default() };
+        assert_eq!(scene.apply(RenderMessage::StatsOverlay(stats)).0, Some(Change::Show((1, 10))));
+        assert_eq!(scene.window((1, 10)).unwrap().stats, Some(stats));
+        assert_eq!(scene.window((1, 10)).unwrap().damage(), Some(&[OVERLAY_RECT][..]));
+
+        // A second toplevel of the client gets a window of its own
+        scene.apply(target(11, false));
+        assert_eq!(scene.apply(RenderMessage::Frame(frame(1))).0, Some(Change::Show((1, 11))));
+        assert_eq!(scene.window((1, 10)).unwrap().frame.as_ref().unwrap().sequence, 5);
+        assert_eq!(scene.apply(target(11, true)).0, Some(Change::Destroy((1, 11))));
+        assert!(scene.window((1, 10)).is_some());
+
+        // Surface 0 closes all of the client's windows
+        assert_eq!(scene.apply(target(0, true)).0, Some(Change::DestroyClient(1)));
+        assert!(scene.window((1, 10)).is_none());
+    }
+
+    #[test]
+    fn test_scene_applies_frame_updates() {
+        use crate::render::FrameUpdate;
+
+        let mut scene = Scene::new();
+        scene.apply(RenderMessage::Frame(frame(5)));
+        scene.presented((0, 0), 0);
+        let delta = BufferDelta {
+            buffer_id: 10,
+            regions: vec![DeltaRegion { x: 0, y: 0, width: 1, height: 1, data: vec![3; 4], xor: false }],
+            total_bytes: 4,
+        };
+        let update = FrameUpdate { sequence: 9, width: 2, height: 1, delta };
+        assert_eq!(scene.apply(RenderMessage::FrameUpdate(update.clone())), (Some(Change::Show((0, 0))), Vec::new()));
+        assert_eq!(scene.window((0, 0)).unwrap().damage(), Some(&[Rect::new(0, 0, 1, 1)][..]));
+        assert_eq!(scene.window((0, 0)).unwrap().frame.as_ref().unwrap().data, [[3; 4], [1; 4]].concat());
+
+        // The update's own sequence is acknowledged
+        assert!(matches!(scene.presented((0, 0), 10)[..], [_, RendererEvent::FrameAck { sequence: 9 }]));
+
+        // One for another frame size asks for a full frame
+        let resized = FrameUpdate { width: 4, ..update };
+        assert_eq!(scene.apply(RenderMessage::FrameUpdate(resized)).1, vec![RendererEvent::Resync { buffer_id: 10 }]);
+    }
+
+    #[test]
+    fn test_presenter_capabilities() {
+        assert_eq!("d3d11".parse(), Ok(Presenter::D3d11));
+        assert!("opengl".parse::<Presenter>().is_err());
+
+        // Swapchain presents come from vblanks
+        let mut scene = Scene::with_presenter(Presenter::D3d11);
+        let hello = RenderMessage::Hello(Hello { version: FRAME_VERSION });
+        let (_, events) = scene.apply(hello);
+        assert!(matches!(events[..], [RendererEvent::Capabilities(caps)] if caps.flags & capability_flags::VSYNC != 0));
+
+        // Until the GPU is lost
+        assert_eq!(scene.set_presenter(Presenter::Software), RendererEvent::Capabilities(CAPABILITIES));
+    }
+
+    #[test]
+    fn test_linux_key_codes() {
+        // A, then keypad 8 and the up arrow sharing scan code 0x48
+        assert_eq!(linux_key(0x1e, false), 30);
+        assert_eq!((linux_key(0x48, false), linux_key(0x48, true)), (72, 103));
+    }
+
+    #[tokio::test]
+    async fn test_bridge_carries_messages_and_events() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, renderer) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let (messages_tx, messages) = std::sync::mpsc::channel();
+        let (events_tx, events) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(bridge(renderer, messages_tx, || {}, events));
+        // Stands in for the window thread
+        std::thread::spawn(move || {
+            let mut scene = Scene::new();
+            for message in messages {
+                if let (Some(Change::Show(window)), _) = scene.apply(message) {
+                    scene.presented(window, 0).into_iter().for_each(|event| events_tx.send(event).unwrap());
+                }
+            }
+        });
+
+        let target = RenderMessage::WindowTarget(WindowTarget { window: 2, surface: 10, destroy: false });
+        client.write_all(&[target.encode(), RenderMessage::Frame(frame(7)).encode()].concat()).await.unwrap();
+        let (mut data, mut events) = (Vec::new(), Vec::new());
+        let mut buf = [0u8; 256];
+        while events.len() < 3 {
+            let n = client.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+            while let Some((event, size)) = RendererEvent::decode(&data).unwrap() {
+                events.extend(event);
+                data.drain(..size);
+            }
+        }
+        assert_eq!(events[0], RendererEvent::Window { window: 2, surface: 10 });
+        assert_eq!(events[2], RendererEvent::FrameAck { sequence: 7 });
+    }
+}
@@ -0,0 +1,186 @@
+//! Screen Capture (wlr-screencopy-unstable-v1)
+//!
+//! Screenshot and recording tools (grim, wf-recorder) capture an output,
+//! or a region of it, into a wl_shm buffer of their own. The capture is
+//! the Windows desktop as it is on screen, read from the monitor backing
+//! the output, so it includes native windows around the client's own.
+//! The pixels travel to the client side as buffer content control messages
+//! (see `crate::pipe`) ahead of the ready event.
+//!
+//! Every copy captures right away and reports the whole frame as damaged.
+//! Without Win32 there is no desktop to read and captures fail.
+
+use std::time::Duration;
+
+use crate::output::Monitor;
+use crate::positioner::Rect;
+use crate::wire::{ArgWriter, Message};
+
+/// zwlr_screencopy_frame_v1 event opcodes
+pub mod events {
+    pub const BUFFER: u16 = 0;
+    pub const FLAGS: u16 = 1;
+    pub const READY: u16 = 2;
+    pub const FAILED: u16 = 3;
+    pub const DAMAGE: u16 = 4;
+    pub const LINUX_DMABUF: u16 = 5;
+    pub const BUFFER_DONE: u16 = 6;
+}
+
+/// A captured image, 32-bit XRGB8888 rows top to bottom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn stride(&self) -> u32 {
+        self.width * 4
+    }
+
+    /// Copy of `rect` (in pixels), clipped to the image
+    pub fn crop(&self, rect: Rect) -> Image {
+        let x0 = rect.x.clamp(0, self.width as i32) as usize;
+        let y0 = rect.y.clamp(0, self.height as i32) as usize;
+        let x1 = (rect.x + rect.width).clamp(0, self.width as i32) as usize;
+        let y1 = (rect.y + rect.height).clamp(0, self.height as i32) as usize;
+        let stride = self.stride() as usize;
+        let mut data = Vec::with_capacity((x1 - x0) * (y1 - y0) * 4);
+        for row in y0..y1 {
+            data.extend_from_slice(&self.data[row * stride + x0 * 4..row * stride + x1 * 4]);
+        }
+        Image { width: (x1 - x0) as u32, height: (y1 - y0) as u32, data }
+    }
+}
+
+/// A zwlr_screencopy_frame_v1 waiting for copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureFrame {
+    /// Global name of the captured wl_output
+    pub output: u32,
+    /// Captured region in output pixels, None for the whole output
+    pub region: Option<Rect>,
+    /// Size of the image, announced in the buffer event
+    pub size: (u32, u32),
+    /// copy was requested already
+    pub copied: bool,
+}
+
+/// Buffer constraint events sent when a frame is created
+pub fn buffer_events(frame: u32, format: u32, size: (u32, u32)) -> Vec<Message> {
+    let payload = ArgWriter::new().u32(format).u32(size.0).u32(size.1).u32(size.0 * 4).finish();
+    vec![
+        Message::new(frame, events::BUFFER, payload),
+        Message::new(frame, events::BUFFER_DONE, vec![]),
+    ]
+}
+
+/// flags, optional damage and ready for a completed copy
+pub fn ready_events(frame: u32, size: (u32, u32), with_damage: bool, time: Duration) -> Vec<Message> {
+    let mut events = vec![Message::new(frame, events::FLAGS, 0u32.to_le_bytes().to_vec())];
+    if with_damage {
+        let payload = ArgWriter::new().u32(0).u32(0).u32(size.0).u32(size.1).finish();
+        events.push(Message::new(frame, events::DAMAGE, payload));
+    }
+    let secs = time.as_secs();
+    let payload = ArgWriter::new()
+        .u32((secs >> 32) as u32)
+        .u32(secs as u32)
+        .u32(time.subsec_nanos())
+        .finish();
+    events.push(Message::new(frame, events::READY, payload));
+    events
+}
+
+/// failed()
+pub fn failed(frame: u32) -> Message {
+    Message::new(frame, events::FAILED, vec![])
+}
+
+/// Capture what is on screen on `monitor`
+pub fn capture_monitor(monitor: &Monitor) -> Option<Image> {
+    platform::capture(monitor.x, monitor.y, monitor.width, monitor.height)
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Image;
+
+    use windows_sys::Win32::Graphics::Gdi::{
+        BitBlt, CreateCompatibleBitmap, CreateCompatibleDC, DeleteDC, DeleteObject, GetDC, GetDIBits,
+        ReleaseDC, SelectObject, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, CAPTUREBLT, DIB_RGB_COLORS, SRCCOPY,
+    };
+
+    /// Copy a rectangle of the virtual desktop with GDI
+    pub fn capture(x: i32, y: i32, width: i32, height: i32) -> Option<Image> {
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+        // SAFETY: every GDI object created here is released before returning;
+        // the destination buffer holds width * height 32-bit pixels
+        unsafe {
+            let screen = GetDC(std::ptr::null_mut());
+            if screen.is_null() {
+                return None;
+            }
+            let memory = CreateCompatibleDC(screen);
+            let bitmap = CreateCompatibleBitmap(screen, width, height);
+            let previous = SelectObject(memory, bitmap);
+            let copied = BitBlt(memory, 0, 0, width, height, screen, x, y, SRCCOPY | CAPTUREBLT) != 0;
+
+            let mut info: BITMAPINFO = std::mem::zeroed();
+            info.bmiHeader.biSize = std::mem::size_of::<BITMAPINFOHEADER>() as u32;
+            info.bmiHeader.biWidth = width;
+            // Negative height: rows top to bottom
+            info.bmiHeader.biHeight = -height;
+            info.bmiHeader.biPlanes = 1;
+            info.bmiHeader.biBitCount = 32;
+            info.bmiHeader.biCompression = BI_RGB;
+            let mut data = vec![0u8; width as usize * height as usize * 4];
+            SelectObject(memory, previous);
+            let rows = copied.then(|| {
+                GetDIBits(memory, bitmap, 0, height as u32, data.as_mut_ptr().cast(), &mut info, DIB_RGB_COLORS)
+            });
+
+            DeleteObject(bitmap);
+            DeleteDC(memory);
+            ReleaseDC(std::ptr::null_mut(), screen);
+
+            (rows == Some(height)).then_some(Image { width: width as u32, height: height as u32, data })
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::Image;
+
+    /// No desktop to capture without Win32
+    pub fn capture(_x: i32, _y: i32, _width: i32, _height: i32) -> Option<Image> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_crop_clips_to_image() {
+        let image = Image { width: 4, height: 2, data: (0..32).collect() };
+        let cropped = image.crop(Rect::new(2, 1, 8, 8));
+        assert_eq!((cropped.width, cropped.height), (2, 1));
+        assert_eq!(cropped.data, (24..32).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_ready_splits_timestamp() {
+        let events = ready_events(5, (640, 480), true, Duration::new(7, 250));
+        assert_eq!(events.iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![events::FLAGS, events::DAMAGE, events::READY]);
+        let mut args = ArgReader::new(&events[2].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap(), args.u32().unwrap()), (0, 7, 250));
+    }
+}
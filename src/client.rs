@@ -0,0 +1,887 @@
+//! WSL-Side Client Mode
+//!
+//! `winpipe client` runs next to the Wayland applications. It listens on
+//! `$XDG_RUNTIME_DIR/wayland-winpipe` and bridges every application that
+//! connects to the server, all over one TCP connection (see `crate::mux`)
+//! run as a session that survives reconnecting (see `crate::session`). File
+//! descriptors cannot cross TCP, so the bridge stands in for them:
+//!
+//! - wl_shm pools are mapped here. On commit, the bytes of the attached
+//!   buffer that changed since they were last sent go ahead of the commit
+//!   as `BUFFER_CONTENT` (see `crate::pipe`).
+//! - Pipes passed to `wl_data_offer.receive` stay here and are announced
+//!   with `PIPE_OPEN`; `PIPE_DATA` and `PIPE_CLOSE` fill and close them.
+//! - `FD_CONTENT` becomes a sealed memfd attached to the next event.
+//! - `BUFFER_CONTENT` from the server (screen captures) is written into
+//!   the buffer's pool.
+//!
+//! dmabufs and syncobj timelines are not mirrored yet: their fds are
+//! closed and the request is forwarded without them.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, Interest};
+use tokio::net::unix::pipe::Sender;
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::auth;
+use crate::error::{Result, WinpipeError};
+use crate::keepalive;
+use crate::listen;
+use crate::mapping::SharedMapping;
+use crate::noise;
+use crate::pipe::{self, PipeEvent, CONTROL_OBJECT_ID};
+use crate::mux::Mux;
+use crate::session;
+use crate::tls;
+use crate::wire::{opcodes, ArgReader, Message, WireDecoder};
+
+/// Most fds libwayland sends with a single message
+const MAX_FDS_PER_MESSAGE: usize = 28;
+
+/// Buffers are compared in blocks of this many bytes; neighbouring
+/// changed blocks are sent as one chunk
+const DIFF_BLOCK: usize = 64;
+
+/// `PIPE_DATA` chunks queued for a pipe (16 MiB) before its reader counts
+/// as stuck and the pipe is closed
+const PIPE_QUEUE: usize = 512;
+
+/// A wl_shm_pool, mapped from the fd the client passed
+struct Pool {
+    file: Arc<File>,
+    mapping: Arc<Mutex<SharedMapping>>,
+}
+
+impl Pool {
+    fn map(fd: OwnedFd, size: usize) -> Result<Self> {
+        let file = File::from(fd);
+        let mapping = Self::map_file(&file, size)?;
+        Ok(Self { file: Arc::new(file), mapping })
+    }
+
+    fn map_file(file: &File, size: usize) -> Result<Arc<Mutex<SharedMapping>>> {
+        let path = Path::new("/proc/self/fd").join(file.as_raw_fd().to_string());
+        Ok(Arc::new(Mutex::new(SharedMapping::from_file(&path, file.try_clone()?, size)?)))
+    }
+
+    /// Map the grown pool; buffers keep the old mapping, which shows the
+    /// same memory
+    fn resize(&mut self, size: usize) -> Result<()> {
+        self.mapping = Self::map_file(&self.file, size)?;
+        Ok(())
+    }
+}
+
+/// A wl_buffer in a pool and the bytes the server has of it
+struct ShmBuffer {
+    /// The pool's file, to check its size against
+    file: Arc<File>,
+    mapping: Arc<Mutex<SharedMapping>>,
+    offset: usize,
+    sent: Vec<u8>,
+}
+
+impl ShmBuffer {
+    /// Whether the pool's file still holds all of the buffer
+    ///
+    /// The client may shrink the file under the mapping, and touching
+    /// pages past its end raises SIGBUS, so this is checked before every
+    /// access.
+    fn backed(&self) -> bool {
+        let end = (self.offset + self.sent.len()) as u64;
+        self.file.metadata().is_ok_and(|meta| meta.len() >= end)
+    }
+}
+
+/// Protocol state of one bridged client
+///
+/// Only what is needed to stand in for fds is tracked: the interface of
+/// the objects whose requests or events carry one, the pools and buffers,
+/// and the buffer attached to each surface.
+#[derive(Default)]
+pub struct Bridge {
+    objects: HashMap<u32, String>,
+    pools: HashMap<u32, Pool>,
+    buffers: HashMap<u32, ShmBuffer>,
+    /// Buffer attached to each surface since its last commit
+    attached: HashMap<u32, u32>,
+    /// Queues of the tasks writing to the pipes of wl_data_offer.receive
+    pipes: HashMap<u32, mpsc::Sender<Vec<u8>>>,
+    next_pipe: u32,
+    /// memfd for the next event, from `FD_CONTENT`
+    next_fd: Option<OwnedFd>,
+    /// Control messages answering the server
+    replies: Vec<Message>,
+}
+
+impl Bridge {
+    pub fn new() -> Self {
+        let mut bridge = Self::default();
+        bridge.objects.insert(1, "wl_display".to_string());
+        bridge
+    }
+
+    /// Handle a request from the client, taking the fds it carries
+    ///
+    /// Returns the messages to send to the server: the request, preceded
+    /// by the control messages that stand in for its fds or that upload
+    /// the buffer it commits.
+    pub fn from_client(&mut self, msg: Message, fds: &mut VecDeque<OwnedFd>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let interface = self.objects.get(&msg.object_id).cloned().unwrap_or_default();
+        let mut args = ArgReader::new(&msg.payload);
+
+        match (interface.as_str(), msg.opcode) {
+            ("wl_display", opcodes::display::GET_REGISTRY) => self.track(args.u32(), "wl_registry"),
+
+            // wl_registry.bind(name, interface, version, id)
+            ("wl_registry", opcodes::registry::BIND) => {
+                if let (Ok(_), Ok(bound), Ok(_), Ok(id)) = (args.u32(), args.string(), args.u32(), args.u32()) {
+                    self.objects.insert(id, bound);
+                }
+            }
+
+            ("wl_compositor", opcodes::compositor::CREATE_SURFACE) => self.track(args.u32(), "wl_surface"),
+
+            // wl_shm.create_pool(id, fd, size)
+            ("wl_shm", opcodes::shm::CREATE_POOL) => {
+                let fd = fds.pop_front();
+                if let (Ok(id), Ok(size)) = (args.u32(), args.i32()) {
+                    self.objects.insert(id, "wl_shm_pool".to_string());
+                    match fd.map(|fd| Pool::map(fd, size.max(0) as usize)) {
+                        Some(Ok(pool)) => {
+                            self.pools.insert(id, pool);
+                        }
+                        Some(Err(e)) => warn!("wl_shm.create_pool (id={}): {}", id, e),
+                        None => warn!("wl_shm.create_pool (id={}) without an fd", id),
+                    }
+                }
+            }
+
+            // wl_shm_pool.create_buffer(id, offset, width, height, stride, format)
+            ("wl_shm_pool", opcodes::shm_pool::CREATE_BUFFER) => {
+                let parsed = (|| -> Result<_> { Ok((args.u32()?, args.i32()?, args.i32()?, args.i32()?, args.i32()?)) })();
+                if let (Ok((id, offset, _width, height, stride)), Some(pool)) = (parsed, self.pools.get(&msg.object_id)) {
+                    self.objects.insert(id, "wl_buffer".to_string());
+                    let (offset, len) = (offset.max(0) as usize, stride.max(0) as usize * height.max(0) as usize);
+                    if offset + len <= pool.mapping.lock().unwrap().len() {
+                        let buffer = ShmBuffer { file: Arc::clone(&pool.file), mapping: Arc::clone(&pool.mapping), offset, sent: vec![0; len] };
+                        self.buffers.insert(id, buffer);
+                    } else {
+                        warn!("wl_shm_pool.create_buffer (id={}): {} bytes at {} past the pool", id, len, offset);
+                    }
+                }
+            }
+
+            ("wl_shm_pool", opcodes::shm_pool::RESIZE) => {
+                if let (Ok(size), Some(pool)) = (args.i32(), self.pools.get_mut(&msg.object_id)) {
+                    if let Err(e) = pool.resize(size.max(0) as usize) {
+                        warn!("wl_shm_pool.resize (id={}): {}", msg.object_id, e);
+                    }
+                }
+            }
+
+            ("wl_shm_pool", opcodes::shm_pool::DESTROY) => {
+                self.pools.remove(&msg.object_id);
+            }
+
+            ("wl_buffer", opcodes::buffer::DESTROY) => {
+                self.buffers.remove(&msg.object_id);
+            }
+
+            // wl_surface.attach(buffer, x, y)
+            ("wl_surface", opcodes::surface::ATTACH) => match args.u32() {
+                Ok(0) | Err(_) => {
+                    self.attached.remove(&msg.object_id);
+                }
+                Ok(buffer) => {
+                    self.attached.insert(msg.object_id, buffer);
+                }
+            },
+
+            ("wl_surface", opcodes::surface::COMMIT) => {
+                if let Some(buffer) = self.attached.remove(&msg.object_id) {
+                    messages.extend(self.upload(buffer));
+                }
+            }
+
+            ("wl_surface", opcodes::surface::DESTROY) => {
+                self.attached.remove(&msg.object_id);
+            }
+
+            ("wl_data_device_manager", opcodes::data_device_manager::GET_DATA_DEVICE) => {
+                self.track(args.u32(), "wl_data_device")
+            }
+
+            // wl_data_offer.receive(mime_type, fd)
+            ("wl_data_offer", opcodes::data_offer::RECEIVE) => match fds.pop_front().map(Sender::from_owned_fd) {
+                Some(Ok(sender)) => {
+                    self.next_pipe += 1;
+                    self.pipes.insert(self.next_pipe, spawn_pipe_writer(self.next_pipe, sender));
+                    messages.push(pipe::open_message(self.next_pipe));
+                }
+                Some(Err(e)) => warn!("wl_data_offer.receive: not a pipe: {}", e),
+                None => warn!("wl_data_offer.receive without an fd"),
+            },
+
+            ("zwp_linux_dmabuf_v1", opcodes::linux_dmabuf::CREATE_PARAMS) => {
+                self.track(args.u32(), "zwp_linux_buffer_params_v1")
+            }
+
+            ("zwp_linux_buffer_params_v1", opcodes::linux_buffer_params::ADD)
+            | ("wp_linux_drm_syncobj_manager_v1", opcodes::drm_syncobj_manager::IMPORT_TIMELINE) => {
+                fds.pop_front();
+                warn!("{} fds cannot be mirrored yet", interface);
+            }
+
+            _ => {}
+        }
+
+        messages.push(msg);
+        messages
+    }
+
+    /// Handle a message from the server
+    ///
+    /// Control messages are consumed. Other events are returned with the
+    /// fd to send along, if `FD_CONTENT` provided one.
+    pub fn from_server(&mut self, msg: Message) -> Option<(Message, Option<OwnedFd>)> {
+        if msg.object_id == CONTROL_OBJECT_ID {
+            match PipeEvent::from_message(&msg) {
+                Ok(PipeEvent::Data { id, data }) => {
+                    let sent = self.pipes.get(&id).map(|queue| queue.try_send(data));
+                    match sent {
+                        Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                            warn!("Pipe {} is not being read, closing it", id);
+                            self.pipes.remove(&id);
+                        }
+                        Some(Err(mpsc::error::TrySendError::Closed(_))) => {
+                            self.pipes.remove(&id);
+                        }
+                        Some(Ok(())) | None => {}
+                    }
+                }
+                Ok(PipeEvent::Close { id }) => {
+                    self.pipes.remove(&id);
+                }
+                Ok(PipeEvent::FdContent { data }) => match sealed_memfd(&data) {
+                    Ok(fd) => self.next_fd = Some(fd),
+                    Err(e) => warn!("Cannot create a memfd: {}", e),
+                },
+                Ok(PipeEvent::BufferContent { buffer, offset, data }) => self.write_buffer(buffer, offset as usize, &data),
+                // No timelines are imported, so none are released
+                Ok(PipeEvent::SyncRelease { .. }) => {}
+                Ok(PipeEvent::Ping) => self.replies.push(pipe::ping_message()),
+                Err(e) => warn!("Bad control message: {}", e),
+            }
+            return None;
+        }
+
+        let interface = self.objects.get(&msg.object_id).map(String::as_str);
+        let mut args = ArgReader::new(&msg.payload);
+        match (interface, msg.opcode) {
+            (Some("wl_display"), opcodes::display::DELETE_ID) => {
+                if let Ok(id) = args.u32() {
+                    self.forget(id);
+                }
+            }
+            (Some("wl_data_device"), opcodes::data_device::DATA_OFFER) => self.track(args.u32(), "wl_data_offer"),
+            _ => {}
+        }
+        Some((msg, self.next_fd.take()))
+    }
+
+    /// Control messages to send back to the server
+    pub fn take_replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn track(&mut self, id: Result<u32>, interface: &str) {
+        if let Ok(id) = id {
+            self.objects.insert(id, interface.to_string());
+        }
+    }
+
+    /// Drop everything known about a deleted object
+    fn forget(&mut self, id: u32) {
+        self.objects.remove(&id);
+        self.pools.remove(&id);
+        self.buffers.remove(&id);
+        self.attached.remove(&id);
+    }
+
+    /// Messages sending the bytes of a buffer the server does not have yet
+    fn upload(&mut self, id: u32) -> Vec<Message> {
+        let Some(buffer) = self.buffers.get_mut(&id) else {
+            return Vec::new();
+        };
+        if !buffer.backed() {
+            warn!("Pool of buffer {} shrank below it, no longer mirroring it", id);
+            self.buffers.remove(&id);
+            return Vec::new();
+        }
+        let mapping = buffer.mapping.lock().unwrap();
+        let current = &mapping[buffer.offset..buffer.offset + buffer.sent.len()];
+        let mut messages = Vec::new();
+        for (start, end) in changed_spans(&buffer.sent, current) {
+            messages.extend(pipe::buffer_content_messages_at(id, start as u32, &current[start..end]));
+            buffer.sent[start..end].copy_from_slice(&current[start..end]);
+        }
+        messages
+    }
+
+    /// Write bytes the server produced into a buffer
+    fn write_buffer(&mut self, id: u32, offset: usize, data: &[u8]) {
+        let Some(buffer) = self.buffers.get_mut(&id) else {
+            warn!("Content for unknown buffer {}", id);
+            return;
+        };
+        let end = offset + data.len();
+        if end > buffer.sent.len() {
+            warn!("Content for buffer {} past its end", id);
+            return;
+        }
+        if !buffer.backed() {
+            warn!("Pool of buffer {} shrank below it, no longer mirroring it", id);
+            self.buffers.remove(&id);
+            return;
+        }
+        let start = buffer.offset + offset;
+        buffer.mapping.lock().unwrap()[start..start + data.len()].copy_from_slice(data);
+        buffer.sent[offset..end].copy_from_slice(data);
+    }
+}
+
+/// Start the task writing the data queued for pipe `id`
+///
+/// Each pipe gets its own, so a client that is slow to read one holds up
+/// neither the bridge nor the other pipes. Dropping the queue closes the
+/// pipe once everything queued has been written.
+fn spawn_pipe_writer(id: u32, mut pipe: Sender) -> mpsc::Sender<Vec<u8>> {
+    let (queue, mut queued) = mpsc::channel::<Vec<u8>>(PIPE_QUEUE);
+    tokio::spawn(async move {
+        while let Some(data) = queued.recv().await {
+            if let Err(e) = pipe.write_all(&data).await {
+                debug!("Pipe {} closed by the client: {}", id, e);
+                return;
+            }
+        }
+    });
+    queue
+}
+
+/// Byte ranges where `new` differs from `old`, compared in `DIFF_BLOCK`
+/// blocks
+fn changed_spans(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for (i, (old, new)) in old.chunks(DIFF_BLOCK).zip(new.chunks(DIFF_BLOCK)).enumerate() {
+        if old != new {
+            let (start, end) = (i * DIFF_BLOCK, i * DIFF_BLOCK + new.len());
+            match spans.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => spans.push((start, end)),
+            }
+        }
+    }
+    spans
+}
+
+/// A read-only memfd holding `data`
+fn sealed_memfd(data: &[u8]) -> io::Result<OwnedFd> {
+    // SAFETY: plain syscall; the fd is owned by the File right after
+    let fd = unsafe { libc::memfd_create(c"winpipe".as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just created and nothing else owns it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)?;
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    // SAFETY: fcntl on an fd owned by `file`
+    if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file.into())
+}
+
+/// Where to listen: `name` under `$XDG_RUNTIME_DIR`, or `name` itself if
+/// it is an absolute path, as with WAYLAND_DISPLAY
+pub fn socket_path(name: &str) -> Result<PathBuf> {
+    if Path::new(name).is_absolute() {
+        return Ok(PathBuf::from(name));
+    }
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+    Ok(Path::new(&dir).join(name))
+}
+
+/// How TCP connections to the server are encrypted
+pub enum Encryption {
+    Tls(tls::Connector),
+    Noise(noise::Psk),
+}
+
+/// Accept local Wayland clients and bridge each to the server at
+/// `server`, a HOST:PORT or the path of a Unix socket, presenting `token`
+/// on each connection if the server requires one; TCP connections are
+/// encrypted with `encryption` if given
+pub async fn run(server: &str, socket_name: &str, token: Option<&str>, encryption: Option<Encryption>) -> Result<()> {
+    let path = socket_path(socket_name)?;
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())).into());
+    }
+    // A socket left behind by an earlier run refuses new listeners
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    info!("🚀 Bridging Wayland clients on {} to {}", path.display(), server);
+    info!("💡 Run apps with:");
+    info!("   export WAYLAND_DISPLAY={}", socket_name);
+
+    let server = Arc::new(server.to_string());
+    let token = Arc::new(token.map(str::to_string));
+    let encryption = Arc::new(encryption);
+    let mut mux: Option<Mux> = None;
+    loop {
+        let (client, _) = listener.accept().await?;
+        let stream = match open_channel(&mut mux, &server, &token, &encryption).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Cannot reach the server at {}: {}", server, e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            match bridge_client(client, stream).await {
+                Ok(()) => info!("🔌 Client disconnected"),
+                Err(e) => warn!("Client error: {}", e),
+            }
+        });
+    }
+}
+
+/// A channel for a new client on the connection to the server, which is
+/// opened first if there is none
+async fn open_channel(
+    mux: &mut Option<Mux>,
+    server: &Arc<String>,
+    token: &Arc<Option<String>>,
+    encryption: &Arc<Option<Encryption>>,
+) -> Result<DuplexStream> {
+    if let Some(mux) = mux.as_ref().filter(|mux| !mux.is_closed()) {
+        return mux.open().await;
+    }
+    let connector: session::Connector = {
+        let (server, token, encryption) = (Arc::clone(server), Arc::clone(token), Arc::clone(encryption));
+        Box::new(move || {
+            let (server, token, encryption) = (Arc::clone(&server), Arc::clone(&token), Arc::clone(&encryption));
+            Box::pin(async move { connect_server(&server, token.as_deref(), encryption.as_ref().as_ref()).await })
+        })
+    };
+    // The session resumes on a new connection if this one drops
+    let connected = Mux::connect(session::connect(connector).await?).await?;
+    mux.insert(connected).open().await
+}
+
+/// Connect to the server at `server`, encrypted with `encryption` if
+/// given and the server is reached over TCP but not loopback, and present
+/// `token`
+async fn connect_server(server: &str, token: Option<&str>, encryption: Option<&Encryption>) -> Result<session::BoxTransport> {
+    let mut stream: session::BoxTransport = if Path::new(server).is_absolute() {
+        // A Unix socket, such as the one `winpipe ssh` forwards
+        Box::new(UnixStream::connect(server).await?)
+    } else {
+        let stream = TcpStream::connect(server).await?;
+        stream.set_nodelay(true).ok();
+        if let Err(e) = keepalive::configure_tcp(&stream) {
+            warn!("Cannot enable TCP keepalive: {}", e);
+        }
+        let peer = stream.peer_addr()?;
+        match encryption.filter(|_| listen::encrypts(peer.ip())) {
+            Some(Encryption::Tls(tls)) => Box::new(tls.connect(tls::host_of(server), stream).await?),
+            Some(Encryption::Noise(psk)) => Box::new(noise::connect(stream, psk).await?),
+            None => Box::new(stream),
+        }
+    };
+    if let Some(token) = token {
+        auth::present(&mut stream, token).await?;
+    }
+    Ok(stream)
+}
+
+/// Forward one client's connection until either side closes it
+async fn bridge_client<S: AsyncRead + AsyncWrite + Unpin>(client: UnixStream, server: S) -> Result<()> {
+    let (mut server_read, mut server_write) = tokio::io::split(server);
+    let mut bridge = Bridge::new();
+    let (mut requests, mut events) = (WireDecoder::new(), WireDecoder::new());
+    let mut fds = VecDeque::new();
+    let mut client_buffer = vec![0u8; 65536];
+    let mut server_buffer = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            n = recv_with_fds(&client, &mut client_buffer, &mut fds) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                requests.push(&client_buffer[..n]);
+                let mut out = Vec::new();
+                while let Some(msg) = requests.decode() {
+                    for msg in bridge.from_client(msg, &mut fds) {
+                        out.extend_from_slice(&msg.encode());
+                    }
+                }
+                server_write.write_all(&out).await?;
+            }
+            n = server_read.read(&mut server_buffer) => {
+                let n = n?;
+                if n == 0 {
+                    return Err(WinpipeError::ConnectionClosed);
+                }
+                events.push(&server_buffer[..n]);
+                let mut out = Vec::new();
+                while let Some(msg) = events.decode() {
+                    let Some((msg, fd)) = bridge.from_server(msg) else {
+                        continue;
+                    };
+                    out.extend_from_slice(&msg.encode());
+                    // The fd goes out with the bytes up to its event
+                    if let Some(fd) = fd {
+                        send_with_fds(&client, &out, &[fd]).await?;
+                        out.clear();
+                    }
+                }
+                send_with_fds(&client, &out, &[]).await?;
+                let replies: Vec<u8> = bridge.take_replies().iter().flat_map(Message::encode).collect();
+                if !replies.is_empty() {
+                    server_write.write_all(&replies).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Read from the client, queueing the fds sent along
+async fn recv_with_fds(socket: &UnixStream, buf: &mut [u8], fds: &mut VecDeque<OwnedFd>) -> io::Result<usize> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || recvmsg(socket.as_raw_fd(), buf, fds)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Write all of `data` to the client, sending `fds` with its first bytes
+async fn send_with_fds(socket: &UnixStream, mut data: &[u8], mut fds: &[OwnedFd]) -> io::Result<()> {
+    while !data.is_empty() {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || sendmsg(socket.as_raw_fd(), data, fds)) {
+            Ok(n) => {
+                data = &data[n..];
+                fds = &[];
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Room for the SCM_RIGHTS control message of a full set of fds
+type ControlBuffer = [u64; 32];
+
+const _: () = assert!(16 + MAX_FDS_PER_MESSAGE * std::mem::size_of::<RawFd>() <= std::mem::size_of::<ControlBuffer>());
+
+fn recvmsg(socket: RawFd, buf: &mut [u8], fds: &mut VecDeque<OwnedFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    let mut control: ControlBuffer = [0; 32];
+    // SAFETY: a zeroed msghdr is valid; the buffers it points to outlive
+    // the call, and the control messages walked are the kernel's
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let n = libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push_back(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok(n as usize)
+    }
+}
+
+fn sendmsg(socket: RawFd, data: &[u8], fds: &[OwnedFd]) -> io::Result<usize> {
+    debug_assert!(fds.len() <= MAX_FDS_PER_MESSAGE);
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+    let mut control: ControlBuffer = [0; 32];
+    // SAFETY: as in `recvmsg`; the control message written fits the
+    // buffer (checked above)
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            let len = (fds.len() * std::mem::size_of::<RawFd>()) as u32;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(len) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+            let out = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, fd) in fds.iter().enumerate() {
+                out.add(i).write_unaligned(fd.as_raw_fd());
+            }
+        }
+        let n = libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgWriter;
+    use std::os::unix::fs::FileExt;
+
+    fn request(bridge: &mut Bridge, msg: Message, fds: &mut VecDeque<OwnedFd>) -> Vec<(u32, u16, Vec<u8>)> {
+        bridge.from_client(msg, fds).into_iter().map(|m| (m.object_id, m.opcode, m.payload)).collect()
+    }
+
+    #[test]
+    fn test_shm_changes_uploaded_before_commit() {
+        let path = std::env::temp_dir().join(format!("winpipe-client-pool-{}", std::process::id()));
+        let pool = File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        pool.set_len(512).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut bridge = Bridge::new();
+        let mut fds = VecDeque::new();
+        bridge.from_client(Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()), &mut fds);
+        for (name, interface, id) in [(1, "wl_compositor", 3u32), (2, "wl_shm", 4)] {
+            let bind = ArgWriter::new().u32(name).string(interface).u32(1).u32(id).finish();
+            bridge.from_client(Message::new(2, opcodes::registry::BIND, bind), &mut fds);
+        }
+        bridge.from_client(Message::new(3, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()), &mut fds);
+        fds.push_back(pool.try_clone().unwrap().into());
+        bridge.from_client(Message::new(4, opcodes::shm::CREATE_POOL, ArgWriter::new().u32(5).i32(512).finish()), &mut fds);
+        assert!(fds.is_empty());
+        let create = ArgWriter::new().u32(6).i32(0).i32(16).i32(8).i32(64).u32(0).finish();
+        bridge.from_client(Message::new(5, opcodes::shm_pool::CREATE_BUFFER, create), &mut fds);
+
+        let attach = Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(6).i32(0).i32(0).finish());
+        let commit = Message::new(10, opcodes::surface::COMMIT, vec![]);
+
+        // The server's copy starts zeroed too
+        bridge.from_client(attach.clone(), &mut fds);
+        assert_eq!(request(&mut bridge, commit.clone(), &mut fds).len(), 1);
+
+        // Only the changed block goes ahead of the commit
+        pool.write_at(&[1, 2, 3, 4], 130).unwrap();
+        bridge.from_client(attach.clone(), &mut fds);
+        let sent = request(&mut bridge, commit.clone(), &mut fds);
+        assert_eq!(sent.len(), 2);
+        assert_eq!((sent[0].0, sent[0].1), (CONTROL_OBJECT_ID, pipe::opcodes::BUFFER_CONTENT));
+        let mut args = ArgReader::new(&sent[0].2);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (6, 128));
+        assert_eq!(args.array().unwrap()[2..6], [1, 2, 3, 4]);
+        assert_eq!((sent[1].0, sent[1].1), (10, opcodes::surface::COMMIT));
+
+        bridge.from_client(attach.clone(), &mut fds);
+        assert_eq!(request(&mut bridge, commit.clone(), &mut fds).len(), 1);
+
+        // A pool truncated under the buffer is not touched, which would
+        // raise SIGBUS; only the commit goes out
+        pool.set_len(64).unwrap();
+        bridge.from_client(attach, &mut fds);
+        assert_eq!(request(&mut bridge, commit, &mut fds).len(), 1);
+        assert!(!bridge.buffers.contains_key(&6));
+    }
+
+    #[tokio::test]
+    async fn test_fd_content_attached_to_next_event() {
+        let mut bridge = Bridge::new();
+        assert!(bridge.from_server(pipe::fd_content_message(b"format table")).is_none());
+
+        let event = Message::new(20, 1, 12u32.to_le_bytes().to_vec());
+        let (_, fd) = bridge.from_server(event.clone()).unwrap();
+        let mut file = File::from(fd.unwrap());
+        let mut content = [0; 12];
+        file.read_exact_at(&mut content, 0).unwrap();
+        assert_eq!(&content, b"format table");
+        assert!(file.write_all(b"x").is_err());
+
+        // Only that event carries it
+        assert!(bridge.from_server(event).unwrap().1.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pipes_written_apart_from_the_bridge() {
+        let mut bridge = Bridge::new();
+        let mut fds = VecDeque::new();
+        bridge.objects.insert(7, "wl_data_offer".to_string());
+        let mut readers = Vec::new();
+        for _ in 0..3 {
+            let (sender, receiver) = tokio::net::unix::pipe::pipe().unwrap();
+            fds.push_back(sender.into_nonblocking_fd().unwrap());
+            readers.push(receiver);
+            let receive = ArgWriter::new().string("text/plain").finish();
+            assert_eq!(request(&mut bridge, Message::new(7, opcodes::data_offer::RECEIVE, receive), &mut fds).len(), 2);
+        }
+
+        // A pipe nobody reads yet takes more than the kernel buffers without
+        // holding up the next one
+        let large = vec![7u8; 1 << 20];
+        for msg in pipe::write_messages(1, &large).into_iter().chain(pipe::write_messages(2, b"small")) {
+            assert!(bridge.from_server(msg).is_none());
+        }
+        let mut small = Vec::new();
+        readers[1].read_to_end(&mut small).await.unwrap();
+        assert_eq!(small, b"small");
+        let mut received = Vec::new();
+        readers[0].read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, large);
+
+        // One that stays unread past its queue is closed
+        for msg in pipe::write_messages(3, &vec![0; (PIPE_QUEUE + 1) * pipe::PIPE_CHUNK_SIZE]) {
+            bridge.from_server(msg);
+        }
+        let mut received = Vec::new();
+        readers[2].read_to_end(&mut received).await.unwrap();
+        assert!(received.len() < (PIPE_QUEUE + 1) * pipe::PIPE_CHUNK_SIZE);
+    }
+    #[tokio::test]
+    async fn test_ping_returned_to_server() {
+        let mut bridge = Bridge::new();
+        assert!(bridge.from_server(pipe::ping_message()).is_none());
+        assert_eq!(bridge.take_replies(), vec![pipe::ping_message()]);
+        assert!(bridge.take_replies().is_empty());
+    }
+
+    /// Forward a Unix socket to the server's port on 127.0.0.1, as
+    /// `winpipe ssh` has ssh do
+    fn ssh_forward(socket: &Path, port: u16) {
+        let forward = UnixListener::bind(socket).unwrap();
+        tokio::spawn(async move {
+            let (mut remote, _) = forward.accept().await.unwrap();
+            let mut local = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            tokio::io::copy_bidirectional(&mut remote, &mut local).await
+        });
+    }
+
+    /// Check the server echoes `data` over `stream`
+    async fn assert_echoed(stream: &mut session::BoxTransport, data: &[u8; 5]) {
+        stream.write_all(data).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, data);
+    }
+
+    /// Serve `connections` clients presenting `token` the way the server
+    /// does, encrypting those `listen::encrypts` picks with `encrypt`, and
+    /// echo five bytes to each
+    fn echo_server<F, Fut>(listener: tokio::net::TcpListener, connections: usize, encrypt: F) -> tokio::task::JoinHandle<()>
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = session::BoxTransport> + Send,
+    {
+        tokio::spawn(async move {
+            for _ in 0..connections {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let mut stream: session::BoxTransport =
+                    if listen::encrypts(peer.ip()) { encrypt(stream).await } else { Box::new(stream) };
+                auth::verify(&mut stream, "secret").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ssh_forward_to_tls_server() {
+        let dir = std::env::temp_dir().join(format!("winpipe-client-ssh-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+
+        // A server started with --tls-cert and --token
+        let acceptor = tls::Acceptor::new(&cert, &key).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = echo_server(listener, 2, move |stream| {
+            let acceptor = acceptor.clone();
+            async move { Box::new(acceptor.accept(stream).await.unwrap()) as session::BoxTransport }
+        });
+
+        // The remote client has no certificate
+        let socket = dir.join("remote.sock");
+        ssh_forward(&socket, port);
+        let mut stream = connect_server(socket.to_str().unwrap(), Some("secret"), None).await.unwrap();
+        assert_echoed(&mut stream, b"hello").await;
+
+        // A local client given the certificate stays plain too
+        let tls = Encryption::Tls(tls::Connector::new(&cert, None).unwrap());
+        let mut stream = connect_server(&format!("127.0.0.1:{}", port), Some("secret"), Some(&tls)).await.unwrap();
+        assert_echoed(&mut stream, b"again").await;
+        server.await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ssh_forward_to_noise_server() {
+        let dir = std::env::temp_dir().join(format!("winpipe-client-ssh-noise-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let psk: noise::Psk = "07".repeat(32).parse().unwrap();
+
+        // A server started with --noise-psk and --token
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_psk = psk.clone();
+        let server = echo_server(listener, 2, move |stream| {
+            let psk = server_psk.clone();
+            async move { Box::new(noise::accept(stream, &psk).await.unwrap()) as session::BoxTransport }
+        });
+
+        // The remote client has no key
+        let socket = dir.join("remote.sock");
+        ssh_forward(&socket, port);
+        let mut stream = connect_server(socket.to_str().unwrap(), Some("secret"), None).await.unwrap();
+        assert_echoed(&mut stream, b"hello").await;
+
+        // A local client given the key stays plain too
+        let noise = Encryption::Noise(psk);
+        let mut stream = connect_server(&format!("127.0.0.1:{}", port), Some("secret"), Some(&noise)).await.unwrap();
+        assert_echoed(&mut stream, b"again").await;
+        server.await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+//! Clipboard and Drag-and-Drop Translation
+//!
+//! Wayland clients offer data as MIME types, while Windows uses numbered
+//! clipboard formats. File lists are the awkward case: WSL apps speak
+//! `text/uri-list` with paths like `/mnt/c/Users/...`, while Windows
+//! expects `CF_HDROP` with paths like `C:\Users\...`.
+//!
+//! This module converts between the two worlds:
+//! - MIME type <-> Windows clipboard format mapping
+//! - WSL path <-> Windows path translation
+//! - Payload conversion (UTF-8 <-> UTF-16, uri-list <-> DROPFILES)
+
+use crate::error::{Result, WinpipeError};
+
+/// Standard Windows clipboard format: UTF-16 text
+pub const CF_UNICODETEXT: u32 = 13;
+
+/// Standard Windows clipboard format: file drop list
+pub const CF_HDROP: u32 = 15;
+
+/// Registered clipboard format name used by browsers and Office for PNG data
+pub const PNG_FORMAT_NAME: &str = "PNG";
+
+/// Size of the DROPFILES header that precedes a CF_HDROP file list
+pub const DROPFILES_HEADER_SIZE: usize = 20;
+
+/// MIME types treated as plain text, in order of preference
+pub const TEXT_MIME_TYPES: &[&str] = &[
+    "text/plain;charset=utf-8",
+    "UTF8_STRING",
+    "text/plain",
+    "TEXT",
+    "STRING",
+];
+
+/// MIME type for file lists
+pub const URI_LIST_MIME: &str = "text/uri-list";
+
+/// MIME type for PNG images
+pub const PNG_MIME: &str = "image/png";
+
+/// Windows clipboard formats that winpipe knows how to translate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClipboardFormat {
+    /// CF_UNICODETEXT
+    UnicodeText,
+    /// CF_HDROP
+    HDrop,
+    /// Registered "PNG" format
+    Png,
+}
+
+impl ClipboardFormat {
+    /// Map a Wayland MIME type to a Windows clipboard format
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.trim();
+        if TEXT_MIME_TYPES.iter().any(|m| m.eq_ignore_ascii_case(mime)) {
+            Some(Self::UnicodeText)
+        } else if mime.eq_ignore_ascii_case(URI_LIST_MIME) {
+            Some(Self::HDrop)
+        } else if mime.eq_ignore_ascii_case(PNG_MIME) {
+            Some(Self::Png)
+        } else {
+            None
+        }
+    }
+
+    /// MIME types to advertise to Wayland clients for this format
+    pub fn mime_types(&self) -> &'static [&'static str] {
+        match self {
+            Self::UnicodeText => TEXT_MIME_TYPES,
+            Self::HDrop => &[URI_LIST_MIME],
+            Self::Png => &[PNG_MIME],
+        }
+    }
+
+    /// Standard format ID, or `None` for registered formats
+    /// (which must be looked up by name with RegisterClipboardFormat)
+    pub fn standard_id(&self) -> Option<u32> {
+        match self {
+            Self::UnicodeText => Some(CF_UNICODETEXT),
+            Self::HDrop => Some(CF_HDROP),
+            Self::Png => None,
+        }
+    }
+}
+
+/// Pick the best Windows format for a set of offered MIME types
+///
+/// File lists win over images, which win over text, so that copying files
+/// in a file manager pastes files rather than their names.
+pub fn best_format<'a, I>(mime_types: I) -> Option<ClipboardFormat>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let rank = |f: &ClipboardFormat| match f {
+        ClipboardFormat::HDrop => 0,
+        ClipboardFormat::Png => 1,
+        ClipboardFormat::UnicodeText => 2,
+    };
+    mime_types
+        .into_iter()
+        .filter_map(ClipboardFormat::from_mime)
+        .min_by_key(rank)
+}
+
+/// Translates paths between the WSL and Windows filesystem views
+#[derive(Debug, Clone, Default)]
+pub struct PathTranslator {
+    /// WSL distribution name, used to reach Linux-only paths from Windows
+    /// through `\\wsl.localhost\<distro>\...`
+    pub distro: Option<String>,
+}
+
+impl PathTranslator {
+    pub fn new(distro: Option<String>) -> Self {
+        Self { distro }
+    }
+
+    /// Convert a WSL path to a Windows path
+    ///
+    /// `/mnt/c/Users/me` becomes `C:\Users\me`. Other absolute paths are
+    /// routed through the `\\wsl.localhost` share when the distro is known.
+    pub fn to_windows(&self, path: &str) -> Option<String> {
+        if !path.starts_with('/') {
+            return None;
+        }
+
+        if let Some(rest) = path.strip_prefix("/mnt/") {
+            let mut parts = rest.splitn(2, '/');
+            let drive = parts.next().unwrap_or("");
+            if drive.len() == 1 && drive.as_bytes()[0].is_ascii_alphabetic() {
+                let tail = parts.next().unwrap_or("").replace('/', "\\");
+                return Some(format!("{}:\\{}", drive.to_ascii_uppercase(), tail));
+            }
+        }
+
+        let distro = self.distro.as_ref()?;
+        Some(format!("\\\\wsl.localhost\\{}{}", distro, path.replace('/', "\\")))
+    }
+
+    /// Convert a Windows path to a WSL path
+    ///
+    /// `C:\Users\me` becomes `/mnt/c/Users/me`, and paths on the
+    /// `\\wsl.localhost\<distro>` (or legacy `\\wsl$\<distro>`) share are
+    /// mapped back to their Linux location.
+    pub fn to_wsl(&self, path: &str) -> Option<String> {
+        let bytes = path.as_bytes();
+        if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+            let drive = (bytes[0] as char).to_ascii_lowercase();
+            let tail = path[2..].trim_start_matches(['\\', '/']).replace('\\', "/");
+            return Some(format!("/mnt/{}/{}", drive, tail));
+        }
+
+        let unc = path.replace('/', "\\");
+        let share = ["\\\\wsl.localhost\\", "\\\\wsl$\\"]
+            .iter()
+            .find_map(|prefix| {
+                unc.get(..prefix.len())
+                    .filter(|p| p.eq_ignore_ascii_case(prefix))
+                    .map(|_| &unc[prefix.len()..])
+            })?;
+
+        // Skip the distro component
+        let tail = match share.find('\\') {
+            Some(pos) => &share[pos..],
+            None => "\\",
+        };
+        Some(tail.replace('\\', "/"))
+    }
+
+    /// Convert a `text/uri-list` payload into a list of Windows paths
+    ///
+    /// Comment lines and non-`file://` URIs are skipped.
+    pub fn uri_list_to_windows(&self, uri_list: &str) -> Vec<String> {
+        uri_list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(file_uri_to_path)
+            .filter_map(|path| self.to_windows(&path))
+            .collect()
+    }
+
+    /// Convert a list of Windows paths into a `text/uri-list` payload
+    pub fn windows_to_uri_list<S: AsRef<str>>(&self, paths: &[S]) -> String {
+        let mut out = String::new();
+        for path in paths {
+            if let Some(wsl) = self.to_wsl(path.as_ref()) {
+                out.push_str("file://");
+                out.push_str(&percent_encode_path(&wsl));
+                out.push_str("\r\n");
+            }
+        }
+        out
+    }
+}
+
+/// Extract the local path from a `file://` URI
+fn file_uri_to_path(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("file://")?;
+    // Skip an optional host component (file://localhost/path)
+    let path = &rest[rest.find('/')?..];
+    percent_decode(path)
+}
+
+/// Decode `%XX` escapes in a URI path
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Percent-encode a path for use in a `file://` URI
+fn percent_encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for &b in path.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Convert UTF-8 text to a CF_UNICODETEXT payload (UTF-16LE, NUL-terminated)
+///
+/// Bare `\n` line endings are converted to `\r\n` as Windows apps expect.
+pub fn text_to_unicode_text(text: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity((text.len() + 1) * 2);
+    let mut prev = '\0';
+    for ch in text.chars() {
+        if ch == '\n' && prev != '\r' {
+            out.extend_from_slice(&(b'\r' as u16).to_le_bytes());
+        }
+        let mut units = [0u16; 2];
+        for unit in ch.encode_utf16(&mut units) {
+            out.extend_from_slice(&unit.to_le_bytes());
+        }
+        prev = ch;
+    }
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+/// Convert a CF_UNICODETEXT payload to UTF-8 text
+///
+/// Stops at the first NUL and converts `\r\n` back to `\n`.
+pub fn unicode_text_to_text(data: &[u8]) -> Result<String> {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+    let text = String::from_utf16(&units)
+        .map_err(|e| WinpipeError::InvalidMessage(format!("Invalid UTF-16 clipboard text: {}", e)))?;
+    Ok(text.replace("\r\n", "\n"))
+}
+
+/// Build a CF_HDROP payload (DROPFILES header + wide path list)
+pub fn paths_to_hdrop<S: AsRef<str>>(paths: &[S]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(DROPFILES_HEADER_SIZE as u32).to_le_bytes()); // pFiles
+    out.extend_from_slice(&0i32.to_le_bytes()); // pt.x
+    out.extend_from_slice(&0i32.to_le_bytes()); // pt.y
+    out.extend_from_slice(&0u32.to_le_bytes()); // fNC
+    out.extend_from_slice(&1u32.to_le_bytes()); // fWide
+
+    for path in paths {
+        for unit in path.as_ref().encode_utf16() {
+            out.extend_from_slice(&unit.to_le_bytes());
+        }
+        out.extend_from_slice(&0u16.to_le_bytes());
+    }
+    // Double-NUL terminates the list
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+/// Parse a CF_HDROP payload into a list of paths
+pub fn hdrop_to_paths(data: &[u8]) -> Result<Vec<String>> {
+    if data.len() < DROPFILES_HEADER_SIZE {
+        return Err(WinpipeError::InvalidMessage("DROPFILES too short".to_string()));
+    }
+
+    let offset = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let wide = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) != 0;
+    if offset > data.len() {
+        return Err(WinpipeError::InvalidMessage(
+            format!("DROPFILES offset {} out of range", offset)
+        ));
+    }
+
+    let list = &data[offset..];
+    let mut paths = Vec::new();
+
+    if wide {
+        let units: Vec<u16> = list
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        for entry in units.split(|&u| u == 0) {
+            if entry.is_empty() {
+                break;
+            }
+            paths.push(String::from_utf16(entry).map_err(|e| {
+                WinpipeError::InvalidMessage(format!("Invalid UTF-16 path: {}", e))
+            })?);
+        }
+    } else {
+        for entry in list.split(|&b| b == 0) {
+            if entry.is_empty() {
+                break;
+            }
+            paths.push(String::from_utf8_lossy(entry).into_owned());
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Clipboard content offered to Wayland clients, keyed by MIME type
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Selection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add content for a MIME type
+    pub fn insert(&mut self, mime: &str, data: Vec<u8>) {
+        self.entries.retain(|(m, _)| m != mime);
+        self.entries.push((mime.to_string(), data));
+    }
+
+    /// Build a selection from Windows clipboard content
+    ///
+    /// The content is advertised under every MIME type that maps to the
+    /// Windows format, so toolkits find the flavour they look for.
+    pub fn from_windows(format: ClipboardFormat, data: &[u8], translator: &PathTranslator) -> Result<Self> {
+        let payload = match format {
+            ClipboardFormat::UnicodeText => unicode_text_to_text(data)?.into_bytes(),
+            ClipboardFormat::HDrop => translator.windows_to_uri_list(&hdrop_to_paths(data)?).into_bytes(),
+            ClipboardFormat::Png => data.to_vec(),
+        };
+
+        let mut selection = Self::new();
+        for mime in format.mime_types() {
+            selection.insert(mime, payload.clone());
+        }
+        Ok(selection)
+    }
+
+    /// Offered MIME types
+    pub fn mime_types(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(m, _)| m.as_str())
+    }
+
+    /// Content for a MIME type
+    pub fn get(&self, mime: &str) -> Option<&[u8]> {
+        self.entries.iter().find(|(m, _)| m == mime).map(|(_, d)| d.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_mapping() {
+        assert_eq!(ClipboardFormat::from_mime("text/plain;charset=utf-8"), Some(ClipboardFormat::UnicodeText));
+        assert_eq!(ClipboardFormat::from_mime("UTF8_STRING"), Some(ClipboardFormat::UnicodeText));
+        assert_eq!(ClipboardFormat::from_mime("text/uri-list"), Some(ClipboardFormat::HDrop));
+        assert_eq!(ClipboardFormat::from_mime("image/png"), Some(ClipboardFormat::Png));
+        assert_eq!(ClipboardFormat::from_mime("application/x-foo"), None);
+
+        let offered = ["text/plain", "text/uri-list", "image/png"];
+        assert_eq!(best_format(offered), Some(ClipboardFormat::HDrop));
+    }
+
+    #[test]
+    fn test_path_translation() {
+        let t = PathTranslator::new(Some("Ubuntu".to_string()));
+
+        assert_eq!(t.to_windows("/mnt/c/Users/me/a.txt").as_deref(), Some("C:\\Users\\me\\a.txt"));
+        assert_eq!(t.to_windows("/home/me/a.txt").as_deref(), Some("\\\\wsl.localhost\\Ubuntu\\home\\me\\a.txt"));
+        assert_eq!(t.to_wsl("D:\\Data\\b.png").as_deref(), Some("/mnt/d/Data/b.png"));
+        assert_eq!(t.to_wsl("\\\\wsl$\\Ubuntu\\home\\me").as_deref(), Some("/home/me"));
+
+        // Without a distro, Linux-only paths cannot be reached from Windows
+        assert_eq!(PathTranslator::default().to_windows("/home/me"), None);
+    }
+
+    #[test]
+    fn test_uri_list_roundtrip() {
+        let t = PathTranslator::default();
+        let list = "# comment\r\nfile:///mnt/c/My%20Files/a.txt\r\nhttp://example.com\r\n";
+
+        let paths = t.uri_list_to_windows(list);
+        assert_eq!(paths, vec!["C:\\My Files\\a.txt".to_string()]);
+
+        let back = t.windows_to_uri_list(&paths);
+        assert_eq!(back, "file:///mnt/c/My%20Files/a.txt\r\n");
+    }
+
+    #[test]
+    fn test_payload_conversion() {
+        let text = unicode_text_to_text(&text_to_unicode_text("héllo\nworld")).unwrap();
+        assert_eq!(text, "héllo\nworld");
+
+        let paths = vec!["C:\\a.txt".to_string(), "C:\\b.txt".to_string()];
+        assert_eq!(hdrop_to_paths(&paths_to_hdrop(&paths)).unwrap(), paths);
+    }
+
+    #[test]
+    fn test_selection_from_windows() {
+        let hdrop = paths_to_hdrop(&["C:\\a b.txt"]);
+        let selection = Selection::from_windows(ClipboardFormat::HDrop, &hdrop, &PathTranslator::default()).unwrap();
+
+        assert_eq!(selection.mime_types().collect::<Vec<_>>(), vec![URI_LIST_MIME]);
+        assert_eq!(selection.get(URI_LIST_MIME), Some(&b"file:///mnt/c/a%20b.txt\r\n"[..]));
+        assert_eq!(selection.get(PNG_MIME), None);
+    }
+}
@@ -0,0 +1,769 @@
+//! Color Management (wp-color-management-v1)
+//!
+//! Clients describe their content with image descriptions: a transfer
+//! function, primaries and luminances, built from parameters or taken from
+//! an output. The compositor does no color conversion itself; the
+//! description of each window's root surface goes to the renderer as a
+//! color space message, and the renderer converts when it presents.
+//!
+//! Output descriptions follow the Windows display state:
+//!
+//! - SDR monitors are described as gamma 2.2 with the primaries and peak
+//!   luminance of the monitor's ICC profile, sRGB when it has none.
+//! - With HDR on, Windows composes in scRGB (linear sRGB primaries, 1.0 at
+//!   80 cd/m²) and maps SDR white to the "SDR content brightness" level;
+//!   the monitor's own primaries and peak become the target volume.
+//!
+//! Only parametric descriptions are supported; ICC creators would need the
+//! profile file descriptor to cross the connection.
+
+use std::collections::HashMap;
+
+use crate::render::ColorSpace;
+use crate::wire::{ArgWriter, Message};
+
+/// wp_color_manager_v1.render_intent values
+pub mod render_intent {
+    pub const PERCEPTUAL: u32 = 0;
+}
+
+/// wp_color_manager_v1.feature values
+pub mod feature {
+    pub const PARAMETRIC: u32 = 1;
+    pub const SET_PRIMARIES: u32 = 2;
+    pub const SET_LUMINANCES: u32 = 4;
+    pub const SET_MASTERING_DISPLAY_PRIMARIES: u32 = 5;
+    pub const WINDOWS_SCRGB: u32 = 7;
+}
+
+/// wp_color_manager_v1.primaries values
+pub mod primaries {
+    pub const SRGB: u32 = 1;
+    pub const BT2020: u32 = 6;
+    pub const DCI_P3: u32 = 8;
+    pub const DISPLAY_P3: u32 = 9;
+    pub const ADOBE_RGB: u32 = 10;
+}
+
+/// wp_color_manager_v1.transfer_function values
+pub mod transfer_function {
+    pub const BT1886: u32 = 1;
+    pub const GAMMA22: u32 = 2;
+    pub const EXT_LINEAR: u32 = 5;
+    pub const SRGB: u32 = 9;
+    pub const ST2084_PQ: u32 = 11;
+}
+
+/// wp_color_manager_v1.error codes
+pub mod manager_error {
+    pub const UNSUPPORTED_FEATURE: u32 = 0;
+    pub const SURFACE_EXISTS: u32 = 1;
+}
+
+/// wp_color_management_surface_v1.error codes
+pub mod surface_error {
+    pub const RENDER_INTENT: u32 = 0;
+    pub const IMAGE_DESCRIPTION: u32 = 1;
+}
+
+/// wp_image_description_creator_params_v1.error codes
+pub mod params_error {
+    pub const INCOMPLETE_SET: u32 = 0;
+    pub const ALREADY_SET: u32 = 1;
+    pub const UNSUPPORTED_FEATURE: u32 = 2;
+    pub const INVALID_TF: u32 = 3;
+    pub const INVALID_PRIMARIES_NAMED: u32 = 4;
+    pub const INVALID_LUMINANCE: u32 = 5;
+}
+
+/// wp_image_description_v1.error codes
+pub mod description_error {
+    pub const NO_INFORMATION: u32 = 1;
+}
+
+/// wp_color_manager_v1 event opcodes
+pub mod manager_events {
+    pub const SUPPORTED_INTENT: u16 = 0;
+    pub const SUPPORTED_FEATURE: u16 = 1;
+    pub const SUPPORTED_TF_NAMED: u16 = 2;
+    pub const SUPPORTED_PRIMARIES_NAMED: u16 = 3;
+    pub const DONE: u16 = 4;
+}
+
+/// wp_color_management_output_v1 event opcodes
+pub mod output_events {
+    pub const IMAGE_DESCRIPTION_CHANGED: u16 = 0;
+}
+
+/// wp_color_management_surface_feedback_v1 event opcodes
+pub mod feedback_events {
+    pub const PREFERRED_CHANGED: u16 = 0;
+}
+
+/// wp_image_description_v1 event opcodes
+pub mod description_events {
+    pub const FAILED: u16 = 0;
+    pub const READY: u16 = 1;
+}
+
+/// wp_image_description_v1.cause values
+pub mod failure_cause {
+    pub const NO_OUTPUT: u32 = 3;
+}
+
+/// wp_image_description_info_v1 event opcodes
+pub mod info_events {
+    pub const DONE: u16 = 0;
+    pub const PRIMARIES: u16 = 2;
+    pub const PRIMARIES_NAMED: u16 = 3;
+    pub const TF_NAMED: u16 = 5;
+    pub const LUMINANCES: u16 = 6;
+    pub const TARGET_PRIMARIES: u16 = 7;
+    pub const TARGET_LUMINANCE: u16 = 8;
+    pub const TARGET_MAX_CLL: u16 = 9;
+    pub const TARGET_MAX_FALL: u16 = 10;
+}
+
+pub const SUPPORTED_FEATURES: [u32; 5] = [
+    feature::PARAMETRIC,
+    feature::SET_PRIMARIES,
+    feature::SET_LUMINANCES,
+    feature::SET_MASTERING_DISPLAY_PRIMARIES,
+    feature::WINDOWS_SCRGB,
+];
+
+pub const SUPPORTED_TRANSFER_FUNCTIONS: [u32; 5] = [
+    transfer_function::BT1886,
+    transfer_function::GAMMA22,
+    transfer_function::EXT_LINEAR,
+    transfer_function::SRGB,
+    transfer_function::ST2084_PQ,
+];
+
+pub const SUPPORTED_PRIMARIES: [u32; 5] = [
+    primaries::SRGB,
+    primaries::BT2020,
+    primaries::DCI_P3,
+    primaries::DISPLAY_P3,
+    primaries::ADOBE_RGB,
+];
+
+/// CIE 1931 xy chromaticities of the primaries and white point, times
+/// 1 000 000 as on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chromaticities {
+    pub red: (i32, i32),
+    pub green: (i32, i32),
+    pub blue: (i32, i32),
+    pub white: (i32, i32),
+}
+
+impl Chromaticities {
+    /// Chromaticities of a wp_color_manager_v1.primaries value
+    pub fn named(primaries: u32) -> Option<Self> {
+        const D65: (i32, i32) = (312_700, 329_000);
+        let (red, green, blue, white) = match primaries {
+            primaries::SRGB => ((640_000, 330_000), (300_000, 600_000), (150_000, 60_000), D65),
+            primaries::BT2020 => ((708_000, 292_000), (170_000, 797_000), (131_000, 46_000), D65),
+            primaries::DCI_P3 => ((680_000, 320_000), (265_000, 690_000), (150_000, 60_000), (314_000, 351_000)),
+            primaries::DISPLAY_P3 => ((680_000, 320_000), (265_000, 690_000), (150_000, 60_000), D65),
+            primaries::ADOBE_RGB => ((640_000, 330_000), (210_000, 710_000), (150_000, 60_000), D65),
+            _ => return None,
+        };
+        Some(Self { red, green, blue, white })
+    }
+
+    /// Read the eight coordinates of set_primaries and similar requests
+    pub fn from_args(values: [i32; 8]) -> Self {
+        Self {
+            red: (values[0], values[1]),
+            green: (values[2], values[3]),
+            blue: (values[4], values[5]),
+            white: (values[6], values[7]),
+        }
+    }
+
+    pub fn to_args(&self) -> [i32; 8] {
+        [
+            self.red.0, self.red.1, self.green.0, self.green.1,
+            self.blue.0, self.blue.1, self.white.0, self.white.1,
+        ]
+    }
+}
+
+/// Luminances of an image description: `min` in 0.0001 cd/m², the others
+/// in cd/m²
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Luminances {
+    pub min: u32,
+    pub max: u32,
+    pub reference: u32,
+}
+
+impl Luminances {
+    /// Luminances implied by a transfer function when none are set
+    pub fn default_for(tf: u32) -> Self {
+        match tf {
+            transfer_function::ST2084_PQ => Self { min: 50, max: 10_000, reference: 203 },
+            _ => Self { min: 2_000, max: 80, reference: 80 },
+        }
+    }
+}
+
+/// A wp_image_description_v1 ready for use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageDescription {
+    pub tf: u32,
+    pub primaries: Chromaticities,
+    /// Set when the primaries were given by name
+    pub primaries_named: Option<u32>,
+    pub luminances: Luminances,
+    /// Color volume of the mastering or target display
+    pub target_primaries: Chromaticities,
+    /// Minimum (0.0001 cd/m²) and maximum (cd/m²) target luminance
+    pub target_luminance: (u32, u32),
+    pub max_cll: Option<u32>,
+    pub max_fall: Option<u32>,
+}
+
+impl ImageDescription {
+    /// Description of `tf` and named `primaries` with default luminances
+    pub fn named(tf: u32, primaries: u32) -> Self {
+        let chromaticities = Chromaticities::named(primaries).expect("supported primaries");
+        let luminances = Luminances::default_for(tf);
+        Self {
+            tf,
+            primaries: chromaticities,
+            primaries_named: Some(primaries),
+            luminances,
+            target_primaries: chromaticities,
+            target_luminance: (luminances.min, luminances.max),
+            max_cll: None,
+            max_fall: None,
+        }
+    }
+
+    /// Content without a description is taken to be sRGB
+    pub fn srgb() -> Self {
+        Self::named(transfer_function::GAMMA22, primaries::SRGB)
+    }
+
+    /// The scRGB description of create_windows_scrgb
+    pub fn scrgb() -> Self {
+        Self {
+            luminances: Luminances { min: 0, max: 80, reference: 80 },
+            target_primaries: Chromaticities::named(primaries::BT2020).expect("supported primaries"),
+            target_luminance: (0, 10_000),
+            ..Self::named(transfer_function::EXT_LINEAR, primaries::SRGB)
+        }
+    }
+
+    /// Description of what a monitor shows
+    pub fn for_monitor(color: &MonitorColor) -> Self {
+        let native = color.primaries.unwrap_or_else(|| Chromaticities::named(primaries::SRGB).expect("supported primaries"));
+        if color.hdr {
+            let peak = color.luminance.unwrap_or(1_000);
+            return Self {
+                luminances: Luminances { min: 0, max: 80, reference: color.sdr_white },
+                target_primaries: native,
+                target_luminance: (0, peak),
+                ..Self::named(transfer_function::EXT_LINEAR, primaries::SRGB)
+            };
+        }
+        let peak = color.luminance.unwrap_or(80);
+        let mut description = Self::named(transfer_function::GAMMA22, primaries::SRGB);
+        if color.primaries.is_some() {
+            description.primaries = native;
+            description.primaries_named = None;
+        }
+        description.luminances = Luminances { min: 2_000, max: peak, reference: peak };
+        description.target_primaries = native;
+        description.target_luminance = (2_000, peak);
+        description
+    }
+
+    /// What the renderer needs to present content in this description
+    pub fn color_space(&self) -> ColorSpace {
+        ColorSpace {
+            transfer: self.tf,
+            primaries: self.primaries.to_args(),
+            min_luminance: self.luminances.min,
+            max_luminance: self.luminances.max,
+            reference_luminance: self.luminances.reference,
+        }
+    }
+}
+
+/// A protocol error raised by a wp_image_description_creator_params_v1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamsError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl ParamsError {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+/// A wp_image_description_creator_params_v1 object
+#[derive(Debug, Clone, Default)]
+pub struct DescriptionParams {
+    tf: Option<u32>,
+    primaries: Option<(Chromaticities, Option<u32>)>,
+    luminances: Option<Luminances>,
+    mastering_primaries: Option<Chromaticities>,
+    mastering_luminance: Option<(u32, u32)>,
+    max_cll: Option<u32>,
+    max_fall: Option<u32>,
+}
+
+/// Store `value` in a field that may only be set once
+fn set_once<T>(field: &mut Option<T>, value: T, what: &str) -> Result<(), ParamsError> {
+    if field.is_some() {
+        return Err(ParamsError::new(params_error::ALREADY_SET, format!("{} already set", what)));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+impl DescriptionParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_tf_named(&mut self, tf: u32) -> Result<(), ParamsError> {
+        if !SUPPORTED_TRANSFER_FUNCTIONS.contains(&tf) {
+            return Err(ParamsError::new(params_error::INVALID_TF, format!("unsupported transfer function {}", tf)));
+        }
+        set_once(&mut self.tf, tf, "transfer function")
+    }
+
+    pub fn set_primaries_named(&mut self, named: u32) -> Result<(), ParamsError> {
+        let Some(chromaticities) = Chromaticities::named(named) else {
+            let message = format!("unsupported primaries {}", named);
+            return Err(ParamsError::new(params_error::INVALID_PRIMARIES_NAMED, message));
+        };
+        set_once(&mut self.primaries, (chromaticities, Some(named)), "primaries")
+    }
+
+    pub fn set_primaries(&mut self, chromaticities: Chromaticities) -> Result<(), ParamsError> {
+        set_once(&mut self.primaries, (chromaticities, None), "primaries")
+    }
+
+    pub fn set_luminances(&mut self, luminances: Luminances) -> Result<(), ParamsError> {
+        if luminances.max as u64 * 10_000 <= luminances.min as u64
+            || luminances.reference as u64 * 10_000 <= luminances.min as u64
+        {
+            return Err(ParamsError::new(params_error::INVALID_LUMINANCE, "max and reference must exceed min"));
+        }
+        set_once(&mut self.luminances, luminances, "luminances")
+    }
+
+    pub fn set_mastering_display_primaries(&mut self, chromaticities: Chromaticities) -> Result<(), ParamsError> {
+        set_once(&mut self.mastering_primaries, chromaticities, "mastering display primaries")
+    }
+
+    pub fn set_mastering_luminance(&mut self, min: u32, max: u32) -> Result<(), ParamsError> {
+        if max as u64 * 10_000 <= min as u64 {
+            return Err(ParamsError::new(params_error::INVALID_LUMINANCE, "max must exceed min"));
+        }
+        set_once(&mut self.mastering_luminance, (min, max), "mastering luminance")
+    }
+
+    pub fn set_max_cll(&mut self, max_cll: u32) -> Result<(), ParamsError> {
+        set_once(&mut self.max_cll, max_cll, "max_cll")
+    }
+
+    pub fn set_max_fall(&mut self, max_fall: u32) -> Result<(), ParamsError> {
+        set_once(&mut self.max_fall, max_fall, "max_fall")
+    }
+
+    /// Handle create, filling in defaults for what was not set
+    pub fn build(&self) -> Result<ImageDescription, ParamsError> {
+        let (Some(tf), Some((chromaticities, named))) = (self.tf, self.primaries) else {
+            return Err(ParamsError::new(params_error::INCOMPLETE_SET, "transfer function and primaries are required"));
+        };
+        let luminances = self.luminances.unwrap_or_else(|| Luminances::default_for(tf));
+        Ok(ImageDescription {
+            tf,
+            primaries: chromaticities,
+            primaries_named: named,
+            luminances,
+            target_primaries: self.mastering_primaries.unwrap_or(chromaticities),
+            target_luminance: self.mastering_luminance.unwrap_or((luminances.min, luminances.max)),
+            max_cll: self.max_cll,
+            max_fall: self.max_fall,
+        })
+    }
+}
+
+/// Identities of image descriptions
+///
+/// Descriptions with the same parameters share an identity, so clients can
+/// tell an output change that does not affect color from one that does.
+#[derive(Debug, Default)]
+pub struct Identities {
+    known: HashMap<ImageDescription, u32>,
+}
+
+impl Identities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, description: &ImageDescription) -> u32 {
+        let next = self.known.len() as u32 + 1;
+        *self.known.entry(*description).or_insert(next)
+    }
+}
+
+/// Capabilities sent when wp_color_manager_v1 is bound
+pub fn manager_events(manager: u32) -> Vec<Message> {
+    let event = |opcode, value: u32| Message::new(manager, opcode, value.to_le_bytes().to_vec());
+    let mut events = vec![event(manager_events::SUPPORTED_INTENT, render_intent::PERCEPTUAL)];
+    events.extend(SUPPORTED_FEATURES.iter().map(|&f| event(manager_events::SUPPORTED_FEATURE, f)));
+    events.extend(SUPPORTED_TRANSFER_FUNCTIONS.iter().map(|&tf| event(manager_events::SUPPORTED_TF_NAMED, tf)));
+    events.extend(SUPPORTED_PRIMARIES.iter().map(|&p| event(manager_events::SUPPORTED_PRIMARIES_NAMED, p)));
+    events.push(Message::new(manager, manager_events::DONE, vec![]));
+    events
+}
+
+/// ready(identity)
+pub fn ready(description: u32, identity: u32) -> Message {
+    Message::new(description, description_events::READY, identity.to_le_bytes().to_vec())
+}
+
+/// failed(cause, msg)
+pub fn failed(description: u32, cause: u32, message: &str) -> Message {
+    Message::new(description, description_events::FAILED, ArgWriter::new().u32(cause).string(message).finish())
+}
+
+/// Events of a wp_image_description_info_v1, ending with done
+pub fn info_events(info: u32, description: &ImageDescription) -> Vec<Message> {
+    let chromaticities = |opcode, c: &Chromaticities| {
+        let payload = c.to_args().iter().fold(ArgWriter::new(), |args, &v| args.i32(v)).finish();
+        Message::new(info, opcode, payload)
+    };
+    let uint = |opcode, value: u32| Message::new(info, opcode, value.to_le_bytes().to_vec());
+
+    let mut events = vec![chromaticities(info_events::PRIMARIES, &description.primaries)];
+    if let Some(named) = description.primaries_named {
+        events.push(uint(info_events::PRIMARIES_NAMED, named));
+    }
+    events.push(uint(info_events::TF_NAMED, description.tf));
+    let l = description.luminances;
+    events.push(Message::new(info, info_events::LUMINANCES, ArgWriter::new().u32(l.min).u32(l.max).u32(l.reference).finish()));
+    events.push(chromaticities(info_events::TARGET_PRIMARIES, &description.target_primaries));
+    let (min, max) = description.target_luminance;
+    events.push(Message::new(info, info_events::TARGET_LUMINANCE, ArgWriter::new().u32(min).u32(max).finish()));
+    if let Some(max_cll) = description.max_cll {
+        events.push(uint(info_events::TARGET_MAX_CLL, max_cll));
+    }
+    if let Some(max_fall) = description.max_fall {
+        events.push(uint(info_events::TARGET_MAX_FALL, max_fall));
+    }
+    events.push(Message::new(info, info_events::DONE, vec![]));
+    events
+}
+
+/// Color characteristics of a monitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonitorColor {
+    /// Windows HDR ("Use HDR") is on
+    pub hdr: bool,
+    /// Luminance of SDR white while HDR is on, in cd/m²
+    pub sdr_white: u32,
+    /// Primaries from the monitor's color profile
+    pub primaries: Option<Chromaticities>,
+    /// Peak luminance from the monitor's color profile, in cd/m²
+    pub luminance: Option<u32>,
+}
+
+impl Default for MonitorColor {
+    fn default() -> Self {
+        Self { hdr: false, sdr_white: 80, primaries: None, luminance: None }
+    }
+}
+
+/// What an ICC display profile says about the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IccInfo {
+    pub primaries: Option<Chromaticities>,
+    pub luminance: Option<u32>,
+}
+
+/// Read the colorants, white point and luminance of an ICC profile
+///
+/// Colorants are stored adapted to D50; the chromatic adaptation tag, when
+/// present, takes them back to the display's own white.
+pub fn parse_icc(data: &[u8]) -> Option<IccInfo> {
+    if data.len() < 132 || &data[36..40] != b"acsp" {
+        return None;
+    }
+    let be = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+    };
+    let fixed = |offset: usize| be(offset).map(|v| v as i32 as f64 / 65536.0);
+    let tag = |signature: &[u8; 4]| -> Option<usize> {
+        (0..be(128)? as usize)
+            .map(|i| 132 + i * 12)
+            .find(|&entry| data.get(entry..entry + 4) == Some(&signature[..]))
+            .and_then(|entry| be(entry + 4))
+            .map(|offset| offset as usize)
+    };
+    let xyz = |signature: &[u8; 4]| -> Option<[f64; 3]> {
+        let offset = tag(signature)?;
+        if data.get(offset..offset + 4)? != b"XYZ " {
+            return None;
+        }
+        Some([fixed(offset + 8)?, fixed(offset + 12)?, fixed(offset + 16)?])
+    };
+
+    let luminance = xyz(b"lumi").map(|[_, y, _]| y.round() as u32).filter(|&l| l > 0);
+    let colorants = (|| Some([xyz(b"rXYZ")?, xyz(b"gXYZ")?, xyz(b"bXYZ")?]))();
+    let primaries = colorants.and_then(|colorants| {
+        const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+        let adaptation = tag(b"chad")
+            .filter(|&offset| data.get(offset..offset + 4) == Some(&b"sf32"[..]))
+            .and_then(|offset| {
+                let m: Option<Vec<f64>> = (0..9).map(|i| fixed(offset + 8 + i * 4)).collect();
+                invert(m?.try_into().ok()?)
+            });
+        let (colorants, white) = match adaptation {
+            Some(inverse) => (colorants.map(|c| apply(&inverse, c)), apply(&inverse, D50)),
+            None => (colorants, xyz(b"wtpt").unwrap_or(D50)),
+        };
+        Some(Chromaticities {
+            red: chromaticity(colorants[0])?,
+            green: chromaticity(colorants[1])?,
+            blue: chromaticity(colorants[2])?,
+            white: chromaticity(white)?,
+        })
+    });
+    Some(IccInfo { primaries, luminance })
+}
+
+/// xy chromaticity of an XYZ color, times 1 000 000
+fn chromaticity([x, y, z]: [f64; 3]) -> Option<(i32, i32)> {
+    let sum = x + y + z;
+    (sum > 0.0).then(|| ((x / sum * 1e6).round() as i32, (y / sum * 1e6).round() as i32))
+}
+
+fn apply(m: &[f64; 9], v: [f64; 3]) -> [f64; 3] {
+    [
+        m[0] * v[0] + m[1] * v[1] + m[2] * v[2],
+        m[3] * v[0] + m[4] * v[1] + m[5] * v[2],
+        m[6] * v[0] + m[7] * v[1] + m[8] * v[2],
+    ]
+}
+
+/// Inverse of a row-major 3x3 matrix
+fn invert(m: [f64; 9]) -> Option<[f64; 9]> {
+    let cofactor = |a: usize, b: usize, c: usize, d: usize| m[a] * m[d] - m[b] * m[c];
+    let adjugate = [
+        cofactor(4, 5, 7, 8), -cofactor(1, 2, 7, 8), cofactor(1, 2, 4, 5),
+        -cofactor(3, 5, 6, 8), cofactor(0, 2, 6, 8), -cofactor(0, 2, 3, 5),
+        cofactor(3, 4, 6, 7), -cofactor(0, 1, 6, 7), cofactor(0, 1, 3, 4),
+    ];
+    let determinant = m[0] * adjugate[0] + m[1] * adjugate[3] + m[2] * adjugate[6];
+    (determinant.abs() > f64::EPSILON).then(|| adjugate.map(|v| v / determinant))
+}
+
+/// Color characteristics of the monitor with GDI device name `device`
+pub fn monitor_color(device: &str) -> MonitorColor {
+    platform::monitor_color(device)
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{parse_icc, MonitorColor};
+
+    use windows_sys::Win32::Devices::Display::{
+        DisplayConfigGetDeviceInfo, GetDisplayConfigBufferSizes, QueryDisplayConfig,
+        DISPLAYCONFIG_DEVICE_INFO_GET_ADVANCED_COLOR_INFO, DISPLAYCONFIG_DEVICE_INFO_GET_SDR_WHITE_LEVEL,
+        DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME, DISPLAYCONFIG_DEVICE_INFO_HEADER, DISPLAYCONFIG_DEVICE_INFO_TYPE,
+        DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO, DISPLAYCONFIG_MODE_INFO, DISPLAYCONFIG_PATH_INFO,
+        DISPLAYCONFIG_SDR_WHITE_LEVEL, DISPLAYCONFIG_SOURCE_DEVICE_NAME, QDC_ONLY_ACTIVE_PATHS,
+    };
+    use windows_sys::Win32::Foundation::LUID;
+    use windows_sys::Win32::Graphics::Gdi::{CreateDCW, DeleteDC};
+    use windows_sys::Win32::UI::ColorSystem::GetICMProfileW;
+
+    /// advancedColorEnabled bit of DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO
+    const ADVANCED_COLOR_ENABLED: u32 = 1 << 1;
+
+    pub fn monitor_color(device: &str) -> MonitorColor {
+        let mut color = MonitorColor::default();
+        if let Some(icc) = profile_path(device).and_then(|path| std::fs::read(path).ok()).and_then(|data| parse_icc(&data)) {
+            color.primaries = icc.primaries;
+            color.luminance = icc.luminance;
+        }
+        if let Some((hdr, sdr_white)) = advanced_color(device) {
+            color.hdr = hdr;
+            color.sdr_white = sdr_white;
+        }
+        color
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    fn from_wide(s: &[u16]) -> String {
+        String::from_utf16_lossy(&s[..s.iter().position(|&c| c == 0).unwrap_or(s.len())])
+    }
+
+    /// Path of the ICC profile Windows uses for the monitor
+    fn profile_path(device: &str) -> Option<String> {
+        let device = wide(device);
+        // SAFETY: the DC is deleted before returning and the buffer size is passed in
+        unsafe {
+            let dc = CreateDCW(device.as_ptr(), device.as_ptr(), std::ptr::null(), std::ptr::null());
+            if dc.is_null() {
+                return None;
+            }
+            let mut path = [0u16; 260];
+            let mut len = path.len() as u32;
+            let found = GetICMProfileW(dc, &mut len, path.as_mut_ptr()) != 0;
+            DeleteDC(dc);
+            found.then(|| from_wide(&path))
+        }
+    }
+
+    fn header<T>(kind: DISPLAYCONFIG_DEVICE_INFO_TYPE, adapter: LUID, id: u32) -> DISPLAYCONFIG_DEVICE_INFO_HEADER {
+        DISPLAYCONFIG_DEVICE_INFO_HEADER { r#type: kind, size: std::mem::size_of::<T>() as u32, adapterId: adapter, id }
+    }
+
+    /// Whether HDR is on for the monitor, and the SDR white level
+    fn advanced_color(device: &str) -> Option<(bool, u32)> {
+        // SAFETY: buffers are sized by GetDisplayConfigBufferSizes and every
+        // request packet is zero-initialised with its header filled in
+        unsafe {
+            let (mut path_count, mut mode_count) = (0u32, 0u32);
+            if GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut path_count, &mut mode_count) != 0 {
+                return None;
+            }
+            let mut paths: Vec<DISPLAYCONFIG_PATH_INFO> = vec![std::mem::zeroed(); path_count as usize];
+            let mut modes: Vec<DISPLAYCONFIG_MODE_INFO> = vec![std::mem::zeroed(); mode_count as usize];
+            let queried = QueryDisplayConfig(
+                QDC_ONLY_ACTIVE_PATHS,
+                &mut path_count,
+                paths.as_mut_ptr(),
+                &mut mode_count,
+                modes.as_mut_ptr(),
+                std::ptr::null_mut(),
+            );
+            if queried != 0 {
+                return None;
+            }
+            paths.truncate(path_count as usize);
+
+            for path in &paths {
+                let mut source: DISPLAYCONFIG_SOURCE_DEVICE_NAME = std::mem::zeroed();
+                source.header = header::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>(
+                    DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME, path.sourceInfo.adapterId, path.sourceInfo.id,
+                );
+                if DisplayConfigGetDeviceInfo(&mut source.header) != 0 || from_wide(&source.viewGdiDeviceName) != device {
+                    continue;
+                }
+
+                let target = (path.targetInfo.adapterId, path.targetInfo.id);
+                let mut info: DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO = std::mem::zeroed();
+                info.header = header::<DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO>(
+                    DISPLAYCONFIG_DEVICE_INFO_GET_ADVANCED_COLOR_INFO, target.0, target.1,
+                );
+                if DisplayConfigGetDeviceInfo(&mut info.header) != 0 {
+                    return None;
+                }
+                let hdr = info.Anonymous.value & ADVANCED_COLOR_ENABLED != 0;
+
+                let mut white: DISPLAYCONFIG_SDR_WHITE_LEVEL = std::mem::zeroed();
+                white.header = header::<DISPLAYCONFIG_SDR_WHITE_LEVEL>(
+                    DISPLAYCONFIG_DEVICE_INFO_GET_SDR_WHITE_LEVEL, target.0, target.1,
+                );
+                // SDRWhiteLevel is in thousandths of 80 cd/m²
+                let sdr_white = if DisplayConfigGetDeviceInfo(&mut white.header) == 0 {
+                    white.SDRWhiteLevel * 80 / 1000
+                } else {
+                    80
+                };
+                return Some((hdr, sdr_white));
+            }
+            None
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::MonitorColor;
+
+    /// Without Win32 every monitor is an sRGB SDR display
+    pub fn monitor_color(_device: &str) -> MonitorColor {
+        MonitorColor::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal display profile with the given XYZ tags
+    fn profile(tags: &[(&[u8; 4], [f64; 3])]) -> Vec<u8> {
+        let mut data = vec![0u8; 128];
+        data[36..40].copy_from_slice(b"acsp");
+        data.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let table_end = 132 + tags.len() * 12;
+        for (i, (signature, _)) in tags.iter().enumerate() {
+            data.extend_from_slice(&signature[..]);
+            data.extend_from_slice(&((table_end + i * 20) as u32).to_be_bytes());
+            data.extend_from_slice(&20u32.to_be_bytes());
+        }
+        for (_, xyz) in tags {
+            data.extend_from_slice(b"XYZ \0\0\0\0");
+            for v in xyz {
+                data.extend_from_slice(&((v * 65536.0).round() as i32).to_be_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_icc_primaries_and_luminance() {
+        let data = profile(&[
+            (b"rXYZ", [0.64, 0.33, 0.03]),
+            (b"gXYZ", [0.265, 0.69, 0.045]),
+            (b"bXYZ", [0.15, 0.06, 0.79]),
+            (b"wtpt", [0.3127, 0.329, 0.3583]),
+            (b"lumi", [0.0, 350.0, 0.0]),
+        ]);
+        let info = parse_icc(&data).unwrap();
+        let primaries = info.primaries.unwrap();
+        let close = |(x, y): (i32, i32), (ex, ey): (i32, i32)| (x - ex).abs() < 50 && (y - ey).abs() < 50;
+        assert!(close(primaries.red, (640_000, 330_000)));
+        assert!(close(primaries.green, (265_000, 690_000)));
+        assert!(close(primaries.white, (312_700, 329_000)));
+        assert_eq!(info.luminance, Some(350));
+
+        assert_eq!(parse_icc(&data[..100]), None);
+    }
+
+    #[test]
+    fn test_params_validation() {
+        let mut params = DescriptionParams::new();
+        assert_eq!(params.build().unwrap_err().code, params_error::INCOMPLETE_SET);
+        params.set_tf_named(transfer_function::ST2084_PQ).unwrap();
+        assert_eq!(params.set_tf_named(transfer_function::GAMMA22).unwrap_err().code, params_error::ALREADY_SET);
+        assert_eq!(params.set_primaries_named(42).unwrap_err().code, params_error::INVALID_PRIMARIES_NAMED);
+        params.set_primaries_named(primaries::BT2020).unwrap();
+
+        let description = params.build().unwrap();
+        assert_eq!(description.luminances, Luminances::default_for(transfer_function::ST2084_PQ));
+        assert_eq!(description.target_primaries, Chromaticities::named(primaries::BT2020).unwrap());
+
+        let invalid = Luminances { min: 10_000, max: 1, reference: 80 };
+        assert_eq!(params.set_luminances(invalid).unwrap_err().code, params_error::INVALID_LUMINANCE);
+    }
+}
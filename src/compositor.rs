@@ -4,21 +4,379 @@
 //! allow clients to connect and discover available interfaces.
 //!
 //! This is the missing piece that makes winpipe act as a real compositor.
+//!
+//! Handlers run synchronously and return their events right away. A
+//! handler that has to wait for I/O (a screen capture, the renderer, the
+//! Windows clipboard) starts a `Task` instead: the connection loop drives
+//! it alongside decoding and applies its `Completion` with
+//! `Compositor::complete` once it finishes.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use log::{info, debug, warn};
 
-use crate::wire::{Message, WireEncoder};
-use crate::error::Result;
+use crate::buffer::{BufferDelta, BufferManager, MirrorBuffer};
+use crate::clipboard::Selection;
+use crate::dmabuf::{self, BufferParams, Plane};
+use crate::gestures;
+use crate::capture::{self, CaptureFrame, Image};
+use crate::convert;
+use crate::color::{self, Chromaticities, DescriptionParams, Identities, ImageDescription, Luminances, ParamsError};
+use crate::foreign_toplevel::{self, ForeignToplevel};
+use crate::hooks::CompositorHooks;
+use crate::idle::IdleNotification;
+use crate::introspect::{SurfaceInfo, ToplevelInfo};
+use crate::release::BufferTracker;
+use crate::objects::{ObjectInfo, ObjectTable};
+use crate::output::{Monitor, Transform};
+use crate::overlay::OverlayStats;
+use crate::pipe::{self, VirtualFdQueue, CONTROL_INTERFACE, CONTROL_OBJECT_ID};
+use crate::pointer;
+use crate::pointer_constraints::{self, Constraint, ConstraintKind};
+use crate::positioner::{Edge, Positioner, Rect};
+use crate::presentation;
+use crate::region::Region;
+use crate::render::{
+    constraint_kind, content_type, gesture_kind, window_state, ColorSpace, CursorUpdate, FrameUpdate, ImeState, InputRegion, InteractiveOp,
+    PointerConstraint, PenSample, PixelFormat, PresentationHint, RenderFrame, RenderMessage, RendererCapabilities, RendererEvent, ShortcutsInhibit,
+    StatsOverlay, WindowIcon, WindowInfo, WindowOpacity, HEADER_SIZE,
+};
+use crate::configure::{xdg_surface_error, ConfigureQueue, ToplevelConfigure};
+use crate::scheduling::{
+    self, commit_timer_error, commit_timing_manager_error, fifo_error, fifo_manager_error, CommitQueue, ContentUpdate,
+};
+use crate::surface::{surface_size, BufferView, SurfaceTree, MAX_SURFACE_SIZE};
+use crate::syncobj::{self, SurfaceSync, SyncPoint};
+use crate::tablet::TabletSeat;
+use crate::text_input::{self, TextInput};
+use crate::toplevel_icon::{self, toplevel_icon_error, ToplevelIcon};
+use crate::wire::{event_since, opcodes, ArgReader, ArgWriter, Message, WireEncoder};
+
+/// Size of the fallback virtual output, also the initial floating window size
+pub const OUTPUT_WIDTH: i32 = 1920;
+pub const OUTPUT_HEIGHT: i32 = 1080;
+
+/// xdg_toplevel.state values used in configure events
+pub mod toplevel_state {
+    pub const MAXIMIZED: u32 = 1;
+    pub const FULLSCREEN: u32 = 2;
+    pub const RESIZING: u32 = 3;
+    pub const ACTIVATED: u32 = 4;
+}
+
+/// wl_seat.capability bits
+pub mod seat_capability {
+    pub const POINTER: u32 = 1;
+    pub const KEYBOARD: u32 = 2;
+    pub const TOUCH: u32 = 4;
+}
+
+/// zwlr_screencopy_frame_v1.error codes
+pub mod screencopy_error {
+    pub const ALREADY_USED: u32 = 0;
+    pub const INVALID_BUFFER: u32 = 1;
+}
+
+/// Work a request handler started that finishes later
+pub type Task = Pin<Box<dyn Future<Output = Completion> + Send>>;
+
+/// Applies the outcome of a `Task`, returning events for the client
+pub type Completion = Box<dyn FnOnce(&mut Compositor) -> Vec<Message> + Send>;
+
+/// wl_shm.error codes
+pub mod shm_error {
+    pub const INVALID_FORMAT: u32 = 0;
+    pub const INVALID_STRIDE: u32 = 1;
+    pub const INVALID_FD: u32 = 2;
+}
+
+/// wl_surface.error codes
+pub mod surface_error {
+    pub const INVALID_SCALE: u32 = 0;
+    pub const INVALID_TRANSFORM: u32 = 1;
+}
+
+/// wp_viewport.error codes
+pub mod viewport_error {
+    pub const BAD_VALUE: u32 = 0;
+}
+
+/// wp_alpha_modifier_v1.error codes
+pub mod alpha_modifier_error {
+    pub const ALREADY_CONSTRUCTED: u32 = 0;
+}
+
+/// wp_alpha_modifier_surface_v1.error codes
+pub mod alpha_modifier_surface_error {
+    pub const NO_SURFACE: u32 = 0;
+}
+
+/// wl_display.error codes
+pub mod display_error {
+    pub const INVALID_OBJECT: u32 = 0;
+    pub const INVALID_METHOD: u32 = 1;
+    pub const NO_MEMORY: u32 = 2;
+    pub const IMPLEMENTATION: u32 = 3;
+}
+
+/// wl_shm.format values
+pub mod shm_format {
+    pub const ARGB8888: u32 = 0;
+    pub const XRGB8888: u32 = 1;
+    /// 'RG16'
+    pub const RGB565: u32 = 0x3631_4752;
+    /// 'AB24'
+    pub const ABGR8888: u32 = 0x3432_4241;
+    /// 'XB24'
+    pub const XBGR8888: u32 = 0x3432_4258;
+    /// 'XB30'
+    pub const XBGR2101010: u32 = 0x3033_4258;
+    /// 'XR30'
+    pub const XRGB2101010: u32 = 0x3033_5258;
+    /// 'AB4H': half floats
+    pub const ABGR16161616F: u32 = 0x4834_4241;
+
+    /// Formats every wl_shm supports; always advertised
+    pub const REQUIRED: [u32; 2] = [ARGB8888, XRGB8888];
+
+    /// Format by its lowercase name, e.g. "rgb565"
+    pub fn from_name(name: &str) -> Option<u32> {
+        match name {
+            "argb8888" => Some(ARGB8888),
+            "xrgb8888" => Some(XRGB8888),
+            "rgb565" => Some(RGB565),
+            "abgr8888" => Some(ABGR8888),
+            "xbgr8888" => Some(XBGR8888),
+            "xbgr2101010" => Some(XBGR2101010),
+            "xrgb2101010" => Some(XRGB2101010),
+            "abgr16161616f" => Some(ABGR16161616F),
+            _ => None,
+        }
+    }
+
+    /// Size of one pixel
+    pub fn bytes_per_pixel(format: u32) -> Option<u32> {
+        match format {
+            ARGB8888 | XRGB8888 | ABGR8888 | XBGR8888 | XBGR2101010 | XRGB2101010 => Some(4),
+            RGB565 => Some(2),
+            ABGR16161616F => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// zxdg_toplevel_decoration_v1.mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum DecorationMode {
+    /// The client draws its own title bar and borders
+    ClientSide = 1,
+    /// The native Windows frame is used
+    #[default]
+    ServerSide = 2,
+}
+
+impl DecorationMode {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::ClientSide),
+            2 => Some(Self::ServerSide),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for DecorationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Self::ClientSide),
+            "server" => Ok(Self::ServerSide),
+            other => Err(format!("unknown decoration mode '{}' (expected client or server)", other)),
+        }
+    }
+}
+
+/// Globals in registration order with the highest implemented version;
+/// wl_output is registered once per monitor at its position
+pub const GLOBALS: &[(&str, u32)] = &[
+    ("wl_compositor", 6),
+    ("wl_subcompositor", 1),
+    ("wl_shm", 1),
+    ("wl_output", 4),
+    ("wl_seat", 8),
+    ("wl_data_device_manager", 3),
+    ("xdg_wm_base", 5),
+    ("zxdg_decoration_manager_v1", 1),
+    ("wp_viewporter", 1),
+    ("wp_presentation", 1),
+    ("wp_single_pixel_buffer_manager_v1", 1),
+    ("wp_tearing_control_manager_v1", 1),
+    ("wp_content_type_manager_v1", 1),
+    ("wp_fractional_scale_manager_v1", 1),
+    ("wp_cursor_shape_manager_v1", 1),
+    ("zwp_text_input_manager_v3", 1),
+    ("zwp_relative_pointer_manager_v1", 1),
+    ("zwp_pointer_constraints_v1", 1),
+    ("zwp_keyboard_shortcuts_inhibit_manager_v1", 1),
+    ("zwp_pointer_gestures_v1", 3),
+    ("zwp_tablet_manager_v2", 1),
+    ("zwp_linux_dmabuf_v1", 4),
+    ("wp_linux_drm_syncobj_manager_v1", 1),
+    ("ext_idle_notifier_v1", 2),
+    ("ext_foreign_toplevel_list_v1", 1),
+    ("zwlr_screencopy_manager_v1", 3),
+    ("xdg_toplevel_icon_manager_v1", 1),
+    ("wp_color_manager_v1", 1),
+    ("wp_alpha_modifier_v1", 1),
+    ("wp_fifo_manager_v1", 1),
+    ("wp_commit_timing_manager_v1", 1),
+];
+
+/// Globals only advertised when enabled explicitly. They need the GPU path
+/// of the WSL-side helper, and clients that see them stop using shm.
+pub const OPT_IN_GLOBALS: &[&str] = &["zwp_linux_dmabuf_v1", "wp_linux_drm_syncobj_manager_v1"];
+
+/// Globals no client can work without; they cannot be disabled
+pub const REQUIRED_GLOBALS: &[&str] = &["wl_compositor", "wl_shm", "wl_output", "wl_seat", "xdg_wm_base"];
+
+/// A global named on the command line as `NAME[:VERSION]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalSpec {
+    pub interface: String,
+    /// Highest version to advertise (None = everything implemented)
+    pub version: Option<u32>,
+}
+
+impl std::str::FromStr for GlobalSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (interface, version) = match s.split_once(':') {
+            Some((interface, version)) => {
+                let version = version.parse::<u32>()
+                    .ok()
+                    .filter(|&v| v >= 1)
+                    .ok_or_else(|| format!("invalid version '{}' for global {}", version, interface))?;
+                (interface, Some(version))
+            }
+            None => (s, None),
+        };
+        let Some(&(_, max_version)) = GLOBALS.iter().find(|(name, _)| *name == interface) else {
+            return Err(format!("unknown global '{}'", interface));
+        };
+        if version.is_some_and(|v| v > max_version) {
+            return Err(format!("{} is only implemented up to version {}", interface, max_version));
+        }
+        Ok(Self { interface: interface.to_string(), version })
+    }
+}
+
+/// Which globals a compositor advertises, and at which version
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GlobalConfig {
+    /// Explicitly enabled globals and their version caps
+    enabled: HashMap<String, Option<u32>>,
+    disabled: HashSet<String>,
+    /// wl_shm formats advertised on top of `shm_format::REQUIRED`
+    shm_formats: Vec<u32>,
+}
+
+impl GlobalConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertise a global (including opt-in ones), capping its version if
+    /// the spec has one
+    pub fn enable(&mut self, spec: GlobalSpec) {
+        self.disabled.remove(&spec.interface);
+        self.enabled.insert(spec.interface, spec.version);
+    }
+
+    /// Stop advertising a global
+    pub fn disable(&mut self, interface: &str) -> Result<(), String> {
+        if REQUIRED_GLOBALS.contains(&interface) {
+            return Err(format!("{} is required and cannot be disabled", interface));
+        }
+        self.enabled.remove(interface);
+        self.disabled.insert(interface.to_string());
+        Ok(())
+    }
+
+    /// Also advertise a wl_shm format
+    pub fn add_shm_format(&mut self, format: u32) {
+        if !self.shm_formats.contains(&format) && !shm_format::REQUIRED.contains(&format) {
+            self.shm_formats.push(format);
+        }
+    }
+
+    /// wl_shm formats to advertise, required ones first
+    pub fn shm_formats(&self) -> Vec<u32> {
+        shm_format::REQUIRED.iter().chain(&self.shm_formats).copied().collect()
+    }
+
+    /// Version to advertise a global at, or `None` to leave it out
+    pub fn version(&self, interface: &str, max_version: u32) -> Option<u32> {
+        if self.disabled.contains(interface) {
+            return None;
+        }
+        match self.enabled.get(interface) {
+            Some(cap) => Some(cap.map_or(max_version, |v| v.min(max_version))),
+            None if OPT_IN_GLOBALS.contains(&interface) => None,
+            None => Some(max_version),
+        }
+    }
+}
+
+/// Per-client resource limits
+///
+/// A client exceeding any of them gets a wl_display.error (no_memory) and
+/// is disconnected, so one client cannot exhaust the server's memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Live protocol objects, including server-created ones
+    pub max_objects: usize,
+    /// Live wl_surfaces
+    pub max_surfaces: usize,
+    /// Bytes of mirrored buffer contents
+    pub max_buffer_memory: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_objects: 65536,
+            max_surfaces: 1024,
+            max_buffer_memory: 1 << 30,
+        }
+    }
+}
+
+/// Frame deltas sent between checksums of the renderer's frame
+pub const CHECKSUM_INTERVAL: u32 = 120;
 
-/// Object ID allocator
+/// Sent frames remembered for measuring presentation latency
+pub const MAX_UNACKED_FRAMES: usize = 64;
+
+/// Frame interval when the monitor reports no refresh rate (60 Hz)
+pub const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+
+/// First object ID in the server-allocated range
+pub const SERVER_ID_START: u32 = 0xff00_0000;
+
+/// Object ID allocator for server-created objects (e.g. wl_data_offer)
 pub struct ObjectAllocator {
     next_id: u32,
 }
 
 impl ObjectAllocator {
     pub fn new() -> Self {
-        Self { next_id: 2 } // 1 is reserved for wl_display
+        Self { next_id: SERVER_ID_START }
     }
 
     pub fn alloc(&mut self) -> u32 {
@@ -42,49 +400,629 @@ pub struct Global {
     pub version: u32,
 }
 
+/// State of an xdg_toplevel
+#[derive(Debug, Clone, Default)]
+pub struct Toplevel {
+    /// The xdg_surface this toplevel role is assigned to
+    pub xdg_surface: u32,
+    /// Window title from set_title
+    pub title: String,
+    /// Application ID from set_app_id
+    pub app_id: String,
+    /// Minimum size from set_min_size (0 = unconstrained)
+    pub min_size: (i32, i32),
+    /// Maximum size from set_max_size (0 = unconstrained)
+    pub max_size: (i32, i32),
+    /// Size to use when neither maximized nor fullscreen
+    pub floating_size: (i32, i32),
+    pub maximized: bool,
+    pub fullscreen: bool,
+    pub minimized: bool,
+    /// An interactive resize is in progress
+    pub resizing: bool,
+    /// Negotiated decoration mode, if the client uses xdg-decoration
+    pub decoration: Option<DecorationMode>,
+    /// State the client acknowledged and committed; `maximized` and
+    /// `fullscreen` above are what the compositor asks for
+    pub applied: ToplevelConfigure,
+}
+
+impl Toplevel {
+    pub fn new(xdg_surface: u32) -> Self {
+        Self {
+            xdg_surface,
+            floating_size: (OUTPUT_WIDTH, OUTPUT_HEIGHT),
+            ..Default::default()
+        }
+    }
+
+    /// Size to put in the next configure event
+    ///
+    /// `output_size` is the size of the output a maximized or fullscreen
+    /// window fills.
+    pub fn configure_size(&self, output_size: (i32, i32)) -> (i32, i32) {
+        if self.fullscreen || self.maximized {
+            return output_size;
+        }
+
+        let clamp = |value: i32, min: i32, max: i32| {
+            let value = if max > 0 { value.min(max) } else { value };
+            value.max(min)
+        };
+        (
+            clamp(self.floating_size.0, self.min_size.0, self.max_size.0),
+            clamp(self.floating_size.1, self.min_size.1, self.max_size.1),
+        )
+    }
+
+    /// xdg_toplevel.state values for the next configure event
+    pub fn configure_states(&self) -> Vec<u32> {
+        let mut states = Vec::new();
+        if self.maximized {
+            states.push(toplevel_state::MAXIMIZED);
+        }
+        if self.fullscreen {
+            states.push(toplevel_state::FULLSCREEN);
+        }
+        if self.resizing {
+            states.push(toplevel_state::RESIZING);
+        }
+        states.push(toplevel_state::ACTIVATED);
+        states
+    }
+
+    /// Window metadata for the renderer, with the applied state
+    pub fn window_info(&self) -> WindowInfo {
+        let mut state = 0;
+        if self.applied.maximized {
+            state |= window_state::MAXIMIZED;
+        }
+        if self.applied.fullscreen {
+            state |= window_state::FULLSCREEN;
+        }
+        if self.minimized {
+            state |= window_state::MINIMIZED;
+        }
+        // Toplevels that never negotiate keep the native frame
+        if self.decoration == Some(DecorationMode::ClientSide) {
+            state |= window_state::CLIENT_DECORATED;
+        }
+
+        WindowInfo {
+            title: self.title.clone(),
+            app_id: self.app_id.clone(),
+            min_width: self.min_size.0,
+            min_height: self.min_size.1,
+            max_width: self.max_size.0,
+            max_height: self.max_size.1,
+            state,
+        }
+    }
+}
+
+/// State of a mapped xdg_popup
+#[derive(Debug, Clone)]
+pub struct Popup {
+    /// The xdg_surface this popup role is assigned to
+    pub xdg_surface: u32,
+    /// Parent xdg_surface (toplevel or another popup)
+    pub parent: u32,
+    /// Geometry relative to the parent surface
+    pub geometry: Rect,
+    /// Whether the popup holds an explicit grab (menus)
+    pub grabbed: bool,
+}
+
+/// Renderer-side state of a toplevel's window
+#[derive(Default)]
+struct RendererWindow {
+    /// Last full frame the renderer has, with its format; frames of the
+    /// same size are sent as deltas against it
+    sent_frame: Option<(PixelFormat, MirrorBuffer)>,
+    /// Deltas sent since the last full frame or checksum
+    deltas_since_checksum: u32,
+    /// Sequence number of the last frame or frame delta
+    frame_sequence: u64,
+    /// Sequence and send time of frames the renderer has not acknowledged
+    unacked_frames: VecDeque<(u64, Instant)>,
+    /// The user is moving or resizing the native window
+    native_resize: bool,
+}
+
 /// Wayland compositor state
 pub struct Compositor {
     /// Registered globals
     globals: Vec<Global>,
-    /// Object ID to interface mapping
-    objects: HashMap<u32, String>,
+    /// Live objects and their interfaces
+    objects: ObjectTable,
     /// ID allocator
     allocator: ObjectAllocator,
     /// Encoder for responses
     encoder: WireEncoder,
     /// Next global name
     next_global_name: u32,
+    /// Virtual pipe fds announced by the WSL-side helper
+    pipes: VirtualFdQueue,
+    /// Bound wl_data_device objects
+    data_devices: Vec<u32>,
+    /// Current clipboard selection
+    selection: Option<Arc<Selection>>,
+    /// Live wl_data_offer objects and the selection they describe
+    data_offers: HashMap<u32, Arc<Selection>>,
+    /// xdg_positioner objects
+    positioners: HashMap<u32, Positioner>,
+    /// xdg_popup objects, in creation order
+    popups: Vec<(u32, Popup)>,
+    /// Next configure serial
+    next_serial: u32,
+    /// xdg_surface -> configures not yet applied by a commit
+    configures: HashMap<u32, ConfigureQueue>,
+    /// xdg_toplevel objects
+    toplevels: HashMap<u32, Toplevel>,
+    /// Messages waiting to be forwarded to the renderer, with the renderer
+    /// window (toplevel root wl_surface) each is addressed to
+    render_queue: Vec<(u32, RenderMessage)>,
+    /// Renderer windows of toplevels that went away since the last take
+    closed_windows: Vec<u32>,
+    /// Renderer-side state of each toplevel's window, by root wl_surface
+    windows: HashMap<u32, RendererWindow>,
+    /// Frames sent since the renderer last presented; 0 = no limit
+    max_frames_in_flight: u32,
+    frames_in_flight: u32,
+    /// The renderer acknowledges frames, so acks rather than vblanks tell
+    /// how many are in flight
+    frame_acks: bool,
+    /// Smoothed time from sending a frame to its ack
+    presentation_latency: Option<Duration>,
+    /// Toplevels whose frames were held back while the renderer caught up
+    /// or until their next refresh
+    skipped_frames: HashSet<u32>,
+    /// Frames are paced to the monitor refresh, capped further at the
+    /// given rate unless 0; None sends every commit's frame
+    frame_pacing: Option<u32>,
+    /// When each toplevel's last frame went out
+    last_frame_sent: HashMap<u32, Instant>,
+    /// Frames counted for the stats overlay while it is on
+    overlay_stats: Option<OverlayStats>,
+    /// What the renderer can show
+    renderer_capabilities: RendererCapabilities,
+    /// wl_surface state and subsurface tree
+    surfaces: SurfaceTree,
+    /// Mirrored wl_buffer contents
+    buffers: BufferManager,
+    /// wl_buffers held by surfaces, and their ages
+    buffer_tracker: BufferTracker,
+    /// wl_shm format of each wl_buffer
+    buffer_formats: HashMap<u32, u32>,
+    /// xdg_surface -> wl_surface
+    xdg_surfaces: HashMap<u32, u32>,
+    /// wp_viewport -> wl_surface
+    viewports: HashMap<u32, u32>,
+    /// wl_region objects
+    regions: HashMap<u32, Region>,
+    /// Input region last forwarded to the renderer, per root wl_surface
+    sent_input_regions: HashMap<u32, Option<Region>>,
+    /// wp_tearing_control_v1 and wp_content_type_v1 objects -> wl_surface
+    hint_objects: HashMap<u32, u32>,
+    /// Presentation hints last forwarded to the renderer, by toplevel wl_surface
+    sent_presentation_hints: HashMap<u32, PresentationHint>,
+    /// Advertised monitors by wl_output global name, primary first
+    outputs: Vec<(u32, Monitor)>,
+    /// Bound wl_registry objects
+    registries: Vec<u32>,
+    /// Bound wl_output objects -> (global name, bound version)
+    output_objects: HashMap<u32, (u32, u32)>,
+    /// wl_output objects each mapped toplevel wl_surface has entered
+    surface_outputs: HashMap<u32, Vec<u32>>,
+    /// Bound version of objects created through wl_registry.bind, and of
+    /// wl_surfaces (inherited from their wl_compositor)
+    versions: HashMap<u32, u32>,
+    /// wp_fractional_scale_v1 -> wl_surface
+    fractional_scales: HashMap<u32, u32>,
+    /// Scale last sent to each wl_surface (preferred_buffer_scale) or
+    /// wp_fractional_scale_v1 (preferred_scale)
+    sent_scales: HashMap<u32, u32>,
+    /// Decoration mode answered to every zxdg_toplevel_decoration_v1
+    decoration_mode: DecorationMode,
+    /// zxdg_toplevel_decoration_v1 -> xdg_toplevel
+    decorations: HashMap<u32, u32>,
+    /// wl_surface with the cursor role and its hotspot
+    cursor_surface: Option<(u32, (i32, i32))>,
+    /// Cursor last forwarded to the renderer
+    sent_cursor: Option<(u32, CursorUpdate)>,
+    /// Toplevel wl_surface with keyboard focus
+    keyboard_focus: Option<u32>,
+    /// wl_surface the wl_pointers last entered
+    pointer_focus: Option<u32>,
+    /// zwp_text_input_v3 objects
+    text_inputs: HashMap<u32, TextInput>,
+    /// IME state last forwarded to the renderer
+    sent_ime: ImeState,
+    /// Whether the Windows window has focus
+    window_focused: bool,
+    /// zwp_relative_pointer_v1 objects
+    relative_pointers: Vec<u32>,
+    /// zwp_locked_pointer_v1 and zwp_confined_pointer_v1 objects
+    constraints: HashMap<u32, Constraint>,
+    /// Pointer constraint last forwarded to the renderer
+    sent_constraint: Option<PointerConstraint>,
+    /// Cursor position hint of the active lock last forwarded to the renderer
+    sent_cursor_position: Option<(i32, i32)>,
+    /// zwp_keyboard_shortcuts_inhibitor_v1 -> (wl_surface, active)
+    shortcut_inhibitors: HashMap<u32, (u32, bool)>,
+    /// Shortcuts inhibit state last forwarded to the renderer
+    sent_shortcuts_inhibit: ShortcutsInhibit,
+    /// zwp_pointer_gesture_{swipe,pinch,hold}_v1 objects -> gesture kind
+    gestures: HashMap<u32, u32>,
+    /// Kind of the touchpad gesture in progress, if clients saw it begin
+    active_gesture: Option<u32>,
+    /// zwp_tablet_seat_v2 objects -> their tools and the last pen sample
+    /// they were sent (in surface coordinates)
+    tablet_seats: HashMap<u32, (TabletSeat, Option<PenSample>)>,
+    /// ext_foreign_toplevel_list_v1 objects that were not stopped
+    foreign_lists: Vec<u32>,
+    /// Toplevels of all clients, as last announced
+    foreign_toplevels: Vec<ForeignToplevel>,
+    /// Open ext_foreign_toplevel_handle_v1 objects -> toplevel identifier
+    foreign_handles: HashMap<u32, String>,
+    /// zwlr_screencopy_frame_v1 objects
+    capture_frames: HashMap<u32, CaptureFrame>,
+    /// Reads what a monitor shows, for screen capture
+    screen_capture: fn(&Monitor) -> Option<Image>,
+    /// ext_idle_notification_v1 objects
+    idle_notifications: HashMap<u32, IdleNotification>,
+    /// xdg_toplevel_icon_v1 objects
+    toplevel_icons: HashMap<u32, ToplevelIcon>,
+    /// Identities handed out for image descriptions
+    color_identities: Identities,
+    /// wp_image_description_v1 objects -> description, and whether
+    /// get_information is allowed
+    image_descriptions: HashMap<u32, (ImageDescription, bool)>,
+    /// wp_image_description_creator_params_v1 objects
+    description_params: HashMap<u32, DescriptionParams>,
+    /// wp_color_management_output_v1 objects -> wl_output
+    color_outputs: HashMap<u32, u32>,
+    /// wp_color_management_surface_v1 objects -> wl_surface
+    color_surfaces: HashMap<u32, u32>,
+    /// wp_color_management_surface_feedback_v1 objects -> wl_surface
+    color_feedbacks: HashMap<u32, u32>,
+    /// Color spaces last forwarded to the renderer, by toplevel wl_surface
+    sent_color_spaces: HashMap<u32, ColorSpace>,
+    /// wp_alpha_modifier_surface_v1 objects -> wl_surface
+    alpha_modifiers: HashMap<u32, u32>,
+    /// Opacity last forwarded to the renderer, by toplevel wl_surface
+    sent_opacities: HashMap<u32, WindowOpacity>,
+    /// wp_fifo_v1 objects -> wl_surface
+    fifos: HashMap<u32, u32>,
+    /// wp_commit_timer_v1 objects -> wl_surface
+    commit_timers: HashMap<u32, u32>,
+    /// Scheduling state of surfaces with a fifo or commit timer
+    commit_queues: HashMap<u32, CommitQueue>,
+    /// Time and refresh period of the last vblank, on the presentation clock
+    last_vblank: Option<(Duration, Duration)>,
+    /// Tasks started by handlers, not yet taken by the connection loop
+    tasks: Vec<Task>,
+    /// zwp_linux_buffer_params_v1 objects
+    dmabuf_params: HashMap<u32, BufferParams>,
+    /// wl_buffers backed by a dmabuf
+    dmabuf_buffers: HashSet<u32>,
+    /// wp_linux_drm_syncobj_surface_v1 objects -> wl_surface
+    syncobj_surfaces: HashMap<u32, u32>,
+    /// Pending sync points of wl_surfaces with a syncobj surface
+    surface_sync: HashMap<u32, SurfaceSync>,
+    /// Frames waiting for an acquire point: (acquire, release, root wl_surface)
+    held_frames: Vec<(SyncPoint, SyncPoint, u32)>,
+    /// wl_surface -> wp_presentation_feedback objects for its next commit
+    pending_feedback: HashMap<u32, Vec<u32>>,
+    /// Committed (feedback, wl_surface) pairs waiting for the next vblank
+    awaiting_presentation: Vec<(u32, u32)>,
+    /// wl_surface -> frame callbacks for its next commit
+    pending_frame_callbacks: HashMap<u32, Vec<u32>>,
+    /// Committed (wl_callback, wl_surface) pairs waiting for the next vblank
+    awaiting_frame_callbacks: Vec<(u32, u32)>,
+    /// Resource limits of this client
+    limits: ResourceLimits,
+    /// Limit a request was refused for, reported after dispatch
+    quota_exceeded: Option<String>,
+    /// Protocol error raised by a request, sent after dispatch
+    protocol_error: Option<Message>,
+    /// A wl_display.error was sent; the client must be disconnected
+    failed: bool,
+    /// Callbacks installed by an embedding application
+    hooks: Option<Box<dyn CompositorHooks>>,
+    /// wl_shm formats sent when wl_shm is bound
+    shm_formats: Vec<u32>,
 }
 
 impl Compositor {
+    /// Compositor advertising a single virtual output
     pub fn new() -> Self {
+        Self::with_outputs(vec![Monitor::default()])
+    }
+
+    /// Compositor advertising one wl_output per monitor
+    pub fn with_outputs(monitors: Vec<Monitor>) -> Self {
+        Self::with_config(monitors, &GlobalConfig::default())
+    }
+
+    /// Compositor advertising one wl_output per monitor and the globals
+    /// selected by `config`
+    pub fn with_config(monitors: Vec<Monitor>, config: &GlobalConfig) -> Self {
         let mut comp = Self {
             globals: Vec::new(),
-            objects: HashMap::new(),
+            objects: ObjectTable::new(),
             allocator: ObjectAllocator::new(),
             encoder: WireEncoder::new(),
             next_global_name: 1,
+            pipes: VirtualFdQueue::new(),
+            data_devices: Vec::new(),
+            selection: None,
+            data_offers: HashMap::new(),
+            positioners: HashMap::new(),
+            popups: Vec::new(),
+            next_serial: 1,
+            configures: HashMap::new(),
+            toplevels: HashMap::new(),
+            render_queue: Vec::new(),
+            closed_windows: Vec::new(),
+            windows: HashMap::new(),
+            max_frames_in_flight: 0,
+            frames_in_flight: 0,
+            frame_acks: false,
+            presentation_latency: None,
+            skipped_frames: HashSet::new(),
+            frame_pacing: None,
+            last_frame_sent: HashMap::new(),
+            overlay_stats: None,
+            renderer_capabilities: RendererCapabilities::default(),
+            surfaces: SurfaceTree::new(),
+            buffers: BufferManager::new(),
+            buffer_tracker: BufferTracker::new(),
+            buffer_formats: HashMap::new(),
+            xdg_surfaces: HashMap::new(),
+            viewports: HashMap::new(),
+            regions: HashMap::new(),
+            sent_input_regions: HashMap::new(),
+            hint_objects: HashMap::new(),
+            sent_presentation_hints: HashMap::new(),
+            outputs: Vec::new(),
+            registries: Vec::new(),
+            output_objects: HashMap::new(),
+            surface_outputs: HashMap::new(),
+            versions: HashMap::new(),
+            fractional_scales: HashMap::new(),
+            sent_scales: HashMap::new(),
+            decoration_mode: DecorationMode::default(),
+            decorations: HashMap::new(),
+            cursor_surface: None,
+            sent_cursor: None,
+            keyboard_focus: None,
+            pointer_focus: None,
+            text_inputs: HashMap::new(),
+            sent_ime: ImeState::default(),
+            window_focused: true,
+            relative_pointers: Vec::new(),
+            constraints: HashMap::new(),
+            sent_constraint: None,
+            sent_cursor_position: None,
+            shortcut_inhibitors: HashMap::new(),
+            sent_shortcuts_inhibit: ShortcutsInhibit::default(),
+            gestures: HashMap::new(),
+            active_gesture: None,
+            tablet_seats: HashMap::new(),
+            idle_notifications: HashMap::new(),
+            toplevel_icons: HashMap::new(),
+            color_identities: Identities::new(),
+            image_descriptions: HashMap::new(),
+            description_params: HashMap::new(),
+            color_outputs: HashMap::new(),
+            color_surfaces: HashMap::new(),
+            color_feedbacks: HashMap::new(),
+            sent_color_spaces: HashMap::new(),
+            alpha_modifiers: HashMap::new(),
+            sent_opacities: HashMap::new(),
+            fifos: HashMap::new(),
+            commit_timers: HashMap::new(),
+            commit_queues: HashMap::new(),
+            last_vblank: None,
+            tasks: Vec::new(),
+            capture_frames: HashMap::new(),
+            screen_capture: capture::capture_monitor,
+            foreign_lists: Vec::new(),
+            foreign_toplevels: Vec::new(),
+            foreign_handles: HashMap::new(),
+            dmabuf_params: HashMap::new(),
+            dmabuf_buffers: HashSet::new(),
+            syncobj_surfaces: HashMap::new(),
+            surface_sync: HashMap::new(),
+            held_frames: Vec::new(),
+            pending_feedback: HashMap::new(),
+            awaiting_presentation: Vec::new(),
+            pending_frame_callbacks: HashMap::new(),
+            awaiting_frame_callbacks: Vec::new(),
+            limits: ResourceLimits::default(),
+            quota_exceeded: None,
+            protocol_error: None,
+            failed: false,
+            hooks: None,
+            shm_formats: config.shm_formats(),
         };
 
         // Register wl_display (object 1)
         comp.objects.insert(1, "wl_display".to_string());
 
+        // Register the winpipe control channel (object 0)
+        comp.objects.insert(CONTROL_OBJECT_ID, CONTROL_INTERFACE.to_string());
+
         // Register standard globals
-        comp.register_global("wl_compositor", 5);
-        comp.register_global("wl_subcompositor", 1);
-        comp.register_global("wl_shm", 1);
-        comp.register_global("wl_output", 4);
-        comp.register_global("wl_seat", 8);
-        comp.register_global("wl_data_device_manager", 3);
-        comp.register_global("xdg_wm_base", 5);
-        comp.register_global("wp_viewporter", 1);
-        comp.register_global("zwp_linux_dmabuf_v1", 4);
+        for &(interface, max_version) in GLOBALS {
+            if interface == "wl_output" {
+                for monitor in monitors.iter().cloned() {
+                    let name = comp.register_global("wl_output", max_version);
+                    comp.outputs.push((name, monitor));
+                }
+            } else if let Some(version) = config.version(interface, max_version) {
+                comp.register_global(interface, version);
+            }
+        }
 
         comp
     }
 
-    /// Register a global interface
-    fn register_global(&mut self, interface: &str, version: u32) {
+    /// Set the decoration mode answered to xdg-decoration requests
+    ///
+    /// Clients may state a preference with set_mode, but the configured
+    /// mode always wins so windows look consistent.
+    pub fn set_decoration_mode(&mut self, mode: DecorationMode) {
+        self.decoration_mode = mode;
+    }
+
+    /// Frames the wl_buffer's surface showed since it was last on it:
+    /// 1 = on screen, 0 = never shown (see `crate::release`)
+    pub fn buffer_age(&self, buffer: u32) -> u32 {
+        self.buffer_tracker.age(buffer)
+    }
+
+    /// Set the resource limits enforced on the client
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+    }
+
+    /// Hold frames back once `frames` were sent without the renderer
+    /// presenting or acknowledging them; 0 sends every frame
+    pub fn set_max_frames_in_flight(&mut self, frames: u32) {
+        self.max_frames_in_flight = frames;
+    }
+
+    /// Send at most one frame per refresh for each window, or fewer with a
+    /// frame rate cap (`Some(0)` = none); None sends every commit's frame
+    pub fn set_frame_pacing(&mut self, max_fps: Option<u32>) {
+        self.frame_pacing = max_fps;
+    }
+
+    /// Shortest time between two frames of a window: the refresh period
+    /// of the monitor, or longer with a frame rate cap
+    ///
+    /// Headless, frame callbacks complete at this interval too.
+    pub fn frame_interval(&self) -> Duration {
+        let refresh = match self.primary_output().refresh {
+            millihertz if millihertz > 0 => Duration::from_nanos(1_000_000_000_000 / millihertz as u64),
+            _ => DEFAULT_FRAME_INTERVAL,
+        };
+        match self.frame_pacing {
+            None | Some(0) => refresh,
+            Some(fps) => refresh.max(Duration::from_secs(1) / fps),
+        }
+    }
+
+    /// What the renderer can show; windows with 10-bit or half float
+    /// buffers keep their depth if it shows that format
+    pub fn set_renderer_capabilities(&mut self, capabilities: RendererCapabilities) {
+        self.renderer_capabilities = capabilities;
+    }
+
+    /// Show or hide the renderer's stats overlay; while shown it is
+    /// updated with each refresh after `overlay::SAMPLE_INTERVAL`
+    pub fn set_stats_overlay(&mut self, visible: bool) {
+        match (visible, self.overlay_stats.is_some()) {
+            (true, false) => self.overlay_stats = Some(OverlayStats::new(Instant::now())),
+            (false, true) => {
+                self.overlay_stats = None;
+                self.queue_stats_overlay(StatsOverlay::default());
+            }
+            _ => {}
+        }
+    }
+
+    /// Smoothed time from sending a frame until the renderer acknowledged
+    /// presenting it; None until it does
+    pub fn presentation_latency(&self) -> Option<Duration> {
+        self.presentation_latency
+    }
+
+    /// Install callbacks notified of client activity
+    pub fn set_hooks(&mut self, hooks: Box<dyn CompositorHooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Whether a fatal protocol error was sent to the client
+    ///
+    /// Further requests are ignored; the connection should be closed once
+    /// the error has been written.
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Message asking the client to show it is alive: xdg_wm_base.ping,
+    /// or a round trip through the helper if it bound no xdg_wm_base
+    pub fn ping(&mut self) -> Message {
+        let wm_base = self.objects.iter()
+            .filter(|(_, iface)| iface.as_str() == "xdg_wm_base")
+            .map(|(&id, _)| id)
+            .min();
+        let Some(wm_base) = wm_base else {
+            return pipe::ping_message();
+        };
+        let serial = self.next_serial();
+        Message::new(wm_base, opcodes::xdg_wm_base::PING, serial.to_le_bytes().to_vec())
+    }
+
+    /// Every live object of the client, flagging the ones that look leaked
+    pub fn dump_objects(&self) -> Vec<ObjectInfo> {
+        self.objects.snapshot(std::time::Instant::now())
+    }
+
+    /// Live objects that should have been destroyed already
+    pub fn leaked_objects(&self) -> Vec<ObjectInfo> {
+        self.objects.leaks(std::time::Instant::now())
+    }
+
+    /// Every toplevel with its surface tree, by ID
+    pub fn toplevels(&self) -> Vec<ToplevelInfo> {
+        self.toplevel_ids().into_iter()
+            .filter_map(|id| {
+                let toplevel = self.toplevels.get(&id)?;
+                let surface = self.xdg_surfaces.get(&toplevel.xdg_surface).copied();
+                let surfaces = surface
+                    .map(|root| {
+                        self.surfaces.render_order(root).into_iter()
+                            .map(|(id, x, y)| self.surface_info(id, (x, y)))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Some(ToplevelInfo {
+                    id,
+                    surface,
+                    title: toplevel.title.clone(),
+                    app_id: toplevel.app_id.clone(),
+                    maximized: toplevel.applied.maximized,
+                    fullscreen: toplevel.applied.fullscreen,
+                    minimized: toplevel.minimized,
+                    mapped: surface.is_some_and(|s| self.surface_outputs.contains_key(&s)),
+                    surfaces,
+                })
+            })
+            .collect()
+    }
+
+    fn surface_info(&self, id: u32, position: (i32, i32)) -> SurfaceInfo {
+        let state = self.surfaces.get(id).map(|s| &s.current);
+        let buffer = state.and_then(|s| s.buffer);
+        let size = state.zip(buffer.and_then(|b| self.buffers.get(b))).map(|(state, buffer)| {
+            let view = BufferView {
+                width: buffer.width,
+                height: buffer.height,
+                stride: buffer.stride,
+                data: &buffer.data,
+                opaque: false,
+            };
+            surface_size(&view, state)
+        });
+        SurfaceInfo { id, position, size, buffer }
+    }
+
+    /// Register a global interface, returning its name
+    fn register_global(&mut self, interface: &str, version: u32) -> u32 {
         let name = self.next_global_name;
         self.next_global_name += 1;
         
@@ -95,10 +1033,55 @@ impl Compositor {
         });
         
         debug!("Registered global: {} v{} (name={})", interface, version, name);
+        name
     }
 
     /// Handle an incoming message and return response messages
+    ///
+    /// Events newer than the version the receiving object was bound at
+    /// are left out. A request that takes the client over its resource
+    /// limits, or violates the protocol, is answered with wl_display.error
+    /// instead.
     pub fn handle_message(&mut self, msg: &Message) -> Vec<Message> {
+        if self.failed {
+            return Vec::new();
+        }
+        let events = self.dispatch_message(msg);
+        if let Some(error) = self.protocol_error.take() {
+            self.failed = true;
+            return vec![error];
+        }
+        if let Some(reason) = self.quota_exceeded.take().or_else(|| self.check_limits()) {
+            warn!("Client over its limits: {}", reason);
+            self.failed = true;
+            return vec![display_error(msg.object_id, display_error::NO_MEMORY, &reason)];
+        }
+        self.gate_events(events)
+    }
+
+    /// Apply a finished task
+    pub fn complete(&mut self, completion: Completion) -> Vec<Message> {
+        if self.failed {
+            return Vec::new();
+        }
+        let events = completion(self);
+        if let Some(error) = self.protocol_error.take() {
+            self.failed = true;
+            return vec![error];
+        }
+        self.gate_events(events)
+    }
+
+    /// Tasks started since the last call, for the connection loop to run
+    pub fn take_tasks(&mut self) -> Vec<Task> {
+        std::mem::take(&mut self.tasks)
+    }
+
+    fn spawn(&mut self, task: impl Future<Output = Completion> + Send + 'static) {
+        self.tasks.push(Box::pin(task));
+    }
+
+    fn dispatch_message(&mut self, msg: &Message) -> Vec<Message> {
         let interface = self.objects.get(&msg.object_id)
             .map(|s| s.as_str())
             .unwrap_or("unknown");
@@ -114,17 +1097,15 @@ impl Compositor {
                         msg.payload[0], msg.payload[1], 
                         msg.payload[2], msg.payload[3]
                     ]);
-                    self.objects.insert(callback_id, "wl_callback".to_string());
-                    
-                    // Send wl_callback.done (opcode 0)
-                    let serial = 1u32;
+                    // Send wl_callback.done (opcode 0); the callback is
+                    // destroyed right away, so it is never tracked
                     let response = Message::new(
                         callback_id, 
                         0, // done
-                        serial.to_le_bytes().to_vec()
+                        callback_time().to_le_bytes().to_vec()
                     );
                     info!("wl_display.sync -> callback.done (id={})", callback_id);
-                    return vec![response];
+                    return vec![response, delete_id(callback_id)];
                 }
             }
 
@@ -139,30 +1120,12 @@ impl Compositor {
                     
                     info!("wl_display.get_registry (id={})", registry_id);
                     
+                    self.registries.push(registry_id);
+
                     // Send wl_registry.global for each registered global
-                    let mut responses = Vec::new();
-                    for global in &self.globals {
-                        let mut payload = Vec::new();
-                        
-                        // name (u32)
-                        payload.extend_from_slice(&global.name.to_le_bytes());
-                        
-                        // interface (string: length + data + padding)
-                        let interface_bytes = global.interface.as_bytes();
-                        let len = interface_bytes.len() as u32 + 1; // include null terminator
-                        payload.extend_from_slice(&len.to_le_bytes());
-                        payload.extend_from_slice(interface_bytes);
-                        payload.push(0); // null terminator
-                        // Pad to 4-byte boundary
-                        while payload.len() % 4 != 0 {
-                            payload.push(0);
-                        }
-                        
-                        // version (u32)
-                        payload.extend_from_slice(&global.version.to_le_bytes());
-                        
-                        responses.push(Message::new(registry_id, 0, payload)); // opcode 0 = global
-                    }
+                    let responses = self.globals.iter()
+                        .map(|global| global_event(registry_id, global))
+                        .collect();
                     
                     return responses;
                 }
@@ -179,23 +1142,53 @@ impl Compositor {
                     
                     // Find the global
                     if let Some(global) = self.globals.iter().find(|g| g.name == name) {
-                        // The new_id is at the end of payload (need to parse string first)
-                        // For simplicity, we'll extract from the end
-                        let payload_len = msg.payload.len();
-                        if payload_len >= 8 {
-                            let new_id = u32::from_le_bytes([
-                                msg.payload[payload_len - 4],
-                                msg.payload[payload_len - 3],
-                                msg.payload[payload_len - 2],
-                                msg.payload[payload_len - 1],
-                            ]);
-                            
+                        let mut args = ArgReader::new(&msg.payload[4..]);
+                        let bound = args.string().and_then(|_| Ok((args.u32()?, args.u32()?)));
+                        if let Ok((version, new_id)) = bound {
                             self.objects.insert(new_id, global.interface.clone());
-                            info!("wl_registry.bind: {}@{}", global.interface, new_id);
-                            
+                            self.versions.insert(new_id, version);
+                            info!("wl_registry.bind: {}@{} v{}", global.interface, new_id, version);
+
+                            if global.interface == "wl_seat" {
+                                return seat_events(new_id, version);
+                            }
+
+                            if global.interface == "wl_shm" {
+                                return self.shm_formats.iter()
+                                    .map(|format| Message::new(new_id, opcodes::shm::FORMAT, format.to_le_bytes().to_vec()))
+                                    .collect();
+                            }
+
+                            if global.interface == "zwp_linux_dmabuf_v1" {
+                                return dmabuf::format_events(new_id, version);
+                            }
+
+                            if global.interface == "wp_presentation" {
+                                return vec![presentation::clock_id(new_id)];
+                            }
+
+                            if global.interface == "wp_color_manager_v1" {
+                                return color::manager_events(new_id);
+                            }
+
+                            if global.interface == "xdg_toplevel_icon_manager_v1" {
+                                return toplevel_icon::size_events(new_id);
+                            }
+
+                            if global.interface == "ext_foreign_toplevel_list_v1" {
+                                self.foreign_lists.push(new_id);
+                                let toplevels = self.foreign_toplevels.clone();
+                                return toplevels.iter()
+                                    .flat_map(|toplevel| self.announce_foreign_toplevel(new_id, toplevel))
+                                    .collect();
+                            }
+
                             // Send wl_output events when output is bound
                             if global.interface == "wl_output" {
-                                return self.send_output_info(new_id);
+                                self.output_objects.insert(new_id, (name, version));
+                                let mut responses = self.send_output_info(name, new_id, version);
+                                responses.extend(self.sync_surface_outputs());
+                                return responses;
                             }
                         }
                     }
@@ -209,35 +1202,98 @@ impl Compositor {
                         msg.payload[0], msg.payload[1],
                         msg.payload[2], msg.payload[3]
                     ]);
-                    self.objects.insert(surface_id, "wl_surface".to_string());
+                    self.insert_child(surface_id, "wl_surface", msg.object_id);
+                    self.surfaces.create(surface_id);
                     info!("wl_compositor.create_surface (id={})", surface_id);
+                    self.hook(|h| h.surface_created(surface_id));
+                    return self.scale_events();
                 }
             }
 
-            // wl_shm.create_pool (opcode 0)
-            ("wl_shm", 0) => {
-                if msg.payload.len() >= 8 {
-                    let pool_id = u32::from_le_bytes([
-                        msg.payload[0], msg.payload[1],
-                        msg.payload[2], msg.payload[3]
-                    ]);
-                    self.objects.insert(pool_id, "wl_shm_pool".to_string());
-                    info!("wl_shm.create_pool (id={})", pool_id);
-                    
-                    // Send wl_shm.format events for supported formats
-                    let formats = [0u32, 1]; // ARGB8888, XRGB8888
-                    let mut responses = Vec::new();
-                    for format in formats {
-                        responses.push(Message::new(
-                            msg.object_id,
-                            0, // format event
-                            format.to_le_bytes().to_vec()
-                        ));
+            // wl_compositor.create_region
+            ("wl_compositor", opcodes::compositor::CREATE_REGION) => {
+                if let Ok(region_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(region_id, "wl_region", msg.object_id);
+                    self.regions.insert(region_id, Region::new());
+                    debug!("wl_compositor.create_region (id={})", region_id);
+                }
+            }
+
+            // wl_region.add / subtract(x, y, width, height)
+            ("wl_region", opcodes::region::ADD | opcodes::region::SUBTRACT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok(Rect::new(args.i32()?, args.i32()?, args.i32()?, args.i32()?))
+                })();
+                if let (Ok(rect), Some(region)) = (parsed, self.regions.get_mut(&msg.object_id)) {
+                    if msg.opcode == opcodes::region::ADD {
+                        region.add(rect);
+                    } else {
+                        region.subtract(rect);
                     }
-                    return responses;
                 }
             }
 
+            // wl_region.destroy
+            ("wl_region", opcodes::region::DESTROY) => {
+                self.regions.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_surface.set_opaque_region / set_input_region(region)
+            ("wl_surface", opcodes::surface::SET_OPAQUE_REGION | opcodes::surface::SET_INPUT_REGION) => {
+                let Ok(region_id) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                // The region is copied, so the client may destroy it right away
+                let region = self.regions.get(&region_id).cloned();
+                if msg.opcode == opcodes::surface::SET_OPAQUE_REGION {
+                    self.surfaces.set_opaque_region(msg.object_id, region);
+                } else {
+                    self.surfaces.set_input_region(msg.object_id, region);
+                }
+            }
+
+            // wl_shm.create_pool(id, fd, size)
+            ("wl_shm", opcodes::shm::CREATE_POOL) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(pool_id), Ok(size)) = (args.u32(), args.i32()) {
+                    if size <= 0 {
+                        let message = format!("invalid pool size {}", size);
+                        self.protocol_error = Some(display_error(msg.object_id, shm_error::INVALID_STRIDE, &message));
+                        return Vec::new();
+                    }
+                    if !self.reserve_memory(size as u64) {
+                        return Vec::new();
+                    }
+                    self.insert_child(pool_id, "wl_shm_pool", msg.object_id);
+                    self.buffers.create_pool(pool_id, size as usize);
+                    info!("wl_shm.create_pool (id={}, size={})", pool_id, size);
+                }
+            }
+
+            // wl_shm_pool.resize(size) -> grow the mirrored pool
+            ("wl_shm_pool", opcodes::shm_pool::RESIZE) => {
+                let Ok(size) = ArgReader::new(&msg.payload).i32() else {
+                    return Vec::new();
+                };
+                let Some(old) = self.buffers.pool_size(msg.object_id) else {
+                    return Vec::new();
+                };
+                if size > 0 && size as usize > old && !self.reserve_memory((size as usize - old) as u64) {
+                    return Vec::new();
+                }
+                if let Err(e) = self.buffers.resize_pool(msg.object_id, size.max(0) as usize) {
+                    let message = e.to_string();
+                    self.protocol_error = Some(display_error(msg.object_id, shm_error::INVALID_STRIDE, &message));
+                }
+            }
+
+            ("wl_shm_pool", opcodes::shm_pool::DESTROY) => {
+                self.buffers.destroy_pool(msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
             // xdg_wm_base.get_xdg_surface (opcode 2)
             ("xdg_wm_base", 2) => {
                 if msg.payload.len() >= 8 {
@@ -245,7 +1301,12 @@ impl Compositor {
                         msg.payload[0], msg.payload[1],
                         msg.payload[2], msg.payload[3]
                     ]);
-                    self.objects.insert(xdg_surface_id, "xdg_surface".to_string());
+                    let surface_id = u32::from_le_bytes([
+                        msg.payload[4], msg.payload[5],
+                        msg.payload[6], msg.payload[7]
+                    ]);
+                    self.insert_child(xdg_surface_id, "xdg_surface", msg.object_id);
+                    self.xdg_surfaces.insert(xdg_surface_id, surface_id);
                     info!("xdg_wm_base.get_xdg_surface (id={})", xdg_surface_id);
                 }
             }
@@ -257,124 +1318,5453 @@ impl Compositor {
                         msg.payload[0], msg.payload[1],
                         msg.payload[2], msg.payload[3]
                     ]);
-                    self.objects.insert(toplevel_id, "xdg_toplevel".to_string());
+                    self.insert_child(toplevel_id, "xdg_toplevel", msg.object_id);
+                    self.toplevels.insert(toplevel_id, Toplevel::new(msg.object_id));
                     info!("xdg_surface.get_toplevel (id={})", toplevel_id);
-                    
-                    let mut responses = Vec::new();
-                    
-                    // 1. Send xdg_toplevel.configure (width=1920, height=1080, states=[])
-                    let mut toplevel_conf = Vec::new();
-                    toplevel_conf.extend_from_slice(&1920i32.to_le_bytes()); // width
-                    toplevel_conf.extend_from_slice(&1080i32.to_le_bytes()); // height
-                    toplevel_conf.extend_from_slice(&0u32.to_le_bytes());    // states array length
-                    responses.push(Message::new(toplevel_id, 0, toplevel_conf));
-                    
-                    // 2. Send xdg_surface.configure (serial) - THIS IS CRITICAL
-                    let serial = 1u32;
-                    responses.push(Message::new(msg.object_id, 0, serial.to_le_bytes().to_vec()));
-                    
-                    info!("Sent xdg configure: 1920x1080, serial={}", serial);
-                    return responses;
+
+                    return self.configure_toplevel(toplevel_id);
                 }
             }
 
-            // xdg_surface.ack_configure (opcode 4)
-            ("xdg_surface", 4) => {
-                debug!("xdg_surface.ack_configure");
+            // xdg_toplevel.set_title / set_app_id -> forward to the renderer
+            ("xdg_toplevel", opcodes::xdg_toplevel::SET_TITLE | opcodes::xdg_toplevel::SET_APP_ID) => {
+                let value = match ArgReader::new(&msg.payload).string() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        warn!("xdg_toplevel@{}.{}: {}", msg.object_id, msg.opcode, e);
+                        return Vec::new();
+                    }
+                };
+                let window = self.toplevel_window(msg.object_id);
+                if let Some(toplevel) = self.toplevels.get_mut(&msg.object_id) {
+                    if msg.opcode == opcodes::xdg_toplevel::SET_TITLE {
+                        info!("xdg_toplevel.set_title: {:?}", value);
+                        toplevel.title = value.clone();
+                    } else {
+                        info!("xdg_toplevel.set_app_id: {:?}", value);
+                        toplevel.app_id = value.clone();
+                    }
+                    let info = toplevel.window_info();
+                    self.render_queue.push((window, RenderMessage::Window(info)));
+                    if msg.opcode == opcodes::xdg_toplevel::SET_TITLE {
+                        self.hook(|h| h.title_changed(msg.object_id, &value));
+                    } else {
+                        self.hook(|h| h.app_id_changed(msg.object_id, &value));
+                    }
+                }
             }
 
-            // wl_surface.commit (opcode 6)
-            ("wl_surface", 6) => {
-                debug!("wl_surface.commit");
-                // This is where we'd capture the surface content
+            // xdg_toplevel.set_min_size / set_max_size
+            ("xdg_toplevel", opcodes::xdg_toplevel::SET_MIN_SIZE | opcodes::xdg_toplevel::SET_MAX_SIZE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(width), Ok(height)) = (args.i32(), args.i32()) else {
+                    return Vec::new();
+                };
+                let window = self.toplevel_window(msg.object_id);
+                if let Some(toplevel) = self.toplevels.get_mut(&msg.object_id) {
+                    if msg.opcode == opcodes::xdg_toplevel::SET_MIN_SIZE {
+                        debug!("xdg_toplevel.set_min_size: {}x{}", width, height);
+                        toplevel.min_size = (width.max(0), height.max(0));
+                    } else {
+                        debug!("xdg_toplevel.set_max_size: {}x{}", width, height);
+                        toplevel.max_size = (width.max(0), height.max(0));
+                    }
+                    let info = toplevel.window_info();
+                    self.render_queue.push((window, RenderMessage::Window(info)));
+                }
             }
 
-            _ => {
-                debug!("Unhandled: {}@{}.{}", interface, msg.object_id, msg.opcode);
+            // xdg_toplevel.set_maximized / unset_maximized / set_fullscreen / unset_fullscreen
+            ("xdg_toplevel", opcodes::xdg_toplevel::SET_MAXIMIZED
+                | opcodes::xdg_toplevel::UNSET_MAXIMIZED
+                | opcodes::xdg_toplevel::SET_FULLSCREEN
+                | opcodes::xdg_toplevel::UNSET_FULLSCREEN) => {
+                let Some(toplevel) = self.toplevels.get_mut(&msg.object_id) else {
+                    return Vec::new();
+                };
+                match msg.opcode {
+                    opcodes::xdg_toplevel::SET_MAXIMIZED => toplevel.maximized = true,
+                    opcodes::xdg_toplevel::UNSET_MAXIMIZED => toplevel.maximized = false,
+                    opcodes::xdg_toplevel::SET_FULLSCREEN => toplevel.fullscreen = true,
+                    _ => toplevel.fullscreen = false,
+                }
+                let restored = std::mem::take(&mut toplevel.minimized);
+                info!("xdg_toplevel@{}: maximized={} fullscreen={}",
+                      msg.object_id, toplevel.maximized, toplevel.fullscreen);
+
+                // The renderer follows once the client commits the configure
+                let mut responses = if restored { self.sync_surface_outputs() } else { Vec::new() };
+                responses.extend(self.configure_toplevel(msg.object_id));
+                return responses;
             }
-        }
 
-        Vec::new()
-    }
+            // xdg_toplevel.set_minimized (no configure: minimized is not a state)
+            ("xdg_toplevel", opcodes::xdg_toplevel::SET_MINIMIZED) => {
+                let window = self.toplevel_window(msg.object_id);
+                if let Some(toplevel) = self.toplevels.get_mut(&msg.object_id) {
+                    toplevel.minimized = true;
+                    let info = toplevel.window_info();
+                    self.render_queue.push((window, RenderMessage::Window(info)));
+                    return self.sync_surface_outputs();
+                }
+            }
 
-    /// Encode responses to wire format
-    pub fn encode_responses(&self, messages: &[Message]) -> Vec<u8> {
-        self.encoder.encode_batch(messages)
-    }
+            // xdg_toplevel.move(seat, serial) -> native move loop
+            ("xdg_toplevel", opcodes::xdg_toplevel::MOVE) => {
+                if self.toplevels.contains_key(&msg.object_id) {
+                    debug!("xdg_toplevel.move");
+                    let window = self.toplevel_window(msg.object_id);
+                    self.render_queue.push((window, RenderMessage::Interactive(InteractiveOp::Move)));
+                }
+            }
 
-    /// Send wl_output information events
-    fn send_output_info(&self, output_id: u32) -> Vec<Message> {
-        let mut responses = Vec::new();
+            // xdg_toplevel.resize(seat, serial, edges) -> native size loop
+            ("xdg_toplevel", opcodes::xdg_toplevel::RESIZE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(_seat), Ok(_serial), Ok(edges)) = (args.u32(), args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                let window = self.toplevel_window(msg.object_id);
+                if let Some(toplevel) = self.toplevels.get_mut(&msg.object_id) {
+                    if toplevel.maximized || toplevel.fullscreen {
+                        return Vec::new();
+                    }
+                    debug!("xdg_toplevel.resize (edges={})", edges);
+                    toplevel.resizing = true;
+                    self.render_queue.push((window, RenderMessage::Interactive(InteractiveOp::Resize { edges })));
+                }
+            }
 
-        // wl_output.geometry (opcode 0)
-        // x, y, physical_width, physical_height, subpixel, make, model, transform
-        let mut geometry = Vec::new();
-        geometry.extend_from_slice(&0i32.to_le_bytes());    // x
-        geometry.extend_from_slice(&0i32.to_le_bytes());    // y
-        geometry.extend_from_slice(&1920i32.to_le_bytes()); // physical_width mm
-        geometry.extend_from_slice(&1080i32.to_le_bytes()); // physical_height mm
-        geometry.extend_from_slice(&0i32.to_le_bytes());    // subpixel: unknown
-        // make string
-        let make = b"Winpipe";
-        geometry.extend_from_slice(&(make.len() as u32 + 1).to_le_bytes());
-        geometry.extend_from_slice(make);
-        geometry.push(0);
-        while geometry.len() % 4 != 0 { geometry.push(0); }
-        // model string
-        let model = b"Virtual Display";
-        geometry.extend_from_slice(&(model.len() as u32 + 1).to_le_bytes());
-        geometry.extend_from_slice(model);
-        geometry.push(0);
-        while geometry.len() % 4 != 0 { geometry.push(0); }
-        geometry.extend_from_slice(&0i32.to_le_bytes());    // transform: normal
-        responses.push(Message::new(output_id, 0, geometry));
-
-        // wl_output.mode (opcode 1)
-        // flags, width, height, refresh
-        let mut mode = Vec::new();
-        mode.extend_from_slice(&3u32.to_le_bytes());       // flags: current | preferred
-        mode.extend_from_slice(&1920i32.to_le_bytes());    // width
-        mode.extend_from_slice(&1080i32.to_le_bytes());    // height
-        mode.extend_from_slice(&60000i32.to_le_bytes());   // refresh (mHz)
-        responses.push(Message::new(output_id, 1, mode));
-
-        // wl_output.scale (opcode 3) - for version >= 2
-        let scale = 1i32.to_le_bytes().to_vec();
-        responses.push(Message::new(output_id, 3, scale));
-
-        // wl_output.done (opcode 2) - for version >= 2
-        responses.push(Message::new(output_id, 2, vec![]));
-
-        info!("Sent wl_output info: 1920x1080@60Hz");
-        responses
+            // xdg_toplevel.destroy
+            // zxdg_decoration_manager_v1.get_toplevel_decoration(id, toplevel)
+            ("zxdg_decoration_manager_v1", opcodes::decoration_manager::GET_TOPLEVEL_DECORATION) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(decoration_id), Ok(toplevel)) = (args.u32(), args.u32()) {
+                    if self.decorations.values().any(|&t| t == toplevel) {
+                        warn!("zxdg_decoration_manager_v1: xdg_toplevel@{} already has a decoration object", toplevel);
+                    }
+                    self.insert_child(decoration_id, "zxdg_toplevel_decoration_v1", msg.object_id);
+                    self.decorations.insert(decoration_id, toplevel);
+                    debug!("zxdg_decoration_manager_v1.get_toplevel_decoration (id={}, toplevel={})", decoration_id, toplevel);
+                    return self.configure_decoration(decoration_id);
+                }
+            }
+
+            // zxdg_toplevel_decoration_v1.set_mode / unset_mode
+            ("zxdg_toplevel_decoration_v1", opcodes::toplevel_decoration::SET_MODE | opcodes::toplevel_decoration::UNSET_MODE) => {
+                let requested = ArgReader::new(&msg.payload).u32().ok().and_then(DecorationMode::from_u32);
+                debug!("zxdg_toplevel_decoration_v1@{}: client prefers {:?}", msg.object_id, requested);
+                return self.configure_decoration(msg.object_id);
+            }
+
+            ("zxdg_toplevel_decoration_v1", opcodes::toplevel_decoration::DESTROY) => {
+                if let Some(toplevel) = self.decorations.remove(&msg.object_id) {
+                    if let Some(toplevel) = self.toplevels.get_mut(&toplevel) {
+                        toplevel.decoration = None;
+                    }
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("xdg_toplevel", opcodes::xdg_toplevel::DESTROY) => {
+                if let Some(toplevel) = self.toplevels.remove(&msg.object_id) {
+                    let surface = self.xdg_surfaces.get(&toplevel.xdg_surface).copied();
+                    if surface.is_some_and(|surface| self.surface_outputs.remove(&surface).is_some()) {
+                        self.hook(|h| h.toplevel_unmapped(msg.object_id));
+                    }
+                    if let Some(surface) = surface {
+                        self.close_window(surface);
+                    }
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            // xdg_wm_base.create_positioner
+            ("xdg_wm_base", opcodes::xdg_wm_base::CREATE_POSITIONER) => {
+                if let Ok(positioner_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(positioner_id, "xdg_positioner", msg.object_id);
+                    self.positioners.insert(positioner_id, Positioner::new());
+                    debug!("xdg_wm_base.create_positioner (id={})", positioner_id);
+                }
+            }
+
+            // xdg_wm_base.pong -> the client is alive, which its traffic
+            // already showed
+            ("xdg_wm_base", opcodes::xdg_wm_base::PONG) => {
+                if let Ok(serial) = ArgReader::new(&msg.payload).u32() {
+                    debug!("xdg_wm_base.pong (serial={})", serial);
+                }
+            }
+
+            ("xdg_positioner", opcode) => {
+                self.handle_positioner(msg.object_id, opcode, &msg.payload);
+            }
+
+            // xdg_surface.get_popup -> place the popup and configure it
+            ("xdg_surface", opcodes::xdg_surface::GET_POPUP) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(popup_id), Ok(parent), Ok(positioner_id)) = (args.u32(), args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                let Some(positioner) = self.positioners.get(&positioner_id).cloned() else {
+                    warn!("xdg_surface.get_popup: unknown positioner {}", positioner_id);
+                    return Vec::new();
+                };
+
+                let geometry = positioner.get_geometry(self.popup_bounds(parent));
+                self.insert_child(popup_id, "xdg_popup", msg.object_id);
+                self.popups.push((popup_id, Popup {
+                    xdg_surface: msg.object_id,
+                    parent,
+                    geometry,
+                    grabbed: false,
+                }));
+                info!("xdg_surface.get_popup (id={}, parent={}) at {:?}", popup_id, parent, geometry);
+
+                return self.configure_popup(popup_id, None);
+            }
+
+            // xdg_popup.grab
+            ("xdg_popup", opcodes::xdg_popup::GRAB) => {
+                if let Some(popup) = self.popup_mut(msg.object_id) {
+                    popup.grabbed = true;
+                }
+            }
+
+            // xdg_popup.reposition -> recompute with the new positioner
+            ("xdg_popup", opcodes::xdg_popup::REPOSITION) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(positioner_id), Ok(token)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                let Some(positioner) = self.positioners.get(&positioner_id).cloned() else {
+                    return Vec::new();
+                };
+                let Some(parent) = self.popup_mut(msg.object_id).map(|p| p.parent) else {
+                    return Vec::new();
+                };
+
+                let geometry = positioner.get_geometry(self.popup_bounds(parent));
+                if let Some(popup) = self.popup_mut(msg.object_id) {
+                    popup.geometry = geometry;
+                }
+                // The popup's next commit redraws it; its old place needs
+                // redrawing too
+                if let Some(root) = self.xdg_surfaces.get(&parent).map(|&surface| self.frame_root(surface)) {
+                    self.surfaces.damage_all(root);
+                }
+                return self.configure_popup(msg.object_id, Some(token));
+            }
+
+            // xdg_popup.destroy
+            ("xdg_popup", opcodes::xdg_popup::DESTROY) => {
+                let surface = self.popups.iter()
+                    .find(|(id, _)| *id == msg.object_id)
+                    .and_then(|(_, popup)| self.xdg_surfaces.get(&popup.xdg_surface).copied());
+                let root = surface.map(|surface| self.frame_root(surface));
+                self.popups.retain(|(id, _)| *id != msg.object_id);
+                self.objects.remove(&msg.object_id);
+                // Uncover what the popup was drawn over
+                if let Some(root) = root.filter(|&root| Some(root) != surface) {
+                    self.surfaces.damage_all(root);
+                    self.submit_frame(root);
+                }
+            }
+
+            // xdg_surface.ack_configure(serial)
+            ("xdg_surface", opcodes::xdg_surface::ACK_CONFIGURE) => {
+                let Ok(serial) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                debug!("xdg_surface@{}.ack_configure({})", msg.object_id, serial);
+                let queue = self.configures.entry(msg.object_id).or_default();
+                if let Err(e) = queue.ack(serial) {
+                    warn!("xdg_surface@{}.ack_configure: {}", msg.object_id, e);
+                    self.protocol_error = Some(display_error(msg.object_id, xdg_surface_error::INVALID_SERIAL, &e.to_string()));
+                }
+            }
+
+            ("xdg_surface", opcodes::xdg_surface::DESTROY) => {
+                self.xdg_surfaces.remove(&msg.object_id);
+                self.configures.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_surface.damage / damage_buffer(x, y, width, height)
+            ("wl_surface", opcodes::surface::DAMAGE | opcodes::surface::DAMAGE_BUFFER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(x), Ok(y), Ok(width), Ok(height)) = (args.i32(), args.i32(), args.i32(), args.i32()) {
+                    let in_buffer = msg.opcode == opcodes::surface::DAMAGE_BUFFER;
+                    self.surfaces.damage(msg.object_id, Rect::new(x, y, width, height), in_buffer);
+                }
+            }
+
+            // wl_surface.frame(callback)
+            ("wl_surface", opcodes::surface::FRAME) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "wl_callback", msg.object_id);
+                    self.pending_frame_callbacks.entry(msg.object_id).or_default().push(id);
+                }
+            }
+
+            // wl_surface.commit (opcode 6)
+            ("wl_surface", 6) => {
+                debug!("wl_surface.commit");
+                let update = ContentUpdate {
+                    state: self.surfaces.take_pending(msg.object_id).unwrap_or_default(),
+                    feedback: self.pending_feedback.remove(&msg.object_id).unwrap_or_default(),
+                    frame_callbacks: self.pending_frame_callbacks.remove(&msg.object_id).unwrap_or_default(),
+                    sync: self.surface_sync.get_mut(&msg.object_id).map(std::mem::take),
+                    schedule: self.commit_queues.get_mut(&msg.object_id).map(|q| std::mem::take(&mut q.pending)).unwrap_or_default(),
+                };
+                self.apply_configure(msg.object_id);
+                let mut region_changed = false;
+                for constraint in self.constraints.values_mut().filter(|c| c.surface == msg.object_id) {
+                    region_changed |= constraint.commit();
+                }
+                if region_changed {
+                    self.submit_pointer_constraint();
+                }
+                // Scheduled content waits for its refresh
+                let now = self.last_vblank.map(|(time, _)| time);
+                let update = match self.commit_queues.get_mut(&msg.object_id) {
+                    Some(queue) => queue.submit(update, now),
+                    None => Some(update),
+                };
+                if let Some(update) = update {
+                    return self.apply_commit(msg.object_id, update);
+                }
+            }
+
+            // wl_surface.set_buffer_transform(transform)
+            ("wl_surface", opcodes::surface::SET_BUFFER_TRANSFORM) => {
+                let Ok(value) = ArgReader::new(&msg.payload).i32() else {
+                    return Vec::new();
+                };
+                match u32::try_from(value).ok().and_then(Transform::from_u32) {
+                    Some(transform) => self.surfaces.set_buffer_transform(msg.object_id, transform),
+                    None => {
+                        let message = format!("invalid buffer transform {}", value);
+                        self.protocol_error = Some(display_error(msg.object_id, surface_error::INVALID_TRANSFORM, &message));
+                    }
+                }
+            }
+
+            // wl_surface.set_buffer_scale(scale)
+            ("wl_surface", opcodes::surface::SET_BUFFER_SCALE) => {
+                let Ok(scale) = ArgReader::new(&msg.payload).i32() else {
+                    return Vec::new();
+                };
+                if scale < 1 {
+                    let message = format!("invalid buffer scale {}", scale);
+                    self.protocol_error = Some(display_error(msg.object_id, surface_error::INVALID_SCALE, &message));
+                    return Vec::new();
+                }
+                self.surfaces.set_buffer_scale(msg.object_id, scale as u32);
+            }
+
+            // wl_surface.attach(buffer, x, y)
+            ("wl_surface", opcodes::surface::ATTACH) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let Ok(buffer) = args.u32() {
+                    self.surfaces.attach(msg.object_id, (buffer != 0).then_some(buffer));
+                }
+                // For cursors the attach offset moves the hotspot
+                if let (Ok(dx), Ok(dy)) = (args.i32(), args.i32()) {
+                    if let Some((surface, hotspot)) = self.cursor_surface.as_mut() {
+                        if *surface == msg.object_id {
+                            *hotspot = (hotspot.0 - dx, hotspot.1 - dy);
+                        }
+                    }
+                }
+            }
+
+            // wl_output.release
+            ("wl_output", opcodes::output::RELEASE) => {
+                self.output_objects.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+                for entered in self.surface_outputs.values_mut() {
+                    entered.retain(|&o| o != msg.object_id);
+                }
+            }
+
+            // wl_surface.destroy
+            ("wl_surface", opcodes::surface::DESTROY) => {
+                if self.is_toplevel_surface(msg.object_id) {
+                    self.close_window(msg.object_id);
+                }
+                self.surfaces.destroy(msg.object_id);
+                if self.surface_outputs.remove(&msg.object_id).is_some() {
+                    if let Some(toplevel) = self.toplevel_for_surface(msg.object_id) {
+                        self.hook(|h| h.toplevel_unmapped(toplevel));
+                    }
+                }
+                self.hook(|h| h.surface_destroyed(msg.object_id));
+                if self.cursor_surface.is_some_and(|(surface, _)| surface == msg.object_id) {
+                    self.cursor_surface = None;
+                }
+                // No leave for a destroyed surface; just drop the focus
+                if self.pointer_focus == Some(msg.object_id) {
+                    self.pointer_focus = None;
+                }
+                if self.keyboard_focus == Some(msg.object_id) {
+                    self.keyboard_focus = None;
+                    for input in self.text_inputs.values_mut() {
+                        input.focus = None;
+                    }
+                    self.submit_ime_state();
+                }
+                self.sent_scales.remove(&msg.object_id);
+                self.sent_presentation_hints.remove(&msg.object_id);
+                self.sent_color_spaces.remove(&msg.object_id);
+                self.sent_opacities.remove(&msg.object_id);
+                self.buffers.clear_stats(msg.object_id);
+                self.versions.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+
+                // Frame callbacks of a destroyed surface never fire
+                let callbacks = self.pending_frame_callbacks.remove(&msg.object_id).unwrap_or_default();
+                self.awaiting_frame_callbacks.retain(|&(id, surface)| {
+                    if surface == msg.object_id {
+                        self.objects.remove(&id);
+                    }
+                    surface != msg.object_id
+                });
+                for id in callbacks {
+                    self.objects.remove(&id);
+                }
+
+                // Held content updates are dropped with the surface
+                let held = self.commit_queues.remove(&msg.object_id).map(|queue| queue.held).unwrap_or_default();
+                for &id in held.iter().flat_map(|update| &update.frame_callbacks) {
+                    self.objects.remove(&id);
+                }
+
+                // Content that never reached the screen is discarded
+                let mut discarded = self.pending_feedback.remove(&msg.object_id).unwrap_or_default();
+                discarded.extend(held.iter().flat_map(|update| update.feedback.iter().copied()));
+                self.awaiting_presentation.retain(|&(id, surface)| {
+                    if surface == msg.object_id {
+                        discarded.push(id);
+                    }
+                    surface != msg.object_id
+                });
+                let mut responses: Vec<Message> = discarded.into_iter()
+                    .map(|id| {
+                        self.objects.remove(&id);
+                        presentation::discarded(id)
+                    })
+                    .collect();
+
+                // Held frames will never be shown, so their buffers are free
+                self.surface_sync.remove(&msg.object_id);
+                self.held_frames.retain(|&(_, release, root)| {
+                    if root == msg.object_id {
+                        responses.push(syncobj::release_message(release));
+                    }
+                    root != msg.object_id
+                });
+                let releases = held.iter().filter_map(|update| update.sync?.release);
+                responses.extend(releases.map(syncobj::release_message));
+                responses.extend(self.buffer_tracker.surface_destroyed(msg.object_id).map(crate::release::release));
+                return responses;
+            }
+
+            // wp_presentation.feedback(surface, callback)
+            ("wp_presentation", opcodes::presentation::FEEDBACK) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(surface), Ok(id)) = (args.u32(), args.u32()) {
+                    self.insert_child(id, "wp_presentation_feedback", msg.object_id);
+                    self.pending_feedback.entry(surface).or_default().push(id);
+                }
+            }
+
+            ("wp_presentation", opcodes::presentation::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_tearing_control_manager_v1.get_tearing_control(id, surface)
+            // wp_content_type_manager_v1.get_surface_content_type(id, surface)
+            ("wp_tearing_control_manager_v1", opcodes::tearing_control_manager::GET_TEARING_CONTROL)
+            | ("wp_content_type_manager_v1", opcodes::content_type_manager::GET_SURFACE_CONTENT_TYPE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) {
+                    let interface = if interface == "wp_tearing_control_manager_v1" {
+                        "wp_tearing_control_v1"
+                    } else {
+                        "wp_content_type_v1"
+                    };
+                    self.insert_child(id, interface, msg.object_id);
+                    self.hint_objects.insert(id, surface);
+                }
+            }
+
+            ("wp_tearing_control_manager_v1", opcodes::tearing_control_manager::DESTROY)
+            | ("wp_content_type_manager_v1", opcodes::content_type_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_tearing_control_v1.set_presentation_hint(hint): 0 = vsync, 1 = async
+            ("wp_tearing_control_v1", opcodes::tearing_control::SET_PRESENTATION_HINT) => {
+                if let (Ok(hint), Some(&surface)) = (ArgReader::new(&msg.payload).u32(), self.hint_objects.get(&msg.object_id)) {
+                    self.surfaces.set_tearing(surface, hint == 1);
+                }
+            }
+
+            // wp_content_type_v1.set_content_type(type)
+            ("wp_content_type_v1", opcodes::content_type::SET_CONTENT_TYPE) => {
+                if let (Ok(kind), Some(&surface)) = (ArgReader::new(&msg.payload).u32(), self.hint_objects.get(&msg.object_id)) {
+                    self.surfaces.set_content_type(surface, kind);
+                }
+            }
+
+            // Destroying either object resets its hint on the next commit
+            ("wp_tearing_control_v1", opcodes::tearing_control::DESTROY) => {
+                if let Some(surface) = self.hint_objects.remove(&msg.object_id) {
+                    self.surfaces.set_tearing(surface, false);
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("wp_content_type_v1", opcodes::content_type::DESTROY) => {
+                if let Some(surface) = self.hint_objects.remove(&msg.object_id) {
+                    self.surfaces.set_content_type(surface, content_type::NONE);
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_shm_pool.create_buffer(id, offset, width, height, stride, format)
+            ("wl_shm_pool", opcodes::shm_pool::CREATE_BUFFER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.u32()?, args.i32()?, args.i32()?, args.i32()?, args.i32()?, args.u32()?))
+                })();
+                let Ok((buffer_id, offset, width, height, stride, format)) = parsed else {
+                    return Vec::new();
+                };
+                let Some(bpp) = shm_format::bytes_per_pixel(format).filter(|_| self.shm_formats.contains(&format)) else {
+                    warn!("wl_shm_pool.create_buffer: format {:#x} not advertised", format);
+                    return Vec::new();
+                };
+                if width <= 0 || height <= 0 || (stride as i64) < width as i64 * bpp as i64 {
+                    warn!("wl_shm_pool.create_buffer: invalid {}x{} stride={}", width, height, stride);
+                    return Vec::new();
+                }
+                // Buffers of a mirrored pool share its memory
+                if self.buffers.pool_size(msg.object_id).is_some() {
+                    let created = usize::try_from(offset)
+                        .map_err(|_| crate::error::WinpipeError::Buffer(format!("negative offset {}", offset)))
+                        .and_then(|offset| {
+                            let pool = (msg.object_id, offset);
+                            self.buffers.create_in_pool(buffer_id, pool, width as u32, height as u32, bpp, stride as u32)
+                        });
+                    if let Err(e) = created {
+                        let message = e.to_string();
+                        self.protocol_error = Some(display_error(msg.object_id, shm_error::INVALID_STRIDE, &message));
+                        return Vec::new();
+                    }
+                } else if self.reserve_buffer(stride as u32, height as u32) {
+                    self.buffers.create(buffer_id, width as u32, height as u32, bpp, stride as u32);
+                } else {
+                    return Vec::new();
+                }
+
+                self.insert_child(buffer_id, "wl_buffer", msg.object_id);
+                self.buffer_formats.insert(buffer_id, format);
+                debug!("wl_shm_pool.create_buffer (id={}, {}x{}, format={})", buffer_id, width, height, format);
+            }
+
+            // zwp_linux_dmabuf_v1.create_params(id)
+            ("zwp_linux_dmabuf_v1", opcodes::linux_dmabuf::CREATE_PARAMS) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "zwp_linux_buffer_params_v1", msg.object_id);
+                    self.dmabuf_params.insert(id, BufferParams::new());
+                }
+            }
+
+            ("zwp_linux_dmabuf_v1", opcodes::linux_dmabuf::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwp_linux_dmabuf_v1.get_default_feedback(id) / get_surface_feedback(id, surface)
+            // Every surface gets the default feedback
+            ("zwp_linux_dmabuf_v1", opcodes::linux_dmabuf::GET_DEFAULT_FEEDBACK | opcodes::linux_dmabuf::GET_SURFACE_FEEDBACK) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "zwp_linux_dmabuf_feedback_v1", msg.object_id);
+                    return dmabuf::feedback_events(id);
+                }
+            }
+
+            ("zwp_linux_dmabuf_feedback_v1", opcodes::linux_dmabuf_feedback::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwp_linux_buffer_params_v1.add(fd, plane_idx, offset, stride, modifier_hi, modifier_lo)
+            // The fd stays with the helper, so only the layout arrives here
+            ("zwp_linux_buffer_params_v1", opcodes::linux_buffer_params::ADD) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.u32()?, args.u32()?, args.u32()?, args.u32()?, args.u32()?))
+                })();
+                let Ok((plane_idx, offset, stride, modifier_hi, modifier_lo)) = parsed else {
+                    return Vec::new();
+                };
+                let plane = Plane { offset, stride, modifier: ((modifier_hi as u64) << 32) | modifier_lo as u64 };
+                if let Some(params) = self.dmabuf_params.get_mut(&msg.object_id) {
+                    if let Err(e) = params.add(plane_idx, plane) {
+                        warn!("zwp_linux_buffer_params_v1.add: {}", e);
+                    }
+                }
+            }
+
+            // zwp_linux_buffer_params_v1.create(width, height, format, flags)
+            // zwp_linux_buffer_params_v1.create_immed(buffer_id, width, height, format, flags)
+            ("zwp_linux_buffer_params_v1", opcodes::linux_buffer_params::CREATE | opcodes::linux_buffer_params::CREATE_IMMED) => {
+                let immediate = msg.opcode == opcodes::linux_buffer_params::CREATE_IMMED;
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    let buffer_id = if immediate { Some(args.u32()?) } else { None };
+                    Ok((buffer_id, args.i32()?, args.i32()?, args.u32()?))
+                })();
+                let Ok((buffer_id, width, height, format)) = parsed else {
+                    return Vec::new();
+                };
+                let Some(params) = self.dmabuf_params.get_mut(&msg.object_id) else {
+                    return Vec::new();
+                };
+
+                match params.validate(width, height, format) {
+                    Ok(plane) if !self.reserve_buffer(plane.stride, height as u32) => {}
+                    Ok(plane) => {
+                        let buffer_id = buffer_id.unwrap_or_else(|| self.allocator.alloc());
+                        self.create_dmabuf_buffer(buffer_id, width, height, format, plane);
+                        if !immediate {
+                            return vec![Message::new(msg.object_id, dmabuf::events::CREATED, buffer_id.to_le_bytes().to_vec())];
+                        }
+                    }
+                    Err(e) => {
+                        warn!("zwp_linux_buffer_params_v1: {}", e);
+                        // The client destroys an immediately created buffer
+                        // when it sees failed
+                        if let Some(id) = buffer_id {
+                            self.insert_child(id, "wl_buffer", msg.object_id);
+                        }
+                        return vec![Message::new(msg.object_id, dmabuf::events::FAILED, vec![])];
+                    }
+                }
+            }
+
+            ("zwp_linux_buffer_params_v1", opcodes::linux_buffer_params::DESTROY) => {
+                self.dmabuf_params.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_linux_drm_syncobj_manager_v1.import_timeline(id, fd)
+            // The timeline fd stays with the helper, which knows it by id
+            ("wp_linux_drm_syncobj_manager_v1", opcodes::drm_syncobj_manager::IMPORT_TIMELINE) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "wp_linux_drm_syncobj_timeline_v1", msg.object_id);
+                }
+            }
+
+            // wp_linux_drm_syncobj_manager_v1.get_surface(id, surface)
+            ("wp_linux_drm_syncobj_manager_v1", opcodes::drm_syncobj_manager::GET_SURFACE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) {
+                    self.insert_child(id, "wp_linux_drm_syncobj_surface_v1", msg.object_id);
+                    self.syncobj_surfaces.insert(id, surface);
+                    self.surface_sync.insert(surface, SurfaceSync::default());
+                }
+            }
+
+            ("wp_linux_drm_syncobj_manager_v1", opcodes::drm_syncobj_manager::DESTROY)
+            | ("wp_linux_drm_syncobj_timeline_v1", opcodes::drm_syncobj_timeline::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_linux_drm_syncobj_surface_v1.set_acquire_point / set_release_point(timeline, point_hi, point_lo)
+            ("wp_linux_drm_syncobj_surface_v1", opcodes::drm_syncobj_surface::SET_ACQUIRE_POINT
+                | opcodes::drm_syncobj_surface::SET_RELEASE_POINT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok(SyncPoint::from_wire(args.u32()?, args.u32()?, args.u32()?))
+                })();
+                let Ok(point) = parsed else {
+                    return Vec::new();
+                };
+                let surface = self.syncobj_surfaces.get(&msg.object_id);
+                if let Some(sync) = surface.and_then(|surface| self.surface_sync.get_mut(surface)) {
+                    if msg.opcode == opcodes::drm_syncobj_surface::SET_ACQUIRE_POINT {
+                        sync.acquire = Some(point);
+                    } else {
+                        sync.release = Some(point);
+                    }
+                }
+            }
+
+            ("wp_linux_drm_syncobj_surface_v1", opcodes::drm_syncobj_surface::DESTROY) => {
+                if let Some(surface) = self.syncobj_surfaces.remove(&msg.object_id) {
+                    self.surface_sync.remove(&surface);
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            // ext_idle_notifier_v1.get_idle_notification / get_input_idle_notification(id, timeout, seat)
+            ("ext_idle_notifier_v1", opcodes::idle_notifier::GET_IDLE_NOTIFICATION
+                | opcodes::idle_notifier::GET_INPUT_IDLE_NOTIFICATION) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(timeout)) = (args.u32(), args.u32()) {
+                    debug!("ext_idle_notifier_v1: notification {} after {}ms", id, timeout);
+                    self.insert_child(id, "ext_idle_notification_v1", msg.object_id);
+                    self.idle_notifications.insert(id, IdleNotification::new(timeout));
+                }
+            }
+
+            // ext_foreign_toplevel_list_v1.stop -> no more toplevel events
+            ("ext_foreign_toplevel_list_v1", opcodes::foreign_toplevel_list::STOP) => {
+                if self.foreign_lists.contains(&msg.object_id) {
+                    self.foreign_lists.retain(|&list| list != msg.object_id);
+                    return vec![foreign_toplevel::finished(msg.object_id)];
+                }
+            }
+
+            ("ext_foreign_toplevel_list_v1", opcodes::foreign_toplevel_list::DESTROY) => {
+                self.foreign_lists.retain(|&list| list != msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("ext_foreign_toplevel_handle_v1", opcodes::foreign_toplevel_handle::DESTROY) => {
+                self.foreign_handles.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwlr_screencopy_manager_v1.capture_output(frame, overlay_cursor, output)
+            // zwlr_screencopy_manager_v1.capture_output_region(frame, overlay_cursor, output, x, y, width, height)
+            ("zwlr_screencopy_manager_v1", opcodes::screencopy_manager::CAPTURE_OUTPUT
+                | opcodes::screencopy_manager::CAPTURE_OUTPUT_REGION) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(frame), Ok(_overlay_cursor), Ok(output)) = (args.u32(), args.i32(), args.u32()) else {
+                    return Vec::new();
+                };
+                let region = (msg.opcode == opcodes::screencopy_manager::CAPTURE_OUTPUT_REGION)
+                    .then(|| Ok::<_, crate::error::WinpipeError>(Rect::new(args.i32()?, args.i32()?, args.i32()?, args.i32()?)))
+                    .transpose();
+                let Ok(region) = region else {
+                    return Vec::new();
+                };
+                self.insert_child(frame, "zwlr_screencopy_frame_v1", msg.object_id);
+
+                let Some(&(global, _)) = self.output_objects.get(&output) else {
+                    return vec![capture::failed(frame)];
+                };
+                let Some((_, monitor)) = self.outputs.iter().find(|(name, _)| *name == global) else {
+                    return vec![capture::failed(frame)];
+                };
+                // Regions are given in logical pixels; capture in output pixels
+                let scale = monitor.fractional_scale();
+                let to_pixels = |value: i32| (value as f64 * scale).round() as i32;
+                let region = region.map(|r| Rect::new(to_pixels(r.x), to_pixels(r.y), to_pixels(r.width), to_pixels(r.height)));
+                let size = match region {
+                    Some(r) => {
+                        let clip = |start: i32, length: i32, limit: i32| (start + length).clamp(0, limit) - start.clamp(0, limit);
+                        (clip(r.x, r.width, monitor.width) as u32, clip(r.y, r.height, monitor.height) as u32)
+                    }
+                    None => (monitor.width as u32, monitor.height as u32),
+                };
+                debug!("zwlr_screencopy_manager_v1: frame {} of {} {:?} ({}x{})", frame, monitor.name, region, size.0, size.1);
+                self.capture_frames.insert(frame, CaptureFrame { output: global, region, size, copied: false });
+                return capture::buffer_events(frame, shm_format::XRGB8888, size);
+            }
+
+            // zwlr_screencopy_frame_v1.copy / copy_with_damage(buffer)
+            ("zwlr_screencopy_frame_v1", opcodes::screencopy_frame::COPY | opcodes::screencopy_frame::COPY_WITH_DAMAGE) => {
+                let Ok(buffer) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                return self.copy_capture(msg.object_id, buffer, msg.opcode == opcodes::screencopy_frame::COPY_WITH_DAMAGE);
+            }
+
+            ("zwlr_screencopy_frame_v1", opcodes::screencopy_frame::DESTROY) => {
+                self.capture_frames.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("zwlr_screencopy_manager_v1", opcodes::screencopy_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_alpha_modifier_v1.get_surface(id, surface)
+            ("wp_alpha_modifier_v1", opcodes::alpha_modifier::GET_SURFACE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if self.alpha_modifiers.values().any(|&s| s == surface) {
+                    let message = format!("wl_surface@{} already has an alpha modifier", surface);
+                    self.protocol_error = Some(display_error(msg.object_id, alpha_modifier_error::ALREADY_CONSTRUCTED, &message));
+                    return Vec::new();
+                }
+                self.insert_child(id, "wp_alpha_modifier_surface_v1", msg.object_id);
+                self.alpha_modifiers.insert(id, surface);
+            }
+
+            ("wp_alpha_modifier_v1", opcodes::alpha_modifier::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_alpha_modifier_surface_v1.set_multiplier(factor), applied on commit
+            ("wp_alpha_modifier_surface_v1", opcodes::alpha_modifier_surface::SET_MULTIPLIER) => {
+                let Ok(factor) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                let surface = self.alpha_modifiers.get(&msg.object_id).copied();
+                match surface.filter(|&surface| self.surfaces.get(surface).is_some()) {
+                    Some(surface) => self.surfaces.set_alpha(surface, Some(factor)),
+                    None => {
+                        let message = "the wl_surface was destroyed";
+                        self.protocol_error = Some(display_error(msg.object_id, alpha_modifier_surface_error::NO_SURFACE, message));
+                    }
+                }
+            }
+
+            // Destroying the modifier makes the surface opaque again on the next commit
+            ("wp_alpha_modifier_surface_v1", opcodes::alpha_modifier_surface::DESTROY) => {
+                if let Some(surface) = self.alpha_modifiers.remove(&msg.object_id) {
+                    self.surfaces.set_alpha(surface, None);
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_fifo_manager_v1.get_fifo(id, surface)
+            ("wp_fifo_manager_v1", opcodes::fifo_manager::GET_FIFO) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if self.fifos.values().any(|&s| s == surface) {
+                    let message = format!("wl_surface@{} already has a fifo", surface);
+                    self.protocol_error = Some(display_error(msg.object_id, fifo_manager_error::ALREADY_EXISTS, &message));
+                    return Vec::new();
+                }
+                self.insert_child(id, "wp_fifo_v1", msg.object_id);
+                self.fifos.insert(id, surface);
+                self.commit_queues.entry(surface).or_default();
+            }
+
+            ("wp_fifo_manager_v1", opcodes::fifo_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_fifo_v1.set_barrier / wait_barrier, for the next commit
+            ("wp_fifo_v1", opcodes::fifo::SET_BARRIER | opcodes::fifo::WAIT_BARRIER) => {
+                let surface = self.fifos.get(&msg.object_id).copied().filter(|&s| self.surfaces.contains(s));
+                let Some(queue) = surface.and_then(|s| self.commit_queues.get_mut(&s)) else {
+                    let message = "the wl_surface was destroyed";
+                    self.protocol_error = Some(display_error(msg.object_id, fifo_error::SURFACE_DESTROYED, message));
+                    return Vec::new();
+                };
+                if msg.opcode == opcodes::fifo::SET_BARRIER {
+                    queue.pending.set_barrier = true;
+                } else {
+                    queue.pending.wait_barrier = true;
+                }
+            }
+
+            ("wp_fifo_v1", opcodes::fifo::DESTROY) => {
+                self.fifos.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_commit_timing_manager_v1.get_timer(id, surface)
+            ("wp_commit_timing_manager_v1", opcodes::commit_timing_manager::GET_TIMER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if self.commit_timers.values().any(|&s| s == surface) {
+                    let message = format!("wl_surface@{} already has a commit timer", surface);
+                    let code = commit_timing_manager_error::COMMIT_TIMER_EXISTS;
+                    self.protocol_error = Some(display_error(msg.object_id, code, &message));
+                    return Vec::new();
+                }
+                self.insert_child(id, "wp_commit_timer_v1", msg.object_id);
+                self.commit_timers.insert(id, surface);
+                self.commit_queues.entry(surface).or_default();
+            }
+
+            ("wp_commit_timing_manager_v1", opcodes::commit_timing_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_commit_timer_v1.set_timestamp(tv_sec_hi, tv_sec_lo, tv_nsec), for the next commit
+            ("wp_commit_timer_v1", opcodes::commit_timer::SET_TIMESTAMP) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(hi), Ok(lo), Ok(nsec)) = (args.u32(), args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                let surface = self.commit_timers.get(&msg.object_id).copied().filter(|&s| self.surfaces.contains(s));
+                let result = match surface.and_then(|s| self.commit_queues.get_mut(&s)) {
+                    None => Err((commit_timer_error::SURFACE_DESTROYED, "the wl_surface was destroyed")),
+                    Some(queue) if queue.pending.target.is_some() => {
+                        Err((commit_timer_error::TIMESTAMP_EXISTS, "a timestamp is already set for this commit"))
+                    }
+                    Some(queue) => match scheduling::timestamp(hi, lo, nsec) {
+                        Some(target) => {
+                            queue.pending.target = Some(target);
+                            Ok(())
+                        }
+                        None => Err((commit_timer_error::INVALID_TIMESTAMP, "tv_nsec is out of range")),
+                    },
+                };
+                if let Err((code, message)) = result {
+                    self.protocol_error = Some(display_error(msg.object_id, code, message));
+                }
+            }
+
+            ("wp_commit_timer_v1", opcodes::commit_timer::DESTROY) => {
+                self.commit_timers.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_color_manager_v1.get_output(id, output)
+            ("wp_color_manager_v1", opcodes::color_manager::GET_OUTPUT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(output)) = (args.u32(), args.u32()) {
+                    self.insert_child(id, "wp_color_management_output_v1", msg.object_id);
+                    self.color_outputs.insert(id, output);
+                }
+            }
+
+            // wp_color_manager_v1.get_surface(id, surface)
+            ("wp_color_manager_v1", opcodes::color_manager::GET_SURFACE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if self.color_surfaces.values().any(|&s| s == surface) {
+                    let message = format!("wl_surface@{} already has a color management surface", surface);
+                    self.protocol_error = Some(display_error(msg.object_id, color::manager_error::SURFACE_EXISTS, &message));
+                    return Vec::new();
+                }
+                self.insert_child(id, "wp_color_management_surface_v1", msg.object_id);
+                self.color_surfaces.insert(id, surface);
+            }
+
+            // wp_color_manager_v1.get_surface_feedback(id, surface)
+            ("wp_color_manager_v1", opcodes::color_manager::GET_SURFACE_FEEDBACK) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) {
+                    self.insert_child(id, "wp_color_management_surface_feedback_v1", msg.object_id);
+                    self.color_feedbacks.insert(id, surface);
+                    let identity = self.color_identities.get(&self.preferred_description());
+                    return vec![Message::new(id, color::feedback_events::PREFERRED_CHANGED, identity.to_le_bytes().to_vec())];
+                }
+            }
+
+            ("wp_color_manager_v1", opcodes::color_manager::CREATE_ICC_CREATOR) => {
+                let message = "ICC image descriptions are not supported";
+                self.protocol_error = Some(display_error(msg.object_id, color::manager_error::UNSUPPORTED_FEATURE, message));
+            }
+
+            // wp_color_manager_v1.create_parametric_creator(obj)
+            ("wp_color_manager_v1", opcodes::color_manager::CREATE_PARAMETRIC_CREATOR) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "wp_image_description_creator_params_v1", msg.object_id);
+                    self.description_params.insert(id, DescriptionParams::new());
+                }
+            }
+
+            // wp_color_manager_v1.create_windows_scrgb(image_description)
+            ("wp_color_manager_v1", opcodes::color_manager::CREATE_WINDOWS_SCRGB) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "wp_image_description_v1", msg.object_id);
+                    return vec![self.add_image_description(id, ImageDescription::scrgb(), false)];
+                }
+            }
+
+            ("wp_color_manager_v1", opcodes::color_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_color_management_output_v1.get_image_description(image_description)
+            ("wp_color_management_output_v1", opcodes::color_management_output::GET_IMAGE_DESCRIPTION) => {
+                let Ok(id) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                self.insert_child(id, "wp_image_description_v1", msg.object_id);
+                let monitor = self.color_outputs.get(&msg.object_id)
+                    .and_then(|output| self.output_objects.get(output))
+                    .and_then(|&(global, _)| self.outputs.iter().find(|(name, _)| *name == global));
+                let Some((_, monitor)) = monitor else {
+                    return vec![color::failed(id, color::failure_cause::NO_OUTPUT, "the output is gone")];
+                };
+                let description = ImageDescription::for_monitor(&monitor.color);
+                return vec![self.add_image_description(id, description, true)];
+            }
+
+            ("wp_color_management_output_v1", opcodes::color_management_output::DESTROY) => {
+                self.color_outputs.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_color_management_surface_v1.set_image_description(image_description, render_intent)
+            ("wp_color_management_surface_v1", opcodes::color_management_surface::SET_IMAGE_DESCRIPTION) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(description), Ok(intent)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if intent != color::render_intent::PERCEPTUAL {
+                    let message = format!("unsupported render intent {}", intent);
+                    self.protocol_error = Some(display_error(msg.object_id, color::surface_error::RENDER_INTENT, &message));
+                    return Vec::new();
+                }
+                let Some(&(description, _)) = self.image_descriptions.get(&description) else {
+                    let message = format!("image description {} is not ready", description);
+                    self.protocol_error = Some(display_error(msg.object_id, color::surface_error::IMAGE_DESCRIPTION, &message));
+                    return Vec::new();
+                };
+                if let Some(&surface) = self.color_surfaces.get(&msg.object_id) {
+                    self.surfaces.set_color_space(surface, description.color_space());
+                }
+            }
+
+            // Unsetting or destroying goes back to sRGB on the next commit
+            ("wp_color_management_surface_v1", opcodes::color_management_surface::UNSET_IMAGE_DESCRIPTION) => {
+                if let Some(&surface) = self.color_surfaces.get(&msg.object_id) {
+                    self.surfaces.set_color_space(surface, ColorSpace::default());
+                }
+            }
+
+            ("wp_color_management_surface_v1", opcodes::color_management_surface::DESTROY) => {
+                if let Some(surface) = self.color_surfaces.remove(&msg.object_id) {
+                    self.surfaces.set_color_space(surface, ColorSpace::default());
+                }
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_color_management_surface_feedback_v1.get_preferred / get_preferred_parametric(image_description)
+            ("wp_color_management_surface_feedback_v1", opcodes::color_management_surface_feedback::GET_PREFERRED
+                | opcodes::color_management_surface_feedback::GET_PREFERRED_PARAMETRIC) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "wp_image_description_v1", msg.object_id);
+                    let description = self.preferred_description();
+                    return vec![self.add_image_description(id, description, true)];
+                }
+            }
+
+            ("wp_color_management_surface_feedback_v1", opcodes::color_management_surface_feedback::DESTROY) => {
+                self.color_feedbacks.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_image_description_creator_params_v1.create(image_description), destroys the creator
+            ("wp_image_description_creator_params_v1", opcodes::image_description_creator_params::CREATE) => {
+                let Ok(id) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                let Some(params) = self.description_params.remove(&msg.object_id) else {
+                    return Vec::new();
+                };
+                self.objects.remove(&msg.object_id);
+                match params.build() {
+                    Ok(description) => {
+                        self.insert_child(id, "wp_image_description_v1", msg.object_id);
+                        return vec![self.add_image_description(id, description, false)];
+                    }
+                    Err(e) => self.protocol_error = Some(display_error(msg.object_id, e.code, &e.message)),
+                }
+            }
+
+            ("wp_image_description_creator_params_v1", opcode) => {
+                let Some(params) = self.description_params.get_mut(&msg.object_id) else {
+                    return Vec::new();
+                };
+                if let Err(e) = set_description_param(params, opcode, &msg.payload) {
+                    self.protocol_error = Some(display_error(msg.object_id, e.code, &e.message));
+                }
+            }
+
+            // wp_image_description_v1.get_information(information)
+            ("wp_image_description_v1", opcodes::image_description::GET_INFORMATION) => {
+                let Ok(info) = ArgReader::new(&msg.payload).u32() else {
+                    return Vec::new();
+                };
+                match self.image_descriptions.get(&msg.object_id) {
+                    Some(&(description, true)) => {
+                        // The info object is destroyed by its done event
+                        return color::info_events(info, &description);
+                    }
+                    _ => {
+                        let message = "information is only available for output and preferred descriptions";
+                        self.protocol_error = Some(display_error(msg.object_id, color::description_error::NO_INFORMATION, message));
+                    }
+                }
+            }
+
+            ("wp_image_description_v1", opcodes::image_description::DESTROY) => {
+                self.image_descriptions.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // xdg_toplevel_icon_manager_v1.create_icon(id)
+            ("xdg_toplevel_icon_manager_v1", opcodes::toplevel_icon_manager::CREATE_ICON) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "xdg_toplevel_icon_v1", msg.object_id);
+                    self.toplevel_icons.insert(id, ToplevelIcon::new());
+                }
+            }
+
+            // xdg_toplevel_icon_manager_v1.set_icon(toplevel, icon) -> native window icons
+            ("xdg_toplevel_icon_manager_v1", opcodes::toplevel_icon_manager::SET_ICON) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(toplevel), Ok(icon)) = (args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                if !self.toplevels.contains_key(&toplevel) {
+                    return Vec::new();
+                }
+                let window_icon = match self.toplevel_icons.get_mut(&icon) {
+                    Some(icon) => {
+                        icon.immutable = true;
+                        if icon.images.is_empty() {
+                            if let Some(name) = &icon.name {
+                                info!("xdg_toplevel@{}: themed icon {:?} is not supported", toplevel, name);
+                            }
+                        }
+                        icon.window_icon()
+                    }
+                    // A null icon restores the default
+                    None => WindowIcon::default(),
+                };
+                debug!("xdg_toplevel@{}: icon with {} images", toplevel, window_icon.images.len());
+                let window = self.toplevel_window(toplevel);
+                self.render_queue.push((window, RenderMessage::WindowIcon(window_icon)));
+            }
+
+            // xdg_toplevel_icon_v1.set_name(icon_name)
+            ("xdg_toplevel_icon_v1", opcodes::toplevel_icon::SET_NAME) => {
+                let Ok(name) = ArgReader::new(&msg.payload).string() else {
+                    return Vec::new();
+                };
+                if let Some(icon) = self.mutable_icon(msg.object_id) {
+                    icon.name = Some(name);
+                }
+            }
+
+            // xdg_toplevel_icon_v1.add_buffer(buffer, scale)
+            ("xdg_toplevel_icon_v1", opcodes::toplevel_icon::ADD_BUFFER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(buffer), Ok(scale)) = (args.u32(), args.i32()) else {
+                    return Vec::new();
+                };
+                let Some(mirror) = self.buffers.get(buffer) else {
+                    let message = format!("buffer {} does not exist", buffer);
+                    self.protocol_error = Some(display_error(msg.object_id, toplevel_icon_error::NO_BUFFER, &message));
+                    return Vec::new();
+                };
+                let image = (self.buffer_formats.get(&buffer) == Some(&shm_format::ARGB8888))
+                    .then(|| toplevel_icon::icon_image(mirror.width, mirror.height, mirror.stride, &mirror.data))
+                    .flatten();
+                let Some(image) = image else {
+                    let message = format!("buffer {} is not a square ARGB8888 wl_shm buffer", buffer);
+                    self.protocol_error = Some(display_error(msg.object_id, toplevel_icon_error::INVALID_BUFFER, &message));
+                    return Vec::new();
+                };
+                if let Some(icon) = self.mutable_icon(msg.object_id) {
+                    icon.add(image, scale);
+                }
+            }
+
+            ("xdg_toplevel_icon_v1", opcodes::toplevel_icon::DESTROY) => {
+                self.toplevel_icons.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("xdg_toplevel_icon_manager_v1", opcodes::toplevel_icon_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("ext_idle_notifier_v1", opcodes::idle_notifier::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("ext_idle_notification_v1", opcodes::idle_notification::DESTROY) => {
+                self.idle_notifications.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_single_pixel_buffer_manager_v1.create_u32_rgba_buffer(id, r, g, b, a)
+            ("wp_single_pixel_buffer_manager_v1", opcodes::single_pixel_buffer_manager::CREATE_U32_RGBA_BUFFER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.u32()?, [args.u32()?, args.u32()?, args.u32()?, args.u32()?]))
+                })();
+                let Ok((buffer_id, rgba)) = parsed else {
+                    return Vec::new();
+                };
+
+                // A 1x1 buffer filled here, so no shm contents ever follow
+                if !self.reserve_buffer(4, 1) {
+                    return Vec::new();
+                }
+                self.insert_child(buffer_id, "wl_buffer", msg.object_id);
+                self.buffers.create(buffer_id, 1, 1, 4, 4);
+                if let Some(buffer) = self.buffers.get_mut(buffer_id) {
+                    buffer.update(&single_pixel(rgba));
+                }
+                self.buffer_formats.insert(buffer_id, shm_format::ARGB8888);
+                debug!("wp_single_pixel_buffer_manager_v1: wl_buffer@{} rgba={:?}", buffer_id, rgba);
+            }
+
+            ("wp_single_pixel_buffer_manager_v1", opcodes::single_pixel_buffer_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_buffer.destroy
+            ("wl_buffer", opcodes::buffer::DESTROY) => {
+                self.buffers.remove(msg.object_id);
+                self.buffer_tracker.buffer_destroyed(msg.object_id);
+                self.buffer_formats.remove(&msg.object_id);
+                self.dmabuf_buffers.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_subcompositor.get_subsurface(id, surface, parent)
+            ("wl_subcompositor", opcodes::subcompositor::GET_SUBSURFACE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let (Ok(subsurface_id), Ok(surface), Ok(parent)) = (args.u32(), args.u32(), args.u32()) else {
+                    return Vec::new();
+                };
+                match self.surfaces.add_subsurface(subsurface_id, surface, parent) {
+                    Ok(()) => {
+                        self.insert_child(subsurface_id, "wl_subsurface", msg.object_id);
+                        info!("wl_subcompositor.get_subsurface (id={}, surface={}, parent={})",
+                              subsurface_id, surface, parent);
+                    }
+                    Err(e) => warn!("wl_subcompositor.get_subsurface: {}", e),
+                }
+            }
+
+            ("wl_subsurface", opcode) => {
+                self.handle_subsurface(msg.object_id, opcode, &msg.payload);
+            }
+
+            // wp_viewporter.get_viewport(id, surface)
+            ("wp_viewporter", opcodes::viewporter::GET_VIEWPORT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(viewport_id), Ok(surface)) = (args.u32(), args.u32()) {
+                    self.insert_child(viewport_id, "wp_viewport", msg.object_id);
+                    self.viewports.insert(viewport_id, surface);
+                    debug!("wp_viewporter.get_viewport (id={}, surface={})", viewport_id, surface);
+                }
+            }
+
+            ("wp_viewport", opcode) => {
+                self.handle_viewport(msg.object_id, opcode, &msg.payload);
+            }
+
+            // wl_seat.get_pointer(id)
+            ("wl_seat", opcodes::seat::GET_POINTER) => {
+                if let Ok(pointer_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(pointer_id, "wl_pointer", msg.object_id);
+                    debug!("wl_seat.get_pointer (id={})", pointer_id);
+                }
+            }
+
+            // wl_pointer.set_cursor(serial, surface, hotspot_x, hotspot_y)
+            ("wl_pointer", opcodes::pointer::SET_CURSOR) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.u32()?, args.u32()?, args.i32()?, args.i32()?))
+                })();
+                match parsed {
+                    Ok((_serial, 0, _, _)) => {
+                        self.cursor_surface = None;
+                        self.queue_cursor(CursorUpdate::Hidden);
+                    }
+                    Ok((_serial, surface, x, y)) => {
+                        debug!("wl_pointer.set_cursor: wl_surface@{} hotspot=({}, {})", surface, x, y);
+                        self.cursor_surface = Some((surface, (x, y)));
+                        self.submit_cursor();
+                    }
+                    Err(e) => warn!("wl_pointer.set_cursor: {}", e),
+                }
+            }
+
+            ("wl_pointer", opcodes::pointer::RELEASE) | ("wp_cursor_shape_device_v1", opcodes::cursor_shape_device::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wp_cursor_shape_manager_v1.get_pointer(id, pointer)
+            ("wp_cursor_shape_manager_v1", opcodes::cursor_shape_manager::GET_POINTER) => {
+                if let Ok(device_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(device_id, "wp_cursor_shape_device_v1", msg.object_id);
+                }
+            }
+
+            // wp_cursor_shape_device_v1.set_shape(serial, shape)
+            ("wp_cursor_shape_device_v1", opcodes::cursor_shape_device::SET_SHAPE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(_serial), Ok(shape)) = (args.u32(), args.u32()) {
+                    debug!("wp_cursor_shape_device_v1.set_shape: {}", shape);
+                    self.cursor_surface = None;
+                    self.queue_cursor(CursorUpdate::Named(shape));
+                }
+            }
+
+            // zwp_relative_pointer_manager_v1.get_relative_pointer(id, pointer)
+            ("zwp_relative_pointer_manager_v1", opcodes::relative_pointer_manager::GET_RELATIVE_POINTER) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(id, "zwp_relative_pointer_v1", msg.object_id);
+                    self.relative_pointers.push(id);
+                }
+            }
+
+            ("zwp_relative_pointer_v1", opcodes::relative_pointer::DESTROY) => {
+                self.relative_pointers.retain(|&id| id != msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwp_pointer_gestures_v1.get_swipe_gesture / get_pinch_gesture / get_hold_gesture(id, pointer)
+            ("zwp_pointer_gestures_v1", opcodes::pointer_gestures::GET_SWIPE_GESTURE
+                | opcodes::pointer_gestures::GET_PINCH_GESTURE
+                | opcodes::pointer_gestures::GET_HOLD_GESTURE) => {
+                let kind = match msg.opcode {
+                    opcodes::pointer_gestures::GET_SWIPE_GESTURE => gesture_kind::SWIPE,
+                    opcodes::pointer_gestures::GET_PINCH_GESTURE => gesture_kind::PINCH,
+                    _ => gesture_kind::HOLD,
+                };
+                if let (Ok(id), Some(interface)) = (ArgReader::new(&msg.payload).u32(), gestures::interface(kind)) {
+                    self.insert_child(id, interface, msg.object_id);
+                    self.gestures.insert(id, kind);
+                    debug!("zwp_pointer_gestures_v1: {}@{}", interface, id);
+                }
+            }
+
+            ("zwp_pointer_gestures_v1", opcodes::pointer_gestures::RELEASE) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("zwp_pointer_gesture_swipe_v1" | "zwp_pointer_gesture_pinch_v1" | "zwp_pointer_gesture_hold_v1",
+             opcodes::pointer_gesture::DESTROY) => {
+                self.gestures.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwp_tablet_manager_v2.get_tablet_seat(id, seat)
+            ("zwp_tablet_manager_v2", opcodes::tablet_manager::GET_TABLET_SEAT) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    let seat = TabletSeat {
+                        tablet: self.allocator.alloc(),
+                        pen: self.allocator.alloc(),
+                        eraser: self.allocator.alloc(),
+                    };
+                    self.insert_child(id, "zwp_tablet_seat_v2", msg.object_id);
+                    self.insert_child(seat.tablet, "zwp_tablet_v2", msg.object_id);
+                    self.insert_child(seat.pen, "zwp_tablet_tool_v2", msg.object_id);
+                    self.insert_child(seat.eraser, "zwp_tablet_tool_v2", msg.object_id);
+                    self.tablet_seats.insert(id, (seat, None));
+                    debug!("zwp_tablet_manager_v2: tablet seat {}", id);
+                    return seat.added_events(id);
+                }
+            }
+
+            ("zwp_tablet_manager_v2", opcodes::tablet_manager::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("zwp_tablet_seat_v2", opcodes::tablet_seat::DESTROY) => {
+                self.tablet_seats.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            ("zwp_tablet_v2", opcodes::tablet_seat::DESTROY)
+            | ("zwp_tablet_tool_v2", opcodes::tablet_tool::DESTROY) => {
+                self.objects.remove(&msg.object_id);
+            }
+
+            // zwp_tablet_tool_v2.set_cursor: the renderer keeps the Windows
+            // Ink cursor, so the client's pen cursor is not shown
+            ("zwp_tablet_tool_v2", opcodes::tablet_tool::SET_CURSOR) => {}
+
+            // zwp_pointer_constraints_v1.lock_pointer / confine_pointer(id, surface, pointer, region, lifetime)
+            ("zwp_pointer_constraints_v1", opcodes::pointer_constraints::LOCK_POINTER | opcodes::pointer_constraints::CONFINE_POINTER) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.u32()?, args.u32()?, args.u32()?, args.u32()?, args.u32()?))
+                })();
+                let Ok((id, surface, _pointer, region, lifetime)) = parsed else {
+                    return Vec::new();
+                };
+                if self.constraints.values().any(|c| c.surface == surface && !c.defunct) {
+                    warn!("zwp_pointer_constraints_v1: wl_surface@{} is already constrained", surface);
+                }
+
+                let (kind, interface) = if msg.opcode == opcodes::pointer_constraints::LOCK_POINTER {
+                    (ConstraintKind::Lock, "zwp_locked_pointer_v1")
+                } else {
+                    (ConstraintKind::Confine, "zwp_confined_pointer_v1")
+                };
+                let region = self.regions.get(&region).cloned();
+                debug!("{}@{} for wl_surface@{} (lifetime {})", interface, id, surface, lifetime);
+                self.insert_child(id, interface, msg.object_id);
+                self.constraints.insert(id, Constraint::new(kind, surface, region, lifetime));
+                return self.update_pointer_constraints();
+            }
+
+            ("zwp_locked_pointer_v1", opcodes::locked_pointer::DESTROY)
+            | ("zwp_confined_pointer_v1", opcodes::confined_pointer::DESTROY) => {
+                self.constraints.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+                self.submit_pointer_constraint();
+            }
+
+            ("zwp_locked_pointer_v1", opcodes::locked_pointer::SET_REGION)
+            | ("zwp_confined_pointer_v1", opcodes::confined_pointer::SET_REGION) => {
+                let region = ArgReader::new(&msg.payload).u32().ok().and_then(|id| self.regions.get(&id).cloned());
+                if let Some(constraint) = self.constraints.get_mut(&msg.object_id) {
+                    constraint.pending_region = Some(region);
+                }
+            }
+
+            // zwp_locked_pointer_v1.set_cursor_position_hint(surface_x, surface_y)
+            ("zwp_locked_pointer_v1", opcodes::locked_pointer::SET_CURSOR_POSITION_HINT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(x), Ok(y)) = (args.fixed(), args.fixed()) {
+                    if let Some(constraint) = self.constraints.get_mut(&msg.object_id) {
+                        constraint.pending_position_hint = Some((x, y));
+                    }
+                }
+            }
+
+            // zwp_keyboard_shortcuts_inhibit_manager_v1.inhibit_shortcuts(id, surface, seat)
+            ("zwp_keyboard_shortcuts_inhibit_manager_v1", opcodes::keyboard_shortcuts_inhibit_manager::INHIBIT_SHORTCUTS) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(surface)) = (args.u32(), args.u32()) {
+                    if self.shortcut_inhibitors.values().any(|&(s, _)| s == surface) {
+                        warn!("zwp_keyboard_shortcuts_inhibit_manager_v1: wl_surface@{} is already inhibited", surface);
+                    }
+                    self.insert_child(id, "zwp_keyboard_shortcuts_inhibitor_v1", msg.object_id);
+                    self.shortcut_inhibitors.insert(id, (surface, false));
+                    debug!("zwp_keyboard_shortcuts_inhibit_manager_v1.inhibit_shortcuts (id={}, surface={})", id, surface);
+                    return self.update_shortcut_inhibitors();
+                }
+            }
+
+            ("zwp_keyboard_shortcuts_inhibitor_v1", opcodes::keyboard_shortcuts_inhibitor::DESTROY) => {
+                self.shortcut_inhibitors.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+                self.submit_shortcuts_inhibit();
+            }
+
+            // zwp_text_input_manager_v3.get_text_input(id, seat)
+            ("zwp_text_input_manager_v3", opcodes::text_input_manager::GET_TEXT_INPUT) => {
+                if let Ok(input_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(input_id, "zwp_text_input_v3", msg.object_id);
+                    let mut input = TextInput::new();
+                    input.focus = self.keyboard_focus;
+                    self.text_inputs.insert(input_id, input);
+                    debug!("zwp_text_input_manager_v3.get_text_input (id={})", input_id);
+                    if let Some(surface) = self.keyboard_focus {
+                        return vec![Message::new(input_id, text_input::events::ENTER, surface.to_le_bytes().to_vec())];
+                    }
+                }
+            }
+
+            ("zwp_text_input_v3", opcode) => {
+                self.handle_text_input(msg.object_id, opcode, &msg.payload);
+            }
+
+            // wp_fractional_scale_manager_v1.get_fractional_scale(id, surface)
+            ("wp_fractional_scale_manager_v1", opcodes::fractional_scale_manager::GET_FRACTIONAL_SCALE) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(scale_id), Ok(surface)) = (args.u32(), args.u32()) {
+                    if self.fractional_scales.values().any(|&s| s == surface) {
+                        warn!("wp_fractional_scale_manager_v1: wl_surface@{} already has a fractional scale", surface);
+                    }
+                    self.insert_child(scale_id, "wp_fractional_scale_v1", msg.object_id);
+                    self.fractional_scales.insert(scale_id, surface);
+                    debug!("wp_fractional_scale_manager_v1.get_fractional_scale (id={}, surface={})", scale_id, surface);
+                    return self.scale_events();
+                }
+            }
+
+            ("wp_fractional_scale_v1", opcodes::fractional_scale::DESTROY) => {
+                self.fractional_scales.remove(&msg.object_id);
+                self.sent_scales.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // winpipe_control.pipe_open -> queue virtual fd for the next request
+            (CONTROL_INTERFACE, pipe::opcodes::PIPE_OPEN) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    debug!("winpipe_control.pipe_open (pipe={})", id);
+                    self.pipes.push(id);
+                }
+            }
+
+            // winpipe_control.buffer_content(buffer, offset, chunk) -> bytes
+            // the client wrote into its wl_shm buffer since the last commit
+            (CONTROL_INTERFACE, pipe::opcodes::BUFFER_CONTENT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(buffer_id), Ok(offset), Ok(data)) = (args.u32(), args.u32(), args.array()) {
+                    match self.buffers.get_mut(buffer_id) {
+                        Some(buffer) => {
+                            if let Err(e) = buffer.write_at(offset as usize, &data) {
+                                warn!("winpipe_control.buffer_content (buffer={}): {}", buffer_id, e);
+                            }
+                        }
+                        None => warn!("winpipe_control.buffer_content: unknown buffer {}", buffer_id),
+                    }
+                }
+            }
+
+            // winpipe_control.dump_objects(pipe) -> write the object table
+            // into the helper's pipe
+            (CONTROL_INTERFACE, pipe::opcodes::DUMP_OBJECTS) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    let dump = crate::objects::format_dump(&self.dump_objects());
+                    return pipe::write_messages(id, dump.as_bytes());
+                }
+            }
+
+            // winpipe_control.dump_surfaces(pipe) -> write the toplevels and
+            // their surface trees into the helper's pipe
+            (CONTROL_INTERFACE, pipe::opcodes::DUMP_SURFACES) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    let tree = crate::introspect::format_tree(&self.toplevels());
+                    return pipe::write_messages(id, tree.as_bytes());
+                }
+            }
+
+            // winpipe_control.dump_buffer_stats(pipe) -> write how well
+            // delta encoding works for each frame into the helper's pipe
+            (CONTROL_INTERFACE, pipe::opcodes::DUMP_BUFFER_STATS) => {
+                if let Ok(id) = ArgReader::new(&msg.payload).u32() {
+                    let stats = crate::buffer::format_stats(&self.buffers.all_stats());
+                    return pipe::write_messages(id, stats.as_bytes());
+                }
+            }
+
+            // winpipe_control.screenshot(pipe, toplevel) -> write a PNG of
+            // the toplevel's frame into the helper's pipe, empty if it has none
+            (CONTROL_INTERFACE, pipe::opcodes::SCREENSHOT) => {
+                let mut args = ArgReader::new(&msg.payload);
+                if let (Ok(id), Ok(toplevel)) = (args.u32(), args.u32()) {
+                    let png = self.screenshot((toplevel != 0).then_some(toplevel)).unwrap_or_default();
+                    return pipe::write_messages(id, &png);
+                }
+            }
+
+            // winpipe_control.ping -> the helper answered; its traffic
+            // already showed the client is alive
+            (CONTROL_INTERFACE, pipe::opcodes::PING) => {}
+
+            // winpipe_control.sync_signaled(timeline, point_hi, point_lo)
+            // -> composite the frames that waited for it
+            (CONTROL_INTERFACE, pipe::opcodes::SYNC_SIGNALED) => {
+                let mut args = ArgReader::new(&msg.payload);
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok(SyncPoint::from_wire(args.u32()?, args.u32()?, args.u32()?))
+                })();
+                let Ok(point) = parsed else {
+                    return Vec::new();
+                };
+                let (ready, waiting) = std::mem::take(&mut self.held_frames)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|&(acquire, _, _)| acquire == point);
+                self.held_frames = waiting;
+
+                let mut responses = Vec::new();
+                for (_, release, root) in ready {
+                    if !self.held_frames.iter().any(|&(_, _, held)| held == root) {
+                        self.submit_frame(root);
+                    }
+                    responses.push(syncobj::release_message(release));
+                }
+                return responses;
+            }
+
+            // wl_data_device_manager.get_data_device
+            ("wl_data_device_manager", opcodes::data_device_manager::GET_DATA_DEVICE) => {
+                if let Ok(device_id) = ArgReader::new(&msg.payload).u32() {
+                    self.insert_child(device_id, "wl_data_device", msg.object_id);
+                    self.data_devices.push(device_id);
+                    info!("wl_data_device_manager.get_data_device (id={})", device_id);
+
+                    // Late binders still see the current clipboard
+                    if let Some(selection) = self.selection.clone() {
+                        return self.offer_selection(device_id, selection);
+                    }
+                }
+            }
+
+            // wl_data_device.release
+            ("wl_data_device", opcodes::data_device::RELEASE) => {
+                self.data_devices.retain(|&id| id != msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            // wl_data_offer.receive -> stream content into the virtual pipe
+            ("wl_data_offer", opcodes::data_offer::RECEIVE) => {
+                let mime = match ArgReader::new(&msg.payload).string() {
+                    Ok(mime) => mime,
+                    Err(e) => {
+                        warn!("wl_data_offer.receive: {}", e);
+                        return Vec::new();
+                    }
+                };
+
+                let Some(pipe_id) = self.pipes.pop() else {
+                    warn!("wl_data_offer.receive ({}) without a virtual pipe", mime);
+                    return Vec::new();
+                };
+
+                let data = self.data_offers.get(&msg.object_id)
+                    .and_then(|selection| selection.get(&mime))
+                    .unwrap_or(&[]);
+                info!("wl_data_offer.receive: {} ({} bytes) -> pipe {}", mime, data.len(), pipe_id);
+                return pipe::write_messages(pipe_id, data);
+            }
+
+            // wl_data_offer.destroy
+            ("wl_data_offer", opcodes::data_offer::DESTROY) => {
+                self.data_offers.remove(&msg.object_id);
+                self.objects.remove(&msg.object_id);
+            }
+
+            _ => {
+                debug!("Unhandled: {}@{}.{}", interface, msg.object_id, msg.opcode);
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Apply a content update committed on `surface`
+    fn apply_commit(&mut self, surface: u32, update: ContentUpdate) -> Vec<Message> {
+        self.awaiting_presentation.extend(update.feedback.into_iter().map(|id| (id, surface)));
+        self.awaiting_frame_callbacks.extend(update.frame_callbacks.into_iter().map(|id| (id, surface)));
+        // Explicitly synchronized content waits for its acquire point
+        if let Some(sync) = update.sync {
+            let buffer = update.state.buffer
+                .filter(|_| update.state.attached)
+                .map(|buffer| self.dmabuf_buffers.contains(&buffer));
+            match sync.validate(buffer) {
+                Ok(Some((acquire, release))) => {
+                    self.held_frames.push((acquire, release, self.surfaces.root(surface)));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("wp_linux_drm_syncobj_surface_v1: {}", e),
+            }
+        }
+        if update.schedule.set_barrier {
+            if let Some(queue) = self.commit_queues.get_mut(&surface) {
+                queue.barrier = true;
+            }
+        }
+        let attached = update.state.attached;
+        if let Some(root) = self.surfaces.apply(surface, update.state) {
+            self.hook(|h| h.surface_committed(surface));
+            // Buffers no surface of the tree shows any more can be reused
+            let mut responses = Vec::new();
+            for (id, _, _) in self.surfaces.render_order(root) {
+                let buffer = self.surfaces.get(id).and_then(|s| s.current.buffer);
+                if let Some(old) = self.buffer_tracker.commit(id, buffer, attached && id == surface) {
+                    responses.push(crate::release::release(old));
+                }
+            }
+            if self.cursor_surface.is_some_and(|(surface, _)| surface == root) {
+                self.submit_cursor();
+            }
+            if !self.held_frames.iter().any(|&(_, _, held)| held == root) {
+                self.submit_frame(root);
+            }
+            self.submit_input_region(root);
+            self.submit_presentation_hint(root);
+            self.submit_color_space(root);
+            self.submit_opacity(root);
+            // A toplevel enters the output once it is mapped
+            let mapped = self.surfaces.get(root).is_some_and(|s| s.current.buffer.is_some());
+            let toplevel = self.toplevel_for_surface(root).filter(|_| mapped);
+            if let Some(toplevel) = toplevel.filter(|_| !self.surface_outputs.contains_key(&root)) {
+                self.surface_outputs.insert(root, Vec::new());
+                self.hook(|h| h.toplevel_mapped(toplevel, root));
+                responses.extend(self.sync_surface_outputs());
+                // The first mapped window gets keyboard focus
+                if self.keyboard_focus.is_none() {
+                    responses.extend(self.set_keyboard_focus(Some(root)));
+                }
+            }
+            return responses;
+        }
+        Vec::new()
+    }
+
+    /// Complete the frame callbacks of every committed surface
+    ///
+    /// Runs on each vblank reported by the renderer; without a renderer
+    /// it should be called on a timer so clients keep drawing. Frames held
+    /// back meanwhile are sent now, covering all damage since the last one.
+    pub fn frame_done(&mut self) -> Vec<Message> {
+        let time = callback_time();
+        if !self.frame_acks {
+            self.frames_in_flight = 0;
+        }
+        let mut responses = self.release_commits();
+        for root in std::mem::take(&mut self.skipped_frames) {
+            self.send_frame(root);
+        }
+        let latency = self.presentation_latency;
+        if let Some(stats) = self.overlay_stats.as_mut().and_then(|stats| stats.sample(Instant::now(), latency)) {
+            self.queue_stats_overlay(stats);
+        }
+        for (id, _) in std::mem::take(&mut self.awaiting_frame_callbacks) {
+            self.objects.remove(&id);
+            responses.push(Message::new(id, opcodes::callback::DONE, time.to_le_bytes().to_vec()));
+            responses.push(delete_id(id));
+        }
+        responses
+    }
+
+    /// Apply the held content updates due at this refresh
+    ///
+    /// Each refresh clears the FIFO barriers; updates applied now reach the
+    /// screen at the next vblank.
+    fn release_commits(&mut self) -> Vec<Message> {
+        let next_vblank = self.last_vblank.map(|(time, refresh)| time + refresh);
+        let mut responses = Vec::new();
+        for surface in self.commit_queues.keys().copied().collect::<Vec<_>>() {
+            if let Some(queue) = self.commit_queues.get_mut(&surface) {
+                queue.barrier = false;
+            }
+            while let Some(update) = self.commit_queues.get_mut(&surface).and_then(|q| q.release(next_vblank)) {
+                responses.extend(self.apply_commit(surface, update));
+            }
+        }
+        responses
+    }
+
+    /// Record a new wp_image_description_v1 and return its ready event
+    fn add_image_description(&mut self, id: u32, description: ImageDescription, information: bool) -> Message {
+        let identity = self.color_identities.get(&description);
+        debug!("wp_image_description_v1@{}: {:?} (identity {})", id, description, identity);
+        self.image_descriptions.insert(id, (description, information));
+        color::ready(id, identity)
+    }
+
+    /// Events for outputs whose color changed (by global name) and, if the
+    /// preferred description is no longer `old_preferred`, for feedbacks
+    fn color_events(&mut self, old_preferred: ImageDescription, changed: &[u32]) -> Vec<Message> {
+        let mut events: Vec<Message> = self.color_outputs.iter()
+            .filter(|(_, output)| self.output_objects.get(output).is_some_and(|(global, _)| changed.contains(global)))
+            .map(|(&id, _)| Message::new(id, color::output_events::IMAGE_DESCRIPTION_CHANGED, vec![]))
+            .collect();
+        let preferred = self.preferred_description();
+        if preferred != old_preferred {
+            let identity = self.color_identities.get(&preferred);
+            events.extend(self.color_feedbacks.keys().map(|&feedback| {
+                Message::new(feedback, color::feedback_events::PREFERRED_CHANGED, identity.to_le_bytes().to_vec())
+            }));
+        }
+        events
+    }
+
+    /// Image description clients should prefer: that of the primary output,
+    /// which every window is on
+    fn preferred_description(&self) -> ImageDescription {
+        ImageDescription::for_monitor(&self.primary_output().color)
+    }
+
+    /// The icon `id` if it may still change, else a protocol error
+    fn mutable_icon(&mut self, id: u32) -> Option<&mut ToplevelIcon> {
+        if self.toplevel_icons.get(&id)?.immutable {
+            self.protocol_error = Some(display_error(id, toplevel_icon_error::IMMUTABLE, "icon was already assigned to a toplevel"));
+            return None;
+        }
+        self.toplevel_icons.get_mut(&id)
+    }
+
+    /// Capture a screencopy frame into the client's `buffer`
+    fn copy_capture(&mut self, frame_id: u32, buffer: u32, with_damage: bool) -> Vec<Message> {
+        let Some(frame) = self.capture_frames.get_mut(&frame_id) else {
+            return vec![capture::failed(frame_id)];
+        };
+        if std::mem::replace(&mut frame.copied, true) {
+            self.protocol_error = Some(display_error(frame_id, screencopy_error::ALREADY_USED, "frame was already copied"));
+            return Vec::new();
+        }
+        let frame = *frame;
+
+        let (width, height) = frame.size;
+        let fits = self.buffers.get(buffer)
+            .is_some_and(|b| (b.width, b.height, b.stride) == (width, height, width * 4))
+            && matches!(self.buffer_formats.get(&buffer), Some(&shm_format::XRGB8888 | &shm_format::ARGB8888));
+        if !fits {
+            let message = format!("buffer {} is not a {}x{} 32-bit wl_shm buffer", buffer, width, height);
+            self.protocol_error = Some(display_error(frame_id, screencopy_error::INVALID_BUFFER, &message));
+            return Vec::new();
+        }
+
+        let Some((_, monitor)) = self.outputs.iter().find(|(name, _)| *name == frame.output) else {
+            return vec![capture::failed(frame_id)];
+        };
+
+        // Reading the desktop blocks, so it runs off the connection loop
+        let screen_capture = self.screen_capture;
+        let monitor = monitor.clone();
+        self.spawn(async move {
+            let image = tokio::task::spawn_blocking(move || screen_capture(&monitor)).await.ok().flatten();
+            Box::new(move |comp: &mut Compositor| comp.finish_capture(frame_id, buffer, with_damage, image)) as Completion
+        });
+        Vec::new()
+    }
+
+    /// Deliver a screen capture into the client's buffer
+    fn finish_capture(&mut self, frame_id: u32, buffer: u32, with_damage: bool, image: Option<Image>) -> Vec<Message> {
+        // The client may have destroyed the frame while it was captured
+        let Some(frame) = self.capture_frames.get(&frame_id).copied() else {
+            return Vec::new();
+        };
+        let Some(image) = image else {
+            warn!("zwlr_screencopy_frame_v1@{}: screen capture failed", frame_id);
+            return vec![capture::failed(frame_id)];
+        };
+        let image = match frame.region {
+            Some(region) => image.crop(region),
+            None => image,
+        };
+        if (image.width, image.height) != frame.size || self.buffers.get(buffer).is_none() {
+            return vec![capture::failed(frame_id)];
+        }
+
+        let mut responses = pipe::buffer_content_messages(buffer, &image.data);
+        responses.extend(capture::ready_events(frame_id, frame.size, with_damage, monotonic_time()));
+        responses
+    }
+
+    /// Announce the toplevels of all clients to the foreign toplevel lists
+    ///
+    /// `toplevels` is the complete current list; only the difference to
+    /// the previous one is sent.
+    pub fn update_foreign_toplevels(&mut self, toplevels: Vec<ForeignToplevel>) -> Vec<Message> {
+        let old = std::mem::replace(&mut self.foreign_toplevels, toplevels);
+        let mut handles: Vec<(u32, String)> = self.foreign_handles.iter()
+            .map(|(&handle, identifier)| (handle, identifier.clone()))
+            .collect();
+        handles.sort_unstable();
+
+        let mut responses = Vec::new();
+        for (handle, identifier) in handles {
+            let previous = old.iter().find(|t| t.identifier == identifier);
+            match self.foreign_toplevels.iter().find(|t| t.identifier == identifier) {
+                Some(current) => responses.extend(foreign_toplevel::update(handle, previous.unwrap_or(current), current)),
+                None => {
+                    self.foreign_handles.remove(&handle);
+                    responses.push(foreign_toplevel::closed(handle));
+                }
+            }
+        }
+
+        let added: Vec<ForeignToplevel> = self.foreign_toplevels.iter()
+            .filter(|t| !old.iter().any(|o| o.identifier == t.identifier))
+            .cloned()
+            .collect();
+        for list in self.foreign_lists.clone() {
+            for toplevel in &added {
+                responses.extend(self.announce_foreign_toplevel(list, toplevel));
+            }
+        }
+        responses
+    }
+
+    /// Create a handle for `toplevel` on `list`
+    fn announce_foreign_toplevel(&mut self, list: u32, toplevel: &ForeignToplevel) -> Vec<Message> {
+        let handle = self.allocator.alloc();
+        self.insert_child(handle, "ext_foreign_toplevel_handle_v1", list);
+        self.foreign_handles.insert(handle, toplevel.identifier.clone());
+        foreign_toplevel::announce(list, handle, toplevel)
+    }
+
+    /// Send idled/resumed to idle notifications given how long the user
+    /// has been idle
+    pub fn update_idle(&mut self, idle: Duration) -> Vec<Message> {
+        let mut ids: Vec<u32> = self.idle_notifications.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .filter_map(|id| self.idle_notifications.get_mut(&id)?.update(id, idle))
+            .collect()
+    }
+
+    /// Handle an event coming back from the renderer window of the
+    /// toplevel with root wl_surface `window` (0 for the renderer as a whole)
+    pub fn handle_renderer_event(&mut self, window: u32, event: &RendererEvent) -> Vec<Message> {
+        let events = self.dispatch_renderer_event(window, event);
+        self.gate_events(events)
+    }
+
+    fn dispatch_renderer_event(&mut self, window: u32, event: &RendererEvent) -> Vec<Message> {
+        match event {
+            RendererEvent::Close => {
+                // Ask the window's toplevel to close; the client decides
+                // whether to prompt or exit
+                let Some(id) = self.toplevel_for_surface(window) else {
+                    return Vec::new();
+                };
+                info!("Renderer window closed -> xdg_toplevel@{}.close", id);
+                vec![Message::new(id, opcodes::xdg_toplevel::CLOSE, vec![])]
+            }
+
+            RendererEvent::Resize { width, height } => {
+                let Some(id) = self.toplevel_for_surface(window) else {
+                    return Vec::new();
+                };
+                // The renderer reports output pixels; configure in logical units
+                let scale = self.primary_output().fractional_scale();
+                let size = ((*width as f64 / scale).round() as i32, (*height as f64 / scale).round() as i32);
+                let interactive = self.windows.get(&window).is_some_and(|w| w.native_resize);
+                let Some(toplevel) = self.toplevels.get_mut(&id) else {
+                    return Vec::new();
+                };
+                // Dragging the native frame resizes like xdg_toplevel.resize
+                let started = interactive && !toplevel.resizing;
+                if toplevel.maximized || toplevel.fullscreen || (toplevel.floating_size == size && !started) {
+                    return Vec::new();
+                }
+                toplevel.floating_size = size;
+                toplevel.resizing |= interactive;
+                self.configure_toplevel(id)
+            }
+
+            RendererEvent::WindowState { state } => {
+                let Some(id) = self.toplevel_for_surface(window) else {
+                    return Vec::new();
+                };
+                let minimized = state & window_state::MINIMIZED != 0;
+                let maximized = state & window_state::MAXIMIZED != 0;
+                let fullscreen = state & window_state::FULLSCREEN != 0;
+                let Some(toplevel) = self.toplevels.get_mut(&id) else {
+                    return Vec::new();
+                };
+                let outputs_changed = toplevel.minimized != minimized;
+                toplevel.minimized = minimized;
+                let mut responses = Vec::new();
+                // Minimizing keeps the state the window is restored to
+                if !minimized && (toplevel.maximized, toplevel.fullscreen) != (maximized, fullscreen) {
+                    info!("xdg_toplevel@{}: native window maximized={} fullscreen={}", id, maximized, fullscreen);
+                    toplevel.maximized = maximized;
+                    toplevel.fullscreen = fullscreen;
+                    responses.extend(self.configure_toplevel(id));
+                }
+                // Minimized windows leave the output until restored
+                if outputs_changed {
+                    responses.extend(self.sync_surface_outputs());
+                }
+                responses
+            }
+
+            RendererEvent::InteractiveBegin => {
+                if self.is_toplevel_surface(window) {
+                    self.windows.entry(window).or_default().native_resize = true;
+                }
+                Vec::new()
+            }
+
+            RendererEvent::ImePreedit { text, cursor_begin, cursor_end } => {
+                self.active_text_inputs()
+                    .flat_map(|(id, input)| input.preedit_events(id, text, *cursor_begin, *cursor_end))
+                    .collect()
+            }
+
+            RendererEvent::ImeCommit { text } => {
+                self.active_text_inputs()
+                    .flat_map(|(id, input)| input.commit_events(id, text))
+                    .collect()
+            }
+
+            RendererEvent::Focus { focused } => {
+                debug!("Renderer window {} focus: {}", window, focused);
+                let mut responses = Vec::new();
+                if *focused {
+                    // The focused native window takes keyboard focus
+                    if self.is_toplevel_surface(window) && self.keyboard_focus != Some(window) {
+                        responses.extend(self.set_keyboard_focus(Some(window)));
+                    }
+                } else if self.focused_window() != window {
+                    // Another window already took focus
+                    return Vec::new();
+                }
+                self.window_focused = *focused;
+                responses.extend(self.update_pointer_constraints());
+                responses.extend(self.update_shortcut_inhibitors());
+                responses
+            }
+
+            RendererEvent::RelativeMotion { time_usec, dx, dy, dx_unaccel, dy_unaccel } => {
+                self.relative_pointers.iter()
+                    .map(|&id| pointer_constraints::relative_motion(id, *time_usec, *dx, *dy, *dx_unaccel, *dy_unaccel))
+                    .collect()
+            }
+
+            RendererEvent::GestureBegin { kind, time, fingers } => {
+                // Gestures go to the window they were made over
+                if !self.is_toplevel_surface(window) {
+                    return Vec::new();
+                }
+                let surface = window;
+                self.active_gesture = Some(*kind);
+                let serial = self.next_serial();
+                self.gesture_ids(*kind).into_iter()
+                    .map(|id| gestures::begin(id, serial, *time, surface, *fingers))
+                    .collect()
+            }
+
+            RendererEvent::GestureUpdate { kind, time, dx, dy, scale, rotation } => {
+                if self.active_gesture != Some(*kind) {
+                    return Vec::new();
+                }
+                // Deltas arrive in window pixels; clients expect surface units
+                let output_scale = self.primary_output().fractional_scale();
+                let (dx, dy) = (dx / output_scale, dy / output_scale);
+                self.gesture_ids(*kind).into_iter()
+                    .filter_map(|id| gestures::update(id, *kind, *time, dx, dy, *scale, *rotation))
+                    .collect()
+            }
+
+            RendererEvent::GestureEnd { kind, time, cancelled } => {
+                if self.active_gesture.take() != Some(*kind) {
+                    return Vec::new();
+                }
+                let serial = self.next_serial();
+                self.gesture_ids(*kind).into_iter()
+                    .map(|id| gestures::end(id, *kind, serial, *time, *cancelled))
+                    .collect()
+            }
+
+            RendererEvent::Pen(sample) => {
+                // Like gestures, pen input goes to the window it is over
+                if !self.is_toplevel_surface(window) {
+                    return Vec::new();
+                }
+                let surface = window;
+                let output_scale = self.primary_output().fractional_scale();
+                let sample = PenSample { x: sample.x / output_scale, y: sample.y / output_scale, ..*sample };
+                let serial = self.next_serial();
+
+                let mut ids: Vec<u32> = self.tablet_seats.keys().copied().collect();
+                ids.sort_unstable();
+                let mut responses = Vec::new();
+                for id in ids {
+                    let Some((seat, last)) = self.tablet_seats.get_mut(&id) else { continue };
+                    responses.extend(seat.pen_events(last.as_ref(), &sample, surface, serial));
+                    *last = Some(sample).filter(|s| s.in_range());
+                }
+                responses
+            }
+
+            RendererEvent::Presented { time_ns, refresh_ns, seq, flags } => {
+                // Only feedback for what this window shows was presented
+                let mut responses = Vec::new();
+                let (presented, waiting) = std::mem::take(&mut self.awaiting_presentation).into_iter()
+                    .partition(|&(_, surface)| self.window_of(surface) == window);
+                self.awaiting_presentation = waiting;
+                for (id, surface) in presented {
+                    let root = self.surfaces.root(surface);
+                    for &output in self.surface_outputs.get(&root).into_iter().flatten() {
+                        responses.push(presentation::sync_output(id, output));
+                    }
+                    responses.push(presentation::presented(id, *time_ns, *refresh_ns, *seq, *flags));
+                    self.objects.remove(&id);
+                }
+                self.last_vblank = Some((Duration::from_nanos(*time_ns), Duration::from_nanos(*refresh_ns as u64)));
+                responses.extend(self.frame_done());
+                responses
+            }
+
+            RendererEvent::PointerMotion { time, x, y } => {
+                // Like pen input, the pointer is over the window reporting it
+                if !self.is_toplevel_surface(window) {
+                    return Vec::new();
+                }
+                let surface = window;
+                let output_scale = self.primary_output().fractional_scale();
+                let (x, y) = (x / output_scale, y / output_scale);
+                let ids = self.pointer_ids();
+                if self.pointer_focus == Some(surface) {
+                    return ids.into_iter()
+                        .flat_map(|id| [pointer::motion(id, *time, x, y), pointer::frame(id)])
+                        .collect();
+                }
+                let mut responses = self.pointer_leave();
+                let serial = self.next_serial();
+                self.pointer_focus = Some(surface);
+                responses.extend(ids.into_iter().flat_map(|id| [pointer::enter(id, serial, surface, x, y), pointer::frame(id)]));
+                responses
+            }
+
+            RendererEvent::PointerLeave => {
+                // The pointer may already have entered another window
+                if self.pointer_focus.is_some_and(|surface| self.window_of(surface) != window) {
+                    return Vec::new();
+                }
+                self.pointer_leave()
+            }
+
+            RendererEvent::PointerButton { time, button, pressed } => {
+                if self.pointer_focus.is_none() {
+                    return Vec::new();
+                }
+                let serial = self.next_serial();
+                self.pointer_ids().into_iter()
+                    .flat_map(|id| [pointer::button(id, serial, *time, *button, *pressed), pointer::frame(id)])
+                    .collect()
+            }
+
+            RendererEvent::PointerAxis { time, axis, value, value120 } => {
+                if self.pointer_focus.is_none() {
+                    return Vec::new();
+                }
+                let mut responses = Vec::new();
+                for id in self.pointer_ids() {
+                    responses.extend(pointer::axis(id, *time, *axis, *value, *value120));
+                    responses.push(pointer::frame(id));
+                }
+                responses
+            }
+
+            // No wl_keyboard or wl_touch is offered yet
+            RendererEvent::Key { .. } | RendererEvent::Touch { .. } => Vec::new(),
+
+            // Routing information for the shared runtime, never seen here
+            RendererEvent::Window { .. } => Vec::new(),
+
+            RendererEvent::Capabilities(capabilities) => {
+                self.set_renderer_capabilities(*capabilities);
+                Vec::new()
+            }
+
+            RendererEvent::FrameAck { sequence } => {
+                self.frame_acks = true;
+                let Some(state) = self.windows.get_mut(&window) else {
+                    return Vec::new();
+                };
+                while let Some(&(sent, at)) = state.unacked_frames.front().filter(|(sent, _)| sent <= sequence) {
+                    state.unacked_frames.pop_front();
+                    if sent == *sequence {
+                        // Smoothed like a TCP round-trip time
+                        let latency = at.elapsed();
+                        let smoothed = self.presentation_latency.map_or(latency, |average| (average * 7 + latency) / 8);
+                        debug!("Frame {} presented after {:?} (average {:?})", sent, latency, smoothed);
+                        self.presentation_latency = Some(smoothed);
+                    }
+                }
+                self.frames_in_flight = self.windows.values().map(|w| w.unacked_frames.len() as u32).sum();
+                // Frames held back meanwhile go out as one
+                for root in std::mem::take(&mut self.skipped_frames) {
+                    self.send_frame(root);
+                }
+                Vec::new()
+            }
+
+            RendererEvent::Resync { buffer_id } => {
+                // Start over from a full frame
+                let Some(state) = self.windows.get_mut(&window) else {
+                    return Vec::new();
+                };
+                if state.sent_frame.as_ref().is_some_and(|(_, sent)| sent.id == *buffer_id) {
+                    warn!("Renderer copy of wl_surface@{} diverged, resending it", buffer_id);
+                    state.sent_frame = None;
+                    self.send_frame(window);
+                }
+                Vec::new()
+            }
+
+            RendererEvent::InteractiveEnd => {
+                // Drop the resizing state with a final configure
+                if let Some(state) = self.windows.get_mut(&window) {
+                    state.native_resize = false;
+                }
+                let Some(id) = self.toplevel_for_surface(window) else {
+                    return Vec::new();
+                };
+                let resizing = self.toplevels.get_mut(&id).is_some_and(|toplevel| std::mem::take(&mut toplevel.resizing));
+                if resizing { self.configure_toplevel(id) } else { Vec::new() }
+            }
+        }
+    }
+
+    /// wl_pointer objects, in creation order
+    fn pointer_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.objects.iter()
+            .filter(|(_, iface)| iface.as_str() == "wl_pointer")
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Leave the surface the pointer is over, if any
+    fn pointer_leave(&mut self) -> Vec<Message> {
+        let Some(surface) = self.pointer_focus.take() else {
+            return Vec::new();
+        };
+        let serial = self.next_serial();
+        self.pointer_ids().into_iter()
+            .flat_map(|id| [pointer::leave(id, serial, surface), pointer::frame(id)])
+            .collect()
+    }
+
+    /// Gesture objects of a kind, in creation order
+    fn gesture_ids(&self, kind: u32) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.gestures.iter()
+            .filter(|(_, &k)| k == kind)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Enabled text inputs on the focused surface
+    fn active_text_inputs(&self) -> impl Iterator<Item = (u32, &TextInput)> {
+        self.text_inputs.iter()
+            .filter(|(_, input)| input.is_active())
+            .map(|(&id, input)| (id, input))
+    }
+
+    /// Apply a zwp_text_input_v3 request
+    fn handle_text_input(&mut self, input_id: u32, opcode: u16, payload: &[u8]) {
+        use opcodes::text_input as op;
+
+        let Some(input) = self.text_inputs.get_mut(&input_id) else {
+            return;
+        };
+        let mut args = ArgReader::new(payload);
+        match opcode {
+            op::DESTROY => {
+                self.text_inputs.remove(&input_id);
+                self.objects.remove(&input_id);
+                self.submit_ime_state();
+            }
+            op::ENABLE => input.enable(),
+            op::DISABLE => input.disable(),
+            op::SET_SURROUNDING_TEXT => {
+                if let (Ok(text), Ok(cursor), Ok(anchor)) = (args.string(), args.i32(), args.i32()) {
+                    input.pending.surrounding_text = Some((text, cursor, anchor));
+                }
+            }
+            op::SET_TEXT_CHANGE_CAUSE => {}
+            op::SET_CONTENT_TYPE => {
+                if let (Ok(hint), Ok(purpose)) = (args.u32(), args.u32()) {
+                    input.pending.content_hint = hint;
+                    input.pending.content_purpose = purpose;
+                }
+            }
+            op::SET_CURSOR_RECTANGLE => {
+                if let (Ok(x), Ok(y), Ok(w), Ok(h)) = (args.i32(), args.i32(), args.i32(), args.i32()) {
+                    input.pending.cursor_rect = Some(Rect::new(x, y, w, h));
+                }
+            }
+            op::COMMIT => {
+                input.commit();
+                debug!("zwp_text_input_v3@{} commit: {:?}", input_id, input.current);
+                self.submit_ime_state();
+            }
+            _ => debug!("Unhandled: zwp_text_input_v3@{}.{}", input_id, opcode),
+        }
+    }
+
+    /// Move keyboard focus, sending text input leave/enter
+    fn set_keyboard_focus(&mut self, surface: Option<u32>) -> Vec<Message> {
+        let mut ids: Vec<u32> = self.text_inputs.keys().copied().collect();
+        ids.sort_unstable();
+
+        let mut responses = Vec::new();
+        for &id in &ids {
+            let input = self.text_inputs.get_mut(&id).expect("listed above");
+            if let Some(old) = input.focus.take() {
+                responses.push(Message::new(id, text_input::events::LEAVE, old.to_le_bytes().to_vec()));
+            }
+        }
+        // Seat state shown by the old renderer window is released there
+        // before the new one takes it
+        if surface.map(|s| self.window_of(s)) != self.keyboard_focus.map(|s| self.window_of(s)) {
+            let focused = std::mem::replace(&mut self.window_focused, false);
+            self.submit_ime_state();
+            responses.extend(self.update_pointer_constraints());
+            responses.extend(self.update_shortcut_inhibitors());
+            self.window_focused = focused;
+        }
+        for id in ids {
+            let input = self.text_inputs.get_mut(&id).expect("listed above");
+            if let Some(new) = surface {
+                input.focus = Some(new);
+                responses.push(Message::new(id, text_input::events::ENTER, new.to_le_bytes().to_vec()));
+            }
+        }
+        self.keyboard_focus = surface;
+        self.submit_ime_state();
+        responses.extend(self.update_pointer_constraints());
+        responses.extend(self.update_shortcut_inhibitors());
+        responses
+    }
+
+    /// Activate constraints on the focused window and release the rest
+    ///
+    /// Constraints are only honoured while the Windows window has focus,
+    /// so losing focus always unlocks the pointer.
+    fn update_pointer_constraints(&mut self) -> Vec<Message> {
+        let mut ids: Vec<u32> = self.constraints.keys().copied().collect();
+        ids.sort_unstable();
+
+        let mut responses = Vec::new();
+        for id in ids {
+            let surface = self.constraints[&id].surface;
+            let focused = self.window_focused && self.keyboard_focus == Some(self.surfaces.root(surface));
+            let constraint = self.constraints.get_mut(&id).expect("listed above");
+            let event = if focused { constraint.activate(id) } else { constraint.deactivate(id) };
+            responses.extend(event);
+        }
+        self.submit_pointer_constraint();
+        responses
+    }
+
+    /// Forward the active pointer constraint to the renderer if it changed
+    fn submit_pointer_constraint(&mut self) {
+        let active = self.constraints.iter()
+            .filter(|(_, c)| c.active)
+            .min_by_key(|(&id, _)| id)
+            .map(|(_, c)| c);
+
+        let mut position = None;
+        let constraint = match active {
+            Some(constraint) => {
+                // Region is surface-local; the renderer wants window pixels
+                let root = self.surfaces.root(constraint.surface);
+                let (ox, oy) = self.surfaces.render_order(root).iter()
+                    .find(|(id, _, _)| *id == constraint.surface)
+                    .map_or((0, 0), |&(_, x, y)| (x, y));
+                let scale = self.primary_output().fractional_scale();
+                let to_pixels = |value: i32| (value as f64 * scale).round() as i32;
+                let rects = constraint.region.as_ref().map(|region| {
+                    region.rects().iter()
+                        .map(|r| Rect::new(to_pixels(r.x + ox), to_pixels(r.y + oy), to_pixels(r.width), to_pixels(r.height)))
+                        .collect()
+                });
+                let kind = match constraint.kind {
+                    ConstraintKind::Lock => constraint_kind::LOCK,
+                    ConstraintKind::Confine => constraint_kind::CONFINE,
+                };
+                position = constraint.position_hint.filter(|_| kind == constraint_kind::LOCK).map(|(x, y)| {
+                    (((x + ox as f64) * scale).round() as i32, ((y + oy as f64) * scale).round() as i32)
+                });
+                PointerConstraint { kind, rects }
+            }
+            None => PointerConstraint { kind: constraint_kind::NONE, rects: None },
+        };
+
+        // Nothing to release if no constraint was ever sent
+        if self.sent_constraint.is_none() && constraint.kind == constraint_kind::NONE {
+            return;
+        }
+        if self.sent_constraint.as_ref() != Some(&constraint) {
+            self.sent_constraint = Some(constraint.clone());
+            // Only the focused window's constraints are ever active
+            let window = self.focused_window();
+            self.render_queue.push((window, RenderMessage::PointerConstraint(constraint)));
+        }
+
+        // The hint goes out as a cursor position, so a locked pointer moves
+        // the cursor without a new frame or cursor image
+        if let Some((x, y)) = position.filter(|&p| self.sent_cursor_position != Some(p)) {
+            let window = self.focused_window();
+            self.render_queue.push((window, RenderMessage::Cursor(CursorUpdate::Position { x, y })));
+        }
+        self.sent_cursor_position = position;
+    }
+
+    /// Activate shortcut inhibitors on the focused window and deactivate the rest
+    fn update_shortcut_inhibitors(&mut self) -> Vec<Message> {
+        use opcodes::keyboard_shortcuts_inhibitor as events;
+
+        let mut ids: Vec<u32> = self.shortcut_inhibitors.keys().copied().collect();
+        ids.sort_unstable();
+
+        let mut responses = Vec::new();
+        for id in ids {
+            let (surface, active) = self.shortcut_inhibitors[&id];
+            let focused = self.window_focused && self.keyboard_focus == Some(surface);
+            if focused != active {
+                self.shortcut_inhibitors.insert(id, (surface, focused));
+                let opcode = if focused { events::ACTIVE } else { events::INACTIVE };
+                responses.push(Message::new(id, opcode, vec![]));
+            }
+        }
+        self.submit_shortcuts_inhibit();
+        responses
+    }
+
+    /// Tell the renderer whether to capture system shortcuts if that changed
+    fn submit_shortcuts_inhibit(&mut self) {
+        let inhibit = ShortcutsInhibit { active: self.shortcut_inhibitors.values().any(|&(_, active)| active) };
+        if inhibit != self.sent_shortcuts_inhibit {
+            debug!("Keyboard shortcuts inhibited: {}", inhibit.active);
+            self.sent_shortcuts_inhibit = inhibit;
+            let window = self.focused_window();
+            self.render_queue.push((window, RenderMessage::ShortcutsInhibit(inhibit)));
+        }
+    }
+
+    /// Forward the IME state of the active text input to the renderer if it changed
+    fn submit_ime_state(&mut self) {
+        let scale = self.primary_output().fractional_scale();
+        let to_pixels = |value: i32| (value as f64 * scale).round() as i32;
+        let state = match self.active_text_inputs().map(|(_, input)| &input.current).next() {
+            Some(current) => ImeState {
+                enabled: true,
+                cursor_rect: current.cursor_rect
+                    .map(|r| Rect::new(to_pixels(r.x), to_pixels(r.y), to_pixels(r.width), to_pixels(r.height)))
+                    .unwrap_or_default(),
+                purpose: current.content_purpose,
+            },
+            None => ImeState::default(),
+        };
+        if state != self.sent_ime {
+            self.sent_ime = state;
+            let window = self.focused_window();
+            self.render_queue.push((window, RenderMessage::Ime(state)));
+        }
+    }
+
+    /// xdg_toplevel IDs in creation order
+    fn toplevel_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.toplevels.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Mirrored buffer contents, updated as buffer data arrives from the client side
+    pub fn buffers_mut(&mut self) -> &mut BufferManager {
+        &mut self.buffers
+    }
+
+    /// Apply a wl_subsurface request
+    fn handle_subsurface(&mut self, subsurface_id: u32, opcode: u16, payload: &[u8]) {
+        use opcodes::subsurface as op;
+
+        let Some(surface) = self.surfaces.surface_for_subsurface(subsurface_id) else {
+            return;
+        };
+        let mut args = ArgReader::new(payload);
+        match opcode {
+            op::DESTROY => {
+                self.surfaces.remove_subsurface(surface);
+                self.objects.remove(&subsurface_id);
+            }
+            op::SET_POSITION => {
+                if let (Ok(x), Ok(y)) = (args.i32(), args.i32()) {
+                    self.surfaces.set_position(surface, x, y);
+                }
+            }
+            op::PLACE_ABOVE | op::PLACE_BELOW => {
+                if let Ok(sibling) = args.u32() {
+                    if let Err(e) = self.surfaces.restack(surface, sibling, opcode == op::PLACE_ABOVE) {
+                        warn!("wl_subsurface@{}.{}: {}", subsurface_id, opcode, e);
+                    }
+                }
+            }
+            op::SET_SYNC => self.surfaces.set_sync(surface, true),
+            op::SET_DESYNC => {
+                self.surfaces.set_sync(surface, false);
+                let root = self.surfaces.root(surface);
+                self.submit_frame(root);
+            }
+            _ => debug!("Unhandled: wl_subsurface@{}.{}", subsurface_id, opcode),
+        }
+    }
+
+    /// Apply a wp_viewport request
+    fn handle_viewport(&mut self, viewport_id: u32, opcode: u16, payload: &[u8]) {
+        use opcodes::viewport as op;
+
+        let Some(&surface) = self.viewports.get(&viewport_id) else {
+            return;
+        };
+        let mut args = ArgReader::new(payload);
+        match opcode {
+            op::DESTROY => {
+                // The viewport is removed from the surface on the next commit
+                self.surfaces.set_viewport_source(surface, None);
+                self.surfaces.set_viewport_destination(surface, None);
+                self.viewports.remove(&viewport_id);
+                self.objects.remove(&viewport_id);
+            }
+            op::SET_SOURCE => {
+                let parsed = (|| -> crate::error::Result<_> {
+                    Ok((args.fixed()?, args.fixed()?, args.fixed()?, args.fixed()?))
+                })();
+                match parsed {
+                    // All -1 unsets the source rectangle
+                    Ok((x, y, w, h)) if x == -1.0 && y == -1.0 && w == -1.0 && h == -1.0 => {
+                        self.surfaces.set_viewport_source(surface, None);
+                    }
+                    Ok((x, y, w, h)) if x >= 0.0 && y >= 0.0 && w > 0.0 && h > 0.0 => {
+                        self.surfaces.set_viewport_source(surface, Some((x, y, w, h)));
+                    }
+                    Ok(source) => warn!("wp_viewport.set_source: invalid {:?}", source),
+                    Err(e) => warn!("wp_viewport.set_source: {}", e),
+                }
+            }
+            op::SET_DESTINATION => match (args.i32(), args.i32()) {
+                (Ok(-1), Ok(-1)) => self.surfaces.set_viewport_destination(surface, None),
+                (Ok(w), Ok(h)) if w > 0 && h > 0 && w as u32 <= MAX_SURFACE_SIZE && h as u32 <= MAX_SURFACE_SIZE => {
+                    self.surfaces.set_viewport_destination(surface, Some((w, h)));
+                }
+                (Ok(w), Ok(h)) => {
+                    let message = format!("invalid destination size {}x{}", w, h);
+                    self.protocol_error = Some(display_error(viewport_id, viewport_error::BAD_VALUE, &message));
+                }
+                other => warn!("wp_viewport.set_destination: invalid {:?}", other),
+            },
+            _ => debug!("Unhandled: wp_viewport@{}.{}", viewport_id, opcode),
+        }
+    }
+
+    /// Create the mirror of a dmabuf-backed wl_buffer
+    ///
+    /// The helper fills it with the linear copy of the dmabuf contents.
+    fn create_dmabuf_buffer(&mut self, buffer_id: u32, width: i32, height: i32, format: u32, plane: Plane) {
+        self.objects.insert(buffer_id, "wl_buffer".to_string());
+        self.buffers.create(buffer_id, width as u32, height as u32, 4, plane.stride);
+        self.dmabuf_buffers.insert(buffer_id);
+        if let Some(format) = dmabuf::shm_format(format) {
+            self.buffer_formats.insert(buffer_id, format);
+        }
+        debug!("zwp_linux_dmabuf_v1: wl_buffer@{} {}x{} format={:#x} stride={}", buffer_id, width, height, format, plane.stride);
+    }
+
+    /// Composite a toplevel's surface tree and queue it for the renderer
+    ///
+    /// With frame pacing, a window sends at most one frame per
+    /// `frame_interval`; a commit coming sooner is drawn with the next
+    /// refresh.
+    fn submit_frame(&mut self, root: u32) {
+        // Popups are drawn into their window's frame
+        let root = self.frame_root(root);
+        let interval = self.frame_interval();
+        let paced = self.frame_pacing.is_some();
+        if paced && self.last_frame_sent.get(&root).is_some_and(|sent| sent.elapsed() < interval) {
+            debug!("Pacing wl_surface@{} to {:?} per frame", root, interval);
+            self.skipped_frames.insert(root);
+            return;
+        }
+        self.send_frame(root);
+    }
+
+    /// Composite and queue a window's frame now, unless the renderer is
+    /// behind
+    fn send_frame(&mut self, root: u32) {
+        if !self.is_toplevel_surface(root) {
+            return;
+        }
+        // The renderer is behind: the damage keeps accumulating in the tree
+        // until the frame goes out
+        if self.max_frames_in_flight > 0 && self.frames_in_flight >= self.max_frames_in_flight {
+            debug!("Renderer behind, holding back the frame of wl_surface@{}", root);
+            self.skipped_frames.insert(root);
+            return;
+        }
+        self.skipped_frames.remove(&root);
+
+        let frame = self.compose_window(root).map(|frame| self.native_frame(root, frame));
+        let scale = self.primary_output().fractional_scale();
+        let layers = self.popup_layers(root);
+        let damage = self.surfaces.take_layers_damage(root, &layers, scale);
+        if let Some(mut frame) = frame {
+            debug!("Queued frame {}x{} for wl_surface@{}", frame.width, frame.height, root);
+            let sequence = {
+                let window = self.windows.entry(root).or_default();
+                window.frame_sequence += 1;
+                window.frame_sequence
+            };
+            frame.surface_id = root;
+            frame.sequence = sequence;
+            let raw_bytes = frame.data.len();
+            let message = self.frame_message(root, frame, damage);
+            if let Some(stats) = &mut self.overlay_stats {
+                match &message {
+                    RenderMessage::Frame(frame) => stats.record(raw_bytes, HEADER_SIZE + frame.data.len()),
+                    RenderMessage::FrameUpdate(update) => stats.record(raw_bytes, update.delta.total_bytes),
+                    _ => {}
+                }
+            }
+            let is_delta = matches!(message, RenderMessage::FrameUpdate(_));
+            self.render_queue.push((root, message));
+            self.last_frame_sent.insert(root, Instant::now());
+            self.frames_in_flight += 1;
+            let window = self.windows.entry(root).or_default();
+            if window.unacked_frames.len() == MAX_UNACKED_FRAMES {
+                window.unacked_frames.pop_front();
+            }
+            window.unacked_frames.push_back((sequence, Instant::now()));
+            window.deltas_since_checksum = if is_delta { window.deltas_since_checksum + 1 } else { 0 };
+            // Let the renderer check it still has the same picture
+            if window.deltas_since_checksum >= CHECKSUM_INTERVAL {
+                window.deltas_since_checksum = 0;
+                if let Some((_, sent)) = &window.sent_frame {
+                    let checksum = sent.checksum(0);
+                    self.render_queue.push((root, RenderMessage::Checksum(checksum)));
+                }
+            }
+        }
+    }
+
+    /// Composite a window with its subsurfaces and popups
+    fn compose_window(&self, root: u32) -> Option<RenderFrame> {
+        let scale = self.primary_output().fractional_scale();
+        let layers = self.popup_layers(root);
+        let buffers = &self.buffers;
+        let formats = &self.buffer_formats;
+        // Buffers not in ARGB8888 layout are converted first
+        let converted: HashMap<u32, Vec<u8>> = self.surfaces.layer_order(root, &layers).into_iter()
+            .filter_map(|(id, _, _)| self.surfaces.get(id)?.current.buffer)
+            .filter_map(|id| {
+                let buffer = buffers.get(id)?;
+                let pixels = convert::to_argb8888(*formats.get(&id)?, buffer.width, buffer.height, buffer.stride, &buffer.data)?;
+                Some((id, pixels))
+            })
+            .collect();
+        self.surfaces.compose_layers(root, &layers, scale, |id| {
+            let buffer = buffers.get(id)?;
+            let opaque = formats.get(&id).is_some_and(|&format| convert::is_opaque(format));
+            Some(match converted.get(&id) {
+                Some(pixels) => BufferView { width: buffer.width, height: buffer.height, stride: buffer.width * 4, data: pixels, opaque },
+                None => BufferView { width: buffer.width, height: buffer.height, stride: buffer.stride, data: &buffer.data, opaque },
+            })
+        })
+    }
+
+    /// A window's frame in its buffer's format, if that has more than 8
+    /// bits per channel and the renderer shows it
+    ///
+    /// Only windows of a single unscaled surface are sent this way; others
+    /// are composited in ARGB8888.
+    fn native_frame(&self, root: u32, frame: RenderFrame) -> RenderFrame {
+        let layers = self.popup_layers(root);
+        let [(surface, _, _)] = self.surfaces.layer_order(root, &layers)[..] else {
+            return frame;
+        };
+        let Some(id) = self.surfaces.get(surface).and_then(|surface| surface.current.buffer) else {
+            return frame;
+        };
+        let format = match self.buffer_formats.get(&id) {
+            Some(&shm_format::XRGB2101010) => PixelFormat::XRGB2101010,
+            Some(&shm_format::ABGR16161616F) => PixelFormat::ABGR16161616F,
+            _ => return frame,
+        };
+        let Some(buffer) = self.buffers.get(id) else {
+            return frame;
+        };
+        if !self.renderer_capabilities.supports(format) || (buffer.width, buffer.height) != (frame.width, frame.height) {
+            return frame;
+        }
+        match RenderFrame::from_strided(buffer.width, buffer.height, format, buffer.stride, &buffer.data) {
+            Some(native) => RenderFrame { damage: frame.damage, ..native },
+            None => frame,
+        }
+    }
+
+    /// PNG of a toplevel's current content; `None` picks the one with
+    /// keyboard focus, or the first
+    pub fn screenshot(&self, toplevel: Option<u32>) -> Option<Vec<u8>> {
+        let surface = match toplevel {
+            Some(id) => self.xdg_surfaces.get(&self.toplevels.get(&id)?.xdg_surface).copied()?,
+            None => self.keyboard_focus.or_else(|| self.toplevels().into_iter().find_map(|t| t.surface))?,
+        };
+        let frame = self.compose_window(surface)?;
+        Some(crate::png::encode(frame.width, frame.height, &frame.data))
+    }
+
+    /// A frame, or an update holding only what changed since the last
+    /// one if that is smaller
+    ///
+    /// Unchanged frames still go out as empty updates so the renderer
+    /// presents, which completes frame callbacks. With `damage` from the
+    /// surface tree, only the damaged pixels are compared, and a full
+    /// frame carries it for the renderer to update just those parts.
+    fn frame_message(&mut self, root: u32, mut frame: RenderFrame, damage: Option<Vec<Rect>>) -> RenderMessage {
+        let same_size = |(format, last): &(PixelFormat, MirrorBuffer)| {
+            *format == frame.format && (last.width, last.height) == (frame.width, frame.height)
+        };
+        let window = self.windows.entry(root).or_default();
+        let Some((_, last)) = window.sent_frame.as_mut().filter(|sent| same_size(sent)) else {
+            let (width, height) = (frame.width, frame.height);
+            let bpp = frame.format.bytes_per_pixel().unwrap_or(4);
+            let mut mirror = MirrorBuffer::from_data(root, width, height, bpp, width * bpp, frame.data.clone());
+            mirror.xor_deltas = true;
+            window.sent_frame = Some((frame.format, mirror));
+            self.buffers.record_frame(root, frame.data.len(), frame.data.len(), true);
+            return RenderMessage::Frame(frame);
+        };
+        last.update(&frame.data);
+        for rect in damage.iter().flatten() {
+            let (x, y) = (rect.x.max(0), rect.y.max(0));
+            let (right, bottom) = (rect.x.saturating_add(rect.width), rect.y.saturating_add(rect.height));
+            if right > x && bottom > y {
+                last.mark_dirty(x as u32, y as u32, (right - x) as u32, (bottom - y) as u32);
+            }
+        }
+        let delta = damage.as_ref().is_none_or(|rects| !rects.is_empty())
+            .then(|| last.calculate_delta())
+            .flatten()
+            .unwrap_or(BufferDelta { buffer_id: root, regions: Vec::new(), total_bytes: 0 });
+        let full = delta.total_bytes >= frame.data.len() / 2;
+        self.buffers.record_frame(root, frame.data.len(), delta.total_bytes, full);
+        if full {
+            frame.damage = damage;
+            return RenderMessage::Frame(frame);
+        }
+        let delta = BufferDelta { buffer_id: root, ..delta };
+        RenderMessage::FrameUpdate(FrameUpdate { sequence: frame.sequence, width: frame.width, height: frame.height, delta })
+    }
+
+    /// Forward a toplevel's input region to the renderer if it changed
+    fn submit_input_region(&mut self, root: u32) {
+        let is_toplevel = self.is_toplevel_surface(root);
+        let Some(surface) = self.surfaces.get(root).filter(|_| is_toplevel) else {
+            return;
+        };
+
+        let region = surface.current.input_region.clone();
+        if self.sent_input_regions.get(&root) == Some(&region) {
+            return;
+        }
+        // The default (whole window) needs no update before the first change
+        if region.is_none() && !self.sent_input_regions.contains_key(&root) {
+            self.sent_input_regions.insert(root, None);
+            return;
+        }
+
+        // The renderer works in output pixels
+        let scale = self.primary_output().fractional_scale();
+        let to_pixels = |value: i32| (value as f64 * scale).round() as i32;
+        let rects = region.as_ref().map(|r| {
+            r.rects().iter()
+                .map(|rect| Rect::new(to_pixels(rect.x), to_pixels(rect.y), to_pixels(rect.width), to_pixels(rect.height)))
+                .collect()
+        });
+        debug!("Input region for wl_surface@{}: {:?}", root, rects);
+        self.render_queue.push((root, RenderMessage::InputRegion(InputRegion { rects })));
+        self.sent_input_regions.insert(root, region);
+    }
+
+    /// Forward a toplevel's tearing and content type hints to the renderer
+    /// if they changed
+    fn submit_presentation_hint(&mut self, root: u32) {
+        let is_toplevel = self.is_toplevel_surface(root);
+        let Some(surface) = self.surfaces.get(root).filter(|_| is_toplevel) else {
+            return;
+        };
+
+        let hint = surface.current.hints;
+        let sent = self.sent_presentation_hints.get(&root).copied().unwrap_or_default();
+        if hint != sent {
+            debug!("Presentation hint for wl_surface@{}: {:?}", root, hint);
+            self.render_queue.push((root, RenderMessage::PresentationHint(hint)));
+            self.sent_presentation_hints.insert(root, hint);
+        }
+    }
+
+    /// Forward a toplevel's color space to the renderer if it changed
+    fn submit_color_space(&mut self, root: u32) {
+        let is_toplevel = self.is_toplevel_surface(root);
+        let Some(surface) = self.surfaces.get(root).filter(|_| is_toplevel) else {
+            return;
+        };
+
+        let color_space = surface.current.color_space;
+        let sent = self.sent_color_spaces.get(&root).copied().unwrap_or_default();
+        if color_space != sent {
+            debug!("Color space for wl_surface@{}: {:?}", root, color_space);
+            self.render_queue.push((root, RenderMessage::ColorSpace(color_space)));
+            self.sent_color_spaces.insert(root, color_space);
+        }
+    }
+
+    /// Forward a toplevel's alpha multiplier to the renderer if it changed
+    fn submit_opacity(&mut self, root: u32) {
+        let is_toplevel = self.is_toplevel_surface(root);
+        let Some(surface) = self.surfaces.get(root).filter(|_| is_toplevel) else {
+            return;
+        };
+
+        let opacity = WindowOpacity { alpha: surface.current.alpha.unwrap_or(u32::MAX) };
+        let sent = self.sent_opacities.get(&root).copied().unwrap_or_default();
+        if opacity != sent {
+            debug!("Opacity for wl_surface@{}: {:?}", root, opacity);
+            self.render_queue.push((root, RenderMessage::Opacity(opacity)));
+            self.sent_opacities.insert(root, opacity);
+        }
+    }
+
+    /// Forward the cursor surface contents to the renderer
+    ///
+    /// A cursor surface without a buffer hides the cursor.
+    fn submit_cursor(&mut self) {
+        let Some((surface, (hotspot_x, hotspot_y))) = self.cursor_surface else {
+            return;
+        };
+        let scale = self.primary_output().fractional_scale();
+        let buffers = &self.buffers;
+        let frame = self.surfaces.compose(surface, scale, |id| {
+            let buffer = buffers.get(id)?;
+            Some(BufferView {
+                width: buffer.width,
+                height: buffer.height,
+                stride: buffer.stride,
+                data: &buffer.data,
+                opaque: false,
+            })
+        });
+
+        let cursor = match frame {
+            Some(frame) => CursorUpdate::Image {
+                hotspot_x: (hotspot_x as f64 * scale).round() as i32,
+                hotspot_y: (hotspot_y as f64 * scale).round() as i32,
+                width: frame.width,
+                height: frame.height,
+                data: frame.data,
+            },
+            None => CursorUpdate::Hidden,
+        };
+        self.queue_cursor(cursor);
+    }
+
+    /// Queue a cursor update for the window under the pointer unless it
+    /// already shows it
+    fn queue_cursor(&mut self, cursor: CursorUpdate) {
+        let window = self.pointer_focus.map_or_else(|| self.focused_window(), |surface| self.window_of(surface));
+        if self.sent_cursor.as_ref().is_some_and(|(sent_window, sent)| (*sent_window, sent) == (window, &cursor)) {
+            return;
+        }
+        self.sent_cursor = Some((window, cursor.clone()));
+        self.render_queue.push((window, RenderMessage::Cursor(cursor)));
+    }
+
+    /// Take the messages queued for the renderer, with the renderer
+    /// window (toplevel root wl_surface) each is addressed to
+    pub fn take_render_messages(&mut self) -> Vec<(u32, RenderMessage)> {
+        std::mem::take(&mut self.render_queue)
+    }
+
+    /// Take the renderer windows whose toplevels went away
+    pub fn take_closed_windows(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.closed_windows)
+    }
+
+    /// Show the stats overlay in every renderer window
+    fn queue_stats_overlay(&mut self, stats: StatsOverlay) {
+        let mut windows: Vec<u32> = self.windows.keys().copied().collect();
+        windows.sort_unstable();
+        self.render_queue.extend(windows.into_iter().map(|window| (window, RenderMessage::StatsOverlay(stats))));
+    }
+
+    /// Apply an xdg_positioner request
+    fn handle_positioner(&mut self, positioner_id: u32, opcode: u16, payload: &[u8]) {
+        use opcodes::xdg_positioner as op;
+
+        if opcode == op::DESTROY {
+            self.positioners.remove(&positioner_id);
+            self.objects.remove(&positioner_id);
+            return;
+        }
+
+        let Some(positioner) = self.positioners.get_mut(&positioner_id) else {
+            return;
+        };
+        let mut args = ArgReader::new(payload);
+        let result = (|| -> crate::error::Result<()> {
+            match opcode {
+                op::SET_SIZE => {
+                    positioner.width = args.i32()?;
+                    positioner.height = args.i32()?;
+                }
+                op::SET_ANCHOR_RECT => {
+                    positioner.anchor_rect = Rect::new(args.i32()?, args.i32()?, args.i32()?, args.i32()?);
+                }
+                op::SET_ANCHOR => positioner.anchor = Edge::from_u32(args.u32()?).unwrap_or_default(),
+                op::SET_GRAVITY => positioner.gravity = Edge::from_u32(args.u32()?).unwrap_or_default(),
+                op::SET_CONSTRAINT_ADJUSTMENT => positioner.constraint_adjustment = args.u32()?,
+                op::SET_OFFSET => {
+                    positioner.offset_x = args.i32()?;
+                    positioner.offset_y = args.i32()?;
+                }
+                op::SET_REACTIVE => positioner.reactive = true,
+                op::SET_PARENT_SIZE => positioner.parent_size = Some((args.i32()?, args.i32()?)),
+                op::SET_PARENT_CONFIGURE => positioner.parent_configure = Some(args.u32()?),
+                _ => debug!("Unhandled: xdg_positioner@{}.{}", positioner_id, opcode),
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            warn!("xdg_positioner@{}.{}: {}", positioner_id, opcode, e);
+        }
+    }
+
+    /// Output bounds in coordinates relative to an xdg_surface
+    fn popup_bounds(&self, parent: u32) -> Rect {
+        let (x, y) = self.surface_origin(parent);
+        let (width, height) = self.primary_output().logical_size();
+        Rect::new(-x, -y, width, height)
+    }
+
+    /// The output windows are placed on
+    fn primary_output(&self) -> &Monitor {
+        &self.outputs[0].1
+    }
+
+    /// Bound version of an object (1 if unknown)
+    fn version(&self, id: u32) -> u32 {
+        self.versions.get(&id).copied().unwrap_or(1)
+    }
+
+    /// Object and surface count over the limits, if any
+    fn check_limits(&self) -> Option<String> {
+        if self.objects.len() > self.limits.max_objects {
+            return Some(format!("too many objects (limit {})", self.limits.max_objects));
+        }
+        if self.surfaces.count() > self.limits.max_surfaces {
+            return Some(format!("too many surfaces (limit {})", self.limits.max_surfaces));
+        }
+        None
+    }
+
+    /// Check that a new buffer fits in the buffer memory limit
+    ///
+    /// Called before the mirror is allocated; on failure the request is
+    /// dropped and the error sent once dispatch returns.
+    fn reserve_buffer(&mut self, stride: u32, height: u32) -> bool {
+        self.reserve_memory(stride as u64 * height as u64)
+    }
+
+    /// Check that `size` more bytes of mirrored memory fit the limit
+    fn reserve_memory(&mut self, size: u64) -> bool {
+        let used = self.buffers.total_memory() as u64;
+        if used + size > self.limits.max_buffer_memory as u64 {
+            self.quota_exceeded = Some(format!(
+                "buffer memory limit of {} bytes exceeded ({} in use, {} requested)",
+                self.limits.max_buffer_memory, used, size
+            ));
+            return false;
+        }
+        true
+    }
+
+    /// Track an object created by a request on `parent`
+    ///
+    /// Objects created through another object share its version.
+    fn insert_child(&mut self, id: u32, interface: &str, parent: u32) {
+        self.objects.insert_child(id, interface.to_string(), parent);
+        self.versions.insert(id, self.version(parent));
+    }
+
+    /// Drop events the receiving object's version does not have
+    fn gate_events(&self, events: Vec<Message>) -> Vec<Message> {
+        events.into_iter()
+            .filter(|event| {
+                let Some(interface) = self.objects.get(&event.object_id) else {
+                    return true;
+                };
+                let since = event_since(interface, event.opcode);
+                if since > self.version(event.object_id) {
+                    debug!("Dropping {}@{}.{} (since v{})", interface, event.object_id, event.opcode, since);
+                    return false;
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// Send the primary output's scale to surfaces and fractional scale
+    /// objects that have not seen it yet
+    ///
+    /// wl_surface (v6+) gets preferred_buffer_scale, rounded up;
+    /// wp_fractional_scale_v1 gets the exact preferred_scale.
+    fn scale_events(&mut self) -> Vec<Message> {
+        let output = self.primary_output();
+        let (integer, fractional) = (output.integer_scale() as u32, output.scale_120);
+
+        let mut targets: Vec<(u32, u16, u32)> = self.surfaces_with_version(6)
+            .into_iter()
+            .map(|id| (id, opcodes::surface::PREFERRED_BUFFER_SCALE, integer))
+            .collect();
+        targets.extend(self.fractional_scales.keys().map(|&id| (id, opcodes::fractional_scale::PREFERRED_SCALE, fractional)));
+        targets.sort_unstable();
+
+        let mut responses = Vec::new();
+        for (id, opcode, scale) in targets {
+            if self.sent_scales.insert(id, scale) != Some(scale) {
+                responses.push(Message::new(id, opcode, scale.to_le_bytes().to_vec()));
+            }
+        }
+        responses
+    }
+
+    /// wl_surfaces bound at `version` or later
+    fn surfaces_with_version(&self, version: u32) -> Vec<u32> {
+        self.objects.iter()
+            .filter(|(&id, iface)| iface.as_str() == "wl_surface" && self.version(id) >= version)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Whether a wl_surface is the root of an xdg_toplevel
+    fn is_toplevel_surface(&self, surface: u32) -> bool {
+        self.toplevel_for_surface(surface).is_some()
+    }
+
+    /// xdg_toplevel whose role a wl_surface has
+    fn toplevel_for_surface(&self, surface: u32) -> Option<u32> {
+        self.toplevels.iter()
+            .find(|(_, t)| self.xdg_surfaces.get(&t.xdg_surface) == Some(&surface))
+            .map(|(&id, _)| id)
+    }
+
+    /// Renderer window of an xdg_toplevel: its root wl_surface
+    fn toplevel_window(&self, toplevel: u32) -> u32 {
+        self.toplevels.get(&toplevel)
+            .and_then(|t| self.xdg_surfaces.get(&t.xdg_surface))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Renderer window showing a wl_surface (popups and subsurfaces
+    /// included)
+    fn window_of(&self, surface: u32) -> u32 {
+        self.frame_root(self.surfaces.root(surface))
+    }
+
+    /// Renderer window seat state such as IME and shortcut inhibition
+    /// belongs to: the one with keyboard focus, 0 if none
+    fn focused_window(&self) -> u32 {
+        self.keyboard_focus.map_or(0, |surface| self.window_of(surface))
+    }
+
+    /// Renderer window of a toplevel went away with its role or surface
+    fn close_window(&mut self, root: u32) {
+        self.windows.remove(&root);
+        self.skipped_frames.remove(&root);
+        self.last_frame_sent.remove(&root);
+        self.closed_windows.push(root);
+    }
+
+    /// Run a callback on the installed hooks, if any
+    fn hook(&mut self, f: impl FnOnce(&mut dyn CompositorHooks)) {
+        if let Some(hooks) = self.hooks.as_deref_mut() {
+            f(hooks);
+        }
+    }
+
+    /// Replace the advertised monitors after a display change
+    ///
+    /// Monitors are matched by name: new ones are announced with
+    /// wl_registry.global, disconnected ones withdrawn with
+    /// wl_registry.global_remove, and changed ones re-sent to every bound
+    /// wl_output. Surfaces then enter/leave outputs to follow the primary
+    /// monitor, and maximized or fullscreen windows are reconfigured to its
+    /// new size.
+    pub fn update_outputs(&mut self, monitors: Vec<Monitor>) -> Vec<Message> {
+        if monitors.is_empty() {
+            warn!("Ignoring display change without any monitors");
+            return Vec::new();
+        }
+        let old_size = self.primary_output().logical_size();
+        let old_preferred = self.preferred_description();
+        let mut color_changed = Vec::new();
+        let mut responses = Vec::new();
+
+        // Withdraw outputs that went away
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.outputs)
+            .into_iter()
+            .partition(|(_, old)| monitors.iter().any(|m| m.name == old.name));
+        for (name, monitor) in removed {
+            info!("Output {} disconnected (global {})", monitor.name, name);
+            self.globals.retain(|g| g.name != name);
+            for &registry in &self.registries {
+                responses.push(Message::new(registry, opcodes::registry::GLOBAL_REMOVE, name.to_le_bytes().to_vec()));
+            }
+        }
+
+        // Keep the order of `monitors` so the primary stays first
+        for monitor in monitors {
+            match kept.iter().find(|(_, old)| old.name == monitor.name) {
+                Some((name, old)) => {
+                    if *old != monitor {
+                        info!("Output {} changed to {}x{}", monitor.name, monitor.width, monitor.height);
+                        let bound: Vec<(u32, u32)> = self.output_objects.iter()
+                            .filter(|(_, (global, _))| global == name)
+                            .map(|(&id, &(_, version))| (id, version))
+                            .collect();
+                        for (id, version) in bound {
+                            responses.extend(monitor.output_events(id, version));
+                        }
+                        if old.color != monitor.color {
+                            color_changed.push(*name);
+                        }
+                    }
+                    self.outputs.push((*name, monitor));
+                }
+                None => {
+                    let name = self.register_global("wl_output", 4);
+                    info!("Output {} connected (global {})", monitor.name, name);
+                    let global = self.globals.last().expect("just registered");
+                    for &registry in &self.registries {
+                        responses.push(global_event(registry, global));
+                    }
+                    self.outputs.push((name, monitor));
+                }
+            }
+        }
+
+        responses.extend(self.sync_surface_outputs());
+        responses.extend(self.scale_events());
+        responses.extend(self.color_events(old_preferred, &color_changed));
+
+        if self.primary_output().logical_size() != old_size {
+            let filling: Vec<u32> = self.toplevels.iter()
+                .filter(|(_, t)| t.maximized || t.fullscreen)
+                .map(|(&id, _)| id)
+                .collect();
+            for toplevel_id in filling {
+                responses.extend(self.configure_toplevel(toplevel_id));
+            }
+        }
+        responses
+    }
+
+    /// Send wl_surface.enter/leave so mapped toplevels are on the primary
+    /// output, and minimized ones on none
+    fn sync_surface_outputs(&mut self) -> Vec<Message> {
+        let primary = self.outputs[0].0;
+        let mut target: Vec<u32> = self.output_objects.iter()
+            .filter(|(_, (global, _))| *global == primary)
+            .map(|(&id, _)| id)
+            .collect();
+        target.sort_unstable();
+        let minimized: Vec<u32> = self.toplevels.values()
+            .filter(|t| t.minimized)
+            .filter_map(|t| self.xdg_surfaces.get(&t.xdg_surface).copied())
+            .collect();
+
+        let mut responses = Vec::new();
+        for (&surface, entered) in self.surface_outputs.iter_mut() {
+            let target = if minimized.contains(&surface) { &[][..] } else { &target[..] };
+            for &output in entered.iter().filter(|o| !target.contains(o)) {
+                responses.push(Message::new(surface, opcodes::surface::LEAVE, output.to_le_bytes().to_vec()));
+            }
+            for &output in target.iter().filter(|o| !entered.contains(o)) {
+                responses.push(Message::new(surface, opcodes::surface::ENTER, output.to_le_bytes().to_vec()));
+            }
+            *entered = target.to_vec();
+        }
+        responses
+    }
+
+    /// Position of an xdg_surface on the output
+    ///
+    /// Toplevels are placed at the output origin; popups are offset from
+    /// their parent.
+    fn surface_origin(&self, xdg_surface: u32) -> (i32, i32) {
+        match self.popups.iter().find(|(_, p)| p.xdg_surface == xdg_surface) {
+            Some((_, popup)) if popup.parent != xdg_surface => {
+                let (x, y) = self.surface_origin(popup.parent);
+                (x + popup.geometry.x, y + popup.geometry.y)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Surface whose frame shows a surface: the window's for popups
+    fn frame_root(&self, surface: u32) -> u32 {
+        let Some(mut xdg_surface) = self.popups.iter()
+            .map(|(_, p)| p.xdg_surface)
+            .find(|id| self.xdg_surfaces.get(id) == Some(&surface)) else {
+            return surface;
+        };
+        while let Some((_, popup)) = self.popups.iter().find(|(_, p)| p.xdg_surface == xdg_surface && p.parent != xdg_surface) {
+            xdg_surface = popup.parent;
+        }
+        self.xdg_surfaces.get(&xdg_surface).copied().unwrap_or(surface)
+    }
+
+    /// Popups of a window as layers above it, bottom to top: wl_surface
+    /// and its position in the window
+    fn popup_layers(&self, root: u32) -> Vec<(u32, i32, i32)> {
+        self.popups.iter()
+            .filter_map(|(_, popup)| {
+                let surface = *self.xdg_surfaces.get(&popup.xdg_surface)?;
+                let (x, y) = self.surface_origin(popup.xdg_surface);
+                (surface != root && self.frame_root(surface) == root).then_some((surface, x, y))
+            })
+            .collect()
+    }
+
+    fn popup_mut(&mut self, popup_id: u32) -> Option<&mut Popup> {
+        self.popups.iter_mut().find(|(id, _)| *id == popup_id).map(|(_, p)| p)
+    }
+
+    /// Send zxdg_toplevel_decoration_v1.configure with the configured mode
+    ///
+    /// A decoration configure must be followed by xdg_surface.configure,
+    /// so the toplevel is reconfigured too.
+    fn configure_decoration(&mut self, decoration_id: u32) -> Vec<Message> {
+        let Some(&toplevel_id) = self.decorations.get(&decoration_id) else {
+            return Vec::new();
+        };
+        let mode = self.decoration_mode;
+        let window = self.toplevel_window(toplevel_id);
+        let Some(toplevel) = self.toplevels.get_mut(&toplevel_id) else {
+            return Vec::new();
+        };
+        if toplevel.decoration != Some(mode) {
+            toplevel.decoration = Some(mode);
+            let info = toplevel.window_info();
+            self.render_queue.push((window, RenderMessage::Window(info)));
+        }
+
+        let mut responses = vec![Message::new(
+            decoration_id,
+            opcodes::toplevel_decoration::CONFIGURE,
+            (mode as u32).to_le_bytes().to_vec(),
+        )];
+        responses.extend(self.configure_toplevel(toplevel_id));
+        responses
+    }
+
+    /// Send xdg_toplevel.configure followed by xdg_surface.configure
+    fn configure_toplevel(&mut self, toplevel_id: u32) -> Vec<Message> {
+        let Some(toplevel) = self.toplevels.get(&toplevel_id) else {
+            return Vec::new();
+        };
+        let (width, height) = toplevel.configure_size(self.primary_output().logical_size());
+        let states = toplevel.configure_states();
+        let xdg_surface = toplevel.xdg_surface;
+
+        let state_bytes: Vec<u8> = states.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let payload = ArgWriter::new().i32(width).i32(height).array(&state_bytes).finish();
+
+        let configure = ToplevelConfigure {
+            size: (width, height),
+            maximized: toplevel.maximized,
+            fullscreen: toplevel.fullscreen,
+        };
+
+        let serial = self.next_serial();
+        self.configures.entry(xdg_surface).or_default().sent(serial, Some(configure));
+        info!("Sent xdg configure: {}x{} states={:?}, serial={}", width, height, states, serial);
+        vec![
+            Message::new(toplevel_id, opcodes::xdg_toplevel::CONFIGURE, payload),
+            Message::new(xdg_surface, opcodes::xdg_surface::CONFIGURE, serial.to_le_bytes().to_vec()),
+        ]
+    }
+
+    /// Apply the configure a commit of `surface` acknowledged, if any
+    fn apply_configure(&mut self, surface: u32) {
+        let Some(toplevel_id) = self.toplevel_for_surface(surface) else {
+            return;
+        };
+        let Some(toplevel) = self.toplevels.get_mut(&toplevel_id) else {
+            return;
+        };
+        let Some(state) = self.configures.get_mut(&toplevel.xdg_surface).and_then(ConfigureQueue::commit) else {
+            return;
+        };
+        if state != toplevel.applied {
+            debug!("xdg_toplevel@{}: applied {:?}", toplevel_id, state);
+            let window_changed = (state.maximized, state.fullscreen) != (toplevel.applied.maximized, toplevel.applied.fullscreen);
+            toplevel.applied = state;
+            if window_changed {
+                let info = toplevel.window_info();
+                self.render_queue.push((surface, RenderMessage::Window(info)));
+            }
+        }
+    }
+
+    /// Allocate a configure serial
+    fn next_serial(&mut self) -> u32 {
+        let serial = self.next_serial;
+        self.next_serial = self.next_serial.wrapping_add(1);
+        serial
+    }
+
+    /// Send xdg_popup.configure (preceded by repositioned if requested)
+    /// followed by xdg_surface.configure
+    fn configure_popup(&mut self, popup_id: u32, reposition_token: Option<u32>) -> Vec<Message> {
+        let Some((_, popup)) = self.popups.iter().find(|(id, _)| *id == popup_id).cloned() else {
+            return Vec::new();
+        };
+
+        let mut responses = Vec::new();
+        if let Some(token) = reposition_token {
+            let payload = ArgWriter::new().u32(token).finish();
+            responses.push(Message::new(popup_id, opcodes::xdg_popup::REPOSITIONED, payload));
+        }
+
+        let g = popup.geometry;
+        let payload = ArgWriter::new().i32(g.x).i32(g.y).i32(g.width).i32(g.height).finish();
+        responses.push(Message::new(popup_id, opcodes::xdg_popup::CONFIGURE, payload));
+
+        let serial = self.next_serial();
+        self.configures.entry(popup.xdg_surface).or_default().sent(serial, None);
+        responses.push(Message::new(
+            popup.xdg_surface,
+            opcodes::xdg_surface::CONFIGURE,
+            serial.to_le_bytes().to_vec(),
+        ));
+        responses
+    }
+
+    /// Dismiss grabbing popups (e.g. the user clicked outside a menu)
+    ///
+    /// popup_done is sent topmost first, as the protocol requires.
+    pub fn dismiss_popups(&mut self) -> Vec<Message> {
+        self.popups
+            .iter()
+            .rev()
+            .filter(|(_, p)| p.grabbed)
+            .map(|(id, _)| Message::new(*id, opcodes::xdg_popup::POPUP_DONE, vec![]))
+            .collect()
+    }
+
+    /// Replace the clipboard selection (e.g. when the Windows clipboard changes)
+    ///
+    /// Returns the events announcing the new selection to every data device.
+    pub fn set_selection(&mut self, selection: Option<Selection>) -> Vec<Message> {
+        self.selection = selection.map(Arc::new);
+
+        let mut responses = Vec::new();
+        for device_id in self.data_devices.clone() {
+            match self.selection.clone() {
+                Some(selection) => responses.extend(self.offer_selection(device_id, selection)),
+                None => {
+                    // wl_data_device.selection with a null offer clears it
+                    let payload = ArgWriter::new().u32(0).finish();
+                    responses.push(Message::new(device_id, opcodes::data_device::SELECTION, payload));
+                }
+            }
+        }
+        responses
+    }
+
+    /// Create a wl_data_offer for a selection and announce it on a data device
+    fn offer_selection(&mut self, device_id: u32, selection: Arc<Selection>) -> Vec<Message> {
+        let offer_id = self.allocator.alloc();
+        self.insert_child(offer_id, "wl_data_offer", device_id);
+
+        let mut responses = Vec::new();
+
+        // wl_data_device.data_offer introduces the new object
+        let payload = ArgWriter::new().u32(offer_id).finish();
+        responses.push(Message::new(device_id, opcodes::data_device::DATA_OFFER, payload));
+
+        // wl_data_offer.offer for each MIME type
+        for mime in selection.mime_types() {
+            let payload = ArgWriter::new().string(mime).finish();
+            responses.push(Message::new(offer_id, opcodes::data_offer::OFFER, payload));
+        }
+
+        // wl_data_device.selection makes it the clipboard
+        let payload = ArgWriter::new().u32(offer_id).finish();
+        responses.push(Message::new(device_id, opcodes::data_device::SELECTION, payload));
+
+        self.data_offers.insert(offer_id, selection);
+        responses
+    }
+
+    /// Encode responses to wire format
+    pub fn encode_responses(&self, messages: &[Message]) -> Vec<u8> {
+        self.encoder.encode_batch(messages)
+    }
+
+    /// Send wl_output information events for the monitor behind a global
+    fn send_output_info(&self, global_name: u32, output_id: u32, version: u32) -> Vec<Message> {
+        let Some((_, monitor)) = self.outputs.iter().find(|(name, _)| *name == global_name) else {
+            return Vec::new();
+        };
+        info!(
+            "Sent wl_output info: {} {}x{}+{}+{}@{}mHz",
+            monitor.name, monitor.width, monitor.height, monitor.x, monitor.y, monitor.refresh
+        );
+        monitor.output_events(output_id, version)
+    }
+}
+
+/// Events sent when wl_seat is bound
+fn seat_events(seat_id: u32, version: u32) -> Vec<Message> {
+    let mut events = vec![Message::new(
+        seat_id,
+        opcodes::seat::CAPABILITIES,
+        seat_capability::POINTER.to_le_bytes().to_vec(),
+    )];
+    if version >= 2 {
+        events.push(Message::new(seat_id, opcodes::seat::NAME, ArgWriter::new().string("seat0").finish()));
+    }
+    events
+}
+
+/// ARGB8888 pixel (BGRA in memory) for premultiplied RGBA spanning the
+/// full u32 range
+fn single_pixel(rgba: [u32; 4]) -> [u8; 4] {
+    let channel = |v: u32| ((v as u64 * 255 + u32::MAX as u64 / 2) / u32::MAX as u64) as u8;
+    [channel(rgba[2]), channel(rgba[1]), channel(rgba[0]), channel(rgba[3])]
+}
+
+/// wl_display.error(object_id, code, message)
+/// Apply a wp_image_description_creator_params_v1 set_* request
+///
+/// Requests with missing arguments are ignored.
+fn set_description_param(params: &mut DescriptionParams, opcode: u16, payload: &[u8]) -> Result<(), ParamsError> {
+    use opcodes::image_description_creator_params as op;
+    let mut args = ArgReader::new(payload);
+    let values: Vec<u32> = std::iter::from_fn(|| args.u32().ok()).collect();
+    let needed = match opcode {
+        op::SET_PRIMARIES | op::SET_MASTERING_DISPLAY_PRIMARIES => 8,
+        op::SET_LUMINANCES => 3,
+        op::SET_MASTERING_LUMINANCE => 2,
+        _ => 1,
+    };
+    if values.len() < needed {
+        return Ok(());
+    }
+    let chromaticities = || Chromaticities::from_args(std::array::from_fn(|i| values[i] as i32));
+    match opcode {
+        op::SET_TF_NAMED => params.set_tf_named(values[0]),
+        op::SET_PRIMARIES_NAMED => params.set_primaries_named(values[0]),
+        op::SET_PRIMARIES => params.set_primaries(chromaticities()),
+        op::SET_LUMINANCES => params.set_luminances(Luminances { min: values[0], max: values[1], reference: values[2] }),
+        op::SET_MASTERING_DISPLAY_PRIMARIES => params.set_mastering_display_primaries(chromaticities()),
+        op::SET_MASTERING_LUMINANCE => params.set_mastering_luminance(values[0], values[1]),
+        op::SET_MAX_CLL => params.set_max_cll(values[0]),
+        op::SET_MAX_FALL => params.set_max_fall(values[0]),
+        _ => Err(ParamsError {
+            code: color::params_error::UNSUPPORTED_FEATURE,
+            message: format!("request {} is not supported", opcode),
+        }),
+    }
+}
+
+/// wl_display.error, fatal to the client
+pub fn display_error(object_id: u32, code: u32, message: &str) -> Message {
+    let payload = ArgWriter::new().u32(object_id).u32(code).string(message).finish();
+    Message::new(1, opcodes::display::ERROR, payload)
+}
+
+/// Milliseconds on the compositor's monotonic clock, the callback_data of
+/// both wl_display.sync and frame callbacks
+fn callback_time() -> u32 {
+    monotonic_time().as_millis() as u32
+}
+
+/// Time on the compositor's monotonic clock
+fn monotonic_time() -> Duration {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed()
+}
+
+/// wl_display.delete_id(id), acknowledging the destruction of an object
+fn delete_id(id: u32) -> Message {
+    Message::new(1, opcodes::display::DELETE_ID, id.to_le_bytes().to_vec())
+}
+
+/// wl_registry.global event announcing a global
+fn global_event(registry_id: u32, global: &Global) -> Message {
+    let payload = ArgWriter::new()
+        .u32(global.name)
+        .string(&global.interface)
+        .u32(global.version)
+        .finish();
+    Message::new(registry_id, opcodes::registry::GLOBAL, payload)
+}
+
+impl Default for Compositor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::idle;
+    use crate::render::pen_flags;
+    use crate::tablet;
+
+    /// Run the tasks handlers started and apply their completions
+    fn run_tasks(comp: &mut Compositor) -> Vec<Message> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut responses = Vec::new();
+        for task in comp.take_tasks() {
+            let completion = runtime.block_on(task);
+            responses.extend(comp.complete(completion));
+        }
+        responses
+    }
+
+    /// Messages queued for the renderer, whichever window they are for
+    fn render_messages(comp: &mut Compositor) -> Vec<RenderMessage> {
+        comp.take_render_messages().into_iter().map(|(_, message)| message).collect()
+    }
+
+    #[test]
+    fn test_compositor_init() {
+        let comp = Compositor::new();
+        assert!(!comp.globals.is_empty());
+    }
+
+    #[test]
+    fn test_handle_get_registry() {
+        let mut comp = Compositor::new();
+        
+        // wl_display.get_registry with new_id = 2
+        let msg = Message::new(1, 1, 2u32.to_le_bytes().to_vec());
+        let responses = comp.handle_message(&msg);
+        
+        // Should get global events for each registered interface
+        assert!(!responses.is_empty());
+    }
+
+    #[test]
+    fn test_output_per_monitor() {
+        let monitors = vec![
+            Monitor::default(),
+            Monitor { name: "DISPLAY2".to_string(), x: 1920, width: 2560, height: 1440, primary: false, ..Default::default() },
+        ];
+        let mut comp = Compositor::with_outputs(monitors);
+        let outputs: Vec<u32> = comp.globals.iter().filter(|g| g.interface == "wl_output").map(|g| g.name).collect();
+        assert_eq!(outputs.len(), 2);
+
+        comp.objects.insert(2, "wl_registry".to_string());
+        let bind = ArgWriter::new().u32(outputs[1]).string("wl_output").u32(4).u32(10).finish();
+        let responses = comp.handle_message(&Message::new(2, 0, bind));
+
+        let mut geometry = ArgReader::new(&responses[0].payload);
+        assert_eq!(geometry.i32().unwrap(), 1920);
+        let mut mode = ArgReader::new(&responses[1].payload);
+        mode.u32().unwrap();
+        assert_eq!((mode.i32().unwrap(), mode.i32().unwrap()), (2560, 1440));
+    }
+
+    #[test]
+    fn test_events_gated_by_bound_version() {
+        let mut comp = Compositor::new();
+        let seat = comp.globals.iter().find(|g| g.interface == "wl_seat").unwrap().name;
+        comp.objects.insert(2, "wl_registry".to_string());
+
+        // A v1 seat gets capabilities but not name
+        let responses = comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(seat).string("wl_seat").u32(1).u32(5).finish()));
+        assert_eq!(responses.len(), 1);
+
+        // Child objects inherit the seat's version
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(seat).string("wl_seat").u32(7).u32(6).finish()));
+        comp.handle_message(&Message::new(6, opcodes::seat::GET_POINTER, 12u32.to_le_bytes().to_vec()));
+        assert_eq!(comp.version(12), 7);
+
+        let value120 = Message::new(12, 9, ArgWriter::new().u32(0).i32(120).finish());
+        let frame = Message::new(12, 5, vec![]);
+        assert_eq!(comp.gate_events(vec![value120, frame.clone()]), vec![frame]);
+    }
+
+    #[test]
+    fn test_output_hotplug() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let output_global = comp.outputs[0].0;
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(output_global).string("wl_output").u32(4).u32(7).finish()));
+
+        // Mapping a toplevel enters the bound output
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        let responses = comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(responses, vec![Message::new(10, opcodes::surface::ENTER, 7u32.to_le_bytes().to_vec())]);
+
+        // Replace the monitor: old global removed, new one announced, surface leaves
+        let monitor = Monitor { name: "DISPLAY2".to_string(), ..Default::default() };
+        let responses = comp.update_outputs(vec![monitor]);
+        assert!(responses.contains(&Message::new(2, opcodes::registry::GLOBAL_REMOVE, output_global.to_le_bytes().to_vec())));
+        assert!(responses.iter().any(|m| m.object_id == 2 && m.opcode == opcodes::registry::GLOBAL));
+        assert!(responses.contains(&Message::new(10, opcodes::surface::LEAVE, 7u32.to_le_bytes().to_vec())));
+
+        // Binding the new output enters it
+        let new_global = comp.outputs[0].0;
+        let responses = comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(new_global).string("wl_output").u32(4).u32(8).finish()));
+        assert_eq!(responses.last(), Some(&Message::new(10, opcodes::surface::ENTER, 8u32.to_le_bytes().to_vec())));
+    }
+
+    #[test]
+    fn test_fractional_scale() {
+        let monitor = Monitor { width: 2880, height: 1620, scale_120: 180, ..Default::default() };
+        let mut comp = Compositor::with_outputs(vec![monitor.clone()]);
+        let global = |comp: &Compositor, iface: &str| comp.globals.iter().find(|g| g.interface == iface).unwrap().name;
+
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let name = global(&comp, "wl_compositor");
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(name).string("wl_compositor").u32(6).u32(4).finish()));
+        let name = global(&comp, "wp_fractional_scale_manager_v1");
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(name).string("wp_fractional_scale_manager_v1").u32(1).u32(5).finish()));
+
+        // A v6 surface is told to use 2x buffers
+        let responses = comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        assert_eq!(responses, vec![Message::new(10, opcodes::surface::PREFERRED_BUFFER_SCALE, 2u32.to_le_bytes().to_vec())]);
+
+        // The fractional scale object gets the exact 1.5x
+        let args = ArgWriter::new().u32(11).u32(10).finish();
+        let responses = comp.handle_message(&Message::new(5, opcodes::fractional_scale_manager::GET_FRACTIONAL_SCALE, args));
+        assert_eq!(responses, vec![Message::new(11, opcodes::fractional_scale::PREFERRED_SCALE, 180u32.to_le_bytes().to_vec())]);
+
+        // Moving to 1x updates both
+        let responses = comp.update_outputs(vec![Monitor { scale_120: 120, ..monitor }]);
+        assert!(responses.contains(&Message::new(10, opcodes::surface::PREFERRED_BUFFER_SCALE, 1u32.to_le_bytes().to_vec())));
+        assert!(responses.contains(&Message::new(11, opcodes::fractional_scale::PREFERRED_SCALE, 120u32.to_le_bytes().to_vec())));
+    }
+
+    #[test]
+    fn test_decoration_negotiation() {
+        let mut comp = Compositor::new();
+        comp.set_decoration_mode(DecorationMode::ClientSide);
+        comp.objects.insert(20, "xdg_surface".to_string());
+        comp.objects.insert(30, "zxdg_decoration_manager_v1".to_string());
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+        render_messages(&mut comp);
+
+        let args = ArgWriter::new().u32(31).u32(21).finish();
+        let responses = comp.handle_message(&Message::new(30, opcodes::decoration_manager::GET_TOPLEVEL_DECORATION, args));
+        assert_eq!(responses[0], Message::new(31, opcodes::toplevel_decoration::CONFIGURE, 1u32.to_le_bytes().to_vec()));
+        assert_eq!(responses.last().unwrap().object_id, 20); // xdg_surface.configure
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::Window(info)] => assert!(info.is_client_decorated()),
+            other => panic!("expected window info, got {:?}", other),
+        }
+
+        // The configured mode wins over the client's preference
+        let responses = comp.handle_message(&Message::new(31, opcodes::toplevel_decoration::SET_MODE, 2u32.to_le_bytes().to_vec()));
+        assert_eq!(responses[0].payload, 1u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_cursor_updates() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "wl_seat".to_string());
+        comp.objects.insert(8, "wp_cursor_shape_manager_v1".to_string());
+        comp.handle_message(&Message::new(7, opcodes::seat::GET_POINTER, 9u32.to_le_bytes().to_vec()));
+
+        // Cursor surface with a 2x2 buffer, hotspot moved by the attach offset
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(2).i32(2).i32(8).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(9, opcodes::pointer::SET_CURSOR, ArgWriter::new().u32(1).u32(10).i32(1).i32(1).finish()));
+        // Nothing to show until a buffer is committed
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Cursor(CursorUpdate::Hidden)]));
+
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(1).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::Cursor(CursorUpdate::Image { hotspot_x, hotspot_y, width, height, .. })] => {
+                assert_eq!((*hotspot_x, *hotspot_y, *width, *height), (0, 1, 2, 2));
+            }
+            other => panic!("expected cursor image, got {:?}", other),
+        }
+
+        // Named shape, then a null surface hides the cursor
+        comp.handle_message(&Message::new(8, opcodes::cursor_shape_manager::GET_POINTER, ArgWriter::new().u32(11).u32(9).finish()));
+        comp.handle_message(&Message::new(11, opcodes::cursor_shape_device::SET_SHAPE, ArgWriter::new().u32(2).u32(9).finish()));
+        comp.handle_message(&Message::new(9, opcodes::pointer::SET_CURSOR, ArgWriter::new().u32(3).u32(0).i32(0).i32(0).finish()));
+        let messages = render_messages(&mut comp);
+        assert!(matches!(messages[..], [RenderMessage::Cursor(CursorUpdate::Named(9)), RenderMessage::Cursor(CursorUpdate::Hidden)]));
+    }
+
+    #[test]
+    fn test_text_input_ime_bridge() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "zwp_text_input_manager_v3".to_string());
+
+        // Map a toplevel so it takes keyboard focus
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        render_messages(&mut comp);
+
+        let args = ArgWriter::new().u32(30).u32(8).finish();
+        let responses = comp.handle_message(&Message::new(7, opcodes::text_input_manager::GET_TEXT_INPUT, args));
+        assert_eq!(responses, vec![Message::new(30, text_input::events::ENTER, 10u32.to_le_bytes().to_vec())]);
+
+        comp.handle_message(&Message::new(30, opcodes::text_input::ENABLE, vec![]));
+        comp.handle_message(&Message::new(30, opcodes::text_input::SET_CURSOR_RECTANGLE, ArgWriter::new().i32(40).i32(8).i32(1).i32(16).finish()));
+        comp.handle_message(&Message::new(30, opcodes::text_input::COMMIT, vec![]));
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::Ime(state)] => {
+                assert!(state.enabled);
+                assert_eq!(state.cursor_rect, Rect::new(40, 8, 1, 16));
+            }
+            other => panic!("expected IME state, got {:?}", other),
+        }
+
+        let responses = comp.handle_renderer_event(10, &RendererEvent::ImeCommit { text: "漢字".to_string() });
+        assert_eq!(responses.len(), 2);
+        assert_eq!(ArgReader::new(&responses[0].payload).string().unwrap(), "漢字");
+        assert_eq!(responses[1], Message::new(30, text_input::events::DONE, 1u32.to_le_bytes().to_vec()));
+
+        // Disabling turns the IME off again
+        comp.handle_message(&Message::new(30, opcodes::text_input::DISABLE, vec![]));
+        comp.handle_message(&Message::new(30, opcodes::text_input::COMMIT, vec![]));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Ime(ImeState { enabled: false, .. })]));
+    }
+
+    #[test]
+    fn test_pointer_lock_released_on_focus_loss() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "zwp_pointer_constraints_v1".to_string());
+        comp.objects.insert(8, "zwp_relative_pointer_manager_v1".to_string());
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        render_messages(&mut comp);
+
+        // Persistent lock on the focused window activates immediately
+        let args = ArgWriter::new().u32(30).u32(10).u32(9).u32(0).u32(pointer_constraints::lifetime::PERSISTENT).finish();
+        let responses = comp.handle_message(&Message::new(7, opcodes::pointer_constraints::LOCK_POINTER, args));
+        assert_eq!(responses, vec![Message::new(30, pointer_constraints::events::ACTIVATED, vec![])]);
+        assert!(matches!(
+            &render_messages(&mut comp)[..],
+            [RenderMessage::PointerConstraint(PointerConstraint { kind: constraint_kind::LOCK, rects: None })]
+        ));
+
+        // Raw motion goes to relative pointers
+        comp.handle_message(&Message::new(8, opcodes::relative_pointer_manager::GET_RELATIVE_POINTER, ArgWriter::new().u32(31).u32(9).finish()));
+        let motion = RendererEvent::RelativeMotion { time_usec: 1, dx: 2.0, dy: 0.0, dx_unaccel: 2.0, dy_unaccel: 0.0 };
+        assert_eq!(comp.handle_renderer_event(10, &motion)[0].object_id, 31);
+
+        // The position hint applies on commit and moves only the cursor
+        let hint = ArgWriter::new().fixed(12.5).fixed(3.0).finish();
+        comp.handle_message(&Message::new(30, opcodes::locked_pointer::SET_CURSOR_POSITION_HINT, hint));
+        assert!(render_messages(&mut comp).is_empty());
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(render_messages(&mut comp).iter().any(|m| matches!(m, RenderMessage::Cursor(CursorUpdate::Position { x: 13, y: 3 }))));
+
+        // Focus loss unlocks, focus gain re-locks
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Focus { focused: false });
+        assert_eq!(responses, vec![Message::new(30, pointer_constraints::events::DEACTIVATED, vec![])]);
+        assert!(matches!(
+            &render_messages(&mut comp)[..],
+            [RenderMessage::PointerConstraint(PointerConstraint { kind: constraint_kind::NONE, .. })]
+        ));
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Focus { focused: true });
+        assert_eq!(responses, vec![Message::new(30, pointer_constraints::events::ACTIVATED, vec![])]);
+    }
+
+    #[test]
+    fn test_shortcuts_inhibitor_follows_focus() {
+        use opcodes::keyboard_shortcuts_inhibitor as events;
+
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "zwp_keyboard_shortcuts_inhibit_manager_v1".to_string());
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        render_messages(&mut comp);
+
+        let args = ArgWriter::new().u32(30).u32(10).u32(5).finish();
+        let responses = comp.handle_message(&Message::new(7, opcodes::keyboard_shortcuts_inhibit_manager::INHIBIT_SHORTCUTS, args));
+        assert_eq!(responses, vec![Message::new(30, events::ACTIVE, vec![])]);
+        assert!(matches!(
+            render_messages(&mut comp)[..],
+            [RenderMessage::ShortcutsInhibit(ShortcutsInhibit { active: true })]
+        ));
+
+        // Alt+Tab away from the window hands shortcuts back to Windows
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Focus { focused: false });
+        assert_eq!(responses, vec![Message::new(30, events::INACTIVE, vec![])]);
+        assert!(matches!(
+            render_messages(&mut comp)[..],
+            [RenderMessage::ShortcutsInhibit(ShortcutsInhibit { active: false })]
+        ));
+
+        comp.handle_renderer_event(10, &RendererEvent::Focus { focused: true });
+        comp.handle_message(&Message::new(30, opcodes::keyboard_shortcuts_inhibitor::DESTROY, vec![]));
+        let messages = render_messages(&mut comp);
+        assert!(matches!(
+            messages[..],
+            [RenderMessage::ShortcutsInhibit(ShortcutsInhibit { active: true }), RenderMessage::ShortcutsInhibit(ShortcutsInhibit { active: false })]
+        ));
+    }
+
+    #[test]
+    fn test_pinch_gesture_forwarded() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "zwp_pointer_gestures_v1".to_string());
+
+        // Gestures before any window has focus are dropped
+        let begin = RendererEvent::GestureBegin { kind: gesture_kind::PINCH, time: 100, fingers: 2 };
+        assert!(comp.handle_renderer_event(10, &begin).is_empty());
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        comp.handle_message(&Message::new(7, opcodes::pointer_gestures::GET_SWIPE_GESTURE, ArgWriter::new().u32(30).u32(9).finish()));
+        comp.handle_message(&Message::new(7, opcodes::pointer_gestures::GET_PINCH_GESTURE, ArgWriter::new().u32(31).u32(9).finish()));
+
+        let responses = comp.handle_renderer_event(10, &begin);
+        assert_eq!(responses.len(), 1);
+        let mut args = ArgReader::new(&responses[0].payload);
+        args.u32().unwrap();
+        assert_eq!((responses[0].object_id, args.u32().unwrap(), args.u32().unwrap(), args.u32().unwrap()), (31, 100, 10, 2));
+
+        let update = RendererEvent::GestureUpdate { kind: gesture_kind::PINCH, time: 116, dx: 0.0, dy: 0.0, scale: 1.5, rotation: 0.0 };
+        assert_eq!(comp.handle_renderer_event(10, &update)[0].opcode, gestures::events::UPDATE);
+
+        let end = RendererEvent::GestureEnd { kind: gesture_kind::PINCH, time: 132, cancelled: false };
+        assert_eq!(comp.handle_renderer_event(10, &end)[0].opcode, gestures::events::END);
+        assert!(comp.handle_renderer_event(10, &end).is_empty());
+    }
+
+    #[test]
+    fn test_pen_stroke_forwarded_to_tablet_tool() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "zwp_tablet_manager_v2".to_string());
+
+        let added = comp.handle_message(&Message::new(7, opcodes::tablet_manager::GET_TABLET_SEAT, ArgWriter::new().u32(30).u32(9).finish()));
+        let tools: Vec<u32> = added.iter()
+            .filter(|m| m.object_id == 30 && m.opcode == tablet::seat_events::TOOL_ADDED)
+            .map(|m| ArgReader::new(&m.payload).u32().unwrap())
+            .collect();
+        assert_eq!(tools.len(), 2);
+        let tablet_id = ArgReader::new(&added[0].payload).u32().unwrap();
+        assert_eq!(comp.objects.get(&tools[0]).map(String::as_str), Some("zwp_tablet_tool_v2"));
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let pen = |flags| RendererEvent::Pen(PenSample { time: 5, x: 8.0, y: 4.0, pressure: 512, flags, ..Default::default() });
+        let responses = comp.handle_renderer_event(10, &pen(pen_flags::IN_RANGE | pen_flags::IN_CONTACT));
+        assert!(responses.iter().all(|m| m.object_id == tools[0]));
+        let mut args = ArgReader::new(&responses[0].payload);
+        args.u32().unwrap();
+        assert_eq!((responses[0].opcode, args.u32().unwrap(), args.u32().unwrap()), (tablet::tool_events::PROXIMITY_IN, tablet_id, 10));
+        assert!(responses.iter().any(|m| m.opcode == tablet::tool_events::DOWN));
+
+        let responses = comp.handle_renderer_event(10, &pen(pen_flags::IN_RANGE));
+        assert_eq!(responses.iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![tablet::tool_events::UP, tablet::tool_events::FRAME]);
+    }
+
+    #[test]
+    fn test_pointer_input_forwarded_to_focused_window() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(8, "wl_seat".to_string());
+        comp.versions.insert(8, 5);
+        comp.handle_message(&Message::new(8, opcodes::seat::GET_POINTER, 40u32.to_le_bytes().to_vec()));
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let opcodes = |responses: Vec<Message>| responses.iter().map(|m| (m.object_id, m.opcode)).collect::<Vec<_>>();
+        let motion = |x| RendererEvent::PointerMotion { time: 1, x, y: 2.0 };
+        // Buttons before the pointer entered are dropped
+        let click = RendererEvent::PointerButton { time: 2, button: 0x110, pressed: true };
+        assert!(comp.handle_renderer_event(10, &click).is_empty());
+
+        let responses = comp.handle_renderer_event(10, &motion(4.5));
+        let mut args = ArgReader::new(&responses[0].payload);
+        args.u32().unwrap();
+        assert_eq!((args.u32().unwrap(), args.fixed().unwrap(), args.fixed().unwrap()), (10, 4.5, 2.0));
+        assert_eq!(opcodes(responses), [(40, pointer::events::ENTER), (40, pointer::events::FRAME)]);
+        assert_eq!(opcodes(comp.handle_renderer_event(10, &motion(5.0))), [(40, pointer::events::MOTION), (40, pointer::events::FRAME)]);
+        assert_eq!(opcodes(comp.handle_renderer_event(10, &click)), [(40, pointer::events::BUTTON), (40, pointer::events::FRAME)]);
+
+        // axis_value120 needs version 8
+        let wheel = RendererEvent::PointerAxis { time: 3, axis: 0, value: 15.0, value120: 120 };
+        assert_eq!(opcodes(comp.handle_renderer_event(10, &wheel)), [(40, pointer::events::AXIS), (40, pointer::events::FRAME)]);
+        assert_eq!(
+            opcodes(comp.handle_renderer_event(10, &RendererEvent::PointerLeave)),
+            [(40, pointer::events::LEAVE), (40, pointer::events::FRAME)]
+        );
+        assert!(comp.handle_renderer_event(10, &RendererEvent::PointerLeave).is_empty());
+    }
+
+    #[test]
+    fn test_presentation_feedback() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(7, "wp_presentation".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 11u32.to_le_bytes().to_vec()));
+
+        let presented = RendererEvent::Presented { time_ns: 2_000_000_500, refresh_ns: 16_666_666, seq: 42, flags: presentation::kind::VSYNC };
+        comp.handle_message(&Message::new(7, opcodes::presentation::FEEDBACK, ArgWriter::new().u32(10).u32(30).finish()));
+        // Feedback waits for the surface commit
+        assert!(comp.handle_renderer_event(10, &presented).is_empty());
+
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let responses = comp.handle_renderer_event(10, &presented);
+        assert_eq!(responses.len(), 1);
+        assert_eq!((responses[0].object_id, responses[0].opcode), (30, presentation::events::PRESENTED));
+        assert!(!comp.objects.contains_key(&30));
+        assert!(comp.handle_renderer_event(10, &presented).is_empty());
+
+        comp.handle_message(&Message::new(7, opcodes::presentation::FEEDBACK, ArgWriter::new().u32(11).u32(31).finish()));
+        comp.handle_message(&Message::new(11, opcodes::surface::COMMIT, vec![]));
+        let responses = comp.handle_message(&Message::new(11, opcodes::surface::DESTROY, vec![]));
+        assert_eq!((responses[0].object_id, responses[0].opcode), (31, presentation::events::DISCARDED));
+    }
+
+    #[test]
+    fn test_frame_callbacks() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+
+        let sync = comp.handle_message(&Message::new(1, opcodes::display::SYNC, 40u32.to_le_bytes().to_vec()));
+        assert_eq!(sync.iter().map(|m| (m.object_id, m.opcode)).collect::<Vec<_>>(), vec![
+            (40, opcodes::callback::DONE),
+            (1, opcodes::display::DELETE_ID),
+        ]);
+        let sync_time = ArgReader::new(&sync[0].payload).u32().unwrap();
+
+        // Callbacks wait for the surface commit
+        comp.handle_message(&Message::new(10, opcodes::surface::FRAME, 41u32.to_le_bytes().to_vec()));
+        assert!(comp.frame_done().is_empty());
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let presented = RendererEvent::Presented { time_ns: 0, refresh_ns: 16_666_666, seq: 1, flags: 0 };
+        let responses = comp.handle_renderer_event(10, &presented);
+        assert_eq!(responses.iter().map(|m| (m.object_id, m.opcode)).collect::<Vec<_>>(), vec![
+            (41, opcodes::callback::DONE),
+            (1, opcodes::display::DELETE_ID),
+        ]);
+        assert!(ArgReader::new(&responses[0].payload).u32().unwrap() >= sync_time);
+        assert!(!comp.objects.contains_key(&41));
+
+        // A destroyed surface's callbacks are dropped
+        comp.handle_message(&Message::new(10, opcodes::surface::FRAME, 42u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::DESTROY, vec![]));
+        assert!(comp.frame_done().is_empty());
+    }
+
+    #[test]
+    fn test_presentation_hints_applied_on_commit() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(7, "wp_tearing_control_manager_v1".to_string());
+        comp.objects.insert(8, "wp_content_type_manager_v1".to_string());
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+
+        comp.handle_message(&Message::new(7, opcodes::tearing_control_manager::GET_TEARING_CONTROL, ArgWriter::new().u32(30).u32(10).finish()));
+        comp.handle_message(&Message::new(8, opcodes::content_type_manager::GET_SURFACE_CONTENT_TYPE, ArgWriter::new().u32(31).u32(10).finish()));
+        comp.handle_message(&Message::new(30, opcodes::tearing_control::SET_PRESENTATION_HINT, 1u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(31, opcodes::content_type::SET_CONTENT_TYPE, content_type::GAME.to_le_bytes().to_vec()));
+        // Hints are double-buffered
+        assert!(!render_messages(&mut comp).iter().any(|m| matches!(m, RenderMessage::PresentationHint(_))));
+
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let hints: Vec<_> = render_messages(&mut comp).into_iter()
+            .filter_map(|m| match m { RenderMessage::PresentationHint(hint) => Some(hint), _ => None })
+            .collect();
+        assert_eq!(hints, vec![PresentationHint { tearing: true, content_type: content_type::GAME }]);
+
+        comp.handle_message(&Message::new(30, opcodes::tearing_control::DESTROY, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(render_messages(&mut comp).iter().any(|m| matches!(
+            m,
+            RenderMessage::PresentationHint(PresentationHint { tearing: false, content_type: content_type::GAME })
+        )));
+    }
+
+    #[test]
+    fn test_single_pixel_buffer_frame() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(7, "wp_single_pixel_buffer_manager_v1".to_string());
+
+        assert_eq!(single_pixel([u32::MAX, 0, 0x8000_0000, u32::MAX]), [128, 0, 255, 255]);
+
+        let args = ArgWriter::new().u32(100).u32(u32::MAX).u32(0).u32(0).u32(u32::MAX).finish();
+        comp.handle_message(&Message::new(7, opcodes::single_pixel_buffer_manager::CREATE_U32_RGBA_BUFFER, args));
+        assert_eq!(comp.objects.get(&100).map(String::as_str), Some("wl_buffer"));
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::Frame(frame), ..] => {
+                assert_eq!((frame.width, frame.height), (1, 1));
+                assert_eq!(frame.data, vec![0, 0, 255, 255]);
+            }
+            other => panic!("expected a frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dmabuf_buffer_creation() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(7, "zwp_linux_dmabuf_v1".to_string());
+
+        let add = ArgWriter::new().u32(0).u32(0).u32(256).u32(0).u32(0).finish();
+        comp.handle_message(&Message::new(7, opcodes::linux_dmabuf::CREATE_PARAMS, 30u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(30, opcodes::linux_buffer_params::ADD, add.clone()));
+        let create = ArgWriter::new().i32(64).i32(32).u32(dmabuf::fourcc::XRGB8888).u32(0).finish();
+        let responses = comp.handle_message(&Message::new(30, opcodes::linux_buffer_params::CREATE, create));
+        assert_eq!((responses[0].object_id, responses[0].opcode), (30, dmabuf::events::CREATED));
+        let buffer_id = ArgReader::new(&responses[0].payload).u32().unwrap();
+        assert!(buffer_id >= SERVER_ID_START);
+        let buffer = comp.buffers.get(buffer_id).unwrap();
+        assert_eq!((buffer.width, buffer.height, buffer.stride), (64, 32, 256));
+        assert_eq!(comp.buffer_formats.get(&buffer_id), Some(&shm_format::XRGB8888));
+
+        let responses = comp.handle_message(&Message::new(7, opcodes::linux_dmabuf::GET_DEFAULT_FEEDBACK, 50u32.to_le_bytes().to_vec()));
+        assert_eq!(responses[0].object_id, CONTROL_OBJECT_ID);
+        assert_eq!(comp.objects.get(&50).map(String::as_str), Some("zwp_linux_dmabuf_feedback_v1"));
+
+        // Unsupported formats fail instead of creating the buffer
+        comp.handle_message(&Message::new(7, opcodes::linux_dmabuf::CREATE_PARAMS, 31u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(31, opcodes::linux_buffer_params::ADD, add));
+        let create = ArgWriter::new().u32(40).i32(64).i32(32).u32(0x3231_564e).u32(0).finish();
+        let responses = comp.handle_message(&Message::new(31, opcodes::linux_buffer_params::CREATE_IMMED, create));
+        assert_eq!(responses[0].opcode, dmabuf::events::FAILED);
+        assert!(comp.buffers.get(40).is_none());
+    }
+
+    #[test]
+    fn test_explicit_sync_holds_frame() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(7, "zwp_linux_dmabuf_v1".to_string());
+        comp.objects.insert(8, "wp_linux_drm_syncobj_manager_v1".to_string());
+
+        comp.handle_message(&Message::new(7, opcodes::linux_dmabuf::CREATE_PARAMS, 30u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(30, opcodes::linux_buffer_params::ADD, ArgWriter::new().u32(0).u32(0).u32(4).u32(0).u32(0).finish()));
+        let create = ArgWriter::new().u32(100).i32(1).i32(1).u32(dmabuf::fourcc::ARGB8888).u32(0).finish();
+        comp.handle_message(&Message::new(30, opcodes::linux_buffer_params::CREATE_IMMED, create));
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(8, opcodes::drm_syncobj_manager::IMPORT_TIMELINE, 40u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(8, opcodes::drm_syncobj_manager::GET_SURFACE, ArgWriter::new().u32(41).u32(10).finish()));
+
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(41, opcodes::drm_syncobj_surface::SET_ACQUIRE_POINT, ArgWriter::new().u32(40).u32(0).u32(1).finish()));
+        comp.handle_message(&Message::new(41, opcodes::drm_syncobj_surface::SET_RELEASE_POINT, ArgWriter::new().u32(40).u32(0).u32(2).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(!render_messages(&mut comp).iter().any(|m| matches!(m, RenderMessage::Frame(_))));
+
+        // Another point does not release the frame
+        comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::SYNC_SIGNALED, ArgWriter::new().u32(40).u32(0).u32(9).finish()));
+        assert!(render_messages(&mut comp).is_empty());
+
+        let responses = comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::SYNC_SIGNALED, ArgWriter::new().u32(40).u32(0).u32(1).finish()));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Frame(_)]));
+        assert_eq!(
+            pipe::PipeEvent::from_message(&responses[0]).unwrap(),
+            pipe::PipeEvent::SyncRelease { timeline: 40, point: 2 }
+        );
+    }
+
+    #[test]
+    fn test_global_config() {
+        let advertised = |comp: &Compositor, interface: &str| {
+            comp.globals.iter().find(|g| g.interface == interface).map(|g| g.version)
+        };
+
+        let comp = Compositor::new();
+        assert_eq!(advertised(&comp, "zwp_linux_dmabuf_v1"), None);
+        assert_eq!(advertised(&comp, "wl_seat"), Some(8));
+
+        let mut config = GlobalConfig::new();
+        config.enable("zwp_linux_dmabuf_v1:3".parse().unwrap());
+        config.enable("wl_seat:5".parse().unwrap());
+        config.disable("wp_presentation").unwrap();
+        assert!(config.disable("wl_compositor").is_err());
+        let comp = Compositor::with_config(vec![Monitor::default()], &config);
+        assert_eq!(advertised(&comp, "zwp_linux_dmabuf_v1"), Some(3));
+        assert_eq!(advertised(&comp, "wl_seat"), Some(5));
+        assert_eq!(advertised(&comp, "wp_presentation"), None);
+
+        assert!("wl_seat:9".parse::<GlobalSpec>().is_err());
+        assert!("wl_frobnicator".parse::<GlobalSpec>().is_err());
+        assert!("wl_seat:0".parse::<GlobalSpec>().is_err());
+    }
+
+    #[test]
+    fn test_object_dump() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+
+        // Sync callbacks are done and deleted immediately
+        let responses = comp.handle_message(&Message::new(1, opcodes::display::SYNC, 5u32.to_le_bytes().to_vec()));
+        assert_eq!((responses[1].object_id, responses[1].opcode), (1, opcodes::display::DELETE_ID));
+        assert!(!comp.objects.contains_key(&5));
+
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        assert!(comp.leaked_objects().is_empty());
+        comp.objects.remove(&20);
+        assert_eq!(comp.leaked_objects().iter().map(|o| o.id).collect::<Vec<_>>(), vec![21]);
+
+        let writes = comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::DUMP_OBJECTS, 7u32.to_le_bytes().to_vec()));
+        let pipe::PipeEvent::Data { id: 7, data } = pipe::PipeEvent::from_message(&writes[0]).unwrap() else {
+            panic!("expected pipe data");
+        };
+        let dump = String::from_utf8(data).unwrap();
+        assert!(dump.lines().any(|line| line.contains("xdg_toplevel") && line.contains("LEAK")));
+        assert!(dump.ends_with("1 flagged\n"));
+    }
+
+    #[test]
+    fn test_shm_formats_sent_on_bind() {
+        let bind_shm = |comp: &mut Compositor| {
+            let name = comp.globals.iter().find(|g| g.interface == "wl_shm").unwrap().name;
+            let args = ArgWriter::new().u32(name).string("wl_shm").u32(1).u32(5).finish();
+            comp.handle_message(&Message::new(2, opcodes::registry::BIND, args))
+        };
+        let formats = |events: Vec<Message>| events.iter().map(|m| ArgReader::new(&m.payload).u32().unwrap()).collect::<Vec<_>>();
+
+        let mut comp = Compositor::new();
+        comp.objects.insert(2, "wl_registry".to_string());
+        assert_eq!(formats(bind_shm(&mut comp)), vec![shm_format::ARGB8888, shm_format::XRGB8888]);
+        // create_pool no longer answers with formats
+        assert!(comp.handle_message(&Message::new(5, opcodes::shm::CREATE_POOL, ArgWriter::new().u32(6).i32(4096).finish())).is_empty());
+
+        // A 16-bit buffer is refused until the format is advertised
+        let rgb565 = ArgWriter::new().u32(100).i32(0).i32(4).i32(4).i32(8).u32(shm_format::RGB565).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, rgb565.clone()));
+        assert!(comp.buffers.get(100).is_none());
+
+        let mut config = GlobalConfig::new();
+        config.add_shm_format(shm_format::RGB565);
+        config.add_shm_format(shm_format::XRGB8888);
+        let mut comp = Compositor::with_config(vec![Monitor::default()], &config);
+        comp.objects.insert(2, "wl_registry".to_string());
+        assert_eq!(formats(bind_shm(&mut comp)), vec![shm_format::ARGB8888, shm_format::XRGB8888, shm_format::RGB565]);
+        comp.handle_message(&Message::new(5, opcodes::shm::CREATE_POOL, ArgWriter::new().u32(6).i32(4096).finish()));
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, rgb565));
+        assert_eq!(comp.buffers.get(100).map(|b| b.bpp), Some(2));
+    }
+
+    #[test]
+    fn test_buffers_share_shm_pool() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(5, "wl_shm".to_string());
+        comp.handle_message(&Message::new(5, opcodes::shm::CREATE_POOL, ArgWriter::new().u32(6).i32(64).finish()));
+        let create_buffer = |id: u32, offset: i32| {
+            let args = ArgWriter::new().u32(id).i32(offset).i32(2).i32(2).i32(8).u32(shm_format::XRGB8888).finish();
+            Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args)
+        };
+        comp.handle_message(&create_buffer(100, 0));
+        comp.handle_message(&create_buffer(101, 16));
+        assert_eq!(comp.buffers.total_memory(), 64);
+
+        // Both buffers live in the pool, also after it grows
+        comp.buffers_mut().get_mut(100).unwrap().update(&[1; 16]);
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::RESIZE, 128i32.to_le_bytes().to_vec()));
+        assert_eq!(comp.buffers.total_memory(), 128);
+        comp.handle_message(&create_buffer(102, 8));
+        assert_eq!(comp.buffers.get(102).unwrap().data[..8], [1; 8]);
+        comp.buffers_mut().get_mut(101).unwrap().update(&[2; 16]);
+        assert_eq!(comp.buffers.get(102).unwrap().data[8..], [2; 8]);
+
+        // Buffers must fit in the pool
+        let events = comp.handle_message(&create_buffer(103, 120));
+        assert!(comp.buffers.get(103).is_none());
+        let mut args = ArgReader::new(&events[0].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (6, shm_error::INVALID_STRIDE));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let mut comp = Compositor::new();
+        comp.set_limits(ResourceLimits { max_surfaces: 1, max_buffer_memory: 64 * 64 * 4, ..Default::default() });
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+
+        // A buffer too large for the memory limit is refused before allocation
+        let create_buffer = |id: u32, size: i32| {
+            let args = ArgWriter::new().u32(id).i32(0).i32(size).i32(size).i32(size * 4).u32(0).finish();
+            Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args)
+        };
+        assert!(comp.handle_message(&create_buffer(100, 64)).is_empty());
+        let events = comp.handle_message(&create_buffer(101, 1));
+        assert_eq!(comp.buffers.count(), 1);
+        assert_eq!((events[0].object_id, events[0].opcode), (1, opcodes::display::ERROR));
+        let mut args = ArgReader::new(&events[0].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (6, display_error::NO_MEMORY));
+        assert!(comp.has_failed());
+
+        // Nothing is handled once the client has failed
+        let create_surface = |id: u32| Message::new(4, opcodes::compositor::CREATE_SURFACE, id.to_le_bytes().to_vec());
+        assert!(comp.handle_message(&create_surface(10)).is_empty());
+        assert!(!comp.surfaces.contains(10));
+
+        let mut comp = Compositor::new();
+        comp.set_limits(ResourceLimits { max_surfaces: 1, ..Default::default() });
+        comp.objects.insert(4, "wl_compositor".to_string());
+        assert!(comp.handle_message(&create_surface(10)).is_empty());
+        let events = comp.handle_message(&create_surface(11));
+        assert_eq!(events[0].opcode, opcodes::display::ERROR);
+    }
+
+    #[test]
+    fn test_hooks_follow_toplevel_lifecycle() {
+        struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+        impl CompositorHooks for Recorder {
+            fn surface_created(&mut self, surface: u32) {
+                self.0.lock().unwrap().push(format!("created {}", surface));
+            }
+            fn toplevel_mapped(&mut self, toplevel: u32, surface: u32) {
+                self.0.lock().unwrap().push(format!("mapped {} {}", toplevel, surface));
+            }
+            fn toplevel_unmapped(&mut self, toplevel: u32) {
+                self.0.lock().unwrap().push(format!("unmapped {}", toplevel));
+            }
+            fn title_changed(&mut self, toplevel: u32, title: &str) {
+                self.0.lock().unwrap().push(format!("title {} {}", toplevel, title));
+            }
+        }
+
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut comp = Compositor::new();
+        comp.set_hooks(Box::new(Recorder(log.clone())));
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_TITLE, ArgWriter::new().string("vim").finish()));
+        let buffer = ArgWriter::new().u32(100).i32(0).i32(4).i32(4).i32(16).u32(0).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, buffer));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::DESTROY, vec![]));
+
+        assert_eq!(*log.lock().unwrap(), vec!["created 10", "title 21 vim", "mapped 21 10", "unmapped 21"]);
+    }
+
+    #[test]
+    fn test_toplevel_introspection() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_TITLE, ArgWriter::new().string("vim").finish()));
+
+        let toplevels = comp.toplevels();
+        assert_eq!(toplevels.len(), 1);
+        assert!(!toplevels[0].mapped);
+        assert_eq!(toplevels[0].surfaces, vec![SurfaceInfo { id: 10, position: (0, 0), size: None, buffer: None }]);
+
+        let buffer = ArgWriter::new().u32(100).i32(0).i32(8).i32(4).i32(32).u32(0).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, buffer));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let toplevel = &comp.toplevels()[0];
+        assert_eq!((toplevel.id, toplevel.surface, toplevel.title.as_str()), (21, Some(10), "vim"));
+        assert!(toplevel.mapped);
+        assert_eq!(toplevel.surfaces[0].size, Some((8, 4)));
+        assert_eq!(toplevel.surfaces[0].buffer, Some(100));
+
+        let writes = comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::DUMP_SURFACES, 7u32.to_le_bytes().to_vec()));
+        let pipe::PipeEvent::Data { data, .. } = pipe::PipeEvent::from_message(&writes[0]).unwrap() else {
+            panic!("expected pipe data");
+        };
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "xdg_toplevel@21 \"vim\" app_id=\"\" mapped\n  wl_surface@10 at 0,0 8x4 buffer=100\n"
+        );
+    }
+
+    #[test]
+    fn test_toplevel_title_forwarded() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(20, "xdg_surface".to_string());
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+
+        let title = ArgWriter::new().string("~/src — vim").finish();
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_TITLE, title));
+        let app_id = ArgWriter::new().string("org.vim.Vim").finish();
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_APP_ID, app_id));
+
+        let messages = render_messages(&mut comp);
+        assert_eq!(messages.len(), 2);
+        match &messages[1] {
+            RenderMessage::Window(info) => {
+                assert_eq!(info.title, "~/src — vim");
+                assert_eq!(info.app_id, "org.vim.Vim");
+            }
+            other => panic!("expected window info, got {:?}", other),
+        }
+        assert!(render_messages(&mut comp).is_empty());
+    }
+
+    #[test]
+    fn test_toplevel_maximize_configure() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+
+        let max = ArgWriter::new().i32(800).i32(600).finish();
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_MAX_SIZE, max));
+        render_messages(&mut comp);
+
+        let responses = comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::SET_MAXIMIZED, vec![]));
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.i32().unwrap(), args.i32().unwrap()), (OUTPUT_WIDTH, OUTPUT_HEIGHT));
+        let states = args.array().unwrap();
+        assert_eq!(&states[0..4], &toplevel_state::MAXIMIZED.to_le_bytes());
+        let serial = ArgReader::new(&responses[1].payload).u32().unwrap();
+
+        // The window follows once the client commits the configure
+        assert!(render_messages(&mut comp).is_empty());
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::ACK_CONFIGURE, serial.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(comp.toplevels()[0].maximized);
+        match &render_messages(&mut comp)[0] {
+            RenderMessage::Window(info) => {
+                assert!(info.is_maximized());
+                assert_eq!((info.max_width, info.max_height), (800, 600));
+            }
+            other => panic!("expected window info, got {:?}", other),
+        }
+
+        // Restoring goes back to the floating size, clamped to max_size
+        let responses = comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::UNSET_MAXIMIZED, vec![]));
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.i32().unwrap(), args.i32().unwrap()), (800, 600));
+    }
+
+    #[test]
+    fn test_ack_configure_validated() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(20, "xdg_surface".to_string());
+        let responses = comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+        let serial = ArgReader::new(&responses[1].payload).u32().unwrap();
+
+        let ack = |serial: u32| Message::new(20, opcodes::xdg_surface::ACK_CONFIGURE, serial.to_le_bytes().to_vec());
+        assert!(comp.handle_message(&ack(serial)).is_empty());
+
+        // Acknowledging the same configure twice is a protocol error
+        let responses = comp.handle_message(&ack(serial));
+        assert_eq!((responses[0].object_id, responses[0].opcode), (1, opcodes::display::ERROR));
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (20, xdg_surface_error::INVALID_SERIAL));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_native_window_state() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let output_global = comp.outputs[0].0;
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(output_global).string("wl_output").u32(4).u32(7).finish()));
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        // Maximize button
+        let responses = comp.handle_renderer_event(10, &RendererEvent::WindowState { state: window_state::MAXIMIZED });
+        assert_eq!((responses[0].object_id, responses[0].opcode), (21, opcodes::xdg_toplevel::CONFIGURE));
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.i32().unwrap(), args.i32().unwrap()), (OUTPUT_WIDTH, OUTPUT_HEIGHT));
+        assert!(args.array().unwrap().chunks(4).any(|s| s == toplevel_state::MAXIMIZED.to_le_bytes()));
+
+        // Minimizing leaves the output without a configure; restoring enters it again
+        let output = 7u32.to_le_bytes().to_vec();
+        let responses = comp.handle_renderer_event(10, &RendererEvent::WindowState { state: window_state::MINIMIZED });
+        assert_eq!(responses, vec![Message::new(10, opcodes::surface::LEAVE, output.clone())]);
+        let responses = comp.handle_renderer_event(10, &RendererEvent::WindowState { state: window_state::MAXIMIZED });
+        assert_eq!(responses, vec![Message::new(10, opcodes::surface::ENTER, output)]);
+
+        // Restore button
+        let responses = comp.handle_renderer_event(10, &RendererEvent::WindowState { state: 0 });
+        let mut args = ArgReader::new(&responses[0].payload);
+        args.i32().unwrap();
+        args.i32().unwrap();
+        assert!(!args.array().unwrap().chunks(4).any(|s| s == toplevel_state::MAXIMIZED.to_le_bytes()));
     }
-}
 
-impl Default for Compositor {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_idle_notifications() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(9, "ext_idle_notifier_v1".to_string());
+        comp.handle_message(&Message::new(9, opcodes::idle_notifier::GET_IDLE_NOTIFICATION, ArgWriter::new().u32(30).u32(60_000).u32(5).finish()));
+        comp.handle_message(&Message::new(9, opcodes::idle_notifier::GET_INPUT_IDLE_NOTIFICATION, ArgWriter::new().u32(31).u32(1000).u32(5).finish()));
+
+        let sent = |responses: Vec<Message>| responses.iter().map(|m| (m.object_id, m.opcode)).collect::<Vec<_>>();
+        assert_eq!(sent(comp.update_idle(Duration::from_secs(2))), vec![(31, idle::events::IDLED)]);
+        assert_eq!(sent(comp.update_idle(Duration::from_secs(90))), vec![(30, idle::events::IDLED)]);
+        comp.handle_message(&Message::new(31, opcodes::idle_notification::DESTROY, vec![]));
+        assert_eq!(sent(comp.update_idle(Duration::ZERO)), vec![(30, idle::events::RESUMED)]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_foreign_toplevel_list() {
+        let toplevel = |identifier: &str, title: &str| ForeignToplevel {
+            identifier: identifier.to_string(),
+            title: title.to_string(),
+            app_id: "foot".to_string(),
+        };
+        let mut comp = Compositor::new();
+        comp.update_foreign_toplevels(vec![toplevel("winpipe-1", "htop")]);
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let name = comp.globals.iter().find(|g| g.interface == "ext_foreign_toplevel_list_v1").unwrap().name;
+
+        // Binding announces the existing toplevels
+        let bind = ArgWriter::new().u32(name).string("ext_foreign_toplevel_list_v1").u32(1).u32(40).finish();
+        let responses = comp.handle_message(&Message::new(2, 0, bind));
+        assert_eq!(responses.len(), 5);
+        assert_eq!((responses[0].object_id, responses[0].opcode), (40, foreign_toplevel::list_events::TOPLEVEL));
+        let first = ArgReader::new(&responses[0].payload).u32().unwrap();
+        assert_eq!(ArgReader::new(&responses[1].payload).string().unwrap(), "winpipe-1");
+
+        let responses = comp.update_foreign_toplevels(vec![toplevel("winpipe-1", "vim"), toplevel("winpipe-2", "foot")]);
+        let sent: Vec<(u32, u16)> = responses.iter().map(|m| (m.object_id, m.opcode)).collect();
+        assert_eq!(&sent[..2], &[(first, foreign_toplevel::handle_events::TITLE), (first, foreign_toplevel::handle_events::DONE)]);
+        assert_eq!(sent[2], (40, foreign_toplevel::list_events::TOPLEVEL));
+
+        let responses = comp.update_foreign_toplevels(vec![toplevel("winpipe-2", "foot")]);
+        assert_eq!(responses, vec![foreign_toplevel::closed(first)]);
+
+        let responses = comp.handle_message(&Message::new(40, opcodes::foreign_toplevel_list::STOP, vec![]));
+        assert_eq!(responses, vec![foreign_toplevel::finished(40)]);
+        assert!(comp.update_foreign_toplevels(vec![toplevel("winpipe-2", "foot"), toplevel("winpipe-3", "gimp")]).is_empty());
+    }
 
     #[test]
-    fn test_compositor_init() {
-        let comp = Compositor::new();
-        assert!(!comp.globals.is_empty());
+    fn test_screencopy_region() {
+        fn gradient(monitor: &Monitor) -> Option<Image> {
+            let data = (0..monitor.width * monitor.height).flat_map(|i| (i as u32).to_le_bytes()).collect();
+            Some(Image { width: monitor.width as u32, height: monitor.height as u32, data })
+        }
+        let mut comp = Compositor::new();
+        comp.screen_capture = gradient;
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(9, "zwlr_screencopy_manager_v1".to_string());
+        comp.versions.insert(9, 2);
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let output_global = comp.outputs[0].0;
+        comp.handle_message(&Message::new(2, 0, ArgWriter::new().u32(output_global).string("wl_output").u32(4).u32(7).finish()));
+
+        // Two pixels of the second row, announced as XRGB8888 without buffer_done (v3)
+        let capture = ArgWriter::new().u32(40).i32(0).u32(7).i32(1).i32(1).i32(2).i32(1).finish();
+        let responses = comp.handle_message(&Message::new(9, opcodes::screencopy_manager::CAPTURE_OUTPUT_REGION, capture));
+        assert_eq!(responses.len(), 1);
+        let mut args = ArgReader::new(&responses[0].payload);
+        let announced: Vec<u32> = (0..4).map(|_| args.u32().unwrap()).collect();
+        assert_eq!(announced, vec![shm_format::XRGB8888, 2, 1, 8]);
+
+        let buffer = ArgWriter::new().u32(100).i32(0).i32(2).i32(1).i32(8).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, buffer));
+        assert!(comp.handle_message(&Message::new(40, opcodes::screencopy_frame::COPY_WITH_DAMAGE, 100u32.to_le_bytes().to_vec())).is_empty());
+        let responses = run_tasks(&mut comp);
+        let expected: Vec<u8> = [1921u32, 1922].iter().flat_map(|p| p.to_le_bytes()).collect();
+        assert_eq!(
+            pipe::PipeEvent::from_message(&responses[0]).unwrap(),
+            pipe::PipeEvent::BufferContent { buffer: 100, offset: 0, data: expected }
+        );
+        assert_eq!(responses[1..].iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![
+            capture::events::FLAGS,
+            capture::events::DAMAGE,
+            capture::events::READY,
+        ]);
+
+        // A frame is copied once
+        comp.handle_message(&Message::new(40, opcodes::screencopy_frame::COPY, 100u32.to_le_bytes().to_vec()));
+        assert!(comp.has_failed());
     }
 
     #[test]
-    fn test_handle_get_registry() {
+    fn test_color_management() {
+        use crate::color::{primaries, transfer_function, MonitorColor};
+        use opcodes::image_description_creator_params as params;
+
         let mut comp = Compositor::new();
-        
-        // wl_display.get_registry with new_id = 2
-        let msg = Message::new(1, 1, 2u32.to_le_bytes().to_vec());
-        let responses = comp.handle_message(&msg);
-        
-        // Should get global events for each registered interface
-        assert!(!responses.is_empty());
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(30, "wp_color_manager_v1".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+
+        // HDR10 content
+        comp.handle_message(&Message::new(30, opcodes::color_manager::CREATE_PARAMETRIC_CREATOR, 40u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(40, params::SET_TF_NAMED, transfer_function::ST2084_PQ.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(40, params::SET_PRIMARIES_NAMED, primaries::BT2020.to_le_bytes().to_vec()));
+        let responses = comp.handle_message(&Message::new(40, params::CREATE, 41u32.to_le_bytes().to_vec()));
+        assert_eq!((responses[0].object_id, responses[0].opcode), (41, color::description_events::READY));
+
+        comp.handle_message(&Message::new(30, opcodes::color_manager::GET_SURFACE, ArgWriter::new().u32(50).u32(10).finish()));
+        let set = ArgWriter::new().u32(41).u32(color::render_intent::PERCEPTUAL).finish();
+        comp.handle_message(&Message::new(50, opcodes::color_management_surface::SET_IMAGE_DESCRIPTION, set));
+        render_messages(&mut comp);
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let spaces: Vec<ColorSpace> = render_messages(&mut comp).into_iter()
+            .filter_map(|m| match m { RenderMessage::ColorSpace(space) => Some(space), _ => None })
+            .collect();
+        assert_eq!(spaces.len(), 1);
+        assert_eq!(spaces[0].transfer, transfer_function::ST2084_PQ);
+        assert_eq!(spaces[0].max_luminance, 10_000);
+
+        // The preferred description follows the primary monitor's HDR state
+        let responses = comp.handle_message(&Message::new(30, opcodes::color_manager::GET_SURFACE_FEEDBACK, ArgWriter::new().u32(60).u32(10).finish()));
+        let sdr_identity = ArgReader::new(&responses[0].payload).u32().unwrap();
+        let hdr = MonitorColor { hdr: true, sdr_white: 240, ..Default::default() };
+        let responses = comp.update_outputs(vec![Monitor { color: hdr, ..Default::default() }]);
+        let changed = responses.iter().find(|m| m.object_id == 60).unwrap();
+        assert_eq!(changed.opcode, color::feedback_events::PREFERRED_CHANGED);
+        assert_ne!(ArgReader::new(&changed.payload).u32().unwrap(), sdr_identity);
+
+        comp.handle_message(&Message::new(60, opcodes::color_management_surface_feedback::GET_PREFERRED, 61u32.to_le_bytes().to_vec()));
+        let info = comp.handle_message(&Message::new(61, opcodes::image_description::GET_INFORMATION, 62u32.to_le_bytes().to_vec()));
+        let tf = info.iter().find(|m| m.opcode == color::info_events::TF_NAMED).unwrap();
+        assert_eq!(tf.payload, transfer_function::EXT_LINEAR.to_le_bytes());
+        let luminances = info.iter().find(|m| m.opcode == color::info_events::LUMINANCES).unwrap();
+        let mut args = ArgReader::new(&luminances.payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap(), args.u32().unwrap()), (0, 80, 240));
+        assert_eq!(info.last().unwrap().opcode, color::info_events::DONE);
+
+        // One color management surface per wl_surface
+        comp.handle_message(&Message::new(30, opcodes::color_manager::GET_SURFACE, ArgWriter::new().u32(51).u32(10).finish()));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_viewport_destination_limit() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(30, "wp_viewporter".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(30, opcodes::viewporter::GET_VIEWPORT, ArgWriter::new().u32(40).u32(10).finish()));
+
+        let destination = |w: i32, h: i32| Message::new(40, opcodes::viewport::SET_DESTINATION, ArgWriter::new().i32(w).i32(h).finish());
+        comp.handle_message(&destination(MAX_SURFACE_SIZE as i32, 1));
+        assert!(!comp.has_failed());
+        comp.handle_message(&destination(1, MAX_SURFACE_SIZE as i32 + 1));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_alpha_modifier() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(30, "wp_alpha_modifier_v1".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let opacities = |comp: &mut Compositor| -> Vec<u32> {
+            render_messages(comp).into_iter()
+                .filter_map(|m| match m { RenderMessage::Opacity(opacity) => Some(opacity.alpha), _ => None })
+                .collect()
+        };
+        // Opaque windows need no opacity message
+        assert!(opacities(&mut comp).is_empty());
+
+        comp.handle_message(&Message::new(30, opcodes::alpha_modifier::GET_SURFACE, ArgWriter::new().u32(40).u32(10).finish()));
+        comp.handle_message(&Message::new(40, opcodes::alpha_modifier_surface::SET_MULTIPLIER, (u32::MAX / 2).to_le_bytes().to_vec()));
+        assert!(opacities(&mut comp).is_empty());
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(opacities(&mut comp), vec![u32::MAX / 2]);
+
+        comp.handle_message(&Message::new(40, opcodes::alpha_modifier_surface::DESTROY, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(opacities(&mut comp), vec![u32::MAX]);
+
+        comp.handle_message(&Message::new(30, opcodes::alpha_modifier::GET_SURFACE, ArgWriter::new().u32(41).u32(10).finish()));
+        comp.handle_message(&Message::new(30, opcodes::alpha_modifier::GET_SURFACE, ArgWriter::new().u32(42).u32(10).finish()));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_frames_paced_to_refresh() {
+        let mut comp = Compositor::new();
+        assert_eq!(comp.frame_interval(), Duration::from_nanos(16_666_666));
+        comp.set_frame_pacing(Some(30));
+        assert_eq!(comp.frame_interval(), Duration::from_secs(1) / 30);
+
+        comp.set_frame_pacing(Some(1));
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Frame(_), ..]));
+
+        // Commits within the interval are drawn together at the next refresh
+        let is_frame = |m: &RenderMessage| matches!(m, RenderMessage::Frame(_) | RenderMessage::FrameUpdate(_));
+        for _ in 0..3 {
+            comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        }
+        assert!(!render_messages(&mut comp).iter().any(is_frame));
+        comp.frame_done();
+        assert_eq!(render_messages(&mut comp).iter().filter(|m| is_frame(m)).count(), 1);
+    }
+
+    #[test]
+    fn test_stats_overlay_sampled_on_refresh() {
+        let mut comp = Compositor::new();
+        comp.set_stats_overlay(true);
+        // Start the sample a second ago so the next refresh reports it
+        comp.overlay_stats = Some(OverlayStats::new(Instant::now() - crate::overlay::SAMPLE_INTERVAL));
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        render_messages(&mut comp);
+
+        comp.frame_done();
+        let stats = render_messages(&mut comp).into_iter().find_map(|m| match m {
+            RenderMessage::StatsOverlay(stats) => Some(stats),
+            _ => None,
+        });
+        let stats = stats.expect("stats overlay after a second");
+        assert!(stats.visible && stats.frame_rate > 0 && stats.bandwidth > 0);
+
+        // Turning it off clears the renderer's overlay once
+        comp.set_stats_overlay(false);
+        comp.set_stats_overlay(false);
+        let messages = render_messages(&mut comp);
+        assert!(matches!(messages[..], [RenderMessage::StatsOverlay(StatsOverlay { visible: false, .. })]));
+    }
+
+    #[test]
+    fn test_half_float_frames_kept_for_capable_renderer() {
+        let mut config = GlobalConfig::default();
+        config.add_shm_format(shm_format::ABGR16161616F);
+        let mut comp = Compositor::with_config(vec![Monitor::default()], &config);
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(4).i32(1).i32(32).u32(shm_format::ABGR16161616F).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        let white: Vec<u8> = [0x3c00u16; 4].map(u16::to_le_bytes).concat();
+        comp.buffers_mut().get_mut(100).unwrap().update(&white.repeat(4));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+
+        // Renderers without half floats get 8 bits per channel
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::Frame(frame)) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame");
+        };
+        assert_eq!((frame.format, frame.data), (PixelFormat::ARGB8888, vec![255; 16]));
+
+        let mut capabilities = RendererCapabilities::default();
+        capabilities.formats |= 1 << PixelFormat::ABGR16161616F as u32;
+        comp.handle_renderer_event(10, &RendererEvent::Capabilities(capabilities));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::Frame(mut frame)) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame");
+        };
+        assert_eq!((frame.format, frame.data.len()), (PixelFormat::ABGR16161616F, 32));
+
+        // Deltas patch whole 8-byte pixels
+        let red = [0x3c00u16, 0, 0, 0x3c00].map(u16::to_le_bytes).concat();
+        comp.buffers_mut().get_mut(100).unwrap().update(&[white.repeat(3), red.clone()].concat());
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        frame.apply_delta(&delta).unwrap();
+        assert_eq!(frame.data[24..], red);
+    }
+
+    #[test]
+    fn test_frames_sent_as_deltas() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(4).i32(4).i32(16).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Frame(_), ..]));
+
+        // Only the changed row travels
+        let mut data = vec![0u8; 64];
+        data[16..32].fill(0x80);
+        comp.buffers_mut().get_mut(100).unwrap().update(&data);
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(delta.buffer_id, 10);
+        assert_eq!(delta.regions.iter().map(|r| (r.y, r.height)).collect::<Vec<_>>(), vec![(1, 1)]);
+
+        // Unchanged content still presents
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(matches!(&render_messages(&mut comp)[..], [RenderMessage::FrameUpdate(FrameUpdate { delta, .. }), ..] if delta.regions.is_empty()));
+
+        // Reported damage limits the comparison
+        data[32..64].fill(0x40);
+        comp.buffers_mut().get_mut(100).unwrap().update(&data);
+        comp.handle_message(&Message::new(10, opcodes::surface::DAMAGE_BUFFER, ArgWriter::new().i32(0).i32(3).i32(4).i32(1).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(delta.regions.iter().map(|r| (r.y, r.height)).collect::<Vec<_>>(), vec![(3, 1)]);
+
+        // One full frame and three deltas of a row or nothing
+        let stats = comp.buffers.stats(10).copied().unwrap();
+        assert_eq!((stats.frames, stats.full_frames, stats.bytes_saved()), (4, 1, 256 - 64 - 2 * 16));
+        let writes = comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::DUMP_BUFFER_STATS, 7u32.to_le_bytes().to_vec()));
+        let pipe::PipeEvent::Data { data, .. } = pipe::PipeEvent::from_message(&writes[0]).unwrap() else {
+            panic!("expected pipe data");
+        };
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "buffer@10: 4 frames (1 full), 37.5% dirty on average, 160 bytes saved (62.5%)\n"
+        );
+    }
+
+    #[test]
+    fn test_helper_buffer_content_written_to_mirror() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        let args = ArgWriter::new().u32(100).i32(0).i32(2).i32(2).i32(8).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+
+        for msg in pipe::buffer_content_messages_at(100, 8, &[9; 8]) {
+            comp.handle_message(&msg);
+        }
+        assert_eq!(&comp.buffers.get(100).unwrap().data[..], [[0; 8], [9; 8]].concat());
+
+        // Content past the end is dropped as a whole
+        for msg in pipe::buffer_content_messages_at(100, 12, &[1; 8]) {
+            comp.handle_message(&msg);
+        }
+        assert_eq!(&comp.buffers.get(100).unwrap().data[..], [[0; 8], [9; 8]].concat());
+    }
+
+    #[test]
+    fn test_buffers_released_when_replaced() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        for id in [100, 101] {
+            let args = ArgWriter::new().u32(id).i32(0).i32(2).i32(2).i32(8).u32(shm_format::XRGB8888).finish();
+            comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        }
+        let commit = |comp: &mut Compositor, buffer: u32| {
+            comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(buffer).i32(0).i32(0).finish()));
+            comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]))
+        };
+
+        assert!(commit(&mut comp, 100).is_empty());
+        assert_eq!(commit(&mut comp, 101), vec![crate::release::release(100)]);
+        assert_eq!((comp.buffer_age(101), comp.buffer_age(100)), (1, 2));
+
+        // The buffer on screen is released with its surface
+        let responses = comp.handle_message(&Message::new(10, opcodes::surface::DESTROY, vec![]));
+        assert_eq!(responses, vec![crate::release::release(101)]);
+    }
+
+    #[test]
+    fn test_damage_accumulates_while_renderer_behind() {
+        let mut comp = Compositor::new();
+        comp.set_max_frames_in_flight(1);
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(4).i32(4).i32(16).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Frame(_), ..]));
+
+        // Two commits while the first frame is not presented yet
+        let mut data = vec![0u8; 64];
+        for row in [1, 3] {
+            data[row * 16..row * 16 + 4].fill(0x80);
+            comp.buffers_mut().get_mut(100).unwrap().update(&data);
+            let damage = ArgWriter::new().i32(0).i32(row as i32).i32(1).i32(1).finish();
+            comp.handle_message(&Message::new(10, opcodes::surface::DAMAGE_BUFFER, damage));
+            comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+            assert!(!render_messages(&mut comp).iter().any(|m| matches!(m, RenderMessage::Frame(_) | RenderMessage::FrameUpdate(_))));
+        }
+
+        // The next frame covers both (merged into one region)
+        comp.handle_renderer_event(10, &RendererEvent::Presented { time_ns: 0, refresh_ns: 16_666_666, seq: 1, flags: 0 });
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(delta.regions.iter().map(|r| (r.y, r.height)).collect::<Vec<_>>(), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn test_frame_acks_pace_frames() {
+        let mut comp = Compositor::new();
+        comp.set_max_frames_in_flight(1);
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        let frames = |comp: &mut Compositor| {
+            render_messages(comp).iter().filter(|m| matches!(m, RenderMessage::Frame(_) | RenderMessage::FrameUpdate(_))).count()
+        };
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(frames(&mut comp), 1);
+        assert_eq!(comp.presentation_latency(), None);
+
+        // Intermediate frames are dropped until the first is acknowledged
+        comp.handle_renderer_event(10, &RendererEvent::FrameAck { sequence: 1 });
+        assert!(comp.presentation_latency().is_some());
+        for _ in 0..3 {
+            comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        }
+        assert_eq!(frames(&mut comp), 1);
+        // Vblanks no longer count once the renderer acknowledges frames
+        comp.handle_renderer_event(10, &RendererEvent::Presented { time_ns: 0, refresh_ns: 16_666_666, seq: 1, flags: 0 });
+        assert_eq!(frames(&mut comp), 0);
+        comp.handle_renderer_event(10, &RendererEvent::FrameAck { sequence: 2 });
+        assert_eq!(frames(&mut comp), 1);
+    }
+
+    #[test]
+    fn test_checksums_and_resync() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(2).i32(2).i32(8).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let Some(RenderMessage::Frame(mut frame)) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame");
+        };
+        assert_eq!((frame.surface_id, frame.sequence), (10, 1));
+
+        // The renderer's copy is checked every CHECKSUM_INTERVAL deltas
+        let mut checksum = None;
+        for _ in 0..CHECKSUM_INTERVAL {
+            comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+            for message in render_messages(&mut comp) {
+                match message {
+                    RenderMessage::FrameUpdate(update) => frame.apply_update(&update).unwrap(),
+                    RenderMessage::Checksum(sent) => checksum = Some(sent),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(checksum, Some(frame.checksum(10, 0)));
+        assert_eq!(frame.sequence, CHECKSUM_INTERVAL as u64 + 1);
+
+        // A renderer that lost track gets a full frame
+        assert!(comp.handle_renderer_event(10, &RendererEvent::Resync { buffer_id: 10 }).is_empty());
+        let sequence = CHECKSUM_INTERVAL as u64 + 2;
+        assert!(matches!(&render_messages(&mut comp)[..], [RenderMessage::Frame(frame), ..] if frame.sequence == sequence));
+        comp.handle_renderer_event(10, &RendererEvent::Resync { buffer_id: 99 });
+        assert!(render_messages(&mut comp).is_empty());
+    }
+
+    #[test]
+    fn test_fifo_holds_commit_until_refresh() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(30, "wp_fifo_manager_v1".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(30, opcodes::fifo_manager::GET_FIFO, ArgWriter::new().u32(40).u32(10).finish()));
+
+        let frames = |comp: &mut Compositor| {
+            let messages = render_messages(comp);
+            messages.iter().filter(|m| matches!(m, RenderMessage::Frame(_) | RenderMessage::FrameUpdate(_))).count()
+        };
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(40, opcodes::fifo::SET_BARRIER, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(frames(&mut comp), 1);
+
+        // The next commit waits for the refresh, with its frame callback
+        comp.handle_message(&Message::new(40, opcodes::fifo::SET_BARRIER, vec![]));
+        comp.handle_message(&Message::new(40, opcodes::fifo::WAIT_BARRIER, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::FRAME, 50u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        comp.handle_message(&Message::new(40, opcodes::fifo::WAIT_BARRIER, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(frames(&mut comp), 0);
+
+        // One held commit per refresh
+        let responses = comp.frame_done();
+        assert_eq!(responses[0].object_id, 50);
+        assert_eq!(frames(&mut comp), 1);
+        comp.frame_done();
+        assert_eq!(frames(&mut comp), 1);
+
+        comp.handle_message(&Message::new(30, opcodes::fifo_manager::GET_FIFO, ArgWriter::new().u32(41).u32(10).finish()));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_commit_timing_targets_refresh() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(30, "wp_commit_timing_manager_v1".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(30, opcodes::commit_timing_manager::GET_TIMER, ArgWriter::new().u32(40).u32(10).finish()));
+
+        let frames = |comp: &mut Compositor| {
+            render_messages(comp).iter().filter(|m| matches!(m, RenderMessage::Frame(_))).count()
+        };
+        let vblank = |ms: u64| RendererEvent::Presented { time_ns: ms * 1_000_000, refresh_ns: 16_000_000, seq: ms, flags: 0 };
+        comp.handle_renderer_event(10, &vblank(1000));
+
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(40, opcodes::commit_timer::SET_TIMESTAMP, ArgWriter::new().u32(0).u32(1).u32(100_000_000).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert_eq!(frames(&mut comp), 0);
+        comp.handle_renderer_event(10, &vblank(1050));
+        assert_eq!(frames(&mut comp), 0);
+        // The refresh after this one shows the content at 1.106s
+        comp.handle_renderer_event(10, &vblank(1090));
+        assert_eq!(frames(&mut comp), 1);
+
+        comp.handle_message(&Message::new(40, opcodes::commit_timer::SET_TIMESTAMP, ArgWriter::new().u32(0).u32(1).u32(0).finish()));
+        comp.handle_message(&Message::new(40, opcodes::commit_timer::SET_TIMESTAMP, ArgWriter::new().u32(0).u32(1).u32(0).finish()));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_toplevel_icon() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(1, opcodes::display::GET_REGISTRY, 2u32.to_le_bytes().to_vec()));
+        let name = comp.globals.iter().find(|g| g.interface == "xdg_toplevel_icon_manager_v1").unwrap().name;
+        let bind = ArgWriter::new().u32(name).string("xdg_toplevel_icon_manager_v1").u32(1).u32(30).finish();
+        let responses = comp.handle_message(&Message::new(2, 0, bind));
+        assert_eq!(responses.iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![
+            toplevel_icon::events::ICON_SIZE,
+            toplevel_icon::events::ICON_SIZE,
+            toplevel_icon::events::DONE,
+        ]);
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        render_messages(&mut comp);
+
+        for (buffer, size) in [(100, 16), (101, 32)] {
+            let args = ArgWriter::new().u32(buffer).i32(0).i32(size).i32(size).i32(size * 4).u32(shm_format::ARGB8888).finish();
+            comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        }
+        comp.handle_message(&Message::new(30, opcodes::toplevel_icon_manager::CREATE_ICON, 40u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(40, opcodes::toplevel_icon::ADD_BUFFER, ArgWriter::new().u32(101).i32(1).finish()));
+        comp.handle_message(&Message::new(40, opcodes::toplevel_icon::ADD_BUFFER, ArgWriter::new().u32(100).i32(1).finish()));
+        comp.handle_message(&Message::new(30, opcodes::toplevel_icon_manager::SET_ICON, ArgWriter::new().u32(21).u32(40).finish()));
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::WindowIcon(icon)] => {
+                assert_eq!(icon.images.iter().map(|image| image.width).collect::<Vec<_>>(), vec![16, 32]);
+            }
+            other => panic!("expected window icon, got {:?}", other),
+        }
+
+        // A null icon restores the default
+        comp.handle_message(&Message::new(30, opcodes::toplevel_icon_manager::SET_ICON, ArgWriter::new().u32(21).u32(0).finish()));
+        assert!(matches!(&render_messages(&mut comp)[..], [RenderMessage::WindowIcon(icon)] if icon.images.is_empty()));
+
+        // Assigned icons are immutable
+        comp.handle_message(&Message::new(40, opcodes::toplevel_icon::SET_NAME, ArgWriter::new().string("foot").finish()));
+        assert!(comp.has_failed());
+    }
+
+    #[test]
+    fn test_toplevel_icon_rejects_non_square_buffer() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.objects.insert(30, "xdg_toplevel_icon_manager_v1".to_string());
+        let args = ArgWriter::new().u32(100).i32(0).i32(16).i32(8).i32(64).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(30, opcodes::toplevel_icon_manager::CREATE_ICON, 40u32.to_le_bytes().to_vec()));
+        let responses = comp.handle_message(&Message::new(40, opcodes::toplevel_icon::ADD_BUFFER, ArgWriter::new().u32(100).i32(1).finish()));
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (40, toplevel_icon_error::INVALID_BUFFER));
+    }
+
+    #[test]
+    fn test_renderer_close() {
+        let mut comp = Compositor::new();
+        for (xdg_surface, toplevel, surface) in [(20u32, 21u32, 10u32), (22, 23, 11)] {
+            comp.objects.insert(xdg_surface, "xdg_surface".to_string());
+            comp.xdg_surfaces.insert(xdg_surface, surface);
+            comp.handle_message(&Message::new(xdg_surface, opcodes::xdg_surface::GET_TOPLEVEL, toplevel.to_le_bytes().to_vec()));
+        }
+
+        // Only the toplevel whose window was closed is asked to close
+        let responses = comp.handle_renderer_event(11, &RendererEvent::Close);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].object_id, 23);
+        assert_eq!(responses[0].opcode, opcodes::xdg_toplevel::CLOSE);
+
+        // Resizing or maximizing a window configures only its toplevel
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Resize { width: 640, height: 480 });
+        assert_eq!(responses.iter().map(|m| m.object_id).collect::<Vec<_>>(), vec![21, 20]);
+        let responses = comp.handle_renderer_event(11, &RendererEvent::WindowState { state: window_state::MAXIMIZED });
+        assert_eq!(responses.iter().map(|m| m.object_id).collect::<Vec<_>>(), vec![23, 22]);
+    }
+
+    #[test]
+    fn test_toplevels_get_own_windows() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        for (surface, xdg_surface, toplevel, buffer) in [(10u32, 20u32, 21u32, 100u32), (11, 22, 23, 101)] {
+            comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, surface.to_le_bytes().to_vec()));
+            comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(xdg_surface).u32(surface).finish()));
+            comp.handle_message(&Message::new(xdg_surface, 1, toplevel.to_le_bytes().to_vec()));
+            let args = ArgWriter::new().u32(buffer).i32(0).i32(1).i32(1).i32(4).u32(shm_format::ARGB8888).finish();
+            comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+            comp.handle_message(&Message::new(surface, opcodes::surface::ATTACH, ArgWriter::new().u32(buffer).i32(0).i32(0).finish()));
+            comp.handle_message(&Message::new(surface, opcodes::surface::COMMIT, vec![]));
+        }
+
+        // Each window starts from its own full frame and sequence
+        let frames: Vec<(u32, u64)> = comp.take_render_messages().into_iter()
+            .filter_map(|(window, message)| match message {
+                RenderMessage::Frame(frame) => Some((window, frame.sequence)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames, vec![(10, 1), (11, 1)]);
+
+        // Deltas are against the window's own last frame
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        let messages = comp.take_render_messages();
+        assert!(messages.iter().any(|(window, message)| *window == 10 && matches!(message, RenderMessage::FrameUpdate(_))));
+
+        // Destroying a toplevel closes only its window
+        comp.handle_message(&Message::new(23, opcodes::xdg_toplevel::DESTROY, vec![]));
+        assert_eq!(comp.take_closed_windows(), vec![11]);
+        assert!(comp.handle_renderer_event(11, &RendererEvent::Close).is_empty());
+    }
+
+    #[test]
+    fn test_interactive_resize() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(20, "xdg_surface".to_string());
+        comp.xdg_surfaces.insert(20, 10);
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+
+        // resize(seat=5, serial=1, edges=bottom_right)
+        let payload = ArgWriter::new().u32(5).u32(1).u32(10).finish();
+        comp.handle_message(&Message::new(21, opcodes::xdg_toplevel::RESIZE, payload));
+        assert!(matches!(
+            render_messages(&mut comp)[..],
+            [RenderMessage::Interactive(InteractiveOp::Resize { edges: 10 })]
+        ));
+
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Resize { width: 640, height: 480 });
+        let mut args = ArgReader::new(&responses[0].payload);
+        assert_eq!((args.i32().unwrap(), args.i32().unwrap()), (640, 480));
+        let states = args.array().unwrap();
+        assert!(states.chunks(4).any(|s| s == toplevel_state::RESIZING.to_le_bytes()));
+
+        let responses = comp.handle_renderer_event(10, &RendererEvent::InteractiveEnd);
+        let mut args = ArgReader::new(&responses[0].payload);
+        args.i32().unwrap();
+        args.i32().unwrap();
+        let states = args.array().unwrap();
+        assert!(!states.chunks(4).any(|s| s == toplevel_state::RESIZING.to_le_bytes()));
+    }
+
+    #[test]
+    fn test_native_window_resize() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(20, "xdg_surface".to_string());
+        comp.xdg_surfaces.insert(20, 10);
+        comp.handle_message(&Message::new(20, opcodes::xdg_surface::GET_TOPLEVEL, 21u32.to_le_bytes().to_vec()));
+        let configured = |responses: &[Message]| {
+            let mut args = ArgReader::new(&responses[0].payload);
+            let size = (args.i32().unwrap(), args.i32().unwrap());
+            let resizing = args.array().unwrap().chunks(4).any(|s| s == toplevel_state::RESIZING.to_le_bytes());
+            (size, resizing)
+        };
+
+        // The user drags the window frame
+        assert!(comp.handle_renderer_event(10, &RendererEvent::InteractiveBegin).is_empty());
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Resize { width: 1000, height: 700 });
+        assert_eq!(configured(&responses), ((1000, 700), true));
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Resize { width: 1024, height: 700 });
+        assert_eq!(configured(&responses), ((1024, 700), true));
+        let responses = comp.handle_renderer_event(10, &RendererEvent::InteractiveEnd);
+        assert_eq!(configured(&responses), ((1024, 700), false));
+
+        // Snapping resizes without a loop; an unchanged size is not resent
+        let responses = comp.handle_renderer_event(10, &RendererEvent::Resize { width: 960, height: 1080 });
+        assert_eq!(configured(&responses), ((960, 1080), false));
+        assert!(comp.handle_renderer_event(10, &RendererEvent::Resize { width: 960, height: 1080 }).is_empty());
+    }
+
+    #[test]
+    fn test_subsurface_composited_into_frame() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(5, "wl_subcompositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+
+        for surface in [10u32, 11] {
+            comp.handle_message(&Message::new(4, 0, surface.to_le_bytes().to_vec()));
+        }
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(5, opcodes::subcompositor::GET_SUBSURFACE, ArgWriter::new().u32(30).u32(11).u32(10).finish()));
+        comp.handle_message(&Message::new(30, opcodes::subsurface::SET_POSITION, ArgWriter::new().i32(1).i32(1).finish()));
+
+        // 2x2 parent buffer and 1x1 child buffer
+        for (buffer, size) in [(100u32, 2i32), (101, 1)] {
+            let args = ArgWriter::new().u32(buffer).i32(0).i32(size).i32(size).i32(size * 4).u32(shm_format::XRGB8888).finish();
+            comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        }
+        comp.buffers_mut().get_mut(100).unwrap().update(&[0x11; 16]);
+        comp.buffers_mut().get_mut(101).unwrap().update(&[0x22; 4]);
+
+        comp.handle_message(&Message::new(11, opcodes::surface::ATTACH, ArgWriter::new().u32(101).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(11, opcodes::surface::COMMIT, vec![]));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        render_messages(&mut comp);
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::Frame(frame)] => {
+                assert_eq!((frame.width, frame.height), (2, 2));
+                assert_eq!(&frame.data[0..4], &[0x11, 0x11, 0x11, 0xFF]);
+                assert_eq!(&frame.data[12..16], &[0x22, 0x22, 0x22, 0xFF]);
+            }
+            other => panic!("expected one frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_input_region_forwarded_on_commit() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_REGION, 40u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(40, opcodes::region::ADD, ArgWriter::new().i32(0).i32(0).i32(100).i32(100).finish()));
+        comp.handle_message(&Message::new(40, opcodes::region::SUBTRACT, ArgWriter::new().i32(0).i32(0).i32(100).i32(10).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::SET_INPUT_REGION, 40u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(40, opcodes::region::DESTROY, vec![]));
+        render_messages(&mut comp);
+
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        match &render_messages(&mut comp)[..] {
+            [RenderMessage::InputRegion(region)] => {
+                assert_eq!(region.rects, Some(vec![Rect::new(0, 10, 100, 90)]));
+            }
+            other => panic!("expected input region, got {:?}", other),
+        }
+
+        // Unchanged region is not sent again
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(render_messages(&mut comp).is_empty());
+    }
+
+    #[test]
+    fn test_popup_configure() {
+        use crate::positioner::constraint;
+
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(20, "xdg_surface".to_string());
+        comp.objects.insert(30, "xdg_surface".to_string());
+
+        comp.handle_message(&Message::new(3, opcodes::xdg_wm_base::CREATE_POSITIONER, 40u32.to_le_bytes().to_vec()));
+        let requests = [
+            (opcodes::xdg_positioner::SET_SIZE, ArgWriter::new().i32(200).i32(300)),
+            (opcodes::xdg_positioner::SET_ANCHOR_RECT, ArgWriter::new().i32(10).i32(1000).i32(50).i32(20)),
+            (opcodes::xdg_positioner::SET_ANCHOR, ArgWriter::new().u32(Edge::BottomLeft as u32)),
+            (opcodes::xdg_positioner::SET_GRAVITY, ArgWriter::new().u32(Edge::BottomRight as u32)),
+            (opcodes::xdg_positioner::SET_CONSTRAINT_ADJUSTMENT, ArgWriter::new().u32(constraint::FLIP_Y)),
+        ];
+        for (opcode, args) in requests {
+            comp.handle_message(&Message::new(40, opcode, args.finish()));
+        }
+
+        // get_popup(new_id=31, parent=20, positioner=40) on xdg_surface 30
+        let payload = ArgWriter::new().u32(31).u32(20).u32(40).finish();
+        let responses = comp.handle_message(&Message::new(30, opcodes::xdg_surface::GET_POPUP, payload));
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].object_id, 31);
+        let mut args = ArgReader::new(&responses[0].payload);
+        let geometry: Vec<i32> = (0..4).map(|_| args.i32().unwrap()).collect();
+        assert_eq!(geometry, vec![10, 700, 200, 300]); // flipped above the anchor
+        assert_eq!(responses[1].object_id, 30);
+
+        comp.handle_message(&Message::new(31, opcodes::xdg_popup::GRAB, ArgWriter::new().u32(5).u32(1).finish()));
+        let done = comp.dismiss_popups();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].opcode, opcodes::xdg_popup::POPUP_DONE);
+    }
+
+    #[test]
+    fn test_popup_drawn_into_window_frame() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(4).i32(4).i32(16).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+        assert!(matches!(render_messages(&mut comp)[..], [RenderMessage::Frame(_), ..]));
+
+        // A 2x2 popup anchored at (1, 1) of the window
+        comp.handle_message(&Message::new(3, opcodes::xdg_wm_base::CREATE_POSITIONER, 40u32.to_le_bytes().to_vec()));
+        let requests = [
+            (opcodes::xdg_positioner::SET_SIZE, ArgWriter::new().i32(2).i32(2)),
+            (opcodes::xdg_positioner::SET_ANCHOR_RECT, ArgWriter::new().i32(1).i32(1).i32(1).i32(1)),
+            (opcodes::xdg_positioner::SET_ANCHOR, ArgWriter::new().u32(Edge::TopLeft as u32)),
+            (opcodes::xdg_positioner::SET_GRAVITY, ArgWriter::new().u32(Edge::BottomRight as u32)),
+        ];
+        for (opcode, args) in requests {
+            comp.handle_message(&Message::new(40, opcode, args.finish()));
+        }
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 11u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(30).u32(11).finish()));
+        comp.handle_message(&Message::new(30, opcodes::xdg_surface::GET_POPUP, ArgWriter::new().u32(31).u32(20).u32(40).finish()));
+        let args = ArgWriter::new().u32(101).i32(64).i32(2).i32(2).i32(8).u32(shm_format::XRGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.buffers_mut().get_mut(101).unwrap().update(&[0x80; 16]);
+        comp.handle_message(&Message::new(11, opcodes::surface::ATTACH, ArgWriter::new().u32(101).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(11, opcodes::surface::COMMIT, vec![]));
+
+        // The popup's commit updates the window's frame where it lies
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(delta.buffer_id, 10);
+        assert_eq!(delta.regions.iter().map(|r| (r.x, r.y, r.width, r.height)).collect::<Vec<_>>(), vec![(1, 1, 2, 2)]);
+
+        // Destroying it uncovers the window again
+        comp.handle_message(&Message::new(31, opcodes::xdg_popup::DESTROY, vec![]));
+        let Some(RenderMessage::FrameUpdate(FrameUpdate { delta, .. })) = render_messages(&mut comp).into_iter().next() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(delta.regions.iter().map(|r| (r.y, r.height)).collect::<Vec<_>>(), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_screenshot_through_virtual_pipe() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        comp.objects.insert(4, "wl_compositor".to_string());
+        comp.objects.insert(6, "wl_shm_pool".to_string());
+        comp.handle_message(&Message::new(4, opcodes::compositor::CREATE_SURFACE, 10u32.to_le_bytes().to_vec()));
+        comp.handle_message(&Message::new(3, 2, ArgWriter::new().u32(20).u32(10).finish()));
+        comp.handle_message(&Message::new(20, 1, 21u32.to_le_bytes().to_vec()));
+        let args = ArgWriter::new().u32(100).i32(0).i32(2).i32(1).i32(8).u32(shm_format::ARGB8888).finish();
+        comp.handle_message(&Message::new(6, opcodes::shm_pool::CREATE_BUFFER, args));
+        comp.buffers_mut().get_mut(100).unwrap().update(&[0xFF; 8]);
+        comp.handle_message(&Message::new(10, opcodes::surface::ATTACH, ArgWriter::new().u32(100).i32(0).i32(0).finish()));
+        comp.handle_message(&Message::new(10, opcodes::surface::COMMIT, vec![]));
+
+        let png = comp.screenshot(Some(21)).unwrap();
+        assert_eq!(png, crate::png::encode(2, 1, &[0xFF; 8]));
+        assert_eq!(comp.screenshot(None), Some(png.clone()));
+        assert_eq!(comp.screenshot(Some(22)), None);
+
+        // The helper gets the PNG through a virtual pipe
+        let writes = comp.handle_message(&Message::new(CONTROL_OBJECT_ID, pipe::opcodes::SCREENSHOT, ArgWriter::new().u32(7).u32(0).finish()));
+        let pipe::PipeEvent::Data { data, .. } = pipe::PipeEvent::from_message(&writes[0]).unwrap() else {
+            panic!("expected pipe data");
+        };
+        assert_eq!(data, png);
+    }
+
+    #[test]
+    fn test_clipboard_receive_through_virtual_pipe() {
+        let mut comp = Compositor::new();
+        comp.objects.insert(10, "wl_data_device_manager".to_string());
+
+        // get_data_device(new_id=11, seat=5)
+        let payload = ArgWriter::new().u32(11).u32(5).finish();
+        comp.handle_message(&Message::new(10, opcodes::data_device_manager::GET_DATA_DEVICE, payload));
+
+        let mut selection = Selection::new();
+        selection.insert("text/plain", b"hello".to_vec());
+        let events = comp.set_selection(Some(selection));
+        let offer_id = ArgReader::new(&events[0].payload).u32().unwrap();
+        assert!(offer_id >= SERVER_ID_START);
+
+        // Helper announces pipe 42, then the client asks for text/plain
+        comp.handle_message(&pipe::open_message(42));
+        let payload = ArgWriter::new().string("text/plain").finish();
+        let writes = comp.handle_message(&Message::new(offer_id, opcodes::data_offer::RECEIVE, payload));
+
+        assert_eq!(
+            pipe::PipeEvent::from_message(&writes[0]).unwrap(),
+            pipe::PipeEvent::Data { id: 42, data: b"hello".to_vec() }
+        );
+        assert_eq!(pipe::PipeEvent::from_message(&writes[1]).unwrap(), pipe::PipeEvent::Close { id: 42 });
+    }
+
+    #[test]
+    fn test_ping() {
+        // Without xdg_wm_base the helper answers
+        let mut comp = Compositor::new();
+        assert_eq!(comp.ping(), pipe::ping_message());
+        assert!(comp.handle_message(&pipe::ping_message()).is_empty());
+
+        comp.objects.insert(3, "xdg_wm_base".to_string());
+        let ping = comp.ping();
+        assert_eq!((ping.object_id, ping.opcode), (3, opcodes::xdg_wm_base::PING));
+        let pong = Message::new(3, opcodes::xdg_wm_base::PONG, ping.payload);
+        assert!(comp.handle_message(&pong).is_empty());
+        assert!(!comp.has_failed());
     }
 }
@@ -8,19 +8,14 @@ use lz4_flex::{compress_prepend_size, decompress_size_prepended};
 use crate::error::{Result, WinpipeError};
 
 /// Compression level (0 = none, higher = more compression)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CompressionLevel {
     None,
+    #[default]
     Fast,    // LZ4 default
     High,    // LZ4 HC (not supported by lz4_flex, fallback to fast)
 }
 
-impl Default for CompressionLevel {
-    fn default() -> Self {
-        Self::Fast
-    }
-}
-
 /// Compressor/Decompressor for winpipe messages
 pub struct Compressor {
     level: CompressionLevel,
@@ -0,0 +1,108 @@
+//! Configure Sequences
+//!
+//! The compositor asks an xdg_surface to change with a configure event
+//! carrying a new serial. The client answers with ack_configure for the
+//! configure it is about to draw, and the new state takes effect with the
+//! following commit. Acknowledging a serial implicitly acknowledges every
+//! older one; acknowledging a serial that was never sent, or one older than
+//! the last acknowledged, is a protocol error.
+
+use std::collections::VecDeque;
+
+use crate::error::{Result, WinpipeError};
+
+/// xdg_surface.error codes
+pub mod xdg_surface_error {
+    pub const INVALID_SERIAL: u32 = 4;
+}
+
+/// Window state an xdg_toplevel.configure asks the client to apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ToplevelConfigure {
+    pub size: (i32, i32),
+    pub maximized: bool,
+    pub fullscreen: bool,
+}
+
+/// Configures sent to one xdg_surface
+///
+/// Popup configures carry no state, only the serial.
+#[derive(Debug, Default)]
+pub struct ConfigureQueue {
+    /// Sent and not acknowledged yet, oldest first
+    sent: VecDeque<(u32, Option<ToplevelConfigure>)>,
+    /// Acknowledged, waiting for the next commit
+    acked: Option<(u32, Option<ToplevelConfigure>)>,
+    /// Serial of the last acknowledged configure
+    last_acked: Option<u32>,
+}
+
+impl ConfigureQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a configure event
+    pub fn sent(&mut self, serial: u32, state: Option<ToplevelConfigure>) {
+        self.sent.push_back((serial, state));
+    }
+
+    /// Handle ack_configure, superseding older configures
+    pub fn ack(&mut self, serial: u32) -> Result<()> {
+        let Some(index) = self.sent.iter().position(|&(sent, _)| sent == serial) else {
+            return Err(WinpipeError::Protocol(match self.last_acked {
+                Some(last) if last == serial => format!("serial {} was already acknowledged", serial),
+                _ => format!("serial {} does not match an outstanding configure", serial),
+            }));
+        };
+        // A newer ack replaces one that was not committed yet
+        self.acked = self.sent.drain(..=index).next_back();
+        self.last_acked = Some(serial);
+        Ok(())
+    }
+
+    /// Handle a commit, returning the state it applies
+    pub fn commit(&mut self) -> Option<ToplevelConfigure> {
+        self.acked.take().and_then(|(_, state)| state)
+    }
+
+    /// Whether the client acknowledged at least one configure
+    pub fn is_configured(&self) -> bool {
+        self.last_acked.is_some()
+    }
+
+    /// Configures still waiting for an ack
+    pub fn outstanding(&self) -> usize {
+        self.sent.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_supersedes_older_configures() {
+        let maximized = ToplevelConfigure { size: (1920, 1080), maximized: true, fullscreen: false };
+        let floating = ToplevelConfigure { size: (800, 600), ..Default::default() };
+        let mut queue = ConfigureQueue::new();
+        queue.sent(1, Some(floating));
+        queue.sent(2, Some(maximized));
+        queue.sent(3, Some(floating));
+        assert!(!queue.is_configured());
+
+        // Nothing applies before an ack
+        assert_eq!(queue.commit(), None);
+        queue.ack(2).unwrap();
+        assert_eq!(queue.outstanding(), 1);
+        assert!(queue.ack(1).is_err());
+        assert!(queue.ack(2).is_err());
+        assert!(queue.ack(7).is_err());
+        assert_eq!(queue.commit(), Some(maximized));
+        assert_eq!(queue.commit(), None);
+
+        queue.ack(3).unwrap();
+        assert_eq!(queue.commit(), Some(floating));
+        assert!(queue.is_configured());
+    }
+}
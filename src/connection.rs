@@ -4,9 +4,9 @@
 //! Supports both server mode (Windows side) and client mode (WSL side placeholder).
 
 use std::net::SocketAddr;
-use std::sync::Arc;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use log::{info, warn, error, debug};
@@ -115,45 +115,75 @@ impl Server {
     }
 }
 
+/// Messages queued for a connection's writer task before senders wait
+const OUTBOUND_QUEUE: usize = 256;
+
 /// A single client connection
+///
+/// Reading and writing are split: `run` reads in the calling task while a
+/// writer task drains the outbound queue, so messages can be sent through
+/// a `ConnectionSender` from any task, at any time.
 pub struct Connection {
-    stream: TcpStream,
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
     config: ConnectionConfig,
     client_id: u32,
     decoder: WireDecoder,
-    encoder: WireEncoder,
     compressor: Compressor,
+    sender: ConnectionSender,
+    outbound: mpsc::Receiver<Vec<u8>>,
 }
 
 impl Connection {
     /// Create new connection from stream
     pub fn new(stream: TcpStream, config: ConnectionConfig, client_id: u32) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (outbound_tx, outbound) = mpsc::channel(OUTBOUND_QUEUE);
         Self {
-            stream,
+            reader,
+            writer,
             compressor: Compressor::new(config.compression),
             config,
             client_id,
             decoder: WireDecoder::new(),
-            encoder: WireEncoder::new(),
+            sender: ConnectionSender { outbound: outbound_tx },
+            outbound,
         }
     }
 
+    /// A handle to send to the client, also while `run` reads
+    pub fn sender(&self) -> ConnectionSender {
+        self.sender.clone()
+    }
+
     /// Run the connection, forwarding messages to channel
-    pub async fn run(mut self, tx: mpsc::Sender<ConnectionEvent>) -> Result<()> {
-        let mut buffer = vec![0u8; self.config.buffer_size];
+    ///
+    /// Queued data is written by a task of its own, which ends once every
+    /// sender is dropped or writing fails.
+    pub async fn run(self, tx: mpsc::Sender<ConnectionEvent>) -> Result<()> {
+        let Self { mut reader, writer, config, client_id, mut decoder, compressor: mut decompressor, sender, outbound } = self;
+        drop(sender);
+        tokio::spawn(async move {
+            let compressor = Compressor::new(config.compression);
+            if let Err(e) = write_queued(writer, outbound, compressor, config.compression).await {
+                warn!("Client {} write error: {}", client_id, e);
+            }
+        });
+
+        let mut buffer = vec![0u8; config.buffer_size];
         
         loop {
-            let n = self.stream.read(&mut buffer).await?;
+            let n = reader.read(&mut buffer).await?;
             if n == 0 {
                 // Connection closed
                 return Ok(());
             }
             
-            debug!("📥 Received {} bytes from client {}", n, self.client_id);
+            debug!("📥 Received {} bytes from client {}", n, client_id);
             
             // Try to decompress if using compression
-            let data = if self.config.compression != CompressionLevel::None {
-                match self.compressor.decompress(&buffer[..n]) {
+            let data = if config.compression != CompressionLevel::None {
+                match decompressor.decompress(&buffer[..n]) {
                     Ok(d) => d,
                     Err(_) => {
                         // Fallback: treat as raw data
@@ -165,15 +195,15 @@ impl Connection {
             };
             
             // Feed to wire decoder
-            self.decoder.push(&data);
+            decoder.push(&data);
             
             // Extract all complete messages
-            while let Some(msg) = self.decoder.decode() {
+            while let Some(msg) = decoder.decode() {
                 debug!("📨 Decoded message: obj={}, opcode={}, payload={} bytes",
                        msg.object_id, msg.opcode, msg.payload.len());
                 
                 if tx.send(ConnectionEvent::Message { 
-                    id: self.client_id, 
+                    id: client_id, 
                     msg 
                 }).await.is_err() {
                     return Ok(()); // Receiver dropped
@@ -183,7 +213,7 @@ impl Connection {
             // Also send raw data event for passthrough handling
             if !data.is_empty() {
                 let _ = tx.send(ConnectionEvent::RawData {
-                    id: self.client_id,
+                    id: client_id,
                     data: data.clone(),
                 }).await;
             }
@@ -191,22 +221,52 @@ impl Connection {
     }
 
     /// Send a message to the client
-    pub async fn send_message(&mut self, msg: &Message) -> Result<()> {
-        let data = self.encoder.encode(msg);
-        self.send_raw(&data).await
+    pub async fn send_message(&self, msg: &Message) -> Result<()> {
+        self.sender.send_message(msg).await
     }
 
     /// Send raw data to the client
-    pub async fn send_raw(&mut self, data: &[u8]) -> Result<()> {
-        let to_send = if self.config.compression != CompressionLevel::None {
-            self.compressor.compress(data)
+    pub async fn send_raw(&self, data: &[u8]) -> Result<()> {
+        self.sender.send_raw(data).await
+    }
+}
+
+/// Queues data for a connection's writer task; clone it to send from
+/// several tasks
+#[derive(Debug, Clone)]
+pub struct ConnectionSender {
+    outbound: mpsc::Sender<Vec<u8>>,
+}
+
+impl ConnectionSender {
+    /// Send a message to the client
+    pub async fn send_message(&self, msg: &Message) -> Result<()> {
+        self.send_raw(&WireEncoder::new().encode(msg)).await
+    }
+
+    /// Send raw data to the client, waiting while the queue is full
+    pub async fn send_raw(&self, data: &[u8]) -> Result<()> {
+        self.outbound.send(data.to_vec()).await.map_err(|_| WinpipeError::ConnectionClosed)
+    }
+}
+
+/// Write queued data until every sender is dropped
+async fn write_queued(
+    mut writer: OwnedWriteHalf,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    mut compressor: Compressor,
+    compression: CompressionLevel,
+) -> Result<()> {
+    while let Some(data) = outbound.recv().await {
+        let to_send = if compression != CompressionLevel::None {
+            compressor.compress(&data)
         } else {
-            data.to_vec()
+            data
         };
-        
-        self.stream.write_all(&to_send).await?;
-        Ok(())
+        writer.write_all(&to_send).await?;
     }
+    writer.shutdown().await?;
+    Ok(())
 }
 
 /// Utility function to forward between two connections (bidirectional proxy)
@@ -250,4 +310,40 @@ mod tests {
         let server = Server::bind(config).await;
         assert!(server.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_send_while_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let config = ConnectionConfig { compression: CompressionLevel::None, ..Default::default() };
+        let conn = Connection::new(stream, config, 1);
+        let sender = conn.sender();
+        let (tx, mut events) = mpsc::channel(16);
+        tokio::spawn(conn.run(tx));
+
+        // Events pushed from other tasks while run() waits for input
+        let pushes: Vec<_> = (0..2u16)
+            .map(|opcode| {
+                let sender = sender.clone();
+                tokio::spawn(async move { sender.send_message(&Message::new(1, opcode, vec![])).await })
+            })
+            .collect();
+        for push in pushes {
+            push.await.unwrap().unwrap();
+        }
+        let mut received = [0u8; 16];
+        client.read_exact(&mut received).await.unwrap();
+        let mut decoder = WireDecoder::new();
+        decoder.push(&received);
+        let mut opcodes = vec![decoder.decode().unwrap().opcode, decoder.decode().unwrap().opcode];
+        opcodes.sort_unstable();
+        assert_eq!(opcodes, [0, 1]);
+
+        client.write_all(&WireEncoder::new().encode(&Message::new(1, 3, vec![]))).await.unwrap();
+        match events.recv().await {
+            Some(ConnectionEvent::Message { id: 1, msg }) => assert_eq!(msg.opcode, 3),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
 }
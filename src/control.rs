@@ -0,0 +1,191 @@
+//! Local Control Socket
+//!
+//! `winpipe ctl` talks to a running server over a TCP socket bound to
+//! localhost. A request is one line of text; the reply is a status line,
+//! either `ok LENGTH` followed by LENGTH bytes of payload or
+//! `error MESSAGE`.
+//!
+//! Commands:
+//! - `screenshot [IDENTIFIER]`: PNG of a toplevel's current frame. The
+//!   identifier is the toplevel's ext-foreign-toplevel-list identifier
+//!   (`winpipe-N`); without one, the focused window is captured.
+//! - `stats on|off`: show or hide the renderer's stats overlay on every
+//!   window. Replies with no payload.
+//!
+//! The runtime hands each request to the client owning the window, whose
+//! compositor answers it between Wayland messages.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use crate::error::{Result, WinpipeError};
+use crate::runtime::Runtime;
+
+/// Port the control socket listens on unless configured otherwise
+pub const DEFAULT_CONTROL_PORT: u16 = 9997;
+
+/// Longest request line accepted
+const MAX_REQUEST_LINE: usize = 1024;
+
+/// A control socket command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Screenshot { identifier: Option<String> },
+    Stats { visible: bool },
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> std::result::Result<Self, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("screenshot"), identifier, None) => Ok(Self::Screenshot { identifier: identifier.map(str::to_string) }),
+            (Some("stats"), Some("on"), None) => Ok(Self::Stats { visible: true }),
+            (Some("stats"), Some("off"), None) => Ok(Self::Stats { visible: false }),
+            (Some(command), _, _) => Err(format!("bad request '{}'", command)),
+            (None, _, _) => Err("empty request".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Screenshot { identifier: Some(identifier) } => write!(f, "screenshot {}", identifier),
+            Self::Screenshot { identifier: None } => write!(f, "screenshot"),
+            Self::Stats { visible } => write!(f, "stats {}", if *visible { "on" } else { "off" }),
+        }
+    }
+}
+
+/// A command handed to the client owning the window it is about
+#[derive(Debug)]
+pub enum ControlRequest {
+    /// Reply with a PNG of the toplevel (`None` = the focused one), or
+    /// `None` if it has no content
+    Screenshot { identifier: Option<String>, reply: oneshot::Sender<Option<Vec<u8>>> },
+    /// Show or hide the stats overlay
+    StatsOverlay { visible: bool },
+}
+
+/// Answer control socket connections until the listener fails
+pub async fn serve(listener: TcpListener, runtime: Arc<Runtime>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                debug!("Control connection from {}", addr);
+                let runtime = Arc::clone(&runtime);
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &runtime).await {
+                        debug!("Control connection from {}: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Control socket accept error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle(stream: TcpStream, runtime: &Runtime) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    (&mut stream).take(MAX_REQUEST_LINE as u64).read_line(&mut line).await?;
+    let result = match line.parse::<Command>() {
+        Ok(Command::Screenshot { identifier }) => runtime.screenshot(identifier).await,
+        Ok(Command::Stats { visible }) => {
+            runtime.set_stats_overlay(visible);
+            Ok(Vec::new())
+        }
+        Err(e) => Err(WinpipeError::Protocol(e)),
+    };
+    let stream = stream.get_mut();
+    match result {
+        Ok(payload) => {
+            stream.write_all(format!("ok {}\n", payload.len()).as_bytes()).await?;
+            stream.write_all(&payload).await?;
+        }
+        Err(WinpipeError::Protocol(message)) => stream.write_all(format!("error {}\n", message).as_bytes()).await?,
+        Err(e) => stream.write_all(format!("error {}\n", e).as_bytes()).await?,
+    }
+    Ok(())
+}
+
+/// Send a command to the server on this machine and return its payload
+pub async fn request(port: u16, command: &Command) -> Result<Vec<u8>> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut stream = BufReader::new(stream);
+    stream.get_mut().write_all(format!("{}\n", command).as_bytes()).await?;
+
+    let mut status = String::new();
+    stream.read_line(&mut status).await?;
+    let status = status.trim_end();
+    if let Some(message) = status.strip_prefix("error ") {
+        return Err(WinpipeError::Protocol(message.to_string()));
+    }
+    let len = status
+        .strip_prefix("ok ")
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or_else(|| WinpipeError::InvalidMessage(format!("bad control reply '{}'", status)))?;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::introspect::ToplevelInfo;
+    use crate::output::Monitor;
+    use crate::runtime::RuntimeConfig;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_screenshot_routed_to_owning_client() {
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(RuntimeConfig::default(), monitors);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, Arc::clone(&runtime)));
+
+        let screenshot = |identifier: Option<&str>| Command::Screenshot { identifier: identifier.map(str::to_string) };
+        let error = request(port, &screenshot(None)).await.unwrap_err();
+        assert_eq!(error.to_string(), "Protocol error: no window to capture");
+
+        let mut session = runtime.connect().unwrap();
+        session.publish_toplevels(&[ToplevelInfo {
+            id: 21,
+            surface: Some(10),
+            title: "vim".to_string(),
+            app_id: String::new(),
+            maximized: false,
+            fullscreen: false,
+            minimized: false,
+            mapped: true,
+            surfaces: Vec::new(),
+        }]);
+        let identifier = runtime.foreign_toplevels().borrow()[0].identifier.clone();
+        let mut requests = session.take_requests().unwrap();
+        tokio::spawn(async move {
+            let Some(ControlRequest::Screenshot { identifier, reply }) = requests.recv().await else {
+                panic!("expected a screenshot request");
+            };
+            assert_eq!(identifier.and_then(|i| session.toplevel_for(&i)), Some(21));
+            let _ = reply.send(Some(b"png".to_vec()));
+            // Keep the client connected while the reply goes out
+            std::future::pending::<()>().await;
+        });
+        assert_eq!(request(port, &screenshot(Some(&identifier))).await.unwrap(), b"png");
+        assert!(request(port, &screenshot(Some("winpipe-99"))).await.is_err());
+        assert_eq!("screenshot winpipe-1".parse(), Ok(screenshot(Some("winpipe-1"))));
+    }
+}
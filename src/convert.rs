@@ -0,0 +1,178 @@
+//! Pixel Format Conversion
+//!
+//! The compositor and the renderer work in ARGB8888: little-endian 32-bit
+//! pixels, i.e. B, G, R, A bytes in memory, which is what Windows calls
+//! BGRA. Buffers in any other advertised wl_shm format are converted to it
+//! before compositing; reading them as-is would swap channels or garble
+//! 16-bit, 10-bit and half float pixels. Frames in formats a renderer
+//! cannot show (YUV, or more than 8 bits per channel) are converted the
+//! same way before they are sent.
+
+use crate::compositor::shm_format;
+use crate::render::PixelFormat;
+
+/// Whether a format has no alpha channel
+pub fn is_opaque(format: u32) -> bool {
+    matches!(
+        format,
+        shm_format::XRGB8888 | shm_format::XBGR8888 | shm_format::RGB565 | shm_format::XBGR2101010 | shm_format::XRGB2101010
+    )
+}
+
+/// Convert a buffer to tightly packed ARGB8888
+///
+/// Returns `None` for ARGB8888 and XRGB8888, which are used in place, and
+/// for unknown formats.
+pub fn to_argb8888(format: u32, width: u32, height: u32, stride: u32, data: &[u8]) -> Option<Vec<u8>> {
+    let bpp = shm_format::bytes_per_pixel(format)?;
+    let convert: fn(&[u8]) -> [u8; 4] = match format {
+        shm_format::ABGR8888 => |px| [px[2], px[1], px[0], px[3]],
+        shm_format::XBGR8888 => |px| [px[2], px[1], px[0], 255],
+        shm_format::RGB565 => rgb565,
+        shm_format::XBGR2101010 => xbgr2101010,
+        shm_format::XRGB2101010 => xrgb2101010,
+        shm_format::ABGR16161616F => abgr16161616f,
+        _ => return None,
+    };
+
+    let mut out = Vec::with_capacity((width * height * 4) as usize);
+    for row in 0..height {
+        let start = (row * stride) as usize;
+        let line = data.get(start..start + (width * bpp) as usize)?;
+        out.extend(line.chunks_exact(bpp as usize).flat_map(convert));
+    }
+    Some(out)
+}
+
+/// Convert a tightly packed frame of any format but ARGB8888 and
+/// XRGB8888 to ARGB8888
+pub fn frame_to_argb8888(format: PixelFormat, width: u32, height: u32, data: &[u8]) -> Option<Vec<u8>> {
+    let shm = match format {
+        PixelFormat::ARGB8888 | PixelFormat::XRGB8888 => return None,
+        PixelFormat::NV12 | PixelFormat::I420 => return yuv_to_argb8888(format, width, height, data),
+        PixelFormat::XRGB2101010 => shm_format::XRGB2101010,
+        PixelFormat::ABGR16161616F => shm_format::ABGR16161616F,
+    };
+    to_argb8888(shm, width, height, width * format.bytes_per_pixel()?, data)
+}
+
+/// Convert a tightly packed NV12 or I420 frame (BT.601 limited range) to
+/// ARGB8888
+///
+/// Returns `None` for RGB formats and data too short for the size.
+pub fn yuv_to_argb8888(format: PixelFormat, width: u32, height: u32, data: &[u8]) -> Option<Vec<u8>> {
+    if !format.is_yuv() || format.frame_size(width, height).is_none_or(|size| data.len() < size) {
+        return None;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let chroma_width = width.div_ceil(2);
+    let (luma, chroma) = data.split_at(width * height);
+    let chroma_plane = chroma_width * height.div_ceil(2);
+    let mut out = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let sample = (y / 2) * chroma_width + x / 2;
+            let (u, v) = match format {
+                PixelFormat::NV12 => (chroma[sample * 2], chroma[sample * 2 + 1]),
+                _ => (chroma[sample], chroma[chroma_plane + sample]),
+            };
+            out.extend_from_slice(&bt601(luma[y * width + x], u, v));
+        }
+    }
+    Some(out)
+}
+
+/// One limited range BT.601 sample as B, G, R, A
+fn bt601(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let (c, d, e) = (298 * (y as i32 - 16), u as i32 - 128, v as i32 - 128);
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    [clamp(c + 516 * d), clamp(c - 100 * d - 208 * e), clamp(c + 409 * e), 255]
+}
+
+/// 5-6-5 bits of R, G, B, from the high bits down
+fn rgb565(px: &[u8]) -> [u8; 4] {
+    let value = u16::from_le_bytes([px[0], px[1]]);
+    let (r, g, b) = ((value >> 11) & 0x1f, (value >> 5) & 0x3f, value & 0x1f);
+    // Replicate the top bits so full intensity stays 255
+    [(b << 3 | b >> 2) as u8, (g << 2 | g >> 4) as u8, (r << 3 | r >> 2) as u8, 255]
+}
+
+/// 10 bits each of B, G, R below 2 unused bits
+fn xbgr2101010(px: &[u8]) -> [u8; 4] {
+    let value = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+    let to_8 = |shift: u32| (((value >> shift) & 0x3ff) >> 2) as u8;
+    [to_8(20), to_8(10), to_8(0), 255]
+}
+
+/// 10 bits each of R, G, B below 2 unused bits
+fn xrgb2101010(px: &[u8]) -> [u8; 4] {
+    let [b, g, r, a] = xbgr2101010(px);
+    [r, g, b, a]
+}
+
+/// Half float R, G, B, A, clamped to 0..1 without tone mapping
+fn abgr16161616f(px: &[u8]) -> [u8; 4] {
+    let channel = |i: usize| {
+        let value = half_to_f32(u16::from_le_bytes([px[i * 2], px[i * 2 + 1]]));
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    [channel(2), channel(1), channel(0), channel(3)]
+}
+
+/// IEEE 754 binary16 to f32
+fn half_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent as i32 - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_convert_to_argb() {
+        // One orange pixel (R=255, G=128, B=0) per format, padded rows
+        let abgr = [255, 128, 0, 200, 9, 9];
+        assert_eq!(to_argb8888(shm_format::ABGR8888, 1, 1, 6, &abgr), Some(vec![0, 128, 255, 200]));
+        assert_eq!(to_argb8888(shm_format::XBGR8888, 1, 1, 4, &abgr[..4]), Some(vec![0, 128, 255, 255]));
+
+        let rgb565 = (0x1fu16 << 11 | 0x20 << 5).to_le_bytes();
+        assert_eq!(to_argb8888(shm_format::RGB565, 1, 1, 2, &rgb565), Some(vec![0, 130, 255, 255]));
+
+        let xbgr30 = (0x3u32 << 30 | 0x200 << 10 | 0x3ff).to_le_bytes();
+        assert_eq!(to_argb8888(shm_format::XBGR2101010, 1, 1, 4, &xbgr30), Some(vec![0, 128, 255, 255]));
+        let xrgb30 = (0x3ffu32 << 20 | 0x200 << 10).to_le_bytes();
+        assert_eq!(to_argb8888(shm_format::XRGB2101010, 1, 1, 4, &xrgb30), Some(vec![0, 128, 255, 255]));
+
+        // Half floats 1.0, 0.5, 0.0 and an out of range 2.0 alpha
+        let fp16 = [0x3c00u16, 0x3800, 0, 0x4000].map(u16::to_le_bytes).concat();
+        assert_eq!(to_argb8888(shm_format::ABGR16161616F, 1, 1, 8, &fp16), Some(vec![0, 128, 255, 255]));
+        assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(half_to_f32(0xc000), -2.0);
+
+        assert_eq!(to_argb8888(shm_format::ARGB8888, 1, 1, 4, &abgr[..4]), None);
+        assert_eq!(to_argb8888(shm_format::ABGR8888, 2, 1, 4, &abgr[..4]), None);
+        assert!(is_opaque(shm_format::RGB565) && !is_opaque(shm_format::ABGR8888));
+    }
+
+    #[test]
+    fn test_yuv_converts_to_argb() {
+        // 2x1 pixels, white and black, sharing one neutral chroma sample
+        let nv12 = [235, 16, 128, 128];
+        let expected = vec![255, 255, 255, 255, 0, 0, 0, 255];
+        assert_eq!(yuv_to_argb8888(PixelFormat::NV12, 2, 1, &nv12), Some(expected.clone()));
+        assert_eq!(yuv_to_argb8888(PixelFormat::I420, 2, 1, &nv12), Some(expected));
+
+        // Pure red is Y=81, U=90, V=240
+        assert_eq!(bt601(81, 90, 240), [0, 0, 255, 255]);
+        assert_eq!(yuv_to_argb8888(PixelFormat::I420, 2, 2, &nv12), None);
+        assert_eq!(yuv_to_argb8888(PixelFormat::ARGB8888, 1, 1, &[0; 4]), None);
+    }
+}
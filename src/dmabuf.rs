@@ -0,0 +1,237 @@
+//! Linux DMA-BUF Buffers (zwp_linux_dmabuf_v1)
+//!
+//! GPU buffers cannot cross the connection as file descriptors. The
+//! WSL-side helper keeps each plane's dmabuf fd, imports it into Vulkan
+//! (VK_EXT_external_memory_dma_buf) and copies the contents into linear
+//! host memory on commit, streaming them exactly like shm buffer contents.
+//! The compositor only validates the buffer parameters and creates a
+//! mirror buffer with the same size and stride; frames reach the renderer,
+//! and from there D3D textures, through the usual path.
+//!
+//! Compositing works on 32-bit BGRA, so only single-plane ARGB8888 and
+//! XRGB8888 buffers with a linear layout are accepted.
+//!
+//! Version 4 clients learn this through feedback objects instead of format
+//! events: a format table (served to the client as a memfd created by the
+//! helper), the main device, and a single tranche listing every entry.
+
+use crate::error::{Result, WinpipeError};
+use crate::pipe;
+use crate::wire::{ArgWriter, Message};
+
+/// zwp_linux_dmabuf_v1 and zwp_linux_buffer_params_v1 event opcodes
+pub mod events {
+    /// zwp_linux_dmabuf_v1.format (deprecated in v3)
+    pub const FORMAT: u16 = 0;
+    /// zwp_linux_dmabuf_v1.modifier (v3)
+    pub const MODIFIER: u16 = 1;
+    /// zwp_linux_buffer_params_v1.created
+    pub const CREATED: u16 = 0;
+    /// zwp_linux_buffer_params_v1.failed
+    pub const FAILED: u16 = 1;
+}
+
+/// zwp_linux_dmabuf_feedback_v1 event opcodes
+pub mod feedback_events {
+    pub const DONE: u16 = 0;
+    pub const FORMAT_TABLE: u16 = 1;
+    pub const MAIN_DEVICE: u16 = 2;
+    pub const TRANCHE_DONE: u16 = 3;
+    pub const TRANCHE_TARGET_DEVICE: u16 = 4;
+    pub const TRANCHE_FORMATS: u16 = 5;
+    pub const TRANCHE_FLAGS: u16 = 6;
+}
+
+/// DRM fourcc codes
+pub mod fourcc {
+    /// 'AR24'
+    pub const ARGB8888: u32 = 0x3432_5241;
+    /// 'XR24'
+    pub const XRGB8888: u32 = 0x3432_5258;
+}
+
+/// DRM_FORMAT_MOD_LINEAR
+pub const MOD_LINEAR: u64 = 0;
+
+/// Formats the helper can import, all with `MOD_LINEAR`
+pub const FORMATS: [u32; 2] = [fourcc::ARGB8888, fourcc::XRGB8888];
+
+/// dev_t of /dev/dri/renderD128 (major 226, minor 128), the render node
+/// the WSL GPU driver exposes
+pub const MAIN_DEVICE: u64 = (226 << 8) | 128;
+
+/// Size of one format table entry: format, padding and modifier
+pub const FORMAT_TABLE_ENTRY_SIZE: usize = 16;
+
+/// Maximum number of planes in a buffer
+pub const MAX_PLANES: u32 = 4;
+
+/// One plane added with zwp_linux_buffer_params_v1.add
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plane {
+    pub offset: u32,
+    pub stride: u32,
+    pub modifier: u64,
+}
+
+/// A zwp_linux_buffer_params_v1 object
+#[derive(Debug, Clone, Default)]
+pub struct BufferParams {
+    planes: [Option<Plane>; MAX_PLANES as usize],
+    /// create or create_immed was already called
+    used: bool,
+}
+
+impl BufferParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// zwp_linux_buffer_params_v1.add
+    pub fn add(&mut self, plane_idx: u32, plane: Plane) -> Result<()> {
+        if self.used {
+            return Err(WinpipeError::Protocol("dmabuf params already used".to_string()));
+        }
+        let slot = self.planes.get_mut(plane_idx as usize)
+            .ok_or_else(|| WinpipeError::Protocol(format!("dmabuf plane index {} out of range", plane_idx)))?;
+        if slot.is_some() {
+            return Err(WinpipeError::Protocol(format!("dmabuf plane {} already set", plane_idx)));
+        }
+        *slot = Some(plane);
+        Ok(())
+    }
+
+    /// Check the parameters for create/create_immed and return the plane
+    ///
+    /// The params object can only be used once, whether this succeeds or not.
+    pub fn validate(&mut self, width: i32, height: i32, format: u32) -> Result<Plane> {
+        if std::mem::replace(&mut self.used, true) {
+            return Err(WinpipeError::Protocol("dmabuf params already used".to_string()));
+        }
+        if !FORMATS.contains(&format) {
+            return Err(WinpipeError::Buffer(format!("unsupported dmabuf format {:#x}", format)));
+        }
+        if width <= 0 || height <= 0 {
+            return Err(WinpipeError::Buffer(format!("invalid dmabuf size {}x{}", width, height)));
+        }
+
+        // Both supported formats have exactly one plane
+        let plane = match self.planes {
+            [Some(plane), None, None, None] => plane,
+            _ => return Err(WinpipeError::Buffer("expected a single dmabuf plane".to_string())),
+        };
+        if plane.modifier != MOD_LINEAR {
+            return Err(WinpipeError::Buffer(format!("unsupported dmabuf modifier {:#x}", plane.modifier)));
+        }
+        if (plane.stride as u64) < width as u64 * 4 {
+            return Err(WinpipeError::Buffer(format!("dmabuf stride {} too small for width {}", plane.stride, width)));
+        }
+        Ok(plane)
+    }
+}
+
+/// wl_shm.format matching a supported fourcc
+pub fn shm_format(format: u32) -> Option<u32> {
+    match format {
+        fourcc::ARGB8888 => Some(crate::compositor::shm_format::ARGB8888),
+        fourcc::XRGB8888 => Some(crate::compositor::shm_format::XRGB8888),
+        _ => None,
+    }
+}
+
+/// Format (v1-v2) or modifier (v3) events sent when the global is bound;
+/// version 4 clients use feedback instead
+pub fn format_events(id: u32, version: u32) -> Vec<Message> {
+    if version >= 4 {
+        return Vec::new();
+    }
+    FORMATS.iter()
+        .map(|&format| {
+            if version >= 3 {
+                let payload = ArgWriter::new()
+                    .u32(format)
+                    .u32((MOD_LINEAR >> 32) as u32)
+                    .u32(MOD_LINEAR as u32)
+                    .finish();
+                Message::new(id, events::MODIFIER, payload)
+            } else {
+                Message::new(id, events::FORMAT, format.to_le_bytes().to_vec())
+            }
+        })
+        .collect()
+}
+
+/// Contents of the format table: one entry per supported format
+pub fn format_table() -> Vec<u8> {
+    let mut table = Vec::with_capacity(FORMATS.len() * FORMAT_TABLE_ENTRY_SIZE);
+    for format in FORMATS {
+        table.extend_from_slice(&format.to_le_bytes());
+        table.extend_from_slice(&0u32.to_le_bytes());
+        table.extend_from_slice(&MOD_LINEAR.to_le_bytes());
+    }
+    table
+}
+
+/// Everything a new zwp_linux_dmabuf_feedback_v1 object receives
+///
+/// Starts with the control message handing the table contents to the
+/// helper, which attaches the memfd to the format_table event.
+pub fn feedback_events(id: u32) -> Vec<Message> {
+    let table = format_table();
+    let device = MAIN_DEVICE.to_le_bytes();
+    let indices: Vec<u8> = (0..FORMATS.len() as u16).flat_map(u16::to_le_bytes).collect();
+    vec![
+        pipe::fd_content_message(&table),
+        Message { fd_count: 1, ..Message::new(id, feedback_events::FORMAT_TABLE, (table.len() as u32).to_le_bytes().to_vec()) },
+        Message::new(id, feedback_events::MAIN_DEVICE, ArgWriter::new().array(&device).finish()),
+        Message::new(id, feedback_events::TRANCHE_TARGET_DEVICE, ArgWriter::new().array(&device).finish()),
+        Message::new(id, feedback_events::TRANCHE_FLAGS, 0u32.to_le_bytes().to_vec()),
+        Message::new(id, feedback_events::TRANCHE_FORMATS, ArgWriter::new().array(&indices).finish()),
+        Message::new(id, feedback_events::TRANCHE_DONE, vec![]),
+        Message::new(id, feedback_events::DONE, vec![]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINEAR: Plane = Plane { offset: 0, stride: 256, modifier: MOD_LINEAR };
+
+    #[test]
+    fn test_params_validation() {
+        let mut params = BufferParams::new();
+        params.add(0, LINEAR).unwrap();
+        assert!(params.add(0, LINEAR).is_err());
+        assert_eq!(params.validate(64, 32, fourcc::XRGB8888).unwrap(), LINEAR);
+        // Single use
+        assert!(params.validate(64, 32, fourcc::XRGB8888).is_err());
+
+        let mut tiled = BufferParams::new();
+        tiled.add(0, Plane { modifier: 1, ..LINEAR }).unwrap();
+        assert!(tiled.validate(64, 32, fourcc::ARGB8888).is_err());
+
+        let mut narrow = BufferParams::new();
+        narrow.add(0, LINEAR).unwrap();
+        assert!(narrow.validate(65, 32, fourcc::ARGB8888).is_err());
+
+        assert!(BufferParams::new().add(MAX_PLANES, LINEAR).is_err());
+    }
+
+    #[test]
+    fn test_feedback_table_and_tranche() {
+        let events = feedback_events(9);
+        let table = match pipe::PipeEvent::from_message(&events[0]).unwrap() {
+            pipe::PipeEvent::FdContent { data } => data,
+            other => panic!("expected fd content, got {:?}", other),
+        };
+        assert_eq!(table.len(), FORMATS.len() * FORMAT_TABLE_ENTRY_SIZE);
+        assert_eq!(&table[16..20], &fourcc::XRGB8888.to_le_bytes());
+        assert_eq!(events[1].payload, (table.len() as u32).to_le_bytes());
+
+        let formats = events.iter().find(|m| m.opcode == feedback_events::TRANCHE_FORMATS).unwrap();
+        assert_eq!(crate::wire::ArgReader::new(&formats.payload).array().unwrap(), vec![0, 0, 1, 0]);
+        assert_eq!(events.last().unwrap().opcode, feedback_events::DONE);
+        assert!(format_events(9, 4).is_empty());
+    }
+}
@@ -21,6 +21,12 @@ pub enum WinpipeError {
 
     #[error("Buffer error: {0}")]
     Buffer(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("Noise error: {0}")]
+    Noise(String),
 }
 
 pub type Result<T> = std::result::Result<T, WinpipeError>;
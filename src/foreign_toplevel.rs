@@ -0,0 +1,96 @@
+//! Foreign Toplevel List (ext-foreign-toplevel-list-v1)
+//!
+//! Taskbars, docks and window switchers enumerate the mapped toplevels of
+//! every client of the server, not just their own. Each client publishes
+//! its toplevels to the shared runtime, which hands the combined list to
+//! every client; a client's compositor compares it with the list it last
+//! announced and sends the difference to its bound list objects:
+//!
+//! - A new toplevel gets a handle, introduced with the list's toplevel
+//!   event and followed by identifier, title, app_id and done.
+//! - A changed title or app_id is sent to the handle, followed by done.
+//! - A toplevel that went away gets closed; the handle stays until the
+//!   client destroys it.
+
+use crate::wire::{ArgWriter, Message};
+
+/// ext_foreign_toplevel_list_v1 event opcodes
+pub mod list_events {
+    pub const TOPLEVEL: u16 = 0;
+    pub const FINISHED: u16 = 1;
+}
+
+/// ext_foreign_toplevel_handle_v1 event opcodes
+pub mod handle_events {
+    pub const CLOSED: u16 = 0;
+    pub const DONE: u16 = 1;
+    pub const TITLE: u16 = 2;
+    pub const APP_ID: u16 = 3;
+    pub const IDENTIFIER: u16 = 4;
+}
+
+/// A mapped toplevel of some client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForeignToplevel {
+    /// Unique for the lifetime of the server, never reused
+    pub identifier: String,
+    pub title: String,
+    pub app_id: String,
+}
+
+/// Events announcing `toplevel` with the new `handle` on `list`
+pub fn announce(list: u32, handle: u32, toplevel: &ForeignToplevel) -> Vec<Message> {
+    vec![
+        Message::new(list, list_events::TOPLEVEL, handle.to_le_bytes().to_vec()),
+        string_event(handle, handle_events::IDENTIFIER, &toplevel.identifier),
+        string_event(handle, handle_events::TITLE, &toplevel.title),
+        string_event(handle, handle_events::APP_ID, &toplevel.app_id),
+        Message::new(handle, handle_events::DONE, vec![]),
+    ]
+}
+
+/// Events updating `handle` from `old` to `new`, if anything changed
+pub fn update(handle: u32, old: &ForeignToplevel, new: &ForeignToplevel) -> Vec<Message> {
+    let mut events = Vec::new();
+    if old.title != new.title {
+        events.push(string_event(handle, handle_events::TITLE, &new.title));
+    }
+    if old.app_id != new.app_id {
+        events.push(string_event(handle, handle_events::APP_ID, &new.app_id));
+    }
+    if !events.is_empty() {
+        events.push(Message::new(handle, handle_events::DONE, vec![]));
+    }
+    events
+}
+
+/// closed()
+pub fn closed(handle: u32) -> Message {
+    Message::new(handle, handle_events::CLOSED, vec![])
+}
+
+/// finished()
+pub fn finished(list: u32) -> Message {
+    Message::new(list, list_events::FINISHED, vec![])
+}
+
+fn string_event(handle: u32, opcode: u16, value: &str) -> Message {
+    Message::new(handle, opcode, ArgWriter::new().string(value).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_update_sends_changes_only() {
+        let old = ForeignToplevel { identifier: "1".to_string(), title: "vim".to_string(), app_id: "org.vim.Vim".to_string() };
+        assert!(update(50, &old, &old).is_empty());
+
+        let new = ForeignToplevel { title: "~/src — vim".to_string(), ..old.clone() };
+        let events = update(50, &old, &new);
+        assert_eq!(events.iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![handle_events::TITLE, handle_events::DONE]);
+        assert_eq!(ArgReader::new(&events[0].payload).string().unwrap(), "~/src — vim");
+    }
+}
@@ -0,0 +1,79 @@
+//! Pointer Gestures
+//!
+//! Precision touchpad gestures recognised by the renderer (through
+//! DirectManipulation) are reported as begin/update/end sequences and
+//! forwarded to every zwp_pointer_gesture_{swipe,pinch,hold}_v1 object of
+//! the matching kind. Swipe and pinch updates carry deltas; pinch also
+//! carries the absolute scale and the rotation delta in degrees. Hold
+//! gestures only begin and end.
+
+use crate::render::gesture_kind;
+use crate::wire::{ArgWriter, Message};
+
+/// Event opcodes shared by the swipe, pinch and hold gesture objects
+pub mod events {
+    pub const BEGIN: u16 = 0;
+    /// Swipe and pinch only
+    pub const UPDATE: u16 = 1;
+    pub const END: u16 = 2;
+    /// Hold gestures have no update, so end comes second
+    pub const HOLD_END: u16 = 1;
+}
+
+/// Interface name of the gesture objects of a `gesture_kind`
+pub fn interface(kind: u32) -> Option<&'static str> {
+    match kind {
+        gesture_kind::SWIPE => Some("zwp_pointer_gesture_swipe_v1"),
+        gesture_kind::PINCH => Some("zwp_pointer_gesture_pinch_v1"),
+        gesture_kind::HOLD => Some("zwp_pointer_gesture_hold_v1"),
+        _ => None,
+    }
+}
+
+/// begin(serial, time, surface, fingers)
+pub fn begin(id: u32, serial: u32, time: u32, surface: u32, fingers: u32) -> Message {
+    let payload = ArgWriter::new().u32(serial).u32(time).u32(surface).u32(fingers).finish();
+    Message::new(id, events::BEGIN, payload)
+}
+
+/// update(time, dx, dy) for swipes, update(time, dx, dy, scale, rotation)
+/// for pinches; holds have no update
+pub fn update(id: u32, kind: u32, time: u32, dx: f64, dy: f64, scale: f64, rotation: f64) -> Option<Message> {
+    let args = ArgWriter::new().u32(time).fixed(dx).fixed(dy);
+    let payload = match kind {
+        gesture_kind::SWIPE => args.finish(),
+        gesture_kind::PINCH => args.fixed(scale).fixed(rotation).finish(),
+        _ => return None,
+    };
+    Some(Message::new(id, events::UPDATE, payload))
+}
+
+/// end(serial, time, cancelled)
+pub fn end(id: u32, kind: u32, serial: u32, time: u32, cancelled: bool) -> Message {
+    let opcode = if kind == gesture_kind::HOLD { events::HOLD_END } else { events::END };
+    let payload = ArgWriter::new().u32(serial).u32(time).i32(cancelled as i32).finish();
+    Message::new(id, opcode, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_update_per_kind() {
+        let swipe = update(5, gesture_kind::SWIPE, 10, 1.5, -2.0, 1.0, 0.0).unwrap();
+        assert_eq!(swipe.payload.len(), 12);
+
+        let pinch = update(6, gesture_kind::PINCH, 10, 0.0, 0.0, 1.25, -5.0).unwrap();
+        let mut args = ArgReader::new(&pinch.payload);
+        args.u32().unwrap();
+        args.fixed().unwrap();
+        args.fixed().unwrap();
+        assert_eq!((args.fixed().unwrap(), args.fixed().unwrap()), (1.25, -5.0));
+
+        assert!(update(7, gesture_kind::HOLD, 10, 0.0, 0.0, 1.0, 0.0).is_none());
+        assert_eq!(end(7, gesture_kind::HOLD, 1, 10, true).opcode, events::HOLD_END);
+        assert_eq!(end(5, gesture_kind::SWIPE, 1, 10, false).opcode, events::END);
+    }
+}
@@ -0,0 +1,32 @@
+//! Compositor Event Hooks
+//!
+//! Applications embedding winpipe as a library can watch what a client
+//! does without touching the protocol handling: implement the callbacks of
+//! interest and install the hooks with `Compositor::set_hooks`. Callbacks
+//! run after the compositor has updated its own state, in the order the
+//! requests arrive.
+
+/// Callbacks for client activity; every method defaults to doing nothing
+#[allow(unused_variables)]
+pub trait CompositorHooks: Send {
+    /// wl_compositor.create_surface
+    fn surface_created(&mut self, surface: u32) {}
+
+    /// wl_surface.commit, after the surface state was applied
+    fn surface_committed(&mut self, surface: u32) {}
+
+    /// wl_surface.destroy
+    fn surface_destroyed(&mut self, surface: u32) {}
+
+    /// A toplevel's surface was committed with a buffer for the first time
+    fn toplevel_mapped(&mut self, toplevel: u32, surface: u32) {}
+
+    /// A mapped toplevel, or its surface, was destroyed
+    fn toplevel_unmapped(&mut self, toplevel: u32) {}
+
+    /// xdg_toplevel.set_title
+    fn title_changed(&mut self, toplevel: u32, title: &str) {}
+
+    /// xdg_toplevel.set_app_id
+    fn app_id_changed(&mut self, toplevel: u32, app_id: &str) {}
+}
@@ -0,0 +1,97 @@
+//! Idle Notifications (ext-idle-notify-v1)
+//!
+//! Screen lockers and status bars ask to be told when the user has been
+//! idle for a while. Idleness is the Windows session's: the time since the
+//! last keyboard or mouse input anywhere on the desktop, read with
+//! GetLastInputInfo and polled by the connection loop. Without Win32 the
+//! user never becomes idle.
+//!
+//! Idle inhibitors are not implemented, so notifications created with
+//! get_idle_notification and get_input_idle_notification behave the same.
+
+use std::time::Duration;
+
+use crate::wire::Message;
+
+/// ext_idle_notification_v1 event opcodes
+pub mod events {
+    pub const IDLED: u16 = 0;
+    pub const RESUMED: u16 = 1;
+}
+
+/// An ext_idle_notification_v1 object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleNotification {
+    /// Idle time after which `idled` is sent
+    pub timeout: Duration,
+    /// `idled` was sent and `resumed` was not yet
+    pub idled: bool,
+}
+
+impl IdleNotification {
+    pub fn new(timeout_ms: u32) -> Self {
+        Self { timeout: Duration::from_millis(timeout_ms as u64), idled: false }
+    }
+
+    /// Event for notification `id` given how long the user has been idle
+    pub fn update(&mut self, id: u32, idle: Duration) -> Option<Message> {
+        let idled = idle >= self.timeout;
+        if idled == self.idled {
+            return None;
+        }
+        self.idled = idled;
+        let opcode = if idled { events::IDLED } else { events::RESUMED };
+        Some(Message::new(id, opcode, vec![]))
+    }
+}
+
+/// Time since the last user input on the Windows session
+pub fn idle_time() -> Duration {
+    platform::idle_time()
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::time::Duration;
+
+    use windows_sys::Win32::System::SystemInformation::GetTickCount;
+    use windows_sys::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+
+    pub fn idle_time() -> Duration {
+        // SAFETY: the struct is zero-initialised with its size set as the API requires
+        unsafe {
+            let mut info: LASTINPUTINFO = std::mem::zeroed();
+            info.cbSize = std::mem::size_of::<LASTINPUTINFO>() as u32;
+            if GetLastInputInfo(&mut info) == 0 {
+                return Duration::ZERO;
+            }
+            // Both tick counts wrap after 49.7 days
+            Duration::from_millis(GetTickCount().wrapping_sub(info.dwTime) as u64)
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::time::Duration;
+
+    /// No input tracking without Win32
+    pub fn idle_time() -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idled_then_resumed_once() {
+        let mut notification = IdleNotification::new(5000);
+        assert_eq!(notification.update(30, Duration::from_secs(4)), None);
+        assert_eq!(notification.update(30, Duration::from_secs(5)), Some(Message::new(30, events::IDLED, vec![])));
+        assert_eq!(notification.update(30, Duration::from_secs(60)), None);
+        assert_eq!(notification.update(30, Duration::ZERO), Some(Message::new(30, events::RESUMED, vec![])));
+        assert_eq!(notification.update(30, Duration::ZERO), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Surface Tree Introspection
+//!
+//! A read-only snapshot of a client's windows: each toplevel with its
+//! title and state, and the surfaces of its tree in drawing order with
+//! their positions, sizes and attached buffers. External tools get the same
+//! information as text through the control channel.
+
+use std::fmt::Write;
+
+/// A surface of a toplevel's tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SurfaceInfo {
+    pub id: u32,
+    /// Position relative to the toplevel's surface, in logical pixels
+    pub position: (i32, i32),
+    /// Size after the viewport, if the surface has content
+    pub size: Option<(u32, u32)>,
+    /// Currently attached wl_buffer
+    pub buffer: Option<u32>,
+}
+
+/// An xdg_toplevel and its surface tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToplevelInfo {
+    pub id: u32,
+    /// wl_surface with the toplevel role
+    pub surface: Option<u32>,
+    pub title: String,
+    pub app_id: String,
+    pub maximized: bool,
+    pub fullscreen: bool,
+    pub minimized: bool,
+    /// Committed with a buffer at least once
+    pub mapped: bool,
+    /// The toplevel's surface and its subsurfaces, bottom to top
+    pub surfaces: Vec<SurfaceInfo>,
+}
+
+/// Human-readable tree, one toplevel per paragraph
+pub fn format_tree(toplevels: &[ToplevelInfo]) -> String {
+    let mut out = String::new();
+    for toplevel in toplevels {
+        let _ = write!(out, "xdg_toplevel@{} {:?} app_id={:?}", toplevel.id, toplevel.title, toplevel.app_id);
+        for (set, state) in [
+            (toplevel.mapped, "mapped"),
+            (toplevel.maximized, "maximized"),
+            (toplevel.fullscreen, "fullscreen"),
+            (toplevel.minimized, "minimized"),
+        ] {
+            if set {
+                let _ = write!(out, " {}", state);
+            }
+        }
+        out.push('\n');
+        for surface in &toplevel.surfaces {
+            let _ = write!(out, "  wl_surface@{} at {},{}", surface.id, surface.position.0, surface.position.1);
+            if let Some((width, height)) = surface.size {
+                let _ = write!(out, " {}x{}", width, height);
+            }
+            if let Some(buffer) = surface.buffer {
+                let _ = write!(out, " buffer={}", buffer);
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
@@ -0,0 +1,94 @@
+//! Dead Peer Detection
+//!
+//! When WSL or a laptop suspends, TCP connections can end up half-open:
+//! one side is gone, but the other never learns of it, so its client and
+//! window linger forever. Two mechanisms find such connections:
+//!
+//! - TCP keepalive on every winpipe connection, which lets the OS drop
+//!   the connection after a few unanswered probes.
+//! - Heartbeats every `HEARTBEAT_INTERVAL`: sessions (see `crate::session`)
+//!   send ACK frames, and a session silent for `DEAD_PEER_TIMEOUT` waits
+//!   to be resumed. Connections without a session, like plain socat
+//!   relays, are left to TCP keepalive and the idle timeout.
+
+use std::time::{Duration, Instant};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+/// How often heartbeats are sent
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Silence after which a peer that sends heartbeats counts as dead
+pub const DEAD_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Idle time before the first TCP keepalive probe
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+
+/// Time between unanswered TCP keepalive probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Unanswered probes after which the OS drops the connection
+const KEEPALIVE_RETRIES: u32 = 3;
+
+/// Enable TCP keepalive on `stream`
+pub fn configure_tcp(stream: &TcpStream) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE_TIME)
+        .with_interval(KEEPALIVE_INTERVAL)
+        .with_retries(KEEPALIVE_RETRIES);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// When the peer was last heard from
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    last_heard: Instant,
+}
+
+impl Liveness {
+    pub fn new(now: Instant) -> Self {
+        Self { last_heard: now }
+    }
+
+    /// Record traffic from the peer
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = self.last_heard.max(now);
+    }
+
+    /// When the peer counts as dead unless heard from before
+    pub fn deadline(&self) -> Instant {
+        self.last_heard + DEAD_PEER_TIMEOUT
+    }
+
+    pub fn is_dead(&self, now: Instant) -> bool {
+        now >= self.deadline()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_after_silence() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(start);
+        assert!(!liveness.is_dead(start + DEAD_PEER_TIMEOUT - Duration::from_millis(1)));
+        assert!(liveness.is_dead(start + DEAD_PEER_TIMEOUT));
+
+        liveness.heard(start + HEARTBEAT_INTERVAL);
+        assert!(!liveness.is_dead(start + DEAD_PEER_TIMEOUT));
+        // Traffic reported out of order does not move the deadline back
+        liveness.heard(start);
+        assert_eq!(liveness.deadline(), start + HEARTBEAT_INTERVAL + DEAD_PEER_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_configure_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        configure_tcp(&stream).unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
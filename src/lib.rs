@@ -10,3 +10,49 @@ pub mod buffer;
 pub mod render;
 pub mod compositor;
 pub mod error;
+pub mod clipboard;
+pub mod pipe;
+pub mod positioner;
+pub mod surface;
+pub mod region;
+pub mod output;
+pub mod text_input;
+pub mod pointer_constraints;
+pub mod gestures;
+pub mod tablet;
+pub mod presentation;
+pub mod dmabuf;
+pub mod syncobj;
+pub mod objects;
+pub mod runtime;
+pub mod hooks;
+pub mod introspect;
+pub mod configure;
+pub mod idle;
+pub mod foreign_toplevel;
+pub mod capture;
+pub mod toplevel_icon;
+pub mod color;
+pub mod scheduling;
+pub mod convert;
+pub mod mapping;
+pub mod release;
+pub mod pool;
+pub mod shm_transport;
+pub mod builtin_renderer;
+pub mod png;
+pub mod control;
+pub mod overlay;
+pub mod pointer;
+pub mod sink;
+#[cfg(target_os = "linux")]
+pub mod client;
+pub mod ssh;
+pub mod listen;
+pub mod auth;
+pub mod ratelimit;
+pub mod keepalive;
+pub mod tls;
+pub mod noise;
+pub mod session;
+pub mod mux;
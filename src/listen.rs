@@ -0,0 +1,260 @@
+//! Server Listeners
+//!
+//! Wayland clients reach the server over TCP: `winpipe client` in WSL, or
+//! remote machines through `winpipe ssh`. Programs on the same Windows
+//! machine, such as other Wayland bridges or test tools, can connect to a
+//! named pipe instead, selected with `--listen pipe:\\.\pipe\NAME`.
+//!
+//! TCP is only bound to localhost and the WSL virtual adapter unless
+//! other addresses are asked for, and `--allow-from` limits which peers
+//! are accepted on any of them. TLS or Noise, when enabled, covers every
+//! TCP peer but loopback ones, on the client as on the server: those never
+//! leave the machine, and the connections `winpipe ssh` forwards arrive
+//! there after running through ssh.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::render::PIPE_PREFIX;
+
+/// Where the server accepts Wayland clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// TCP, written HOST:PORT or tcp:HOST:PORT
+    Tcp(SocketAddr),
+    /// A Windows named pipe, written pipe:\\.\pipe\NAME
+    Pipe(String),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("pipe:") {
+            return match path.strip_prefix(PIPE_PREFIX) {
+                Some("") | None => Err(format!("invalid pipe '{}' (expected pipe:{}NAME)", path, PIPE_PREFIX)),
+                Some(_) => Ok(Self::Pipe(path.to_string())),
+            };
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        addr.parse().map(Self::Tcp).map_err(|_| {
+            format!("invalid listen address '{}' (expected HOST:PORT or pipe:{}NAME)", s, PIPE_PREFIX)
+        })
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Pipe(path) => write!(f, "pipe:{}", path),
+        }
+    }
+}
+
+/// An IP network, written ADDR/PREFIX or a single ADDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network; IPv4-mapped IPv6 addresses count
+    /// as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(net) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(net) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max).ok_or_else(|| {
+                format!("invalid prefix length in '{}' (expected 0 to {})", s, max)
+            })?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Whether a TCP connection with `peer` runs the TLS or Noise handshake
+/// when encryption is enabled
+pub fn encrypts(peer: IpAddr) -> bool {
+    !peer.to_canonical().is_loopback()
+}
+
+/// Addresses TCP is bound to by default: localhost, which WSL's mirrored
+/// networking also uses, and the Windows side of the WSL virtual adapter
+///
+/// The adapter only exists while WSL runs; start WSL before the server
+/// or bind it explicitly.
+pub fn default_bind_addrs() -> Vec<IpAddr> {
+    let mut addrs = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+    addrs.extend(platform::wsl_adapter_addrs());
+    addrs
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+        IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows_sys::Win32::Networking::WinSock::AF_INET;
+
+    /// Friendly names of the WSL adapter start with this, e.g.
+    /// "vEthernet (WSL (Hyper-V firewall))"
+    const WSL_ADAPTER_PREFIX: &str = "vEthernet (WSL";
+
+    /// IPv4 addresses of the WSL virtual adapter
+    pub fn wsl_adapter_addrs() -> Vec<IpAddr> {
+        let flags = GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+        let mut size = 16 * 1024u32;
+        // u64 keeps the adapter structures aligned
+        let mut buffer: Vec<u64>;
+        // SAFETY: the buffer is as large as `size` says; the list it
+        // receives is only walked while the buffer lives
+        unsafe {
+            loop {
+                buffer = vec![0; (size as usize).div_ceil(8)];
+                let first = buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH;
+                match GetAdaptersAddresses(AF_INET as u32, flags, ptr::null(), first, &mut size) {
+                    ERROR_SUCCESS => break,
+                    ERROR_BUFFER_OVERFLOW => continue,
+                    _ => return Vec::new(),
+                }
+            }
+
+            let mut addrs = Vec::new();
+            let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+            while !adapter.is_null() {
+                if wide_to_string((*adapter).FriendlyName).starts_with(WSL_ADAPTER_PREFIX) {
+                    let mut unicast = (*adapter).FirstUnicastAddress;
+                    while !unicast.is_null() {
+                        let sockaddr = (*unicast).Address.lpSockaddr;
+                        if !sockaddr.is_null() && (*sockaddr).sa_family == AF_INET {
+                            // sockaddr_in: port in bytes 0..2, address in 2..6
+                            let data = (*sockaddr).sa_data.map(|b| b as u8);
+                            addrs.push(IpAddr::V4(Ipv4Addr::new(data[2], data[3], data[4], data[5])));
+                        }
+                        unicast = (*unicast).Next;
+                    }
+                }
+                adapter = (*adapter).Next;
+            }
+            addrs
+        }
+    }
+
+    /// # Safety
+    /// `wide` must be null or a nul-terminated UTF-16 string
+    unsafe fn wide_to_string(wide: *const u16) -> String {
+        if wide.is_null() {
+            return String::new();
+        }
+        let len = (0..).take_while(|&i| *wide.add(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(wide, len))
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::net::IpAddr;
+
+    /// No WSL adapter outside Windows
+    pub fn wsl_adapter_addrs() -> Vec<IpAddr> {
+        Vec::new()
+    }
+}
+
+/// Accepts clients on a named pipe, one pipe instance per client
+#[cfg(windows)]
+pub struct PipeListener {
+    path: String,
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+#[cfg(windows)]
+impl PipeListener {
+    /// Create the pipe; fails if another server already owns it
+    pub fn bind(path: &str) -> std::io::Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let next = ServerOptions::new().first_pipe_instance(true).create(path)?;
+        Ok(Self { path: path.to_string(), next })
+    }
+
+    /// Wait for a client; a new instance is created for the next one
+    /// before this one is handed out
+    pub async fn accept(&mut self) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        self.next.connect().await?;
+        let next = ServerOptions::new().create(&self.path)?;
+        Ok(std::mem::replace(&mut self.next, next))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addr_parsing() {
+        assert_eq!("127.0.0.1:9999".parse(), Ok(ListenAddr::Tcp("127.0.0.1:9999".parse().unwrap())));
+        assert_eq!("tcp:0.0.0.0:1".parse(), Ok(ListenAddr::Tcp("0.0.0.0:1".parse().unwrap())));
+        let pipe: ListenAddr = r"pipe:\\.\pipe\winpipe".parse().unwrap();
+        assert_eq!(pipe, ListenAddr::Pipe(r"\\.\pipe\winpipe".to_string()));
+        assert_eq!(pipe.to_string(), r"pipe:\\.\pipe\winpipe");
+        assert!(r"pipe:\\.\pipe\".parse::<ListenAddr>().is_err());
+        assert!("pipe:winpipe".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let lan: Cidr = "172.16.0.0/12".parse().unwrap();
+        assert!(lan.contains("172.31.250.1".parse().unwrap()));
+        assert!(!lan.contains("172.32.0.1".parse().unwrap()));
+        // Dual-stack sockets report IPv4 peers mapped into IPv6
+        assert!(lan.contains("::ffff:172.20.0.5".parse().unwrap()));
+
+        let host: Cidr = "10.0.0.7".parse().unwrap();
+        assert!(host.contains("10.0.0.7".parse().unwrap()) && !host.contains("10.0.0.8".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains("fd12::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_encrypts_all_but_loopback() {
+        assert!(encrypts("172.20.0.1".parse().unwrap()));
+        assert!(encrypts("fd12::1".parse().unwrap()));
+        assert!(!encrypts("127.0.0.1".parse().unwrap()));
+        assert!(!encrypts("::1".parse().unwrap()));
+        assert!(!encrypts("::ffff:127.0.0.1".parse().unwrap()));
+    }
+}
@@ -4,20 +4,60 @@
 //! running Wayland applications from WSL on Windows.
 //!
 //! Usage:
-//!   winpipe server [--port PORT]     # Run as Wayland compositor server
+//!   winpipe server [--port PORT] [--bind IP]... [--allow-from CIDR]...
+//!                  [--listen HOST:PORT|pipe:PIPE]
+//!                  [--renderer ADDR|shm:NAME|PIPE | --builtin-renderer [--presenter software|d3d11]]
+//!                  [--decorations server|client]
+//!                  [--enable-global NAME[:VERSION]]... [--disable-global NAME]...
+//!                  [--shm-format NAME]...
+//!                  [--max-objects N] [--max-surfaces N] [--max-buffer-memory MIB]
+//!                  [--max-fps N] [--frame-checksums] [--compress-frames]
+//!                  [--max-bytes-per-sec N] [--max-messages-per-sec N]
+//!                  [--max-clients N] [--idle-timeout MINUTES] [--resume-grace SECS]
+//!                  [--control-port PORT] [--token TOKEN]
+//!                  [--tls-cert PEM --tls-key PEM | --noise-psk HEX]
+//!                                                      # Run as Wayland compositor server
+//!   winpipe ctl [--port PORT] screenshot OUT.png [--toplevel IDENTIFIER]
+//!                                                      # Save a window's frame as PNG
+//!   winpipe ctl [--port PORT] stats on|off             # Toggle the stats overlay
+//!   winpipe client [--server HOST:PORT] [--socket NAME] [--token TOKEN]
+//!                  [--tls-ca PEM [--tls-server-name NAME] | --noise-psk HEX]
+//!                                                      # Bridge WSL apps to the server
+//!   winpipe ssh [--port PORT] [--remote-winpipe PATH] [--token TOKEN]
+//!               [SSH_OPTION]... DESTINATION COMMAND...
+//!                                                      # Show a remote app through ssh
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
 use log::{info, error, debug, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::task::JoinSet;
 
-use winpipe::wire::{Message, WireDecoder, WireEncoder, HEADER_SIZE};
-use winpipe::compositor::Compositor;
+use winpipe::auth;
+use winpipe::builtin_renderer::Presenter;
+use winpipe::wire::{WireDecoder, WireEncoder};
+use winpipe::compositor::{display_error, shm_format, Completion, DecorationMode, GlobalConfig, GlobalSpec, ResourceLimits};
+use winpipe::control::{self, ControlRequest, DEFAULT_CONTROL_PORT};
+use winpipe::idle;
+use winpipe::keepalive;
+#[cfg(windows)]
+use winpipe::listen::PipeListener;
+use winpipe::listen::{self, Cidr, ListenAddr};
+use winpipe::mux::{self, Accepted};
+use winpipe::objects::format_dump;
+use winpipe::output::watch_monitors;
+use winpipe::ratelimit::{RateLimiter, RateLimits};
+use winpipe::render::{RendererAddr, RendererEvent};
+use winpipe::runtime::{ClientSession, Runtime, RuntimeConfig};
+use winpipe::session::{self, Sessions};
+use winpipe::ssh::{remote_socket_path, SshInvocation};
+use winpipe::tls;
+use winpipe::noise;
 
 /// Winpipe: Windows-native Waypipe Implementation
 #[derive(Parser, Debug)]
@@ -31,6 +71,8 @@ struct Args {
     command: Commands,
 }
 
+// Parsed once at startup, so the size of the server options is no concern
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Run as Wayland compositor server (Windows side)
@@ -38,6 +80,224 @@ enum Commands {
         /// Port to listen on
         #[arg(short, long, default_value_t = 9999)]
         port: u16,
+
+        /// Listen on HOST:PORT instead, or on a Windows named pipe for
+        /// clients on this machine with pipe:\\.\pipe\NAME
+        #[arg(long, value_name = "ADDR", conflicts_with_all = ["port", "bind"])]
+        listen: Option<ListenAddr>,
+
+        /// Address to accept TCP clients on; repeat for several (default:
+        /// 127.0.0.1 and the WSL virtual adapter, 0.0.0.0 for all)
+        #[arg(long, value_name = "IP")]
+        bind: Vec<IpAddr>,
+
+        /// Only accept TCP clients from this network, e.g.
+        /// 172.16.0.0/12; repeat for several
+        #[arg(long = "allow-from", value_name = "CIDR")]
+        allow_from: Vec<Cidr>,
+
+        /// Address of the win-way renderer to forward windows to,
+        /// shm:NAME for its shared-memory transport on this machine, or a
+        /// named pipe such as \\.\pipe\winway
+        #[arg(short, long)]
+        renderer: Option<RendererAddr>,
+
+        /// Show windows with the renderer built into winpipe instead of
+        /// win-way (Windows only)
+        #[arg(long, conflicts_with = "renderer")]
+        builtin_renderer: bool,
+
+        /// How the built-in renderer draws: software, or d3d11 for GPU
+        /// textures and DXGI swapchains with scaling and vsync
+        #[arg(long, default_value = "software", requires = "builtin_renderer")]
+        presenter: Presenter,
+
+        /// Decoration mode for xdg-decoration clients: server (native
+        /// Windows title bar) or client
+        #[arg(long, default_value = "server")]
+        decorations: DecorationMode,
+
+        /// Advertise a global, optionally capping its version, e.g.
+        /// zwp_linux_dmabuf_v1 for the GPU buffer path or wl_seat:5
+        #[arg(long = "enable-global", value_name = "NAME[:VERSION]")]
+        enable_globals: Vec<GlobalSpec>,
+
+        /// Stop advertising a global
+        #[arg(long = "disable-global", value_name = "NAME")]
+        disable_globals: Vec<GlobalSpec>,
+
+        /// Advertise a wl_shm format besides argb8888 and xrgb8888:
+        /// rgb565, abgr8888, xbgr8888 or xbgr2101010
+        #[arg(long = "shm-format", value_name = "NAME", value_parser = parse_shm_format)]
+        shm_formats: Vec<u32>,
+
+        /// Maximum number of live protocol objects per client
+        #[arg(long, value_name = "N")]
+        max_objects: Option<usize>,
+
+        /// Maximum number of wl_surfaces per client
+        #[arg(long, value_name = "N")]
+        max_surfaces: Option<usize>,
+
+        /// Maximum mirrored buffer memory per client, in MiB
+        #[arg(long, value_name = "MIB")]
+        max_buffer_memory: Option<usize>,
+
+        /// Frames a window may send before the renderer presents one;
+        /// later frames are merged into the next, 0 for no limit
+        #[arg(long, value_name = "N", default_value_t = 2)]
+        max_frames_in_flight: u32,
+
+        /// Frame rate cap per window; 0 paces frames to the monitor
+        /// refresh only
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        max_fps: u32,
+
+        /// Send frames with a checksum so the renderer can detect
+        /// transport corruption
+        #[arg(long)]
+        frame_checksums: bool,
+
+        /// LZ4 compress frames for renderers that support it; worth it
+        /// when bandwidth, not CPU, is the limit
+        #[arg(long)]
+        compress_frames: bool,
+
+        /// Bytes per second a client may send before its connection is
+        /// throttled
+        #[arg(long, value_name = "N")]
+        max_bytes_per_sec: Option<u64>,
+
+        /// Wayland messages per second a client may send before its
+        /// connection is throttled
+        #[arg(long, value_name = "N")]
+        max_messages_per_sec: Option<u64>,
+
+        /// Clients served at once; later ones are turned away, 0 for no
+        /// limit
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        max_clients: usize,
+
+        /// Ping clients that sent nothing for this many minutes and
+        /// disconnect them if they do not answer
+        #[arg(long, value_name = "MINUTES")]
+        idle_timeout: Option<u64>,
+
+        /// Seconds a `winpipe client` session keeps its app after the
+        /// connection drops, waiting to be resumed
+        #[arg(long, value_name = "SECS", default_value_t = session::DEFAULT_RESUME_GRACE.as_secs())]
+        resume_grace: u64,
+
+        /// Local port for `winpipe ctl`
+        #[arg(long, value_name = "PORT", default_value_t = DEFAULT_CONTROL_PORT)]
+        control_port: u16,
+
+        /// Token clients must present before any Wayland traffic
+        /// (default: $WINPIPE_TOKEN)
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+
+        /// Certificate chain (PEM) for TLS on TCP connections, other than
+        /// loopback ones
+        #[arg(long, value_name = "PEM", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// Private key (PEM) of the TLS certificate
+        #[arg(long, value_name = "PEM", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Key shared with clients (64 hex digits) for Noise encryption on
+        /// TCP connections other than loopback ones, instead of TLS
+        /// (default: $WINPIPE_NOISE_PSK)
+        #[arg(long, value_name = "HEX", conflicts_with = "tls_cert")]
+        noise_psk: Option<String>,
+    },
+
+    /// Control a running server on this machine
+    Ctl {
+        /// Control port of the server
+        #[arg(short, long, default_value_t = DEFAULT_CONTROL_PORT)]
+        port: u16,
+
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+
+    /// Bridge local Wayland clients to a server (WSL side)
+    Client {
+        /// Address of the winpipe server, or the path of a Unix socket
+        /// forwarded to it
+        #[arg(short, long, value_name = "HOST:PORT", default_value = "127.0.0.1:9999")]
+        server: String,
+
+        /// Socket to listen on, under $XDG_RUNTIME_DIR unless absolute
+        #[arg(long, value_name = "NAME", default_value = DEFAULT_SOCKET_NAME)]
+        socket: String,
+
+        /// Token to present to the server (default: $WINPIPE_TOKEN)
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+
+        /// Connect over TLS, trusting the certificates in this PEM file
+        #[arg(long, value_name = "PEM")]
+        tls_ca: Option<PathBuf>,
+
+        /// Name the server's certificate is for, if not the host in
+        /// --server
+        #[arg(long, value_name = "NAME", requires = "tls_ca")]
+        tls_server_name: Option<String>,
+
+        /// Key shared with the server (64 hex digits) for Noise
+        /// encryption, instead of TLS (default: $WINPIPE_NOISE_PSK)
+        #[arg(long, value_name = "HEX", conflicts_with = "tls_ca")]
+        noise_psk: Option<String>,
+    },
+
+    /// Run a command on another machine over ssh and show its windows
+    /// through the server on this one
+    Ssh {
+        /// Port of the local server
+        #[arg(long, default_value_t = 9999)]
+        port: u16,
+
+        /// winpipe on the remote machine
+        #[arg(long, value_name = "PATH", default_value = "winpipe")]
+        remote_winpipe: String,
+
+        /// Token of the local server, passed to the remote client as
+        /// WINPIPE_TOKEN, which the remote sshd must accept (AcceptEnv)
+        /// (default: $WINPIPE_TOKEN)
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+
+        /// ssh options, the destination and the command to run
+        #[arg(value_name = "SSH_ARGS", trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        args: Vec<String>,
+    },
+}
+
+/// Socket name of `winpipe client`
+const DEFAULT_SOCKET_NAME: &str = "wayland-winpipe";
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Save the current frame of a window as PNG
+    Screenshot {
+        /// File to write
+        output: PathBuf,
+
+        /// Foreign toplevel identifier of the window (default: the
+        /// focused one)
+        #[arg(long, value_name = "IDENTIFIER")]
+        toplevel: Option<String>,
+    },
+
+    /// Show or hide the stats overlay (fps, bandwidth, compression,
+    /// latency) on every window
+    Stats {
+        /// on or off
+        #[arg(value_name = "on|off", value_parser = parse_on_off)]
+        visible: bool,
     },
 }
 
@@ -56,71 +316,558 @@ async fn main() -> anyhow::Result<()> {
         ).init();
     }
 
-    println!();
-    println!("  ╔═══════════════════════════════════════════════════╗");
-    println!("  ║       🔌 Winpipe: Wayland Compositor Proxy        ║");
-    println!("  ║       Windows-native Waypipe Implementation       ║");
-    println!("  ╚═══════════════════════════════════════════════════╝");
-    println!();
-
     match args.command {
-        Commands::Server { port } => {
-            run_server(port).await?;
+        Commands::Server {
+            port,
+            listen,
+            bind,
+            allow_from,
+            renderer,
+            builtin_renderer,
+            presenter,
+            decorations,
+            enable_globals,
+            disable_globals,
+            shm_formats,
+            max_objects,
+            max_surfaces,
+            max_buffer_memory,
+            max_frames_in_flight,
+            max_fps,
+            frame_checksums,
+            compress_frames,
+            max_bytes_per_sec,
+            max_messages_per_sec,
+            max_clients,
+            idle_timeout,
+            resume_grace,
+            control_port,
+            token,
+            tls_cert,
+            tls_key,
+            noise_psk,
+        } => {
+            println!();
+            println!("  ╔═══════════════════════════════════════════════════╗");
+            println!("  ║       🔌 Winpipe: Wayland Compositor Proxy        ║");
+            println!("  ║       Windows-native Waypipe Implementation       ║");
+            println!("  ╚═══════════════════════════════════════════════════╝");
+            println!();
+
+            let mut globals = GlobalConfig::new();
+            for spec in enable_globals {
+                globals.enable(spec);
+            }
+            for spec in disable_globals {
+                globals.disable(&spec.interface).map_err(anyhow::Error::msg)?;
+            }
+            for format in shm_formats {
+                globals.add_shm_format(format);
+            }
+            let defaults = ResourceLimits::default();
+            let limits = ResourceLimits {
+                max_objects: max_objects.unwrap_or(defaults.max_objects),
+                max_surfaces: max_surfaces.unwrap_or(defaults.max_surfaces),
+                max_buffer_memory: max_buffer_memory.map_or(defaults.max_buffer_memory, |mib| mib << 20),
+            };
+            let renderer = if builtin_renderer { Some(RendererAddr::Builtin(presenter)) } else { renderer };
+            let frame_pacing = Some(max_fps);
+            let config = RuntimeConfig {
+                renderer,
+                decorations,
+                globals,
+                limits,
+                max_frames_in_flight,
+                frame_pacing,
+                frame_checksums,
+                frame_compression: compress_frames,
+                rate_limits: RateLimits { bytes_per_sec: max_bytes_per_sec, messages_per_sec: max_messages_per_sec },
+                max_clients,
+                idle_timeout: idle_timeout.map(|minutes| Duration::from_secs(minutes * 60)),
+                resume_grace: Duration::from_secs(resume_grace),
+            };
+            let listen = match listen {
+                Some(addr) => vec![addr],
+                None => {
+                    let ips = if bind.is_empty() { listen::default_bind_addrs() } else { bind };
+                    ips.into_iter().map(|ip| ListenAddr::Tcp(SocketAddr::new(ip, port))).collect()
+                }
+            };
+            let encryption = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some(Encryption::Tls(tls::Acceptor::new(&cert, &key)?)),
+                _ => noise::psk_from(noise_psk)?.map(Encryption::Noise),
+            };
+            let admission = Admission { token: auth::token_from(token), allow_from, encryption };
+            run_server(listen, control_port, admission, config).await?;
+        }
+        Commands::Ctl { port, command } => match command {
+            CtlCommand::Screenshot { output, toplevel } => {
+                let png = control::request(port, &control::Command::Screenshot { identifier: toplevel }).await?;
+                std::fs::write(&output, png)?;
+                println!("Saved {}", output.display());
+            }
+            CtlCommand::Stats { visible } => {
+                control::request(port, &control::Command::Stats { visible }).await?;
+            }
+        },
+        #[cfg(target_os = "linux")]
+        Commands::Client { server, socket, token, tls_ca, tls_server_name, noise_psk } => {
+            let encryption = match tls_ca {
+                Some(ca) => Some(winpipe::client::Encryption::Tls(tls::Connector::new(&ca, tls_server_name)?)),
+                None => noise::psk_from(noise_psk)?.map(winpipe::client::Encryption::Noise),
+            };
+            winpipe::client::run(&server, &socket, auth::token_from(token).as_deref(), encryption).await?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Commands::Client { server, socket, .. } => {
+            anyhow::bail!("winpipe client runs on the Linux side (bridging {} to {})", socket, server);
+        }
+        Commands::Ssh { port, remote_winpipe, token, args } => {
+            let Some(invocation) = SshInvocation::parse(&args) else {
+                anyhow::bail!("usage: winpipe ssh [SSH_OPTION]... DESTINATION COMMAND...");
+            };
+            let token = auth::token_from(token);
+            if TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+                anyhow::bail!("no winpipe server on port {}; start `winpipe server` first", port);
+            }
+            let ssh_args = invocation.ssh_args(port, &remote_socket_path(), &remote_winpipe, token.is_some());
+            debug!("ssh {:?}", ssh_args);
+            let mut ssh = tokio::process::Command::new("ssh");
+            ssh.args(&ssh_args);
+            if let Some(token) = &token {
+                ssh.env(auth::TOKEN_ENV, token);
+            }
+            let status = ssh.status().await?;
+            std::process::exit(status.code().unwrap_or(1));
         }
     }
 
     Ok(())
 }
 
+/// Timer completing frame callbacks without a renderer
+fn headless_frame_timer(interval: Duration) -> tokio::time::Interval {
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    timer
+}
+
+/// How often the Windows idle time is checked for idle notifications
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a client silent for the idle timeout has to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parse a wl_shm format name
+fn parse_shm_format(name: &str) -> Result<u32, String> {
+    shm_format::from_name(&name.to_ascii_lowercase()).ok_or_else(|| format!("unknown wl_shm format '{}'", name))
+}
+
+fn parse_on_off(state: &str) -> Result<bool, String> {
+    match state {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got '{}'", state)),
+    }
+}
+
 /// Run winpipe as a Wayland compositor server
-async fn run_server(port: u16) -> anyhow::Result<()> {
-    let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
-    let listener = TcpListener::bind(addr).await?;
+async fn run_server(listen: Vec<ListenAddr>, control_port: u16, admission: Admission, config: RuntimeConfig) -> anyhow::Result<()> {
+    let mut listeners = Vec::new();
+    for addr in &listen {
+        let listener = match addr {
+            ListenAddr::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            #[cfg(windows)]
+            ListenAddr::Pipe(path) => PipeListener::bind(path).map(Listener::Pipe),
+            #[cfg(not(windows))]
+            ListenAddr::Pipe(path) => anyhow::bail!("cannot listen on {}: named pipes are only available on Windows", path),
+        };
+        match listener {
+            Ok(listener) => {
+                info!("🚀 Winpipe Wayland compositor listening on {}", addr);
+                listeners.push(listener);
+            }
+            Err(e) => warn!("Cannot listen on {}: {}", addr, e),
+        }
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("no address to listen on");
+    }
+
+    for addr in &listen {
+        let ListenAddr::Tcp(addr) = addr else { continue };
+        if addr.ip().is_loopback() {
+            continue;
+        }
+        if admission.token.is_none() {
+            warn!("No --token set: anyone who can reach {} can connect", addr);
+        }
+        if admission.encryption.is_none() {
+            warn!("No --tls-cert or --noise-psk set: traffic on {} is not encrypted", addr);
+        }
+    }
+    if let Some(port) = listen.iter().find_map(|addr| match addr {
+        ListenAddr::Tcp(addr) => Some(addr.port()),
+        ListenAddr::Pipe(_) => None,
+    }) {
+        info!("💡 Connect from WSL:");
+        info!("   WIN_IP=$(ip route | grep default | cut -d' ' -f3)");
+        info!("   winpipe client --server $WIN_IP:{} &", port);
+        info!("   export WAYLAND_DISPLAY=wayland-winpipe");
+        info!("   your-wayland-app");
+    }
+
+    let monitors = watch_monitors();
+    for monitor in monitors.borrow().iter() {
+        info!(
+            "🖥️  Output {}: {}x{} at ({}, {}){}",
+            monitor.name, monitor.width, monitor.height, monitor.x, monitor.y,
+            if monitor.primary { " [primary]" } else { "" }
+        );
+    }
 
-    info!("🚀 Winpipe Wayland compositor listening on port {}", port);
-    info!("💡 Connect from WSL:");
-    info!("   WIN_IP=$(ip route | grep default | cut -d' ' -f3)");
-    info!("   rm -f /tmp/wayland-winpipe && socat UNIX-LISTEN:/tmp/wayland-winpipe,fork TCP:$WIN_IP:{} &", port);
-    info!("   export WAYLAND_DISPLAY=/tmp/wayland-winpipe");
-    info!("   your-wayland-app");
+    let sessions = Sessions::new(config.resume_grace);
+
+    // One renderer connection shared by every client's window
+    let renderer = config.renderer.clone();
+    let runtime = Runtime::new(config, monitors);
+    if let Err(e) = runtime.start_renderer().await {
+        warn!("Renderer unavailable at {:?}, retrying: {}", renderer, e);
+    }
+
+    // Local only: screenshots show whatever the user's windows show
+    match TcpListener::bind(("127.0.0.1", control_port)).await {
+        Ok(listener) => {
+            tokio::spawn(control::serve(listener, Arc::clone(&runtime)));
+        }
+        Err(e) => warn!("Control socket unavailable on port {}: {}", control_port, e),
+    }
 
     info!("✅ Server ready, waiting for connections...");
 
-    let mut client_id = 0u32;
+    let admission = Arc::new(admission);
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        accepting.spawn(accept_clients(listener, Arc::clone(&runtime), Arc::clone(&sessions), Arc::clone(&admission)));
+    }
+    // The accept loops run until the server is stopped
+    while accepting.join_next().await.is_some() {}
+    Ok(())
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                client_id = client_id.wrapping_add(1);
-                info!("🔗 Client {} connected from {}", client_id, addr);
-                
-                let id = client_id;
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, id).await {
-                        warn!("Client {} error: {}", id, e);
+/// Where the server accepts clients
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(windows)]
+    Pipe(PipeListener),
+}
+
+/// Who may connect
+struct Admission {
+    /// Token clients must present
+    token: Option<String>,
+    /// Networks TCP clients must come from, any if empty
+    allow_from: Vec<Cidr>,
+    /// Encryption TCP clients must use
+    encryption: Option<Encryption>,
+}
+
+/// How TCP connections are encrypted
+#[derive(Clone)]
+enum Encryption {
+    Tls(tls::Acceptor),
+    Noise(noise::Psk),
+}
+
+impl Admission {
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.allow_from.is_empty() || self.allow_from.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Accept clients on one listener
+async fn accept_clients(listener: Listener, runtime: Arc<Runtime>, sessions: Arc<Sessions>, admission: Arc<Admission>) {
+    match listener {
+        Listener::Tcp(listener) => loop {
+            match listener.accept().await {
+                Ok((_, addr)) if !admission.allows(addr.ip()) => {
+                    warn!("🚫 Rejected client from {}: not in --allow-from", addr);
+                }
+                Ok((stream, addr)) => {
+                    if let Err(e) = keepalive::configure_tcp(&stream) {
+                        warn!("Cannot enable TCP keepalive for {}: {}", addr, e);
                     }
-                    info!("🔌 Client {} disconnected", id);
-                });
+                    match admission.encryption.clone().filter(|_| listen::encrypts(addr.ip())) {
+                        Some(encryption) => {
+                            spawn_encrypted_client(&runtime, &sessions, stream, addr, admission.token.clone(), encryption)
+                        }
+                        None => spawn_client(&runtime, &sessions, stream, addr, admission.token.clone()),
+                    }
+                }
+                Err(e) => error!("Accept error: {}", e),
+            }
+        },
+        #[cfg(windows)]
+        Listener::Pipe(mut listener) => loop {
+            match listener.accept().await {
+                Ok(pipe) => spawn_client(&runtime, &sessions, pipe, listener.path(), admission.token.clone()),
+                Err(e) => error!("Accept error: {}", e),
+            }
+        },
+    }
+}
+
+/// Serve a newly connected client in its own task, once it presented
+/// the token if one is required; a connection resuming a session goes to
+/// the session instead, and a multiplexed one is served per channel
+fn spawn_client<S>(
+    runtime: &Arc<Runtime>,
+    sessions: &Arc<Sessions>,
+    mut stream: S,
+    peer: impl std::fmt::Display,
+    token: Option<String>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let runtime = Arc::clone(runtime);
+    let sessions = Arc::clone(sessions);
+    let peer = peer.to_string();
+
+    tokio::spawn(async move {
+        if let Some(token) = token {
+            if let Err(e) = auth::verify(&mut stream, &token).await {
+                warn!("🚫 Rejected client from {}: {}", peer, e);
+                return;
+            }
+        }
+        let stream = match sessions.accept(stream).await {
+            Ok(Some(stream)) => stream,
+            Ok(None) => {
+                info!("🔁 Session resumed from {}", peer);
+                return;
             }
             Err(e) => {
-                error!("Accept error: {}", e);
+                warn!("🚫 Rejected client from {}: {}", peer, e);
+                return;
             }
+        };
+        match mux::accept(stream).await {
+            Ok(Accepted::Single(stream)) => serve_client(&runtime, stream, &peer).await,
+            // Each channel is a client of its own
+            Ok(Accepted::Multiplexed(mut demux)) => {
+                while let Some((channel, stream)) = demux.next().await {
+                    let runtime = Arc::clone(&runtime);
+                    let peer = format!("{} #{}", peer, channel);
+                    tokio::spawn(async move { serve_client(&runtime, stream, &peer).await });
+                }
+            }
+            Err(e) => warn!("🚫 Rejected client from {}: {}", peer, e),
+        }
+    });
+}
+
+/// Serve a newly connected TCP client like `spawn_client`, once its TLS
+/// or Noise handshake completed
+fn spawn_encrypted_client(
+    runtime: &Arc<Runtime>,
+    sessions: &Arc<Sessions>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    token: Option<String>,
+    encryption: Encryption,
+) {
+    let runtime = Arc::clone(runtime);
+    let sessions = Arc::clone(sessions);
+    tokio::spawn(async move {
+        let accepted = match encryption {
+            Encryption::Tls(tls) => tls.accept(stream).await.map(|stream| spawn_client(&runtime, &sessions, stream, peer, token)),
+            Encryption::Noise(psk) => {
+                noise::accept(stream, &psk).await.map(|stream| spawn_client(&runtime, &sessions, stream, peer, token))
+            }
+        };
+        if let Err(e) = accepted {
+            warn!("🚫 Rejected client from {}: {}", peer, e);
         }
+    });
+}
+
+/// Serve one Wayland client unless the client limit is reached
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(runtime: &Arc<Runtime>, mut stream: S, peer: &str) {
+    let session = match runtime.connect() {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("🚫 Rejected client from {}: {}", peer, e);
+            let error = display_error(1, display_error::NO_MEMORY, "too many clients");
+            let _ = stream.write_all(&WireEncoder::new().encode_batch(&[error])).await;
+            return;
+        }
+    };
+    let id = session.id();
+    info!("🔗 Client {} connected from {}", id, peer);
+
+    if let Err(e) = handle_client(stream, session).await {
+        warn!("Client {} error: {}", id, e);
     }
+    info!("🔌 Client {} disconnected", id);
 }
 
 /// Handle a single Wayland client connection
-async fn handle_client(mut stream: TcpStream, client_id: u32) -> anyhow::Result<()> {
-    let mut compositor = Compositor::new();
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, mut session: ClientSession) -> anyhow::Result<()> {
+    let client_id = session.id();
+    let mut compositor = session.compositor();
+    let mut monitors = session.runtime().monitors();
+    monitors.mark_unchanged();
+    let mut foreign_toplevels = session.runtime().foreign_toplevels();
+    compositor.update_foreign_toplevels(foreign_toplevels.borrow_and_update().clone());
+
+    let mut requests = session.take_requests().expect("requests are taken once, here");
     let mut decoder = WireDecoder::new();
     let encoder = WireEncoder::new();
     let mut buffer = vec![0u8; 65536];
 
     let mut msg_count = 0u64;
 
+    // Reading stops while the client is over its rate limits
+    let mut limiter = RateLimiter::new(session.runtime().config().rate_limits);
+    let mut resume_at: Option<tokio::time::Instant> = None;
+    // Silent clients are pinged, and dropped if the ping goes unanswered
+    let idle_timeout = session.runtime().config().idle_timeout;
+    let mut idle_deadline = idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut pinged = false;
+
+    // Paces frame callbacks at the monitor refresh while no renderer
+    // reports vblanks
+    let mut frame_timer = headless_frame_timer(compositor.frame_interval());
+    let mut idle_timer = tokio::time::interval(IDLE_POLL_INTERVAL);
+    idle_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // Handlers waiting for I/O, run while decoding goes on
+    let mut tasks = JoinSet::new();
+
     loop {
-        let n = stream.read(&mut buffer).await?;
+        for task in compositor.take_tasks() {
+            tasks.spawn(task);
+        }
+
+        // Wait for Wayland traffic or an event from the renderer
+        let input = {
+            let display_change = async {
+                if monitors.changed().await.is_err() {
+                    // No display change notifications on this platform
+                    std::future::pending::<()>().await;
+                }
+            };
+            let headless = !session.has_renderer();
+            tokio::select! {
+                result = stream.read(&mut buffer), if resume_at.is_none() => ClientInput::Wayland(result?),
+                () = sleep_until(resume_at) => ClientInput::Resume,
+                () = sleep_until(idle_deadline) => ClientInput::Silent,
+                (surface, event) = session.next_event() => ClientInput::Renderer(surface, event),
+                () = display_change => ClientInput::DisplayChange,
+                _ = frame_timer.tick(), if headless => ClientInput::FrameTick,
+                _ = idle_timer.tick() => ClientInput::IdleTick,
+                Ok(()) = foreign_toplevels.changed() => ClientInput::ToplevelsChange,
+                Some(result) = tasks.join_next() => ClientInput::TaskDone(result?),
+                Some(request) = requests.recv() => ClientInput::Control(request),
+            }
+        };
+
+        let n = match input {
+            ClientInput::Wayland(n) => n,
+            ClientInput::Resume => {
+                resume_at = None;
+                continue;
+            }
+            ClientInput::Silent => {
+                if pinged {
+                    info!("[{}] No traffic within the idle timeout, disconnecting", client_id);
+                    return Ok(());
+                }
+                let ping = compositor.ping();
+                debug!("[{}] Idle, pinging", client_id);
+                stream.write_all(&encoder.encode_batch(&[ping])).await?;
+                pinged = true;
+                idle_deadline = Some(tokio::time::Instant::now() + PING_TIMEOUT);
+                continue;
+            }
+            ClientInput::Renderer(surface, event) => {
+                debug!("[{}] Renderer event for wl_surface@{}: {:?}", client_id, surface, event);
+                let responses = compositor.handle_renderer_event(surface, &event);
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                // Frames held back or resent for the renderer
+                session.forward(&mut compositor);
+                continue;
+            }
+            ClientInput::FrameTick => {
+                let responses = compositor.frame_done();
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                // Frames held back, kept for a renderer being reconnected
+                session.forward(&mut compositor);
+                continue;
+            }
+            ClientInput::IdleTick => {
+                let responses = compositor.update_idle(idle::idle_time());
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                continue;
+            }
+            ClientInput::ToplevelsChange => {
+                let current = foreign_toplevels.borrow_and_update().clone();
+                let responses = compositor.update_foreign_toplevels(current);
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                continue;
+            }
+            ClientInput::TaskDone(completion) => {
+                let responses = compositor.complete(completion);
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                if compositor.has_failed() {
+                    warn!("[{}] Protocol error sent, disconnecting", client_id);
+                    return Ok(());
+                }
+                session.forward(&mut compositor);
+                continue;
+            }
+            ClientInput::Control(ControlRequest::Screenshot { identifier, reply }) => {
+                let png = match identifier {
+                    Some(identifier) => session.toplevel_for(&identifier).and_then(|id| compositor.screenshot(Some(id))),
+                    None => compositor.screenshot(None),
+                };
+                let _ = reply.send(png);
+                continue;
+            }
+            ClientInput::Control(ControlRequest::StatsOverlay { visible }) => {
+                compositor.set_stats_overlay(visible);
+                session.forward(&mut compositor);
+                continue;
+            }
+            ClientInput::DisplayChange => {
+                let current = monitors.borrow_and_update().clone();
+                let interval = compositor.frame_interval();
+                let responses = compositor.update_outputs(current);
+                if !responses.is_empty() {
+                    stream.write_all(&encoder.encode_batch(&responses)).await?;
+                }
+                // The refresh rate may have changed
+                if compositor.frame_interval() != interval {
+                    frame_timer = headless_frame_timer(compositor.frame_interval());
+                }
+                continue;
+            }
+        };
         if n == 0 {
+            let leaks = compositor.leaked_objects();
+            if !leaks.is_empty() {
+                debug!("[{}] Objects still alive that should have been destroyed:\n{}",
+                       client_id, format_dump(&leaks));
+            }
             return Ok(()); // Connection closed
         }
 
@@ -129,8 +876,10 @@ async fn handle_client(mut stream: TcpStream, client_id: u32) -> anyhow::Result<
         // Decode messages
         decoder.push(&buffer[..n]);
 
+        let mut decoded = 0;
         while let Some(msg) = decoder.decode() {
             msg_count += 1;
+            decoded += 1;
             debug!("[{}] Message #{}: obj={} op={} payload={} bytes",
                    client_id, msg_count, msg.object_id, msg.opcode, msg.payload.len());
 
@@ -144,6 +893,58 @@ async fn handle_client(mut stream: TcpStream, client_id: u32) -> anyhow::Result<
                        client_id, responses.len(), response_data.len());
                 stream.write_all(&response_data).await?;
             }
+            if compositor.has_failed() {
+                warn!("[{}] Protocol error sent, disconnecting", client_id);
+                return Ok(());
+            }
+
+            // Forward window updates to the renderer
+            session.forward(&mut compositor);
         }
+        session.publish_toplevels(&compositor.toplevels());
+
+        if limiter.is_limited() {
+            let wait = limiter.charge(n, decoded, std::time::Instant::now());
+            if !wait.is_zero() {
+                debug!("[{}] Over rate limit, pausing reads for {:?}", client_id, wait);
+                resume_at = Some(tokio::time::Instant::now() + wait);
+            }
+        }
+        // Timeouts run from when reading resumes
+        let heard = resume_at.unwrap_or_else(tokio::time::Instant::now);
+        idle_deadline = idle_timeout.map(|timeout| heard + timeout);
+        pinged = false;
     }
 }
+
+/// Completes at `deadline`, never without one
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Input that woke up a client handler
+enum ClientInput {
+    /// Bytes read from the Wayland client
+    Wayland(usize),
+    /// The client is back within its rate limits
+    Resume,
+    /// The client sent nothing for the idle timeout, or since a ping
+    Silent,
+    /// Event from the renderer window of a toplevel wl_surface
+    Renderer(u32, RendererEvent),
+    /// The Windows monitor configuration changed
+    DisplayChange,
+    /// Time to complete frame callbacks (headless only)
+    FrameTick,
+    /// Time to check whether the user went idle or came back
+    IdleTick,
+    /// A client's toplevels were mapped, renamed or closed
+    ToplevelsChange,
+    /// A task started by a request handler finished
+    TaskDone(Completion),
+    /// A request from the control socket
+    Control(ControlRequest),
+}
@@ -0,0 +1,215 @@
+//! Memory-Mapped Pixel Storage
+//!
+//! A renderer on the same machine does not need frame pixels pushed through
+//! TCP: winpipe can keep the frame in a file both processes map. Writing
+//! the frame is then the only copy, and the renderer reads the pixels in
+//! place when told a new frame is ready.
+//!
+//! The mapping is shared read-write, so the file must not shrink while it
+//! is mapped; `SharedMapping::create` sizes it up front.
+
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, WinpipeError};
+
+/// A file mapped into memory, shared with other processes mapping it
+pub struct SharedMapping {
+    path: PathBuf,
+    map: platform::Map,
+    // Keeps the file open for the lifetime of the mapping
+    _file: File,
+}
+
+impl SharedMapping {
+    /// Create (or truncate) the file at `path` with `len` zero bytes and
+    /// map it
+    pub fn create(path: &Path, len: usize) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(len as u64)?;
+        Self::map(path, file, len)
+    }
+
+    /// Map an existing file, e.g. one created by the other side
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| WinpipeError::Buffer(format!("{} is too large to map", path.display())))?;
+        Self::map(path, file, len)
+    }
+
+    /// Map the first `len` bytes of an already open file, such as a pool
+    /// fd a Wayland client passed; `path` only names it
+    pub fn from_file(path: &Path, file: File, len: usize) -> Result<Self> {
+        let size = file.metadata()?.len();
+        if (size as u128) < len as u128 {
+            return Err(WinpipeError::Buffer(format!("cannot map {} bytes of {}: only {} bytes", len, path.display(), size)));
+        }
+        Self::map(path, file, len)
+    }
+
+    fn map(path: &Path, file: File, len: usize) -> Result<Self> {
+        let map = platform::map(&file, len)
+            .map_err(|e| WinpipeError::Buffer(format!("cannot map {}: {}", path.display(), e)))?;
+        Ok(Self { path: path.to_path_buf(), map, _file: file })
+    }
+
+    /// The mapped file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Start of the mapping, for memory the other process writes while
+    /// this one reads it
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.map.ptr
+    }
+}
+
+impl Deref for SharedMapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the view is `len` bytes and lives as long as `self`
+        unsafe { std::slice::from_raw_parts(self.map.ptr, self.map.len) }
+    }
+}
+
+impl DerefMut for SharedMapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above; `&mut self` makes this the only Rust reference
+        unsafe { std::slice::from_raw_parts_mut(self.map.ptr, self.map.len) }
+    }
+}
+
+impl std::fmt::Debug for SharedMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMapping").field("path", &self.path).field("len", &self.map.len).finish()
+    }
+}
+
+// SAFETY: the mapping is plain memory owned by this value
+unsafe impl Send for SharedMapping {}
+unsafe impl Sync for SharedMapping {}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::ptr::{self, NonNull};
+
+    pub struct Map {
+        pub ptr: *mut u8,
+        pub len: usize,
+    }
+
+    pub fn map(file: &File, len: usize) -> io::Result<Map> {
+        if len == 0 {
+            // mmap refuses empty mappings
+            return Ok(Map { ptr: NonNull::dangling().as_ptr(), len });
+        }
+        // SAFETY: maps `len` bytes of an open file; checked below
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Map { ptr: ptr.cast(), len })
+    }
+
+    impl Drop for Map {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                // SAFETY: unmaps exactly what `map` mapped
+                unsafe { libc::munmap(self.ptr.cast(), self.len) };
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr::{self, NonNull};
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::Memory::{
+        CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS,
+        PAGE_READWRITE,
+    };
+
+    pub struct Map {
+        pub ptr: *mut u8,
+        pub len: usize,
+        handle: HANDLE,
+    }
+
+    pub fn map(file: &File, len: usize) -> io::Result<Map> {
+        if len == 0 {
+            // Windows refuses empty mappings
+            return Ok(Map { ptr: NonNull::dangling().as_ptr(), len, handle: ptr::null_mut() });
+        }
+        let size = len as u64;
+        // SAFETY: plain Win32 calls on an open file; results are checked
+        unsafe {
+            let handle = CreateFileMappingW(
+                file.as_raw_handle() as HANDLE,
+                ptr::null(),
+                PAGE_READWRITE,
+                (size >> 32) as u32,
+                size as u32,
+                ptr::null(),
+            );
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let view = MapViewOfFile(handle, FILE_MAP_ALL_ACCESS, 0, 0, len);
+            if view.Value.is_null() {
+                let error = io::Error::last_os_error();
+                CloseHandle(handle);
+                return Err(error);
+            }
+            Ok(Map { ptr: view.Value.cast(), len, handle })
+        }
+    }
+
+    impl Drop for Map {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                // SAFETY: releases exactly what `map` created
+                unsafe {
+                    UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: self.ptr.cast() });
+                    CloseHandle(self.handle);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping_is_shared() {
+        let path = std::env::temp_dir().join(format!("winpipe-mapping-{}", std::process::id()));
+        let mut writer = SharedMapping::create(&path, 16).unwrap();
+        writer[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        // A second mapping (as the renderer would open) sees the writes
+        let reader = SharedMapping::open(&path).unwrap();
+        assert_eq!(reader.len(), 16);
+        assert_eq!(&reader[..5], &[1, 2, 3, 4, 0]);
+        writer[15] = 9;
+        assert_eq!(reader[15], 9);
+        assert_eq!(reader.path(), path);
+
+        drop((writer, reader));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,531 @@
+//! Client Multiplexing
+//!
+//! `winpipe client` forwards all of its Wayland clients through a single
+//! connection to the server rather than one each. The connection (a
+//! session, see `crate::session`) starts with the magic "WPMX", followed
+//! by frames: channel ID (u32 LE), kind (u8), payload length (u32 LE) and
+//! payload.
+//!
+//! - OPEN: a Wayland client connected to the helper, which picks the
+//!   channel ID; the server serves the channel as a client of its own
+//! - DATA: bytes of the channel's Wayland stream
+//! - CLOSE: the sender's side of the channel ended
+//!
+//! Channels have no flow control of their own: data for a channel is
+//! queued until its client reads it, so one whose client stops reading
+//! holds up nothing but its own memory. Once more than `MAX_INBOX` bytes
+//! wait, the channel is closed on both ends, as a Wayland compositor
+//! disconnects a client that does not read its events. A helper may keep
+//! at most `MAX_CHANNELS` open; further OPENs are answered with CLOSE.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::auth::HANDSHAKE_TIMEOUT;
+use crate::error::{Result, WinpipeError};
+use crate::session::{unread, BoxTransport, Transport};
+
+/// Magic opening a multiplexed connection
+pub const MAGIC: &[u8; 4] = b"WPMX";
+
+/// Bytes buffered per channel and direction
+const CHANNEL_BUFFER: usize = 256 * 1024;
+
+/// Frames waiting to be written to the connection
+const OUTGOING_FRAMES: usize = 64;
+
+/// Channel ID, kind and payload length
+const FRAME_HEADER_SIZE: usize = 9;
+
+/// Largest frame payload accepted
+const MAX_FRAME_PAYLOAD: usize = 1 << 20;
+
+/// Bytes queued for a channel whose client does not read after which the
+/// channel is closed
+const MAX_INBOX: usize = 16 << 20;
+
+/// Channels a helper may have open on one connection
+const MAX_CHANNELS: usize = 256;
+
+/// Frame kinds
+mod kind {
+    pub const OPEN: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const CLOSE: u8 = 2;
+}
+
+/// Identifies a Wayland client on a multiplexed connection
+pub type ChannelId = u32;
+
+/// Data waiting to be written to a channel; dropping it closes the channel
+struct Inbox {
+    data: mpsc::UnboundedSender<Vec<u8>>,
+    /// Bytes queued and not written yet
+    queued: Arc<AtomicUsize>,
+    /// The tasks serving the channel's end
+    tasks: [AbortHandle; 2],
+}
+
+impl Inbox {
+    /// Whether `len` more bytes stay within `MAX_INBOX`
+    fn fits(&self, len: usize) -> bool {
+        self.queued.load(Ordering::Relaxed) + len <= MAX_INBOX
+    }
+
+    /// Queue `data`; false if the channel's writer is gone
+    fn push(&self, data: Vec<u8>) -> bool {
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        self.data.send(data).is_ok()
+    }
+
+    /// Drop the channel's end with whatever is queued, so its client sees
+    /// the connection end
+    fn reset(self) {
+        self.tasks.iter().for_each(AbortHandle::abort);
+    }
+}
+
+fn frame(channel: ChannelId, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&channel.to_le_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    channel: ChannelId,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+/// Splits connection bytes into frames
+#[derive(Debug, Default)]
+struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn decode(&mut self) -> Result<Option<Frame>> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let channel = u32::from_le_bytes(self.buffer[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(self.buffer[5..9].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(WinpipeError::Protocol(format!("channel frame of {} bytes is too large", len)));
+        }
+        if self.buffer.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let kind = self.buffer[4];
+        let payload = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(Frame { channel, kind, payload }))
+    }
+}
+
+/// Client side: Wayland clients forwarded over one connection
+pub struct Mux {
+    next_channel: AtomicU32,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    register: mpsc::UnboundedSender<(ChannelId, Inbox)>,
+}
+
+impl Mux {
+    /// Multiplex clients over `transport`
+    pub async fn connect<T: Transport + 'static>(mut transport: T) -> Result<Self> {
+        transport.write_all(MAGIC).await?;
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_FRAMES);
+        let (register, register_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(Box::new(transport), outgoing.clone(), outgoing_rx, register_rx, None));
+        Ok(Self { next_channel: AtomicU32::new(1), outgoing, register })
+    }
+
+    /// Whether the connection ended, so no more channels can be opened
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    /// Open a channel for a new Wayland client
+    pub async fn open(&self) -> Result<DuplexStream> {
+        let channel = self.next_channel.fetch_add(1, Ordering::Relaxed);
+        let (stream, end) = tokio::io::duplex(CHANNEL_BUFFER);
+        let inbox = open_channel(channel, end, self.outgoing.clone());
+        self.register.send((channel, inbox)).map_err(|_| WinpipeError::ConnectionClosed)?;
+        self.outgoing.send(frame(channel, kind::OPEN, &[])).await.map_err(|_| WinpipeError::ConnectionClosed)?;
+        Ok(stream)
+    }
+}
+
+/// Server side: the channels a multiplexing helper opens
+pub struct Demux {
+    channels: mpsc::UnboundedReceiver<(ChannelId, DuplexStream)>,
+}
+
+impl Demux {
+    /// The next channel opened; None once the connection ended
+    pub async fn next(&mut self) -> Option<(ChannelId, DuplexStream)> {
+        self.channels.recv().await
+    }
+}
+
+/// What a server connection carries
+pub enum Accepted {
+    /// A single Wayland client
+    Single(BoxTransport),
+    /// Channels of a multiplexing helper
+    Multiplexed(Demux),
+}
+
+/// Tell a multiplexed connection from a single Wayland client
+pub async fn accept(mut stream: BoxTransport) -> Result<Accepted> {
+    let mut magic = [0u8; 4];
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut magic))
+        .await
+        .map_err(|_| WinpipeError::Protocol("no data within the handshake timeout".to_string()))??;
+    if &magic != MAGIC {
+        return Ok(Accepted::Single(unread(&magic, stream)));
+    }
+    let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_FRAMES);
+    // Channels are only opened by the helper
+    let (_, register_rx) = mpsc::unbounded_channel();
+    let (channels_tx, channels) = mpsc::unbounded_channel();
+    tokio::spawn(run(stream, outgoing, outgoing_rx, register_rx, Some(channels_tx)));
+    Ok(Accepted::Multiplexed(Demux { channels }))
+}
+
+/// Serve the connection's end of a channel: what is written to it goes
+/// out as frames, and what its inbox receives is written to it
+fn open_channel(channel: ChannelId, end: DuplexStream, outgoing: mpsc::Sender<Vec<u8>>) -> Inbox {
+    let (reader, writer) = tokio::io::split(end);
+    let (data, inbox_rx) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let forwarding = tokio::spawn(forward(channel, reader, outgoing));
+    let delivering = tokio::spawn(deliver(writer, inbox_rx, Arc::clone(&queued)));
+    Inbox { data, queued, tasks: [forwarding.abort_handle(), delivering.abort_handle()] }
+}
+
+/// Write a channel's incoming data to it, then shut it down once the
+/// inbox closes
+async fn deliver(mut writer: WriteHalf<DuplexStream>, mut inbox: mpsc::UnboundedReceiver<Vec<u8>>, queued: Arc<AtomicUsize>) {
+    while let Some(data) = inbox.recv().await {
+        // Its reader is gone; forward() sends CLOSE
+        if writer.write_all(&data).await.is_err() {
+            return;
+        }
+        queued.fetch_sub(data.len(), Ordering::Relaxed);
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Send what is written to a channel as DATA frames, then CLOSE
+async fn forward(channel: ChannelId, mut reader: ReadHalf<DuplexStream>, outgoing: mpsc::Sender<Vec<u8>>) {
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let n = reader.read(&mut buffer).await.unwrap_or(0);
+        let frame = match n {
+            0 => frame(channel, kind::CLOSE, &[]),
+            n => frame(channel, kind::DATA, &buffer[..n]),
+        };
+        if outgoing.send(frame).await.is_err() || n == 0 {
+            return;
+        }
+    }
+}
+
+/// Write queued frames to the connection and deliver the frames it
+/// carries to their channels, until it ends
+///
+/// Writing runs next to reading rather than in turn with it, so a write
+/// waiting for the peer to read never keeps this end from reading what
+/// the peer writes. With `accepted`, channels the peer opens are passed
+/// there.
+async fn run(
+    transport: BoxTransport,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    outgoing_rx: mpsc::Receiver<Vec<u8>>,
+    register: mpsc::UnboundedReceiver<(ChannelId, Inbox)>,
+    accepted: Option<mpsc::UnboundedSender<(ChannelId, DuplexStream)>>,
+) {
+    let (reader, writer) = tokio::io::split(transport);
+    tokio::select! {
+        _ = send(writer, outgoing_rx) => {}
+        _ = receive(reader, outgoing, register, accepted) => {}
+    }
+}
+
+/// Write queued frames to the connection until it fails
+async fn send(mut writer: WriteHalf<BoxTransport>, mut outgoing_rx: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = outgoing_rx.recv().await {
+        if let Err(e) = writer.write_all(&frame).await {
+            debug!("Multiplexed connection failed: {}", e);
+            return;
+        }
+    }
+}
+
+/// Deliver the frames the connection carries to their channels, until it
+/// ends
+async fn receive(
+    mut reader: ReadHalf<BoxTransport>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    mut register: mpsc::UnboundedReceiver<(ChannelId, Inbox)>,
+    accepted: Option<mpsc::UnboundedSender<(ChannelId, DuplexStream)>>,
+) {
+    let mut channels: HashMap<ChannelId, Inbox> = HashMap::new();
+    let mut decoder = FrameDecoder::default();
+    let mut buffer = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            Some((channel, inbox)) = register.recv() => {
+                channels.insert(channel, inbox);
+            }
+            result = reader.read(&mut buffer) => {
+                let n = match result {
+                    Ok(0) => return,
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!("Multiplexed connection failed: {}", e);
+                        return;
+                    }
+                };
+                decoder.push(&buffer[..n]);
+                loop {
+                    let frame = match decoder.decode() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Bad multiplexing frame: {}", e);
+                            return;
+                        }
+                    };
+                    // Channels opened meanwhile may not be picked up yet
+                    while let Ok((channel, inbox)) = register.try_recv() {
+                        channels.insert(channel, inbox);
+                    }
+                    match frame.kind {
+                        kind::OPEN => {
+                            let Some(accepted) = &accepted else {
+                                warn!("Unexpected request to open channel {}", frame.channel);
+                                continue;
+                            };
+                            if channels.contains_key(&frame.channel) {
+                                warn!("Channel {} is open already", frame.channel);
+                                continue;
+                            }
+                            if channels.len() >= MAX_CHANNELS {
+                                warn!("Refusing channel {}: {} are open already", frame.channel, MAX_CHANNELS);
+                                let _ = outgoing.send(self::frame(frame.channel, kind::CLOSE, &[])).await;
+                                continue;
+                            }
+                            let (served, end) = tokio::io::duplex(CHANNEL_BUFFER);
+                            channels.insert(frame.channel, open_channel(frame.channel, end, outgoing.clone()));
+                            let _ = accepted.send((frame.channel, served));
+                        }
+                        kind::DATA => {
+                            // Queued rather than written here, so a channel
+                            // that is not read never blocks the others
+                            let Some(inbox) = channels.get(&frame.channel) else {
+                                continue;
+                            };
+                            if !inbox.fits(frame.payload.len()) {
+                                warn!("Closing channel {}: its client fell more than {} bytes behind", frame.channel, MAX_INBOX);
+                                if let Some(inbox) = channels.remove(&frame.channel) {
+                                    inbox.reset();
+                                }
+                                let _ = outgoing.send(self::frame(frame.channel, kind::CLOSE, &[])).await;
+                            } else if !inbox.push(frame.payload) {
+                                channels.remove(&frame.channel);
+                            }
+                        }
+                        kind::CLOSE => {
+                            channels.remove(&frame.channel);
+                        }
+                        other => {
+                            warn!("Unknown multiplexing frame kind {}", other);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_channels_share_one_connection() {
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let mux = Mux::connect(client_end).await.unwrap();
+        let Ok(Accepted::Multiplexed(mut demux)) = accept(Box::new(server_end)).await else {
+            panic!("not multiplexed");
+        };
+
+        let mut first = mux.open().await.unwrap();
+        let mut second = mux.open().await.unwrap();
+        second.write_all(b"two").await.unwrap();
+        first.write_all(b"one").await.unwrap();
+        let (id1, mut served1) = demux.next().await.unwrap();
+        let (id2, mut served2) = demux.next().await.unwrap();
+        assert_eq!((id1, id2), (1, 2));
+
+        let mut buf = [0u8; 3];
+        served1.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"one");
+        served2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"two");
+        served2.write_all(b"owt").await.unwrap();
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"owt");
+
+        // Closing one channel leaves the other open
+        drop(first);
+        assert_eq!(served1.read(&mut buf).await.unwrap(), 0);
+        served2.write_all(b"still").await.unwrap();
+        let mut buf = [0u8; 5];
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"still");
+        assert!(!mux.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_unread_channel_does_not_block_others() {
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let mux = Mux::connect(client_end).await.unwrap();
+        let Ok(Accepted::Multiplexed(mut demux)) = accept(Box::new(server_end)).await else {
+            panic!("not multiplexed");
+        };
+        let _stalled = mux.open().await.unwrap();
+        let mut second = mux.open().await.unwrap();
+        let (_, mut stalled_served) = demux.next().await.unwrap();
+        let (_, mut served) = demux.next().await.unwrap();
+
+        // More than the channel buffer for a client that never reads
+        let data = vec![7u8; CHANNEL_BUFFER * 2];
+        stalled_served.write_all(&data).await.unwrap();
+        served.write_all(b"ok").await.unwrap();
+        let mut buf = [0u8; 2];
+        tokio::time::timeout(std::time::Duration::from_secs(5), second.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_channel_closed_when_client_falls_behind() {
+        let (client_end, server_end) = tokio::io::duplex(1 << 20);
+        let mux = Mux::connect(client_end).await.unwrap();
+        let Ok(Accepted::Multiplexed(mut demux)) = accept(Box::new(server_end)).await else {
+            panic!("not multiplexed");
+        };
+        let mut stalled = mux.open().await.unwrap();
+        let mut second = mux.open().await.unwrap();
+        let (_, stalled_served) = demux.next().await.unwrap();
+        let (_, mut served) = demux.next().await.unwrap();
+
+        // More than the inbox holds, for a client that does not read
+        let (mut stalled_reader, mut stalled_writer) = tokio::io::split(stalled_served);
+        let data = vec![7u8; MAX_INBOX + CHANNEL_BUFFER * 2];
+        tokio::spawn(async move { stalled_writer.write_all(&data).await });
+        let closed = async {
+            // Both ends of the channel see it close
+            assert_eq!(stalled_reader.read(&mut [0u8; 1]).await.unwrap(), 0);
+            let mut received = Vec::new();
+            stalled.read_to_end(&mut received).await.unwrap();
+            assert!(received.len() <= CHANNEL_BUFFER);
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), closed).await.unwrap();
+
+        served.write_all(b"ok").await.unwrap();
+        let mut buf = [0u8; 2];
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_channel_sends_both_ways_at_once() {
+        // Far less than either side sends
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let mux = Mux::connect(client_end).await.unwrap();
+        let Ok(Accepted::Multiplexed(mut demux)) = accept(Box::new(server_end)).await else {
+            panic!("not multiplexed");
+        };
+        let client = mux.open().await.unwrap();
+        let (_, served) = demux.next().await.unwrap();
+
+        let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let exchange = |stream: DuplexStream| {
+            let data = data.clone();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let sent = data.clone();
+                let writing = tokio::spawn(async move { writer.write_all(&sent).await.unwrap() });
+                let mut received = vec![0u8; data.len()];
+                reader.read_exact(&mut received).await.unwrap();
+                writing.await.unwrap();
+                assert!(received == data);
+            })
+        };
+        let (client, served) = (exchange(client), exchange(served));
+        tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            client.await.unwrap();
+            served.await.unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_channels_limited_per_connection() {
+        let (mut client_end, server_end) = tokio::io::duplex(1 << 16);
+        client_end.write_all(MAGIC).await.unwrap();
+        let Ok(Accepted::Multiplexed(mut demux)) = accept(Box::new(server_end)).await else {
+            panic!("not multiplexed");
+        };
+        for channel in 1..=MAX_CHANNELS as u32 + 1 {
+            client_end.write_all(&frame(channel, kind::OPEN, &[])).await.unwrap();
+        }
+
+        // The channel past the limit is refused
+        let mut refused = vec![0u8; FRAME_HEADER_SIZE];
+        client_end.read_exact(&mut refused).await.unwrap();
+        assert_eq!(refused, frame(MAX_CHANNELS as u32 + 1, kind::CLOSE, &[]));
+        let mut served = Vec::new();
+        for _ in 0..MAX_CHANNELS {
+            served.push(demux.next().await.unwrap());
+        }
+        assert_eq!(served.last().unwrap().0, MAX_CHANNELS as u32);
+
+        // Closing one makes room for another
+        client_end.write_all(&frame(1, kind::CLOSE, &[])).await.unwrap();
+        client_end.write_all(&frame(1000, kind::OPEN, &[])).await.unwrap();
+        assert_eq!(demux.next().await.unwrap().0, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_plain_connection_passed_through() {
+        let (mut client_end, server_end) = tokio::io::duplex(64);
+        client_end.write_all(&[1, 0, 0, 0, 1, 0, 12, 0, 2, 0, 0, 0]).await.unwrap();
+        let Ok(Accepted::Single(mut stream)) = accept(Box::new(server_end)).await else {
+            panic!("multiplexed");
+        };
+        let mut buf = [0u8; 12];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[..4], [1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Noise Connections
+//!
+//! An alternative to TLS (see `crate::tls`) without certificates: server
+//! and clients share one secret key, `--noise-psk` or
+//! `WINPIPE_NOISE_PSK`, 32 bytes written as 64 hex digits (such as
+//! `openssl rand -hex 32`). Every TCP connection but loopback ones (see
+//! `crate::listen`) starts with a Noise_XXpsk3 handshake that only peers
+//! holding the key complete, and everything after it, token included, is
+//! encrypted. The server checks the key with the handshake's last
+//! message and answers with an empty transport message, which the client
+//! waits for, so both sides know the other holds the key before the
+//! connection is used.
+//!
+//! Handshake and transport messages go out as a length (u16 LE) followed
+//! by the message, at most `MAX_MESSAGE_LEN` bytes. Static keys are
+//! generated per connection and identify nobody; the shared key is what
+//! authenticates a peer.
+
+use std::sync::Arc;
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+use crate::auth::HANDSHAKE_TIMEOUT;
+use crate::error::{Result, WinpipeError};
+
+/// Environment variable holding the key when none is given on the
+/// command line
+pub const PSK_ENV: &str = "WINPIPE_NOISE_PSK";
+
+/// Handshake pattern and primitives
+const PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message
+pub const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Authentication tag added to each transport message
+const TAG_LEN: usize = 16;
+
+/// Bytes buffered between the connection and its user
+const STREAM_BUFFER: usize = 256 * 1024;
+
+fn noise_error(e: impl std::fmt::Display) -> WinpipeError {
+    WinpipeError::Noise(e.to_string())
+}
+
+/// The shared key
+#[derive(Clone, PartialEq, Eq)]
+pub struct Psk([u8; 32]);
+
+impl std::fmt::Debug for Psk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+
+impl std::str::FromStr for Psk {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || "expected 64 hex digits (32 bytes)".to_string();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; 32];
+        for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(key))
+    }
+}
+
+/// The key given on the command line, or else in `WINPIPE_NOISE_PSK`
+pub fn psk_from(arg: Option<String>) -> Result<Option<Psk>> {
+    let Some(hex) = arg.or_else(|| std::env::var(PSK_ENV).ok()).filter(|hex| !hex.is_empty()) else {
+        return Ok(None);
+    };
+    hex.parse().map(Some).map_err(|e| noise_error(format!("invalid key: {}", e)))
+}
+
+/// Run the handshake as the server on a newly accepted connection
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, psk: &Psk) -> Result<DuplexStream> {
+    start(stream, psk, false).await
+}
+
+/// Run the handshake as a client on a new connection to the server
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, psk: &Psk) -> Result<DuplexStream> {
+    start(stream, psk, true).await
+}
+
+/// Run the handshake on `stream`, then encrypt what is written to the
+/// returned stream and decrypt what arrives
+async fn start<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut stream: S, psk: &Psk, initiator: bool) -> Result<DuplexStream> {
+    let builder = Builder::new(PARAMS.parse().map_err(noise_error)?);
+    let keypair = builder.generate_keypair().map_err(noise_error)?;
+    let builder = builder.local_private_key(&keypair.private).psk(3, &psk.0);
+    let state = if initiator { builder.build_initiator() } else { builder.build_responder() }.map_err(noise_error)?;
+    let transport = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let transport = handshake(&mut stream, state).await?;
+        confirm(&mut stream, &transport, initiator).await?;
+        Ok::<_, WinpipeError>(transport)
+    })
+    .await
+    .map_err(|_| noise_error("handshake timed out"))??;
+
+    // The confirmation took the server's first nonce
+    let (sent, received) = if initiator { (0, 1) } else { (1, 0) };
+    let transport = Arc::new(transport);
+    let (local, end) = tokio::io::duplex(STREAM_BUFFER);
+    let (reader, writer) = tokio::io::split(stream);
+    let (end_reader, end_writer) = tokio::io::split(end);
+    tokio::spawn(encrypt(Arc::clone(&transport), sent, end_reader, writer));
+    tokio::spawn(decrypt(transport, received, reader, end_writer));
+    Ok(local)
+}
+
+/// Exchange handshake messages until both sides hold the transport keys
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, mut state: HandshakeState) -> Result<StatelessTransportState> {
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut message).map_err(noise_error)?;
+            write_message(stream, &message[..len]).await?;
+        } else {
+            let received = read_message(stream).await?;
+            // A wrong key fails here, at the third message
+            state.read_message(&received, &mut message).map_err(noise_error)?;
+        }
+    }
+    state.into_stateless_transport_mode().map_err(noise_error)
+}
+
+/// Have the server tell the client it accepted the key: the client only
+/// learns that from a message the server encrypted, and a server with
+/// another key drops the connection instead
+async fn confirm<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, transport: &StatelessTransportState, initiator: bool) -> Result<()> {
+    let mut message = vec![0u8; TAG_LEN];
+    if !initiator {
+        let len = transport.write_message(0, &[], &mut message).map_err(noise_error)?;
+        return write_message(stream, &message[..len]).await;
+    }
+    let rejected = || noise_error("the server did not accept the key");
+    let received = read_message(stream).await.map_err(|_| rejected())?;
+    transport.read_message(0, &received, &mut message).map_err(|_| rejected())?;
+    Ok(())
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_le_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let mut message = vec![0u8; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Send what is written locally as transport messages, numbered from
+/// `first`, until either side closes
+async fn encrypt<S: AsyncWrite>(
+    transport: Arc<StatelessTransportState>,
+    first: u64,
+    mut local: ReadHalf<DuplexStream>,
+    mut writer: WriteHalf<S>,
+) {
+    let mut plain = vec![0u8; MAX_MESSAGE_LEN - TAG_LEN];
+    let mut message = vec![0u8; MAX_MESSAGE_LEN];
+    for nonce in first.. {
+        let n = local.read(&mut plain).await.unwrap_or(0);
+        if n == 0 {
+            break;
+        }
+        let Ok(len) = transport.write_message(nonce, &plain[..n], &mut message) else {
+            break;
+        };
+        if write_message(&mut writer, &message[..len]).await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Pass on what arrives, decrypted and numbered from `first`, until
+/// either side closes or a message does not decrypt
+async fn decrypt<S: AsyncRead>(
+    transport: Arc<StatelessTransportState>,
+    first: u64,
+    mut reader: ReadHalf<S>,
+    mut local: WriteHalf<DuplexStream>,
+) {
+    let mut plain = vec![0u8; MAX_MESSAGE_LEN];
+    for nonce in first.. {
+        let Ok(message) = read_message(&mut reader).await else {
+            break;
+        };
+        let Ok(n) = transport.read_message(nonce, &message, &mut plain) else {
+            warn!("Dropping a Noise connection: a message did not decrypt");
+            break;
+        };
+        if local.write_all(&plain[..n]).await.is_err() {
+            break;
+        }
+    }
+    let _ = local.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psk(byte: u8) -> Psk {
+        Psk([byte; 32])
+    }
+
+    #[tokio::test]
+    async fn test_noise_roundtrip() {
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = accept(server_end, &psk(7)).await.unwrap();
+            let mut buf = vec![0u8; 100_000];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream
+        });
+        let mut stream = connect(client_end, &psk(7)).await.unwrap();
+
+        // More than fits one message
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+        let mut echoed = vec![0u8; data.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+
+        // Closing one side ends the other
+        drop(stream);
+        let mut served = server.await.unwrap();
+        assert_eq!(served.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_noise_rejects_other_key() {
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { accept(server_end, &psk(1)).await });
+        // The client learns before it sends anything
+        assert!(matches!(connect(client_end, &psk(2)).await, Err(WinpipeError::Noise(_))));
+        assert!(matches!(server.await.unwrap(), Err(WinpipeError::Noise(_))));
+    }
+
+    #[test]
+    fn test_parse_psk() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let key: Psk = hex.parse().unwrap();
+        assert_eq!(key.0[..3], [0x00, 0x11, 0x22]);
+        assert_eq!(key.0[31], 0xff);
+        assert!("0011".parse::<Psk>().is_err());
+        assert!(hex.replace('0', "g").parse::<Psk>().is_err());
+        assert_eq!(format!("{:?}", key), "Psk(..)");
+    }
+}
@@ -0,0 +1,200 @@
+//! Object Table
+//!
+//! Every live object of a client, with its interface, the object whose
+//! request created it and when it was created. A dump of the table helps
+//! tracking down objects a toolkit forgets to destroy, as well as objects
+//! winpipe itself fails to clean up. Two kinds of objects are flagged:
+//!
+//! - One-shot objects the compositor destroys after their last event
+//!   (wl_callback, wp_presentation_feedback) that are still alive long
+//!   after they were created.
+//! - Objects that must be destroyed before the object that created them
+//!   (xdg_surface, xdg_toplevel, xdg_popup) whose creator is gone.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Age after which a one-shot object is considered leaked
+pub const STALE_AFTER: Duration = Duration::from_secs(10);
+
+/// Interfaces destroyed by the compositor after their last event
+pub const ONE_SHOT: &[&str] = &["wl_callback", "wp_presentation_feedback"];
+
+/// Interfaces that must not outlive the object that created them
+pub const BOUND_TO_PARENT: &[&str] = &["xdg_surface", "xdg_toplevel", "xdg_popup"];
+
+/// A live object
+#[derive(Debug, Clone)]
+pub struct ObjectEntry {
+    pub interface: String,
+    /// Object whose request created this one
+    pub parent: Option<u32>,
+    pub created: Instant,
+    /// Creation order, telling a reused ID from the original object
+    seq: u64,
+}
+
+/// Why an object looks leaked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leak {
+    /// A one-shot object older than `STALE_AFTER`
+    Stale,
+    /// The object that created it was destroyed first
+    Orphaned { parent: u32 },
+}
+
+/// One line of an object dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub id: u32,
+    pub interface: String,
+    pub parent: Option<u32>,
+    pub age: Duration,
+    pub leak: Option<Leak>,
+}
+
+/// Object ID to interface mapping of one client
+#[derive(Debug, Default)]
+pub struct ObjectTable {
+    entries: HashMap<u32, ObjectEntry>,
+    next_seq: u64,
+}
+
+impl ObjectTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track an object created outside of any object (globals, wl_display)
+    pub fn insert(&mut self, id: u32, interface: String) {
+        self.track(id, interface, None);
+    }
+
+    /// Track an object created by a request on `parent`
+    pub fn insert_child(&mut self, id: u32, interface: String, parent: u32) {
+        self.track(id, interface, Some(parent));
+    }
+
+    fn track(&mut self, id: u32, interface: String, parent: Option<u32>) {
+        self.next_seq += 1;
+        self.entries.insert(id, ObjectEntry { interface, parent, created: Instant::now(), seq: self.next_seq });
+    }
+
+    /// Interface of a live object
+    pub fn get(&self, id: &u32) -> Option<&String> {
+        self.entries.get(id).map(|entry| &entry.interface)
+    }
+
+    /// Full entry of a live object
+    pub fn entry(&self, id: u32) -> Option<&ObjectEntry> {
+        self.entries.get(&id)
+    }
+
+    pub fn contains_key(&self, id: &u32) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Stop tracking an object, returning its interface
+    pub fn remove(&mut self, id: &u32) -> Option<String> {
+        self.entries.remove(id).map(|entry| entry.interface)
+    }
+
+    /// Number of live objects
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Live objects and their interfaces, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&u32, &String)> {
+        self.entries.iter().map(|(id, entry)| (id, &entry.interface))
+    }
+
+    /// Why an object looks leaked at `now`, if it does
+    fn leak(&self, entry: &ObjectEntry, now: Instant) -> Option<Leak> {
+        let interface = entry.interface.as_str();
+        if ONE_SHOT.contains(&interface) && now.saturating_duration_since(entry.created) > STALE_AFTER {
+            return Some(Leak::Stale);
+        }
+        let parent = entry.parent?;
+        // An ID reused after the creator was destroyed is not the creator
+        let parent_alive = self.entries.get(&parent).is_some_and(|p| p.seq < entry.seq);
+        if BOUND_TO_PARENT.contains(&interface) && !parent_alive {
+            return Some(Leak::Orphaned { parent });
+        }
+        None
+    }
+
+    /// Every live object at `now`, by ID
+    pub fn snapshot(&self, now: Instant) -> Vec<ObjectInfo> {
+        let mut objects: Vec<ObjectInfo> = self.entries.iter()
+            .map(|(&id, entry)| ObjectInfo {
+                id,
+                interface: entry.interface.clone(),
+                parent: entry.parent,
+                age: now.saturating_duration_since(entry.created),
+                leak: self.leak(entry, now),
+            })
+            .collect();
+        objects.sort_unstable_by_key(|object| object.id);
+        objects
+    }
+
+    /// Objects that look leaked at `now`, by ID
+    pub fn leaks(&self, now: Instant) -> Vec<ObjectInfo> {
+        self.snapshot(now).into_iter().filter(|object| object.leak.is_some()).collect()
+    }
+}
+
+/// Human-readable dump, one object per line, followed by a summary
+pub fn format_dump(objects: &[ObjectInfo]) -> String {
+    let mut out = String::new();
+    for object in objects {
+        let _ = write!(out, "{:>10} {:<40} age={:.1}s", object.id, object.interface, object.age.as_secs_f64());
+        if let Some(parent) = object.parent {
+            let _ = write!(out, " parent={}", parent);
+        }
+        match object.leak {
+            Some(Leak::Stale) => out.push_str(" LEAK: not destroyed after its last event"),
+            Some(Leak::Orphaned { parent }) => {
+                let _ = write!(out, " LEAK: outlived its creator {}", parent);
+            }
+            None => {}
+        }
+        out.push('\n');
+    }
+    let leaks = objects.iter().filter(|object| object.leak.is_some()).count();
+    let _ = writeln!(out, "{} live objects, {} flagged", objects.len(), leaks);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leak_flags() {
+        let mut table = ObjectTable::new();
+        table.insert(3, "xdg_wm_base".to_string());
+        table.insert_child(20, "xdg_surface".to_string(), 3);
+        table.insert_child(21, "xdg_toplevel".to_string(), 20);
+        table.insert_child(30, "wl_callback".to_string(), 10);
+
+        let now = Instant::now();
+        assert!(table.leaks(now).is_empty());
+
+        // The toplevel outlives its xdg_surface, even once the ID is reused
+        table.remove(&20);
+        table.insert(20, "wl_region".to_string());
+        let leaks = table.leaks(now + STALE_AFTER * 2);
+        assert_eq!(leaks.iter().map(|o| (o.id, o.leak)).collect::<Vec<_>>(), vec![
+            (21, Some(Leak::Orphaned { parent: 20 })),
+            (30, Some(Leak::Stale)),
+        ]);
+        assert!(format_dump(&leaks).ends_with("2 live objects, 2 flagged\n"));
+    }
+}
@@ -0,0 +1,505 @@
+//! Output (Monitor) Discovery
+//!
+//! Each Windows monitor is advertised to clients as its own wl_output,
+//! with the real position, resolution, refresh rate and physical size.
+//! On other platforms (and if enumeration fails) a single virtual
+//! 1920x1080 display is used instead.
+//!
+//! Scale comes from the per-monitor DPI (96 DPI = 1x). It is advertised
+//! rounded up as wl_output.scale and exactly, in 120ths, through
+//! wp_fractional_scale_v1.
+//!
+//! Monitors can come and go while the server runs; `watch_monitors`
+//! re-enumerates on WM_DISPLAYCHANGE so compositors can add or withdraw
+//! wl_output globals. Turning HDR on or off also changes the display mode
+//! and is picked up the same way.
+//!
+//! Rotated displays report their rotation as wl_output.transform, with the
+//! mode in the panel's native (unrotated) orientation as Wayland expects.
+//! Windows rotates the desktop itself, so window contents stay upright.
+
+use tokio::sync::watch;
+
+use crate::color::MonitorColor;
+use crate::wire::{ArgWriter, Message};
+
+/// wl_output event opcodes
+pub mod opcodes {
+    pub const GEOMETRY: u16 = 0;
+    pub const MODE: u16 = 1;
+    pub const DONE: u16 = 2;
+    pub const SCALE: u16 = 3;
+    pub const NAME: u16 = 4;
+    pub const DESCRIPTION: u16 = 5;
+}
+
+/// wl_output.mode flags
+pub mod mode_flags {
+    pub const CURRENT: u32 = 0x1;
+    pub const PREFERRED: u32 = 0x2;
+}
+
+/// wl_output.transform
+///
+/// Rotations are counter-clockwise; the flipped variants mirror around the
+/// vertical axis first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum Transform {
+    #[default]
+    Normal = 0,
+    Rotate90 = 1,
+    Rotate180 = 2,
+    Rotate270 = 3,
+    Flipped = 4,
+    Flipped90 = 5,
+    Flipped180 = 6,
+    Flipped270 = 7,
+}
+
+impl Transform {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Rotate90),
+            2 => Some(Self::Rotate180),
+            3 => Some(Self::Rotate270),
+            4 => Some(Self::Flipped),
+            5 => Some(Self::Flipped90),
+            6 => Some(Self::Flipped180),
+            7 => Some(Self::Flipped270),
+            _ => None,
+        }
+    }
+
+    /// Whether width and height trade places
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Self::Rotate90 | Self::Rotate270 | Self::Flipped90 | Self::Flipped270)
+    }
+
+    /// Size after applying the transform to `size`
+    pub fn apply_size(self, (width, height): (u32, u32)) -> (u32, u32) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+}
+
+/// A physical (or virtual) monitor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Monitor {
+    /// Connector-style name, e.g. "DISPLAY1"
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    /// Position in the global (virtual desktop) coordinate space
+    pub x: i32,
+    pub y: i32,
+    /// Resolution in pixels, as the desktop is laid out (after rotation)
+    pub width: i32,
+    pub height: i32,
+    /// Physical size in millimetres (0 if unknown)
+    pub physical_width: i32,
+    pub physical_height: i32,
+    /// Refresh rate in mHz
+    pub refresh: i32,
+    /// Scale factor in 120ths (120 = 1x, 180 = 1.5x)
+    pub scale_120: u32,
+    /// Whether this is the Windows primary monitor
+    pub primary: bool,
+    /// HDR state and color profile
+    pub color: MonitorColor,
+    /// Rotation of the display
+    pub transform: Transform,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            name: "WINPIPE-1".to_string(),
+            make: "Winpipe".to_string(),
+            model: "Virtual Display".to_string(),
+            x: 0,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            physical_width: 0,
+            physical_height: 0,
+            refresh: 60000,
+            scale_120: 120,
+            primary: true,
+            color: MonitorColor::default(),
+            transform: Transform::Normal,
+        }
+    }
+}
+
+impl Monitor {
+    /// Scale factor in 120ths for a DPI value (96 DPI = 1x)
+    pub fn scale_120_from_dpi(dpi: u32) -> u32 {
+        (dpi * 120 / 96).max(120)
+    }
+
+    /// Exact scale factor
+    pub fn fractional_scale(&self) -> f64 {
+        self.scale_120 as f64 / 120.0
+    }
+
+    /// wl_output.scale: the fractional scale rounded up
+    pub fn integer_scale(&self) -> i32 {
+        self.scale_120.div_ceil(120) as i32
+    }
+
+    /// Size in logical (surface-local) coordinates
+    pub fn logical_size(&self) -> (i32, i32) {
+        let scale = self.fractional_scale();
+        ((self.width as f64 / scale).round() as i32, (self.height as f64 / scale).round() as i32)
+    }
+
+    /// Resolution of the panel in its native orientation
+    pub fn native_size(&self) -> (i32, i32) {
+        if self.transform.swaps_axes() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    /// Events describing this monitor to a freshly bound wl_output
+    ///
+    /// `version` is the version the client bound; name and description
+    /// only exist from version 4, scale and done from version 2.
+    pub fn output_events(&self, output_id: u32, version: u32) -> Vec<Message> {
+        let mut events = Vec::new();
+
+        // geometry: x, y, physical_width, physical_height, subpixel, make, model, transform
+        let geometry = ArgWriter::new()
+            .i32(self.x)
+            .i32(self.y)
+            .i32(self.physical_width)
+            .i32(self.physical_height)
+            .i32(0) // subpixel: unknown
+            .string(&self.make)
+            .string(&self.model)
+            .i32(self.transform as i32)
+            .finish();
+        events.push(Message::new(output_id, opcodes::GEOMETRY, geometry));
+
+        // mode: flags, width, height, refresh
+        let (width, height) = self.native_size();
+        let mode = ArgWriter::new()
+            .u32(mode_flags::CURRENT | mode_flags::PREFERRED)
+            .i32(width)
+            .i32(height)
+            .i32(self.refresh)
+            .finish();
+        events.push(Message::new(output_id, opcodes::MODE, mode));
+
+        if version >= 2 {
+            events.push(Message::new(output_id, opcodes::SCALE, self.integer_scale().to_le_bytes().to_vec()));
+        }
+        if version >= 4 {
+            events.push(Message::new(output_id, opcodes::NAME, ArgWriter::new().string(&self.name).finish()));
+            let description = format!("{} {} ({}x{})", self.make, self.model, self.width, self.height);
+            events.push(Message::new(output_id, opcodes::DESCRIPTION, ArgWriter::new().string(&description).finish()));
+        }
+        if version >= 2 {
+            events.push(Message::new(output_id, opcodes::DONE, vec![]));
+        }
+
+        events
+    }
+}
+
+/// Enumerate the monitors to advertise, primary first
+///
+/// Always returns at least one monitor.
+pub fn enumerate_monitors() -> Vec<Monitor> {
+    let mut monitors = platform::enumerate();
+    if monitors.is_empty() {
+        monitors.push(Monitor::default());
+    }
+    // Clients tend to treat the first output as the main one
+    monitors.sort_by_key(|m| !m.primary);
+    monitors
+}
+
+/// Watch the monitor configuration
+///
+/// The receiver starts with the current monitors and is updated whenever
+/// Windows reports a display change. On other platforms it never changes
+/// (the sender is dropped, so `changed()` returns an error).
+pub fn watch_monitors() -> watch::Receiver<Vec<Monitor>> {
+    let (sender, receiver) = watch::channel(enumerate_monitors());
+    platform::watch(sender);
+    receiver
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{Monitor, Transform};
+
+    use std::cell::RefCell;
+
+    use tokio::sync::watch;
+    use windows_sys::Win32::Foundation::{BOOL, HWND, LPARAM, LRESULT, RECT, TRUE, WPARAM};
+    use windows_sys::Win32::Graphics::Gdi::{
+        CreateDCW, DeleteDC, EnumDisplayMonitors, EnumDisplaySettingsW, GetDeviceCaps,
+        GetMonitorInfoW, DEVMODEW, DMDO_180, DMDO_270, DMDO_90, ENUM_CURRENT_SETTINGS, HDC, HMONITOR,
+        HORZSIZE, MONITORINFO, MONITORINFOEXW, VERTSIZE,
+    };
+    use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows_sys::Win32::UI::HiDpi::{
+        GetDpiForMonitor, SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+        MDT_EFFECTIVE_DPI,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+        TranslateMessage, MSG, WM_DISPLAYCHANGE, WNDCLASSW,
+    };
+
+    /// MONITORINFOF_PRIMARY
+    const PRIMARY_FLAG: u32 = 1;
+
+    thread_local! {
+        /// Sender used by the display-change window procedure
+        static WATCHER: RefCell<Option<watch::Sender<Vec<Monitor>>>> = const { RefCell::new(None) };
+    }
+
+    /// Run a hidden window on its own thread to receive WM_DISPLAYCHANGE
+    ///
+    /// The window is a (never shown) top-level window because message-only
+    /// windows do not receive broadcast messages.
+    pub fn watch(sender: watch::Sender<Vec<Monitor>>) {
+        let spawned = std::thread::Builder::new()
+            .name("winpipe-display-watch".to_string())
+            .spawn(move || {
+                WATCHER.with(|w| *w.borrow_mut() = Some(sender));
+
+                let class_name: Vec<u16> = "WinpipeDisplayWatch\0".encode_utf16().collect();
+                // SAFETY: plain Win32 window creation and message loop on this thread
+                unsafe {
+                    let instance = GetModuleHandleW(std::ptr::null());
+                    let mut class: WNDCLASSW = std::mem::zeroed();
+                    class.lpfnWndProc = Some(window_proc);
+                    class.hInstance = instance;
+                    class.lpszClassName = class_name.as_ptr();
+                    RegisterClassW(&class);
+
+                    let window = CreateWindowExW(
+                        0,
+                        class_name.as_ptr(),
+                        class_name.as_ptr(),
+                        0,
+                        0, 0, 0, 0,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        instance,
+                        std::ptr::null(),
+                    );
+                    if window.is_null() {
+                        log::warn!("Display change watcher unavailable");
+                        return;
+                    }
+
+                    let mut msg: MSG = std::mem::zeroed();
+                    while GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) > 0 {
+                        TranslateMessage(&msg);
+                        DispatchMessageW(&msg);
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            log::warn!("Failed to start display change watcher: {}", e);
+        }
+    }
+
+    unsafe extern "system" fn window_proc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+        if message == WM_DISPLAYCHANGE {
+            let monitors = super::enumerate_monitors();
+            log::info!("Display configuration changed: {} monitor(s)", monitors.len());
+            WATCHER.with(|w| {
+                if let Some(sender) = w.borrow().as_ref() {
+                    sender.send_if_modified(|current| {
+                        let changed = *current != monitors;
+                        *current = monitors;
+                        changed
+                    });
+                }
+            });
+            return 0;
+        }
+        DefWindowProcW(window, message, wparam, lparam)
+    }
+
+    pub fn enumerate() -> Vec<Monitor> {
+        // Without per-monitor awareness Windows reports DPI-virtualized
+        // geometry and 96 DPI everywhere
+        static DPI_AWARE: std::sync::Once = std::sync::Once::new();
+        // SAFETY: no pointers involved; failure (already set) is harmless
+        DPI_AWARE.call_once(|| unsafe {
+            SetProcessDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2);
+        });
+
+        let mut handles: Vec<HMONITOR> = Vec::new();
+
+        unsafe extern "system" fn collect(monitor: HMONITOR, _dc: HDC, _rect: *mut RECT, data: LPARAM) -> BOOL {
+            let handles = &mut *(data as *mut Vec<HMONITOR>);
+            handles.push(monitor);
+            TRUE
+        }
+
+        // SAFETY: the callback only runs during this call, while `handles` is alive
+        unsafe {
+            EnumDisplayMonitors(
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                Some(collect),
+                &mut handles as *mut Vec<HMONITOR> as LPARAM,
+            );
+        }
+
+        handles.into_iter().filter_map(describe).collect()
+    }
+
+    fn describe(handle: HMONITOR) -> Option<Monitor> {
+        // SAFETY: all structs are zero-initialised with their size set as the APIs require
+        unsafe {
+            let mut info: MONITORINFOEXW = std::mem::zeroed();
+            info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+            if GetMonitorInfoW(handle, &mut info as *mut MONITORINFOEXW as *mut MONITORINFO) == 0 {
+                return None;
+            }
+
+            let rect = info.monitorInfo.rcMonitor;
+            let device = info.szDevice;
+            let name = String::from_utf16_lossy(&device[..device.iter().position(|&c| c == 0).unwrap_or(device.len())]);
+
+            let mut mode: DEVMODEW = std::mem::zeroed();
+            mode.dmSize = std::mem::size_of::<DEVMODEW>() as u16;
+            let current = EnumDisplaySettingsW(device.as_ptr(), ENUM_CURRENT_SETTINGS, &mut mode) != 0;
+            let refresh = if current && mode.dmDisplayFrequency > 1 {
+                mode.dmDisplayFrequency as i32 * 1000
+            } else {
+                60000
+            };
+            // The DMDO_* orientations line up with the wl_output rotations
+            let orientation = if current { mode.Anonymous1.Anonymous2.dmDisplayOrientation } else { 0 };
+            let transform = match orientation {
+                DMDO_90 => Transform::Rotate90,
+                DMDO_180 => Transform::Rotate180,
+                DMDO_270 => Transform::Rotate270,
+                _ => Transform::Normal,
+            };
+
+            let (physical_width, physical_height) = {
+                let dc = CreateDCW(device.as_ptr(), device.as_ptr(), std::ptr::null(), std::ptr::null());
+                if dc.is_null() {
+                    (0, 0)
+                } else {
+                    let size = (GetDeviceCaps(dc, HORZSIZE as i32), GetDeviceCaps(dc, VERTSIZE as i32));
+                    DeleteDC(dc);
+                    size
+                }
+            };
+
+            let (mut dpi_x, mut dpi_y) = (96, 96);
+            if GetDpiForMonitor(handle, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) < 0 {
+                dpi_x = 96;
+            }
+
+            Some(Monitor {
+                // "\\.\DISPLAY1" -> "DISPLAY1"
+                name: name.trim_start_matches(['\\', '.']).to_string(),
+                make: "Windows".to_string(),
+                model: name.clone(),
+                x: rect.left,
+                y: rect.top,
+                width: rect.right - rect.left,
+                height: rect.bottom - rect.top,
+                physical_width,
+                physical_height,
+                refresh,
+                scale_120: Monitor::scale_120_from_dpi(dpi_x),
+                primary: info.monitorInfo.dwFlags & PRIMARY_FLAG != 0,
+                color: crate::color::monitor_color(&name),
+                transform,
+            })
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::Monitor;
+
+    use tokio::sync::watch;
+
+    pub fn enumerate() -> Vec<Monitor> {
+        Vec::new()
+    }
+
+    /// No display change notifications without Win32
+    pub fn watch(_sender: watch::Sender<Vec<Monitor>>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_enumerate_has_primary_first() {
+        let monitors = enumerate_monitors();
+        assert!(!monitors.is_empty());
+        assert!(monitors[0].primary || monitors.iter().all(|m| !m.primary));
+    }
+
+    #[test]
+    fn test_output_events_by_version() {
+        let monitor = Monitor { x: 1920, width: 2560, height: 1440, ..Default::default() };
+
+        let v1 = monitor.output_events(7, 1);
+        assert_eq!(v1.iter().map(|m| m.opcode).collect::<Vec<_>>(), vec![opcodes::GEOMETRY, opcodes::MODE]);
+
+        let v4 = monitor.output_events(7, 4);
+        assert_eq!(v4.last().unwrap().opcode, opcodes::DONE);
+        assert!(v4.iter().any(|m| m.opcode == opcodes::NAME));
+
+        let mut geometry = ArgReader::new(&v4[0].payload);
+        assert_eq!(geometry.i32().unwrap(), 1920);
+        let mut mode = ArgReader::new(&v4[1].payload);
+        mode.u32().unwrap();
+        assert_eq!((mode.i32().unwrap(), mode.i32().unwrap()), (2560, 1440));
+    }
+
+    #[test]
+    fn test_rotated_monitor_reports_native_mode() {
+        let monitor = Monitor { width: 1080, height: 1920, transform: Transform::Rotate90, ..Default::default() };
+        let events = monitor.output_events(7, 2);
+
+        let mut geometry = ArgReader::new(&events[0].payload);
+        (0..5).for_each(|_| { geometry.i32().unwrap(); });
+        geometry.string().unwrap();
+        geometry.string().unwrap();
+        assert_eq!(geometry.i32().unwrap(), Transform::Rotate90 as i32);
+        let mut mode = ArgReader::new(&events[1].payload);
+        mode.u32().unwrap();
+        assert_eq!((mode.i32().unwrap(), mode.i32().unwrap()), (1920, 1080));
+        assert_eq!(monitor.logical_size(), (1080, 1920));
+    }
+
+    #[test]
+    fn test_scale_from_dpi() {
+        let monitor = Monitor { width: 2880, height: 1620, scale_120: Monitor::scale_120_from_dpi(144), ..Default::default() };
+        assert_eq!(monitor.scale_120, 180);
+        assert_eq!(monitor.integer_scale(), 2);
+        assert_eq!(monitor.logical_size(), (1920, 1080));
+
+        let events = monitor.output_events(7, 2);
+        let scale = events.iter().find(|m| m.opcode == opcodes::SCALE).unwrap();
+        assert_eq!(scale.payload, 2i32.to_le_bytes());
+    }
+}
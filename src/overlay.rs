@@ -0,0 +1,84 @@
+//! On-Screen Stats Overlay
+//!
+//! With the overlay on, the compositor counts the frames it sends and
+//! their size, and about once a second hands the renderer a `StatsOverlay`
+//! message to draw over the window: frame rate, bandwidth, how much delta
+//! encoding saved, and presentation latency. It is toggled at runtime
+//! through the control socket (`stats on|off`).
+
+use std::time::{Duration, Instant};
+
+use crate::render::StatsOverlay;
+
+/// How often the overlay is updated
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames sent since the last sample
+#[derive(Debug, Clone)]
+pub struct OverlayStats {
+    since: Instant,
+    frames: u32,
+    /// Bytes of the frames in full
+    raw_bytes: u64,
+    /// Bytes sent for them, as frames or deltas
+    sent_bytes: u64,
+}
+
+impl OverlayStats {
+    pub fn new(now: Instant) -> Self {
+        Self { since: now, frames: 0, raw_bytes: 0, sent_bytes: 0 }
+    }
+
+    /// Count a frame of `raw` bytes that went out as `sent` bytes
+    pub fn record(&mut self, raw: usize, sent: usize) {
+        self.frames += 1;
+        self.raw_bytes += raw as u64;
+        self.sent_bytes += sent as u64;
+    }
+
+    /// The overlay for the frames since the last sample, once
+    /// `SAMPLE_INTERVAL` has passed; starts the next sample
+    pub fn sample(&mut self, now: Instant, latency: Option<Duration>) -> Option<StatsOverlay> {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < SAMPLE_INTERVAL {
+            return None;
+        }
+        let secs = elapsed.as_secs_f64();
+        let stats = StatsOverlay {
+            visible: true,
+            frame_rate: (self.frames as f64 * 100.0 / secs).round() as u32,
+            bandwidth: (self.sent_bytes as f64 / secs).round() as u64,
+            compression: match self.sent_bytes {
+                0 => 0,
+                sent => (self.raw_bytes * 100 / sent).min(u32::MAX as u64) as u32,
+            },
+            latency_us: latency.map_or(0, |latency| latency.as_micros().clamp(1, u32::MAX as u128) as u32),
+        };
+        *self = Self::new(now);
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_once_per_interval() {
+        let start = Instant::now();
+        let mut stats = OverlayStats::new(start);
+        for _ in 0..60 {
+            stats.record(4000, 1000);
+        }
+        assert!(stats.sample(start + Duration::from_millis(500), None).is_none());
+
+        let sample = stats.sample(start + Duration::from_secs(2), Some(Duration::from_micros(8300))).unwrap();
+        assert_eq!(
+            sample,
+            StatsOverlay { visible: true, frame_rate: 3000, bandwidth: 30_000, compression: 400, latency_us: 8300 }
+        );
+        // The next sample starts empty
+        let sample = stats.sample(start + Duration::from_secs(3), None).unwrap();
+        assert_eq!((sample.frame_rate, sample.compression, sample.latency_us), (0, 0, 0));
+    }
+}
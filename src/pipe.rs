@@ -0,0 +1,236 @@
+//! Virtual Pipe Channel
+//!
+//! Requests like `wl_data_offer.receive` pass a pipe file descriptor that
+//! the compositor writes into. File descriptors cannot cross TCP, so the
+//! WSL-side helper keeps the real pipe and replaces it with a virtual one:
+//!
+//! 1. Before forwarding a request that carries a pipe fd, the helper sends
+//!    `PIPE_OPEN(id)` on the control object. Virtual fds are queued in
+//!    order, just like fds in SCM_RIGHTS ancillary data.
+//! 2. The compositor pops the next virtual fd when it handles the request.
+//! 3. Content is streamed back as `PIPE_DATA(id, chunk)` messages followed
+//!    by `PIPE_CLOSE(id)`, which the helper writes into the real pipe.
+//!
+//! Events that carry an fd to the client (such as the dmabuf format table)
+//! work the other way round: the compositor first sends
+//! `FD_CONTENT(data)`, and the helper puts the data in a sealed memfd that
+//! it attaches to the next forwarded event carrying an fd.
+//!
+//! Contents the compositor produces for a client's wl_shm buffer (screen
+//! captures) are sent as `BUFFER_CONTENT(buffer, offset, chunk)`, which the
+//! helper writes into the buffer's shared memory before forwarding the
+//! events that follow. The helper uses the same message the other way
+//! round to upload what a client drew into a wl_shm buffer before
+//! forwarding the commit that shows it.
+//!
+//! `PING` is the compositor's round trip for clients without xdg_wm_base:
+//! the helper sends it straight back, and a relay without a helper never
+//! answers, so the idle timeout can drop it.
+//!
+//! The control channel uses object ID 0, which is never a valid Wayland
+//! object, so it can share the stream with regular protocol traffic.
+
+use std::collections::VecDeque;
+
+use crate::error::{Result, WinpipeError};
+use crate::wire::{ArgReader, ArgWriter, Message, HEADER_SIZE, MAX_MESSAGE_SIZE};
+
+/// Object ID reserved for the winpipe control channel
+pub const CONTROL_OBJECT_ID: u32 = 0;
+
+/// Interface name used for the control object in the object table
+pub const CONTROL_INTERFACE: &str = "winpipe_control";
+
+/// Largest chunk of pipe data carried by a single message
+/// (header + pipe id + array length must fit in a wire message)
+pub const PIPE_CHUNK_SIZE: usize = 32768;
+
+/// Control channel opcodes
+pub mod opcodes {
+    /// Helper -> compositor: a virtual pipe fd accompanies the next request
+    pub const PIPE_OPEN: u16 = 0;
+    /// Compositor -> helper: data to write into the pipe
+    pub const PIPE_DATA: u16 = 1;
+    /// Compositor -> helper: close the write end of the pipe
+    pub const PIPE_CLOSE: u16 = 2;
+    /// Compositor -> helper: memfd contents for the next event with an fd
+    pub const FD_CONTENT: u16 = 3;
+    /// Helper -> compositor: a syncobj acquire point signaled and the
+    /// buffer contents were copied (see `crate::syncobj`)
+    pub const SYNC_SIGNALED: u16 = 4;
+    /// Compositor -> helper: signal a syncobj release point
+    pub const SYNC_RELEASE: u16 = 5;
+    /// Helper -> compositor: write a dump of the live objects into a
+    /// virtual pipe (see `crate::objects`)
+    pub const DUMP_OBJECTS: u16 = 6;
+    /// Helper -> compositor: write the toplevels and their surface trees
+    /// into a virtual pipe (see `crate::introspect`)
+    pub const DUMP_SURFACES: u16 = 7;
+    /// Both ways: bytes to write into a wl_buffer's memory
+    pub const BUFFER_CONTENT: u16 = 8;
+    /// Helper -> compositor: write per-buffer delta statistics into a
+    /// virtual pipe (see `crate::buffer::DeltaStats`)
+    pub const DUMP_BUFFER_STATS: u16 = 9;
+    /// Helper -> compositor: write a PNG of a toplevel's current frame
+    /// into a virtual pipe (0 = the focused toplevel; see `crate::png`)
+    pub const SCREENSHOT: u16 = 10;
+    /// Compositor -> helper, which sends it back: a round trip showing
+    /// the connection is alive
+    pub const PING: u16 = 11;
+}
+
+/// Pipe events delivered to the WSL-side helper
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipeEvent {
+    Data { id: u32, data: Vec<u8> },
+    Close { id: u32 },
+    FdContent { data: Vec<u8> },
+    SyncRelease { timeline: u32, point: u64 },
+    BufferContent { buffer: u32, offset: u32, data: Vec<u8> },
+    Ping,
+}
+
+impl PipeEvent {
+    /// Parse a control message sent by the compositor
+    pub fn from_message(msg: &Message) -> Result<Self> {
+        if msg.object_id != CONTROL_OBJECT_ID {
+            return Err(WinpipeError::Protocol(
+                format!("Not a control message: object {}", msg.object_id)
+            ));
+        }
+
+        let mut args = ArgReader::new(&msg.payload);
+        match msg.opcode {
+            opcodes::PIPE_DATA => Ok(Self::Data { id: args.u32()?, data: args.array()? }),
+            opcodes::PIPE_CLOSE => Ok(Self::Close { id: args.u32()? }),
+            opcodes::FD_CONTENT => Ok(Self::FdContent { data: args.array()? }),
+            opcodes::SYNC_RELEASE => {
+                let timeline = args.u32()?;
+                let point = ((args.u32()? as u64) << 32) | args.u32()? as u64;
+                Ok(Self::SyncRelease { timeline, point })
+            }
+            opcodes::BUFFER_CONTENT => Ok(Self::BufferContent { buffer: args.u32()?, offset: args.u32()?, data: args.array()? }),
+            opcodes::PING => Ok(Self::Ping),
+            op => Err(WinpipeError::Protocol(format!("Unknown pipe event opcode {}", op))),
+        }
+    }
+}
+
+/// Build the control message announcing a virtual pipe fd
+pub fn open_message(id: u32) -> Message {
+    Message::new(CONTROL_OBJECT_ID, opcodes::PIPE_OPEN, id.to_le_bytes().to_vec())
+}
+
+/// Build a ping, sent by the compositor and returned by the helper
+pub fn ping_message() -> Message {
+    Message::new(CONTROL_OBJECT_ID, opcodes::PING, Vec::new())
+}
+
+/// Build the messages that stream `data` into a virtual pipe and close it
+pub fn write_messages(id: u32, data: &[u8]) -> Vec<Message> {
+    let mut messages: Vec<Message> = data
+        .chunks(PIPE_CHUNK_SIZE)
+        .map(|chunk| {
+            let payload = ArgWriter::new().u32(id).array(chunk).finish();
+            Message::new(CONTROL_OBJECT_ID, opcodes::PIPE_DATA, payload)
+        })
+        .collect();
+    messages.push(Message::new(CONTROL_OBJECT_ID, opcodes::PIPE_CLOSE, id.to_le_bytes().to_vec()));
+    messages
+}
+
+/// Build the control message providing the fd of the next event
+///
+/// `data` must fit in a single message (at most `PIPE_CHUNK_SIZE` bytes).
+pub fn fd_content_message(data: &[u8]) -> Message {
+    debug_assert!(data.len() <= PIPE_CHUNK_SIZE);
+    Message::new(CONTROL_OBJECT_ID, opcodes::FD_CONTENT, ArgWriter::new().array(data).finish())
+}
+
+/// Build the messages that write `data` into a wl_buffer from its start
+pub fn buffer_content_messages(buffer: u32, data: &[u8]) -> Vec<Message> {
+    buffer_content_messages_at(buffer, 0, data)
+}
+
+/// Build the messages that write `data` into a wl_buffer at `offset`
+pub fn buffer_content_messages_at(buffer: u32, offset: u32, data: &[u8]) -> Vec<Message> {
+    data.chunks(PIPE_CHUNK_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let offset = offset + (i * PIPE_CHUNK_SIZE) as u32;
+            let payload = ArgWriter::new().u32(buffer).u32(offset).array(chunk).finish();
+            Message::new(CONTROL_OBJECT_ID, opcodes::BUFFER_CONTENT, payload)
+        })
+        .collect()
+}
+
+/// Queue of virtual fds announced by the helper but not yet consumed
+#[derive(Debug, Default)]
+pub struct VirtualFdQueue {
+    pending: VecDeque<u32>,
+}
+
+impl VirtualFdQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a virtual fd announced with `PIPE_OPEN`
+    pub fn push(&mut self, id: u32) {
+        self.pending.push_back(id);
+    }
+
+    /// Take the next virtual fd for a request that carries one
+    pub fn pop(&mut self) -> Option<u32> {
+        self.pending.pop_front()
+    }
+
+    /// Number of virtual fds waiting to be consumed
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// A full chunk must still fit in one wire message
+const _: () = assert!(HEADER_SIZE + 12 + PIPE_CHUNK_SIZE <= MAX_MESSAGE_SIZE);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_messages_chunking() {
+        let data = vec![0xAB; PIPE_CHUNK_SIZE + 10];
+        let messages = write_messages(3, &data);
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            PipeEvent::from_message(&messages[1]).unwrap(),
+            PipeEvent::Data { id: 3, data: vec![0xAB; 10] }
+        );
+        assert_eq!(PipeEvent::from_message(&messages[2]).unwrap(), PipeEvent::Close { id: 3 });
+
+        // Empty content still closes the pipe
+        assert_eq!(write_messages(4, &[]).len(), 1);
+
+        let messages = buffer_content_messages(100, &data);
+        assert_eq!(
+            PipeEvent::from_message(&messages[1]).unwrap(),
+            PipeEvent::BufferContent { buffer: 100, offset: PIPE_CHUNK_SIZE as u32, data: vec![0xAB; 10] }
+        );
+    }
+
+    #[test]
+    fn test_virtual_fd_queue_order() {
+        let mut queue = VirtualFdQueue::new();
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert!(queue.is_empty());
+    }
+}
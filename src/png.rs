@@ -0,0 +1,128 @@
+//! PNG Encoding of Frames
+//!
+//! Screenshots of a window are written as PNG so any image viewer or test
+//! harness can read them. Frames are small enough and screenshots rare
+//! enough that the image data goes into stored (uncompressed) deflate
+//! blocks, which needs no compression library.
+//!
+//! Frames hold premultiplied BGRA (wl_shm ARGB8888 in memory order); the
+//! PNG gets straight RGBA.
+
+/// PNG file signature
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Largest stored deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// Encode a frame of premultiplied BGRA pixels as an RGBA PNG
+///
+/// `data` holds `width * height` tightly packed pixels.
+pub fn encode(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+    let row = width as usize * 4;
+    // Each scanline starts with its filter type, 0 = none
+    let mut raw = Vec::with_capacity((row + 1) * height as usize);
+    for line in data.chunks_exact(row.max(1)).take(height as usize) {
+        raw.push(0);
+        for pixel in line.chunks_exact(4) {
+            raw.extend_from_slice(&unpremultiply([pixel[2], pixel[1], pixel[0], pixel[3]]));
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, color type 6 (RGBA), deflate, no filter variant,
+    // not interlaced
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn unpremultiply([r, g, b, a]: [u8; 4]) -> [u8; 4] {
+    if a == 0 || a == 255 {
+        return [r, g, b, a];
+    }
+    let straight = |c: u8| ((c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8;
+    [straight(r), straight(g), straight(b), a]
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // Deflate with a 32 KiB window, no preset dictionary
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_layout() {
+        // One opaque blue pixel and one half-transparent premultiplied red
+        let png = encode(2, 1, &[255, 0, 0, 255, 0, 0, 128, 128]);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // IDAT holds the scanline uncompressed after the zlib and block headers
+        let idat = 8 + 25;
+        assert_eq!(&png[idat + 4..idat + 8], b"IDAT");
+        let scanline = &png[idat + 8 + 2 + 5..][..9];
+        assert_eq!(scanline, [0, 0, 0, 255, 255, 255, 0, 0, 128]);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,83 @@
+//! Pointer Input
+//!
+//! The renderer reports mouse motion, buttons and scrolling over its window
+//! as render protocol events. They go to every wl_pointer as
+//! enter/leave, motion, button and axis events on the focused window,
+//! each group closed by a frame event (dropped for pointers older than
+//! version 5). Positions arrive in window pixels and are sent in surface
+//! units.
+
+use crate::wire::{ArgWriter, Message};
+
+/// wl_pointer event opcodes
+pub mod events {
+    pub const ENTER: u16 = 0;
+    pub const LEAVE: u16 = 1;
+    pub const MOTION: u16 = 2;
+    pub const BUTTON: u16 = 3;
+    pub const AXIS: u16 = 4;
+    pub const FRAME: u16 = 5;
+    pub const AXIS_VALUE120: u16 = 9;
+}
+
+/// wl_pointer.button_state values
+pub mod button_state {
+    pub const RELEASED: u32 = 0;
+    pub const PRESSED: u32 = 1;
+}
+
+/// enter(serial, surface, x, y)
+pub fn enter(id: u32, serial: u32, surface: u32, x: f64, y: f64) -> Message {
+    let payload = ArgWriter::new().u32(serial).u32(surface).fixed(x).fixed(y).finish();
+    Message::new(id, events::ENTER, payload)
+}
+
+/// leave(serial, surface)
+pub fn leave(id: u32, serial: u32, surface: u32) -> Message {
+    Message::new(id, events::LEAVE, ArgWriter::new().u32(serial).u32(surface).finish())
+}
+
+/// motion(time, x, y)
+pub fn motion(id: u32, time: u32, x: f64, y: f64) -> Message {
+    Message::new(id, events::MOTION, ArgWriter::new().u32(time).fixed(x).fixed(y).finish())
+}
+
+/// button(serial, time, button, state)
+pub fn button(id: u32, serial: u32, time: u32, button: u32, pressed: bool) -> Message {
+    let state = if pressed { button_state::PRESSED } else { button_state::RELEASED };
+    let payload = ArgWriter::new().u32(serial).u32(time).u32(button).u32(state).finish();
+    Message::new(id, events::BUTTON, payload)
+}
+
+/// axis_value120(axis, value120) followed by axis(time, axis, value) for
+/// wheel scrolling; the first is dropped for pointers older than version 8
+pub fn axis(id: u32, time: u32, axis: u32, value: f64, value120: i32) -> Vec<Message> {
+    let mut messages = Vec::new();
+    if value120 != 0 {
+        messages.push(Message::new(id, events::AXIS_VALUE120, ArgWriter::new().u32(axis).i32(value120).finish()));
+    }
+    messages.push(Message::new(id, events::AXIS, ArgWriter::new().u32(time).u32(axis).fixed(value).finish()));
+    messages
+}
+
+/// frame()
+pub fn frame(id: u32) -> Message {
+    Message::new(id, events::FRAME, vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_wheel_axis_carries_value120() {
+        let messages = axis(5, 100, 0, 15.0, 120);
+        assert_eq!(messages.iter().map(|m| m.opcode).collect::<Vec<_>>(), [events::AXIS_VALUE120, events::AXIS]);
+        let mut args = ArgReader::new(&messages[1].payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap(), args.fixed().unwrap()), (100, 0, 15.0));
+
+        // Touchpads scroll without steps
+        assert_eq!(axis(5, 100, 1, 2.5, 0).len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+//! Pointer Constraints and Relative Pointer
+//!
+//! Games and 3D apps lock the pointer in place (zwp_locked_pointer_v1) or
+//! confine it to a region (zwp_confined_pointer_v1) and read raw motion
+//! through zwp_relative_pointer_v1. A constraint only takes effect while
+//! its surface has focus; when the Windows window loses focus it is
+//! released, and a oneshot constraint is then dead for good while a
+//! persistent one is re-activated when focus returns.
+
+use crate::region::Region;
+use crate::wire::{ArgWriter, Message};
+
+/// zwp_pointer_constraints_v1.lifetime values
+pub mod lifetime {
+    pub const ONESHOT: u32 = 1;
+    pub const PERSISTENT: u32 = 2;
+}
+
+/// Event opcodes shared by zwp_locked_pointer_v1 (locked/unlocked) and
+/// zwp_confined_pointer_v1 (confined/unconfined)
+pub mod events {
+    pub const ACTIVATED: u16 = 0;
+    pub const DEACTIVATED: u16 = 1;
+}
+
+/// zwp_relative_pointer_v1.relative_motion event opcode
+pub const RELATIVE_MOTION: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintKind {
+    Lock,
+    Confine,
+}
+
+/// A zwp_locked_pointer_v1 or zwp_confined_pointer_v1 object
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    pub kind: ConstraintKind,
+    pub surface: u32,
+    /// Constraint region in surface coordinates (`None` = whole surface)
+    pub region: Option<Region>,
+    pub pending_region: Option<Option<Region>>,
+    /// zwp_locked_pointer_v1.set_cursor_position_hint, surface coordinates
+    pub position_hint: Option<(f64, f64)>,
+    pub pending_position_hint: Option<(f64, f64)>,
+    pub persistent: bool,
+    pub active: bool,
+    /// A oneshot constraint that was deactivated; it never activates again
+    pub defunct: bool,
+}
+
+impl Constraint {
+    pub fn new(kind: ConstraintKind, surface: u32, region: Option<Region>, lifetime: u32) -> Self {
+        Self {
+            kind,
+            surface,
+            region,
+            pending_region: None,
+            position_hint: None,
+            pending_position_hint: None,
+            persistent: lifetime == lifetime::PERSISTENT,
+            active: false,
+            defunct: false,
+        }
+    }
+
+    /// Activate if possible, returning the locked/confined event
+    pub fn activate(&mut self, id: u32) -> Option<Message> {
+        if self.active || self.defunct {
+            return None;
+        }
+        self.active = true;
+        Some(Message::new(id, events::ACTIVATED, vec![]))
+    }
+
+    /// Deactivate if active, returning the unlocked/unconfined event
+    pub fn deactivate(&mut self, id: u32) -> Option<Message> {
+        if !self.active {
+            return None;
+        }
+        self.active = false;
+        self.defunct = !self.persistent;
+        Some(Message::new(id, events::DEACTIVATED, vec![]))
+    }
+
+    /// Apply set_region and set_cursor_position_hint requests on the next
+    /// surface commit; true if either was pending
+    pub fn commit(&mut self) -> bool {
+        let hint = self.pending_position_hint.take();
+        if hint.is_some() {
+            self.position_hint = hint;
+        }
+        match self.pending_region.take() {
+            Some(region) => {
+                self.region = region;
+                true
+            }
+            None => hint.is_some(),
+        }
+    }
+}
+
+/// zwp_relative_pointer_v1.relative_motion
+pub fn relative_motion(id: u32, time_usec: u64, dx: f64, dy: f64, dx_unaccel: f64, dy_unaccel: f64) -> Message {
+    let payload = ArgWriter::new()
+        .u32((time_usec >> 32) as u32)
+        .u32(time_usec as u32)
+        .fixed(dx)
+        .fixed(dy)
+        .fixed(dx_unaccel)
+        .fixed(dy_unaccel)
+        .finish();
+    Message::new(id, RELATIVE_MOTION, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_oneshot_is_defunct_after_deactivation() {
+        let mut oneshot = Constraint::new(ConstraintKind::Lock, 10, None, lifetime::ONESHOT);
+        assert!(oneshot.activate(5).is_some());
+        assert!(oneshot.activate(5).is_none());
+        assert!(oneshot.deactivate(5).is_some());
+        assert!(oneshot.activate(5).is_none());
+
+        let mut persistent = Constraint::new(ConstraintKind::Confine, 10, None, lifetime::PERSISTENT);
+        persistent.activate(6);
+        persistent.deactivate(6);
+        assert_eq!(persistent.activate(6), Some(Message::new(6, events::ACTIVATED, vec![])));
+    }
+
+    #[test]
+    fn test_relative_motion_encoding() {
+        let msg = relative_motion(3, (1 << 32) | 7, 1.5, -2.0, 1.0, -1.0);
+        let mut args = ArgReader::new(&msg.payload);
+        assert_eq!((args.u32().unwrap(), args.u32().unwrap()), (1, 7));
+        assert_eq!((args.fixed().unwrap(), args.fixed().unwrap()), (1.5, -2.0));
+    }
+}
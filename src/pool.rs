@@ -0,0 +1,175 @@
+//! Shared Memory Pools
+//!
+//! Clients allocate a wl_shm_pool once and carve their buffers out of it,
+//! typically two or three at different offsets for double or triple
+//! buffering. The mirrored pool is shared the same way: each buffer is a
+//! window onto the pool's memory instead of a copy of its own, so writes
+//! through one buffer show in any other overlapping it.
+//!
+//! wl_shm_pool.resize only grows a pool. The pool then moves to a larger
+//! block and `BufferManager` points its buffers at it; a buffer no longer
+//! tracked there keeps the old block alive.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// A block of pool memory, addressed only through raw pointers so
+/// windows onto different parts never alias
+struct Block {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the block is plain memory. Buffers reach it only through their
+// `BufferManager`, whose borrows keep writes exclusive.
+unsafe impl Send for Block {}
+unsafe impl Sync for Block {}
+
+impl Block {
+    fn new(data: Box<[u8]>) -> Arc<Self> {
+        let len = data.len();
+        Arc::new(Self { ptr: Box::into_raw(data).cast(), len })
+    }
+
+    /// SAFETY: `offset + len` is within the block, and the bytes are not
+    /// written while the slice is alive
+    unsafe fn bytes(&self, offset: usize, len: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.add(offset), len) }
+    }
+
+    /// SAFETY: `offset + len` is within the block, and nothing else
+    /// accesses the bytes while the slice is alive
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn bytes_mut(&self, offset: usize, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.add(offset), len) }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        // SAFETY: reclaims the allocation `new` leaked
+        drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.ptr, self.len)) });
+    }
+}
+
+/// Mirror of a wl_shm_pool
+pub struct ShmPool {
+    block: Arc<Block>,
+    size: usize,
+}
+
+impl ShmPool {
+    pub fn new(size: usize) -> Self {
+        Self { block: Block::new(vec![0; size].into_boxed_slice()), size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Grow to `size` bytes, keeping the contents; false if that would
+    /// shrink the pool
+    ///
+    /// Windows taken before must be moved over with `PoolSlice::rebase`.
+    pub fn grow(&mut self, size: usize) -> bool {
+        if size < self.size {
+            return false;
+        }
+        let mut data = vec![0; size];
+        // SAFETY: `&mut self` rules out writes through the windows the
+        // caller tracks
+        data[..self.size].copy_from_slice(unsafe { self.block.bytes(0, self.size) });
+        self.block = Block::new(data.into_boxed_slice());
+        self.size = size;
+        true
+    }
+
+    /// Identifies the block for counting memory once per pool
+    pub(crate) fn block_id(&self) -> usize {
+        Arc::as_ptr(&self.block) as usize
+    }
+
+    /// A window of `len` bytes at `offset`, None if it does not fit
+    pub fn slice(&self, offset: usize, len: usize) -> Option<PoolSlice> {
+        (offset.checked_add(len)? <= self.size).then(|| PoolSlice { block: Arc::clone(&self.block), offset, len })
+    }
+}
+
+/// A buffer's window onto pool memory
+pub struct PoolSlice {
+    block: Arc<Block>,
+    offset: usize,
+    len: usize,
+}
+
+impl PoolSlice {
+    /// Point the window at the pool's current block
+    pub fn rebase(&mut self, pool: &ShmPool) {
+        self.block = Arc::clone(&pool.block);
+    }
+
+    /// Whether the window lies in the pool's memory
+    pub fn is_in(&self, pool: &ShmPool) -> bool {
+        Arc::ptr_eq(&self.block, &pool.block)
+    }
+
+    /// Identifies the block for counting memory once per pool
+    pub(crate) fn block_id(&self) -> usize {
+        Arc::as_ptr(&self.block) as usize
+    }
+
+    /// Size of the whole block
+    pub(crate) fn block_size(&self) -> usize {
+        self.block.len
+    }
+}
+
+impl Deref for PoolSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `slice` checked the range; see `Block` for aliasing
+        unsafe { self.block.bytes(self.offset, self.len) }
+    }
+}
+
+impl DerefMut for PoolSlice {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above; `&mut self` of the buffer owning the window,
+        // reached through its `BufferManager`, makes this the only access
+        unsafe { self.block.bytes_mut(self.offset, self.len) }
+    }
+}
+
+impl std::fmt::Debug for PoolSlice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoolSlice").field("offset", &self.offset).field("len", &self.len).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_share_pool_memory() {
+        let mut pool = ShmPool::new(16);
+        let mut front = pool.slice(0, 8).unwrap();
+        let mut back = pool.slice(8, 8).unwrap();
+        assert!(pool.slice(12, 8).is_none());
+
+        front[7] = 1;
+        back[0] = 2;
+        assert_eq!(&pool.slice(6, 4).unwrap()[..], &[0, 1, 2, 0]);
+
+        // Growing keeps the contents once the windows follow
+        assert!(!pool.grow(8));
+        assert!(pool.grow(32));
+        assert!(!front.is_in(&pool));
+        front.rebase(&pool);
+        back.rebase(&pool);
+        back[1] = 3;
+        assert_eq!(&pool.slice(6, 4).unwrap()[..], &[0, 1, 2, 3]);
+        assert_eq!(pool.slice(16, 16).unwrap().len(), 16);
+    }
+}
@@ -0,0 +1,298 @@
+//! xdg_positioner Placement Math
+//!
+//! Popups (menus, tooltips, combo boxes) are placed relative to their
+//! parent surface using an xdg_positioner: an anchor rectangle, an anchor
+//! edge, a gravity direction and an offset. When the result would leave
+//! the output, the constraint adjustments (flip, slide, resize) are tried
+//! in the order the xdg-shell protocol specifies.
+
+/// A rectangle in surface-local coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    /// Whether `other` lies entirely inside this rectangle on the x axis
+    fn contains_x(&self, other: &Rect) -> bool {
+        other.x >= self.x && other.x + other.width <= self.x + self.width
+    }
+
+    /// Whether `other` lies entirely inside this rectangle on the y axis
+    fn contains_y(&self, other: &Rect) -> bool {
+        other.y >= self.y && other.y + other.height <= self.y + self.height
+    }
+}
+
+/// xdg_positioner.anchor and xdg_positioner.gravity values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum Edge {
+    #[default]
+    None = 0,
+    Top = 1,
+    Bottom = 2,
+    Left = 3,
+    Right = 4,
+    TopLeft = 5,
+    BottomLeft = 6,
+    TopRight = 7,
+    BottomRight = 8,
+}
+
+impl Edge {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            0 => Self::None,
+            1 => Self::Top,
+            2 => Self::Bottom,
+            3 => Self::Left,
+            4 => Self::Right,
+            5 => Self::TopLeft,
+            6 => Self::BottomLeft,
+            7 => Self::TopRight,
+            8 => Self::BottomRight,
+            _ => return None,
+        })
+    }
+
+    fn has_top(self) -> bool {
+        matches!(self, Self::Top | Self::TopLeft | Self::TopRight)
+    }
+
+    fn has_bottom(self) -> bool {
+        matches!(self, Self::Bottom | Self::BottomLeft | Self::BottomRight)
+    }
+
+    fn has_left(self) -> bool {
+        matches!(self, Self::Left | Self::TopLeft | Self::BottomLeft)
+    }
+
+    fn has_right(self) -> bool {
+        matches!(self, Self::Right | Self::TopRight | Self::BottomRight)
+    }
+
+    /// Mirror left <-> right
+    fn flip_x(self) -> Self {
+        match self {
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+            Self::TopLeft => Self::TopRight,
+            Self::TopRight => Self::TopLeft,
+            Self::BottomLeft => Self::BottomRight,
+            Self::BottomRight => Self::BottomLeft,
+            other => other,
+        }
+    }
+
+    /// Mirror top <-> bottom
+    fn flip_y(self) -> Self {
+        match self {
+            Self::Top => Self::Bottom,
+            Self::Bottom => Self::Top,
+            Self::TopLeft => Self::BottomLeft,
+            Self::BottomLeft => Self::TopLeft,
+            Self::TopRight => Self::BottomRight,
+            Self::BottomRight => Self::TopRight,
+            other => other,
+        }
+    }
+}
+
+/// xdg_positioner.constraint_adjustment bits
+pub mod constraint {
+    pub const NONE: u32 = 0;
+    pub const SLIDE_X: u32 = 1;
+    pub const SLIDE_Y: u32 = 2;
+    pub const FLIP_X: u32 = 4;
+    pub const FLIP_Y: u32 = 8;
+    pub const RESIZE_X: u32 = 16;
+    pub const RESIZE_Y: u32 = 32;
+}
+
+/// Accumulated xdg_positioner state
+#[derive(Debug, Clone, Default)]
+pub struct Positioner {
+    pub width: i32,
+    pub height: i32,
+    pub anchor_rect: Rect,
+    pub anchor: Edge,
+    pub gravity: Edge,
+    pub constraint_adjustment: u32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub reactive: bool,
+    pub parent_size: Option<(i32, i32)>,
+    pub parent_configure: Option<u32>,
+}
+
+impl Positioner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compute the popup geometry relative to the parent surface
+    ///
+    /// `bounds` is the area the popup must stay within (usually the output),
+    /// also in parent-relative coordinates.
+    pub fn get_geometry(&self, bounds: Rect) -> Rect {
+        let mut anchor = self.anchor;
+        let mut gravity = self.gravity;
+        let mut offset = (self.offset_x, self.offset_y);
+        let mut geometry = self.place(anchor, gravity, offset);
+
+        // X axis: flip, then slide, then resize. Flipping mirrors the
+        // offset along with the anchor and gravity.
+        if !bounds.contains_x(&geometry) && self.constraint_adjustment & constraint::FLIP_X != 0 {
+            let flipped = self.place(anchor.flip_x(), gravity.flip_x(), (-offset.0, offset.1));
+            if bounds.contains_x(&flipped) {
+                anchor = anchor.flip_x();
+                gravity = gravity.flip_x();
+                offset.0 = -offset.0;
+                geometry.x = flipped.x;
+            }
+        }
+        if !bounds.contains_x(&geometry) && self.constraint_adjustment & constraint::SLIDE_X != 0 {
+            let overflow_right = geometry.x + geometry.width - (bounds.x + bounds.width);
+            if overflow_right > 0 {
+                geometry.x -= overflow_right;
+            }
+            // The left edge wins when the popup is wider than the bounds
+            if geometry.x < bounds.x {
+                geometry.x = bounds.x;
+            }
+        }
+        if !bounds.contains_x(&geometry) && self.constraint_adjustment & constraint::RESIZE_X != 0 {
+            let left = geometry.x.max(bounds.x);
+            let right = (geometry.x + geometry.width).min(bounds.x + bounds.width);
+            if right > left {
+                geometry.x = left;
+                geometry.width = right - left;
+            }
+        }
+
+        // Y axis: flip, then slide, then resize
+        if !bounds.contains_y(&geometry) && self.constraint_adjustment & constraint::FLIP_Y != 0 {
+            let flipped = self.place(anchor.flip_y(), gravity.flip_y(), (offset.0, -offset.1));
+            if bounds.contains_y(&flipped) {
+                geometry.y = flipped.y;
+            }
+        }
+        if !bounds.contains_y(&geometry) && self.constraint_adjustment & constraint::SLIDE_Y != 0 {
+            let overflow_bottom = geometry.y + geometry.height - (bounds.y + bounds.height);
+            if overflow_bottom > 0 {
+                geometry.y -= overflow_bottom;
+            }
+            if geometry.y < bounds.y {
+                geometry.y = bounds.y;
+            }
+        }
+        if !bounds.contains_y(&geometry) && self.constraint_adjustment & constraint::RESIZE_Y != 0 {
+            let top = geometry.y.max(bounds.y);
+            let bottom = (geometry.y + geometry.height).min(bounds.y + bounds.height);
+            if bottom > top {
+                geometry.y = top;
+                geometry.height = bottom - top;
+            }
+        }
+
+        geometry
+    }
+
+    /// Unconstrained placement for a given anchor, gravity and offset
+    fn place(&self, anchor: Edge, gravity: Edge, offset: (i32, i32)) -> Rect {
+        let rect = self.anchor_rect;
+
+        let anchor_x = if anchor.has_left() {
+            rect.x
+        } else if anchor.has_right() {
+            rect.x + rect.width
+        } else {
+            rect.x + rect.width / 2
+        };
+        let anchor_y = if anchor.has_top() {
+            rect.y
+        } else if anchor.has_bottom() {
+            rect.y + rect.height
+        } else {
+            rect.y + rect.height / 2
+        };
+
+        // Gravity says which direction the popup extends from the anchor point
+        let x = if gravity.has_left() {
+            anchor_x - self.width
+        } else if gravity.has_right() {
+            anchor_x
+        } else {
+            anchor_x - self.width / 2
+        };
+        let y = if gravity.has_top() {
+            anchor_y - self.height
+        } else if gravity.has_bottom() {
+            anchor_y
+        } else {
+            anchor_y - self.height / 2
+        };
+
+        Rect::new(x + offset.0, y + offset.1, self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn menu_positioner() -> Positioner {
+        Positioner {
+            width: 200,
+            height: 300,
+            anchor_rect: Rect::new(100, 10, 50, 20),
+            anchor: Edge::BottomLeft,
+            gravity: Edge::BottomRight,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unconstrained_placement() {
+        let geometry = menu_positioner().get_geometry(Rect::new(0, 0, 1920, 1080));
+        assert_eq!(geometry, Rect::new(100, 30, 200, 300));
+    }
+
+    #[test]
+    fn test_flip_and_slide() {
+        let bounds = Rect::new(0, 0, 1920, 1080);
+
+        // Near the bottom edge: flipping puts the menu above the anchor
+        let mut positioner = menu_positioner();
+        positioner.anchor_rect = Rect::new(100, 900, 50, 20);
+        positioner.constraint_adjustment = constraint::FLIP_Y;
+        assert_eq!(positioner.get_geometry(bounds), Rect::new(100, 600, 200, 300));
+
+        // Near the right edge: sliding keeps it on screen
+        positioner.anchor_rect = Rect::new(1800, 10, 50, 20);
+        positioner.constraint_adjustment = constraint::SLIDE_X;
+        assert_eq!(positioner.get_geometry(bounds), Rect::new(1720, 30, 200, 300));
+
+        // A flipped offset points away from the anchor on the other side
+        positioner.anchor_rect = Rect::new(100, 900, 50, 20);
+        positioner.offset_y = 4;
+        positioner.constraint_adjustment = constraint::FLIP_Y;
+        assert_eq!(positioner.get_geometry(bounds), Rect::new(100, 596, 200, 300));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut positioner = menu_positioner();
+        positioner.constraint_adjustment = constraint::RESIZE_Y;
+        let geometry = positioner.get_geometry(Rect::new(0, 0, 1920, 200));
+        assert_eq!(geometry, Rect::new(100, 30, 200, 170));
+    }
+}
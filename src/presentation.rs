@@ -0,0 +1,78 @@
+//! Presentation Time (wp_presentation)
+//!
+//! The renderer reports each vblank at which a new frame reached the
+//! screen, read from DWM composition timing. Feedback objects committed
+//! with a surface are answered with that timestamp, the refresh period and
+//! the vblank counter, so clients can schedule frames against the real
+//! display instead of timers. Feedback for content that never reached the
+//! screen (the surface was destroyed first) is discarded.
+//!
+//! Timestamps are taken from the renderer's monotonic clock (QPC on
+//! Windows) and advertised as CLOCK_MONOTONIC.
+
+use crate::wire::{ArgWriter, Message};
+
+/// wp_presentation.clock_id event opcode
+pub const CLOCK_ID: u16 = 0;
+
+/// CLOCK_MONOTONIC clock id from <time.h>
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// wp_presentation_feedback event opcodes
+pub mod events {
+    pub const SYNC_OUTPUT: u16 = 0;
+    pub const PRESENTED: u16 = 1;
+    pub const DISCARDED: u16 = 2;
+}
+
+/// wp_presentation_feedback.kind flags
+pub mod kind {
+    pub const VSYNC: u32 = 0x1;
+    pub const HW_CLOCK: u32 = 0x2;
+    pub const HW_COMPLETION: u32 = 0x4;
+    pub const ZERO_COPY: u32 = 0x8;
+}
+
+/// clock_id(clk_id), sent when wp_presentation is bound
+pub fn clock_id(id: u32) -> Message {
+    Message::new(id, CLOCK_ID, CLOCK_MONOTONIC.to_le_bytes().to_vec())
+}
+
+/// sync_output(output)
+pub fn sync_output(id: u32, output: u32) -> Message {
+    Message::new(id, events::SYNC_OUTPUT, output.to_le_bytes().to_vec())
+}
+
+/// presented(tv_sec_hi, tv_sec_lo, tv_nsec, refresh, seq_hi, seq_lo, flags)
+pub fn presented(id: u32, time_ns: u64, refresh_ns: u32, seq: u64, flags: u32) -> Message {
+    let secs = time_ns / 1_000_000_000;
+    let payload = ArgWriter::new()
+        .u32((secs >> 32) as u32)
+        .u32(secs as u32)
+        .u32((time_ns % 1_000_000_000) as u32)
+        .u32(refresh_ns)
+        .u32((seq >> 32) as u32)
+        .u32(seq as u32)
+        .u32(flags)
+        .finish();
+    Message::new(id, events::PRESENTED, payload)
+}
+
+/// discarded()
+pub fn discarded(id: u32) -> Message {
+    Message::new(id, events::DISCARDED, vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_presented_splits_timestamp() {
+        let msg = presented(5, (1 << 32) * 1_000_000_000 + 7 * 1_000_000_000 + 250, 16_666_666, (3 << 32) | 9, kind::VSYNC);
+        let mut args = ArgReader::new(&msg.payload);
+        let fields: Vec<u32> = (0..7).map(|_| args.u32().unwrap()).collect();
+        assert_eq!(fields, vec![1, 7, 250, 16_666_666, 3, 9, kind::VSYNC]);
+    }
+}
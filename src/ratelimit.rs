@@ -0,0 +1,98 @@
+//! Per-Client Rate Limits
+//!
+//! Every client is served by its own task, but they share the CPU, the
+//! renderer connection and the network. With limits configured, a client
+//! that sends more bytes or messages per second than allowed is throttled
+//! by not reading its connection until it is back within budget; TCP
+//! flow control then slows the client down without affecting others.
+//!
+//! Each limit is a token bucket holding one second's worth of traffic, so
+//! short bursts pass unthrottled. A read that overdraws the bucket is
+//! still handled whole; the debt decides how long reading pauses.
+
+use std::time::{Duration, Instant};
+
+/// Limits applied to each client; None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub bytes_per_sec: Option<u64>,
+    pub messages_per_sec: Option<u64>,
+}
+
+/// A token bucket that may go into debt
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64, updated: now }
+    }
+
+    /// Take `amount` tokens; returns how long until the bucket is out of
+    /// debt again
+    fn take(&mut self, amount: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - amount as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Rate limiter of one client
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes: Option<Bucket>,
+    messages: Option<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: Option<u64>| rate.filter(|&rate| rate > 0).map(|rate| Bucket::new(rate, now));
+        Self { bytes: bucket(limits.bytes_per_sec), messages: bucket(limits.messages_per_sec) }
+    }
+
+    /// Whether any limit is configured
+    pub fn is_limited(&self) -> bool {
+        self.bytes.is_some() || self.messages.is_some()
+    }
+
+    /// Account for traffic that was read; returns how long to stop
+    /// reading, zero if the client is within its limits
+    pub fn charge(&mut self, bytes: usize, messages: usize, now: Instant) -> Duration {
+        let bytes = self.bytes.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(bytes, now));
+        let messages = self.messages.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(messages, now));
+        bytes.max(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttles_after_burst() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimits { bytes_per_sec: Some(1000), messages_per_sec: Some(10) });
+
+        // A second's worth passes, then the debt sets the pause
+        assert_eq!(limiter.charge(1000, 5, start), Duration::ZERO);
+        assert_eq!(limiter.charge(500, 1, start), Duration::from_millis(500));
+        // Message limits apply on their own
+        assert_eq!(limiter.charge(0, 9, start), Duration::from_millis(500));
+
+        // Time refills the buckets, up to one second's worth
+        assert_eq!(limiter.charge(0, 0, start + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(limiter.charge(1001, 0, start + Duration::from_secs(10)), Duration::from_millis(1));
+
+        assert!(!RateLimiter::new(RateLimits::default()).is_limited());
+    }
+}
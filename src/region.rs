@@ -0,0 +1,191 @@
+//! wl_region Geometry
+//!
+//! A region is built from a sequence of add and subtract operations on
+//! rectangles. It is stored as a list of non-overlapping rectangles so
+//! point tests and forwarding to the renderer stay simple.
+
+use crate::positioner::Rect;
+
+/// Rectangles kept per region before it is collapsed to its bounding box
+const MAX_REGION_RECTS: usize = 256;
+
+/// A set of non-overlapping rectangles
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Region {
+    rects: Vec<Rect>,
+}
+
+impl Region {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Region covering a single rectangle
+    pub fn from_rect(rect: Rect) -> Self {
+        let mut region = Self::new();
+        region.add(rect);
+        region
+    }
+
+    /// wl_region.add
+    pub fn add(&mut self, rect: Rect) {
+        let Some(rect) = clip(rect) else {
+            return;
+        };
+
+        // Only keep the parts of the new rectangle not already covered
+        let mut fresh = vec![rect];
+        for existing in &self.rects {
+            fresh = fresh.into_iter().flat_map(|r| subtract_rect(r, existing)).collect();
+        }
+        self.rects.extend(fresh);
+        self.limit();
+    }
+
+    /// wl_region.subtract
+    pub fn subtract(&mut self, rect: Rect) {
+        let Some(rect) = clip(rect) else {
+            return;
+        };
+        self.rects = self.rects.iter().flat_map(|r| subtract_rect(*r, &rect)).collect();
+        self.limit();
+    }
+
+    /// Collapse a region of too many rectangles to their bounding box, so
+    /// a client cannot make every later operation arbitrarily slow
+    fn limit(&mut self) {
+        if self.rects.len() <= MAX_REGION_RECTS {
+            return;
+        }
+        let (x0, y0) = (self.rects.iter().map(|r| r.x).min().unwrap(), self.rects.iter().map(|r| r.y).min().unwrap());
+        let x1 = self.rects.iter().map(|r| r.x + r.width).max().unwrap();
+        let y1 = self.rects.iter().map(|r| r.y + r.height).max().unwrap();
+        // A box spanning all of i32 loses its last column and row
+        let size = |start: i32, end: i32| (end as i64 - start as i64).min(i32::MAX as i64) as i32;
+        self.rects = vec![Rect::new(x0, y0, size(x0, x1), size(y0, y1))];
+    }
+
+    /// Whether a point lies inside the region
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let (x, y) = (x as i64, y as i64);
+        self.rects.iter().any(|r| {
+            let (left, top) = (r.x as i64, r.y as i64);
+            x >= left && x < left + r.width as i64 && y >= top && y < top + r.height as i64
+        })
+    }
+
+    /// Rectangles making up the region
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// Total covered area in pixels
+    pub fn area(&self) -> i64 {
+        self.rects.iter().map(|r| r.width as i64 * r.height as i64).sum()
+    }
+}
+
+/// A rectangle shrunk so its right and bottom edges fit in an i32, or
+/// `None` if nothing is left of it
+fn clip(rect: Rect) -> Option<Rect> {
+    let width = (rect.width as i64).min(i32::MAX as i64 - rect.x as i64) as i32;
+    let height = (rect.height as i64).min(i32::MAX as i64 - rect.y as i64) as i32;
+    (width > 0 && height > 0).then_some(Rect::new(rect.x, rect.y, width, height))
+}
+
+/// Parts of `a` not covered by `b` (at most four rectangles)
+///
+/// Edges are computed in i64, so rectangles reaching past i32::MAX are
+/// cut off there rather than overflowing.
+pub fn subtract_rect(a: Rect, b: &Rect) -> Vec<Rect> {
+    let edges = |r: &Rect| (r.x as i64, r.y as i64, r.x as i64 + r.width as i64, r.y as i64 + r.height as i64);
+    let (a_left, a_top, a_right, a_bottom) = edges(&a);
+    let (b_left, b_top, b_right, b_bottom) = edges(b);
+    let left = a_left.max(b_left);
+    let top = a_top.max(b_top);
+    let right = a_right.min(b_right);
+    let bottom = a_bottom.min(b_bottom);
+
+    if left >= right || top >= bottom {
+        return vec![a];
+    }
+
+    let rect = |x: i64, y: i64, x1: i64, y1: i64| {
+        let (x1, y1) = (x1.min(i32::MAX as i64), y1.min(i32::MAX as i64));
+        Rect::new(x as i32, y as i32, (x1 - x) as i32, (y1 - y) as i32)
+    };
+    let mut out = Vec::with_capacity(4);
+    // Band above the overlap
+    if top > a_top {
+        out.push(rect(a_left, a_top, a_right, top));
+    }
+    // Band below the overlap
+    if bottom < a_bottom {
+        out.push(rect(a_left, bottom, a_right, a_bottom));
+    }
+    // Left and right of the overlap, within its rows
+    if left > a_left {
+        out.push(rect(a_left, top, left, bottom));
+    }
+    if right < a_right {
+        out.push(rect(right, top, a_right, bottom));
+    }
+    out.retain(|r| r.width > 0 && r.height > 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_overlapping() {
+        let mut region = Region::new();
+        region.add(Rect::new(0, 0, 10, 10));
+        region.add(Rect::new(5, 5, 10, 10));
+
+        assert_eq!(region.area(), 175);
+        assert!(region.contains(14, 14));
+        assert!(!region.contains(14, 0));
+    }
+
+    #[test]
+    fn test_subtract_hole() {
+        let mut region = Region::from_rect(Rect::new(0, 0, 10, 10));
+        region.subtract(Rect::new(2, 2, 6, 6));
+
+        assert_eq!(region.area(), 64);
+        assert_eq!(region.rects().len(), 4);
+        assert!(!region.contains(5, 5));
+        assert!(region.contains(0, 5));
+    }
+
+    #[test]
+    fn test_extreme_rects_do_not_overflow() {
+        let mut region = Region::from_rect(Rect::new(i32::MAX - 1, i32::MIN, i32::MAX, i32::MAX));
+        assert_eq!(region.rects(), [Rect::new(i32::MAX - 1, i32::MIN, 1, i32::MAX)]);
+        assert!(region.contains(i32::MAX - 1, -2));
+        assert!(!region.contains(i32::MAX, -2));
+
+        region.add(Rect::new(i32::MIN, i32::MIN, i32::MAX, i32::MAX));
+        region.subtract(Rect::new(-10, -10, i32::MAX, i32::MAX));
+        assert!(region.contains(-11, -11));
+        assert!(!region.contains(-10, -10));
+        assert!(region.rects().iter().all(|r| r.width > 0 && r.height > 0));
+    }
+
+    #[test]
+    fn test_many_rects_collapse_to_bounds() {
+        let mut region = Region::new();
+        for i in 0..MAX_REGION_RECTS as i32 {
+            region.add(Rect::new(i * 2, 0, 1, 1));
+        }
+        assert_eq!(region.rects().len(), MAX_REGION_RECTS);
+        region.add(Rect::new(-2, 4, 1, 1));
+        assert_eq!(region.rects(), [Rect::new(-2, 0, MAX_REGION_RECTS as i32 * 2 + 1, 5)]);
+    }
+}
@@ -0,0 +1,133 @@
+//! wl_buffer Release and Buffer Age
+//!
+//! Surfaces are composited from the mirrored copy of their buffers, so a
+//! client may draw into a wl_buffer again as soon as a commit replaces it
+//! on its surface or the surface is destroyed; wl_buffer.release is sent
+//! then. Until that happens the buffer is held, and clients cycling
+//! through two or three buffers pick a released one for the next frame.
+//!
+//! Buffer age counts the frames a surface showed since a buffer was last
+//! on it: 1 for the buffer on screen, 2 for the one before, 0 if it never
+//! was. A client redrawing into an old buffer only needs to repaint the
+//! damage of that many frames.
+
+use std::collections::HashMap;
+
+use crate::wire::{opcodes, Message};
+
+/// Held buffers and buffer ages of a client's surfaces
+#[derive(Debug, Default)]
+pub struct BufferTracker {
+    /// Buffer held for each surface
+    held: HashMap<u32, u32>,
+    /// Frames each surface showed
+    frames: HashMap<u32, u64>,
+    /// Surface and frame that last showed each buffer
+    shown: HashMap<u32, (u32, u64)>,
+}
+
+impl BufferTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a surface's current buffer after a commit; `attached` if the
+    /// commit attached a buffer, even the same one as before
+    ///
+    /// Returns the buffer the surface held until now if nothing holds it
+    /// any more.
+    pub fn commit(&mut self, surface: u32, buffer: Option<u32>, attached: bool) -> Option<u32> {
+        let previous = self.held.get(&surface).copied();
+        if buffer == previous && !attached {
+            return None;
+        }
+        match buffer {
+            Some(buffer) => {
+                let frame = self.frames.entry(surface).or_default();
+                *frame += 1;
+                self.shown.insert(buffer, (surface, *frame));
+                self.held.insert(surface, buffer);
+            }
+            None => {
+                self.held.remove(&surface);
+            }
+        }
+        previous.filter(|&old| !self.is_held(old))
+    }
+
+    /// Forget a destroyed surface; returns its buffer if nothing else
+    /// holds it
+    pub fn surface_destroyed(&mut self, surface: u32) -> Option<u32> {
+        self.frames.remove(&surface);
+        self.shown.retain(|_, (shown_on, _)| *shown_on != surface);
+        let buffer = self.held.remove(&surface)?;
+        (!self.is_held(buffer)).then_some(buffer)
+    }
+
+    /// Forget a destroyed buffer
+    pub fn buffer_destroyed(&mut self, buffer: u32) {
+        self.held.retain(|_, held| *held != buffer);
+        self.shown.remove(&buffer);
+    }
+
+    /// Whether a surface still shows the buffer
+    pub fn is_held(&self, buffer: u32) -> bool {
+        self.held.values().any(|&held| held == buffer)
+    }
+
+    /// Frames since the buffer was last shown: 1 = on screen, 0 = never
+    pub fn age(&self, buffer: u32) -> u32 {
+        let Some(&(surface, frame)) = self.shown.get(&buffer) else {
+            return 0;
+        };
+        let current = self.frames.get(&surface).copied().unwrap_or(frame);
+        u32::try_from(current - frame + 1).unwrap_or(u32::MAX)
+    }
+}
+
+/// wl_buffer.release
+pub fn release(buffer: u32) -> Message {
+    Message::new(buffer, opcodes::buffer::RELEASE, Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_released_when_replaced() {
+        let mut tracker = BufferTracker::new();
+        assert_eq!(tracker.commit(10, Some(100), true), None);
+        // Redrawing the same buffer keeps it
+        assert_eq!(tracker.commit(10, Some(100), true), None);
+        assert_eq!(tracker.commit(10, Some(101), true), Some(100));
+        // State-only commits change nothing
+        assert_eq!(tracker.commit(10, Some(101), false), None);
+
+        // A buffer shown on two surfaces stays held by the other
+        tracker.commit(11, Some(101), true);
+        assert_eq!(tracker.commit(10, None, true), None);
+        assert_eq!(tracker.surface_destroyed(11), Some(101));
+        assert!(!tracker.is_held(101));
+
+        // A destroyed buffer is never released
+        tracker.commit(12, Some(102), true);
+        tracker.buffer_destroyed(102);
+        assert_eq!(tracker.commit(12, Some(103), true), None);
+    }
+
+    #[test]
+    fn test_buffer_age() {
+        let mut tracker = BufferTracker::new();
+        for buffer in [100, 101, 102, 100] {
+            tracker.commit(10, Some(buffer), true);
+        }
+        assert_eq!(tracker.age(100), 1);
+        assert_eq!(tracker.age(102), 2);
+        assert_eq!(tracker.age(101), 3);
+        assert_eq!(tracker.age(103), 0);
+
+        tracker.surface_destroyed(10);
+        assert_eq!(tracker.age(100), 0);
+    }
+}
@@ -4,204 +4,2382 @@
 //!
 //! Frame format:
 //! - Magic (4 bytes): "WPRD" (WinPipe RenDer)
+//! - Version (4 bytes, LE): 3, or 4 for compressed frames
+//! - Surface ID (4 bytes, LE): root wl_surface of the window
+//! - Sequence (8 bytes, LE): frame number, increasing per window with
+//!   every frame, frame update or frame delta
 //! - Width (4 bytes, LE)
 //! - Height (4 bytes, LE)
-//! - Format (4 bytes, LE): 0=ARGB8888, 1=XRGB8888
-//! - Data size (4 bytes, LE)
-//! - Data (N bytes): Raw pixel data
+//! - Format (4 bytes, LE): 0=ARGB8888, 1=XRGB8888, 2=NV12, 3=I420,
+//!   4=XRGB2101010 (LE u32 pixels, 10 bits each of R, G, B), 5=ABGR16161616F
+//!   (premultiplied R, G, B, A half floats)
+//! - Data size (4 bytes, LE): size of the data as sent
+//! - Checksum (8 bytes, LE): XXH64 of the uncompressed data, 0 = not
+//!   computed; the decoder drops frames that do not match
+//! - Version 4 only, sent to renderers with the LZ4 capability:
+//!   - Compression (4 bytes, LE): 0=none, 1=LZ4 block
+//!   - Uncompressed size (4 bytes, LE)
+//! - Damage rectangle count and rectangles, as in the input region format;
+//!   0xFFFFFFFF = the whole frame changed
+//! - Data (N bytes): Pixel data, compressed as a whole if the
+//!   compression says so; planes one after another with tightly
+//!   packed rows. NV12 is a Y plane and an interleaved UV plane, I420 a Y,
+//!   a U and a V plane; chroma planes have half the width and height,
+//!   rounded up. YUV is BT.601 limited range. Frame deltas patch any
+//!   format but the YUV ones.
+//!
+//! Frame delta format:
+//! - Magic (4 bytes): "WPDL" (WinPipe DeLta)
+//! - A `crate::buffer::BufferDelta` (buffer ID = root wl_surface) whose
+//!   regions patch the last frame; sent instead of a frame when only part
+//!   of it changed, with no regions when nothing did
+//!
+//! Frame update format, sent instead of a frame delta to renderers with
+//! the update capability:
+//! - Magic (4 bytes): "WPUP" (WinPipe UPdate)
+//! - Sequence (8 bytes, LE): number of the frame the update produces
+//! - Width, height (4 bytes each, LE): size of the frame it patches; a
+//!   renderer holding another size asks for a resync
+//! - A `crate::buffer::BufferDelta` as in a frame delta, holding only the
+//!   changed rectangles and their pixels
+//!
+//! Frame checksum format:
+//! - Magic (4 bytes): "WPCK" (WinPipe ChecKsum)
+//! - A `crate::buffer::BufferChecksum` of the frame the renderer should
+//!   have after the preceding messages; on a mismatch it answers with a
+//!   resync event
+//!
+//! Window format:
+//! - Magic (4 bytes): "WPWN" (WinPipe WiNdow)
+//! - Title length (4 bytes, LE) + UTF-8 title
+//! - App ID length (4 bytes, LE) + UTF-8 app ID
+//! - Min width, min height, max width, max height (4 bytes each, LE)
+//! - State flags (4 bytes, LE): 1=maximized, 2=fullscreen, 4=minimized
+//!
+//! Interactive operation format:
+//! - Magic (4 bytes): "WPOP" (WinPipe OPeration)
+//! - Kind (4 bytes, LE): 1=move, 2=resize
+//! - Edges (4 bytes, LE): xdg_toplevel.resize_edge for resize, else 0
+//!
+//! Input region format:
+//! - Magic (4 bytes): "WPIR" (WinPipe Input Region)
+//! - Rectangle count (4 bytes, LE): 0xFFFFFFFF = whole window
+//! - Rectangles (16 bytes each): x, y, width, height (i32, LE)
+//!
+//! Cursor format:
+//! - Magic (4 bytes): "WPCR" (WinPipe CuRsor)
+//! - Kind (4 bytes, LE): 0=hidden, 1=named shape, 2=image, 3=position
+//! - Named: shape (4 bytes, LE), a wp_cursor_shape_device_v1.shape value
+//! - Image: hotspot x, hotspot y, width, height (4 bytes each, LE) followed
+//!   by width * height * 4 bytes of premultiplied BGRA
+//! - Position: x, y (i32, LE) in window pixels. Moves the cursor without
+//!   touching its image; the renderer draws it there while the pointer is
+//!   locked and puts the system cursor there when the lock is released.
+//!
+//! IME state format:
+//! - Magic (4 bytes): "WPIM" (WinPipe IMe)
+//! - Enabled (4 bytes, LE): 1 if a text field has focus
+//! - Cursor rectangle: x, y, width, height (i32, LE) in window pixels
+//! - Content purpose (4 bytes, LE): zwp_text_input_v3.content_purpose
+//!
+//! Pointer constraint format:
+//! - Magic (4 bytes): "WPPC" (WinPipe Pointer Constraint)
+//! - Kind (4 bytes, LE): 0=none, 1=lock, 2=confine
+//! - Rectangle count and rectangles, as in the input region format
+//!
+//! Shortcuts inhibit format:
+//! - Magic (4 bytes): "WPKI" (WinPipe Keyboard Inhibit)
+//! - Active (4 bytes, LE): 1 while system shortcuts go to the client
+//!
+//! Presentation hint format:
+//! - Magic (4 bytes): "WPPH" (WinPipe Presentation Hint)
+//! - Tearing (4 bytes, LE): 1 if presents may skip vsync
+//! - Content type (4 bytes, LE): 0=none, 1=photo, 2=video, 3=game
+//!
+//! Window target format:
+//! - Magic (4 bytes): "WPSW" (WinPipe Select Window)
+//! - Window (4 bytes, LE): client the following messages come from
+//! - Surface (4 bytes, LE): root wl_surface of the client's toplevel the
+//!   following messages apply to; each toplevel has a window of its own
+//! - Destroy (4 bytes, LE): 1 to close the window instead; with surface
+//!   0, every window of the client
+//!
+//! Color space format:
+//! - Magic (4 bytes): "WPCS" (WinPipe Color Space)
+//! - Transfer function (4 bytes, LE): wp_color_manager_v1.transfer_function
+//! - Primaries: red, green, blue and white x, y (i32 each, LE), CIE 1931
+//!   chromaticities times 1000000
+//! - Luminances (4 bytes each, LE): min in 0.0001 cd/m², max and reference
+//!   white in cd/m²
+//!
+//! Window opacity format:
+//! - Magic (4 bytes): "WPOC" (WinPipe OpaCity)
+//! - Alpha (4 bytes, LE): multiplier for the whole window, 0xFFFFFFFF = 1.0
+//!
+//! Stats overlay format:
+//! - Magic (4 bytes): "WPSO" (WinPipe Stats Overlay)
+//! - Visible (4 bytes, LE): 1 to draw the overlay, 0 to remove it
+//! - Frame rate (4 bytes, LE): frames per second times 100
+//! - Bandwidth (8 bytes, LE): frame bytes sent per second
+//! - Compression (4 bytes, LE): raw frame bytes per byte sent, times 100
+//! - Latency (4 bytes, LE): smoothed presentation latency in
+//!   microseconds, 0 = unknown
+//!
+//! Hello format (first message on a connection):
+//! - Magic (4 bytes): "WPHI" (WinPipe HIgh)
+//! - Version (4 bytes, LE): the frame format version winpipe sends
+//!
+//! The renderer answers with a capabilities event; until it does, or if it
+//! never does, winpipe assumes ARGB8888 and XRGB8888 frames with damage.
+//!
+//! Window icon format:
+//! - Magic (4 bytes): "WPIC" (WinPipe ICon)
+//! - Image count (4 bytes, LE): 0 = default icon, else the small icon
+//!   followed by the large one
+//! - Per image: width, height (4 bytes each, LE) followed by
+//!   width * height * 4 bytes of premultiplied BGRA
+//!
+//! Event format (win-way -> winpipe):
+//! - Magic (4 bytes): "WPEV" (WinPipe EVent)
+//! - Event type (4 bytes, LE): 1=close, 2=resize, 3=interactive end,
+//!   4=IME preedit (cursor begin, cursor end, text), 5=IME commit (text),
+//!   6=focus (1 = gained, 0 = lost), 7=relative motion (time in
+//!   microseconds as u64, then dx, dy, unaccelerated dx, dy as 24.8 fixed),
+//!   8=gesture begin (kind, time in ms, fingers), 9=gesture update (kind,
+//!   time, then dx, dy, scale, rotation as 24.8 fixed), 10=gesture end
+//!   (kind, time, cancelled); kind is 1=swipe, 2=pinch, 3=hold,
+//!   11=pen (time, x, y as 24.8 fixed in window pixels, pressure 0-1024,
+//!   tilt x, tilt y and rotation in degrees, flags: 1=in range,
+//!   2=in contact, 4=barrel button, 8=eraser), 12=presented (vblank time
+//!   in nanoseconds as u64, refresh period in nanoseconds, vblank counter
+//!   as u64, wp_presentation_feedback.kind flags), 13=window (client and
+//!   toplevel wl_surface the following events come from, as in the window
+//!   target format), 16=resync (buffer ID of a frame whose
+//!   checksum did not match), 17=frame ack (sequence of the last frame
+//!   presented, u64), 18=capabilities (bit mask of supported formats,
+//!   1 << format; max width, max height, 0 = unlimited; flags: 1=damage,
+//!   2=vsync, 4=frame updates, 8=LZ4 compressed frames), 19=pointer motion (time in ms, x, y as 24.8 fixed in
+//!   window pixels), 20=pointer leave, 21=pointer button (time, Linux
+//!   button code, 1 = pressed), 22=pointer axis (time, axis: 0=vertical,
+//!   1=horizontal, value as 24.8 fixed in wl_pointer units, wheel steps in
+//!   120ths of a notch, 0 for continuous scrolling), 23=key (time, Linux
+//!   key code, 1 = pressed), 24=touch (kind: 1=down, 2=motion, 3=up,
+//!   4=cancel; time, touch point ID, x, y as 24.8 fixed in window pixels)
+//! - Payload size (4 bytes, LE)
+//! - Payload (N bytes): Event-specific data
 
+use std::hash::Hasher;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::DuplexStream;
 use tokio::net::TcpStream;
-use log::{info, debug, error};
+use twox_hash::XxHash64;
+use log::{info, debug, warn};
 
+use crate::buffer::{pack_rows, BufferChecksum, BufferDelta, MAX_BYTES_PER_PIXEL};
+use crate::builtin_renderer;
+use crate::convert;
 use crate::error::{Result, WinpipeError};
+use crate::positioner::Rect;
+use crate::shm_transport::ShmConnection;
+use crate::sink::RenderSink;
+use crate::surface::MAX_SURFACE_SIZE;
 
 /// Magic bytes for render frame
 pub const FRAME_MAGIC: &[u8; 4] = b"WPRD";
 
-/// Frame header size
-pub const HEADER_SIZE: usize = 20;
+/// Frame format version
+pub const FRAME_VERSION: u32 = 3;
+
+/// Frame format version with compression fields
+pub const COMPRESSED_FRAME_VERSION: u32 = 4;
+
+/// Magic bytes for frame deltas
+pub const FRAME_DELTA_MAGIC: &[u8; 4] = b"WPDL";
+
+/// Magic bytes for frame updates
+pub const FRAME_UPDATE_MAGIC: &[u8; 4] = b"WPUP";
+
+/// Frame update header size, up to the delta
+pub const FRAME_UPDATE_HEADER_SIZE: usize = 20;
+
+/// Magic bytes for frame checksums
+pub const CHECKSUM_MAGIC: &[u8; 4] = b"WPCK";
+
+/// Magic bytes for window metadata
+pub const WINDOW_MAGIC: &[u8; 4] = b"WPWN";
+
+/// Magic bytes for interactive move/resize requests
+pub const OPERATION_MAGIC: &[u8; 4] = b"WPOP";
+
+/// Interactive operation message size
+pub const OPERATION_SIZE: usize = 12;
+
+/// Magic bytes for input region updates
+pub const INPUT_REGION_MAGIC: &[u8; 4] = b"WPIR";
+
+/// Rectangle count meaning "the whole window accepts input"
+pub const INFINITE_REGION: u32 = u32::MAX;
+
+/// Magic bytes for cursor updates
+pub const CURSOR_MAGIC: &[u8; 4] = b"WPCR";
+
+/// Magic bytes for IME state updates
+pub const IME_MAGIC: &[u8; 4] = b"WPIM";
+
+/// IME state message size
+pub const IME_SIZE: usize = 28;
+
+/// Magic bytes for pointer lock/confinement updates
+pub const CONSTRAINT_MAGIC: &[u8; 4] = b"WPPC";
+
+/// Magic bytes for keyboard shortcuts inhibit updates
+pub const SHORTCUTS_INHIBIT_MAGIC: &[u8; 4] = b"WPKI";
+
+/// Shortcuts inhibit message size
+pub const SHORTCUTS_INHIBIT_SIZE: usize = 8;
+
+/// Magic bytes for presentation hint updates
+pub const PRESENTATION_HINT_MAGIC: &[u8; 4] = b"WPPH";
+
+/// Presentation hint message size
+pub const PRESENTATION_HINT_SIZE: usize = 12;
+
+/// Magic bytes for window target changes
+pub const WINDOW_TARGET_MAGIC: &[u8; 4] = b"WPSW";
+
+/// Window target message size
+pub const WINDOW_TARGET_SIZE: usize = 16;
+
+/// Magic bytes for color space updates
+pub const COLOR_SPACE_MAGIC: &[u8; 4] = b"WPCS";
+
+/// Color space message size
+pub const COLOR_SPACE_SIZE: usize = 52;
+
+/// Magic bytes for window opacity updates
+pub const OPACITY_MAGIC: &[u8; 4] = b"WPOC";
+
+/// Window opacity message size
+pub const OPACITY_SIZE: usize = 8;
+
+/// Magic bytes for stats overlay updates
+pub const STATS_OVERLAY_MAGIC: &[u8; 4] = b"WPSO";
+
+/// Stats overlay message size
+pub const STATS_OVERLAY_SIZE: usize = 28;
+
+/// Magic bytes for the connection hello
+pub const HELLO_MAGIC: &[u8; 4] = b"WPHI";
+
+/// Hello message size
+pub const HELLO_SIZE: usize = 8;
+
+/// Magic bytes for window icon updates
+pub const WINDOW_ICON_MAGIC: &[u8; 4] = b"WPIC";
+
+/// Magic bytes for events sent back by the renderer
+pub const EVENT_MAGIC: &[u8; 4] = b"WPEV";
+
+/// Frame header size, up to the damage rectangles
+pub const HEADER_SIZE: usize = 44;
+
+/// Frame header size of compressed frames
+pub const COMPRESSED_HEADER_SIZE: usize = 52;
+
+/// Event header size
+pub const EVENT_HEADER_SIZE: usize = 12;
+
+/// Frame data compression
+pub mod frame_compression {
+    pub const NONE: u32 = 0;
+    pub const LZ4: u32 = 1;
+}
+
+/// Renderer event types
+pub mod event_type {
+    pub const CLOSE: u32 = 1;
+    pub const RESIZE: u32 = 2;
+    pub const INTERACTIVE_END: u32 = 3;
+    pub const IME_PREEDIT: u32 = 4;
+    pub const IME_COMMIT: u32 = 5;
+    pub const FOCUS: u32 = 6;
+    pub const RELATIVE_MOTION: u32 = 7;
+    pub const GESTURE_BEGIN: u32 = 8;
+    pub const GESTURE_UPDATE: u32 = 9;
+    pub const GESTURE_END: u32 = 10;
+    pub const PEN: u32 = 11;
+    pub const PRESENTED: u32 = 12;
+    pub const WINDOW: u32 = 13;
+    pub const INTERACTIVE_BEGIN: u32 = 14;
+    pub const WINDOW_STATE: u32 = 15;
+    pub const RESYNC: u32 = 16;
+    pub const FRAME_ACK: u32 = 17;
+    pub const CAPABILITIES: u32 = 18;
+    pub const POINTER_MOTION: u32 = 19;
+    pub const POINTER_LEAVE: u32 = 20;
+    pub const POINTER_BUTTON: u32 = 21;
+    pub const POINTER_AXIS: u32 = 22;
+    pub const KEY: u32 = 23;
+    pub const TOUCH: u32 = 24;
+}
+
+/// Renderer capability flags in capabilities events
+pub mod capability_flags {
+    /// Damage rectangles are used to repaint only what changed
+    pub const DAMAGE: u32 = 1;
+    /// Presented events come from real vblanks
+    pub const VSYNC: u32 = 2;
+    /// Frame updates (WPUP) are understood
+    pub const UPDATE: u32 = 4;
+    /// LZ4 compressed frames (version 4) are understood
+    pub const LZ4: u32 = 8;
+}
+
+/// Touchpad gesture kinds in gesture events
+pub mod gesture_kind {
+    pub const SWIPE: u32 = 1;
+    pub const PINCH: u32 = 2;
+    pub const HOLD: u32 = 3;
+}
+
+/// Touch point changes in touch events
+pub mod touch_kind {
+    pub const DOWN: u32 = 1;
+    pub const MOTION: u32 = 2;
+    pub const UP: u32 = 3;
+    /// The whole touch sequence was taken over (e.g. by a system gesture)
+    pub const CANCEL: u32 = 4;
+}
+
+/// Pen state flags in pen events
+pub mod pen_flags {
+    pub const IN_RANGE: u32 = 1;
+    pub const IN_CONTACT: u32 = 2;
+    pub const BARREL: u32 = 4;
+    pub const ERASER: u32 = 8;
+}
+
+/// Pen state from one Windows Ink pointer update (POINTER_PEN_INFO)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PenSample {
+    /// Timestamp in milliseconds
+    pub time: u32,
+    /// Position in window pixels
+    pub x: f64,
+    pub y: f64,
+    /// 0-1024
+    pub pressure: u32,
+    /// -90 to 90 degrees
+    pub tilt_x: i32,
+    pub tilt_y: i32,
+    /// 0-359 degrees
+    pub rotation: u32,
+    /// `pen_flags` bits
+    pub flags: u32,
+}
+
+impl PenSample {
+    pub fn in_range(&self) -> bool {
+        self.flags & pen_flags::IN_RANGE != 0
+    }
+
+    pub fn in_contact(&self) -> bool {
+        self.flags & pen_flags::IN_CONTACT != 0
+    }
+}
+
+/// Pixel format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PixelFormat {
+    ARGB8888 = 0,
+    XRGB8888 = 1,
+    /// Planar Y, then interleaved U and V at half resolution
+    NV12 = 2,
+    /// Planar Y, then U, then V at half resolution
+    I420 = 3,
+    /// 10 bits per channel
+    XRGB2101010 = 4,
+    /// Half float per channel
+    ABGR16161616F = 5,
+}
+
+impl PixelFormat {
+    pub fn is_yuv(self) -> bool {
+        matches!(self, Self::NV12 | Self::I420)
+    }
+
+    /// Size of one pixel; None for the planar formats
+    pub fn bytes_per_pixel(self) -> Option<u32> {
+        match self {
+            Self::ARGB8888 | Self::XRGB8888 | Self::XRGB2101010 => Some(4),
+            Self::ABGR16161616F => Some(8),
+            Self::NV12 | Self::I420 => None,
+        }
+    }
+
+    /// Row length and row count of each plane, tightly packed
+    pub fn planes(self, width: u32, height: u32) -> Vec<(usize, usize)> {
+        let (width, height) = (width as usize, height as usize);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        match self {
+            Self::ARGB8888 | Self::XRGB8888 | Self::XRGB2101010 => vec![(width * 4, height)],
+            Self::ABGR16161616F => vec![(width * 8, height)],
+            Self::NV12 => vec![(width, height), (chroma_width * 2, chroma_height)],
+            Self::I420 => vec![(width, height), (chroma_width, chroma_height), (chroma_width, chroma_height)],
+        }
+    }
+
+    /// Bytes of a tightly packed frame, `None` if that overflows
+    pub fn frame_size(self, width: u32, height: u32) -> Option<usize> {
+        self.planes(width, height).iter().try_fold(0usize, |size, (row_len, rows)| size.checked_add(row_len.checked_mul(*rows)?))
+    }
+}
+
+/// A render frame to send to win-way
+#[derive(Debug)]
+pub struct RenderFrame {
+    /// Root wl_surface of the window the frame shows
+    pub surface_id: u32,
+    /// Frame number, for the renderer to spot skipped frames
+    pub sequence: u64,
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// Parts that changed since the last frame, None for all of it
+    pub damage: Option<Vec<Rect>>,
+    pub data: Vec<u8>,
+}
+
+impl RenderFrame {
+    /// Create a new render frame
+    pub fn new(width: u32, height: u32, format: PixelFormat, data: Vec<u8>) -> Self {
+        Self { surface_id: 0, sequence: 0, width, height, format, damage: None, data }
+    }
+
+    /// Create a frame from rows `stride` bytes apart
+    ///
+    /// Frames on the wire are tightly packed, so any row padding is
+    /// dropped. Returns `None` if the data is too short for the size.
+    pub fn from_strided(width: u32, height: u32, format: PixelFormat, stride: u32, data: &[u8]) -> Option<Self> {
+        let row_len = width as usize * format.bytes_per_pixel()? as usize;
+        let pixels = pack_rows(data, row_len, stride as usize, height as usize)?;
+        Some(Self::new(width, height, format, pixels.into_owned()))
+    }
+
+    /// Create a frame from planes given as data and stride, in the order
+    /// of the format's planes
+    ///
+    /// Returns `None` if a plane is missing or too short for the size.
+    pub fn from_planes(width: u32, height: u32, format: PixelFormat, planes: &[(&[u8], u32)]) -> Option<Self> {
+        let layout = format.planes(width, height);
+        if planes.len() != layout.len() {
+            return None;
+        }
+        let mut data = Vec::with_capacity(format.frame_size(width, height)?);
+        for (&(plane, stride), (row_len, rows)) in planes.iter().zip(layout) {
+            data.extend_from_slice(&pack_rows(plane, row_len, stride as usize, rows)?);
+        }
+        Some(Self::new(width, height, format, data))
+    }
+
+    /// Encode to wire format, uncompressed and without a checksum
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(false, false)
+    }
+
+    /// Encode to wire format, with a checksum of the data and LZ4
+    /// compressed if asked to
+    ///
+    /// Data that does not shrink is sent uncompressed, as a version 3
+    /// frame.
+    pub fn encode_with(&self, checksum: bool, compress: bool) -> Vec<u8> {
+        let compressed = compress.then(|| lz4_flex::block::compress(&self.data)).filter(|data| data.len() < self.data.len());
+        let payload = compressed.as_deref().unwrap_or(&self.data);
+        let rects = self.damage.as_ref().map_or(0, Vec::len);
+        let mut buf = Vec::with_capacity(COMPRESSED_HEADER_SIZE + 4 + rects * 16 + payload.len());
+        
+        let version = if compressed.is_some() { COMPRESSED_FRAME_VERSION } else { FRAME_VERSION };
+        buf.extend_from_slice(FRAME_MAGIC);
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&self.surface_id.to_le_bytes());
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&(self.format as u32).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let hash = if checksum { frame_hash(&self.data) } else { 0 };
+        buf.extend_from_slice(&hash.to_le_bytes());
+        if compressed.is_some() {
+            buf.extend_from_slice(&frame_compression::LZ4.to_le_bytes());
+            buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        }
+        encode_rects(&mut buf, self.damage.as_deref());
+        buf.extend_from_slice(payload);
+        
+        buf
+    }
+
+    /// Decode from wire format, returning `None` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        // Check magic
+        if &data[0..4] != FRAME_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid frame magic".to_string()));
+        }
+
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let version = field(4);
+        let header_size = match version {
+            FRAME_VERSION => HEADER_SIZE,
+            COMPRESSED_FRAME_VERSION => COMPRESSED_HEADER_SIZE,
+            _ => return Err(WinpipeError::InvalidMessage(format!("Unsupported frame version {}", version))),
+        };
+        if data.len() < header_size {
+            return Ok(None);
+        }
+        let surface_id = field(8);
+        let sequence = u64::from_le_bytes(data[12..20].try_into().unwrap());
+        let width = field(20);
+        let height = field(24);
+        let format_val = field(28);
+        let data_size = field(32) as usize;
+        let (compression, uncompressed_size) = match version {
+            COMPRESSED_FRAME_VERSION => (field(44), field(48) as usize),
+            _ => (frame_compression::NONE, data_size),
+        };
+
+        let format = match format_val {
+            0 => PixelFormat::ARGB8888,
+            1 => PixelFormat::XRGB8888,
+            2 => PixelFormat::NV12,
+            3 => PixelFormat::I420,
+            4 => PixelFormat::XRGB2101010,
+            5 => PixelFormat::ABGR16161616F,
+            _ => PixelFormat::ARGB8888,
+        };
+
+        // The data must fill the frame exactly, which also bounds what it
+        // may decompress to
+        let expected = format.frame_size(width, height).filter(|&size| size <= MAX_FRAME_SIZE);
+        if expected != Some(uncompressed_size) {
+            return Err(WinpipeError::InvalidMessage(format!(
+                "Frame data is {} bytes, a {}x{} {:?} frame needs {:?}",
+                uncompressed_size, width, height, format, expected
+            )));
+        }
+
+        let Some((damage, rects_size)) = decode_rects(&data[header_size..]) else {
+            return Ok(None);
+        };
+        let start = header_size + rects_size;
+        let Some(pixels) = data.get(start..start + data_size) else {
+            return Ok(None);
+        };
+
+        let pixels = match compression {
+            frame_compression::NONE => pixels.to_vec(),
+            frame_compression::LZ4 => lz4_flex::block::decompress(pixels, uncompressed_size)
+                .map_err(|e| WinpipeError::Compression(e.to_string()))?,
+            other => return Err(WinpipeError::InvalidMessage(format!("Unknown frame compression {}", other))),
+        };
+        if pixels.len() != uncompressed_size {
+            return Err(WinpipeError::InvalidMessage(format!(
+                "Frame data is {} bytes, header says {}",
+                pixels.len(),
+                uncompressed_size
+            )));
+        }
+
+        let frame = Self { surface_id, sequence, width, height, format, damage, data: pixels };
+        Ok(Some((frame, start + data_size)))
+    }
+
+    /// Checksum of the frame contents, to compare against a received one
+    pub fn checksum(&self, buffer_id: u32, tile_size: u32) -> BufferChecksum {
+        let bpp = self.format.bytes_per_pixel().unwrap_or(4);
+        BufferChecksum::compute(buffer_id, (self.width, self.height, bpp), self.width * bpp, &self.data, tile_size)
+    }
+
+    /// Patch the frame with the regions of a frame delta
+    ///
+    /// The delta is rejected as a whole if any region does not fit.
+    pub fn apply_delta(&mut self, delta: &BufferDelta) -> Result<()> {
+        let Some(bpp) = self.format.bytes_per_pixel() else {
+            return Err(WinpipeError::InvalidMessage(format!("Frame delta for a {:?} frame", self.format)));
+        };
+        for region in &delta.regions {
+            region.validate(self.width, self.height, bpp)?;
+        }
+        let bpp = bpp as usize;
+        let stride = self.width as usize * bpp;
+        for region in &delta.regions {
+            let row_len = region.width as usize * bpp;
+            for (row, line) in region.data.chunks_exact(row_len.max(1)).enumerate() {
+                let start = (region.y as usize + row) * stride + region.x as usize * bpp;
+                let target = &mut self.data[start..start + row_len];
+                if region.xor {
+                    target.iter_mut().zip(line).for_each(|(old, delta)| *old ^= delta);
+                } else {
+                    target.copy_from_slice(line);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Patch the frame with a frame update, which becomes its sequence
+    ///
+    /// An update for another frame size is rejected.
+    pub fn apply_update(&mut self, update: &FrameUpdate) -> Result<()> {
+        if (update.width, update.height) != (self.width, self.height) {
+            return Err(WinpipeError::InvalidMessage(format!(
+                "Frame update for {}x{} applied to a {}x{} frame",
+                update.width, update.height, self.width, self.height
+            )));
+        }
+        self.apply_delta(&update.delta)?;
+        self.sequence = update.sequence;
+        Ok(())
+    }
+}
+
+/// The changed parts of a window's frame, without the rest of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameUpdate {
+    /// Number of the frame the update produces
+    pub sequence: u64,
+    /// Size of the frame it patches
+    pub width: u32,
+    pub height: u32,
+    /// Changed rectangles and their pixels (buffer ID = root wl_surface)
+    pub delta: BufferDelta,
+}
+
+impl FrameUpdate {
+    /// Encode to wire format, with the delta's regions compressed
+    pub fn encode(&self) -> Vec<u8> {
+        let delta = self.delta.encode(true);
+        let mut buf = Vec::with_capacity(FRAME_UPDATE_HEADER_SIZE + delta.len());
+        buf.extend_from_slice(FRAME_UPDATE_MAGIC);
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(&delta);
+        buf
+    }
+
+    /// Decode from wire format; `Ok(None)` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < FRAME_UPDATE_HEADER_SIZE {
+            return Ok(None);
+        }
+        if &data[0..4] != FRAME_UPDATE_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid frame update magic".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let sequence = u64::from_le_bytes(data[4..12].try_into().unwrap());
+        let (width, height) = (field(12), field(16));
+        let max_bytes = (width as u64 * height as u64 * MAX_BYTES_PER_PIXEL).min(MAX_FRAME_SIZE as u64);
+        let Some((delta, size)) = BufferDelta::decode(&data[FRAME_UPDATE_HEADER_SIZE..], max_bytes)? else {
+            return Ok(None);
+        };
+        Ok(Some((Self { sequence, width, height, delta }, FRAME_UPDATE_HEADER_SIZE + size)))
+    }
+}
+
+/// Desired window state flags
+pub mod window_state {
+    pub const MAXIMIZED: u32 = 1 << 0;
+    pub const FULLSCREEN: u32 = 1 << 1;
+    pub const MINIMIZED: u32 = 1 << 2;
+    /// The client draws its own decorations; use a borderless window
+    pub const CLIENT_DECORATED: u32 = 1 << 3;
+}
+
+/// Window metadata (title bar text, taskbar grouping and desired state)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowInfo {
+    /// xdg_toplevel title
+    pub title: String,
+    /// xdg_toplevel app_id, used as the Windows AppUserModelID
+    pub app_id: String,
+    /// Minimum size (0 = unconstrained)
+    pub min_width: i32,
+    pub min_height: i32,
+    /// Maximum size (0 = unconstrained)
+    pub max_width: i32,
+    pub max_height: i32,
+    /// `window_state` flags
+    pub state: u32,
+}
+
+impl WindowInfo {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + self.title.len() + self.app_id.len());
+        buf.extend_from_slice(WINDOW_MAGIC);
+        buf.extend_from_slice(&(self.title.len() as u32).to_le_bytes());
+        buf.extend_from_slice(self.title.as_bytes());
+        buf.extend_from_slice(&(self.app_id.len() as u32).to_le_bytes());
+        buf.extend_from_slice(self.app_id.as_bytes());
+        buf.extend_from_slice(&self.min_width.to_le_bytes());
+        buf.extend_from_slice(&self.min_height.to_le_bytes());
+        buf.extend_from_slice(&self.max_width.to_le_bytes());
+        buf.extend_from_slice(&self.max_height.to_le_bytes());
+        buf.extend_from_slice(&self.state.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format, returning the message and its encoded size
+    pub fn decode(data: &[u8]) -> Result<(Self, usize)> {
+        if data.len() < 4 || &data[0..4] != WINDOW_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid window magic".to_string()));
+        }
+
+        let mut pos = 4;
+        let title = read_string(data, &mut pos)?;
+        let app_id = read_string(data, &mut pos)?;
+        let min_width = read_u32(data, &mut pos)? as i32;
+        let min_height = read_u32(data, &mut pos)? as i32;
+        let max_width = read_u32(data, &mut pos)? as i32;
+        let max_height = read_u32(data, &mut pos)? as i32;
+        let state = read_u32(data, &mut pos)?;
+
+        Ok((Self { title, app_id, min_width, min_height, max_width, max_height, state }, pos))
+    }
+
+    pub fn is_maximized(&self) -> bool {
+        self.state & window_state::MAXIMIZED != 0
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.state & window_state::FULLSCREEN != 0
+    }
+
+    pub fn is_minimized(&self) -> bool {
+        self.state & window_state::MINIMIZED != 0
+    }
+
+    pub fn is_client_decorated(&self) -> bool {
+        self.state & window_state::CLIENT_DECORATED != 0
+    }
+}
+
+/// Read a little-endian u32 at `pos`, advancing it
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let bytes = data.get(*pos..*pos + 4)
+        .ok_or_else(|| WinpipeError::InvalidMessage("Incomplete window data".to_string()))?;
+    *pos += 4;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read a length-prefixed UTF-8 string at `pos`, advancing it
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String> {
+    let len = read_u32(data, pos)? as usize;
+    let bytes = data.get(*pos..*pos + len)
+        .ok_or_else(|| WinpipeError::InvalidMessage("Incomplete window data".to_string()))?;
+    *pos += len;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Interactive window operation requested by the client
+///
+/// The renderer starts the native move/size loop (as if the user had
+/// grabbed the title bar or a border).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InteractiveOp {
+    Move,
+    /// Resize from the given xdg_toplevel.resize_edge
+    Resize { edges: u32 },
+}
+
+impl InteractiveOp {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, edges) = match self {
+            Self::Move => (1u32, 0u32),
+            Self::Resize { edges } => (2, *edges),
+        };
+        let mut buf = Vec::with_capacity(OPERATION_SIZE);
+        buf.extend_from_slice(OPERATION_MAGIC);
+        buf.extend_from_slice(&kind.to_le_bytes());
+        buf.extend_from_slice(&edges.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < OPERATION_SIZE || &data[0..4] != OPERATION_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid operation message".to_string()));
+        }
+        let kind = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let edges = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        match kind {
+            1 => Ok(Self::Move),
+            2 => Ok(Self::Resize { edges }),
+            _ => Err(WinpipeError::InvalidMessage(format!("Unknown operation {}", kind))),
+        }
+    }
+}
+
+/// Area of the window that accepts pointer input
+///
+/// Clicks outside it should pass through to whatever is below the window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRegion {
+    /// Rectangles in window coordinates; `None` means the whole window
+    pub rects: Option<Vec<Rect>>,
+}
+
+impl InputRegion {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8);
+        buf.extend_from_slice(INPUT_REGION_MAGIC);
+        encode_rects(&mut buf, self.rects.as_deref());
+        buf
+    }
+
+    /// Decode from wire format, returning `Ok(None)` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < 4 {
+            return Ok(None);
+        }
+        if &data[0..4] != INPUT_REGION_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid input region magic".to_string()));
+        }
+        Ok(decode_rects(&data[4..]).map(|(rects, size)| (Self { rects }, 4 + size)))
+    }
+}
+
+/// Pointer constraint kinds
+pub mod constraint_kind {
+    /// No constraint: release the cursor clip and raw input
+    pub const NONE: u32 = 0;
+    /// Keep the cursor where it is and report relative motion only
+    pub const LOCK: u32 = 1;
+    /// Keep the cursor inside the region
+    pub const CONFINE: u32 = 2;
+}
+
+/// Active pointer lock or confinement for the window
+///
+/// The renderer implements it with ClipCursor and raw mouse input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PointerConstraint {
+    /// `constraint_kind` value
+    pub kind: u32,
+    /// Region in window pixels; `None` means the whole window
+    pub rects: Option<Vec<Rect>>,
+}
+
+impl PointerConstraint {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12);
+        buf.extend_from_slice(CONSTRAINT_MAGIC);
+        buf.extend_from_slice(&self.kind.to_le_bytes());
+        encode_rects(&mut buf, self.rects.as_deref());
+        buf
+    }
+
+    /// Decode from wire format, returning `Ok(None)` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        if &data[0..4] != CONSTRAINT_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid pointer constraint magic".to_string()));
+        }
+        let kind = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        Ok(decode_rects(&data[8..]).map(|(rects, size)| (Self { kind, rects }, 8 + size)))
+    }
+}
+
+/// Frame data checksum: XXH64 with seed 0
+pub fn frame_hash(data: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// Append a rectangle count (INFINITE_REGION for `None`) and the rectangles
+fn encode_rects(buf: &mut Vec<u8>, rects: Option<&[Rect]>) {
+    let count = rects.map_or(INFINITE_REGION, |r| r.len() as u32);
+    buf.extend_from_slice(&count.to_le_bytes());
+    for r in rects.unwrap_or(&[]) {
+        for v in [r.x, r.y, r.width, r.height] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+}
+
+/// Read rectangles written by `encode_rects`, returning `None` if more data is needed
+fn decode_rects(data: &[u8]) -> Option<(Option<Vec<Rect>>, usize)> {
+    let count = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    if count == INFINITE_REGION {
+        return Some((None, 4));
+    }
+
+    let size = 4 + count as usize * 16;
+    let rects = data.get(4..size)?
+        .chunks_exact(16)
+        .map(|c| {
+            let v = |i: usize| i32::from_le_bytes([c[i], c[i + 1], c[i + 2], c[i + 3]]);
+            Rect::new(v(0), v(4), v(8), v(12))
+        })
+        .collect();
+    Some((Some(rects), size))
+}
+
+/// Whether system keyboard shortcuts should go to the client
+///
+/// While active, the renderer installs a low-level keyboard hook and
+/// forwards Alt+Tab, the Windows key and similar combinations to the
+/// window instead of letting Windows act on them. Secure attention keys
+/// (Ctrl+Alt+Del, Win+L) can never be captured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShortcutsInhibit {
+    pub active: bool,
+}
+
+impl ShortcutsInhibit {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SHORTCUTS_INHIBIT_SIZE);
+        buf.extend_from_slice(SHORTCUTS_INHIBIT_MAGIC);
+        buf.extend_from_slice(&(self.active as u32).to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < SHORTCUTS_INHIBIT_SIZE || &data[0..4] != SHORTCUTS_INHIBIT_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid shortcuts inhibit message".to_string()));
+        }
+        Ok(Self { active: u32::from_le_bytes([data[4], data[5], data[6], data[7]]) != 0 })
+    }
+}
+
+/// wp_content_type_v1.type values
+pub mod content_type {
+    pub const NONE: u32 = 0;
+    pub const PHOTO: u32 = 1;
+    pub const VIDEO: u32 = 2;
+    pub const GAME: u32 = 3;
+}
+
+/// How the window's frames should be presented
+///
+/// With `tearing` the renderer presents with DXGI_PRESENT_ALLOW_TEARING
+/// instead of waiting for vsync. Game content also makes it skip frame
+/// queuing (a maximum frame latency of 1) in favour of low latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PresentationHint {
+    /// wp_tearing_control_v1 async presentation hint
+    pub tearing: bool,
+    /// `content_type` value from wp_content_type_v1
+    pub content_type: u32,
+}
+
+impl PresentationHint {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PRESENTATION_HINT_SIZE);
+        buf.extend_from_slice(PRESENTATION_HINT_MAGIC);
+        buf.extend_from_slice(&(self.tearing as u32).to_le_bytes());
+        buf.extend_from_slice(&self.content_type.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < PRESENTATION_HINT_SIZE || &data[0..4] != PRESENTATION_HINT_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid presentation hint message".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self { tearing: field(4) != 0, content_type: field(8) })
+    }
+}
+
+/// Color space of the window's frames
+///
+/// The renderer converts frames from this space to the swap chain's: sRGB
+/// for SDR monitors, scRGB with HDR on. Windows start out in sRGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub transfer: u32,
+    /// Red, green, blue and white x, y
+    pub primaries: [i32; 8],
+    /// In 0.0001 cd/m²
+    pub min_luminance: u32,
+    pub max_luminance: u32,
+    pub reference_luminance: u32,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        crate::color::ImageDescription::srgb().color_space()
+    }
+}
+
+impl ColorSpace {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(COLOR_SPACE_SIZE);
+        buf.extend_from_slice(COLOR_SPACE_MAGIC);
+        buf.extend_from_slice(&self.transfer.to_le_bytes());
+        for value in self.primaries {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.min_luminance.to_le_bytes());
+        buf.extend_from_slice(&self.max_luminance.to_le_bytes());
+        buf.extend_from_slice(&self.reference_luminance.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < COLOR_SPACE_SIZE || &data[0..4] != COLOR_SPACE_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid color space message".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            transfer: field(4),
+            primaries: std::array::from_fn(|i| field(8 + i * 4) as i32),
+            min_luminance: field(40),
+            max_luminance: field(44),
+            reference_luminance: field(48),
+        })
+    }
+}
+
+/// Opacity of the whole window, from the root surface's
+/// wp_alpha_modifier_surface_v1
+///
+/// The renderer presents translucent windows through DirectComposition and
+/// applies this as the visual's opacity on top of the frame's own alpha.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowOpacity {
+    /// u32::MAX = opaque
+    pub alpha: u32,
+}
+
+impl Default for WindowOpacity {
+    fn default() -> Self {
+        Self { alpha: u32::MAX }
+    }
+}
+
+impl WindowOpacity {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(OPACITY_SIZE);
+        buf.extend_from_slice(OPACITY_MAGIC);
+        buf.extend_from_slice(&self.alpha.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < OPACITY_SIZE || &data[0..4] != OPACITY_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid window opacity message".to_string()));
+        }
+        Ok(Self { alpha: u32::from_le_bytes([data[4], data[5], data[6], data[7]]) })
+    }
+}
+
+/// First message on a renderer connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    /// Frame format version
+    pub version: u32,
+}
+
+impl Hello {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HELLO_SIZE);
+        buf.extend_from_slice(HELLO_MAGIC);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < HELLO_SIZE || &data[0..4] != HELLO_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid hello message".to_string()));
+        }
+        Ok(Self { version: u32::from_le_bytes([data[4], data[5], data[6], data[7]]) })
+    }
+}
+
+/// What a renderer can show, from its capabilities event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RendererCapabilities {
+    /// Bit mask of supported `PixelFormat`s, 1 << format
+    pub formats: u32,
+    /// Largest frame size, 0 = unlimited
+    pub max_width: u32,
+    pub max_height: u32,
+    /// `capability_flags`
+    pub flags: u32,
+}
+
+impl RendererCapabilities {
+    pub fn supports(&self, format: PixelFormat) -> bool {
+        self.formats & (1 << format as u32) != 0
+    }
+
+    /// Whether a frame of this size fits
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        (self.max_width == 0 || width <= self.max_width) && (self.max_height == 0 || height <= self.max_height)
+    }
+}
+
+impl Default for RendererCapabilities {
+    /// What renderers without the handshake handle
+    fn default() -> Self {
+        Self {
+            formats: 1 << PixelFormat::ARGB8888 as u32 | 1 << PixelFormat::XRGB8888 as u32,
+            max_width: 0,
+            max_height: 0,
+            flags: capability_flags::DAMAGE,
+        }
+    }
+}
+
+/// Transport statistics for the renderer to draw over the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatsOverlay {
+    pub visible: bool,
+    /// Frames per second times 100
+    pub frame_rate: u32,
+    /// Frame bytes sent per second
+    pub bandwidth: u64,
+    /// Raw frame bytes per byte sent, times 100
+    pub compression: u32,
+    /// Smoothed presentation latency in microseconds, 0 = unknown
+    pub latency_us: u32,
+}
+
+impl StatsOverlay {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(STATS_OVERLAY_SIZE);
+        buf.extend_from_slice(STATS_OVERLAY_MAGIC);
+        buf.extend_from_slice(&u32::from(self.visible).to_le_bytes());
+        buf.extend_from_slice(&self.frame_rate.to_le_bytes());
+        buf.extend_from_slice(&self.bandwidth.to_le_bytes());
+        buf.extend_from_slice(&self.compression.to_le_bytes());
+        buf.extend_from_slice(&self.latency_us.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < STATS_OVERLAY_SIZE || &data[0..4] != STATS_OVERLAY_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid stats overlay message".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            visible: field(4) != 0,
+            frame_rate: field(8),
+            bandwidth: u64::from(field(12)) | u64::from(field(16)) << 32,
+            compression: field(20),
+            latency_us: field(24),
+        })
+    }
+
+    /// One line of text to draw, e.g. "60.0 fps  1.5 MB/s  x4.20  8.3 ms"
+    pub fn summary(&self) -> String {
+        let latency = match self.latency_us {
+            0 => "-".to_string(),
+            us => format!("{:.1}", us as f64 / 1000.0),
+        };
+        format!(
+            "{:.1} fps  {:.1} MB/s  x{:.2}  {} ms",
+            self.frame_rate as f64 / 100.0,
+            self.bandwidth as f64 / 1e6,
+            self.compression as f64 / 100.0,
+            latency,
+        )
+    }
+}
+
+/// Window the following messages apply to
+///
+/// Clients sharing a renderer connection get a window for each of their
+/// toplevels. Until the first target is sent, messages apply to window
+/// 0, surface 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WindowTarget {
+    /// The client
+    pub window: u32,
+    /// Root wl_surface of the toplevel; 0 with `destroy` for all of the
+    /// client's windows
+    pub surface: u32,
+    /// Close the window (its toplevel or client went away)
+    pub destroy: bool,
+}
+
+impl WindowTarget {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(WINDOW_TARGET_SIZE);
+        buf.extend_from_slice(WINDOW_TARGET_MAGIC);
+        buf.extend_from_slice(&self.window.to_le_bytes());
+        buf.extend_from_slice(&self.surface.to_le_bytes());
+        buf.extend_from_slice(&(self.destroy as u32).to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < WINDOW_TARGET_SIZE || &data[0..4] != WINDOW_TARGET_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid window target message".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self { window: field(4), surface: field(8), destroy: field(12) != 0 })
+    }
+}
+
+/// One image of a window icon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconImage {
+    pub width: u32,
+    pub height: u32,
+    /// Premultiplied BGRA, rows top to bottom
+    pub data: Vec<u8>,
+}
+
+/// Taskbar and title bar icons of the window
+///
+/// The renderer uses the first image as the small icon and the last as the
+/// large one. Without images the window goes back to the default icon.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WindowIcon {
+    pub images: Vec<IconImage>,
+}
+
+impl WindowIcon {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let data_size: usize = self.images.iter().map(|image| 8 + image.data.len()).sum();
+        let mut buf = Vec::with_capacity(8 + data_size);
+        buf.extend_from_slice(WINDOW_ICON_MAGIC);
+        buf.extend_from_slice(&(self.images.len() as u32).to_le_bytes());
+        for image in &self.images {
+            buf.extend_from_slice(&image.width.to_le_bytes());
+            buf.extend_from_slice(&image.height.to_le_bytes());
+            buf.extend_from_slice(&image.data);
+        }
+        buf
+    }
+
+    /// Decode from wire format, returning `Ok(None)` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        if &data[0..4] != WINDOW_ICON_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid window icon magic".to_string()));
+        }
+
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let mut images = Vec::new();
+        let mut offset = 8;
+        for _ in 0..field(4) {
+            if data.len() < offset + 8 {
+                return Ok(None);
+            }
+            let (width, height) = (field(offset), field(offset + 4));
+            let end = offset + 8 + width as usize * height as usize * 4;
+            if data.len() < end {
+                return Ok(None);
+            }
+            images.push(IconImage { width, height, data: data[offset + 8..end].to_vec() });
+            offset = end;
+        }
+        Ok(Some((Self { images }, offset)))
+    }
+}
+
+/// wp_cursor_shape_device_v1.shape values
+pub mod cursor_shape {
+    pub const DEFAULT: u32 = 1;
+    pub const CONTEXT_MENU: u32 = 2;
+    pub const HELP: u32 = 3;
+    pub const POINTER: u32 = 4;
+    pub const PROGRESS: u32 = 5;
+    pub const WAIT: u32 = 6;
+    pub const CELL: u32 = 7;
+    pub const CROSSHAIR: u32 = 8;
+    pub const TEXT: u32 = 9;
+    pub const VERTICAL_TEXT: u32 = 10;
+    pub const ALIAS: u32 = 11;
+    pub const COPY: u32 = 12;
+    pub const MOVE: u32 = 13;
+    pub const NO_DROP: u32 = 14;
+    pub const NOT_ALLOWED: u32 = 15;
+    pub const GRAB: u32 = 16;
+    pub const GRABBING: u32 = 17;
+    pub const E_RESIZE: u32 = 18;
+    pub const N_RESIZE: u32 = 19;
+    pub const NE_RESIZE: u32 = 20;
+    pub const NW_RESIZE: u32 = 21;
+    pub const S_RESIZE: u32 = 22;
+    pub const SE_RESIZE: u32 = 23;
+    pub const SW_RESIZE: u32 = 24;
+    pub const W_RESIZE: u32 = 25;
+    pub const EW_RESIZE: u32 = 26;
+    pub const NS_RESIZE: u32 = 27;
+    pub const NESW_RESIZE: u32 = 28;
+    pub const NWSE_RESIZE: u32 = 29;
+    pub const COL_RESIZE: u32 = 30;
+    pub const ROW_RESIZE: u32 = 31;
+    pub const ALL_SCROLL: u32 = 32;
+    pub const ZOOM_IN: u32 = 33;
+    pub const ZOOM_OUT: u32 = 34;
+
+    /// Closest Windows system cursor (IDC_* resource ID) for a shape
+    pub fn to_windows_cursor(shape: u32) -> u16 {
+        const IDC_ARROW: u16 = 32512;
+        const IDC_IBEAM: u16 = 32513;
+        const IDC_WAIT: u16 = 32514;
+        const IDC_CROSS: u16 = 32515;
+        const IDC_SIZENWSE: u16 = 32642;
+        const IDC_SIZENESW: u16 = 32643;
+        const IDC_SIZEWE: u16 = 32644;
+        const IDC_SIZENS: u16 = 32645;
+        const IDC_SIZEALL: u16 = 32646;
+        const IDC_NO: u16 = 32648;
+        const IDC_HAND: u16 = 32649;
+        const IDC_APPSTARTING: u16 = 32650;
+        const IDC_HELP: u16 = 32651;
+
+        match shape {
+            HELP => IDC_HELP,
+            POINTER | GRAB | GRABBING => IDC_HAND,
+            PROGRESS => IDC_APPSTARTING,
+            WAIT => IDC_WAIT,
+            CELL | CROSSHAIR => IDC_CROSS,
+            TEXT | VERTICAL_TEXT => IDC_IBEAM,
+            MOVE | ALL_SCROLL => IDC_SIZEALL,
+            NO_DROP | NOT_ALLOWED => IDC_NO,
+            E_RESIZE | W_RESIZE | EW_RESIZE | COL_RESIZE => IDC_SIZEWE,
+            N_RESIZE | S_RESIZE | NS_RESIZE | ROW_RESIZE => IDC_SIZENS,
+            NE_RESIZE | SW_RESIZE | NESW_RESIZE => IDC_SIZENESW,
+            NW_RESIZE | SE_RESIZE | NWSE_RESIZE => IDC_SIZENWSE,
+            _ => IDC_ARROW,
+        }
+    }
+}
+
+/// Cursor to show while the pointer is over the window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CursorUpdate {
+    /// The client set a null cursor surface
+    Hidden,
+    /// A `cursor_shape` value
+    Named(u32),
+    /// Contents of the client's cursor surface, in output pixels
+    Image {
+        hotspot_x: i32,
+        hotspot_y: i32,
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    },
+    /// Where the cursor is, in window pixels; the image stays as it is
+    Position { x: i32, y: i32 },
+}
+
+impl CursorUpdate {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12);
+        buf.extend_from_slice(CURSOR_MAGIC);
+        match self {
+            Self::Hidden => buf.extend_from_slice(&0u32.to_le_bytes()),
+            Self::Named(shape) => {
+                buf.extend_from_slice(&1u32.to_le_bytes());
+                buf.extend_from_slice(&shape.to_le_bytes());
+            }
+            Self::Image { hotspot_x, hotspot_y, width, height, data } => {
+                buf.extend_from_slice(&2u32.to_le_bytes());
+                buf.extend_from_slice(&hotspot_x.to_le_bytes());
+                buf.extend_from_slice(&hotspot_y.to_le_bytes());
+                buf.extend_from_slice(&width.to_le_bytes());
+                buf.extend_from_slice(&height.to_le_bytes());
+                buf.extend_from_slice(data);
+            }
+            Self::Position { x, y } => {
+                buf.extend_from_slice(&3u32.to_le_bytes());
+                buf.extend_from_slice(&x.to_le_bytes());
+                buf.extend_from_slice(&y.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Decode from wire format, returning `Ok(None)` if more data is needed
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        if &data[0..4] != CURSOR_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid cursor magic".to_string()));
+        }
+
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        match field(4) {
+            0 => Ok(Some((Self::Hidden, 8))),
+            1 if data.len() < 12 => Ok(None),
+            1 => Ok(Some((Self::Named(field(8)), 12))),
+            2 if data.len() < 24 => Ok(None),
+            2 => {
+                let (width, height) = (field(16), field(20));
+                let size = 24 + width as usize * height as usize * 4;
+                if data.len() < size {
+                    return Ok(None);
+                }
+                let cursor = Self::Image {
+                    hotspot_x: field(8) as i32,
+                    hotspot_y: field(12) as i32,
+                    width,
+                    height,
+                    data: data[24..size].to_vec(),
+                };
+                Ok(Some((cursor, size)))
+            }
+            3 if data.len() < 16 => Ok(None),
+            3 => Ok(Some((Self::Position { x: field(8) as i32, y: field(12) as i32 }, 16))),
+            kind => Err(WinpipeError::InvalidMessage(format!("Unknown cursor kind {}", kind))),
+        }
+    }
+}
+
+/// Text input state for the Windows IME
+///
+/// While enabled, the renderer lets the IME compose text and places the
+/// candidate window next to `cursor_rect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImeState {
+    pub enabled: bool,
+    /// Text cursor in window pixels
+    pub cursor_rect: Rect,
+    /// zwp_text_input_v3.content_purpose (e.g. 8 = password)
+    pub purpose: u32,
+}
+
+impl ImeState {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(IME_SIZE);
+        buf.extend_from_slice(IME_MAGIC);
+        buf.extend_from_slice(&(self.enabled as u32).to_le_bytes());
+        let r = self.cursor_rect;
+        for v in [r.x, r.y, r.width, r.height] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.purpose.to_le_bytes());
+        buf
+    }
+
+    /// Decode from wire format
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < IME_SIZE || &data[0..4] != IME_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid IME message".to_string()));
+        }
+        let field = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        Ok(Self {
+            enabled: field(4) != 0,
+            cursor_rect: Rect::new(field(8) as i32, field(12) as i32, field(16) as i32, field(20) as i32),
+            purpose: field(24),
+        })
+    }
+}
+
+/// Any message sent from winpipe to the renderer
+#[derive(Debug)]
+pub enum RenderMessage {
+    Frame(RenderFrame),
+    FrameDelta(BufferDelta),
+    FrameUpdate(FrameUpdate),
+    Checksum(BufferChecksum),
+    Window(WindowInfo),
+    Interactive(InteractiveOp),
+    InputRegion(InputRegion),
+    Cursor(CursorUpdate),
+    Ime(ImeState),
+    PointerConstraint(PointerConstraint),
+    ShortcutsInhibit(ShortcutsInhibit),
+    PresentationHint(PresentationHint),
+    WindowTarget(WindowTarget),
+    WindowIcon(WindowIcon),
+    ColorSpace(ColorSpace),
+    Opacity(WindowOpacity),
+    StatsOverlay(StatsOverlay),
+    Hello(Hello),
+}
+
+impl RenderMessage {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Frame(frame) => frame.encode(),
+            Self::FrameDelta(delta) => [&FRAME_DELTA_MAGIC[..], &delta.encode(true)].concat(),
+            Self::FrameUpdate(update) => update.encode(),
+            Self::Checksum(checksum) => [&CHECKSUM_MAGIC[..], &checksum.encode()].concat(),
+            Self::Window(info) => info.encode(),
+            Self::Interactive(op) => op.encode(),
+            Self::InputRegion(region) => region.encode(),
+            Self::Cursor(cursor) => cursor.encode(),
+            Self::Ime(state) => state.encode(),
+            Self::PointerConstraint(constraint) => constraint.encode(),
+            Self::ShortcutsInhibit(inhibit) => inhibit.encode(),
+            Self::PresentationHint(hint) => hint.encode(),
+            Self::WindowTarget(target) => target.encode(),
+            Self::WindowIcon(icon) => icon.encode(),
+            Self::ColorSpace(space) => space.encode(),
+            Self::Opacity(opacity) => opacity.encode(),
+            Self::StatsOverlay(stats) => stats.encode(),
+            Self::Hello(hello) => hello.encode(),
+        }
+    }
+}
+
+/// Events sent from win-way back to winpipe
+#[derive(Debug, Clone, PartialEq)]
+pub enum RendererEvent {
+    /// The user closed the Windows window
+    Close,
+    /// The Windows window client area was resized
+    Resize { width: i32, height: i32 },
+    /// The user started moving or resizing the Windows window
+    InteractiveBegin,
+    /// An interactive move/resize loop finished
+    InteractiveEnd,
+    /// The user maximized, restored or minimized the Windows window;
+    /// `state` holds `window_state` flags
+    WindowState { state: u32 },
+    /// The IME composition string changed (empty text clears it);
+    /// cursor positions are byte offsets into `text`
+    ImePreedit { text: String, cursor_begin: i32, cursor_end: i32 },
+    /// The IME produced final text
+    ImeCommit { text: String },
+    /// The Windows window gained or lost keyboard focus
+    Focus { focused: bool },
+    /// Raw mouse motion (only sent while the pointer is locked or confined)
+    RelativeMotion { time_usec: u64, dx: f64, dy: f64, dx_unaccel: f64, dy_unaccel: f64 },
+    /// A precision touchpad gesture of a `gesture_kind` started
+    GestureBegin { kind: u32, time: u32, fingers: u32 },
+    /// Gesture progress: deltas in window pixels, absolute pinch scale and
+    /// rotation delta in degrees
+    GestureUpdate { kind: u32, time: u32, dx: f64, dy: f64, scale: f64, rotation: f64 },
+    /// The gesture finished or was cancelled
+    GestureEnd { kind: u32, time: u32, cancelled: bool },
+    /// Windows Ink pen input
+    Pen(PenSample),
+    /// The latest frame reached the screen at the vblank `seq`
+    Presented { time_ns: u64, refresh_ns: u32, seq: u64, flags: u32 },
+    /// The following events come from the window of this client's
+    /// toplevel wl_surface
+    Window { window: u32, surface: u32 },
+    /// The renderer's copy of a frame no longer matches its checksum
+    Resync { buffer_id: u32 },
+    /// Frames up to `sequence` reached the screen
+    FrameAck { sequence: u64 },
+    /// Reply to the hello
+    Capabilities(RendererCapabilities),
+    /// The mouse moved over the window, to a position in window pixels
+    PointerMotion { time: u32, x: f64, y: f64 },
+    /// The mouse left the window
+    PointerLeave,
+    /// A mouse button (Linux `BTN_*` code) was pressed or released
+    PointerButton { time: u32, button: u32, pressed: bool },
+    /// Scrolling along a wl_pointer axis; `value120` counts wheel notches
+    /// in 120ths and is 0 for touchpads
+    PointerAxis { time: u32, axis: u32, value: f64, value120: i32 },
+    /// A key (Linux `KEY_*` code) was pressed or released
+    Key { time: u32, key: u32, pressed: bool },
+    /// A touch point of a `touch_kind` changed, at a position in window
+    /// pixels
+    Touch { kind: u32, time: u32, id: i32, x: f64, y: f64 },
+}
+
+impl RendererEvent {
+    /// Encode to wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload): (u32, Vec<u8>) = match self {
+            Self::Close => (event_type::CLOSE, Vec::new()),
+            Self::Resize { width, height } => {
+                (event_type::RESIZE, [width.to_le_bytes(), height.to_le_bytes()].concat())
+            }
+            Self::InteractiveBegin => (event_type::INTERACTIVE_BEGIN, Vec::new()),
+            Self::InteractiveEnd => (event_type::INTERACTIVE_END, Vec::new()),
+            Self::WindowState { state } => (event_type::WINDOW_STATE, state.to_le_bytes().to_vec()),
+            Self::ImePreedit { text, cursor_begin, cursor_end } => {
+                let payload = [&cursor_begin.to_le_bytes()[..], &cursor_end.to_le_bytes(), text.as_bytes()].concat();
+                (event_type::IME_PREEDIT, payload)
+            }
+            Self::ImeCommit { text } => (event_type::IME_COMMIT, text.as_bytes().to_vec()),
+            Self::Focus { focused } => (event_type::FOCUS, (*focused as u32).to_le_bytes().to_vec()),
+            Self::RelativeMotion { time_usec, dx, dy, dx_unaccel, dy_unaccel } => {
+                let mut payload = time_usec.to_le_bytes().to_vec();
+                for v in [dx, dy, dx_unaccel, dy_unaccel] {
+                    payload.extend_from_slice(&((v * 256.0) as i32).to_le_bytes());
+                }
+                (event_type::RELATIVE_MOTION, payload)
+            }
+            Self::GestureBegin { kind, time, fingers } => {
+                (event_type::GESTURE_BEGIN, [kind.to_le_bytes(), time.to_le_bytes(), fingers.to_le_bytes()].concat())
+            }
+            Self::GestureUpdate { kind, time, dx, dy, scale, rotation } => {
+                let mut payload = [kind.to_le_bytes(), time.to_le_bytes()].concat();
+                for v in [dx, dy, scale, rotation] {
+                    payload.extend_from_slice(&((v * 256.0) as i32).to_le_bytes());
+                }
+                (event_type::GESTURE_UPDATE, payload)
+            }
+            Self::GestureEnd { kind, time, cancelled } => {
+                let payload = [kind.to_le_bytes(), time.to_le_bytes(), (*cancelled as u32).to_le_bytes()].concat();
+                (event_type::GESTURE_END, payload)
+            }
+            Self::Pen(pen) => {
+                let mut payload = pen.time.to_le_bytes().to_vec();
+                payload.extend_from_slice(&((pen.x * 256.0) as i32).to_le_bytes());
+                payload.extend_from_slice(&((pen.y * 256.0) as i32).to_le_bytes());
+                payload.extend_from_slice(&pen.pressure.to_le_bytes());
+                payload.extend_from_slice(&pen.tilt_x.to_le_bytes());
+                payload.extend_from_slice(&pen.tilt_y.to_le_bytes());
+                payload.extend_from_slice(&pen.rotation.to_le_bytes());
+                payload.extend_from_slice(&pen.flags.to_le_bytes());
+                (event_type::PEN, payload)
+            }
+            Self::Presented { time_ns, refresh_ns, seq, flags } => {
+                let payload = [&time_ns.to_le_bytes()[..], &refresh_ns.to_le_bytes(), &seq.to_le_bytes(), &flags.to_le_bytes()].concat();
+                (event_type::PRESENTED, payload)
+            }
+            Self::Window { window, surface } => (event_type::WINDOW, [window.to_le_bytes(), surface.to_le_bytes()].concat()),
+            Self::Resync { buffer_id } => (event_type::RESYNC, buffer_id.to_le_bytes().to_vec()),
+            Self::FrameAck { sequence } => (event_type::FRAME_ACK, sequence.to_le_bytes().to_vec()),
+            Self::Capabilities(caps) => {
+                let payload = [caps.formats, caps.max_width, caps.max_height, caps.flags].map(u32::to_le_bytes).concat();
+                (event_type::CAPABILITIES, payload)
+            }
+            Self::PointerMotion { time, x, y } => {
+                (event_type::POINTER_MOTION, [*time as i32, (x * 256.0) as i32, (y * 256.0) as i32].map(i32::to_le_bytes).concat())
+            }
+            Self::PointerLeave => (event_type::POINTER_LEAVE, Vec::new()),
+            Self::PointerButton { time, button, pressed } => {
+                (event_type::POINTER_BUTTON, [*time, *button, *pressed as u32].map(u32::to_le_bytes).concat())
+            }
+            Self::PointerAxis { time, axis, value, value120 } => {
+                let payload = [*time as i32, *axis as i32, (value * 256.0) as i32, *value120].map(i32::to_le_bytes).concat();
+                (event_type::POINTER_AXIS, payload)
+            }
+            Self::Key { time, key, pressed } => {
+                (event_type::KEY, [*time, *key, *pressed as u32].map(u32::to_le_bytes).concat())
+            }
+            Self::Touch { kind, time, id, x, y } => {
+                let payload = [*kind as i32, *time as i32, *id, (x * 256.0) as i32, (y * 256.0) as i32].map(i32::to_le_bytes).concat();
+                (event_type::TOUCH, payload)
+            }
+        };
+
+        let mut buf = Vec::with_capacity(EVENT_HEADER_SIZE + payload.len());
+        buf.extend_from_slice(EVENT_MAGIC);
+        buf.extend_from_slice(&kind.to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    /// Decode from wire format
+    ///
+    /// Returns `Ok(None)` if more data is needed, otherwise the event and
+    /// its encoded size. Unknown event types decode to `None` payloads and
+    /// are skipped by the caller using the returned size.
+    pub fn decode(data: &[u8]) -> Result<Option<(Option<Self>, usize)>> {
+        if data.len() < EVENT_HEADER_SIZE {
+            return Ok(None);
+        }
+        if &data[0..4] != EVENT_MAGIC {
+            return Err(WinpipeError::InvalidMessage("Invalid event magic".to_string()));
+        }
+
+        let kind = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let payload_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
+        let total_size = EVENT_HEADER_SIZE + payload_size;
+        if data.len() < total_size {
+            return Ok(None);
+        }
+
+        let payload = &data[EVENT_HEADER_SIZE..total_size];
+        let read_i32 = |offset: usize| {
+            payload.get(offset..offset + 4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| WinpipeError::InvalidMessage("Event payload too short".to_string()))
+        };
 
-/// Pixel format
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum PixelFormat {
-    ARGB8888 = 0,
-    XRGB8888 = 1,
+        let event = match kind {
+            event_type::CLOSE => Some(Self::Close),
+            event_type::RESIZE => Some(Self::Resize { width: read_i32(0)?, height: read_i32(4)? }),
+            event_type::INTERACTIVE_BEGIN => Some(Self::InteractiveBegin),
+            event_type::INTERACTIVE_END => Some(Self::InteractiveEnd),
+            event_type::WINDOW_STATE => Some(Self::WindowState { state: read_i32(0)? as u32 }),
+            event_type::IME_PREEDIT => Some(Self::ImePreedit {
+                cursor_begin: read_i32(0)?,
+                cursor_end: read_i32(4)?,
+                text: String::from_utf8_lossy(&payload[8..]).into_owned(),
+            }),
+            event_type::IME_COMMIT => Some(Self::ImeCommit { text: String::from_utf8_lossy(payload).into_owned() }),
+            event_type::FOCUS => Some(Self::Focus { focused: read_i32(0)? != 0 }),
+            event_type::RELATIVE_MOTION => {
+                let time = (read_i32(0)? as u32 as u64) | ((read_i32(4)? as u32 as u64) << 32);
+                let fixed = |offset: usize| read_i32(offset).map(|v| v as f64 / 256.0);
+                Some(Self::RelativeMotion {
+                    time_usec: time,
+                    dx: fixed(8)?,
+                    dy: fixed(12)?,
+                    dx_unaccel: fixed(16)?,
+                    dy_unaccel: fixed(20)?,
+                })
+            }
+            event_type::GESTURE_BEGIN => Some(Self::GestureBegin {
+                kind: read_i32(0)? as u32,
+                time: read_i32(4)? as u32,
+                fingers: read_i32(8)? as u32,
+            }),
+            event_type::GESTURE_UPDATE => {
+                let fixed = |offset: usize| read_i32(offset).map(|v| v as f64 / 256.0);
+                Some(Self::GestureUpdate {
+                    kind: read_i32(0)? as u32,
+                    time: read_i32(4)? as u32,
+                    dx: fixed(8)?,
+                    dy: fixed(12)?,
+                    scale: fixed(16)?,
+                    rotation: fixed(20)?,
+                })
+            }
+            event_type::GESTURE_END => Some(Self::GestureEnd {
+                kind: read_i32(0)? as u32,
+                time: read_i32(4)? as u32,
+                cancelled: read_i32(8)? != 0,
+            }),
+            event_type::PEN => Some(Self::Pen(PenSample {
+                time: read_i32(0)? as u32,
+                x: read_i32(4)? as f64 / 256.0,
+                y: read_i32(8)? as f64 / 256.0,
+                pressure: read_i32(12)? as u32,
+                tilt_x: read_i32(16)?,
+                tilt_y: read_i32(20)?,
+                rotation: read_i32(24)? as u32,
+                flags: read_i32(28)? as u32,
+            })),
+            event_type::PRESENTED => {
+                let u64_at = |offset: usize| -> Result<u64> {
+                    Ok((read_i32(offset)? as u32 as u64) | ((read_i32(offset + 4)? as u32 as u64) << 32))
+                };
+                Some(Self::Presented {
+                    time_ns: u64_at(0)?,
+                    refresh_ns: read_i32(8)? as u32,
+                    seq: u64_at(12)?,
+                    flags: read_i32(20)? as u32,
+                })
+            }
+            event_type::WINDOW => Some(Self::Window { window: read_i32(0)? as u32, surface: read_i32(4)? as u32 }),
+            event_type::RESYNC => Some(Self::Resync { buffer_id: read_i32(0)? as u32 }),
+            event_type::FRAME_ACK => {
+                let sequence = (read_i32(0)? as u32 as u64) | ((read_i32(4)? as u32 as u64) << 32);
+                Some(Self::FrameAck { sequence })
+            }
+            event_type::CAPABILITIES => Some(Self::Capabilities(RendererCapabilities {
+                formats: read_i32(0)? as u32,
+                max_width: read_i32(4)? as u32,
+                max_height: read_i32(8)? as u32,
+                flags: read_i32(12)? as u32,
+            })),
+            event_type::POINTER_MOTION => Some(Self::PointerMotion {
+                time: read_i32(0)? as u32,
+                x: read_i32(4)? as f64 / 256.0,
+                y: read_i32(8)? as f64 / 256.0,
+            }),
+            event_type::POINTER_LEAVE => Some(Self::PointerLeave),
+            event_type::POINTER_BUTTON => Some(Self::PointerButton {
+                time: read_i32(0)? as u32,
+                button: read_i32(4)? as u32,
+                pressed: read_i32(8)? != 0,
+            }),
+            event_type::POINTER_AXIS => Some(Self::PointerAxis {
+                time: read_i32(0)? as u32,
+                axis: read_i32(4)? as u32,
+                value: read_i32(8)? as f64 / 256.0,
+                value120: read_i32(12)?,
+            }),
+            event_type::KEY => Some(Self::Key {
+                time: read_i32(0)? as u32,
+                key: read_i32(4)? as u32,
+                pressed: read_i32(8)? != 0,
+            }),
+            event_type::TOUCH => Some(Self::Touch {
+                kind: read_i32(0)? as u32,
+                time: read_i32(4)? as u32,
+                id: read_i32(8)?,
+                x: read_i32(12)? as f64 / 256.0,
+                y: read_i32(16)? as f64 / 256.0,
+            }),
+            _ => None,
+        };
+        Ok(Some((event, total_size)))
+    }
 }
 
-/// A render frame to send to win-way
-#[derive(Debug)]
-pub struct RenderFrame {
-    pub width: u32,
-    pub height: u32,
-    pub format: PixelFormat,
-    pub data: Vec<u8>,
+/// Where to reach win-way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RendererAddr {
+    /// Listening on TCP
+    Tcp(SocketAddr),
+    /// On the same machine, through the shared-memory transport of this
+    /// name; written "shm:NAME"
+    Shm(String),
+    /// A Windows named pipe, written as its path (\\.\pipe\NAME)
+    Pipe(String),
+    /// The renderer built into winpipe, drawing with this presenter
+    Builtin(builtin_renderer::Presenter),
 }
 
-impl RenderFrame {
-    /// Create a new render frame
-    pub fn new(width: u32, height: u32, format: PixelFormat, data: Vec<u8>) -> Self {
-        Self { width, height, format, data }
-    }
+/// Path prefix of Windows named pipes
+pub const PIPE_PREFIX: &str = r"\\.\pipe\";
 
-    /// Encode to wire format
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(HEADER_SIZE + self.data.len());
-        
-        buf.extend_from_slice(FRAME_MAGIC);
-        buf.extend_from_slice(&self.width.to_le_bytes());
-        buf.extend_from_slice(&self.height.to_le_bytes());
-        buf.extend_from_slice(&(self.format as u32).to_le_bytes());
-        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
-        buf.extend_from_slice(&self.data);
-        
-        buf
-    }
+impl std::str::FromStr for RendererAddr {
+    type Err = String;
 
-    /// Decode from wire format
-    pub fn decode(data: &[u8]) -> Result<Self> {
-        if data.len() < HEADER_SIZE {
-            return Err(WinpipeError::InvalidMessage("Frame too short".to_string()));
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix(PIPE_PREFIX) {
+            return match name {
+                "" => Err(format!("missing pipe name after '{}'", PIPE_PREFIX)),
+                _ => Ok(Self::Pipe(s.to_string())),
+            };
+        }
+        match s.strip_prefix("shm:") {
+            Some("") => Err("missing shared memory name after 'shm:'".to_string()),
+            Some(name) => Ok(Self::Shm(name.to_string())),
+            None => s.parse().map(Self::Tcp).map_err(|_| {
+                format!("invalid renderer address '{}' (expected HOST:PORT, shm:NAME or {}NAME)", s, PIPE_PREFIX)
+            }),
         }
+    }
+}
 
-        // Check magic
-        if &data[0..4] != FRAME_MAGIC {
-            return Err(WinpipeError::InvalidMessage("Invalid frame magic".to_string()));
+impl std::fmt::Display for RendererAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Shm(name) => write!(f, "shm:{}", name),
+            Self::Pipe(path) => write!(f, "{}", path),
+            Self::Builtin(_) => write!(f, "the built-in renderer"),
         }
+    }
+}
 
-        let width = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
-        let height = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
-        let format_val = u32::from_le_bytes([data[12], data[13], data[14], data[15]]);
-        let data_size = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+/// An open connection to win-way
+enum Connection {
+    Tcp(TcpStream),
+    Shm(ShmConnection),
+    #[cfg(windows)]
+    Pipe(tokio::net::windows::named_pipe::NamedPipeClient),
+    Builtin(DuplexStream),
+}
 
-        let format = match format_val {
-            0 => PixelFormat::ARGB8888,
-            1 => PixelFormat::XRGB8888,
-            _ => PixelFormat::ARGB8888,
-        };
+impl RenderSink for Connection {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Tcp(stream) => RenderSink::write_all(stream, data).await,
+            Self::Shm(connection) => RenderSink::write_all(connection, data).await,
+            #[cfg(windows)]
+            Self::Pipe(pipe) => RenderSink::write_all(pipe, data).await,
+            Self::Builtin(stream) => RenderSink::write_all(stream, data).await,
+        }
+    }
 
-        if data.len() < HEADER_SIZE + data_size {
-            return Err(WinpipeError::InvalidMessage("Incomplete frame data".to_string()));
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::Tcp(stream) => RenderSink::read(stream, buf).await,
+            Self::Shm(connection) => RenderSink::read(connection, buf).await,
+            #[cfg(windows)]
+            Self::Pipe(pipe) => RenderSink::read(pipe, buf).await,
+            Self::Builtin(stream) => RenderSink::read(stream, buf).await,
         }
+    }
+}
 
-        Ok(Self {
-            width,
-            height,
-            format,
-            data: data[HEADER_SIZE..HEADER_SIZE + data_size].to_vec(),
-        })
+/// Open a named pipe, waiting while all its instances are busy
+#[cfg(windows)]
+async fn open_pipe(path: &str) -> Result<Connection> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+
+    loop {
+        match ClientOptions::new().open(path) {
+            Ok(pipe) => return Ok(Connection::Pipe(pipe)),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {}
+            Err(e) => return Err(e.into()),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
     }
 }
 
+#[cfg(not(windows))]
+async fn open_pipe(path: &str) -> Result<Connection> {
+    Err(WinpipeError::Protocol(format!("cannot open {}: named pipes are only available on Windows", path)))
+}
+
 /// Client for sending frames to win-way
 pub struct RenderClient {
-    stream: Option<TcpStream>,
-    addr: SocketAddr,
+    connection: Option<Connection>,
+    addr: RendererAddr,
+    /// Partially received renderer events
+    event_buffer: Vec<u8>,
+    /// Send frames with a checksum of their data
+    frame_checksums: bool,
+    /// Send frame data LZ4 compressed
+    frame_compression: bool,
+    /// What the renderer said it can show
+    capabilities: RendererCapabilities,
 }
 
 impl RenderClient {
     /// Create a new render client
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new(addr: RendererAddr) -> Self {
         Self {
-            stream: None,
+            connection: None,
             addr,
+            event_buffer: Vec::new(),
+            frame_checksums: false,
+            frame_compression: false,
+            capabilities: RendererCapabilities::default(),
         }
     }
 
+    /// What the renderer can show, as far as it told
+    pub fn capabilities(&self) -> RendererCapabilities {
+        self.capabilities
+    }
+
+    /// Checksum the data of frames sent from now on, so the renderer can
+    /// tell transport corruption from a garbled picture
+    pub fn set_frame_checksums(&mut self, enabled: bool) {
+        self.frame_checksums = enabled;
+    }
+
+    /// LZ4 compress the data of frames sent from now on, trading CPU time
+    /// for bandwidth
+    pub fn set_frame_compression(&mut self, enabled: bool) {
+        self.frame_compression = enabled;
+    }
+
     /// Connect to win-way
     pub async fn connect(&mut self) -> Result<()> {
         info!("🎨 Connecting to win-way at {}", self.addr);
-        let stream = TcpStream::connect(self.addr).await?;
-        self.stream = Some(stream);
+        let connection = match &self.addr {
+            RendererAddr::Tcp(addr) => Connection::Tcp(TcpStream::connect(addr).await?),
+            RendererAddr::Shm(name) => Connection::Shm(ShmConnection::connect(name)?),
+            RendererAddr::Pipe(path) => open_pipe(path).await?,
+            RendererAddr::Builtin(presenter) => Connection::Builtin(builtin_renderer::spawn(*presenter)?),
+        };
+        self.connection = Some(connection);
+        self.capabilities = RendererCapabilities::default();
         info!("✅ Connected to win-way renderer");
+        // The capabilities come back as an event
+        let hello = Hello { version: FRAME_VERSION };
+        self.connection()?.send_message(&RenderMessage::Hello(hello)).await?;
         Ok(())
     }
 
-    /// Send a frame to win-way
+    fn connection(&mut self) -> Result<&mut Connection> {
+        self.connection.as_mut().ok_or_else(|| WinpipeError::Protocol("Not connected".to_string()))
+    }
+
+    /// Send a frame to win-way, converted to ARGB8888 if the renderer
+    /// cannot show its format
     pub async fn send_frame(&mut self, frame: &RenderFrame) -> Result<()> {
-        let stream = self.stream.as_mut()
-            .ok_or_else(|| WinpipeError::Protocol("Not connected".to_string()))?;
-        
-        let data = frame.encode();
-        debug!("📤 Sending frame {}x{} ({} bytes)", frame.width, frame.height, data.len());
-        
-        stream.write_all(&data).await?;
-        Ok(())
+        let converted;
+        let unsupported = !self.capabilities.supports(frame.format);
+        let frame = match unsupported.then(|| convert::frame_to_argb8888(frame.format, frame.width, frame.height, &frame.data)).flatten() {
+            Some(data) => {
+                converted = RenderFrame { format: PixelFormat::XRGB8888, data, damage: frame.damage.clone(), ..*frame };
+                &converted
+            }
+            _ => frame,
+        };
+        if !self.capabilities.fits(frame.width, frame.height) {
+            warn!("Frame {}x{} exceeds the renderer's limit", frame.width, frame.height);
+        }
+        // Renderers that do not report LZ4 only understand version 3 frames
+        let compress = self.frame_compression && self.capabilities.flags & capability_flags::LZ4 != 0;
+        let checksum = self.frame_checksums;
+        debug!("📤 Sending frame {}x{} ({} bytes)", frame.width, frame.height, frame.data.len());
+        self.connection()?.submit_frame(frame, checksum, compress).await
+    }
+
+    /// Send window metadata to win-way
+    pub async fn send_window_info(&mut self, info: &WindowInfo) -> Result<()> {
+        debug!("📤 Sending window info: title={:?} app_id={:?}", info.title, info.app_id);
+        self.connection()?.send_window_info(info).await
+    }
+
+    /// Send any render message to win-way
+    pub async fn send(&mut self, message: &RenderMessage) -> Result<()> {
+        match message {
+            RenderMessage::Frame(frame) => self.send_frame(frame).await,
+            RenderMessage::Window(info) => self.send_window_info(info).await,
+            RenderMessage::Interactive(_)
+            | RenderMessage::InputRegion(_)
+            | RenderMessage::Ime(_)
+            | RenderMessage::PointerConstraint(_)
+            | RenderMessage::ShortcutsInhibit(_)
+            | RenderMessage::PresentationHint(_)
+            | RenderMessage::WindowTarget(_)
+            | RenderMessage::ColorSpace(_)
+            | RenderMessage::Opacity(_)
+            | RenderMessage::StatsOverlay(_)
+            | RenderMessage::Hello(_) => {
+                debug!("📤 Sending {:?}", message);
+                self.connection()?.send_message(message).await
+            }
+            RenderMessage::Cursor(cursor) => {
+                match cursor {
+                    CursorUpdate::Image { width, height, .. } => debug!("📤 Sending cursor image {}x{}", width, height),
+                    other => debug!("📤 Sending cursor {:?}", other),
+                }
+                self.connection()?.set_cursor(cursor).await
+            }
+            RenderMessage::FrameDelta(delta) => {
+                debug!("📤 Sending frame delta: {} regions ({} bytes)", delta.regions.len(), delta.total_bytes);
+                self.connection()?.send_message(message).await
+            }
+            RenderMessage::FrameUpdate(update) => {
+                // Renderers without updates get the same regions as a delta
+                let data = if self.capabilities.flags & capability_flags::UPDATE != 0 {
+                    update.encode()
+                } else {
+                    [&FRAME_DELTA_MAGIC[..], &update.delta.encode(true)].concat()
+                };
+                let stream = self.connection()?;
+                debug!("📤 Sending frame update {}: {} regions ({} bytes)", update.sequence, update.delta.regions.len(), data.len());
+                stream.write_all(&data).await?;
+                Ok(())
+            }
+            RenderMessage::Checksum(checksum) => {
+                debug!("📤 Sending frame checksum {:016x}", checksum.hash);
+                self.connection()?.send_message(message).await
+            }
+            RenderMessage::WindowIcon(icon) => {
+                let sizes: Vec<_> = icon.images.iter().map(|image| (image.width, image.height)).collect();
+                debug!("📤 Sending window icon {:?}", sizes);
+                self.connection()?.send_message(message).await
+            }
+        }
+    }
+
+    /// Wait for the next event from win-way
+    ///
+    /// Cancel-safe: partially received events are kept in an internal
+    /// buffer, so this can be used in `tokio::select!`.
+    pub async fn next_event(&mut self) -> Result<RendererEvent> {
+        loop {
+            while let Some((event, size)) = RendererEvent::decode(&self.event_buffer)? {
+                self.event_buffer.drain(..size);
+                match event {
+                    Some(event) => {
+                        if let RendererEvent::Capabilities(capabilities) = event {
+                            info!("🎨 Renderer capabilities: {:?}", capabilities);
+                            self.capabilities = capabilities;
+                        }
+                        return Ok(event);
+                    }
+                    None => debug!("Skipping unknown renderer event"),
+                }
+            }
+
+            let stream = self.connection()?;
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(WinpipeError::ConnectionClosed);
+            }
+            self.event_buffer.extend_from_slice(&buf[..n]);
+        }
     }
 
     /// Check if connected
     pub fn is_connected(&self) -> bool {
-        self.stream.is_some()
+        self.connection.is_some()
     }
 
     /// Disconnect
     pub fn disconnect(&mut self) {
-        self.stream = None;
+        self.connection = None;
+        self.event_buffer.clear();
     }
 }
 
+/// Largest frame the decoder buffers: 8192x8192 pixels of 4 bytes
+pub const MAX_FRAME_SIZE: usize = 256 << 20;
+
+/// Data the decoder had to drop
+///
+/// After any of these the decoder has resynced at the next known magic.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Bytes that do not start a known message
+    #[error("skipped {skipped} bytes without a known magic")]
+    BadMagic { skipped: usize },
+    /// The stream ended inside a message
+    #[error("stream ended {len} bytes into a {} message", .magic.escape_ascii())]
+    Truncated { magic: [u8; 4], len: usize },
+    /// A frame larger than `MAX_FRAME_SIZE`
+    #[error("{} message of {size} bytes exceeds {limit}", .magic.escape_ascii())]
+    Oversized { magic: [u8; 4], size: usize, limit: usize },
+    /// A frame whose data does not match its checksum
+    #[error("frame checksum {actual:016x} does not match {expected:016x}")]
+    Checksum { expected: u64, actual: u64 },
+    /// A message that does not decode, such as an unsupported version
+    #[error("malformed {} message: {reason}", .magic.escape_ascii())]
+    Malformed { magic: [u8; 4], reason: String },
+}
+
+/// Errors a decoder has run into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeErrors {
+    pub bad_magic: u64,
+    pub truncated: u64,
+    pub oversized: u64,
+    pub malformed: u64,
+    pub checksum: u64,
+    /// Bytes dropped for all of them
+    pub skipped_bytes: u64,
+}
+
 /// Frame decoder for receiving frames (used by win-way)
 pub struct FrameDecoder {
     buffer: Vec<u8>,
+    errors: DecodeErrors,
+    /// Panic on decode errors instead of skipping the bad data
+    strict: bool,
 }
 
 impl FrameDecoder {
     pub fn new() -> Self {
         Self {
             buffer: Vec::with_capacity(1024 * 1024), // 1MB initial
+            errors: DecodeErrors::default(),
+            strict: false,
         }
     }
 
+    /// A decoder for tests: `decode` and `decode_message` panic on the
+    /// first decode error rather than hiding the corruption
+    pub fn strict() -> Self {
+        Self { strict: true, ..Self::new() }
+    }
+
     /// Add data to buffer
     pub fn push(&mut self, data: &[u8]) {
         self.buffer.extend_from_slice(data);
     }
 
-    /// Try to decode next frame
+    /// Errors run into so far
+    pub fn errors(&self) -> DecodeErrors {
+        self.errors
+    }
+
+    /// Try to decode next frame, skipping other message types
     pub fn decode(&mut self) -> Option<RenderFrame> {
-        if self.buffer.len() < HEADER_SIZE {
-            return None;
+        loop {
+            match self.decode_message()? {
+                RenderMessage::Frame(frame) => return Some(frame),
+                _ => continue,
+            }
         }
+    }
 
-        // Check magic
-        if &self.buffer[0..4] != FRAME_MAGIC {
-            // Skip to find next magic
-            if let Some(pos) = self.find_magic() {
-                self.buffer.drain(..pos);
-            } else {
-                self.buffer.clear();
+    /// Try to decode the next message of any type, logging and skipping
+    /// data that does not decode
+    ///
+    /// # Panics
+    ///
+    /// On a decode error, if the decoder is strict.
+    pub fn decode_message(&mut self) -> Option<RenderMessage> {
+        loop {
+            match self.try_decode() {
+                Ok(message) => return message,
+                Err(e) if self.strict => panic!("render stream corrupt: {}", e),
+                Err(e) => warn!("Render stream: {}", e),
             }
-            return None;
         }
+    }
+
+    /// Data left over once the stream has ended
+    pub fn finish(&mut self) -> std::result::Result<(), DecodeError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let len = self.buffer.len();
+        let mut magic = [0; 4];
+        magic[..len.min(4)].copy_from_slice(&self.buffer[..len.min(4)]);
+        self.buffer.clear();
+        Err(self.dropped(DecodeError::Truncated { magic, len }, len))
+    }
 
-        // Get data size
-        let data_size = u32::from_le_bytes([
-            self.buffer[16], self.buffer[17], self.buffer[18], self.buffer[19]
-        ]) as usize;
+    /// Try to decode the next message of any type
+    ///
+    /// `Ok(None)` means more data is needed. After an error the bad data
+    /// has been dropped and decoding can go on.
+    pub fn try_decode(&mut self) -> std::result::Result<Option<RenderMessage>, DecodeError> {
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+        let magic: [u8; 4] = self.buffer[0..4].try_into().unwrap();
+        match &magic {
+            IME_MAGIC => self.fixed(IME_SIZE, ImeState::decode).map(|m| m.map(RenderMessage::Ime)),
+            SHORTCUTS_INHIBIT_MAGIC => {
+                self.fixed(SHORTCUTS_INHIBIT_SIZE, ShortcutsInhibit::decode).map(|m| m.map(RenderMessage::ShortcutsInhibit))
+            }
+            PRESENTATION_HINT_MAGIC => {
+                self.fixed(PRESENTATION_HINT_SIZE, PresentationHint::decode).map(|m| m.map(RenderMessage::PresentationHint))
+            }
+            WINDOW_TARGET_MAGIC => self.fixed(WINDOW_TARGET_SIZE, WindowTarget::decode).map(|m| m.map(RenderMessage::WindowTarget)),
+            COLOR_SPACE_MAGIC => self.fixed(COLOR_SPACE_SIZE, ColorSpace::decode).map(|m| m.map(RenderMessage::ColorSpace)),
+            OPACITY_MAGIC => self.fixed(OPACITY_SIZE, WindowOpacity::decode).map(|m| m.map(RenderMessage::Opacity)),
+            STATS_OVERLAY_MAGIC => self.fixed(STATS_OVERLAY_SIZE, StatsOverlay::decode).map(|m| m.map(RenderMessage::StatsOverlay)),
+            HELLO_MAGIC => self.fixed(HELLO_SIZE, Hello::decode).map(|m| m.map(RenderMessage::Hello)),
+            OPERATION_MAGIC => self.fixed(OPERATION_SIZE, InteractiveOp::decode).map(|m| m.map(RenderMessage::Interactive)),
+            CURSOR_MAGIC => self.sized(CursorUpdate::decode).map(|m| m.map(RenderMessage::Cursor)),
+            WINDOW_ICON_MAGIC => self.sized(WindowIcon::decode).map(|m| m.map(RenderMessage::WindowIcon)),
+            CONSTRAINT_MAGIC => self.sized(PointerConstraint::decode).map(|m| m.map(RenderMessage::PointerConstraint)),
+            INPUT_REGION_MAGIC => self.sized(InputRegion::decode).map(|m| m.map(RenderMessage::InputRegion)),
+            CHECKSUM_MAGIC => {
+                // Checksums cover composited frames, which are never larger
+                let max_size = (MAX_SURFACE_SIZE, MAX_SURFACE_SIZE);
+                let decoded = BufferChecksum::decode(&self.buffer[4..], max_size)
+                    .map(|checksum| checksum.map(|(checksum, size)| (checksum, 4 + size)));
+                self.sized(|_| decoded).map(|m| m.map(RenderMessage::Checksum))
+            }
+            FRAME_DELTA_MAGIC => {
+                // The delta follows the magic
+                let decoded = BufferDelta::decode(&self.buffer[4..], MAX_FRAME_SIZE as u64)
+                    .map(|delta| delta.map(|(delta, size)| (delta, 4 + size)));
+                self.sized(|_| decoded).map(|m| m.map(RenderMessage::FrameDelta))
+            }
+            FRAME_UPDATE_MAGIC => self.sized(FrameUpdate::decode).map(|m| m.map(RenderMessage::FrameUpdate)),
+            WINDOW_MAGIC => match WindowInfo::decode(&self.buffer) {
+                Ok((info, size)) => {
+                    self.buffer.drain(..size);
+                    Ok(Some(RenderMessage::Window(info)))
+                }
+                Err(_) => Ok(None), // Need more data
+            },
+            FRAME_MAGIC => {
+                if self.buffer.len() < HEADER_SIZE {
+                    return Ok(None);
+                }
+                let field = |range: std::ops::Range<usize>| u32::from_le_bytes(self.buffer[range].try_into().unwrap()) as usize;
+                let compressed = field(4..8) == COMPRESSED_FRAME_VERSION as usize;
+                if compressed && self.buffer.len() < COMPRESSED_HEADER_SIZE {
+                    return Ok(None);
+                }
+                // Compressed data must not unpack beyond the limit either
+                let size = if compressed { field(32..36).max(field(48..52)) } else { field(32..36) };
+                if size > MAX_FRAME_SIZE {
+                    self.buffer.drain(..4);
+                    let error = DecodeError::Oversized { magic, size, limit: MAX_FRAME_SIZE };
+                    return Err(self.resync(error, 4));
+                }
+                let expected = u64::from_le_bytes(self.buffer[36..44].try_into().unwrap());
+                let buffered = self.buffer.len();
+                let Some(frame) = self.sized(RenderFrame::decode)? else {
+                    return Ok(None);
+                };
+                let sent = buffered - self.buffer.len();
+                let actual = if expected == 0 { 0 } else { frame_hash(&frame.data) };
+                if actual != expected {
+                    return Err(self.dropped(DecodeError::Checksum { expected, actual }, sent));
+                }
+                Ok(Some(RenderMessage::Frame(frame)))
+            }
+            _ => {
+                let skipped = self.find_magic().unwrap_or(self.buffer.len() - 3);
+                self.buffer.drain(..skipped);
+                Err(self.dropped(DecodeError::BadMagic { skipped }, skipped))
+            }
+        }
+    }
 
-        let total_size = HEADER_SIZE + data_size;
-        if self.buffer.len() < total_size {
-            return None; // Need more data
+    /// Decode a message of `size` bytes once it is all there
+    fn fixed<T>(&mut self, size: usize, decode: impl FnOnce(&[u8]) -> Result<T>) -> std::result::Result<Option<T>, DecodeError> {
+        if self.buffer.len() < size {
+            return Ok(None);
         }
+        let decoded = decode(&self.buffer[..size]);
+        let magic = self.buffer[0..4].try_into().unwrap();
+        self.buffer.drain(..size);
+        decoded.map(Some).map_err(|e| self.dropped(DecodeError::Malformed { magic, reason: e.to_string() }, size))
+    }
 
-        // Decode frame
-        match RenderFrame::decode(&self.buffer[..total_size]) {
-            Ok(frame) => {
-                self.buffer.drain(..total_size);
-                Some(frame)
+    /// Decode a message that tells its own size
+    fn sized<T>(&mut self, decode: impl FnOnce(&[u8]) -> Result<Option<(T, usize)>>) -> std::result::Result<Option<T>, DecodeError> {
+        match decode(&self.buffer) {
+            Ok(Some((message, size))) => {
+                self.buffer.drain(..size);
+                Ok(Some(message))
             }
-            Err(_) => {
-                self.buffer.drain(..4); // Skip bad magic
-                None
+            Ok(None) => Ok(None), // Need more data
+            Err(e) => {
+                let magic = self.buffer[0..4].try_into().unwrap();
+                self.buffer.drain(..4);
+                Err(self.resync(DecodeError::Malformed { magic, reason: e.to_string() }, 4))
             }
         }
     }
 
+    /// Drop everything up to the next magic after a message whose end
+    /// is unknown; `dropped` bytes are gone already
+    fn resync(&mut self, error: DecodeError, dropped: usize) -> DecodeError {
+        let skipped = self.find_magic().unwrap_or(self.buffer.len().saturating_sub(3));
+        self.buffer.drain(..skipped);
+        self.dropped(error, dropped + skipped)
+    }
+
+    /// Count an error that dropped `bytes`
+    fn dropped(&mut self, error: DecodeError, bytes: usize) -> DecodeError {
+        match error {
+            DecodeError::BadMagic { .. } => self.errors.bad_magic += 1,
+            DecodeError::Truncated { .. } => self.errors.truncated += 1,
+            DecodeError::Oversized { .. } => self.errors.oversized += 1,
+            DecodeError::Malformed { .. } => self.errors.malformed += 1,
+            DecodeError::Checksum { .. } => self.errors.checksum += 1,
+        }
+        self.errors.skipped_bytes += bytes as u64;
+        error
+    }
+
     fn find_magic(&self) -> Option<usize> {
         self.buffer.windows(4)
-            .position(|w| w == FRAME_MAGIC)
+            .position(|w| {
+                w == FRAME_MAGIC || w == WINDOW_MAGIC || w == OPERATION_MAGIC || w == INPUT_REGION_MAGIC
+                    || w == CURSOR_MAGIC || w == IME_MAGIC || w == CONSTRAINT_MAGIC || w == SHORTCUTS_INHIBIT_MAGIC
+                    || w == PRESENTATION_HINT_MAGIC || w == WINDOW_TARGET_MAGIC || w == WINDOW_ICON_MAGIC
+                    || w == COLOR_SPACE_MAGIC || w == OPACITY_MAGIC || w == FRAME_DELTA_MAGIC || w == CHECKSUM_MAGIC
+                    || w == STATS_OVERLAY_MAGIC || w == HELLO_MAGIC || w == FRAME_UPDATE_MAGIC
+            })
     }
 }
 
@@ -217,24 +2395,69 @@ mod tests {
 
     #[test]
     fn test_frame_encode_decode() {
-        let frame = RenderFrame::new(
+        let mut frame = RenderFrame::new(
             100, 100,
             PixelFormat::ARGB8888,
             vec![0xFF; 100 * 100 * 4],
         );
+        frame.surface_id = 10;
+        frame.sequence = 1 << 40;
+        frame.damage = Some(vec![Rect::new(0, 10, 100, 20)]);
 
         let encoded = frame.encode();
-        let decoded = RenderFrame::decode(&encoded).unwrap();
+        let (decoded, size) = RenderFrame::decode(&encoded).unwrap().unwrap();
 
+        assert_eq!(size, encoded.len());
+        assert_eq!((decoded.surface_id, decoded.sequence), (10, 1 << 40));
         assert_eq!(decoded.width, 100);
         assert_eq!(decoded.height, 100);
         assert_eq!(decoded.format, PixelFormat::ARGB8888);
+        assert_eq!(decoded.damage, Some(vec![Rect::new(0, 10, 100, 20)]));
         assert_eq!(decoded.data.len(), 100 * 100 * 4);
+        assert!(RenderFrame::decode(&encoded[..HEADER_SIZE + 8]).unwrap().is_none());
+
+        // Other versions are rejected
+        let mut other = encoded.clone();
+        other[4] = 1;
+        assert!(RenderFrame::decode(&other).is_err());
+
+        // Row padding is dropped
+        let padded = RenderFrame::from_strided(1, 2, PixelFormat::XRGB8888, 8, &[1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8]).unwrap();
+        assert_eq!(padded.data, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(RenderFrame::from_strided(2, 2, PixelFormat::XRGB8888, 8, &[0; 12]).is_none());
+    }
+
+    #[test]
+    fn test_yuv_frame_planes() {
+        // 3x3 pixels have 2x2 chroma samples
+        assert_eq!(PixelFormat::NV12.frame_size(3, 3), Some(9 + 8));
+        assert_eq!(PixelFormat::I420.planes(3, 3), vec![(3, 3), (2, 2), (2, 2)]);
+
+        let (y, u, v) = ([1; 12], [2; 8], [3; 8]);
+        let mut frame = RenderFrame::from_planes(3, 3, PixelFormat::I420, &[(&y, 4), (&u, 4), (&v, 4)]).unwrap();
+        assert_eq!(frame.data, [&[1; 9][..], &[2; 4], &[3; 4]].concat());
+        assert!(RenderFrame::from_planes(3, 3, PixelFormat::I420, &[(&y, 4), (&u, 4)]).is_none());
+
+        let (decoded, _) = RenderFrame::decode(&frame.encode()).unwrap().unwrap();
+        assert_eq!((decoded.format, &decoded.data), (PixelFormat::I420, &frame.data));
+        let delta = BufferDelta { buffer_id: 0, regions: Vec::new(), total_bytes: 0 };
+        assert!(frame.apply_delta(&delta).is_err());
+    }
+
+    #[test]
+    fn test_renderer_addr_parse() {
+        assert_eq!("127.0.0.1:9999".parse(), Ok(RendererAddr::Tcp("127.0.0.1:9999".parse().unwrap())));
+        assert_eq!("shm:win-way".parse(), Ok(RendererAddr::Shm("win-way".to_string())));
+        assert_eq!(RendererAddr::Shm("win-way".to_string()).to_string(), "shm:win-way");
+        assert_eq!(r"\\.\pipe\winway".parse(), Ok(RendererAddr::Pipe(r"\\.\pipe\winway".to_string())));
+        assert!("shm:".parse::<RendererAddr>().is_err());
+        assert!(r"\\.\pipe\".parse::<RendererAddr>().is_err());
+        assert!("localhost".parse::<RendererAddr>().is_err());
     }
 
     #[test]
     fn test_frame_decoder_streaming() {
-        let mut decoder = FrameDecoder::new();
+        let mut decoder = FrameDecoder::strict();
         
         let frame = RenderFrame::new(10, 10, PixelFormat::XRGB8888, vec![0u8; 400]);
         let data = frame.encode();
@@ -248,4 +2471,472 @@ mod tests {
         let decoded = decoder.decode().unwrap();
         assert_eq!(decoded.width, 10);
     }
+
+    #[test]
+    fn test_frame_decoder_reports_errors() {
+        let frame = RenderFrame::new(1, 1, PixelFormat::ARGB8888, vec![1; 4]).encode();
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"junk");
+        decoder.push(&frame);
+        assert_eq!(decoder.try_decode().unwrap_err(), DecodeError::BadMagic { skipped: 4 });
+        assert!(matches!(decoder.try_decode(), Ok(Some(RenderMessage::Frame(_)))));
+
+        // A frame claiming more than the limit is dropped up to the next message
+        let mut oversized = frame.clone();
+        oversized[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        decoder.push(&oversized);
+        decoder.push(&frame);
+        assert!(matches!(decoder.try_decode(), Err(DecodeError::Oversized { size, .. }) if size == u32::MAX as usize));
+        assert!(matches!(decoder.try_decode(), Ok(Some(RenderMessage::Frame(_)))));
+
+        // Other versions are malformed; the lenient decoder skips past them
+        let mut other = frame.clone();
+        other[4] = 1;
+        decoder.push(&other);
+        decoder.push(&frame);
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Frame(_))));
+
+        decoder.push(&frame[..10]);
+        assert_eq!(decoder.finish(), Err(DecodeError::Truncated { magic: *FRAME_MAGIC, len: 10 }));
+        let errors = decoder.errors();
+        assert_eq!((errors.bad_magic, errors.oversized, errors.malformed, errors.truncated), (1, 1, 1, 1));
+        assert_eq!(errors.skipped_bytes, (4 + oversized.len() + other.len() + 10) as u64);
+    }
+
+    #[test]
+    fn test_frame_checksum_verified() {
+        let frame = RenderFrame::new(1, 1, PixelFormat::ARGB8888, vec![1; 4]);
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&frame.encode_with(true, false));
+        assert!(decoder.decode().is_some());
+
+        // Flipped pixel bits are caught; frames without a checksum pass
+        let mut corrupt = frame.encode_with(true, false);
+        *corrupt.last_mut().unwrap() ^= 0x10;
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&corrupt);
+        decoder.push(&frame.encode());
+        assert!(matches!(decoder.try_decode(), Err(DecodeError::Checksum { actual, .. }) if actual != 0));
+        assert!(matches!(decoder.try_decode(), Ok(Some(RenderMessage::Frame(_)))));
+        assert_eq!(decoder.errors().checksum, 1);
+    }
+
+    #[test]
+    fn test_compressed_frame_roundtrip() {
+        let mut frame = RenderFrame::new(64, 64, PixelFormat::XRGB8888, vec![0x40; 64 * 64 * 4]);
+        frame.damage = Some(vec![Rect::new(0, 0, 8, 8)]);
+        let encoded = frame.encode_with(true, true);
+        assert!(encoded.len() < frame.data.len() / 10);
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&encoded);
+        let decoded = decoder.decode().unwrap();
+        assert_eq!((decoded.data, decoded.damage), (frame.data, frame.damage));
+
+        // Data that does not shrink goes out as is
+        let noise: Vec<u8> = (0..64u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let frame = RenderFrame::new(4, 4, PixelFormat::XRGB8888, noise);
+        assert_eq!(frame.encode_with(false, true), frame.encode());
+        assert_eq!(frame.encode()[4..8], FRAME_VERSION.to_le_bytes());
+
+        // Data of another size than the frame is refused, compressed or not
+        let frame = RenderFrame::new(64, 63, PixelFormat::XRGB8888, vec![0x40; 64 * 64 * 4]);
+        assert!(RenderFrame::decode(&frame.encode()).is_err());
+        assert!(RenderFrame::decode(&frame.encode_with(false, true)).is_err());
+        let huge = RenderFrame::new(u32::MAX, u32::MAX, PixelFormat::ABGR16161616F, vec![0x40; 64]);
+        assert!(RenderFrame::decode(&huge.encode_with(false, true)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression_needs_lz4_capability() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = RenderClient::new(RendererAddr::Tcp(listener.local_addr().unwrap()));
+        client.set_frame_compression(true);
+        client.connect().await.unwrap();
+        let (mut renderer, _) = listener.accept().await.unwrap();
+
+        async fn receive(renderer: &mut tokio::net::TcpStream, len: usize) -> Vec<u8> {
+            let mut buf = vec![0u8; len];
+            renderer.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+        assert_eq!(receive(&mut renderer, HELLO_SIZE).await, Hello { version: FRAME_VERSION }.encode());
+
+        // Renderers that did not report LZ4 get uncompressed frames
+        let frame = RenderFrame::new(64, 64, PixelFormat::XRGB8888, vec![0x40; 64 * 64 * 4]);
+        client.send_frame(&frame).await.unwrap();
+        assert_eq!(receive(&mut renderer, frame.encode().len()).await, frame.encode());
+
+        let capabilities = RendererCapabilities { flags: capability_flags::LZ4, ..Default::default() };
+        RenderSink::write_all(&mut renderer, &RendererEvent::Capabilities(capabilities).encode()).await.unwrap();
+        client.next_event().await.unwrap();
+        client.send_frame(&frame).await.unwrap();
+        let compressed = frame.encode_with(false, true);
+        assert_eq!(receive(&mut renderer, compressed.len()).await, compressed);
+    }
+
+    #[test]
+    #[should_panic(expected = "render stream corrupt")]
+    fn test_strict_decoder_panics() {
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(b"junkjunk");
+        decoder.decode_message();
+    }
+
+    #[test]
+    fn test_frame_delta_patches_frame() {
+        use crate::buffer::{DeltaRegion, MirrorBuffer};
+
+        let delta = BufferDelta {
+            buffer_id: 10,
+            regions: vec![DeltaRegion { x: 0, y: 1, width: 2, height: 1, data: vec![9; 8], xor: false }],
+            total_bytes: 8,
+        };
+        let mut decoder = FrameDecoder::strict();
+        let data = RenderMessage::FrameDelta(delta.clone()).encode();
+        decoder.push(&data[..12]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[12..]);
+        let Some(RenderMessage::FrameDelta(decoded)) = decoder.decode_message() else {
+            panic!("expected a frame delta");
+        };
+        assert_eq!(decoded, delta);
+
+        let mut frame = RenderFrame::new(2, 2, PixelFormat::ARGB8888, vec![0; 16]);
+        frame.apply_delta(&decoded).unwrap();
+        assert_eq!(frame.data, [[0; 8], [9; 8]].concat());
+
+        // The sender's checksum matches the patched frame
+        let sent = MirrorBuffer::from_data(1, 2, 2, 4, 8, frame.data.clone()).checksum(0);
+        decoder.push(&RenderMessage::Checksum(sent.clone()).encode());
+        let Some(RenderMessage::Checksum(received)) = decoder.decode_message() else {
+            panic!("expected a checksum");
+        };
+        assert_eq!(received, frame.checksum(1, 0));
+    }
+
+    #[test]
+    fn test_renderer_event_decode() {
+        let data = RendererEvent::Close.encode();
+        assert_eq!(RendererEvent::decode(&data[..8]).unwrap(), None);
+        assert_eq!(
+            RendererEvent::decode(&data).unwrap(),
+            Some((Some(RendererEvent::Close), EVENT_HEADER_SIZE))
+        );
+        assert!(RendererEvent::decode(b"XXXXXXXXXXXX").is_err());
+
+        let data = RendererEvent::Resize { width: 640, height: 480 }.encode();
+        assert_eq!(
+            RendererEvent::decode(&data).unwrap(),
+            Some((Some(RendererEvent::Resize { width: 640, height: 480 }), EVENT_HEADER_SIZE + 8))
+        );
+        let data = RendererEvent::InteractiveBegin.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(RendererEvent::InteractiveBegin), EVENT_HEADER_SIZE)));
+
+        let state = RendererEvent::WindowState { state: window_state::MAXIMIZED };
+        let data = state.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(state), EVENT_HEADER_SIZE + 4)));
+
+        let preedit = RendererEvent::ImePreedit { text: "にほん".to_string(), cursor_begin: 9, cursor_end: 9 };
+        let data = preedit.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(preedit), data.len())));
+
+        let data = RendererEvent::Resync { buffer_id: 10 }.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(RendererEvent::Resync { buffer_id: 10 }), EVENT_HEADER_SIZE + 4)));
+        let ack = RendererEvent::FrameAck { sequence: (1 << 40) + 3 };
+        assert_eq!(RendererEvent::decode(&ack.encode()).unwrap(), Some((Some(ack), EVENT_HEADER_SIZE + 8)));
+        let window = RendererEvent::Window { window: 2, surface: 10 };
+        assert_eq!(RendererEvent::decode(&window.encode()).unwrap(), Some((Some(window), EVENT_HEADER_SIZE + 8)));
+    }
+
+    #[test]
+    fn test_input_region_roundtrip() {
+        let mut decoder = FrameDecoder::strict();
+        let region = InputRegion { rects: Some(vec![Rect::new(0, 0, 100, 20), Rect::new(10, 20, 80, 50)]) };
+        decoder.push(&region.encode());
+        decoder.push(&InputRegion { rects: None }.encode());
+
+        match decoder.decode_message() {
+            Some(RenderMessage::InputRegion(decoded)) => assert_eq!(decoded, region),
+            other => panic!("expected input region, got {:?}", other),
+        }
+        assert!(matches!(
+            decoder.decode_message(),
+            Some(RenderMessage::InputRegion(InputRegion { rects: None }))
+        ));
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let mut decoder = FrameDecoder::strict();
+        let image = CursorUpdate::Image { hotspot_x: 1, hotspot_y: 2, width: 2, height: 1, data: vec![7; 8] };
+        let encoded = image.encode();
+        // Partial image waits for the rest
+        decoder.push(&encoded[..20]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&encoded[20..]);
+        decoder.push(&CursorUpdate::Named(cursor_shape::TEXT).encode());
+        decoder.push(&CursorUpdate::Position { x: -3, y: 40 }.encode());
+
+        match decoder.decode_message() {
+            Some(RenderMessage::Cursor(decoded)) => assert_eq!(decoded, image),
+            other => panic!("expected cursor, got {:?}", other),
+        }
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Cursor(CursorUpdate::Named(9)))));
+        assert!(matches!(
+            decoder.decode_message(),
+            Some(RenderMessage::Cursor(CursorUpdate::Position { x: -3, y: 40 }))
+        ));
+        assert_eq!(cursor_shape::to_windows_cursor(cursor_shape::TEXT), 32513);
+    }
+
+    #[test]
+    fn test_pointer_constraint_roundtrip() {
+        let mut decoder = FrameDecoder::strict();
+        let confine = PointerConstraint { kind: constraint_kind::CONFINE, rects: Some(vec![Rect::new(0, 0, 640, 480)]) };
+        decoder.push(&confine.encode());
+        match decoder.decode_message() {
+            Some(RenderMessage::PointerConstraint(decoded)) => assert_eq!(decoded, confine),
+            other => panic!("expected pointer constraint, got {:?}", other),
+        }
+
+        let motion = RendererEvent::RelativeMotion { time_usec: 1 << 40, dx: 1.5, dy: -0.25, dx_unaccel: 3.0, dy_unaccel: -0.5 };
+        let data = motion.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(motion), data.len())));
+    }
+
+    #[test]
+    fn test_presentation_hint_roundtrip() {
+        let hint = PresentationHint { tearing: true, content_type: content_type::GAME };
+        let mut decoder = FrameDecoder::strict();
+        let data = hint.encode();
+        decoder.push(&data[..6]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[6..]);
+        match decoder.decode_message() {
+            Some(RenderMessage::PresentationHint(decoded)) => assert_eq!(decoded, hint),
+            other => panic!("expected presentation hint, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_color_space_roundtrip() {
+        let space = crate::color::ImageDescription::scrgb().color_space();
+        let mut decoder = FrameDecoder::strict();
+        let data = space.encode();
+        assert_eq!(data.len(), COLOR_SPACE_SIZE);
+        decoder.push(&data[..30]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[30..]);
+        match decoder.decode_message() {
+            Some(RenderMessage::ColorSpace(decoded)) => assert_eq!(decoded, space),
+            other => panic!("expected color space, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_frame_update_patches_frame() {
+        use crate::buffer::DeltaRegion;
+
+        let delta = BufferDelta {
+            buffer_id: 10,
+            regions: vec![DeltaRegion { x: 1, y: 1, width: 1, height: 1, data: vec![7; 4], xor: false }],
+            total_bytes: 4,
+        };
+        let update = FrameUpdate { sequence: 42, width: 2, height: 2, delta };
+        let data = RenderMessage::FrameUpdate(update.clone()).encode();
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&data[..FRAME_UPDATE_HEADER_SIZE + 4]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[FRAME_UPDATE_HEADER_SIZE + 4..]);
+        let Some(RenderMessage::FrameUpdate(decoded)) = decoder.decode_message() else {
+            panic!("expected a frame update");
+        };
+        assert_eq!(decoded, update);
+
+        // Only the changed pixel is carried, and the frame takes the
+        // update's sequence
+        let mut frame = RenderFrame::new(2, 2, PixelFormat::ARGB8888, vec![0; 16]);
+        frame.apply_update(&decoded).unwrap();
+        assert_eq!(frame.data, [&[0; 12][..], &[7; 4]].concat());
+        assert_eq!(frame.sequence, 42);
+
+        // An update for another size leaves the frame alone
+        let mut other = RenderFrame::new(4, 1, PixelFormat::ARGB8888, vec![0; 16]);
+        assert!(other.apply_update(&decoded).is_err());
+        assert_eq!((other.data, other.sequence), (vec![0; 16], 0));
+    }
+
+    #[tokio::test]
+    async fn test_frame_update_falls_back_to_delta() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = RenderClient::new(RendererAddr::Tcp(listener.local_addr().unwrap()));
+        client.connect().await.unwrap();
+        let (mut renderer, _) = listener.accept().await.unwrap();
+
+        async fn receive(renderer: &mut TcpStream) -> RenderMessage {
+            let mut decoder = FrameDecoder::strict();
+            let mut buf = [0u8; 256];
+            loop {
+                while let Some(message) = decoder.decode_message() {
+                    if !matches!(message, RenderMessage::Hello(_)) {
+                        return message;
+                    }
+                }
+                let n = renderer.read(&mut buf).await.unwrap();
+                decoder.push(&buf[..n]);
+            }
+        }
+
+        let delta = BufferDelta { buffer_id: 10, regions: Vec::new(), total_bytes: 0 };
+        let update = RenderMessage::FrameUpdate(FrameUpdate { sequence: 3, width: 2, height: 2, delta: delta.clone() });
+
+        // Until the renderer says it understands updates
+        client.send(&update).await.unwrap();
+        assert!(matches!(receive(&mut renderer).await, RenderMessage::FrameDelta(received) if received == delta));
+
+        let caps = RendererCapabilities { flags: capability_flags::DAMAGE | capability_flags::UPDATE, ..Default::default() };
+        renderer.write_all(&RendererEvent::Capabilities(caps).encode()).await.unwrap();
+        assert_eq!(client.next_event().await.unwrap(), RendererEvent::Capabilities(caps));
+        client.send(&update).await.unwrap();
+        assert!(matches!(receive(&mut renderer).await, RenderMessage::FrameUpdate(FrameUpdate { sequence: 3, .. })));
+    }
+
+    #[test]
+    fn test_hello_and_capabilities() {
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&Hello { version: FRAME_VERSION }.encode());
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Hello(Hello { version: FRAME_VERSION }))));
+
+        let caps = RendererCapabilities {
+            formats: 1 << PixelFormat::NV12 as u32,
+            max_width: 4096,
+            max_height: 2160,
+            flags: capability_flags::VSYNC,
+        };
+        let event = RendererEvent::Capabilities(caps);
+        let data = event.encode();
+        assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(event), data.len())));
+        assert!(caps.supports(PixelFormat::NV12) && !caps.supports(PixelFormat::ARGB8888));
+        assert!(caps.fits(4096, 100) && !caps.fits(4097, 100));
+        assert!(RendererCapabilities::default().supports(PixelFormat::XRGB8888));
+    }
+
+    #[test]
+    fn test_stats_overlay_roundtrip() {
+        let stats = StatsOverlay {
+            visible: true,
+            frame_rate: 5994,
+            bandwidth: 5_000_000_000,
+            compression: 420,
+            latency_us: 8300,
+        };
+        let mut decoder = FrameDecoder::strict();
+        let data = stats.encode();
+        assert_eq!(data.len(), STATS_OVERLAY_SIZE);
+        decoder.push(&data);
+        match decoder.decode_message() {
+            Some(RenderMessage::StatsOverlay(decoded)) => assert_eq!(decoded, stats),
+            other => panic!("expected stats overlay, got {:?}", other),
+        }
+        assert_eq!(stats.summary(), "59.9 fps  5000.0 MB/s  x4.20  8.3 ms");
+    }
+
+    #[test]
+    fn test_window_icon_roundtrip() {
+        let icon = WindowIcon {
+            images: vec![
+                IconImage { width: 1, height: 1, data: vec![1; 4] },
+                IconImage { width: 2, height: 2, data: vec![2; 16] },
+            ],
+        };
+        let mut decoder = FrameDecoder::strict();
+        let data = icon.encode();
+        // Second image still incomplete
+        decoder.push(&data[..24]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[24..]);
+        decoder.push(&WindowIcon::default().encode());
+        match decoder.decode_message() {
+            Some(RenderMessage::WindowIcon(decoded)) => assert_eq!(decoded, icon),
+            other => panic!("expected window icon, got {:?}", other),
+        }
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::WindowIcon(WindowIcon { images })) if images.is_empty()));
+    }
+
+    #[test]
+    fn test_gesture_event_roundtrip() {
+        let events = [
+            RendererEvent::GestureBegin { kind: gesture_kind::PINCH, time: 100, fingers: 2 },
+            RendererEvent::GestureUpdate { kind: gesture_kind::PINCH, time: 116, dx: 0.5, dy: 0.0, scale: 1.25, rotation: -3.0 },
+            RendererEvent::GestureEnd { kind: gesture_kind::PINCH, time: 132, cancelled: true },
+            RendererEvent::Pen(PenSample {
+                time: 140,
+                x: 12.5,
+                y: 40.0,
+                pressure: 512,
+                tilt_x: -30,
+                tilt_y: 15,
+                rotation: 90,
+                flags: pen_flags::IN_RANGE | pen_flags::IN_CONTACT,
+            }),
+            RendererEvent::Presented { time_ns: 5 << 33, refresh_ns: 16_666_666, seq: 1 << 35, flags: 1 },
+        ];
+        for event in events {
+            let data = event.encode();
+            assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(event), data.len())));
+        }
+    }
+
+    #[test]
+    fn test_input_event_roundtrip() {
+        let events = [
+            RendererEvent::PointerMotion { time: 10, x: 100.5, y: -2.25 },
+            RendererEvent::PointerButton { time: 11, button: 0x110, pressed: true },
+            RendererEvent::PointerAxis { time: 12, axis: 0, value: -15.0, value120: -120 },
+            RendererEvent::PointerLeave,
+            RendererEvent::Key { time: 13, key: 30, pressed: false },
+            RendererEvent::Touch { kind: touch_kind::DOWN, time: 14, id: 3, x: 8.0, y: 9.5 },
+        ];
+        for event in events {
+            let data = event.encode();
+            assert_eq!(RendererEvent::decode(&data).unwrap(), Some((Some(event), data.len())));
+        }
+    }
+
+    #[test]
+    fn test_interactive_op_roundtrip() {
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&InteractiveOp::Resize { edges: 10 }.encode());
+        assert!(matches!(
+            decoder.decode_message(),
+            Some(RenderMessage::Interactive(InteractiveOp::Resize { edges: 10 }))
+        ));
+    }
+
+    #[test]
+    fn test_window_info_streaming() {
+        let mut decoder = FrameDecoder::strict();
+        let info = WindowInfo {
+            title: "Terminal — foot".to_string(),
+            app_id: "foot".to_string(),
+            min_width: 200,
+            max_height: 900,
+            state: window_state::MAXIMIZED,
+            ..Default::default()
+        };
+        let frame = RenderFrame::new(2, 2, PixelFormat::ARGB8888, vec![0u8; 16]);
+        let data = [info.encode(), frame.encode()].concat();
+
+        decoder.push(&data[..7]);
+        assert!(decoder.decode_message().is_none());
+        decoder.push(&data[7..]);
+
+        match decoder.decode_message() {
+            Some(RenderMessage::Window(decoded)) => assert_eq!(decoded, info),
+            other => panic!("expected window info, got {:?}", other),
+        }
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Frame(_))));
+    }
 }
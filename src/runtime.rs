@@ -0,0 +1,800 @@
+//! Shared Compositor Runtime
+//!
+//! All clients of a server share one runtime: the outputs, the settings
+//! every compositor is created with, the seat's keyboard focus and a single
+//! renderer connection. Each client keeps its own `Compositor`, which is its
+//! object namespace, and owns a renderer window for each of its toplevels,
+//! identified by its client ID and the toplevel's root wl_surface:
+//!
+//! - Render messages are preceded by a `WindowTarget` whenever the
+//!   connection switches to another window. A window is destroyed when its
+//!   toplevel goes away, and all of a client's when the client disconnects.
+//! - The renderer prefixes events with a window event, and the runtime
+//!   routes them to the client owning that window, along with the
+//!   toplevel's wl_surface.
+//!
+//! Clients also publish their mapped toplevels, and the combined list of
+//! all clients is available to each for ext-foreign-toplevel-list. Requests
+//! on the local control socket (see `crate::control`) go to the client
+//! owning the toplevel they name.
+//!
+//! If the renderer goes away, the runtime reconnects with exponential
+//! backoff. Meanwhile frames fold into each window's last frame and other
+//! messages wait in a bounded queue; once reconnected, every window gets
+//! its metadata and last frame again before the queued messages, so it
+//! shows up right away.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::compositor::{Compositor, DecorationMode, GlobalConfig, ResourceLimits};
+use crate::control::ControlRequest;
+use crate::error::{Result, WinpipeError};
+use crate::foreign_toplevel::ForeignToplevel;
+use crate::introspect::ToplevelInfo;
+use crate::output::Monitor;
+use crate::ratelimit::RateLimits;
+use crate::render::{
+    RenderClient, RenderFrame, RenderMessage, RendererAddr, RendererCapabilities, RendererEvent, WindowInfo, WindowTarget,
+};
+
+/// Identifies a client
+pub type ClientId = u32;
+
+/// Identifies a renderer window: its client and the root wl_surface of
+/// the toplevel it shows
+pub type WindowId = (ClientId, u32);
+
+/// Settings shared by every client's compositor
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    /// Address of the renderer (None = headless)
+    pub renderer: Option<RendererAddr>,
+    pub decorations: DecorationMode,
+    pub globals: GlobalConfig,
+    pub limits: ResourceLimits,
+    /// Frames a client may send before the renderer presents; 0 = no limit
+    pub max_frames_in_flight: u32,
+    /// Pace each window's frames to the monitor refresh, capped further
+    /// at the given rate unless 0; None sends every commit's frame
+    pub frame_pacing: Option<u32>,
+    /// Send frames with a checksum the renderer verifies
+    pub frame_checksums: bool,
+    /// LZ4 compress frame data, if the renderer reports LZ4
+    pub frame_compression: bool,
+    /// Bytes and messages each client may send per second
+    pub rate_limits: RateLimits,
+    /// Clients served at once; 0 = no limit
+    pub max_clients: usize,
+    /// Ping clients silent this long, and disconnect them if the ping
+    /// goes unanswered
+    pub idle_timeout: Option<Duration>,
+    /// How long a session whose connection dropped waits to be resumed
+    pub resume_grace: Duration,
+}
+
+/// First delay before reconnecting to the renderer
+pub const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(100);
+
+/// Longest delay between reconnection attempts
+pub const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// Messages kept for the renderer while it is away, besides frames
+pub const MAX_PENDING_MESSAGES: usize = 256;
+
+/// Work for the renderer connection task
+enum Outgoing {
+    Message(WindowId, RenderMessage),
+    /// A toplevel went away
+    Closed(WindowId),
+    Disconnected(ClientId),
+}
+
+/// What a restarted renderer needs to show the windows again
+#[derive(Default)]
+struct Replay {
+    /// Last frame of each window, with later deltas applied
+    frames: HashMap<WindowId, RenderFrame>,
+    /// Last metadata of each window
+    windows: HashMap<WindowId, WindowInfo>,
+    /// Other messages that came in while disconnected, oldest first
+    pending: VecDeque<(WindowId, RenderMessage)>,
+}
+
+impl Replay {
+    /// Keep what a message changes about the window's picture
+    fn record(&mut self, window: WindowId, message: RenderMessage) {
+        match message {
+            RenderMessage::Frame(frame) => {
+                self.frames.insert(window, frame);
+            }
+            RenderMessage::FrameDelta(delta) => {
+                let Some(frame) = self.frames.get_mut(&window) else {
+                    return;
+                };
+                if let Err(e) = frame.apply_delta(&delta) {
+                    debug!("Dropping the last frame of window {:?}: {}", window, e);
+                    self.frames.remove(&window);
+                }
+            }
+            RenderMessage::FrameUpdate(update) => {
+                let Some(frame) = self.frames.get_mut(&window) else {
+                    return;
+                };
+                if let Err(e) = frame.apply_update(&update) {
+                    debug!("Dropping the last frame of window {:?}: {}", window, e);
+                    self.frames.remove(&window);
+                }
+            }
+            RenderMessage::Window(info) => {
+                self.windows.insert(window, info);
+            }
+            _ => {}
+        }
+    }
+
+    /// Keep a message that cannot be sent now
+    ///
+    /// Frames replace stale ones and checksums are moot after the replay;
+    /// the oldest other message goes once the queue is full.
+    fn hold(&mut self, window: WindowId, message: RenderMessage) {
+        match message {
+            RenderMessage::Frame(_) | RenderMessage::FrameDelta(_) | RenderMessage::FrameUpdate(_) | RenderMessage::Window(_) => {
+                self.record(window, message)
+            }
+            RenderMessage::Checksum(_) => {}
+            message => {
+                if self.pending.len() == MAX_PENDING_MESSAGES {
+                    self.pending.pop_front();
+                }
+                self.pending.push_back((window, message));
+            }
+        }
+    }
+
+    /// Drop the windows `forgotten` matches
+    fn forget(&mut self, forgotten: impl Fn(&WindowId) -> bool) {
+        self.frames.retain(|window, _| !forgotten(window));
+        self.windows.retain(|window, _| !forgotten(window));
+        self.pending.retain(|(window, _)| !forgotten(window));
+    }
+
+    /// Messages to bring a new renderer up to date
+    fn take(&mut self) -> Vec<(WindowId, RenderMessage)> {
+        let mut windows: Vec<WindowId> = self.frames.keys().chain(self.windows.keys()).copied().collect();
+        windows.sort_unstable();
+        windows.dedup();
+        let mut messages = Vec::new();
+        for window in windows {
+            if let Some(info) = self.windows.remove(&window) {
+                messages.push((window, RenderMessage::Window(info)));
+            }
+            if let Some(frame) = self.frames.remove(&window) {
+                messages.push((window, RenderMessage::Frame(frame)));
+            }
+        }
+        messages.extend(self.pending.drain(..));
+        messages
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    next_client: ClientId,
+    /// Event channel of each connected client
+    clients: HashMap<ClientId, mpsc::UnboundedSender<(u32, RendererEvent)>>,
+    /// Control request channel of each connected client
+    requests: HashMap<ClientId, mpsc::UnboundedSender<ControlRequest>>,
+    /// Queue of the renderer connection task, while it runs
+    renderer: Option<mpsc::UnboundedSender<Outgoing>>,
+    /// The renderer is connected, not being reconnected to
+    connected: bool,
+    /// Client whose window has keyboard focus
+    focus: Option<ClientId>,
+    /// Mapped toplevels published by each client
+    published: HashMap<ClientId, Vec<ForeignToplevel>>,
+    /// Source of foreign toplevel identifiers
+    next_identifier: u64,
+    /// The renderer draws the stats overlay
+    stats_overlay: bool,
+    /// What the connected renderer can show
+    capabilities: RendererCapabilities,
+}
+
+impl Shared {
+    /// Toplevels of all clients, in client order
+    fn all_toplevels(&self) -> Vec<ForeignToplevel> {
+        let mut clients: Vec<&ClientId> = self.published.keys().collect();
+        clients.sort_unstable();
+        clients.into_iter().flat_map(|client| self.published[client].iter().cloned()).collect()
+    }
+}
+
+/// State shared by all clients of a server
+pub struct Runtime {
+    config: RuntimeConfig,
+    monitors: watch::Receiver<Vec<Monitor>>,
+    shared: Mutex<Shared>,
+    /// Toplevels of all clients, updated as clients publish theirs
+    toplevels: watch::Sender<Vec<ForeignToplevel>>,
+}
+
+impl Runtime {
+    pub fn new(config: RuntimeConfig, monitors: watch::Receiver<Vec<Monitor>>) -> Arc<Self> {
+        let (toplevels, _) = watch::channel(Vec::new());
+        Arc::new(Self { config, monitors, shared: Mutex::new(Shared::default()), toplevels })
+    }
+
+    pub fn config(&self) -> &RuntimeConfig {
+        &self.config
+    }
+
+    /// Current monitors, updated on display changes
+    pub fn monitors(&self) -> watch::Receiver<Vec<Monitor>> {
+        self.monitors.clone()
+    }
+
+    /// Mapped toplevels of all clients, updated as they change
+    pub fn foreign_toplevels(&self) -> watch::Receiver<Vec<ForeignToplevel>> {
+        self.toplevels.subscribe()
+    }
+
+    /// Replace the toplevels a client published
+    fn publish(&self, client: ClientId, toplevels: Vec<ForeignToplevel>) {
+        let mut shared = self.shared.lock().unwrap();
+        let previous = if toplevels.is_empty() {
+            shared.published.remove(&client)
+        } else {
+            shared.published.insert(client, toplevels.clone())
+        };
+        if previous.unwrap_or_default() != toplevels {
+            self.toplevels.send_replace(shared.all_toplevels());
+        }
+    }
+
+    /// Client whose window has keyboard focus
+    pub fn focused_client(&self) -> Option<ClientId> {
+        self.shared.lock().unwrap().focus
+    }
+
+    /// PNG of a toplevel's current frame, by its foreign toplevel
+    /// identifier; without one, of the focused client's window or else the
+    /// first one published
+    pub async fn screenshot(&self, identifier: Option<String>) -> Result<Vec<u8>> {
+        let (reply, response) = oneshot::channel();
+        {
+            let shared = self.shared.lock().unwrap();
+            let owner = |client: &ClientId| {
+                shared.published[client].iter().any(|t| identifier.as_ref().is_none_or(|id| t.identifier == *id))
+            };
+            let mut clients: Vec<&ClientId> = shared.published.keys().collect();
+            clients.sort_unstable();
+            let client = match &identifier {
+                Some(_) => clients.into_iter().find(|client| owner(client)),
+                None => shared.focus.as_ref().filter(|client| shared.published.contains_key(client)).or(clients.first().copied()),
+            };
+            let Some(requests) = client.and_then(|client| shared.requests.get(client)) else {
+                let message = match &identifier {
+                    Some(identifier) => format!("no toplevel {}", identifier),
+                    None => "no window to capture".to_string(),
+                };
+                return Err(WinpipeError::Protocol(message));
+            };
+            let _ = requests.send(ControlRequest::Screenshot { identifier, reply });
+        }
+        response.await.ok().flatten().ok_or_else(|| WinpipeError::Protocol("window has no content".to_string()))
+    }
+
+    /// Show or hide the stats overlay over every client's windows,
+    /// including clients connecting later
+    pub fn set_stats_overlay(&self, visible: bool) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.stats_overlay = visible;
+        for requests in shared.requests.values() {
+            let _ = requests.send(ControlRequest::StatsOverlay { visible });
+        }
+    }
+
+    /// Connect to the configured renderer and start forwarding
+    ///
+    /// Does nothing without a renderer. If it cannot be reached, the
+    /// error is returned and clients run headless until a retry reaches
+    /// it; a lost connection is retried the same way.
+    pub async fn start_renderer(self: &Arc<Self>) -> Result<()> {
+        let Some(addr) = self.config.renderer.clone() else {
+            return Ok(());
+        };
+        let mut client = RenderClient::new(addr);
+        client.set_frame_checksums(self.config.frame_checksums);
+        client.set_frame_compression(self.config.frame_compression);
+        let result = client.connect().await;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.set_renderer(Some(tx), result.is_ok());
+        let runtime = Arc::clone(self);
+        let mut connected = result.is_ok();
+        tokio::spawn(async move {
+            let mut replay = Replay::default();
+            loop {
+                if !connected {
+                    if !runtime.reconnect(&mut client, &mut rx, &mut replay).await {
+                        break;
+                    }
+                    runtime.shared.lock().unwrap().connected = true;
+                }
+                match runtime.run_renderer(&mut client, &mut rx, &mut replay).await {
+                    Ok(()) => break,
+                    Err(e) => warn!("Renderer connection lost: {}", e),
+                }
+                runtime.shared.lock().unwrap().connected = false;
+                connected = false;
+            }
+            runtime.set_renderer(None, false);
+        });
+        result
+    }
+
+    fn set_renderer(&self, renderer: Option<mpsc::UnboundedSender<Outgoing>>, connected: bool) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.renderer = renderer;
+        shared.connected = connected;
+    }
+
+    /// Retry the renderer with exponential backoff, holding messages
+    /// meanwhile; false once the queue closes
+    async fn reconnect(
+        &self,
+        client: &mut RenderClient,
+        rx: &mut mpsc::UnboundedReceiver<Outgoing>,
+        replay: &mut Replay,
+    ) -> bool {
+        client.disconnect();
+        let mut delay = RECONNECT_DELAY_MIN;
+        loop {
+            let retry = tokio::time::sleep(delay);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    () = &mut retry => break,
+                    outgoing = rx.recv() => match outgoing {
+                        Some(Outgoing::Message(window, message)) => replay.hold(window, message),
+                        Some(Outgoing::Closed(window)) => replay.forget(|held| *held == window),
+                        Some(Outgoing::Disconnected(client)) => replay.forget(|held| held.0 == client),
+                        None => return false,
+                    },
+                }
+            }
+            match client.connect().await {
+                Ok(()) => return true,
+                Err(e) => debug!("Renderer still unavailable: {}", e),
+            }
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+    }
+
+    /// Multiplex the clients' windows over the renderer connection
+    ///
+    /// Starts by replaying what a new renderer is missing. Returns Ok once
+    /// the queue closes.
+    async fn run_renderer(
+        &self,
+        client: &mut RenderClient,
+        rx: &mut mpsc::UnboundedReceiver<Outgoing>,
+        replay: &mut Replay,
+    ) -> Result<()> {
+        // Window the connection currently targets, in each direction
+        let mut target: WindowId = (0, 0);
+        let mut source: WindowId = (0, 0);
+        // Until a new renderer answers the hello
+        self.set_capabilities(client.capabilities());
+        for (window, message) in replay.take() {
+            Self::forward(client, &mut target, window, message, replay).await?;
+        }
+        loop {
+            tokio::select! {
+                outgoing = rx.recv() => {
+                    match outgoing {
+                        None => return Ok(()),
+                        Some(Outgoing::Message(window, message)) => {
+                            Self::forward(client, &mut target, window, message, replay).await?;
+                        }
+                        Some(Outgoing::Closed(window)) => {
+                            replay.forget(|held| *held == window);
+                            let (window, surface) = window;
+                            client.send(&RenderMessage::WindowTarget(WindowTarget { window, surface, destroy: true })).await?;
+                        }
+                        Some(Outgoing::Disconnected(window)) => {
+                            replay.forget(|held| held.0 == window);
+                            client.send(&RenderMessage::WindowTarget(WindowTarget { window, surface: 0, destroy: true })).await?;
+                        }
+                    }
+                }
+                event = client.next_event() => {
+                    match event? {
+                        RendererEvent::Window { window, surface } => source = (window, surface),
+                        RendererEvent::Capabilities(capabilities) => self.set_capabilities(capabilities),
+                        event => self.route_event(source, event),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a window's message, switching the target window first if needed
+    ///
+    /// The message is recorded for a replay even if sending fails, since
+    /// the client considers it sent.
+    async fn forward(
+        client: &mut RenderClient,
+        target: &mut WindowId,
+        window: WindowId,
+        message: RenderMessage,
+        replay: &mut Replay,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        if window != *target {
+            let (client_id, surface) = window;
+            result = client.send(&RenderMessage::WindowTarget(WindowTarget { window: client_id, surface, destroy: false })).await;
+            *target = window;
+        }
+        if result.is_ok() {
+            result = client.send(&message).await;
+        }
+        replay.record(window, message);
+        result
+    }
+
+    /// Tell every client what the renderer can show, if that changed
+    fn set_capabilities(&self, capabilities: RendererCapabilities) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.capabilities == capabilities {
+            return;
+        }
+        shared.capabilities = capabilities;
+        for events in shared.clients.values() {
+            let _ = events.send((0, RendererEvent::Capabilities(capabilities)));
+        }
+    }
+
+    /// Deliver a renderer event to the client owning `window`
+    fn route_event(&self, window: WindowId, event: RendererEvent) {
+        let (client, surface) = window;
+        let mut shared = self.shared.lock().unwrap();
+        if let RendererEvent::Focus { focused } = event {
+            if focused {
+                shared.focus = Some(client);
+            } else if shared.focus == Some(client) {
+                shared.focus = None;
+            }
+        }
+        match shared.clients.get(&client) {
+            Some(events) => {
+                let _ = events.send((surface, event));
+            }
+            None => debug!("Dropping renderer event for unknown window {:?}: {:?}", window, event),
+        }
+    }
+
+    /// Register a new client; fails if `max_clients` are connected already
+    pub fn connect(self: &Arc<Self>) -> Result<ClientSession> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+        let mut shared = self.shared.lock().unwrap();
+        if self.config.max_clients > 0 && shared.clients.len() >= self.config.max_clients {
+            return Err(WinpipeError::Protocol(format!("client limit of {} reached", self.config.max_clients)));
+        }
+        shared.next_client = shared.next_client.wrapping_add(1);
+        let id = shared.next_client;
+        shared.clients.insert(id, tx);
+        shared.requests.insert(id, requests_tx);
+        info!("Client {} connected to the runtime", id);
+        Ok(ClientSession { id, runtime: Arc::clone(self), events: rx, requests: Some(requests), identifiers: HashMap::new() })
+    }
+}
+
+/// A client's handle on the runtime; unregisters the client when dropped
+pub struct ClientSession {
+    id: ClientId,
+    runtime: Arc<Runtime>,
+    events: mpsc::UnboundedReceiver<(u32, RendererEvent)>,
+    requests: Option<mpsc::UnboundedReceiver<ControlRequest>>,
+    /// Foreign toplevel identifier of each xdg_toplevel
+    identifiers: HashMap<u32, String>,
+}
+
+impl ClientSession {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+
+    /// A compositor for the client, with the shared settings applied
+    pub fn compositor(&self) -> Compositor {
+        let config = &self.runtime.config;
+        let monitors = self.runtime.monitors.borrow().clone();
+        let mut compositor = Compositor::with_config(monitors, &config.globals);
+        compositor.set_decoration_mode(config.decorations);
+        compositor.set_limits(config.limits);
+        compositor.set_max_frames_in_flight(config.max_frames_in_flight);
+        compositor.set_frame_pacing(config.frame_pacing);
+        let shared = self.runtime.shared.lock().unwrap();
+        compositor.set_stats_overlay(shared.stats_overlay);
+        compositor.set_renderer_capabilities(shared.capabilities);
+        compositor
+    }
+
+    /// Whether a renderer is connected to show the client's windows
+    pub fn has_renderer(&self) -> bool {
+        self.runtime.shared.lock().unwrap().connected
+    }
+
+    /// Publish the client's toplevels; only mapped ones are listed
+    pub fn publish_toplevels(&mut self, toplevels: &[ToplevelInfo]) {
+        let mapped: Vec<&ToplevelInfo> = toplevels.iter().filter(|t| t.mapped).collect();
+        self.identifiers.retain(|id, _| mapped.iter().any(|t| t.id == *id));
+        let mut published = Vec::with_capacity(mapped.len());
+        for toplevel in mapped {
+            let identifier = self.identifiers.entry(toplevel.id).or_insert_with(|| {
+                let mut shared = self.runtime.shared.lock().unwrap();
+                shared.next_identifier += 1;
+                format!("winpipe-{}", shared.next_identifier)
+            });
+            published.push(ForeignToplevel {
+                identifier: identifier.clone(),
+                title: toplevel.title.clone(),
+                app_id: toplevel.app_id.clone(),
+            });
+        }
+        self.runtime.publish(self.id, published);
+    }
+
+    /// Forward render messages to the windows of the toplevel wl_surfaces
+    /// they are paired with
+    pub fn send(&self, messages: Vec<(u32, RenderMessage)>) {
+        let shared = self.runtime.shared.lock().unwrap();
+        if let Some(renderer) = &shared.renderer {
+            for (surface, message) in messages {
+                let _ = renderer.send(Outgoing::Message((self.id, surface), message));
+            }
+        }
+    }
+
+    /// Forward what the compositor queued for the renderer: its render
+    /// messages, then the windows of toplevels that went away
+    pub fn forward(&self, compositor: &mut Compositor) {
+        self.send(compositor.take_render_messages());
+        self.close_windows(compositor.take_closed_windows());
+    }
+
+    /// Close the windows of toplevel wl_surfaces that went away
+    pub fn close_windows(&self, surfaces: Vec<u32>) {
+        let shared = self.runtime.shared.lock().unwrap();
+        if let Some(renderer) = &shared.renderer {
+            for surface in surfaces {
+                let _ = renderer.send(Outgoing::Closed((self.id, surface)));
+            }
+        }
+    }
+
+    /// Wait for the next event from one of the client's windows, with the
+    /// toplevel wl_surface it belongs to (0 for events about the renderer
+    /// as a whole)
+    ///
+    /// Cancel-safe; never resolves while no renderer is connected.
+    pub async fn next_event(&mut self) -> (u32, RendererEvent) {
+        match self.events.recv().await {
+            Some(event) => event,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Control socket requests for the client, for the task serving it;
+    /// `None` once taken
+    pub fn take_requests(&mut self) -> Option<mpsc::UnboundedReceiver<ControlRequest>> {
+        self.requests.take()
+    }
+
+    /// xdg_toplevel published under a foreign toplevel identifier
+    pub fn toplevel_for(&self, identifier: &str) -> Option<u32> {
+        self.identifiers.iter().find(|(_, published)| *published == identifier).map(|(&id, _)| id)
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        self.runtime.publish(self.id, Vec::new());
+        let mut shared = self.runtime.shared.lock().unwrap();
+        shared.clients.remove(&self.id);
+        shared.requests.remove(&self.id);
+        if shared.focus == Some(self.id) {
+            shared.focus = None;
+        }
+        if let Some(renderer) = &shared.renderer {
+            let _ = renderer.send(Outgoing::Disconnected(self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{FrameDecoder, Hello, FRAME_VERSION};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_windows_share_renderer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = RuntimeConfig { renderer: Some(RendererAddr::Tcp(listener.local_addr().unwrap())), ..Default::default() };
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(config, monitors);
+        runtime.start_renderer().await.unwrap();
+        let (mut renderer, _) = listener.accept().await.unwrap();
+
+        let first = runtime.connect().unwrap();
+        let mut second = runtime.connect().unwrap();
+        let message = || RenderMessage::ShortcutsInhibit(Default::default());
+        first.send(vec![(10, message())]);
+        second.send(vec![(10, message()), (11, message()), (11, message())]);
+        second.close_windows(vec![11]);
+        drop(first);
+
+        let mut decoder = FrameDecoder::strict();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 256];
+        while received.len() < 10 {
+            let n = renderer.read(&mut buf).await.unwrap();
+            decoder.push(&buf[..n]);
+            while let Some(message) = decoder.decode_message() {
+                received.push(format!("{:?}", message));
+            }
+        }
+        let target = |window, surface, destroy| RenderMessage::WindowTarget(WindowTarget { window, surface, destroy });
+        let expected = [
+            RenderMessage::Hello(Hello { version: FRAME_VERSION }),
+            target(1, 10, false), message(),
+            target(2, 10, false), message(),
+            target(2, 11, false), message(), message(),
+            target(2, 11, true),
+            target(1, 0, true),
+        ];
+        assert_eq!(received, expected.iter().map(|m| format!("{:?}", m)).collect::<Vec<_>>());
+
+        // Events go to the window they come from
+        let events = [RendererEvent::Window { window: 2, surface: 11 }, RendererEvent::Focus { focused: true }];
+        renderer.write_all(&events.iter().flat_map(RendererEvent::encode).collect::<Vec<_>>()).await.unwrap();
+        assert_eq!(second.next_event().await, (11, RendererEvent::Focus { focused: true }));
+        assert_eq!(runtime.focused_client(), Some(2));
+    }
+
+    #[test]
+    fn test_toplevels_published_across_clients() {
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(RuntimeConfig::default(), monitors);
+        let toplevels = runtime.foreign_toplevels();
+        let info = |id, title: &str, mapped| ToplevelInfo {
+            id,
+            surface: Some(10),
+            title: title.to_string(),
+            app_id: String::new(),
+            maximized: false,
+            fullscreen: false,
+            minimized: false,
+            mapped,
+            surfaces: Vec::new(),
+        };
+
+        let mut first = runtime.connect().unwrap();
+        let mut second = runtime.connect().unwrap();
+        second.publish_toplevels(&[info(21, "htop", true)]);
+        first.publish_toplevels(&[info(21, "vim", true), info(22, "unmapped", false)]);
+        let titles = |list: &[ForeignToplevel]| list.iter().map(|t| t.title.clone()).collect::<Vec<_>>();
+        assert_eq!(titles(&toplevels.borrow()), ["vim", "htop"]);
+        let identifiers: Vec<String> = toplevels.borrow().iter().map(|t| t.identifier.clone()).collect();
+        assert_ne!(identifiers[0], identifiers[1]);
+
+        // Identifiers stay with their toplevel
+        first.publish_toplevels(&[info(21, "vim: main.rs", true)]);
+        assert_eq!(toplevels.borrow()[0].identifier, identifiers[0]);
+
+        drop(first);
+        assert_eq!(titles(&toplevels.borrow()), ["htop"]);
+    }
+
+    #[tokio::test]
+    async fn test_renderer_reconnects_and_replays() {
+        use crate::buffer::{BufferDelta, DeltaRegion};
+        use crate::render::{PixelFormat, ShortcutsInhibit};
+
+        async fn receive(renderer: &mut tokio::net::TcpStream, count: usize) -> Vec<RenderMessage> {
+            let mut decoder = FrameDecoder::strict();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 256];
+            while received.len() < count {
+                let n = renderer.read(&mut buf).await.unwrap();
+                decoder.push(&buf[..n]);
+                received.extend(std::iter::from_fn(|| decoder.decode_message()));
+            }
+            received
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = RuntimeConfig { renderer: Some(RendererAddr::Tcp(listener.local_addr().unwrap())), ..Default::default() };
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(config, monitors);
+        runtime.start_renderer().await.unwrap();
+        let (mut renderer, _) = listener.accept().await.unwrap();
+
+        let session = runtime.connect().unwrap();
+        let delta = BufferDelta {
+            buffer_id: 10,
+            regions: vec![DeltaRegion { x: 1, y: 0, width: 1, height: 1, data: vec![9; 4], xor: false }],
+            total_bytes: 4,
+        };
+        session.send(vec![
+            (10, RenderMessage::Frame(RenderFrame::new(2, 1, PixelFormat::ARGB8888, vec![1; 8]))),
+            (10, RenderMessage::FrameDelta(delta)),
+        ]);
+        assert_eq!(receive(&mut renderer, 4).await.len(), 4);
+
+        // win-way restarts
+        drop(renderer);
+        while session.has_renderer() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let inhibit = ShortcutsInhibit { active: true };
+        session.send(vec![(10, RenderMessage::ShortcutsInhibit(inhibit))]);
+        let (mut renderer, _) = listener.accept().await.unwrap();
+
+        // The window comes back with its last picture
+        match &receive(&mut renderer, 4).await[..] {
+            [RenderMessage::Hello(_), RenderMessage::WindowTarget(WindowTarget { window: 1, surface: 10, destroy: false }), RenderMessage::Frame(frame), RenderMessage::ShortcutsInhibit(received)] => {
+                assert_eq!(frame.data, [[1; 4], [9; 4]].concat());
+                assert_eq!(*received, inhibit);
+            }
+            other => panic!("unexpected replay {:?}", other),
+        }
+        while !session.has_renderer() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_renderer_started_later() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = RuntimeConfig { renderer: Some(RendererAddr::Tcp(addr)), ..Default::default() };
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(config, monitors);
+        assert!(runtime.start_renderer().await.is_err());
+        let session = runtime.connect().unwrap();
+        assert!(!session.has_renderer());
+
+        // Retried until win-way comes up
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let _renderer = listener.accept().await.unwrap();
+        while !session.has_renderer() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_clients() {
+        let (_monitors_tx, monitors) = watch::channel(vec![Monitor::default()]);
+        let runtime = Runtime::new(RuntimeConfig { max_clients: 1, ..Default::default() }, monitors);
+
+        let first = runtime.connect().unwrap();
+        assert!(runtime.connect().is_err());
+        // A disconnect frees the slot
+        drop(first);
+        assert!(runtime.connect().is_ok());
+    }
+}
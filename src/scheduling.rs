@@ -0,0 +1,151 @@
+//! Frame Scheduling (wp-fifo-v1, wp-commit-timing-v1)
+//!
+//! Games and video players decide when their content reaches the screen
+//! instead of presenting as fast as possible. A commit may carry:
+//!
+//! - a FIFO barrier: set_barrier makes the surface wait for the next
+//!   refresh once the commit is applied, and a later commit with
+//!   wait_barrier is held until then, giving one update per vblank;
+//! - a target time: the commit is held until the refresh that shows it
+//!   at or after the target, on the presentation clock.
+//!
+//! Held commits are content updates snapshotted at commit time. They
+//! queue in order per surface, so a plain commit behind a held one waits
+//! too, and are released on each vblank (the renderer's Presented event,
+//! or the frame timer when headless). Without a renderer there is no
+//! presentation clock and target times pass at the next tick.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::surface::SurfaceState;
+use crate::syncobj::SurfaceSync;
+
+/// wp_fifo_manager_v1.error codes
+pub mod fifo_manager_error {
+    pub const ALREADY_EXISTS: u32 = 0;
+}
+
+/// wp_fifo_v1.error codes
+pub mod fifo_error {
+    pub const SURFACE_DESTROYED: u32 = 0;
+}
+
+/// wp_commit_timing_manager_v1.error codes
+pub mod commit_timing_manager_error {
+    pub const COMMIT_TIMER_EXISTS: u32 = 0;
+}
+
+/// wp_commit_timer_v1.error codes
+pub mod commit_timer_error {
+    pub const INVALID_TIMESTAMP: u32 = 0;
+    pub const TIMESTAMP_EXISTS: u32 = 1;
+    pub const SURFACE_DESTROYED: u32 = 2;
+}
+
+/// Scheduling requests for a surface's next commit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitSchedule {
+    /// wp_fifo_v1.set_barrier
+    pub set_barrier: bool,
+    /// wp_fifo_v1.wait_barrier
+    pub wait_barrier: bool,
+    /// wp_commit_timer_v1.set_timestamp, on the presentation clock
+    pub target: Option<Duration>,
+}
+
+/// Everything a wl_surface.commit applies
+#[derive(Debug, Clone, Default)]
+pub struct ContentUpdate {
+    pub state: SurfaceState,
+    /// wp_presentation_feedback objects
+    pub feedback: Vec<u32>,
+    /// wl_callback objects from wl_surface.frame
+    pub frame_callbacks: Vec<u32>,
+    /// Explicit sync points, if the surface has a syncobj surface
+    pub sync: Option<SurfaceSync>,
+    pub schedule: CommitSchedule,
+}
+
+/// Per-surface scheduling state
+#[derive(Debug, Clone, Default)]
+pub struct CommitQueue {
+    /// Requests for the next commit
+    pub pending: CommitSchedule,
+    /// A FIFO barrier is set and not yet cleared by a refresh
+    pub barrier: bool,
+    /// Held content updates, oldest first
+    pub held: VecDeque<ContentUpdate>,
+}
+
+impl CommitQueue {
+    /// Hold a new content update, or hand it back to apply right away
+    ///
+    /// `now` is the last vblank on the presentation clock, if known.
+    pub fn submit(&mut self, update: ContentUpdate, now: Option<Duration>) -> Option<ContentUpdate> {
+        if self.held.is_empty() && self.is_ready(&update.schedule, now) {
+            return Some(update);
+        }
+        self.held.push_back(update);
+        None
+    }
+
+    /// Next held update the refresh showing content at `next_vblank` may
+    /// apply; None for a clock-less tick
+    pub fn release(&mut self, next_vblank: Option<Duration>) -> Option<ContentUpdate> {
+        let front = self.held.front()?;
+        let due = front.schedule.target.is_none_or(|target| next_vblank.is_none_or(|vblank| target <= vblank));
+        if !due || (front.schedule.wait_barrier && self.barrier) {
+            return None;
+        }
+        self.held.pop_front()
+    }
+
+    fn is_ready(&self, schedule: &CommitSchedule, now: Option<Duration>) -> bool {
+        let due = schedule.target.is_none_or(|target| now.is_some_and(|now| target <= now));
+        due && !(schedule.wait_barrier && self.barrier)
+    }
+}
+
+/// set_timestamp arguments as a time, None if tv_nsec is out of range
+pub fn timestamp(tv_sec_hi: u32, tv_sec_lo: u32, tv_nsec: u32) -> Option<Duration> {
+    let secs = ((tv_sec_hi as u64) << 32) | tv_sec_lo as u64;
+    (tv_nsec < 1_000_000_000).then(|| Duration::new(secs, tv_nsec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(schedule: CommitSchedule) -> ContentUpdate {
+        ContentUpdate { schedule, ..Default::default() }
+    }
+
+    #[test]
+    fn test_updates_wait_in_order() {
+        let mut queue = CommitQueue { barrier: true, ..Default::default() };
+        let waiting = CommitSchedule { wait_barrier: true, ..Default::default() };
+        assert!(queue.submit(update(waiting), None).is_none());
+        // A plain commit queues behind the waiting one
+        assert!(queue.submit(update(CommitSchedule::default()), None).is_none());
+        assert!(queue.release(None).is_none());
+
+        queue.barrier = false;
+        assert_eq!(queue.release(None).unwrap().schedule, waiting);
+        assert_eq!(queue.release(None).unwrap().schedule, CommitSchedule::default());
+        assert!(queue.release(None).is_none());
+    }
+
+    #[test]
+    fn test_target_time_waits_for_refresh() {
+        let mut queue = CommitQueue::default();
+        let at = |ms| CommitSchedule { target: Some(Duration::from_millis(ms)), ..Default::default() };
+        assert!(queue.submit(update(at(10)), Some(Duration::from_millis(10))).is_some());
+        assert!(queue.submit(update(at(50)), Some(Duration::from_millis(16))).is_none());
+        assert!(queue.release(Some(Duration::from_millis(33))).is_none());
+        assert!(queue.release(Some(Duration::from_millis(50))).is_some());
+
+        assert_eq!(timestamp(1, 2, 3), Some(Duration::new((1 << 32) + 2, 3)));
+        assert_eq!(timestamp(0, 0, 1_000_000_000), None);
+    }
+}
@@ -0,0 +1,757 @@
+//! Session Resumption
+//!
+//! A Wi-Fi blip or a WSL network restart breaks the connection between
+//! `winpipe client` and the server, which would end the application's
+//! Wayland connection with it. The helper therefore runs its connection as
+//! a session that outlives the transport carrying it:
+//!
+//! - Client -> server hello: magic "WPSS", a session ID the client
+//!   generated (16 bytes) and the number of data frames it received so far
+//!   (u64 LE), 0 for a new session
+//! - Server -> client: one status byte, 1 if the session was created or
+//!   resumed and 0 if it is unknown or expired, then the number of data
+//!   frames the server received (u64 LE)
+//!
+//! Then each direction is a sequence of frames: kind (u8), sequence number
+//! (u64 LE), payload length (u32 LE) and payload.
+//!
+//! - DATA carries stream bytes, numbered from 0
+//! - ACK acknowledges the data frames numbered below its sequence number;
+//!   it is sent after data arrives and every `HEARTBEAT_INTERVAL`, so it
+//!   also shows the peer is alive
+//! - FIN ends the session. The end sending it keeps reading until its
+//!   data frames are acknowledged or the peer's FIN arrives, for up to
+//!   `FIN_TIMEOUT`, and the end receiving it acknowledges what arrived
+//!   before closing.
+//!
+//! Data frames are kept until acknowledged. When the transport fails, the
+//! client reconnects with the same session ID and both sides resend what
+//! the other has not received. The server keeps a session without a
+//! transport for a grace period (`--resume-grace`) before its Wayland
+//! client is disconnected.
+//!
+//! Connections that do not start with the hello, like socat relays, are
+//! served as plain Wayland streams that cannot be resumed.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+
+use crate::auth::HANDSHAKE_TIMEOUT;
+use crate::error::{Result, WinpipeError};
+use crate::keepalive::{Liveness, HEARTBEAT_INTERVAL};
+
+/// Magic opening the session hello
+pub const MAGIC: &[u8; 4] = b"WPSS";
+
+/// How long the server keeps a session whose transport was lost
+pub const DEFAULT_RESUME_GRACE: Duration = Duration::from_secs(60);
+
+/// How long the client keeps trying to resume a session
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// First delay before the client reconnects
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(100);
+
+/// Longest delay between reconnection attempts
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// Unacknowledged bytes after which no more data is sent
+const MAX_UNACKED: usize = 16 << 20;
+
+/// Received bytes waiting to be read after which the transport is not read
+const MAX_INBOX: usize = 1 << 20;
+
+/// Frames waiting for the transport after which the local stream is not
+/// read
+const MAX_OUTBOX: usize = 1 << 20;
+
+/// Bytes buffered between a session and the stream it provides
+const LOCAL_BUFFER: usize = 256 * 1024;
+
+/// How long an end that sent FIN waits for its data to be acknowledged
+const FIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest frame payload accepted
+const MAX_FRAME_PAYLOAD: usize = 1 << 20;
+
+/// Frame kind, sequence number and payload length
+const FRAME_HEADER_SIZE: usize = 13;
+
+/// Frame kinds
+mod kind {
+    pub const DATA: u8 = 0;
+    pub const ACK: u8 = 1;
+    pub const FIN: u8 = 2;
+}
+
+/// A connection a session can run on
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type BoxTransport = Box<dyn Transport>;
+
+/// Opens a new transport to the server, authenticated if required
+pub type Connector = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<BoxTransport>> + Send>> + Send + Sync>;
+
+/// Identifies a session across transports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId([u8; 16]);
+
+impl SessionId {
+    /// A random ID, from the randomly keyed std hasher
+    pub fn generate() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos());
+        let mut id = [0u8; 16];
+        for (i, half) in id.chunks_mut(8).enumerate() {
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_usize(i);
+            hasher.write_u128(nanos);
+            hasher.write_u32(std::process::id());
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        Self(id)
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0[..4].iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+fn frame(kind: u8, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    kind: u8,
+    seq: u64,
+    payload: Vec<u8>,
+}
+
+/// Splits transport bytes into frames
+#[derive(Debug, Default)]
+struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn decode(&mut self) -> Result<Option<Frame>> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let seq = u64::from_le_bytes(self.buffer[1..9].try_into().unwrap());
+        let len = u32::from_le_bytes(self.buffer[9..13].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(WinpipeError::Protocol(format!("session frame of {} bytes is too large", len)));
+        }
+        if self.buffer.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let kind = self.buffer[0];
+        let payload = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(Frame { kind, seq, payload }))
+    }
+}
+
+/// Sequence numbers and unacknowledged data of one end of a session
+#[derive(Debug, Default)]
+struct SessionState {
+    /// Sequence number of the next data frame sent
+    sent: u64,
+    /// Sequence number of the next data frame expected
+    received: u64,
+    /// `received` as last acknowledged
+    acked: u64,
+    /// Data frames sent but not acknowledged
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    unacked_bytes: usize,
+}
+
+impl SessionState {
+    /// Frame `payload`, keeping it until acknowledged
+    fn send(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        let data = frame(kind::DATA, self.sent, &payload);
+        self.unacked_bytes += payload.len();
+        self.unacked.push_back((self.sent, payload));
+        self.sent += 1;
+        data
+    }
+
+    /// The peer received the data frames numbered below `count`
+    fn acknowledged(&mut self, count: u64) {
+        while let Some((seq, payload)) = self.unacked.front() {
+            if *seq >= count {
+                break;
+            }
+            self.unacked_bytes -= payload.len();
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Payload of a received data frame; None if it is a resent duplicate
+    fn receive(&mut self, seq: u64, payload: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if seq < self.received {
+            return Ok(None);
+        }
+        if seq > self.received {
+            return Err(WinpipeError::Protocol(format!("session frame {} arrived before {}", seq, self.received)));
+        }
+        self.received += 1;
+        Ok(Some(payload))
+    }
+
+    /// ACK for the data received since the last one
+    fn ack(&mut self) -> Option<Vec<u8>> {
+        (self.received != self.acked).then(|| self.keepalive())
+    }
+
+    /// ACK sent whether or not data arrived
+    fn keepalive(&mut self) -> Vec<u8> {
+        self.acked = self.received;
+        frame(kind::ACK, self.received, &[])
+    }
+
+    /// The data frames a peer that expects `expected` next is missing
+    fn resend(&mut self, expected: u64) -> Result<Vec<u8>> {
+        if expected > self.sent {
+            return Err(WinpipeError::Protocol(format!("peer expects frame {} of {}", expected, self.sent)));
+        }
+        self.acknowledged(expected);
+        if self.unacked.front().is_some_and(|&(seq, _)| seq > expected) {
+            return Err(WinpipeError::Protocol(format!("frame {} is no longer available", expected)));
+        }
+        Ok(self.unacked.iter().flat_map(|(seq, payload)| frame(kind::DATA, *seq, payload)).collect())
+    }
+}
+
+/// The session's end of the stream it provides, and data received for it
+struct Local {
+    reader: ReadHalf<DuplexStream>,
+    writer: WriteHalf<DuplexStream>,
+    inbox: Vec<u8>,
+}
+
+impl Local {
+    fn new(stream: DuplexStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self { reader, writer, inbox: Vec::new() }
+    }
+
+    /// Deliver what is left and close the stream
+    async fn finish(mut self) {
+        let _ = self.writer.write_all(&self.inbox).await;
+        let _ = self.writer.shutdown().await;
+    }
+}
+
+/// The client reconnected on a new transport
+struct Resume {
+    transport: BoxTransport,
+    /// Data frame the client expects next
+    expected: u64,
+}
+
+/// Why a session stopped using its transport
+enum PumpEnd {
+    /// The local stream closed, FIN was sent and the peer acknowledged
+    /// the data or did not within `FIN_TIMEOUT`
+    Finished,
+    /// The peer sent FIN
+    PeerFinished,
+    /// The transport failed or the peer stopped answering
+    Lost,
+    /// The client resumed on another transport (server only)
+    Replaced(Resume),
+}
+
+/// Move data between the local stream and the transport until either ends
+///
+/// Frames for the transport wait in an outbox written by a branch of its
+/// own, so the transport is still read while a write is blocked: two ends
+/// sending each other more than the transport buffers cannot wait on each
+/// other forever.
+async fn pump(
+    state: &mut SessionState,
+    local: &mut Local,
+    transport: &mut BoxTransport,
+    mut resumes: Option<&mut mpsc::UnboundedReceiver<Resume>>,
+) -> PumpEnd {
+    let (mut reader, mut writer) = tokio::io::split(transport);
+    let mut decoder = FrameDecoder::default();
+    let mut liveness = Liveness::new(Instant::now());
+    let mut keepalive = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut local_buffer = vec![0u8; 65536];
+    let mut transport_buffer = vec![0u8; 65536];
+    let mut outbox = Vec::new();
+    // Once FIN is queued, when to stop waiting for the peer's ACK
+    let mut fin_deadline: Option<tokio::time::Instant> = None;
+
+    loop {
+        if fin_deadline.is_some() && outbox.is_empty() && state.unacked.is_empty() {
+            return PumpEnd::Finished;
+        }
+        let sending = state.unacked_bytes < MAX_UNACKED && outbox.len() < MAX_OUTBOX && fin_deadline.is_none();
+        let receiving = local.inbox.len() < MAX_INBOX;
+        let resumed = async {
+            match resumes.as_deref_mut() {
+                Some(resumes) => resumes.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let fin_expired = async {
+            match fin_deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = local.reader.read(&mut local_buffer), if sending => {
+                let n = result.unwrap_or(0);
+                if n == 0 {
+                    outbox.extend_from_slice(&frame(kind::FIN, state.sent, &[]));
+                    fin_deadline = Some(tokio::time::Instant::now() + FIN_TIMEOUT);
+                    continue;
+                }
+                outbox.extend_from_slice(&state.send(local_buffer[..n].to_vec()));
+            }
+            result = local.writer.write(&local.inbox), if !local.inbox.is_empty() => match result {
+                Ok(n) if n > 0 => {
+                    local.inbox.drain(..n);
+                }
+                _ => {
+                    // Nobody reads what arrives anymore
+                    local.inbox.clear();
+                    if fin_deadline.is_none() {
+                        outbox.extend_from_slice(&frame(kind::FIN, state.sent, &[]));
+                        fin_deadline = Some(tokio::time::Instant::now() + FIN_TIMEOUT);
+                    }
+                }
+            },
+            result = writer.write(&outbox), if !outbox.is_empty() => match result {
+                Ok(n) if n > 0 => {
+                    outbox.drain(..n);
+                }
+                _ => return PumpEnd::Lost,
+            },
+            result = reader.read(&mut transport_buffer), if receiving => {
+                let n = match result {
+                    Ok(0) | Err(_) => return PumpEnd::Lost,
+                    Ok(n) => n,
+                };
+                liveness.heard(Instant::now());
+                decoder.push(&transport_buffer[..n]);
+                let mut peer_finished = false;
+                while !peer_finished {
+                    let frame = match decoder.decode() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Bad session frame: {}", e);
+                            return PumpEnd::Lost;
+                        }
+                    };
+                    match frame.kind {
+                        kind::DATA => match state.receive(frame.seq, frame.payload) {
+                            Ok(Some(payload)) => local.inbox.extend_from_slice(&payload),
+                            Ok(None) => {}
+                            Err(e) => {
+                                warn!("Bad session frame: {}", e);
+                                return PumpEnd::Lost;
+                            }
+                        },
+                        kind::ACK => state.acknowledged(frame.seq),
+                        kind::FIN => peer_finished = true,
+                        other => {
+                            warn!("Unknown session frame kind {}", other);
+                            return PumpEnd::Lost;
+                        }
+                    }
+                }
+                outbox.extend(state.ack().unwrap_or_default());
+                if peer_finished {
+                    // The peer waits for this ACK
+                    let _ = tokio::time::timeout(FIN_TIMEOUT, writer.write_all(&outbox)).await;
+                    return PumpEnd::PeerFinished;
+                }
+            }
+            _ = keepalive.tick() => {
+                // Silence while the transport is not read proves nothing
+                if !receiving {
+                    liveness.heard(Instant::now());
+                }
+                if liveness.is_dead(Instant::now()) {
+                    return PumpEnd::Lost;
+                }
+                outbox.extend_from_slice(&state.keepalive());
+            }
+            Some(resume) = resumed => return PumpEnd::Replaced(resume),
+            _ = fin_expired => {
+                debug!("The peer did not acknowledge the last {} data frames", state.unacked.len());
+                return PumpEnd::Finished;
+            }
+        }
+    }
+}
+
+/// `stream` with `prefix`, which was read from it already, put back
+pub(crate) fn unread<S: Transport + 'static>(prefix: &[u8], stream: S) -> BoxTransport {
+    let (reader, writer) = tokio::io::split(stream);
+    Box::new(tokio::io::join(Cursor::new(prefix.to_vec()).chain(reader), writer))
+}
+
+/// Server side: the sessions that are live or waiting to be resumed
+pub struct Sessions {
+    grace: Duration,
+    live: Mutex<HashMap<SessionId, mpsc::UnboundedSender<Resume>>>,
+}
+
+impl Sessions {
+    /// Keep sessions without a transport for `grace`
+    pub fn new(grace: Duration) -> Arc<Self> {
+        Arc::new(Self { grace, live: Mutex::new(HashMap::new()) })
+    }
+
+    /// Take over a new connection
+    ///
+    /// Returns the stream to serve as a Wayland client: the connection
+    /// itself unless it opens a session. None if it resumed a session that
+    /// is served already.
+    pub async fn accept<S: Transport + 'static>(self: &Arc<Self>, mut stream: S) -> Result<Option<BoxTransport>> {
+        let mut magic = [0u8; 4];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut magic))
+            .await
+            .map_err(|_| WinpipeError::Protocol("no data within the handshake timeout".to_string()))??;
+        if &magic != MAGIC {
+            // Plain Wayland
+            return Ok(Some(unread(&magic, stream)));
+        }
+        let mut hello = [0u8; 24];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut hello))
+            .await
+            .map_err(|_| WinpipeError::Protocol("incomplete session hello".to_string()))??;
+        let id = SessionId(hello[..16].try_into().unwrap());
+        let expected = u64::from_le_bytes(hello[16..].try_into().unwrap());
+        let resume = Resume { transport: Box::new(stream), expected };
+
+        let mut resume = {
+            let live = self.live.lock().unwrap();
+            match live.get(&id) {
+                Some(session) => match session.send(resume) {
+                    Ok(()) => return Ok(None),
+                    // The session just ended
+                    Err(mpsc::error::SendError(resume)) => resume,
+                },
+                None => resume,
+            }
+        };
+        if expected != 0 {
+            let _ = resume.transport.write_all(&[0; 9]).await;
+            return Err(WinpipeError::Protocol(format!("session {} is unknown or expired", id)));
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.live.lock().unwrap().insert(id, tx);
+        let (served, local) = tokio::io::duplex(LOCAL_BUFFER);
+        tokio::spawn(Arc::clone(self).serve(id, Local::new(local), rx, resume));
+        Ok(Some(Box::new(served)))
+    }
+
+    /// Run a session on each transport the client resumes it on, until it
+    /// ends or stays without one for the grace period
+    async fn serve(self: Arc<Self>, id: SessionId, mut local: Local, mut resumes: mpsc::UnboundedReceiver<Resume>, first: Resume) {
+        let mut state = SessionState::default();
+        let mut next = Some(first);
+        loop {
+            let Resume { mut transport, expected } = match next.take() {
+                Some(resume) => resume,
+                None => match tokio::time::timeout(self.grace, resumes.recv()).await {
+                    Ok(Some(resume)) => resume,
+                    _ => {
+                        info!("Session {} was not resumed in time", id);
+                        break;
+                    }
+                },
+            };
+            let missed = match state.resend(expected) {
+                Ok(missed) => missed,
+                Err(e) => {
+                    warn!("Cannot resume session {}: {}", id, e);
+                    let _ = transport.write_all(&[0; 9]).await;
+                    break;
+                }
+            };
+            let mut reply = vec![1];
+            reply.extend_from_slice(&state.received.to_le_bytes());
+            reply.extend_from_slice(&missed);
+            if transport.write_all(&reply).await.is_err() {
+                continue;
+            }
+
+            match pump(&mut state, &mut local, &mut transport, Some(&mut resumes)).await {
+                PumpEnd::Finished => break,
+                PumpEnd::PeerFinished => {
+                    local.finish().await;
+                    break;
+                }
+                PumpEnd::Lost => info!("Session {} lost its connection, keeping it for {:?}", id, self.grace),
+                PumpEnd::Replaced(resume) => next = Some(resume),
+            }
+        }
+        self.live.lock().unwrap().remove(&id);
+    }
+}
+
+/// Client side: open a session on transports from `connector`
+///
+/// The returned stream carries the session; it ends once the session
+/// does, or could not be resumed.
+pub async fn connect(connector: Connector) -> Result<DuplexStream> {
+    let id = SessionId::generate();
+    let mut state = SessionState::default();
+    let transport = handshake(&connector, id, &mut state)
+        .await?
+        .ok_or_else(|| WinpipeError::Protocol("the server refused the session".to_string()))?;
+    let (stream, local) = tokio::io::duplex(LOCAL_BUFFER);
+    tokio::spawn(run_client(connector, id, state, Local::new(local), transport));
+    Ok(stream)
+}
+
+/// Open a transport and resume the session on it; None if the server no
+/// longer has the session
+async fn handshake(connector: &Connector, id: SessionId, state: &mut SessionState) -> Result<Option<BoxTransport>> {
+    let mut transport = connector().await?;
+    let mut hello = MAGIC.to_vec();
+    hello.extend_from_slice(&id.0);
+    hello.extend_from_slice(&state.received.to_le_bytes());
+    transport.write_all(&hello).await?;
+
+    let mut reply = [0u8; 9];
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, transport.read_exact(&mut reply))
+        .await
+        .map_err(|_| WinpipeError::Protocol("no session reply in time".to_string()))??;
+    if reply[0] != 1 {
+        return Ok(None);
+    }
+    let expected = u64::from_le_bytes(reply[1..].try_into().unwrap());
+    transport.write_all(&state.resend(expected)?).await?;
+    Ok(Some(transport))
+}
+
+async fn run_client(connector: Connector, id: SessionId, mut state: SessionState, mut local: Local, mut transport: BoxTransport) {
+    loop {
+        match pump(&mut state, &mut local, &mut transport, None).await {
+            PumpEnd::Finished => return,
+            PumpEnd::PeerFinished => return local.finish().await,
+            PumpEnd::Lost | PumpEnd::Replaced(_) => {}
+        }
+        warn!("Lost the connection to the server, resuming session {}", id);
+        match reconnect(&connector, id, &mut state).await {
+            Some(resumed) => {
+                info!("🔁 Session {} resumed", id);
+                transport = resumed;
+            }
+            None => return,
+        }
+    }
+}
+
+/// Retry with exponential backoff until the session is resumed, the
+/// server dropped it or `RECONNECT_TIMEOUT` passed
+async fn reconnect(connector: &Connector, id: SessionId, state: &mut SessionState) -> Option<BoxTransport> {
+    let deadline = Instant::now() + RECONNECT_TIMEOUT;
+    let mut delay = RECONNECT_DELAY_MIN;
+    while Instant::now() < deadline {
+        tokio::time::sleep(delay).await;
+        match handshake(connector, id, state).await {
+            Ok(Some(transport)) => return Some(transport),
+            Ok(None) => {
+                warn!("The server dropped session {}", id);
+                return None;
+            }
+            Err(e) => debug!("Cannot resume session {} yet: {}", id, e),
+        }
+        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+    }
+    warn!("Gave up resuming session {}", id);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resend_unacknowledged() {
+        let mut state = SessionState::default();
+        for payload in [b"a", b"b", b"c"] {
+            state.send(payload.to_vec());
+        }
+        state.acknowledged(1);
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&state.resend(2).unwrap());
+        assert_eq!(decoder.decode().unwrap(), Some(Frame { kind: kind::DATA, seq: 2, payload: b"c".to_vec() }));
+        assert_eq!(decoder.decode().unwrap(), None);
+        // Acknowledged frames are gone
+        assert!(state.resend(1).is_err());
+        assert!(state.resend(4).is_err());
+
+        // Resent frames that arrived already are dropped
+        assert_eq!(state.receive(0, b"x".to_vec()).unwrap(), Some(b"x".to_vec()));
+        assert_eq!(state.receive(0, b"x".to_vec()).unwrap(), None);
+        assert!(state.receive(2, b"z".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_survives_transport_loss() {
+        let sessions = Sessions::new(Duration::from_secs(5));
+        let (served_tx, mut served) = mpsc::unbounded_channel();
+        let links = Arc::new(Mutex::new(Vec::new()));
+        let connector: Connector = {
+            let (sessions, links) = (Arc::clone(&sessions), Arc::clone(&links));
+            Box::new(move || {
+                let (sessions, served_tx, links) = (Arc::clone(&sessions), served_tx.clone(), Arc::clone(&links));
+                Box::pin(async move {
+                    // A link that can be cut, between the client and the server
+                    let (client_end, mut near) = tokio::io::duplex(1024);
+                    let (mut far, server_end) = tokio::io::duplex(1024);
+                    links.lock().unwrap().push(tokio::spawn(async move {
+                        let _ = tokio::io::copy_bidirectional(&mut near, &mut far).await;
+                    }));
+                    tokio::spawn(async move {
+                        if let Ok(Some(stream)) = sessions.accept(server_end).await {
+                            let _ = served_tx.send(stream);
+                        }
+                    });
+                    Ok(Box::new(client_end) as BoxTransport)
+                })
+            })
+        };
+
+        let mut client = connect(connector).await.unwrap();
+        let mut server = served.recv().await.unwrap();
+        let mut buf = [0u8; 5];
+        client.write_all(b"hello").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        for link in links.lock().unwrap().drain(..) {
+            link.abort();
+        }
+        client.write_all(b"world").await.unwrap();
+        server.write_all(b"back!").await.unwrap();
+        let exchange = async {
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"back!");
+        };
+        tokio::time::timeout(Duration::from_secs(10), exchange).await.unwrap();
+        // Resumed, not served as a new client
+        assert!(served.try_recv().is_err());
+
+        // Closing the client ends the session on the server
+        drop(client);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fin_waits_for_ack() {
+        let (app, session_end) = tokio::io::duplex(1024);
+        let (transport, mut peer) = tokio::io::duplex(1024);
+        let pumping = tokio::spawn(async move {
+            let (mut state, mut local) = (SessionState::default(), Local::new(session_end));
+            let mut transport: BoxTransport = Box::new(transport);
+            matches!(pump(&mut state, &mut local, &mut transport, None).await, PumpEnd::Finished)
+        });
+        // The application writes and closes
+        let (_app_reader, mut app_writer) = tokio::io::split(app);
+        app_writer.write_all(b"bye!!").await.unwrap();
+        app_writer.shutdown().await.unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        let mut frames = Vec::new();
+        while frames.len() < 2 {
+            let mut buf = [0u8; 64];
+            let n = peer.read(&mut buf).await.unwrap();
+            decoder.push(&buf[..n]);
+            while let Some(frame) = decoder.decode().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames[0], Frame { kind: kind::DATA, seq: 0, payload: b"bye!!".to_vec() });
+        assert_eq!(frames[1].kind, kind::FIN);
+
+        // The transport stays open until the data is acknowledged
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pumping.is_finished());
+        peer.write_all(&frame(kind::ACK, 1, &[])).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(5), pumping).await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_session_sends_both_ways_at_once() {
+        let sessions = Sessions::new(Duration::from_secs(5));
+        let (served_tx, mut served) = mpsc::unbounded_channel();
+        let connector: Connector = Box::new(move || {
+            let (sessions, served_tx) = (Arc::clone(&sessions), served_tx.clone());
+            Box::pin(async move {
+                // Far less than either side sends
+                let (client_end, server_end) = tokio::io::duplex(4096);
+                tokio::spawn(async move {
+                    if let Ok(Some(stream)) = sessions.accept(server_end).await {
+                        let _ = served_tx.send(stream);
+                    }
+                });
+                Ok(Box::new(client_end) as BoxTransport)
+            })
+        });
+        let client = connect(connector).await.unwrap();
+        let server = served.recv().await.unwrap();
+
+        let data: Vec<u8> = (0..12 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let exchange = |stream: BoxTransport| {
+            let data = data.clone();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let sent = data.clone();
+                let writing = tokio::spawn(async move { writer.write_all(&sent).await.unwrap() });
+                let mut received = vec![0u8; data.len()];
+                reader.read_exact(&mut received).await.unwrap();
+                writing.await.unwrap();
+                assert!(received == data);
+            })
+        };
+        let (client, server) = (exchange(Box::new(client)), exchange(server));
+        tokio::time::timeout(Duration::from_secs(30), async {
+            client.await.unwrap();
+            server.await.unwrap();
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! Shared-Memory Render Transport
+//!
+//! When win-way runs on the same machine, render messages do not need to
+//! go through TCP loopback: win-way creates a named shared-memory file
+//! with two ring buffers, and winpipe writes the same byte stream it would
+//! send over TCP into one and reads renderer events from the other.
+//!
+//! Layout (all integers LE):
+//! - Magic (4 bytes): "WPSM" (WinPipe Shared Memory)
+//! - Version (4 bytes)
+//! - Message ring size, event ring size (4 bytes each)
+//! - State (4 bytes): 1=connected, 2=winpipe closed, 4=renderer closed
+//! - Message ring write and read positions, event ring write and read
+//!   positions (8 bytes each), counting bytes since the start
+//! - The message ring, then the event ring
+//!
+//! As with TCP, win-way listens and winpipe connects: it sets the
+//! connected state and signals win-way's named event. win-way waits on
+//! that event for the handshake and for new messages; winpipe, in the
+//! async loop, checks the event ring on a short timer. Where named events
+//! are not available, win-way polls the same way.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::{Result, WinpipeError};
+use crate::mapping::SharedMapping;
+
+/// Shared-memory transport magic
+pub const SHM_MAGIC: &[u8; 4] = b"WPSM";
+
+/// Shared-memory layout version
+pub const SHM_VERSION: u32 = 1;
+
+/// Header size; the message ring starts here
+pub const SHM_HEADER_SIZE: usize = 64;
+
+/// Default message ring size, a few 4K frames
+pub const DEFAULT_RING_SIZE: usize = 128 << 20;
+
+/// Event ring size
+pub const EVENT_RING_SIZE: usize = 64 << 10;
+
+/// How often a side without an event to wait on checks the rings
+pub const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+mod state {
+    pub const CONNECTED: u32 = 1;
+    pub const WINPIPE_CLOSED: u32 = 2;
+    pub const RENDERER_CLOSED: u32 = 4;
+}
+
+const STATE_OFFSET: usize = 16;
+const MESSAGES: Ring = Ring { write: 24, read: 32 };
+const EVENTS: Ring = Ring { write: 40, read: 48 };
+
+/// Offsets of a ring's positions in the header
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Ring {
+    write: usize,
+    read: usize,
+}
+
+/// Path of the shared-memory file for a transport name
+pub fn shm_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("winpipe-{}.shm", name))
+}
+
+/// The mapped file, accessed only through raw pointers and atomics since
+/// the other process writes it concurrently
+struct Channel {
+    map: SharedMapping,
+    messages: usize,
+    events: usize,
+}
+
+impl Channel {
+    fn u32_at(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: the offset is an aligned header field within the mapping
+        unsafe { &*self.map.as_ptr().add(offset).cast::<AtomicU32>() }
+    }
+
+    fn u64_at(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY: as above
+        unsafe { &*self.map.as_ptr().add(offset).cast::<AtomicU64>() }
+    }
+
+    fn state(&self) -> u32 {
+        self.u32_at(STATE_OFFSET).load(Ordering::Acquire)
+    }
+
+    fn set_state(&self, flags: u32) {
+        self.u32_at(STATE_OFFSET).fetch_or(flags, Ordering::AcqRel);
+    }
+
+    /// Start and size of a ring's data
+    fn area(&self, ring: Ring) -> (usize, usize) {
+        if ring == MESSAGES {
+            (SHM_HEADER_SIZE, self.messages)
+        } else {
+            (SHM_HEADER_SIZE + self.messages, self.events)
+        }
+    }
+
+    /// Bytes that can be written to a ring now
+    fn free(&self, ring: Ring) -> usize {
+        let (_, size) = self.area(ring);
+        let used = self.u64_at(ring.write).load(Ordering::Relaxed) - self.u64_at(ring.read).load(Ordering::Acquire);
+        size - used as usize
+    }
+
+    /// Write as much of `data` as fits; only one side writes each ring
+    fn push(&self, ring: Ring, data: &[u8]) -> usize {
+        let (start, size) = self.area(ring);
+        let n = data.len().min(self.free(ring));
+        let write = self.u64_at(ring.write).load(Ordering::Relaxed);
+        let at = (write % size as u64) as usize;
+        let first = n.min(size - at);
+        // SAFETY: both copies stay within the ring, in bytes the reader is
+        // done with
+        unsafe {
+            let base = self.map.as_ptr().add(start);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), base.add(at), first);
+            std::ptr::copy_nonoverlapping(data.as_ptr().add(first), base, n - first);
+        }
+        self.u64_at(ring.write).store(write + n as u64, Ordering::Release);
+        n
+    }
+
+    /// Read as much as is available into `buf`; only one side reads each ring
+    fn pop(&self, ring: Ring, buf: &mut [u8]) -> usize {
+        let (start, size) = self.area(ring);
+        let read = self.u64_at(ring.read).load(Ordering::Relaxed);
+        let available = (self.u64_at(ring.write).load(Ordering::Acquire) - read) as usize;
+        let n = buf.len().min(available);
+        let at = (read % size as u64) as usize;
+        let first = n.min(size - at);
+        // SAFETY: both copies stay within the ring, in bytes the writer
+        // published
+        unsafe {
+            let base = self.map.as_ptr().add(start);
+            std::ptr::copy_nonoverlapping(base.add(at), buf.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(base, buf.as_mut_ptr().add(first), n - first);
+        }
+        self.u64_at(ring.read).store(read + n as u64, Ordering::Release);
+        n
+    }
+}
+
+/// winpipe's end of the transport
+pub struct ShmConnection {
+    channel: Channel,
+    doorbell: platform::Doorbell,
+}
+
+impl ShmConnection {
+    /// Connect to the transport win-way created under `name`
+    pub fn connect(name: &str) -> Result<Self> {
+        let map = SharedMapping::open(&shm_path(name))?;
+        let invalid = |reason: &str| WinpipeError::Protocol(format!("shared memory {}: {}", name, reason));
+        if map.len() < SHM_HEADER_SIZE || &map[0..4] != SHM_MAGIC {
+            return Err(invalid("not a render transport"));
+        }
+        let field = |i: usize| u32::from_le_bytes([map[i], map[i + 1], map[i + 2], map[i + 3]]) as usize;
+        if field(4) != SHM_VERSION as usize {
+            return Err(invalid("unsupported version"));
+        }
+        let (messages, events) = (field(8), field(12));
+        if messages == 0 || events == 0 || map.len() < SHM_HEADER_SIZE + messages + events {
+            return Err(invalid("rings do not fit"));
+        }
+
+        let channel = Channel { map, messages, events };
+        if channel.u32_at(STATE_OFFSET).compare_exchange(0, state::CONNECTED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(invalid("already in use"));
+        }
+        let doorbell = platform::Doorbell::open(name)?;
+        doorbell.ring();
+        Ok(Self { channel, doorbell })
+    }
+
+    /// Write render messages, waiting while the ring is full
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<()> {
+        loop {
+            if self.channel.state() & state::RENDERER_CLOSED != 0 {
+                return Err(WinpipeError::ConnectionClosed);
+            }
+            let n = self.channel.push(MESSAGES, data);
+            if n > 0 {
+                self.doorbell.ring();
+            }
+            data = &data[n..];
+            if data.is_empty() {
+                return Ok(());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Read renderer events, waiting until there are some
+    ///
+    /// Returns 0 once win-way closed its end. Cancel-safe.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let n = self.channel.pop(EVENTS, buf);
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            if self.channel.state() & state::RENDERER_CLOSED != 0 {
+                return Ok(0);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for ShmConnection {
+    fn drop(&mut self) {
+        self.channel.set_state(state::WINPIPE_CLOSED);
+        self.doorbell.ring();
+    }
+}
+
+/// win-way's end of the transport
+pub struct ShmListener {
+    channel: Channel,
+    doorbell: platform::Doorbell,
+}
+
+impl ShmListener {
+    /// Create the transport under `name` with a `size` byte message ring
+    pub fn create(name: &str, size: usize) -> Result<Self> {
+        let (messages, events) = (size.max(1), EVENT_RING_SIZE);
+        if u32::try_from(messages).is_err() {
+            return Err(WinpipeError::Buffer(format!("ring size {} is too large", messages)));
+        }
+        let doorbell = platform::Doorbell::create(name)?;
+        let mut map = SharedMapping::create(&shm_path(name), SHM_HEADER_SIZE + messages + events)?;
+        map[0..4].copy_from_slice(SHM_MAGIC);
+        map[4..8].copy_from_slice(&SHM_VERSION.to_le_bytes());
+        map[8..12].copy_from_slice(&(messages as u32).to_le_bytes());
+        map[12..16].copy_from_slice(&(events as u32).to_le_bytes());
+        Ok(Self { channel: Channel { map, messages, events }, doorbell })
+    }
+
+    /// Wait up to `timeout` for winpipe to connect
+    pub fn accept(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.channel.state() & state::CONNECTED != 0 {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            self.doorbell.wait(deadline - now);
+        }
+    }
+
+    /// Read render messages, waiting up to `timeout`; 0 if none came
+    pub fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let n = self.channel.pop(MESSAGES, buf);
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            if self.channel.state() & state::WINPIPE_CLOSED != 0 {
+                return Err(WinpipeError::ConnectionClosed);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(0);
+            }
+            self.doorbell.wait(deadline - now);
+        }
+    }
+
+    /// Send renderer events; fails if winpipe has not read enough of the
+    /// earlier ones to fit them
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.channel.free(EVENTS) < data.len() {
+            return Err(WinpipeError::Buffer("renderer event ring is full".to_string()));
+        }
+        self.channel.push(EVENTS, data);
+        Ok(())
+    }
+}
+
+impl Drop for ShmListener {
+    fn drop(&mut self) {
+        self.channel.set_state(state::RENDERER_CLOSED);
+        let _ = std::fs::remove_file(self.channel.map.path());
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+    use std::time::Duration;
+
+    use super::POLL_INTERVAL;
+
+    /// No named events: the waiting side polls
+    pub struct Doorbell;
+
+    impl Doorbell {
+        pub fn create(_name: &str) -> io::Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn open(_name: &str) -> io::Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn ring(&self) {}
+
+        pub fn wait(&self, timeout: Duration) {
+            std::thread::sleep(timeout.min(POLL_INTERVAL));
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::ptr;
+    use std::time::Duration;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::Threading::{CreateEventW, OpenEventW, SetEvent, WaitForSingleObject, EVENT_MODIFY_STATE};
+
+    /// Named auto-reset event win-way waits on
+    pub struct Doorbell(HANDLE);
+
+    // SAFETY: event handles may be used from any thread
+    unsafe impl Send for Doorbell {}
+    unsafe impl Sync for Doorbell {}
+
+    fn event_name(name: &str) -> Vec<u16> {
+        format!("Local\\winpipe-{}", name).encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn checked(handle: HANDLE) -> io::Result<Doorbell> {
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Doorbell(handle))
+    }
+
+    impl Doorbell {
+        pub fn create(name: &str) -> io::Result<Self> {
+            // SAFETY: plain Win32 call with a NUL-terminated name
+            checked(unsafe { CreateEventW(ptr::null(), 0, 0, event_name(name).as_ptr()) })
+        }
+
+        pub fn open(name: &str) -> io::Result<Self> {
+            // SAFETY: as above
+            checked(unsafe { OpenEventW(EVENT_MODIFY_STATE, 0, event_name(name).as_ptr()) })
+        }
+
+        pub fn ring(&self) {
+            // SAFETY: the handle is open for the lifetime of `self`
+            unsafe { SetEvent(self.0) };
+        }
+
+        pub fn wait(&self, timeout: Duration) {
+            let ms = timeout.as_millis().min(u32::MAX as u128 - 1) as u32;
+            // SAFETY: as above
+            unsafe { WaitForSingleObject(self.0, ms) };
+        }
+    }
+
+    impl Drop for Doorbell {
+        fn drop(&mut self) {
+            // SAFETY: closes the handle `create` or `open` returned
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_wrap_around_ring() {
+        let name = format!("test-{}", std::process::id());
+        let mut listener = ShmListener::create(&name, 64).unwrap();
+        assert!(!listener.accept(Duration::ZERO));
+        let mut connection = ShmConnection::connect(&name).unwrap();
+        assert!(listener.accept(Duration::ZERO));
+        assert!(ShmConnection::connect(&name).is_err());
+
+        // More than the ring holds goes through as the renderer reads
+        let sent: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 48];
+            while received.len() < 1000 {
+                let n = listener.read(&mut buf, Duration::from_secs(1)).unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            (listener, received)
+        });
+        connection.write_all(&sent).await.unwrap();
+        let (mut listener, received) = reader.join().unwrap();
+        assert_eq!(received, sent);
+
+        listener.write(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert!(listener.write(&vec![0; EVENT_RING_SIZE + 1]).is_err());
+
+        drop(connection);
+        assert!(matches!(listener.read(&mut buf, Duration::ZERO), Err(WinpipeError::ConnectionClosed)));
+        let path = shm_path(&name);
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,117 @@
+//! Renderer Backends
+//!
+//! A `RenderSink` is how render messages reach the screen and renderer
+//! events come back. Backends only move bytes: TCP to win-way, the
+//! shared-memory rings, a Windows named pipe or the in-process pipe of the
+//! built-in renderer. Encoding messages is the same for all of them and
+//! lives in the provided methods, so `RenderClient` and the compositor
+//! above it do not care which backend is in use.
+
+use std::future::Future;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+
+use crate::error::Result;
+use crate::render::{CursorUpdate, RenderFrame, RenderMessage, WindowInfo};
+use crate::shm_transport::ShmConnection;
+
+/// Where render messages go and renderer events come from
+pub trait RenderSink: Send {
+    /// Send encoded render messages
+    fn write_all(&mut self, data: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Receive encoded renderer events; 0 bytes once the renderer is gone
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Send a frame, checksummed and compressed if asked to
+    fn submit_frame(&mut self, frame: &RenderFrame, checksum: bool, compress: bool) -> impl Future<Output = Result<()>> + Send {
+        let data = frame.encode_with(checksum, compress);
+        async move { self.write_all(&data).await }
+    }
+
+    /// Change the cursor shown over the current window
+    fn set_cursor(&mut self, cursor: &CursorUpdate) -> impl Future<Output = Result<()>> + Send {
+        let data = cursor.encode();
+        async move { self.write_all(&data).await }
+    }
+
+    /// Update the title and state of the current window
+    fn send_window_info(&mut self, info: &WindowInfo) -> impl Future<Output = Result<()>> + Send {
+        let data = info.encode();
+        async move { self.write_all(&data).await }
+    }
+
+    /// Send any other render message
+    fn send_message(&mut self, message: &RenderMessage) -> impl Future<Output = Result<()>> + Send {
+        let data = message.encode();
+        async move { self.write_all(&data).await }
+    }
+}
+
+/// win-way over TCP
+impl RenderSink for TcpStream {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        Ok(AsyncWriteExt::write_all(self, data).await?)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(AsyncReadExt::read(self, buf).await?)
+    }
+}
+
+/// win-way on the same machine, through shared memory
+impl RenderSink for ShmConnection {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        ShmConnection::write_all(self, data).await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        ShmConnection::read(self, buf).await
+    }
+}
+
+/// The built-in renderer, through its in-process pipe
+impl RenderSink for DuplexStream {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        Ok(AsyncWriteExt::write_all(self, data).await?)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(AsyncReadExt::read(self, buf).await?)
+    }
+}
+
+/// win-way over a Windows named pipe
+#[cfg(windows)]
+impl RenderSink for tokio::net::windows::named_pipe::NamedPipeClient {
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        Ok(AsyncWriteExt::write_all(self, data).await?)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(AsyncReadExt::read(self, buf).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{FrameDecoder, PixelFormat};
+
+    #[tokio::test]
+    async fn test_messages_encoded_once_for_all_backends() {
+        let (mut sink, mut renderer) = tokio::io::duplex(1 << 16);
+        let frame = RenderFrame::new(2, 1, PixelFormat::ARGB8888, vec![7; 8]);
+        sink.submit_frame(&frame, true, false).await.unwrap();
+        sink.set_cursor(&CursorUpdate::Named(2)).await.unwrap();
+        drop(sink);
+
+        let mut data = Vec::new();
+        renderer.read_to_end(&mut data).await.unwrap();
+        let mut decoder = FrameDecoder::strict();
+        decoder.push(&data);
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Frame(decoded)) if decoded.data == frame.data));
+        assert!(matches!(decoder.decode_message(), Some(RenderMessage::Cursor(CursorUpdate::Named(2)))));
+    }
+}
@@ -0,0 +1,180 @@
+//! `winpipe ssh`
+//!
+//! Shows an application running on any Linux machine reachable over SSH,
+//! like `waypipe ssh` does:
+//!
+//! ```text
+//! winpipe ssh user@host foot
+//! ```
+//!
+//! ssh forwards a Unix socket on the remote machine to the local server,
+//! and the remote command starts `winpipe client` on it, waits for its
+//! Wayland socket, runs the application with WAYLAND_DISPLAY pointing
+//! there, and removes both sockets when the application exits or the
+//! session is cut.
+//!
+//! The server's token travels in the `WINPIPE_TOKEN` environment variable
+//! (`SendEnv`), so it never shows in a command line and the session's
+//! input stays with the application, `-t` included. The remote sshd has
+//! to list it in `AcceptEnv`.
+
+use crate::auth::TOKEN_ENV;
+
+/// ssh options that take a value, so the value is not mistaken for the
+/// destination
+const SSH_OPTIONS_WITH_VALUE: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// ssh arguments split into options, destination and remote command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshInvocation {
+    pub options: Vec<String>,
+    pub destination: String,
+    pub command: Vec<String>,
+}
+
+impl SshInvocation {
+    /// Split `[SSH_OPTION]... DESTINATION COMMAND...`; None without a
+    /// destination and command
+    pub fn parse(args: &[String]) -> Option<Self> {
+        let mut options = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            }
+            let Some(flags) = arg.strip_prefix('-') else {
+                let command: Vec<String> = args.cloned().collect();
+                return (!command.is_empty()).then(|| Self { options, destination: arg.clone(), command });
+            };
+            options.push(arg.clone());
+            // -p 22, but not -p22 or -vp22
+            let takes_value = flags.chars().last().is_some_and(|flag| SSH_OPTIONS_WITH_VALUE.contains(flag));
+            if takes_value && flags.chars().filter(|c| c.is_ascii_alphabetic()).count() == flags.len() {
+                options.extend(args.next().cloned());
+            }
+        }
+        let destination = args.next()?.clone();
+        let command: Vec<String> = args.cloned().collect();
+        (!command.is_empty()).then_some(Self { options, destination, command })
+    }
+
+    /// Arguments for ssh: the tunnel from `remote_socket` to the server on
+    /// `port`, then the user's options, destination and the remote script
+    ///
+    /// With `send_token`, ssh passes on `WINPIPE_TOKEN` from its
+    /// environment and the script requires it.
+    pub fn ssh_args(&self, port: u16, remote_socket: &str, remote_winpipe: &str, send_token: bool) -> Vec<String> {
+        let mut args = vec![
+            "-R".to_string(),
+            format!("{}:127.0.0.1:{}", remote_socket, port),
+            "-o".to_string(),
+            "StreamLocalBindUnlink=yes".to_string(),
+        ];
+        if send_token {
+            args.extend(["-o".to_string(), format!("SendEnv={}", TOKEN_ENV)]);
+        }
+        args.extend(self.options.iter().cloned());
+        args.push(self.destination.clone());
+        // The login shell may not be sh
+        let script = remote_script(remote_socket, remote_winpipe, &self.command, send_token);
+        args.push(format!("sh -c {}", shell_quote(&script)));
+        args
+    }
+}
+
+/// The remote side: run `winpipe client` on the forwarded socket and the
+/// command against it, cleaning up on exit or hangup
+///
+/// With `require_token`, the script stops unless the token arrived, and
+/// it only reaches the client's environment.
+pub fn remote_script(remote_socket: &str, remote_winpipe: &str, command: &[String], require_token: bool) -> String {
+    let display = format!("\"${{XDG_RUNTIME_DIR:-/tmp}}\"/{}", shell_quote(&wayland_socket_name(remote_socket)));
+    let command: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+    let (check, unset) = if require_token {
+        (
+            format!(
+                "[ -n \"${env}\" ] || {{ echo 'winpipe: {env} did not arrive; add it to AcceptEnv in sshd_config' >&2; exit 1; }}\n",
+                env = TOKEN_ENV
+            ),
+            format!("unset {}\n", TOKEN_ENV),
+        )
+    } else {
+        (String::new(), String::new())
+    };
+    format!(
+        "{check}display={display}\n\
+         {winpipe} client --server {server} --socket \"$display\" &\n\
+         client=$!\n\
+         {unset}\
+         trap 'kill $client 2>/dev/null; rm -f {server} \"$display\"' EXIT\n\
+         trap 'exit 129' HUP INT TERM\n\
+         while [ ! -S \"$display\" ]; do kill -0 $client 2>/dev/null || exit 1; sleep 0.1; done\n\
+         WAYLAND_DISPLAY=\"$display\" {command}\n",
+        winpipe = shell_quote(remote_winpipe),
+        server = shell_quote(remote_socket),
+        command = command.join(" "),
+    )
+}
+
+/// A remote socket path no other session uses
+pub fn remote_socket_path() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    format!("/tmp/winpipe-{:x}-{:x}.sock", std::process::id(), nanos)
+}
+
+/// Name of the Wayland socket for the session on `remote_socket`
+fn wayland_socket_name(remote_socket: &str) -> String {
+    let session = remote_socket.rsplit('/').next().unwrap_or(remote_socket).trim_end_matches(".sock");
+    format!("wayland-{}", session)
+}
+
+/// Quote `arg` for a POSIX shell
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:@,+%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ssh_options_split_from_command() {
+        let invocation = SshInvocation::parse(&args(&["-p", "2222", "-v", "-oBatchMode=yes", "me@box", "foot", "-e", "top"])).unwrap();
+        assert_eq!(invocation.options, args(&["-p", "2222", "-v", "-oBatchMode=yes"]));
+        assert_eq!(invocation.destination, "me@box");
+        assert_eq!(invocation.command, args(&["foot", "-e", "top"]));
+
+        // A command is required
+        assert_eq!(SshInvocation::parse(&args(&["-p", "22", "box"])), None);
+        assert_eq!(SshInvocation::parse(&args(&["--", "-box", "app"])).unwrap().destination, "-box");
+    }
+
+    #[test]
+    fn test_remote_script_quotes_command() {
+        let script = remote_script("/tmp/winpipe-1.sock", "winpipe", &args(&["sh", "-c", "echo 'hi' $HOME"]), false);
+        assert!(script.starts_with("display=\"${XDG_RUNTIME_DIR:-/tmp}\"/wayland-winpipe-1\n"));
+        assert!(script.contains("winpipe client --server /tmp/winpipe-1.sock --socket \"$display\" &\n"));
+        assert!(script.ends_with("WAYLAND_DISPLAY=\"$display\" sh -c 'echo '\\''hi'\\'' $HOME'\n"));
+        assert_eq!(shell_quote(""), "''");
+
+        // The token comes through the environment, not the command line,
+        // and only the client keeps it
+        let script = remote_script("/tmp/winpipe-1.sock", "winpipe", &args(&["foot"]), true);
+        assert!(script.starts_with("[ -n \"$WINPIPE_TOKEN\" ] || {"));
+        assert!(script.contains("client=$!\nunset WINPIPE_TOKEN\n"));
+        let invocation = SshInvocation::parse(&args(&["-t", "box", "foot"])).unwrap();
+        let ssh_args = invocation.ssh_args(9999, "/tmp/winpipe-1.sock", "winpipe", true);
+        assert_eq!(ssh_args[4..7], args(&["-o", "SendEnv=WINPIPE_TOKEN", "-t"]));
+    }
+}
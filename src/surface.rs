@@ -0,0 +1,1058 @@
+//! Surface State and Subsurface Tree
+//!
+//! Wayland surface state is double-buffered: requests like attach update
+//! the pending state, and commit makes it current. Subsurfaces add a tree
+//! on top of that:
+//! - Each subsurface has a parent and a position relative to it
+//! - Siblings (and the parent itself) form a stacking order
+//! - In synchronized mode a subsurface commit is cached and only applied
+//!   when the parent commits
+//!
+//! Before a frame goes to the renderer, the whole tree is composited into
+//! the root surface's buffer, followed by the trees of the window's popups
+//! (menus, tooltips) on top. The damage the trees reported since the last
+//! frame tells which parts of it need diffing.
+
+use std::collections::HashMap;
+
+use crate::error::{Result, WinpipeError};
+use crate::output::Transform;
+use crate::positioner::Rect;
+use crate::region::Region;
+use crate::render::{ColorSpace, PixelFormat, PresentationHint, RenderFrame};
+
+/// wp_viewport crop and scale state
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Viewport {
+    /// Source rectangle in surface coordinates (x, y, width, height), i.e.
+    /// after the buffer transform and scale
+    pub source: Option<(f64, f64, f64, f64)>,
+    /// Destination size in surface coordinates
+    pub destination: Option<(i32, i32)>,
+}
+
+/// Largest width or height of a surface, in surface coordinates and in
+/// composited pixels
+pub const MAX_SURFACE_SIZE: u32 = 16384;
+
+/// Damage rectangles kept per surface before it counts as fully damaged
+const MAX_DAMAGE_RECTS: usize = 64;
+
+/// Damage accumulated since the surface was last composited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Damage {
+    /// Nothing to redraw
+    #[default]
+    None,
+    /// wl_surface.damage rectangles in surface coordinates and
+    /// damage_buffer rectangles in buffer coordinates
+    Rects { surface: Vec<Rect>, buffer: Vec<Rect> },
+    /// The whole surface, e.g. after a commit that reported no damage
+    Full,
+}
+
+impl Damage {
+    /// Add newer damage to this one
+    fn add(&mut self, newer: &Damage) {
+        match (&mut *self, newer) {
+            (_, Damage::None) | (Damage::Full, _) => {}
+            (Damage::None, _) | (_, Damage::Full) => *self = newer.clone(),
+            (Damage::Rects { surface, buffer }, Damage::Rects { surface: more, buffer: more_buffer }) => {
+                surface.extend_from_slice(more);
+                buffer.extend_from_slice(more_buffer);
+                if surface.len() + buffer.len() > MAX_DAMAGE_RECTS {
+                    *self = Damage::Full;
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, rect: Rect, in_buffer: bool) {
+        let mut rects = Damage::Rects { surface: Vec::new(), buffer: Vec::new() };
+        if let Damage::Rects { surface, buffer } = &mut rects {
+            if in_buffer { buffer.push(rect) } else { surface.push(rect) }
+        }
+        self.add(&rects);
+    }
+}
+
+/// Double-buffered surface state
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceState {
+    /// Attached wl_buffer (None = no content)
+    pub buffer: Option<u32>,
+    /// Whether `buffer` was set by an attach since the last commit
+    pub attached: bool,
+    /// Crop and scale from wp_viewport
+    pub viewport: Viewport,
+    /// Area known to be opaque (None = nothing is opaque)
+    pub opaque_region: Option<Region>,
+    /// Area accepting input (None = the whole surface)
+    pub input_region: Option<Region>,
+    /// Tearing and content type hints
+    pub hints: PresentationHint,
+    /// Image description from wp_color_management_surface_v1
+    pub color_space: ColorSpace,
+    /// wp_alpha_modifier_surface_v1 multiplier, u32::MAX = 1.0 (None = no
+    /// modifier)
+    pub alpha: Option<u32>,
+    /// Transform the client already applied to the buffer contents
+    pub transform: Transform,
+    /// wl_surface.set_buffer_scale (None = 1)
+    pub buffer_scale: Option<u32>,
+    /// wl_surface.damage / damage_buffer
+    pub damage: Damage,
+}
+
+impl SurfaceState {
+    /// Fold newer state into this one
+    ///
+    /// The buffer only changes if it was attached and damage accumulates;
+    /// everything else is plain double-buffered state and is copied as-is.
+    fn merge(&mut self, newer: &SurfaceState) {
+        if newer.attached {
+            self.buffer = newer.buffer;
+            self.attached = true;
+        }
+        self.viewport = newer.viewport;
+        self.opaque_region = newer.opaque_region.clone();
+        self.input_region = newer.input_region.clone();
+        self.hints = newer.hints;
+        self.color_space = newer.color_space;
+        self.alpha = newer.alpha;
+        self.transform = newer.transform;
+        self.buffer_scale = newer.buffer_scale;
+        self.damage.add(&newer.damage);
+    }
+
+    /// Buffer pixels per surface unit
+    pub fn scale(&self) -> u32 {
+        self.buffer_scale.unwrap_or(1)
+    }
+}
+
+/// Subsurface role state
+#[derive(Debug, Clone)]
+pub struct Subsurface {
+    /// The wl_subsurface object
+    pub object_id: u32,
+    /// Parent wl_surface
+    pub parent: u32,
+    /// Position relative to the parent (applied on parent commit)
+    pub position: (i32, i32),
+    pub pending_position: (i32, i32),
+    /// Synchronized mode (the default)
+    pub sync: bool,
+    /// State committed while synchronized, waiting for the parent commit
+    pub cached: Option<SurfaceState>,
+}
+
+/// A wl_surface
+#[derive(Debug, Clone, Default)]
+pub struct Surface {
+    pub pending: SurfaceState,
+    pub current: SurfaceState,
+    /// Subsurface role, if any
+    pub subsurface: Option<Subsurface>,
+    /// Stacking order of this surface and its direct children, bottom to top.
+    /// Always contains the surface itself.
+    pub stack: Vec<u32>,
+}
+
+/// A view of buffer pixels used for compositing
+#[derive(Debug, Clone, Copy)]
+pub struct BufferView<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub data: &'a [u8],
+    /// Whether the alpha channel should be ignored (XRGB8888)
+    pub opaque: bool,
+}
+
+/// All surfaces of a client
+#[derive(Debug, Default)]
+pub struct SurfaceTree {
+    surfaces: HashMap<u32, Surface>,
+}
+
+impl SurfaceTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new wl_surface
+    pub fn create(&mut self, id: u32) {
+        self.surfaces.insert(id, Surface {
+            stack: vec![id],
+            ..Default::default()
+        });
+    }
+
+    /// Number of live wl_surfaces
+    pub fn count(&self) -> usize {
+        self.surfaces.len()
+    }
+
+    /// Destroy a wl_surface, unmapping any subsurfaces attached to it
+    pub fn destroy(&mut self, id: u32) {
+        let Some(surface) = self.surfaces.remove(&id) else {
+            return;
+        };
+
+        if let Some(sub) = &surface.subsurface {
+            if let Some(parent) = self.surfaces.get_mut(&sub.parent) {
+                parent.stack.retain(|&s| s != id);
+                parent.current.damage = Damage::Full;
+            }
+        }
+        for child in surface.stack.into_iter().filter(|&c| c != id) {
+            if let Some(child) = self.surfaces.get_mut(&child) {
+                child.subsurface = None;
+            }
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Surface> {
+        self.surfaces.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Surface> {
+        self.surfaces.get_mut(&id)
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.surfaces.contains_key(&id)
+    }
+
+    /// wl_surface.attach
+    pub fn attach(&mut self, id: u32, buffer: Option<u32>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.buffer = buffer;
+            surface.pending.attached = true;
+        }
+    }
+
+    /// wp_viewport.set_source (None unsets it)
+    pub fn set_viewport_source(&mut self, id: u32, source: Option<(f64, f64, f64, f64)>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.viewport.source = source;
+        }
+    }
+
+    /// wp_viewport.set_destination (None unsets it)
+    pub fn set_viewport_destination(&mut self, id: u32, destination: Option<(i32, i32)>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.viewport.destination = destination;
+        }
+    }
+
+    /// wl_surface.set_opaque_region (None resets to empty)
+    pub fn set_opaque_region(&mut self, id: u32, region: Option<Region>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.opaque_region = region;
+        }
+    }
+
+    /// wp_tearing_control_v1.set_presentation_hint (true = async)
+    pub fn set_tearing(&mut self, id: u32, tearing: bool) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.hints.tearing = tearing;
+        }
+    }
+
+    /// wp_content_type_v1.set_content_type
+    pub fn set_content_type(&mut self, id: u32, content_type: u32) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.hints.content_type = content_type;
+        }
+    }
+
+    /// wp_color_management_surface_v1.set_image_description (sRGB when unset)
+    pub fn set_color_space(&mut self, id: u32, color_space: ColorSpace) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.color_space = color_space;
+        }
+    }
+
+    /// wp_alpha_modifier_surface_v1.set_multiplier (None removes the modifier)
+    pub fn set_alpha(&mut self, id: u32, alpha: Option<u32>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.alpha = alpha;
+        }
+    }
+
+    /// wl_surface.set_buffer_transform
+    pub fn set_buffer_transform(&mut self, id: u32, transform: Transform) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.transform = transform;
+        }
+    }
+
+    /// wl_surface.set_buffer_scale
+    pub fn set_buffer_scale(&mut self, id: u32, scale: u32) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.buffer_scale = (scale != 1).then_some(scale);
+        }
+    }
+
+    /// wl_surface.damage (surface coordinates) or damage_buffer
+    ///
+    /// Surface damage is clipped to the largest surface. Buffer damage is
+    /// only clipped to where its edges fit in an i32, since the buffer and
+    /// its scale may still change before the commit.
+    pub fn damage(&mut self, id: u32, rect: Rect, in_buffer: bool) {
+        let limit = if in_buffer { i32::MAX as i64 } else { MAX_SURFACE_SIZE as i64 };
+        let clip = |start: i32, len: i32| {
+            let end = (start as i64 + len as i64).min(limit);
+            let start = (start as i64).clamp(0, limit);
+            (start as i32, (end - start).max(0) as i32)
+        };
+        let ((x, width), (y, height)) = (clip(rect.x, rect.width), clip(rect.y, rect.height));
+        if width == 0 || height == 0 {
+            return;
+        }
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.damage.push(Rect::new(x, y, width, height), in_buffer);
+        }
+    }
+
+    /// Redraw the whole surface with the next frame, e.g. when a popup
+    /// above it went away
+    pub fn damage_all(&mut self, id: u32) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.current.damage = Damage::Full;
+        }
+    }
+
+    /// wl_surface.set_input_region (None resets to infinite)
+    pub fn set_input_region(&mut self, id: u32, region: Option<Region>) {
+        if let Some(surface) = self.surfaces.get_mut(&id) {
+            surface.pending.input_region = region;
+        }
+    }
+
+    /// wl_subcompositor.get_subsurface: give `surface` the subsurface role
+    pub fn add_subsurface(&mut self, object_id: u32, surface: u32, parent: u32) -> Result<()> {
+        if surface == parent || self.is_ancestor(surface, parent) {
+            return Err(WinpipeError::Protocol(
+                format!("wl_surface@{} cannot be its own ancestor", surface)
+            ));
+        }
+        if !self.surfaces.contains_key(&parent) {
+            return Err(WinpipeError::Protocol(format!("Unknown parent wl_surface@{}", parent)));
+        }
+        let Some(child) = self.surfaces.get_mut(&surface) else {
+            return Err(WinpipeError::Protocol(format!("Unknown wl_surface@{}", surface)));
+        };
+        if child.subsurface.is_some() {
+            return Err(WinpipeError::Protocol(format!("wl_surface@{} already has a role", surface)));
+        }
+
+        child.subsurface = Some(Subsurface {
+            object_id,
+            parent,
+            position: (0, 0),
+            pending_position: (0, 0),
+            sync: true,
+            cached: None,
+        });
+
+        // New subsurfaces start at the top of the parent's stack
+        if let Some(parent) = self.surfaces.get_mut(&parent) {
+            parent.stack.push(surface);
+        }
+        Ok(())
+    }
+
+    /// wl_subsurface.destroy: remove the role, unmapping the surface
+    pub fn remove_subsurface(&mut self, surface: u32) {
+        let Some(sub) = self.surfaces.get_mut(&surface).and_then(|s| s.subsurface.take()) else {
+            return;
+        };
+        if let Some(parent) = self.surfaces.get_mut(&sub.parent) {
+            parent.stack.retain(|&s| s != surface);
+            parent.current.damage = Damage::Full;
+        }
+    }
+
+    /// Find the surface holding a wl_subsurface object
+    pub fn surface_for_subsurface(&self, object_id: u32) -> Option<u32> {
+        self.surfaces.iter()
+            .find(|(_, s)| s.subsurface.as_ref().is_some_and(|sub| sub.object_id == object_id))
+            .map(|(&id, _)| id)
+    }
+
+    /// wl_subsurface.set_position
+    pub fn set_position(&mut self, surface: u32, x: i32, y: i32) {
+        if let Some(sub) = self.surfaces.get_mut(&surface).and_then(|s| s.subsurface.as_mut()) {
+            sub.pending_position = (x, y);
+        }
+    }
+
+    /// wl_subsurface.place_above / place_below
+    pub fn restack(&mut self, surface: u32, sibling: u32, above: bool) -> Result<()> {
+        let parent = self.parent(surface)
+            .ok_or_else(|| WinpipeError::Protocol(format!("wl_surface@{} is not a subsurface", surface)))?;
+        let stack = &mut self.surfaces.get_mut(&parent)
+            .ok_or_else(|| WinpipeError::Protocol(format!("Unknown parent wl_surface@{}", parent)))?
+            .stack;
+
+        if sibling == surface || !stack.contains(&sibling) {
+            return Err(WinpipeError::Protocol(
+                format!("wl_surface@{} is not a sibling of wl_surface@{}", sibling, surface)
+            ));
+        }
+
+        stack.retain(|&s| s != surface);
+        let pos = stack.iter().position(|&s| s == sibling).unwrap_or(0);
+        stack.insert(if above { pos + 1 } else { pos }, surface);
+        if let Some(parent) = self.surfaces.get_mut(&parent) {
+            parent.current.damage = Damage::Full;
+        }
+        Ok(())
+    }
+
+    /// wl_subsurface.set_sync / set_desync
+    ///
+    /// Switching to desynchronized mode applies any cached state right away.
+    pub fn set_sync(&mut self, surface: u32, sync: bool) {
+        if let Some(sub) = self.surfaces.get_mut(&surface).and_then(|s| s.subsurface.as_mut()) {
+            sub.sync = sync;
+        }
+        if !sync && !self.is_synchronized(surface) {
+            self.apply_cached(surface);
+        }
+    }
+
+    /// wl_surface.commit
+    ///
+    /// Returns the root surface whose composited content changed, or `None`
+    /// if the commit was cached (synchronized subsurface) or is unmapped.
+    pub fn commit(&mut self, id: u32) -> Option<u32> {
+        let pending = self.take_pending(id)?;
+        self.apply(id, pending)
+    }
+
+    /// Snapshot the pending state for a commit
+    ///
+    /// A commit that reported no damage damages the whole surface.
+    pub fn take_pending(&mut self, id: u32) -> Option<SurfaceState> {
+        let surface = self.surfaces.get_mut(&id)?;
+        let mut pending = surface.pending.clone();
+        if pending.damage == Damage::None {
+            pending.damage = Damage::Full;
+        }
+        surface.pending.attached = false;
+        surface.pending.damage = Damage::None;
+        Some(pending)
+    }
+
+    /// Apply committed state, possibly snapshotted by an earlier commit
+    ///
+    /// Returns the root surface if the state reached the current state.
+    pub fn apply(&mut self, id: u32, pending: SurfaceState) -> Option<u32> {
+        let synchronized = self.is_synchronized(id);
+        let surface = self.surfaces.get_mut(&id)?;
+
+        if synchronized {
+            if let Some(sub) = surface.subsurface.as_mut() {
+                sub.cached.get_or_insert_with(SurfaceState::default).merge(&pending);
+            }
+            return None;
+        }
+
+        surface.current.merge(&pending);
+        self.apply_children(id);
+        Some(self.root(id))
+    }
+
+    /// Parent of a subsurface
+    pub fn parent(&self, id: u32) -> Option<u32> {
+        self.surfaces.get(&id)?.subsurface.as_ref().map(|s| s.parent)
+    }
+
+    /// Top-most ancestor of a surface
+    pub fn root(&self, mut id: u32) -> u32 {
+        while let Some(parent) = self.parent(id) {
+            id = parent;
+        }
+        id
+    }
+
+    /// Whether a subsurface (or any of its ancestors) is synchronized
+    pub fn is_synchronized(&self, mut id: u32) -> bool {
+        while let Some(sub) = self.surfaces.get(&id).and_then(|s| s.subsurface.as_ref()) {
+            if sub.sync {
+                return true;
+            }
+            id = sub.parent;
+        }
+        false
+    }
+
+    /// Surfaces to draw for a tree, bottom to top, with absolute offsets
+    pub fn render_order(&self, root: u32) -> Vec<(u32, i32, i32)> {
+        let mut out = Vec::new();
+        self.collect(root, 0, 0, &mut out);
+        out
+    }
+
+    /// Surfaces to draw for a tree with other trees stacked on top, e.g. a
+    /// window and its popups, each layer given as (root, x, y)
+    pub fn layer_order(&self, root: u32, layers: &[(u32, i32, i32)]) -> Vec<(u32, i32, i32)> {
+        let mut out = self.render_order(root);
+        for &(layer, lx, ly) in layers {
+            out.extend(self.render_order(layer).into_iter().map(|(id, x, y)| (id, lx + x, ly + y)));
+        }
+        out
+    }
+
+    /// Composite a surface tree into a single frame
+    ///
+    /// Surface sizes and subsurface positions are logical; `scale` maps
+    /// them to output pixels, so a client that renders at the output scale
+    /// (e.g. via wp_fractional_scale_v1 and a viewport) is drawn 1:1.
+    ///
+    /// Buffers are turned upright according to their buffer transform and
+    /// shrunk by their buffer scale before the viewport applies, so a 2x
+    /// buffer covers the same logical area as a 1x one. Subsurfaces are drawn with their alpha
+    /// multiplier. The root's is left to the renderer, which applies it to
+    /// the window as a whole.
+    pub fn compose<'a, F>(&self, root: u32, scale: f64, lookup: F) -> Option<RenderFrame>
+    where
+        F: Fn(u32) -> Option<BufferView<'a>>,
+    {
+        self.compose_layers(root, &[], scale, lookup)
+    }
+
+    /// Composite a surface tree and the `layers` above it into a frame the
+    /// size of the root surface; layers are clipped to it
+    pub fn compose_layers<'a, F>(&self, root: u32, layers: &[(u32, i32, i32)], scale: f64, lookup: F) -> Option<RenderFrame>
+    where
+        F: Fn(u32) -> Option<BufferView<'a>>,
+    {
+        let to_pixels = |value: i32| (value as f64 * scale).round() as i32;
+        let to_size = |value: u32| to_pixels(value as i32).clamp(1, MAX_SURFACE_SIZE as i32) as u32;
+
+        let root_state = &self.surfaces.get(&root)?.current;
+        let (width, height) = surface_size(&lookup(root_state.buffer?)?, root_state);
+        let (width, height) = (to_size(width), to_size(height));
+        let mut canvas = vec![0u8; pixels_len(width, height)?];
+
+        for (id, x, y) in self.layer_order(root, layers) {
+            let Some(state) = self.surfaces.get(&id).map(|s| &s.current) else {
+                continue;
+            };
+            let Some(view) = state.buffer.and_then(&lookup) else {
+                continue;
+            };
+            let (w, h) = surface_size(&view, state);
+            let upright = if state.transform != Transform::Normal { apply_transform(&view, state.transform) } else { None };
+            let view = match &upright {
+                Some((w, h, pixels)) => BufferView { width: *w, height: *h, stride: w * 4, data: pixels, opaque: view.opaque },
+                None => view,
+            };
+            let target = (to_size(w), to_size(h));
+            let scaled = apply_viewport(&view, &state.viewport, state.scale(), target);
+            let view = match &scaled {
+                Some((w, h, pixels)) => BufferView { width: *w, height: *h, stride: w * 4, data: pixels, opaque: view.opaque },
+                None => view,
+            };
+            let faded = state.alpha.filter(|&alpha| id != root && alpha != u32::MAX).map(|alpha| apply_alpha(&view, alpha));
+            let view = match &faded {
+                Some(pixels) => BufferView { width: view.width, height: view.height, stride: view.width * 4, data: pixels, opaque: false },
+                None => view,
+            };
+            blit(&mut canvas, width, height, &view, to_pixels(x), to_pixels(y));
+        }
+
+        Some(RenderFrame::new(width, height, PixelFormat::ARGB8888, canvas))
+    }
+
+    /// Take the damage a tree accumulated, as rectangles in output pixels
+    /// of its composited frame
+    ///
+    /// Returns `None` if the whole frame must be treated as damaged.
+    /// Buffer damage is only mapped for buffers drawn without a transform
+    /// or viewport; otherwise it damages the whole frame too.
+    pub fn take_damage(&mut self, root: u32, scale: f64) -> Option<Vec<Rect>> {
+        self.take_layers_damage(root, &[], scale)
+    }
+
+    /// Take the damage of a tree and the `layers` above it, as for
+    /// `compose_layers`
+    pub fn take_layers_damage(&mut self, root: u32, layers: &[(u32, i32, i32)], scale: f64) -> Option<Vec<Rect>> {
+        let mut rects = Some(Vec::new());
+        for (id, x, y) in self.layer_order(root, layers) {
+            let Some(state) = self.surfaces.get_mut(&id).map(|s| &mut s.current) else {
+                continue;
+            };
+            let plain = state.transform == Transform::Normal && state.viewport == Viewport::default();
+            match std::mem::take(&mut state.damage) {
+                Damage::None => {}
+                Damage::Rects { buffer, .. } if !plain && !buffer.is_empty() => rects = None,
+                Damage::Rects { surface, buffer } => {
+                    let buffer_scale = state.scale() as f64;
+                    let to_surface = |r: Rect| (r.x as f64 / buffer_scale, r.y as f64 / buffer_scale,
+                        r.width as f64 / buffer_scale, r.height as f64 / buffer_scale);
+                    let all = surface.into_iter()
+                        .map(|r| (r.x as f64, r.y as f64, r.width as f64, r.height as f64))
+                        .chain(buffer.into_iter().map(to_surface));
+                    for (rx, ry, rw, rh) in all {
+                        // Round outwards so partially covered pixels count
+                        let x0 = ((x as f64 + rx) * scale).floor() as i32;
+                        let y0 = ((y as f64 + ry) * scale).floor() as i32;
+                        let x1 = ((x as f64 + rx + rw) * scale).ceil() as i32;
+                        let y1 = ((y as f64 + ry + rh) * scale).ceil() as i32;
+                        if let Some(rects) = rects.as_mut() {
+                            rects.push(Rect::new(x0, y0, x1.saturating_sub(x0), y1.saturating_sub(y0)));
+                        }
+                    }
+                }
+                Damage::Full => rects = None,
+            }
+        }
+        rects
+    }
+
+    fn collect(&self, id: u32, x: i32, y: i32, out: &mut Vec<(u32, i32, i32)>) {
+        let Some(surface) = self.surfaces.get(&id) else {
+            return;
+        };
+        for &entry in &surface.stack {
+            if entry == id {
+                out.push((id, x, y));
+            } else if let Some(sub) = self.surfaces.get(&entry).and_then(|s| s.subsurface.as_ref()) {
+                self.collect(entry, x + sub.position.0, y + sub.position.1, out);
+            }
+        }
+    }
+
+    /// Apply pending positions and cached state of children after a parent commit
+    fn apply_children(&mut self, id: u32) {
+        let children: Vec<u32> = match self.surfaces.get(&id) {
+            Some(surface) => surface.stack.iter().copied().filter(|&c| c != id).collect(),
+            None => return,
+        };
+
+        for child in children {
+            if let Some(sub) = self.surfaces.get_mut(&child).and_then(|s| s.subsurface.as_mut()) {
+                if sub.position != sub.pending_position {
+                    sub.position = sub.pending_position;
+                    if let Some(parent) = self.surfaces.get_mut(&id) {
+                        parent.current.damage = Damage::Full;
+                    }
+                }
+            }
+            if self.is_synchronized(child) {
+                self.apply_cached(child);
+            }
+        }
+    }
+
+    /// Make a subsurface's cached state current
+    fn apply_cached(&mut self, id: u32) {
+        let Some(surface) = self.surfaces.get_mut(&id) else {
+            return;
+        };
+        if let Some(cached) = surface.subsurface.as_mut().and_then(|s| s.cached.take()) {
+            surface.current.merge(&cached);
+        }
+        self.apply_children(id);
+    }
+
+    fn is_ancestor(&self, ancestor: u32, mut id: u32) -> bool {
+        while let Some(parent) = self.parent(id) {
+            if parent == ancestor {
+                return true;
+            }
+            id = parent;
+        }
+        false
+    }
+}
+
+/// Surface size of a buffer under `state`
+///
+/// The viewport destination size wins; otherwise the source rectangle
+/// size is used; otherwise the buffer size, transformed and divided by the
+/// buffer scale.
+pub fn surface_size(view: &BufferView, state: &SurfaceState) -> (u32, u32) {
+    let clamp = |value: u32| value.clamp(1, MAX_SURFACE_SIZE);
+    if let Some((w, h)) = state.viewport.destination {
+        return (clamp(w.max(1) as u32), clamp(h.max(1) as u32));
+    }
+    if let Some((_, _, w, h)) = state.viewport.source {
+        return (clamp(w as u32), clamp(h as u32));
+    }
+    let (w, h) = state.transform.apply_size((view.width, view.height));
+    (clamp(w / state.scale()), clamp(h / state.scale()))
+}
+
+/// Bytes of a width x height ARGB canvas, `None` if it does not fit in
+/// memory
+fn pixels_len(width: u32, height: u32) -> Option<usize> {
+    (width as usize).checked_mul(height as usize)?.checked_mul(4)
+}
+
+/// Undo a buffer transform, returning the upright (width, height, pixels)
+fn apply_transform(view: &BufferView, transform: Transform) -> Option<(u32, u32, Vec<u8>)> {
+    let (width, height) = transform.apply_size((view.width, view.height));
+    let mut pixels = vec![0u8; pixels_len(width, height)?];
+    for y in 0..height {
+        for x in 0..width {
+            // Where the surface pixel ended up in the transformed buffer
+            let (bx, by) = match transform {
+                Transform::Normal => (x, y),
+                Transform::Rotate90 => (y, width - 1 - x),
+                Transform::Rotate180 => (width - 1 - x, height - 1 - y),
+                Transform::Rotate270 => (height - 1 - y, x),
+                Transform::Flipped => (width - 1 - x, y),
+                Transform::Flipped90 => (y, x),
+                Transform::Flipped180 => (x, height - 1 - y),
+                Transform::Flipped270 => (height - 1 - y, width - 1 - x),
+            };
+            let src = by as usize * view.stride as usize + bx as usize * 4;
+            let dst = (y as usize * width as usize + x as usize) * 4;
+            if let Some(px) = view.data.get(src..src + 4) {
+                pixels[dst..dst + 4].copy_from_slice(px);
+            }
+        }
+    }
+    Some((width, height, pixels))
+}
+
+/// Crop a buffer according to its viewport and scale it to `target` pixels
+///
+/// `buffer_scale` maps the viewport's surface coordinates to buffer
+/// pixels. Returns `None` when no cropping is needed and the buffer
+/// already has the target size, so it can be used directly, or when the
+/// target is too large to allocate. Scaling uses nearest-neighbour
+/// sampling.
+fn apply_viewport(view: &BufferView, viewport: &Viewport, buffer_scale: u32, target: (u32, u32)) -> Option<(u32, u32, Vec<u8>)> {
+    if viewport.source.is_none() && target == (view.width, view.height) {
+        return None;
+    }
+
+    let scale = buffer_scale as f64;
+    let (src_x, src_y, src_w, src_h) = viewport.source
+        .map(|(x, y, w, h)| (x * scale, y * scale, w * scale, h * scale))
+        .unwrap_or((0.0, 0.0, view.width as f64, view.height as f64));
+    let (dst_w, dst_h) = target;
+
+    let mut pixels = vec![0u8; pixels_len(dst_w, dst_h)?];
+    for dy in 0..dst_h {
+        let sy = (src_y + (dy as f64 + 0.5) * src_h / dst_h as f64) as u32;
+        if sy >= view.height {
+            continue;
+        }
+        for dx in 0..dst_w {
+            let sx = (src_x + (dx as f64 + 0.5) * src_w / dst_w as f64) as u32;
+            if sx >= view.width {
+                continue;
+            }
+            let src = sy as usize * view.stride as usize + sx as usize * 4;
+            let dst = (dy as usize * dst_w as usize + dx as usize) * 4;
+            if let Some(px) = view.data.get(src..src + 4) {
+                pixels[dst..dst + 4].copy_from_slice(px);
+            }
+        }
+    }
+    Some((dst_w, dst_h, pixels))
+}
+
+/// Copy of a buffer's pixels with premultiplied alpha scaled by
+/// `multiplier` (u32::MAX = 1.0)
+fn apply_alpha(view: &BufferView, multiplier: u32) -> Vec<u8> {
+    let factor = multiplier as u64;
+    let mut pixels = Vec::with_capacity((view.width * view.height * 4) as usize);
+    for row in 0..view.height {
+        let start = (row * view.stride) as usize;
+        let Some(line) = view.data.get(start..start + view.width as usize * 4) else {
+            pixels.resize(((row + 1) * view.width * 4) as usize, 0);
+            continue;
+        };
+        for px in line.chunks_exact(4) {
+            let alpha = if view.opaque { 255 } else { px[3] };
+            for value in [px[0], px[1], px[2], alpha] {
+                pixels.push((value as u64 * factor / u32::MAX as u64) as u8);
+            }
+        }
+    }
+    pixels
+}
+
+/// Draw a buffer onto the canvas at (x, y) with premultiplied source-over
+fn blit(canvas: &mut [u8], width: u32, height: u32, view: &BufferView, x: i32, y: i32) {
+    for row in 0..view.height as i32 {
+        let dst_y = y + row;
+        if dst_y < 0 || dst_y >= height as i32 {
+            continue;
+        }
+        for col in 0..view.width as i32 {
+            let dst_x = x + col;
+            if dst_x < 0 || dst_x >= width as i32 {
+                continue;
+            }
+
+            let src = (row as u32 * view.stride + col as u32 * 4) as usize;
+            let Some(px) = view.data.get(src..src + 4) else {
+                continue;
+            };
+            let dst = ((dst_y as u32 * width + dst_x as u32) * 4) as usize;
+
+            let alpha = if view.opaque { 255 } else { px[3] as u32 };
+            if alpha == 255 {
+                canvas[dst..dst + 3].copy_from_slice(&px[..3]);
+                canvas[dst + 3] = 255;
+            } else if alpha > 0 {
+                for c in 0..4 {
+                    let s = if c == 3 { alpha } else { px[c] as u32 };
+                    let d = canvas[dst + c] as u32;
+                    canvas[dst + c] = (s + d * (255 - alpha) / 255).min(255) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_subsurface_waits_for_parent() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.create(2);
+        tree.add_subsurface(10, 2, 1).unwrap();
+
+        tree.attach(2, Some(200));
+        tree.set_position(2, 5, 6);
+        assert_eq!(tree.commit(2), None); // cached
+        assert_eq!(tree.get(2).unwrap().current.buffer, None);
+
+        assert_eq!(tree.commit(1), Some(1));
+        assert_eq!(tree.get(2).unwrap().current.buffer, Some(200));
+        assert_eq!(tree.render_order(1), vec![(1, 0, 0), (2, 5, 6)]);
+
+        // Desynchronized subsurfaces apply immediately
+        tree.set_sync(2, false);
+        tree.attach(2, Some(201));
+        assert_eq!(tree.commit(2), Some(1));
+        assert_eq!(tree.get(2).unwrap().current.buffer, Some(201));
+    }
+
+    #[test]
+    fn test_restack_and_cycles() {
+        let mut tree = SurfaceTree::new();
+        for id in 1..=3 {
+            tree.create(id);
+        }
+        tree.add_subsurface(10, 2, 1).unwrap();
+        tree.add_subsurface(11, 3, 1).unwrap();
+        tree.restack(2, 3, true).unwrap();
+        tree.restack(3, 1, false).unwrap();
+        assert_eq!(tree.get(1).unwrap().stack, vec![3, 1, 2]);
+
+        assert!(tree.add_subsurface(12, 1, 2).is_err());
+        assert_eq!(tree.surface_for_subsurface(11), Some(3));
+    }
+
+    #[test]
+    fn test_state_persists_across_commits() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.attach(1, Some(100));
+        tree.set_viewport_destination(1, Some((8, 8)));
+        tree.commit(1);
+
+        // A commit without attach keeps the buffer and the viewport
+        tree.commit(1);
+        let state = &tree.get(1).unwrap().current;
+        assert_eq!(state.buffer, Some(100));
+        assert_eq!(state.viewport.destination, Some((8, 8)));
+    }
+
+    #[test]
+    fn test_viewport_crop_and_scale() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.attach(1, Some(100));
+        // Crop the right column of a 2x1 buffer and scale it to 2x2
+        tree.set_viewport_source(1, Some((1.0, 0.0, 1.0, 1.0)));
+        tree.set_viewport_destination(1, Some((2, 2)));
+        tree.commit(1);
+
+        let pixels = [[1u8, 1, 1, 255], [2, 2, 2, 255]].concat();
+        let frame = tree.compose(1, 1.0, |_| {
+            Some(BufferView { width: 2, height: 1, stride: 8, data: &pixels, opaque: false })
+        }).unwrap();
+
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert!(frame.data.chunks(4).all(|px| px == [2, 2, 2, 255]));
+    }
+
+    #[test]
+    fn test_surface_size_clamped() {
+        let mut state = SurfaceState::default();
+        let view = BufferView { width: 1, height: 1, stride: 4, data: &[0; 4], opaque: false };
+        state.viewport.source = Some((0.0, 0.0, 1e12, 1.0));
+        assert_eq!(surface_size(&view, &state), (MAX_SURFACE_SIZE, 1));
+        state.viewport.destination = Some((i32::MAX, 2));
+        assert_eq!(surface_size(&view, &state), (MAX_SURFACE_SIZE, 2));
+        assert_eq!(pixels_len(u32::MAX, u32::MAX), None);
+    }
+
+    #[test]
+    fn test_compose_at_fractional_scale() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.attach(1, Some(100));
+        // 2x2 logical surface backed by a 3x3 buffer, as a 1.5x client does
+        tree.set_viewport_destination(1, Some((2, 2)));
+        tree.commit(1);
+
+        let pixels: Vec<u8> = (0..9u8).flat_map(|i| [i, i, i, 255]).collect();
+        let frame = tree.compose(1, 1.5, |_| {
+            Some(BufferView { width: 3, height: 3, stride: 12, data: &pixels, opaque: false })
+        }).unwrap();
+
+        // Drawn 1:1 without resampling
+        assert_eq!((frame.width, frame.height), (3, 3));
+        assert_eq!(frame.data, pixels);
+    }
+
+    #[test]
+    fn test_compose_blends_children() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.create(2);
+        tree.add_subsurface(10, 2, 1).unwrap();
+        tree.set_position(2, 1, 0);
+        tree.attach(1, Some(100));
+        tree.attach(2, Some(200));
+        tree.commit(2);
+        tree.commit(1);
+
+        let parent = [0u8, 0, 255, 255].repeat(4); // 2x2 opaque red (BGRA)
+        let child = [255u8, 0, 0, 255].repeat(4);  // 2x2 opaque blue
+        let frame = tree.compose(1, 1.0, |buffer| {
+            let data: &[u8] = if buffer == 100 { &parent } else { &child };
+            Some(BufferView { width: 2, height: 2, stride: 8, data, opaque: false })
+        }).unwrap();
+
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert_eq!(&frame.data[0..4], &[0, 0, 255, 255]); // parent shows at x=0
+        assert_eq!(&frame.data[4..8], &[255, 0, 0, 255]); // child covers x=1
+    }
+
+    #[test]
+    fn test_compose_applies_subsurface_alpha() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.create(2);
+        tree.add_subsurface(10, 2, 1).unwrap();
+        tree.attach(1, Some(100));
+        tree.attach(2, Some(200));
+        tree.set_alpha(1, Some(0));
+        tree.set_alpha(2, Some(u32::MAX / 2 + 1));
+        tree.commit(2);
+        tree.commit(1);
+
+        let parent = [0u8, 0, 255, 255];
+        let child = [255u8, 0, 0, 0]; // opaque buffer, alpha ignored
+        let frame = tree.compose(1, 1.0, |buffer| {
+            let (data, opaque): (&[u8], bool) = if buffer == 100 { (&parent, false) } else { (&child, true) };
+            Some(BufferView { width: 1, height: 1, stride: 4, data, opaque })
+        }).unwrap();
+
+        // The root's multiplier is the renderer's; the child is half blended
+        assert_eq!(frame.data, vec![127, 0, 128, 255]);
+    }
+
+    #[test]
+    fn test_compose_shrinks_scaled_buffers() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.attach(1, Some(100));
+        tree.set_buffer_scale(1, 2);
+        tree.commit(1);
+
+        let pixels = [7u8; 4 * 4 * 4];
+        let lookup = |_| Some(BufferView { width: 4, height: 4, stride: 16, data: &pixels, opaque: false });
+        // A 2x buffer is a 2x2 surface, drawn 1:1 on a 2x output
+        assert_eq!(surface_size(&lookup(100).unwrap(), &tree.get(1).unwrap().current), (2, 2));
+        let frame = tree.compose(1, 1.0, lookup).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 2));
+        let frame = tree.compose(1, 2.0, lookup).unwrap();
+        assert_eq!((frame.width, frame.height), (4, 4));
+        assert_eq!(frame.data, pixels);
+    }
+
+    #[test]
+    fn test_damage_maps_to_frame_pixels() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.create(2);
+        tree.add_subsurface(10, 2, 1).unwrap();
+        tree.set_position(2, 10, 20);
+        tree.commit(1);
+        // The first commits report no damage
+        assert_eq!(tree.take_damage(1, 1.0), None);
+
+        tree.damage(1, Rect::new(0, 0, 4, 4), false);
+        tree.commit(1);
+        tree.set_buffer_scale(2, 2);
+        tree.damage(2, Rect::new(2, 2, 3, 3), true);
+        tree.commit(2);
+        // The synchronized child's damage arrives with the next parent commit
+        assert_eq!(tree.take_damage(1, 1.5), Some(vec![Rect::new(0, 0, 6, 6)]));
+        tree.damage(1, Rect::new(0, 0, 1, 1), false);
+        tree.commit(1);
+        assert_eq!(tree.take_damage(1, 1.5), Some(vec![
+            Rect::new(0, 0, 2, 2),
+            Rect::new(16, 31, 3, 3),
+        ]));
+        assert_eq!(tree.take_damage(1, 1.5), Some(Vec::new()));
+
+        // Damage is clipped to the surface, however large it claims to be
+        tree.damage(1, Rect::new(-10, i32::MAX, 20, i32::MAX), false);
+        tree.damage(1, Rect::new(i32::MIN, -1, i32::MAX, i32::MAX), true);
+        tree.damage(1, Rect::new(-10, 0, 5, 5), false);
+        tree.damage(1, Rect::new(i32::MAX - 1, 0, i32::MAX, 1), true);
+        tree.damage(1, Rect::new(-1, -1, i32::MAX, i32::MAX), false);
+        tree.commit(1);
+        assert_eq!(tree.take_damage(1, 1.0), Some(vec![
+            Rect::new(0, 0, MAX_SURFACE_SIZE as i32, MAX_SURFACE_SIZE as i32),
+            Rect::new(i32::MAX - 1, 0, 1, 1),
+        ]));
+
+        // Moving a child damages everything
+        tree.set_position(2, 0, 0);
+        tree.damage(1, Rect::new(0, 0, 1, 1), false);
+        tree.commit(1);
+        assert_eq!(tree.take_damage(1, 1.0), None);
+    }
+
+    #[test]
+    fn test_compose_undoes_buffer_transform() {
+        let mut tree = SurfaceTree::new();
+        tree.create(1);
+        tree.attach(1, Some(100));
+        tree.set_buffer_transform(1, Transform::Rotate90);
+        tree.commit(1);
+
+        // A 2x1 buffer rotated counter-clockwise is an upright 1x2 surface
+        let pixels = [1u8, 1, 1, 255, 2, 2, 2, 255];
+        let frame = tree.compose(1, 1.0, |_| Some(BufferView { width: 2, height: 1, stride: 8, data: &pixels, opaque: false })).unwrap();
+        assert_eq!((frame.width, frame.height), (1, 2));
+        assert_eq!(frame.data, pixels);
+
+        tree.set_buffer_transform(1, Transform::Flipped);
+        tree.commit(1);
+        let frame = tree.compose(1, 1.0, |_| Some(BufferView { width: 2, height: 1, stride: 8, data: &pixels, opaque: false })).unwrap();
+        assert_eq!(frame.data, [2, 2, 2, 255, 1, 1, 1, 255]);
+    }
+}
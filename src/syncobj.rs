@@ -0,0 +1,104 @@
+//! Explicit Synchronization (linux-drm-syncobj-v1)
+//!
+//! Vulkan clients commit dmabuf buffers together with an acquire point,
+//! signaled once the GPU has finished rendering into the buffer, and a
+//! release point the compositor signals once it no longer reads it. The
+//! timeline fds stay with the WSL-side helper, which knows them by their
+//! wp_linux_drm_syncobj_timeline_v1 object:
+//!
+//! 1. On commit the compositor holds back the frame of the surface tree.
+//! 2. The helper waits for the acquire point, copies the dmabuf contents and
+//!    reports `SYNC_SIGNALED(timeline, point)` on the control channel.
+//! 3. The compositor composites the held frame and answers with
+//!    `SYNC_RELEASE(timeline, point)`, and the helper signals the release
+//!    point.
+//!
+//! Frames therefore never show a buffer the client is still rendering to.
+
+use crate::error::{Result, WinpipeError};
+use crate::pipe::{opcodes, CONTROL_OBJECT_ID};
+use crate::wire::{ArgWriter, Message};
+
+/// A point on a timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncPoint {
+    /// wp_linux_drm_syncobj_timeline_v1 object
+    pub timeline: u32,
+    pub point: u64,
+}
+
+impl SyncPoint {
+    /// Point from the (hi, lo) pair used on the wire
+    pub fn from_wire(timeline: u32, point_hi: u32, point_lo: u32) -> Self {
+        Self { timeline, point: ((point_hi as u64) << 32) | point_lo as u64 }
+    }
+}
+
+/// Pending points of a wp_linux_drm_syncobj_surface_v1, reset on commit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SurfaceSync {
+    pub acquire: Option<SyncPoint>,
+    pub release: Option<SyncPoint>,
+}
+
+impl SurfaceSync {
+    /// Check the points for a commit
+    ///
+    /// `buffer` is the newly attached buffer, if any, and whether it is a
+    /// dmabuf. Returns the (acquire, release) pair the commit waits on.
+    pub fn validate(&self, buffer: Option<bool>) -> Result<Option<(SyncPoint, SyncPoint)>> {
+        let error = |message: &str| Err(WinpipeError::Protocol(message.to_string()));
+        match (buffer, self.acquire, self.release) {
+            (None, None, None) => Ok(None),
+            (None, _, _) => error("sync points set without a buffer"),
+            (Some(_), None, _) => error("buffer committed without an acquire point"),
+            (Some(_), _, None) => error("buffer committed without a release point"),
+            (Some(false), _, _) => error("explicit sync requires a dmabuf buffer"),
+            (Some(true), Some(acquire), Some(release)) => {
+                if acquire.timeline == release.timeline && acquire.point >= release.point {
+                    return error("acquire point is not before the release point");
+                }
+                Ok(Some((acquire, release)))
+            }
+        }
+    }
+}
+
+/// Control message asking the helper to signal a release point
+pub fn release_message(release: SyncPoint) -> Message {
+    let payload = ArgWriter::new()
+        .u32(release.timeline)
+        .u32((release.point >> 32) as u32)
+        .u32(release.point as u32)
+        .finish();
+    Message::new(CONTROL_OBJECT_ID, opcodes::SYNC_RELEASE, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::PipeEvent;
+
+    #[test]
+    fn test_commit_validation() {
+        let acquire = SyncPoint { timeline: 5, point: 1 };
+        let release = SyncPoint { timeline: 5, point: 2 };
+        let both = SurfaceSync { acquire: Some(acquire), release: Some(release) };
+
+        assert_eq!(both.validate(Some(true)).unwrap(), Some((acquire, release)));
+        assert_eq!(SurfaceSync::default().validate(None).unwrap(), None);
+        assert!(both.validate(None).is_err());
+        assert!(both.validate(Some(false)).is_err());
+        assert!(SurfaceSync { release: None, ..both }.validate(Some(true)).is_err());
+        assert!(SurfaceSync { release: Some(acquire), ..both }.validate(Some(true)).is_err());
+    }
+
+    #[test]
+    fn test_release_message() {
+        let point = SyncPoint::from_wire(7, 1, 3);
+        assert_eq!(
+            PipeEvent::from_message(&release_message(point)).unwrap(),
+            PipeEvent::SyncRelease { timeline: 7, point: (1 << 32) | 3 }
+        );
+    }
+}
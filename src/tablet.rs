@@ -0,0 +1,201 @@
+//! Tablet Input (tablet-v2)
+//!
+//! The renderer reads Windows Ink pen input (WM_POINTER with
+//! POINTER_PEN_INFO) and forwards one `PenSample` per pointer update over
+//! the event channel. Each zwp_tablet_seat_v2 gets a virtual tablet with
+//! a pen and an eraser tool; successive samples are diffed into
+//! proximity_in/out, down/up, motion, pressure, tilt, rotation and button
+//! events, each batch closed by a frame.
+
+use crate::render::{pen_flags, PenSample};
+use crate::wire::{ArgWriter, Message};
+
+/// zwp_tablet_seat_v2 event opcodes
+pub mod seat_events {
+    pub const TABLET_ADDED: u16 = 0;
+    pub const TOOL_ADDED: u16 = 1;
+}
+
+/// zwp_tablet_v2 event opcodes
+pub mod tablet_events {
+    pub const NAME: u16 = 0;
+    pub const DONE: u16 = 3;
+}
+
+/// zwp_tablet_tool_v2 event opcodes
+pub mod tool_events {
+    pub const TYPE: u16 = 0;
+    pub const CAPABILITY: u16 = 3;
+    pub const DONE: u16 = 4;
+    pub const PROXIMITY_IN: u16 = 6;
+    pub const PROXIMITY_OUT: u16 = 7;
+    pub const DOWN: u16 = 8;
+    pub const UP: u16 = 9;
+    pub const MOTION: u16 = 10;
+    pub const PRESSURE: u16 = 11;
+    pub const TILT: u16 = 13;
+    pub const ROTATION: u16 = 14;
+    pub const BUTTON: u16 = 17;
+    pub const FRAME: u16 = 18;
+}
+
+/// zwp_tablet_tool_v2.type values
+pub mod tool_type {
+    pub const PEN: u32 = 0x140;
+    pub const ERASER: u32 = 0x141;
+}
+
+/// zwp_tablet_tool_v2.capability values
+pub mod capability {
+    pub const TILT: u32 = 1;
+    pub const PRESSURE: u32 = 2;
+    pub const ROTATION: u32 = 4;
+}
+
+/// Linux button code reported for the pen barrel button
+pub const BTN_STYLUS: u32 = 0x14b;
+
+/// Windows Ink pressure range (0..=1024)
+pub const WINDOWS_MAX_PRESSURE: u32 = 1024;
+
+/// The virtual tablet and tools created for one zwp_tablet_seat_v2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TabletSeat {
+    pub tablet: u32,
+    pub pen: u32,
+    pub eraser: u32,
+}
+
+impl TabletSeat {
+    /// Events announcing the tablet and its tools on the seat object
+    pub fn added_events(&self, seat_id: u32) -> Vec<Message> {
+        let mut events = vec![
+            Message::new(seat_id, seat_events::TABLET_ADDED, self.tablet.to_le_bytes().to_vec()),
+            Message::new(self.tablet, tablet_events::NAME, ArgWriter::new().string("Windows Ink").finish()),
+            Message::new(self.tablet, tablet_events::DONE, vec![]),
+        ];
+
+        let tools = [
+            (self.pen, tool_type::PEN, &[capability::TILT, capability::PRESSURE, capability::ROTATION][..]),
+            (self.eraser, tool_type::ERASER, &[capability::TILT, capability::PRESSURE][..]),
+        ];
+        for (tool, kind, capabilities) in tools {
+            events.push(Message::new(seat_id, seat_events::TOOL_ADDED, tool.to_le_bytes().to_vec()));
+            events.push(Message::new(tool, tool_events::TYPE, kind.to_le_bytes().to_vec()));
+            for cap in capabilities {
+                events.push(Message::new(tool, tool_events::CAPABILITY, cap.to_le_bytes().to_vec()));
+            }
+            events.push(Message::new(tool, tool_events::DONE, vec![]));
+        }
+        events
+    }
+
+    /// Tool object a sample belongs to
+    fn tool(&self, sample: &PenSample) -> u32 {
+        if sample.flags & pen_flags::ERASER != 0 { self.eraser } else { self.pen }
+    }
+
+    /// Events moving the tools from `prev` to `next`
+    ///
+    /// `next` must already be in surface coordinates. `prev` is `None`
+    /// while no tool is in range.
+    pub fn pen_events(&self, prev: Option<&PenSample>, next: &PenSample, surface: u32, serial: u32) -> Vec<Message> {
+        let mut events = Vec::new();
+        let prev = prev.filter(|p| p.in_range());
+
+        // Leaving proximity, or flipping the pen over to the eraser
+        if let Some(p) = prev.filter(|p| !next.in_range() || self.tool(p) != self.tool(next)) {
+            let tool = self.tool(p);
+            if p.in_contact() {
+                events.push(Message::new(tool, tool_events::UP, vec![]));
+            }
+            events.push(Message::new(tool, tool_events::PROXIMITY_OUT, vec![]));
+            events.push(frame(tool, next.time));
+        }
+        if !next.in_range() {
+            return events;
+        }
+
+        let tool = self.tool(next);
+        let prev = prev.filter(|p| self.tool(p) == tool);
+        if prev.is_none() {
+            let payload = ArgWriter::new().u32(serial).u32(self.tablet).u32(surface).finish();
+            events.push(Message::new(tool, tool_events::PROXIMITY_IN, payload));
+        }
+
+        if prev.is_none_or(|p| (p.x, p.y) != (next.x, next.y)) {
+            events.push(Message::new(tool, tool_events::MOTION, ArgWriter::new().fixed(next.x).fixed(next.y).finish()));
+        }
+        if prev.is_none_or(|p| p.pressure != next.pressure) {
+            let pressure = next.pressure.min(WINDOWS_MAX_PRESSURE) * 65535 / WINDOWS_MAX_PRESSURE;
+            events.push(Message::new(tool, tool_events::PRESSURE, pressure.to_le_bytes().to_vec()));
+        }
+        if prev.is_none_or(|p| (p.tilt_x, p.tilt_y) != (next.tilt_x, next.tilt_y)) {
+            let payload = ArgWriter::new().fixed(next.tilt_x as f64).fixed(next.tilt_y as f64).finish();
+            events.push(Message::new(tool, tool_events::TILT, payload));
+        }
+        if tool == self.pen && prev.is_none_or(|p| p.rotation != next.rotation) {
+            events.push(Message::new(tool, tool_events::ROTATION, ArgWriter::new().fixed(next.rotation as f64).finish()));
+        }
+
+        let was_down = prev.is_some_and(|p| p.in_contact());
+        if next.in_contact() && !was_down {
+            events.push(Message::new(tool, tool_events::DOWN, serial.to_le_bytes().to_vec()));
+        } else if !next.in_contact() && was_down {
+            events.push(Message::new(tool, tool_events::UP, vec![]));
+        }
+
+        let barrel = |s: &PenSample| s.flags & pen_flags::BARREL != 0;
+        if barrel(next) != prev.is_some_and(barrel) {
+            let payload = ArgWriter::new().u32(serial).u32(BTN_STYLUS).u32(barrel(next) as u32).finish();
+            events.push(Message::new(tool, tool_events::BUTTON, payload));
+        }
+
+        events.push(frame(tool, next.time));
+        events
+    }
+}
+
+fn frame(tool: u32, time: u32) -> Message {
+    Message::new(tool, tool_events::FRAME, time.to_le_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEAT: TabletSeat = TabletSeat { tablet: 100, pen: 101, eraser: 102 };
+
+    fn sample(flags: u32, pressure: u32) -> PenSample {
+        PenSample { time: 1, x: 10.0, y: 20.0, pressure, flags, ..Default::default() }
+    }
+
+    #[test]
+    fn test_stroke_sequence() {
+        let hover = sample(pen_flags::IN_RANGE, 0);
+        let opcodes = |events: Vec<Message>| events.iter().map(|m| m.opcode).collect::<Vec<_>>();
+
+        let events = SEAT.pen_events(None, &hover, 10, 1);
+        assert_eq!(events[0].opcode, tool_events::PROXIMITY_IN);
+        assert!(events.iter().all(|m| m.object_id == SEAT.pen));
+
+        let down = sample(pen_flags::IN_RANGE | pen_flags::IN_CONTACT, 1024);
+        let events = SEAT.pen_events(Some(&hover), &down, 10, 2);
+        assert_eq!(opcodes(events.clone()), vec![tool_events::PRESSURE, tool_events::DOWN, tool_events::FRAME]);
+        assert_eq!(events[0].payload, 65535u32.to_le_bytes());
+
+        // Lifting straight out of range releases before leaving
+        let events = SEAT.pen_events(Some(&down), &PenSample::default(), 10, 3);
+        assert_eq!(opcodes(events), vec![tool_events::UP, tool_events::PROXIMITY_OUT, tool_events::FRAME]);
+    }
+
+    #[test]
+    fn test_eraser_switches_tool() {
+        let pen = sample(pen_flags::IN_RANGE, 0);
+        let eraser = sample(pen_flags::IN_RANGE | pen_flags::ERASER, 0);
+        let events = SEAT.pen_events(Some(&pen), &eraser, 10, 1);
+
+        assert_eq!((events[0].object_id, events[0].opcode), (SEAT.pen, tool_events::PROXIMITY_OUT));
+        assert!(events.iter().any(|m| m.object_id == SEAT.eraser && m.opcode == tool_events::PROXIMITY_IN));
+    }
+}
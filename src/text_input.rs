@@ -0,0 +1,128 @@
+//! zwp_text_input_v3 State
+//!
+//! Text input objects describe the focused text field: whether it wants
+//! input method support, where the text cursor is and what kind of
+//! content it holds. All of it is double-buffered and applied on commit.
+//! Composition results from the Windows IME are sent back as
+//! preedit_string / commit_string followed by done, whose serial is the
+//! number of commits the client has made.
+
+use crate::positioner::Rect;
+use crate::wire::{ArgWriter, Message};
+
+/// zwp_text_input_v3 event opcodes
+pub mod events {
+    pub const ENTER: u16 = 0;
+    pub const LEAVE: u16 = 1;
+    pub const PREEDIT_STRING: u16 = 2;
+    pub const COMMIT_STRING: u16 = 3;
+    pub const DELETE_SURROUNDING_TEXT: u16 = 4;
+    pub const DONE: u16 = 5;
+}
+
+/// zwp_text_input_v3.content_purpose values the renderer cares about
+pub mod content_purpose {
+    pub const NORMAL: u32 = 0;
+    pub const PASSWORD: u32 = 8;
+    pub const PIN: u32 = 9;
+}
+
+/// Double-buffered text input state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextInputState {
+    pub enabled: bool,
+    /// Text cursor in surface-local coordinates
+    pub cursor_rect: Option<Rect>,
+    pub content_hint: u32,
+    pub content_purpose: u32,
+    /// Text around the cursor, with cursor and anchor byte offsets
+    pub surrounding_text: Option<(String, i32, i32)>,
+}
+
+/// A zwp_text_input_v3 object
+#[derive(Debug, Clone, Default)]
+pub struct TextInput {
+    pub pending: TextInputState,
+    pub current: TextInputState,
+    /// Number of commit requests, used as the done serial
+    pub commits: u32,
+    /// Surface the text input has entered (keyboard focus)
+    pub focus: Option<u32>,
+}
+
+impl TextInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// zwp_text_input_v3.enable: resets the pending state
+    pub fn enable(&mut self) {
+        self.pending = TextInputState { enabled: true, ..Default::default() };
+    }
+
+    /// zwp_text_input_v3.disable
+    pub fn disable(&mut self) {
+        self.pending.enabled = false;
+    }
+
+    /// zwp_text_input_v3.commit
+    pub fn commit(&mut self) {
+        self.current = self.pending.clone();
+        self.commits = self.commits.wrapping_add(1);
+    }
+
+    /// Whether IME results should be delivered to this text input
+    pub fn is_active(&self) -> bool {
+        self.current.enabled && self.focus.is_some()
+    }
+
+    /// Events replacing the preedit string (empty text clears it)
+    pub fn preedit_events(&self, id: u32, text: &str, cursor_begin: i32, cursor_end: i32) -> Vec<Message> {
+        let payload = ArgWriter::new().string(text).i32(cursor_begin).i32(cursor_end).finish();
+        vec![Message::new(id, events::PREEDIT_STRING, payload), self.done(id)]
+    }
+
+    /// Events inserting final text (this also clears the preedit string)
+    pub fn commit_events(&self, id: u32, text: &str) -> Vec<Message> {
+        let payload = ArgWriter::new().string(text).finish();
+        vec![Message::new(id, events::COMMIT_STRING, payload), self.done(id)]
+    }
+
+    fn done(&self, id: u32) -> Message {
+        Message::new(id, events::DONE, self.commits.to_le_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::ArgReader;
+
+    #[test]
+    fn test_double_buffered_enable() {
+        let mut input = TextInput::new();
+        input.enable();
+        input.pending.cursor_rect = Some(Rect::new(10, 20, 1, 16));
+        assert!(!input.current.enabled);
+
+        input.commit();
+        assert!(input.current.enabled);
+        assert_eq!(input.commits, 1);
+
+        // enable resets previously committed state
+        input.enable();
+        assert_eq!(input.pending.cursor_rect, None);
+    }
+
+    #[test]
+    fn test_done_serial_counts_commits() {
+        let mut input = TextInput::new();
+        input.commit();
+        input.commit();
+
+        let messages = input.commit_events(5, "日本");
+        assert_eq!(ArgReader::new(&messages[0].payload).string().unwrap(), "日本");
+        assert_eq!(messages[1].opcode, events::DONE);
+        assert_eq!(messages[1].payload, 2u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,186 @@
+//! TLS Connections
+//!
+//! Over networks that are not trusted, the server can encrypt its TCP
+//! connections: started with `--tls-cert` and `--tls-key`, it runs a TLS
+//! handshake on every remote TCP client before anything else, token included.
+//! `winpipe client --tls-ca` trusts the certificates in that file (the
+//! server's own, if self-signed) and checks the server's name against
+//! them, so a client cannot be pointed at an impostor.
+//!
+//! Named pipes and loopback TCP connections stay plain on both ends: they
+//! never leave the machine. That includes the connections `winpipe ssh`
+//! forwards, which reach the server on 127.0.0.1 after running through
+//! ssh, so the remote client needs no certificate.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::auth::HANDSHAKE_TIMEOUT;
+use crate::error::{Result, WinpipeError};
+
+fn tls_error(e: impl std::fmt::Display) -> WinpipeError {
+    WinpipeError::Tls(e.to_string())
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Certificate chain in a PEM file
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(tls_error(format!("{}: no certificate", path.display())));
+    }
+    Ok(certs)
+}
+
+/// TLS for the server's TCP clients
+#[derive(Clone)]
+pub struct Acceptor(TlsAcceptor);
+
+impl Acceptor {
+    /// Serve the certificate chain in `cert` with the private key in
+    /// `key`, both PEM
+    pub fn new(cert: &Path, key: &Path) -> Result<Self> {
+        let certs = load_certs(cert)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| tls_error(format!("{}: {}", key.display(), e)))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(tls_error)?;
+        Ok(Self(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Run the handshake on a newly accepted connection
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<server::TlsStream<S>> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.0.accept(stream))
+            .await
+            .map_err(|_| tls_error("handshake timed out"))?
+            .map_err(tls_error)
+    }
+}
+
+/// TLS for `winpipe client`'s connection to the server
+#[derive(Clone)]
+pub struct Connector {
+    connector: TlsConnector,
+    /// Name the server's certificate must have, instead of the host
+    /// connected to
+    server_name: Option<String>,
+}
+
+impl Connector {
+    /// Trust the certificates in the PEM file `ca`
+    pub fn new(ca: &Path, server_name: Option<String>) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert).map_err(|e| tls_error(format!("{}: {}", ca.display(), e)))?;
+        }
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self { connector: TlsConnector::from(Arc::new(config)), server_name })
+    }
+
+    /// Run the handshake with the server at `host`, a name or an IP
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(&self, host: &str, stream: S) -> Result<client::TlsStream<S>> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+        let name = ServerName::try_from(name.to_string()).map_err(|_| tls_error(format!("invalid server name '{}'", name)))?;
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.connector.connect(name, stream))
+            .await
+            .map_err(|_| tls_error("handshake timed out"))?
+            .map_err(tls_error)
+    }
+}
+
+/// Host part of HOST:PORT, without the brackets of an IPv6 address
+pub fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Self-signed certificate and key for `names`, written to PEM files
+    fn certificate(dir: &Path, names: &[&str]) -> (std::path::PathBuf, std::path::PathBuf) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("winpipe-tls-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_tls_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        let (cert, key) = certificate(&dir, &["localhost", "127.0.0.1"]);
+        let acceptor = Acceptor::new(&cert, &key).unwrap();
+        let connector = Connector::new(&cert, None).unwrap();
+
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_end).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+        let mut stream = connector.connect(host_of("127.0.0.1:9999"), client_end).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_rejects_other_name() {
+        let dir = temp_dir("name");
+        let (cert, key) = certificate(&dir, &["winpipe.example"]);
+        let acceptor = Acceptor::new(&cert, &key).unwrap();
+
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        tokio::spawn(async move { acceptor.accept(server_end).await });
+        let connector = Connector::new(&cert, None).unwrap();
+        assert!(matches!(connector.connect("localhost", client_end).await, Err(WinpipeError::Tls(_))));
+
+        // Unless the expected name is given
+        let acceptor = Acceptor::new(&cert, &key).unwrap();
+        let (client_end, server_end) = tokio::io::duplex(4096);
+        tokio::spawn(async move { acceptor.accept(server_end).await });
+        let connector = Connector::new(&cert, Some("winpipe.example".to_string())).unwrap();
+        assert!(connector.connect("10.0.0.2", client_end).await.is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("172.20.0.1:9999"), "172.20.0.1");
+        assert_eq!(host_of("[::1]:9999"), "::1");
+        assert_eq!(host_of("winbox"), "winbox");
+    }
+}
@@ -0,0 +1,123 @@
+//! Toplevel Icons (xdg-toplevel-icon-v1)
+//!
+//! Without an icon every WSL window shows the generic one in the taskbar.
+//! Clients that set an icon hand over square ARGB8888 wl_shm buffers,
+//! usually in a few sizes; the contents are copied when a buffer is added,
+//! and set_icon forwards the icon to the renderer, which uses it as the
+//! native window's small (title bar) and large (taskbar, Alt+Tab) icon.
+//!
+//! Icons given only by XDG icon theme name cannot be looked up on the
+//! Windows side, so the window keeps the default icon for those.
+
+use crate::buffer::pack_rows;
+use crate::render::{IconImage, WindowIcon};
+use crate::wire::Message;
+
+/// xdg_toplevel_icon_manager_v1 event opcodes
+pub mod events {
+    pub const ICON_SIZE: u16 = 0;
+    pub const DONE: u16 = 1;
+}
+
+/// xdg_toplevel_icon_v1.error codes
+pub mod toplevel_icon_error {
+    pub const INVALID_BUFFER: u32 = 1;
+    pub const IMMUTABLE: u32 = 2;
+    pub const NO_BUFFER: u32 = 3;
+}
+
+/// Icon sizes Windows draws at 100% scale: SM_CXSMICON and SM_CXICON
+pub const ICON_SIZES: [i32; 2] = [16, 32];
+
+/// An xdg_toplevel_icon_v1 object
+#[derive(Debug, Clone, Default)]
+pub struct ToplevelIcon {
+    /// Icon theme name from set_name
+    pub name: Option<String>,
+    /// Images from add_buffer with their scale
+    pub images: Vec<(IconImage, i32)>,
+    /// The icon was assigned with set_icon and can no longer change
+    pub immutable: bool,
+}
+
+impl ToplevelIcon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an image, replacing one of the same size and scale
+    pub fn add(&mut self, image: IconImage, scale: i32) {
+        self.images.retain(|(old, old_scale)| old.width != image.width || *old_scale != scale);
+        self.images.push((image, scale));
+    }
+
+    /// The small and large images for the native window
+    ///
+    /// The small icon is the smallest image of at least 16 pixels and the
+    /// large one the smallest of at least 32, falling back to the largest
+    /// image when none is big enough. Named icons have no images.
+    pub fn window_icon(&self) -> WindowIcon {
+        let pick = |min: u32| {
+            let images = self.images.iter().map(|(image, _)| image);
+            images.clone().filter(|image| image.width >= min).min_by_key(|image| image.width)
+                .or_else(|| images.max_by_key(|image| image.width))
+        };
+        match (pick(ICON_SIZES[0] as u32), pick(ICON_SIZES[1] as u32)) {
+            (Some(small), Some(large)) => WindowIcon { images: vec![small.clone(), large.clone()] },
+            _ => WindowIcon::default(),
+        }
+    }
+}
+
+/// Copy a square ARGB8888 buffer into an icon image
+pub fn icon_image(width: u32, height: u32, stride: u32, data: &[u8]) -> Option<IconImage> {
+    if width == 0 || width != height {
+        return None;
+    }
+    let pixels = pack_rows(data, width as usize * 4, stride as usize, height as usize)?;
+    Some(IconImage { width, height, data: pixels.into_owned() })
+}
+
+/// icon_size events for the preferred sizes, followed by done
+pub fn size_events(manager: u32) -> Vec<Message> {
+    ICON_SIZES.iter()
+        .map(|size| Message::new(manager, events::ICON_SIZE, size.to_le_bytes().to_vec()))
+        .chain(std::iter::once(Message::new(manager, events::DONE, vec![])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: u32) -> IconImage {
+        IconImage { width: size, height: size, data: vec![size as u8; (size * size * 4) as usize] }
+    }
+
+    #[test]
+    fn test_window_icon_picks_small_and_large() {
+        let mut icon = ToplevelIcon::new();
+        assert_eq!(icon.window_icon(), WindowIcon::default());
+
+        icon.add(image(24), 1);
+        icon.add(image(64), 1);
+        icon.add(image(48), 1);
+        let sizes = |icon: &ToplevelIcon| icon.window_icon().images.iter().map(|i| i.width).collect::<Vec<_>>();
+        assert_eq!(sizes(&icon), vec![24, 48]);
+
+        // Only small images: both use the largest
+        let mut small = ToplevelIcon::new();
+        small.add(image(8), 1);
+        small.add(image(12), 1);
+        assert_eq!(sizes(&small), vec![12, 12]);
+    }
+
+    #[test]
+    fn test_icon_image_drops_stride_padding() {
+        let data = [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8];
+        assert_eq!(icon_image(1, 1, 8, &data).unwrap().data, vec![1, 2, 3, 4]);
+        assert!(icon_image(2, 1, 8, &data).is_none());
+        assert!(icon_image(1, 2, 8, &data).is_none());
+        assert!(icon_image(1, 1, 2, &data).is_none());
+    }
+}
@@ -9,7 +9,7 @@
 //! File descriptors are passed via ancillary data (which we handle specially
 //! since Windows doesn't have Unix domain sockets).
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::BytesMut;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::Cursor;
 
@@ -22,7 +22,7 @@ pub const HEADER_SIZE: usize = 8;
 pub const MAX_MESSAGE_SIZE: usize = 65536;
 
 /// A parsed Wayland wire message
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     /// Target object ID
     pub object_id: u32,
@@ -104,7 +104,6 @@ impl Message {
         }
         
         // Extract payload
-        let payload_size = size - HEADER_SIZE;
         let payload = data[HEADER_SIZE..size].to_vec();
         
         Ok(Self {
@@ -149,7 +148,7 @@ impl WireDecoder {
         let size = (size_opcode >> 16) as usize;
 
         // Validate and check if we have the complete message
-        if size < HEADER_SIZE || size > MAX_MESSAGE_SIZE {
+        if !(HEADER_SIZE..=MAX_MESSAGE_SIZE).contains(&size) {
             // Protocol error - clear buffer to recover
             self.buffer.clear();
             return None;
@@ -211,6 +210,126 @@ impl Default for WireEncoder {
     }
 }
 
+/// Reader for typed Wayland message arguments
+///
+/// Arguments are 32-bit aligned; strings and arrays are length-prefixed
+/// and padded to a 4-byte boundary.
+pub struct ArgReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ArgReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Read a uint / object / new_id argument
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read an int argument
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    /// Read a fixed (24.8 signed fixed-point) argument
+    pub fn fixed(&mut self) -> Result<f64> {
+        Ok(self.i32()? as f64 / 256.0)
+    }
+
+    /// Read a string argument (without the NUL terminator)
+    pub fn string(&mut self) -> Result<String> {
+        let bytes = self.array()?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(&bytes);
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| WinpipeError::InvalidMessage(format!("Invalid string argument: {}", e)))
+    }
+
+    /// Read an array argument
+    pub fn array(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?.to_vec();
+        let padding = (4 - len % 4) % 4;
+        self.take(padding)?;
+        Ok(bytes)
+    }
+
+    /// Bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(WinpipeError::InvalidMessage(
+                format!("Argument out of bounds: need {} bytes, have {}", len, self.remaining())
+            ));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// Builder for Wayland message arguments
+#[derive(Debug, Default)]
+pub struct ArgWriter {
+    buf: Vec<u8>,
+}
+
+impl ArgWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a uint / object / new_id argument
+    pub fn u32(mut self, value: u32) -> Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Append an int argument
+    pub fn i32(self, value: i32) -> Self {
+        self.u32(value as u32)
+    }
+
+    /// Append a fixed (24.8 signed fixed-point) argument
+    pub fn fixed(self, value: f64) -> Self {
+        self.i32((value * 256.0).round() as i32)
+    }
+
+    /// Append a string argument (NUL-terminated and padded)
+    pub fn string(mut self, value: &str) -> Self {
+        self.buf.extend_from_slice(&(value.len() as u32 + 1).to_le_bytes());
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+        self.pad();
+        self
+    }
+
+    /// Append an array argument (padded)
+    pub fn array(mut self, value: &[u8]) -> Self {
+        self.buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(value);
+        self.pad();
+        self
+    }
+
+    /// Finish and return the payload
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn pad(&mut self) {
+        while !self.buf.len().is_multiple_of(4) {
+            self.buf.push(0);
+        }
+    }
+}
+
 /// Well-known Wayland protocol opcodes for core objects
 pub mod opcodes {
     // wl_display (object 1)
@@ -266,6 +385,409 @@ pub mod opcodes {
         pub const SET_BUFFER_TRANSFORM: u16 = 7;
         pub const SET_BUFFER_SCALE: u16 = 8;
         pub const DAMAGE_BUFFER: u16 = 9;
+        pub const PREFERRED_BUFFER_SCALE: u16 = 2;     // Event (v6)
+        pub const PREFERRED_BUFFER_TRANSFORM: u16 = 3; // Event (v6)
+    }
+
+    // wl_output
+    pub mod output {
+        pub const GEOMETRY: u16 = 0;    // Event
+        pub const MODE: u16 = 1;        // Event
+        pub const DONE: u16 = 2;        // Event (v2)
+        pub const SCALE: u16 = 3;       // Event (v2)
+        pub const NAME: u16 = 4;        // Event (v4)
+        pub const DESCRIPTION: u16 = 5; // Event (v4)
+        pub const RELEASE: u16 = 0;     // Request (v3)
+    }
+
+    // wl_seat
+    pub mod seat {
+        pub const CAPABILITIES: u16 = 0; // Event
+        pub const NAME: u16 = 1;         // Event (v2)
+        pub const GET_POINTER: u16 = 0;
+        pub const GET_KEYBOARD: u16 = 1;
+        pub const GET_TOUCH: u16 = 2;
+        pub const RELEASE: u16 = 3;
+    }
+
+    // wl_pointer
+    pub mod pointer {
+        pub const SET_CURSOR: u16 = 0;
+        pub const RELEASE: u16 = 1;
+    }
+
+    // wp_cursor_shape_manager_v1
+    pub mod cursor_shape_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_POINTER: u16 = 1;
+        pub const GET_TABLET_TOOL_V2: u16 = 2;
+    }
+
+    // wp_cursor_shape_device_v1
+    pub mod cursor_shape_device {
+        pub const DESTROY: u16 = 0;
+        pub const SET_SHAPE: u16 = 1;
+    }
+
+    // zwp_relative_pointer_manager_v1
+    pub mod relative_pointer_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_RELATIVE_POINTER: u16 = 1;
+    }
+
+    // zwp_relative_pointer_v1
+    pub mod relative_pointer {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwp_pointer_gestures_v1
+    pub mod pointer_gestures {
+        pub const GET_SWIPE_GESTURE: u16 = 0;
+        pub const GET_PINCH_GESTURE: u16 = 1;
+        pub const RELEASE: u16 = 2;           // v2
+        pub const GET_HOLD_GESTURE: u16 = 3;  // v3
+    }
+
+    // zwp_pointer_gesture_{swipe,pinch,hold}_v1 (events are in crate::gestures::events)
+    pub mod pointer_gesture {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwp_tablet_manager_v2
+    pub mod tablet_manager {
+        pub const GET_TABLET_SEAT: u16 = 0;
+        pub const DESTROY: u16 = 1;
+    }
+
+    // zwp_tablet_seat_v2 and zwp_tablet_v2 (events are in crate::tablet)
+    pub mod tablet_seat {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwp_tablet_tool_v2
+    pub mod tablet_tool {
+        pub const SET_CURSOR: u16 = 0;
+        pub const DESTROY: u16 = 1;
+    }
+
+    // zwp_pointer_constraints_v1
+    pub mod pointer_constraints {
+        pub const DESTROY: u16 = 0;
+        pub const LOCK_POINTER: u16 = 1;
+        pub const CONFINE_POINTER: u16 = 2;
+    }
+
+    // zwp_locked_pointer_v1
+    pub mod locked_pointer {
+        pub const DESTROY: u16 = 0;
+        pub const SET_CURSOR_POSITION_HINT: u16 = 1;
+        pub const SET_REGION: u16 = 2;
+    }
+
+    // zwp_confined_pointer_v1
+    pub mod confined_pointer {
+        pub const DESTROY: u16 = 0;
+        pub const SET_REGION: u16 = 1;
+    }
+
+    // zwp_keyboard_shortcuts_inhibit_manager_v1
+    pub mod keyboard_shortcuts_inhibit_manager {
+        pub const DESTROY: u16 = 0;
+        pub const INHIBIT_SHORTCUTS: u16 = 1;
+    }
+
+    // zwp_keyboard_shortcuts_inhibitor_v1
+    pub mod keyboard_shortcuts_inhibitor {
+        pub const ACTIVE: u16 = 0;   // Event
+        pub const INACTIVE: u16 = 1; // Event
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwp_text_input_manager_v3
+    pub mod text_input_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_TEXT_INPUT: u16 = 1;
+    }
+
+    // zwp_text_input_v3 (events are in crate::text_input::events)
+    pub mod text_input {
+        pub const DESTROY: u16 = 0;
+        pub const ENABLE: u16 = 1;
+        pub const DISABLE: u16 = 2;
+        pub const SET_SURROUNDING_TEXT: u16 = 3;
+        pub const SET_TEXT_CHANGE_CAUSE: u16 = 4;
+        pub const SET_CONTENT_TYPE: u16 = 5;
+        pub const SET_CURSOR_RECTANGLE: u16 = 6;
+        pub const COMMIT: u16 = 7;
+    }
+
+    // wl_compositor
+    pub mod compositor {
+        pub const CREATE_SURFACE: u16 = 0;
+        pub const CREATE_REGION: u16 = 1;
+    }
+
+    // wl_region
+    pub mod region {
+        pub const DESTROY: u16 = 0;
+        pub const ADD: u16 = 1;
+        pub const SUBTRACT: u16 = 2;
+    }
+
+    // wp_presentation (feedback events are in crate::presentation)
+    pub mod presentation {
+        pub const DESTROY: u16 = 0;
+        pub const FEEDBACK: u16 = 1;
+    }
+
+    // wp_viewporter
+    pub mod viewporter {
+        pub const DESTROY: u16 = 0;
+        pub const GET_VIEWPORT: u16 = 1;
+    }
+
+    // wp_viewport
+    pub mod viewport {
+        pub const DESTROY: u16 = 0;
+        pub const SET_SOURCE: u16 = 1;
+        pub const SET_DESTINATION: u16 = 2;
+    }
+
+    // zwp_linux_dmabuf_v1 (events are in crate::dmabuf::events)
+    pub mod linux_dmabuf {
+        pub const DESTROY: u16 = 0;
+        pub const CREATE_PARAMS: u16 = 1;
+        pub const GET_DEFAULT_FEEDBACK: u16 = 2; // v4
+        pub const GET_SURFACE_FEEDBACK: u16 = 3; // v4
+    }
+
+    // zwp_linux_dmabuf_feedback_v1
+    pub mod linux_dmabuf_feedback {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwp_linux_buffer_params_v1
+    pub mod linux_buffer_params {
+        pub const DESTROY: u16 = 0;
+        pub const ADD: u16 = 1;
+        pub const CREATE: u16 = 2;
+        pub const CREATE_IMMED: u16 = 3;
+    }
+
+    // wp_linux_drm_syncobj_manager_v1
+    pub mod drm_syncobj_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_SURFACE: u16 = 1;
+        pub const IMPORT_TIMELINE: u16 = 2;
+    }
+
+    // wp_linux_drm_syncobj_timeline_v1
+    pub mod drm_syncobj_timeline {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // wp_linux_drm_syncobj_surface_v1
+    pub mod drm_syncobj_surface {
+        pub const DESTROY: u16 = 0;
+        pub const SET_ACQUIRE_POINT: u16 = 1;
+        pub const SET_RELEASE_POINT: u16 = 2;
+    }
+
+    // ext_idle_notifier_v1
+    pub mod idle_notifier {
+        pub const DESTROY: u16 = 0;
+        pub const GET_IDLE_NOTIFICATION: u16 = 1;
+        pub const GET_INPUT_IDLE_NOTIFICATION: u16 = 2;
+    }
+
+    // ext_idle_notification_v1
+    pub mod idle_notification {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // ext_foreign_toplevel_list_v1
+    pub mod foreign_toplevel_list {
+        pub const STOP: u16 = 0;
+        pub const DESTROY: u16 = 1;
+    }
+
+    // ext_foreign_toplevel_handle_v1
+    pub mod foreign_toplevel_handle {
+        pub const DESTROY: u16 = 0;
+    }
+
+    // zwlr_screencopy_manager_v1
+    pub mod screencopy_manager {
+        pub const CAPTURE_OUTPUT: u16 = 0;
+        pub const CAPTURE_OUTPUT_REGION: u16 = 1;
+        pub const DESTROY: u16 = 2;
+    }
+
+    // zwlr_screencopy_frame_v1
+    pub mod screencopy_frame {
+        pub const COPY: u16 = 0;
+        pub const DESTROY: u16 = 1;
+        pub const COPY_WITH_DAMAGE: u16 = 2;
+    }
+
+    // xdg_toplevel_icon_manager_v1
+    pub mod toplevel_icon_manager {
+        pub const DESTROY: u16 = 0;
+        pub const CREATE_ICON: u16 = 1;
+        pub const SET_ICON: u16 = 2;
+    }
+
+    // xdg_toplevel_icon_v1
+    pub mod toplevel_icon {
+        pub const DESTROY: u16 = 0;
+        pub const SET_NAME: u16 = 1;
+        pub const ADD_BUFFER: u16 = 2;
+    }
+
+    // wp_color_manager_v1
+    pub mod color_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_OUTPUT: u16 = 1;
+        pub const GET_SURFACE: u16 = 2;
+        pub const GET_SURFACE_FEEDBACK: u16 = 3;
+        pub const CREATE_ICC_CREATOR: u16 = 4;
+        pub const CREATE_PARAMETRIC_CREATOR: u16 = 5;
+        pub const CREATE_WINDOWS_SCRGB: u16 = 6;
+    }
+
+    // wp_color_management_output_v1
+    pub mod color_management_output {
+        pub const DESTROY: u16 = 0;
+        pub const GET_IMAGE_DESCRIPTION: u16 = 1;
+    }
+
+    // wp_color_management_surface_v1
+    pub mod color_management_surface {
+        pub const DESTROY: u16 = 0;
+        pub const SET_IMAGE_DESCRIPTION: u16 = 1;
+        pub const UNSET_IMAGE_DESCRIPTION: u16 = 2;
+    }
+
+    // wp_color_management_surface_feedback_v1
+    pub mod color_management_surface_feedback {
+        pub const DESTROY: u16 = 0;
+        pub const GET_PREFERRED: u16 = 1;
+        pub const GET_PREFERRED_PARAMETRIC: u16 = 2;
+    }
+
+    // wp_image_description_creator_params_v1
+    pub mod image_description_creator_params {
+        pub const CREATE: u16 = 0;
+        pub const SET_TF_NAMED: u16 = 1;
+        pub const SET_TF_POWER: u16 = 2;
+        pub const SET_PRIMARIES_NAMED: u16 = 3;
+        pub const SET_PRIMARIES: u16 = 4;
+        pub const SET_LUMINANCES: u16 = 5;
+        pub const SET_MASTERING_DISPLAY_PRIMARIES: u16 = 6;
+        pub const SET_MASTERING_LUMINANCE: u16 = 7;
+        pub const SET_MAX_CLL: u16 = 8;
+        pub const SET_MAX_FALL: u16 = 9;
+    }
+
+    // wp_image_description_v1
+    pub mod image_description {
+        pub const DESTROY: u16 = 0;
+        pub const GET_INFORMATION: u16 = 1;
+    }
+
+    // wp_alpha_modifier_v1
+    pub mod alpha_modifier {
+        pub const DESTROY: u16 = 0;
+        pub const GET_SURFACE: u16 = 1;
+    }
+
+    // wp_alpha_modifier_surface_v1
+    pub mod alpha_modifier_surface {
+        pub const DESTROY: u16 = 0;
+        pub const SET_MULTIPLIER: u16 = 1;
+    }
+
+    // wp_fifo_manager_v1
+    pub mod fifo_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_FIFO: u16 = 1;
+    }
+
+    // wp_fifo_v1
+    pub mod fifo {
+        pub const SET_BARRIER: u16 = 0;
+        pub const WAIT_BARRIER: u16 = 1;
+        pub const DESTROY: u16 = 2;
+    }
+
+    // wp_commit_timing_manager_v1
+    pub mod commit_timing_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_TIMER: u16 = 1;
+    }
+
+    // wp_commit_timer_v1
+    pub mod commit_timer {
+        pub const SET_TIMESTAMP: u16 = 0;
+        pub const DESTROY: u16 = 1;
+    }
+
+    // wp_single_pixel_buffer_manager_v1
+    pub mod single_pixel_buffer_manager {
+        pub const DESTROY: u16 = 0;
+        pub const CREATE_U32_RGBA_BUFFER: u16 = 1;
+    }
+
+    // wp_tearing_control_manager_v1
+    pub mod tearing_control_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_TEARING_CONTROL: u16 = 1;
+    }
+
+    // wp_tearing_control_v1
+    pub mod tearing_control {
+        pub const SET_PRESENTATION_HINT: u16 = 0;
+        pub const DESTROY: u16 = 1;
+    }
+
+    // wp_content_type_manager_v1
+    pub mod content_type_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_SURFACE_CONTENT_TYPE: u16 = 1;
+    }
+
+    // wp_content_type_v1
+    pub mod content_type {
+        pub const DESTROY: u16 = 0;
+        pub const SET_CONTENT_TYPE: u16 = 1;
+    }
+
+    // wp_fractional_scale_manager_v1
+    pub mod fractional_scale_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_FRACTIONAL_SCALE: u16 = 1;
+    }
+
+    // wp_fractional_scale_v1
+    pub mod fractional_scale {
+        pub const DESTROY: u16 = 0;
+        pub const PREFERRED_SCALE: u16 = 0; // Event
+    }
+
+    // wl_subcompositor
+    pub mod subcompositor {
+        pub const DESTROY: u16 = 0;
+        pub const GET_SUBSURFACE: u16 = 1;
+    }
+
+    // wl_subsurface
+    pub mod subsurface {
+        pub const DESTROY: u16 = 0;
+        pub const SET_POSITION: u16 = 1;
+        pub const PLACE_ABOVE: u16 = 2;
+        pub const PLACE_BELOW: u16 = 3;
+        pub const SET_SYNC: u16 = 4;
+        pub const SET_DESYNC: u16 = 5;
     }
 
     // xdg_wm_base
@@ -306,6 +828,101 @@ pub mod opcodes {
         pub const UNSET_FULLSCREEN: u16 = 12;
         pub const SET_MINIMIZED: u16 = 13;
     }
+
+    // zxdg_decoration_manager_v1
+    pub mod decoration_manager {
+        pub const DESTROY: u16 = 0;
+        pub const GET_TOPLEVEL_DECORATION: u16 = 1;
+    }
+
+    // zxdg_toplevel_decoration_v1
+    pub mod toplevel_decoration {
+        pub const CONFIGURE: u16 = 0; // Event
+        pub const DESTROY: u16 = 0;
+        pub const SET_MODE: u16 = 1;
+        pub const UNSET_MODE: u16 = 2;
+    }
+
+    // xdg_positioner
+    pub mod xdg_positioner {
+        pub const DESTROY: u16 = 0;
+        pub const SET_SIZE: u16 = 1;
+        pub const SET_ANCHOR_RECT: u16 = 2;
+        pub const SET_ANCHOR: u16 = 3;
+        pub const SET_GRAVITY: u16 = 4;
+        pub const SET_CONSTRAINT_ADJUSTMENT: u16 = 5;
+        pub const SET_OFFSET: u16 = 6;
+        pub const SET_REACTIVE: u16 = 7;
+        pub const SET_PARENT_SIZE: u16 = 8;
+        pub const SET_PARENT_CONFIGURE: u16 = 9;
+    }
+
+    // xdg_popup
+    pub mod xdg_popup {
+        pub const CONFIGURE: u16 = 0;       // Event
+        pub const POPUP_DONE: u16 = 1;      // Event
+        pub const REPOSITIONED: u16 = 2;    // Event
+        pub const DESTROY: u16 = 0;
+        pub const GRAB: u16 = 1;
+        pub const REPOSITION: u16 = 2;
+    }
+
+    // wl_data_device_manager
+    pub mod data_device_manager {
+        pub const CREATE_DATA_SOURCE: u16 = 0;
+        pub const GET_DATA_DEVICE: u16 = 1;
+    }
+
+    // wl_data_device
+    pub mod data_device {
+        pub const DATA_OFFER: u16 = 0;      // Event
+        pub const SELECTION: u16 = 5;       // Event
+        pub const START_DRAG: u16 = 0;
+        pub const SET_SELECTION: u16 = 1;
+        pub const RELEASE: u16 = 2;
+    }
+
+    // wl_data_offer
+    pub mod data_offer {
+        pub const OFFER: u16 = 0;           // Event
+        pub const ACCEPT: u16 = 0;
+        pub const RECEIVE: u16 = 1;
+        pub const DESTROY: u16 = 2;
+    }
+}
+
+/// Version an event first appeared in
+///
+/// Objects bound at an older version must not receive the event.
+pub fn event_since(interface: &str, opcode: u16) -> u32 {
+    match (interface, opcode) {
+        ("wl_output", opcodes::output::DONE | opcodes::output::SCALE) => 2,
+        ("wl_output", opcodes::output::NAME | opcodes::output::DESCRIPTION) => 4,
+        ("wl_seat", opcodes::seat::NAME) => 2,
+        ("wl_surface", opcodes::surface::PREFERRED_BUFFER_SCALE | opcodes::surface::PREFERRED_BUFFER_TRANSFORM) => 6,
+        // frame, axis_source, axis_stop, axis_discrete
+        ("wl_pointer", 5..=8) => 5,
+        // axis_value120
+        ("wl_pointer", 9) => 8,
+        // axis_relative_direction
+        ("wl_pointer", 10) => 9,
+        // repeat_info
+        ("wl_keyboard", 5) => 4,
+        // source_actions, action
+        ("wl_data_offer", 1 | 2) => 3,
+        // dnd_drop_performed, dnd_finished, action
+        ("wl_data_source", 3..=5) => 3,
+        // configure_bounds, wm_capabilities
+        ("xdg_toplevel", 2) => 4,
+        ("xdg_toplevel", 3) => 5,
+        ("xdg_popup", opcodes::xdg_popup::REPOSITIONED) => 3,
+        // modifier
+        ("zwp_linux_dmabuf_v1", 1) => 3,
+        // damage; linux_dmabuf, buffer_done
+        ("zwlr_screencopy_frame_v1", 4) => 2,
+        ("zwlr_screencopy_frame_v1", 5 | 6) => 3,
+        _ => 1,
+    }
 }
 
 #[cfg(test)]
@@ -350,4 +967,25 @@ mod tests {
         
         assert!(decoder.decode().is_none());
     }
+
+    #[test]
+    fn test_arg_reader_writer() {
+        let payload = ArgWriter::new()
+            .u32(7)
+            .fixed(-1.5)
+            .string("text/plain")
+            .i32(-3)
+            .array(&[1, 2, 3])
+            .finish();
+        assert_eq!(payload.len() % 4, 0);
+
+        let mut args = ArgReader::new(&payload);
+        assert_eq!(args.u32().unwrap(), 7);
+        assert_eq!(args.fixed().unwrap(), -1.5);
+        assert_eq!(args.string().unwrap(), "text/plain");
+        assert_eq!(args.i32().unwrap(), -3);
+        assert_eq!(args.array().unwrap(), vec![1, 2, 3]);
+        assert_eq!(args.remaining(), 0);
+        assert!(args.u32().is_err());
+    }
 }